@@ -0,0 +1,267 @@
+//! An audit log of everything done to a local backup, for setups that have to account for
+//! every change to their backups.
+//!
+//! With [`AuditedBackend`](crate::backend::AuditedBackend) in front of a backend, every
+//! copy, delete, rename and restore is appended to `backup_dir/.evilmount/audit.log` as a
+//! line of JSON, with when it happened, the size and hash of the file and whether it
+//! worked. Paths are the ones in work_dir, before names are sanitized or files compressed.
+//! Once the log grows past [`MAX_LOG_SIZE`] it's moved to `audit.log.1`, older logs move up
+//! by one, and only [`KEPT_LOGS`] of them are kept. `evil_mount audit` reads them back
+//! oldest first
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Mutex,
+};
+use tracing::warn;
+
+use crate::meta::metadata_dir;
+
+/// The log is rotated once it would grow past this
+pub const MAX_LOG_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many rotated logs are kept next to the current one
+pub const KEPT_LOGS: usize = 5;
+
+/// Where the current audit log of `backup_dir` is kept
+pub fn audit_log_path(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("audit.log")
+}
+
+/// The log rotated away `generation` times ago, the current one at 0
+fn rotated_path(backup_dir: &Path, generation: usize) -> PathBuf {
+    let path = audit_log_path(backup_dir);
+    match generation {
+        0 => path,
+        _ => {
+            let mut path = path.into_os_string();
+            path.push(format!(".{generation}"));
+            PathBuf::from(path)
+        }
+    }
+}
+
+/// What was done to the backup
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum AuditOp {
+    /// A file was stored in the backup
+    Copy,
+    Delete,
+    Rename,
+    /// A file was copied out of the backup
+    Restore,
+}
+
+impl FromStr for AuditOp {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "copy" => Ok(Self::Copy),
+            "delete" => Ok(Self::Delete),
+            "rename" => Ok(Self::Rename),
+            "restore" => Ok(Self::Restore),
+            _ => Err(format!(
+                "unknown operation {s}, expected copy, delete, rename or restore"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for AuditOp {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Copy => "copy",
+            Self::Delete => "delete",
+            Self::Rename => "rename",
+            Self::Restore => "restore",
+        })
+    }
+}
+
+/// One line of the audit log
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct AuditRecord {
+    /// When it finished, in RFC 3339 with milliseconds in UTC, so they sort as strings
+    pub at: String,
+    pub op: AuditOp,
+    pub path: PathBuf,
+    /// What a rename moved to `path`
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub from: Option<PathBuf>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub size: Option<u64>,
+    /// The BLAKE3 hash of the file, in hex
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash: Option<String>,
+    /// Whether it worked
+    pub ok: bool,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<String>,
+}
+
+impl AuditRecord {
+    /// The record of `op` on `path` that just finished with `result`
+    pub fn new<T>(op: AuditOp, path: &Path, result: &Result<T>) -> Self {
+        Self {
+            at: chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Millis, true),
+            op,
+            path: path.to_path_buf(),
+            from: None,
+            size: None,
+            hash: None,
+            ok: result.is_ok(),
+            error: result.as_ref().err().map(|err| format!("{err:#}")),
+        }
+    }
+}
+
+/// Which records `evil_mount audit` prints
+#[derive(Debug, Clone, Default)]
+pub struct AuditQuery {
+    /// Only records of this path or of files under it, either side of a rename
+    pub path: Option<PathBuf>,
+    pub op: Option<AuditOp>,
+    /// Only records at or after this, as it would be written in [`AuditRecord::at`]
+    pub since: Option<String>,
+    /// Only operations that failed
+    pub failed: bool,
+}
+
+impl AuditQuery {
+    pub fn matches(&self, record: &AuditRecord) -> bool {
+        let under = |prefix: &Path| {
+            record.path.starts_with(prefix)
+                || record
+                    .from
+                    .as_ref()
+                    .is_some_and(|from| from.starts_with(prefix))
+        };
+        self.path.as_deref().is_none_or(under)
+            && self.op.is_none_or(|op| op == record.op)
+            && self.since.as_ref().is_none_or(|since| record.at >= *since)
+            && (!self.failed || !record.ok)
+    }
+}
+
+/// The audit log of `backup_dir`, oldest record first. Lines that can't be read, like the
+/// last one of a log that was cut off, are left out
+pub fn read_audit_log(backup_dir: &Path) -> Result<Vec<AuditRecord>> {
+    let mut records = Vec::new();
+    for generation in (0..=KEPT_LOGS).rev() {
+        let path = rotated_path(backup_dir, generation);
+        let contents = match fs::read_to_string(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => continue,
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Error reading {}", path.display()))
+            }
+        };
+        records.extend(
+            contents
+                .lines()
+                .filter_map(|line| serde_json::from_str::<AuditRecord>(line).ok()),
+        );
+    }
+    Ok(records)
+}
+
+/// The audit log a backend appends to
+#[derive(Debug)]
+pub(crate) struct AuditLog {
+    backup_dir: PathBuf,
+    state: Mutex<LogState>,
+}
+
+#[derive(Debug, Default)]
+struct LogState {
+    /// The current log and how big it is, opened with the first record
+    file: Option<(File, u64)>,
+    /// Whether the last record couldn't be written, so failures are only warned about once
+    failing: bool,
+}
+
+impl AuditLog {
+    pub fn new(backup_dir: &Path) -> Self {
+        Self {
+            backup_dir: backup_dir.to_path_buf(),
+            state: Mutex::default(),
+        }
+    }
+
+    /// Appends `record`, rotating the log first if it would grow past [`MAX_LOG_SIZE`].
+    /// Records that can't be written are warned about and otherwise don't stop the sync.
+    /// This does blocking IO
+    pub fn append(&self, record: &AuditRecord) {
+        let mut state = self.state.lock().unwrap();
+        match self.write(&mut state, record) {
+            Ok(()) => state.failing = false,
+            Err(err) => {
+                if !std::mem::replace(&mut state.failing, true) {
+                    warn!("Error writing the audit log: {err:#}");
+                }
+                // Opened again with the next record
+                state.file = None;
+            }
+        }
+    }
+
+    fn write(&self, state: &mut LogState, record: &AuditRecord) -> Result<()> {
+        let mut line = serde_json::to_vec(record)?;
+        line.push(b'\n');
+
+        let (mut file, mut size) = match state.file.take() {
+            Some((file, size)) if size + line.len() as u64 > MAX_LOG_SIZE => {
+                drop(file);
+                self.rotate()?;
+                self.open()?
+            }
+            Some(file) => file,
+            None => self.open()?,
+        };
+        file.write_all(&line)?;
+        size += line.len() as u64;
+        state.file = Some((file, size));
+        Ok(())
+    }
+
+    /// The current log for appending, and how big it is. A log left as big as it may get
+    /// by an earlier run is rotated first
+    fn open(&self) -> Result<(File, u64)> {
+        let path = audit_log_path(&self.backup_dir);
+        fs::create_dir_all(metadata_dir(&self.backup_dir))?;
+        if fs::metadata(&path).is_ok_and(|metadata| metadata.len() >= MAX_LOG_SIZE) {
+            self.rotate()?;
+        }
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(&path)
+            .with_context(|| anyhow!("Error opening {}", path.display()))?;
+        let size = file.metadata()?.len();
+        Ok((file, size))
+    }
+
+    /// Moves every log up by one, dropping the oldest
+    fn rotate(&self) -> Result<()> {
+        for generation in (0..KEPT_LOGS).rev() {
+            let from = rotated_path(&self.backup_dir, generation);
+            let to = rotated_path(&self.backup_dir, generation + 1);
+            match fs::rename(&from, &to) {
+                Ok(()) => {}
+                Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Error rotating {}", from.display()))
+                }
+            }
+        }
+        Ok(())
+    }
+}
@@ -0,0 +1,605 @@
+//! A backup kept in a single archive, `.tar`, `.tar.gz`, `.tar.zst` or `.zip`.
+//!
+//! Files stored during a sync cycle are staged next to the archive and only written into it
+//! when the cycle ends. Tarballs can't be changed in place, so every cycle appends another
+//! one to the end of the archive, which `tar --ignore-zeros` reads as a whole. Replaced and
+//! deleted files stay in it until the archive is rewritten from scratch, after
+//! [`REWRITE_EVERY`] appends or once they take up more space than the rest. Zip archives are
+//! updated in place instead.
+//!
+//! An index of what's in the archive is kept with the staged files in `.<archive>.evilmount`,
+//! so listing the backup doesn't read it. Reading a file extracts the whole archive once,
+//! which is what restoring needs anyway. The `tar`, `zip` and `unzip` commands do the work,
+//! and `.tar.zst` needs `zstd` as well
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+use tokio::{fs, process::Command, sync::RwLock};
+use tracing::debug;
+
+use super::{list_files, Backend, FileMetadata};
+use crate::{
+    copy::{copy_file, CopyOptions, VerifyWrites},
+    filter::IgnoreSet,
+    meta::TEMP_SUFFIX,
+};
+
+/// Tarballs are rewritten from scratch after this many appends
+pub const REWRITE_EVERY: u32 = 50;
+
+/// The kinds of archive a backup can be kept in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ArchiveFormat {
+    Tar,
+    TarGz,
+    TarZst,
+    Zip,
+}
+
+impl ArchiveFormat {
+    /// The format `path` names by its extension, if it names an archive
+    pub fn of(path: &Path) -> Option<Self> {
+        let name = path.file_name()?.to_str()?.to_ascii_lowercase();
+        [
+            (".tar", Self::Tar),
+            (".tar.gz", Self::TarGz),
+            (".tgz", Self::TarGz),
+            (".tar.zst", Self::TarZst),
+            (".tzst", Self::TarZst),
+            (".zip", Self::Zip),
+        ]
+        .into_iter()
+        .find(|(extension, _)| name.ends_with(extension))
+        .map(|(_, format)| format)
+    }
+
+    /// What tells tar how the archive is compressed
+    fn tar_flag(self) -> Option<&'static str> {
+        match self {
+            Self::TarGz => Some("--gzip"),
+            Self::TarZst => Some("--zstd"),
+            Self::Tar | Self::Zip => None,
+        }
+    }
+}
+
+/// What's in the archive, saved next to it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+struct Index {
+    files: BTreeMap<PathBuf, FileMetadata>,
+    /// Tarballs appended since the archive was last written from scratch
+    appended: u32,
+    /// Bytes of replaced and deleted files that are still in the archive
+    stale_bytes: u64,
+}
+
+#[derive(Debug, Clone, Default)]
+struct State {
+    index: Index,
+    /// Staged since the archive was last written
+    staged: BTreeMap<PathBuf, FileMetadata>,
+    /// Files in the archive deleted since it was last written
+    deleted: BTreeSet<PathBuf>,
+    /// Whether `extracted/` holds what's in the archive right now
+    extracted: bool,
+}
+
+impl State {
+    fn metadata(&self, relative_path: &Path) -> Option<FileMetadata> {
+        match self.staged.get(relative_path) {
+            Some(metadata) => Some(*metadata),
+            None if self.deleted.contains(relative_path) => None,
+            None => self.index.files.get(relative_path).copied(),
+        }
+    }
+}
+
+/// A backup in one archive on the local filesystem, see the module docs
+#[derive(Debug)]
+pub struct ArchiveBackend {
+    archive: PathBuf,
+    format: ArchiveFormat,
+    /// `.<archive>.evilmount`, with the index, staged files and the extracted archive
+    work_dir: PathBuf,
+    copy: CopyOptions,
+    state: Mutex<State>,
+    /// Held for writing while the archive or `extracted/` change, and for reading while
+    /// files are staged
+    writing: RwLock<()>,
+}
+
+impl ArchiveBackend {
+    /// Opens the archive at `archive`, which is created by the first sync cycle that stores
+    /// anything if it doesn't exist yet
+    pub async fn open(archive: &Path, format: ArchiveFormat, copy: CopyOptions) -> Result<Self> {
+        let file_name = archive
+            .file_name()
+            .ok_or_else(|| anyhow!("{} is not a file name", archive.display()))?;
+        let parent = match archive.parent() {
+            Some(parent) if !parent.as_os_str().is_empty() => parent,
+            _ => Path::new("."),
+        };
+        let parent = parent
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", parent.display()))?;
+        let archive = parent.join(file_name);
+        let mut work_dir_name = OsString::from(".");
+        work_dir_name.push(file_name);
+        work_dir_name.push(".evilmount");
+
+        let backend = Self {
+            work_dir: parent.join(work_dir_name),
+            archive,
+            format,
+            copy,
+            state: Mutex::new(State::default()),
+            writing: RwLock::new(()),
+        };
+        // Whatever was staged when the last run stopped didn't make it into the index, so
+        // it gets stored again
+        let _ = fs::remove_dir_all(backend.staged_dir()).await;
+        fs::create_dir_all(backend.staged_dir())
+            .await
+            .with_context(|| anyhow!("Error creating {}", backend.work_dir.display()))?;
+        let _ = fs::remove_dir_all(backend.extracted_dir()).await;
+
+        let index = backend.load_index().await?;
+        backend.state.lock().unwrap().index = index;
+        Ok(backend)
+    }
+
+    fn staged_dir(&self) -> PathBuf {
+        self.work_dir.join("staged")
+    }
+
+    fn extracted_dir(&self) -> PathBuf {
+        self.work_dir.join("extracted")
+    }
+
+    /// Where `zip -d` runs, since it takes names of directories in its current directory
+    /// for directories in the archive
+    fn empty_dir(&self) -> PathBuf {
+        self.work_dir.join("empty")
+    }
+
+    fn index_path(&self) -> PathBuf {
+        self.work_dir.join("index.json")
+    }
+
+    /// Reads the index, or builds it from the archive when there is none
+    async fn load_index(&self) -> Result<Index> {
+        if !fs::try_exists(&self.archive).await? {
+            return Ok(Index::default());
+        }
+        let index_path = self.index_path();
+        match fs::read(&index_path).await {
+            Ok(contents) => {
+                return serde_json::from_slice(&contents)
+                    .with_context(|| anyhow!("Error parsing {}", index_path.display()))
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => {}
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Error reading {}", index_path.display()))
+            }
+        }
+
+        debug!(archive = %self.archive.display(), "Indexing the archive");
+        self.extract().await?;
+        self.state.lock().unwrap().extracted = true;
+        let extracted = self.extracted_dir();
+        let symlinks = self.copy.symlinks;
+        let files = tokio::task::spawn_blocking(move || {
+            list_files(&extracted, &IgnoreSet::default(), symlinks)
+        })
+        .await??;
+        let index = Index {
+            files,
+            ..Index::default()
+        };
+        self.save_index(&index).await?;
+        Ok(index)
+    }
+
+    async fn save_index(&self, index: &Index) -> Result<()> {
+        let index_path = self.index_path();
+        let tmp_path = self.work_dir.join(format!("index.json{TEMP_SUFFIX}"));
+        fs::write(&tmp_path, serde_json::to_vec(index)?).await?;
+        fs::rename(&tmp_path, &index_path)
+            .await
+            .with_context(|| anyhow!("Error writing {}", index_path.display()))
+    }
+
+    /// Extracts the whole archive into `extracted/`, later copies of a file replacing
+    /// earlier ones
+    async fn extract(&self) -> Result<()> {
+        let extracted = self.extracted_dir();
+        let _ = fs::remove_dir_all(&extracted).await;
+        fs::create_dir_all(&extracted).await?;
+        if !fs::try_exists(&self.archive).await? {
+            return Ok(());
+        }
+
+        let mut command = match self.format {
+            ArchiveFormat::Zip => {
+                let mut command = Command::new("unzip");
+                command
+                    .args(["-q", "-o"])
+                    .arg(&self.archive)
+                    .arg("-d")
+                    .arg(&extracted);
+                command
+            }
+            _ => {
+                let mut command = Command::new("tar");
+                command
+                    .args(["-x", "--ignore-zeros"])
+                    .args(self.format.tar_flag())
+                    .arg("-f")
+                    .arg(&self.archive)
+                    .arg("-C")
+                    .arg(&extracted);
+                command
+            }
+        };
+        run(&mut command)
+            .await
+            .with_context(|| anyhow!("Error extracting {}", self.archive.display()))
+    }
+
+    /// Writes `paths`, relative to `dir`, into a new tarball at `path`
+    async fn create_tarball(&self, path: &Path, dir: &Path, paths: &[&PathBuf]) -> Result<()> {
+        // NUL separated, since paths can have newlines in them
+        let mut list = Vec::new();
+        for path in paths {
+            list.extend_from_slice(path.as_os_str().as_bytes());
+            list.push(0);
+        }
+        let list_path = self.work_dir.join("files");
+        fs::write(&list_path, list).await?;
+
+        let result = run(Command::new("tar")
+            .args(["-c", "--format=posix"])
+            .args(self.format.tar_flag())
+            .arg("-f")
+            .arg(path)
+            .arg("-C")
+            .arg(dir)
+            .args(["--null", "--no-recursion", "-T"])
+            .arg(&list_path))
+        .await;
+        let _ = fs::remove_file(&list_path).await;
+        result
+    }
+
+    /// Appends what was staged to the end of a tarball
+    async fn append(&self, staged: &[&PathBuf]) -> Result<()> {
+        let appended = self.work_dir.join(format!("appended{TEMP_SUFFIX}"));
+        self.create_tarball(&appended, &self.staged_dir(), staged)
+            .await?;
+        let result = async {
+            let mut from = fs::File::open(&appended).await?;
+            let mut to = fs::OpenOptions::new()
+                .create(true)
+                .append(true)
+                .open(&self.archive)
+                .await?;
+            tokio::io::copy(&mut from, &mut to).await?;
+            to.sync_all().await
+        }
+        .await;
+        let _ = fs::remove_file(&appended).await;
+        result.with_context(|| anyhow!("Error appending to {}", self.archive.display()))
+    }
+
+    /// Writes the tarball from scratch, leaving out everything that was replaced or deleted.
+    /// `extracted/` ends up holding what's in it
+    async fn rewrite(&self, state: &State) -> Result<()> {
+        if !state.extracted {
+            self.extract().await?;
+        }
+        let extracted = self.extracted_dir();
+        for relative_path in &state.deleted {
+            let _ = fs::remove_file(extracted.join(relative_path)).await;
+        }
+        for relative_path in state.staged.keys() {
+            let path = extracted.join(relative_path);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            // Linked rather than moved, so they're still staged if writing the archive fails
+            let _ = fs::remove_file(&path).await;
+            fs::hard_link(self.staged_dir().join(relative_path), &path).await?;
+        }
+
+        let mut files: BTreeSet<&PathBuf> = state.index.files.keys().collect();
+        files.retain(|path| !state.deleted.contains(*path));
+        files.extend(state.staged.keys());
+        let files: Vec<&PathBuf> = files.into_iter().collect();
+        let mut tmp_name = self.archive.as_os_str().to_owned();
+        tmp_name.push(TEMP_SUFFIX);
+        let tmp_path = PathBuf::from(tmp_name);
+        if let Err(err) = self.create_tarball(&tmp_path, &extracted, &files).await {
+            let _ = fs::remove_file(&tmp_path).await;
+            return Err(err);
+        }
+        fs::rename(&tmp_path, &self.archive)
+            .await
+            .with_context(|| anyhow!("Error replacing {}", self.archive.display()))
+    }
+
+    /// Removes what was deleted from the zip archive and adds what was staged to it
+    async fn update_zip(&self, state: &State) -> Result<()> {
+        let deleted: Vec<&PathBuf> = state
+            .deleted
+            .iter()
+            .filter(|path| !state.staged.contains_key(*path))
+            .collect();
+        if !deleted.is_empty() {
+            fs::create_dir_all(self.empty_dir()).await?;
+            run_with_names(
+                Command::new("zip")
+                    .args(["-q", "-nw", "-d"])
+                    .arg(&self.archive)
+                    .arg("-@")
+                    .current_dir(self.empty_dir()),
+                &deleted,
+                // "Nothing to do!", none of them were in the archive anymore
+                &[ZIP_NOTHING_TO_DO],
+            )
+            .await?;
+        }
+        let staged: Vec<&PathBuf> = state.staged.keys().collect();
+        if !staged.is_empty() {
+            run_with_names(
+                Command::new("zip")
+                    .args(["-q", "-nw", "-y"])
+                    .arg(&self.archive)
+                    .arg("-@")
+                    .current_dir(self.staged_dir()),
+                &staged,
+                &[],
+            )
+            .await?;
+        }
+        Ok(())
+    }
+
+    /// Writes what changed since the last time into the archive
+    async fn write_archive(&self, state: &mut State) -> Result<()> {
+        let stale_bytes: u64 = state
+            .deleted
+            .iter()
+            .chain(state.staged.keys())
+            .filter_map(|path| state.index.files.get(path))
+            .map(|metadata| metadata.size)
+            .sum();
+        let stale_bytes = state.index.stale_bytes + stale_bytes;
+        let live_bytes: u64 = state
+            .index
+            .files
+            .iter()
+            .filter(|(path, _)| !state.deleted.contains(*path))
+            .map(|(_, metadata)| metadata.size)
+            .chain(state.staged.values().map(|metadata| metadata.size))
+            .sum();
+
+        match self.format {
+            ArchiveFormat::Zip => {
+                self.update_zip(state).await?;
+                state.extracted = false;
+            }
+            _ if state.index.appended + 1 >= REWRITE_EVERY || stale_bytes > live_bytes => {
+                debug!(archive = %self.archive.display(), "Rewriting the archive");
+                self.rewrite(state).await?;
+                state.index.appended = 0;
+                state.index.stale_bytes = 0;
+                state.extracted = true;
+            }
+            _ => {
+                let staged: Vec<&PathBuf> = state.staged.keys().collect();
+                if !staged.is_empty() {
+                    self.append(&staged).await?;
+                    state.index.appended += 1;
+                }
+                state.index.stale_bytes = stale_bytes;
+                state.extracted = false;
+            }
+        }
+
+        for relative_path in std::mem::take(&mut state.deleted) {
+            state.index.files.remove(&relative_path);
+        }
+        state.index.files.extend(std::mem::take(&mut state.staged));
+        self.save_index(&state.index).await?;
+
+        let _ = fs::remove_dir_all(self.staged_dir()).await;
+        fs::create_dir_all(self.staged_dir()).await?;
+        if !state.extracted {
+            let _ = fs::remove_dir_all(self.extracted_dir()).await;
+        }
+        Ok(())
+    }
+}
+
+/// What zip exits with when there was nothing to do
+const ZIP_NOTHING_TO_DO: i32 = 12;
+
+/// Runs `command`, failing with what it printed if it fails
+async fn run(command: &mut Command) -> Result<()> {
+    let output = command
+        .output()
+        .await
+        .with_context(|| anyhow!("Error running {:?}", command.as_std().get_program()))?;
+    check(command, &output, &[])
+}
+
+/// Fails with what `command` printed unless it succeeded or exited with one of `ok`. zip
+/// prints its errors to standard output, tar to standard error
+fn check(command: &Command, output: &std::process::Output, ok: &[i32]) -> Result<()> {
+    if output.status.success() || output.status.code().is_some_and(|code| ok.contains(&code)) {
+        return Ok(());
+    }
+    let printed: Vec<String> = [&output.stderr, &output.stdout]
+        .into_iter()
+        .map(|printed| String::from_utf8_lossy(printed).trim().to_string())
+        .filter(|printed| !printed.is_empty())
+        .collect();
+    Err(match printed.is_empty() {
+        true => anyhow!(
+            "{:?} failed with {}",
+            command.as_std().get_program(),
+            output.status
+        ),
+        false => anyhow!(
+            "{:?} failed: {}",
+            command.as_std().get_program(),
+            printed.join("\n")
+        ),
+    })
+}
+
+/// Runs `command` with `names` on its standard input, one per line, as `zip -@` reads them.
+/// Exiting with one of `ok` counts as success
+async fn run_with_names(command: &mut Command, names: &[&PathBuf], ok: &[i32]) -> Result<()> {
+    use tokio::io::AsyncWriteExt;
+
+    let mut input = Vec::new();
+    for name in names {
+        input.extend_from_slice(name.as_os_str().as_bytes());
+        input.push(b'\n');
+    }
+    let mut child = command
+        .stdin(std::process::Stdio::piped())
+        .stdout(std::process::Stdio::piped())
+        .stderr(std::process::Stdio::piped())
+        .spawn()
+        .with_context(|| anyhow!("Error running {:?}", command.as_std().get_program()))?;
+    if let Some(mut stdin) = child.stdin.take() {
+        stdin.write_all(&input).await?;
+    }
+    let output = child.wait_with_output().await?;
+    check(command, &output, ok)
+}
+
+impl fmt::Display for ArchiveBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.archive.display())
+    }
+}
+
+#[async_trait]
+impl Backend for ArchiveBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        self.put_verified(relative_path, source).await.map(|_| ())
+    }
+
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        let _writing = self.writing.read().await;
+        let staged_path = self.staged_dir().join(relative_path);
+        let hash = copy_file(source, &staged_path, &self.copy).await?;
+        let metadata = fs::symlink_metadata(&staged_path).await?;
+        self.state.lock().unwrap().staged.insert(
+            relative_path.to_path_buf(),
+            FileMetadata {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            },
+        );
+        Ok(hash)
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let _writing = self.writing.write().await;
+        let (staged, extracted) = {
+            let state = self.state.lock().unwrap();
+            if state.metadata(relative_path).is_none() {
+                return Err(anyhow!(
+                    "{} is not in {}",
+                    relative_path.display(),
+                    self.archive.display()
+                ));
+            }
+            (state.staged.contains_key(relative_path), state.extracted)
+        };
+        let path = match staged {
+            true => self.staged_dir().join(relative_path),
+            false => {
+                if !extracted {
+                    self.extract().await?;
+                    self.state.lock().unwrap().extracted = true;
+                }
+                self.extracted_dir().join(relative_path)
+            }
+        };
+
+        let copy = CopyOptions {
+            bwlimit: None,
+            verify_writes: VerifyWrites::Off,
+            ..self.copy.clone()
+        };
+        copy_file(&path, destination, &copy).await.map(|_| ())
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let _writing = self.writing.read().await;
+        let staged = {
+            let mut state = self.state.lock().unwrap();
+            if state.index.files.contains_key(relative_path) {
+                state.deleted.insert(relative_path.to_path_buf());
+            }
+            state.staged.remove(relative_path).is_some()
+        };
+        if staged {
+            let _ = fs::remove_file(self.staged_dir().join(relative_path)).await;
+        }
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let state = self.state.lock().unwrap();
+        let mut files = state.index.files.clone();
+        files.retain(|path, _| !state.deleted.contains(path));
+        files.extend(state.staged.clone());
+        Ok(files)
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        Ok(self.state.lock().unwrap().metadata(relative_path))
+    }
+
+    async fn check(&self) -> Result<()> {
+        fs::metadata(&self.work_dir)
+            .await
+            .map(|_| ())
+            .with_context(|| anyhow!("Error reading {}", self.work_dir.display()))
+    }
+
+    async fn flush(&self) -> Result<()> {
+        let _writing = self.writing.write().await;
+        let mut state = self.state.lock().unwrap().clone();
+        if state.staged.is_empty() && state.deleted.is_empty() {
+            return Ok(());
+        }
+        match self.write_archive(&mut state).await {
+            Ok(()) => {
+                *self.state.lock().unwrap() = state;
+                Ok(())
+            }
+            // Everything stays staged for the next try
+            Err(err) => {
+                self.state.lock().unwrap().extracted = false;
+                Err(err).with_context(|| anyhow!("Error writing {}", self.archive.display()))
+            }
+        }
+    }
+}
@@ -0,0 +1,173 @@
+//! Writing the [audit log](crate::audit) of a local backup. Sits in front of every other
+//! backend, so the paths logged are the ones in work_dir. Copies that weren't verified with
+//! a hash are hashed once more for the log
+
+use anyhow::{anyhow, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+
+use super::{Backend, FileMetadata};
+use crate::{
+    audit::{AuditLog, AuditOp, AuditRecord},
+    hash::hash_file,
+    prune::{Pruned, Retention},
+};
+
+/// A backend that logs every copy, delete, rename and restore in the audit log of its
+/// backup_dir
+#[derive(Debug)]
+pub struct AuditedBackend {
+    inner: Arc<dyn Backend>,
+    log: AuditLog,
+}
+
+impl AuditedBackend {
+    /// Logs what's done to `inner` if `enabled`, which has to be a local backup
+    pub fn wrap(inner: Arc<dyn Backend>, enabled: bool) -> Result<Arc<dyn Backend>> {
+        if !enabled {
+            return Ok(inner);
+        }
+        let Some(backup_dir) = inner.local_dir() else {
+            return Err(anyhow!(
+                "Audit logs are only supported for local backup directories"
+            ));
+        };
+        let log = AuditLog::new(backup_dir);
+        Ok(Arc::new(Self { inner, log }))
+    }
+
+    /// Logs the copy of `source` into `relative_path`, hashing `source` unless the copy did
+    async fn copied(&self, relative_path: &Path, source: &Path, put: &Result<Option<Hash>>) {
+        let mut record = AuditRecord::new(AuditOp::Copy, relative_path, put);
+        if let Ok(hash) = put {
+            record.size = fs::metadata(source)
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+            record.hash = match hash {
+                Some(hash) => Some(hash.to_hex().to_string()),
+                None => hash_of(source).await,
+            };
+        }
+        self.log.append(&record);
+    }
+}
+
+/// The hash of the file at `path` in hex, if it can be read
+async fn hash_of(path: &Path) -> Option<String> {
+    let path = path.to_path_buf();
+    let hash = tokio::task::spawn_blocking(move || hash_file(&path)).await;
+    Some(hash.ok()?.ok()?.to_hex().to_string())
+}
+
+impl fmt::Display for AuditedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl Backend for AuditedBackend {
+    fn local_dir(&self) -> Option<&Path> {
+        self.inner.local_dir()
+    }
+
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let put = self.inner.put(relative_path, source).await.map(|()| None);
+        self.copied(relative_path, source, &put).await;
+        put.map(|_| ())
+    }
+
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        let put = self.inner.put_verified(relative_path, source).await;
+        self.copied(relative_path, source, &put).await;
+        put
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let got = self.inner.get(relative_path, destination).await;
+        let mut record = AuditRecord::new(AuditOp::Restore, relative_path, &got);
+        if got.is_ok() {
+            record.size = fs::metadata(destination)
+                .await
+                .ok()
+                .map(|metadata| metadata.len());
+            record.hash = hash_of(destination).await;
+        }
+        self.log.append(&record);
+        got
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let size = match self.inner.metadata(relative_path).await {
+            Ok(Some(metadata)) => Some(metadata.size),
+            _ => None,
+        };
+        let deleted = self.inner.delete(relative_path).await;
+        let mut record = AuditRecord::new(AuditOp::Delete, relative_path, &deleted);
+        record.size = size;
+        self.log.append(&record);
+        deleted
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner.create_dir(relative_path).await
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner.delete_dir(relative_path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let renamed = self.inner.rename(from, to).await;
+        // Nothing happened, the file is stored again instead
+        if matches!(renamed, Ok(false)) {
+            return renamed;
+        }
+        let mut record = AuditRecord::new(AuditOp::Rename, to, &renamed);
+        record.from = Some(from.to_path_buf());
+        if renamed.is_ok() {
+            record.size = match self.inner.metadata(to).await {
+                Ok(Some(metadata)) => Some(metadata.size),
+                _ => None,
+            };
+        }
+        self.log.append(&record);
+        renamed
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        self.inner.list().await
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        self.inner.list_dirs().await
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        self.inner.metadata(relative_path).await
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        self.inner.prune(retention, dry_run).await
+    }
+
+    async fn hash(&self, relative_path: &Path) -> Result<Hash> {
+        self.inner.hash(relative_path).await
+    }
+}
@@ -0,0 +1,484 @@
+//! A content addressed backup, which stores every distinct file once no matter how many
+//! copies of it there are, in work_dir or across snapshots.
+//!
+//! The data of each file lives in `objects/<ab>/<cdef...>`, named by its BLAKE3 hash.
+//! Which file has which hash is kept in a tree under `trees/`, and every sync cycle that
+//! changed something adds a new one named by when it was written, so older trees are
+//! snapshots that cost no more than their own index. The newest tree is the backup.
+//! `evil_mount prune` removes old trees along with the objects nothing refers to any more.
+//!
+//! Since the trees already know the hash of every file, verifying the backup compares them
+//! with work_dir without reading the objects back
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicUsize, Ordering},
+        Mutex,
+    },
+};
+use tokio::{fs, sync::RwLock};
+
+use super::{Backend, FileMetadata};
+use crate::{
+    copy::{
+        copy_file, copy_streaming, create_dirs, finish_copy, remove_and_prune, sync_parent,
+        verify_copy, Durability,
+    },
+    copy::{CopyOptions, VerifyWrites},
+    detect::hex_hash,
+    hash::hash_file,
+    meta::{metadata_dir, METADATA_DIR_NAME, TEMP_SUFFIX},
+    prune::{expired, Pruned, Retention},
+    snapshot::SNAPSHOT_FORMAT,
+};
+
+/// What `.evilmount/format` holds in a content addressed backup
+const FORMAT_MARKER: &str = "cas";
+
+/// A file in a tree
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Entry {
+    #[serde(with = "hex_hash")]
+    hash: Hash,
+    #[serde(flatten)]
+    metadata: FileMetadata,
+}
+
+type Tree = BTreeMap<PathBuf, Entry>;
+
+fn format_marker(root: &Path) -> PathBuf {
+    metadata_dir(root).join("format")
+}
+
+/// A content addressed backup in a local directory, see the module docs
+#[derive(Debug)]
+pub struct CasBackend {
+    root: PathBuf,
+    copy: CopyOptions,
+    /// The files as of the newest tree, and whatever changed since
+    tree: Mutex<Tree>,
+    /// Whether `tree` changed since it was written out
+    dirty: AtomicBool,
+    /// Held for reading while objects are written, and for writing while unreferenced ones
+    /// are removed, so an object is never removed right before a tree refers to it
+    writing: RwLock<()>,
+    incoming: AtomicUsize,
+}
+
+impl CasBackend {
+    /// Whether `root` holds a content addressed backup
+    pub fn is_cas(root: &Path) -> bool {
+        std::fs::read_to_string(format_marker(root))
+            .is_ok_and(|format| format.trim() == FORMAT_MARKER)
+    }
+
+    /// Opens the content addressed backup in `root`, starting a new one if `root` is empty
+    pub async fn open(root: impl Into<PathBuf>, copy: CopyOptions) -> Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            return Err(anyhow!("backup_dir must be a directory!"));
+        }
+        let root = root
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", root.display()))?;
+
+        let tree = match Self::is_cas(&root) {
+            true => match list_trees(&root)?.last() {
+                Some(newest) => read_tree(newest)?,
+                None => Tree::new(),
+            },
+            false => {
+                let mut entries = std::fs::read_dir(&root)
+                    .with_context(|| anyhow!("Error reading {}", root.display()))?;
+                if entries
+                    .any(|entry| entry.is_ok_and(|entry| entry.file_name() != METADATA_DIR_NAME))
+                {
+                    return Err(anyhow!(
+                        "{} already holds a backup in another format, start a content addressed one in an empty directory",
+                        root.display()
+                    ));
+                }
+                let marker = format_marker(&root);
+                std::fs::create_dir_all(metadata_dir(&root))?;
+                std::fs::write(&marker, format!("{FORMAT_MARKER}\n"))
+                    .with_context(|| anyhow!("Error writing {}", marker.display()))?;
+                Tree::new()
+            }
+        };
+
+        Ok(Self {
+            root,
+            copy,
+            tree: Mutex::new(tree),
+            dirty: AtomicBool::new(false),
+            writing: RwLock::new(()),
+            incoming: AtomicUsize::new(0),
+        })
+    }
+
+    fn objects_dir(&self) -> PathBuf {
+        self.root.join("objects")
+    }
+
+    fn object_path(&self, hash: &Hash) -> PathBuf {
+        let hex = hash.to_hex();
+        self.objects_dir().join(&hex[..2]).join(&hex[2..])
+    }
+
+    fn entry(&self, relative_path: &Path) -> Option<Entry> {
+        self.tree.lock().unwrap().get(relative_path).copied()
+    }
+
+    fn set_entry(&self, relative_path: &Path, entry: Option<Entry>) {
+        let mut tree = self.tree.lock().unwrap();
+        match entry {
+            Some(entry) => tree.insert(relative_path.to_path_buf(), entry),
+            None => tree.remove(relative_path),
+        };
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    /// Files in `.evilmount`, like the manifest, are stored as they are rather than in
+    /// the tree
+    fn is_sidecar(relative_path: &Path) -> bool {
+        relative_path.starts_with(METADATA_DIR_NAME)
+    }
+
+    /// Adds the file at `source` to the objects unless one with the same contents is there
+    /// already, returning its hash
+    async fn store_object(&self, source: &Path) -> Result<Hash> {
+        let hashed = {
+            let source = source.to_path_buf();
+            tokio::task::spawn_blocking(move || hash_file(&source)).await??
+        };
+        let object_path = self.object_path(&hashed);
+        if fs::try_exists(&object_path).await? {
+            return Ok(hashed);
+        }
+
+        let parent = object_path.parent().unwrap();
+        fs::create_dir_all(parent)
+            .await
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        let tmp_path = parent.join(format!(
+            "incoming-{}{TEMP_SUFFIX}",
+            self.incoming.fetch_add(1, Ordering::Relaxed)
+        ));
+        let stored = async {
+            let (hash, len) =
+                copy_streaming(source, &tmp_path, self.copy.bwlimit.as_deref()).await?;
+            if self.copy.verify_writes != VerifyWrites::Off {
+                verify_copy(
+                    source,
+                    &tmp_path,
+                    Some((hash, len)),
+                    self.copy.verify_writes,
+                )
+                .await?;
+            }
+            finish_copy(source, &tmp_path, &self.copy).await?;
+            // Named by what was copied, in case the file changed since it was hashed
+            let object_path = self.object_path(&hash);
+            if let Some(parent) = object_path.parent() {
+                create_dirs(parent, self.copy.durability).await?;
+            }
+            fs::rename(&tmp_path, &object_path).await?;
+            sync_parent(&object_path, self.copy.durability).await?;
+            Ok::<_, anyhow::Error>(hash)
+        }
+        .await;
+        if stored.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+        }
+        stored.with_context(|| anyhow!("Error storing {}", source.display()))
+    }
+
+    /// Writes the tree out under `trees/` if it changed
+    async fn save_tree(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&*self.tree.lock().unwrap())?;
+
+        let trees_dir = trees_dir(&self.root);
+        let name = chrono::Utc::now().format(SNAPSHOT_FORMAT).to_string();
+        // Two trees within the same second get a counter, like snapshots
+        let mut tree_path = trees_dir.join(&name);
+        let mut counter = 1;
+        while fs::try_exists(&tree_path).await? {
+            tree_path = trees_dir.join(format!("{name}.{counter}"));
+            counter += 1;
+        }
+
+        let tmp_path = trees_dir.join(format!("{name}{TEMP_SUFFIX}"));
+        let saved = async {
+            fs::create_dir_all(&trees_dir).await?;
+            fs::write(&tmp_path, contents).await?;
+            if self.copy.durability != Durability::None {
+                fs::File::open(&tmp_path).await?.sync_all().await?;
+            }
+            fs::rename(&tmp_path, &tree_path).await?;
+            sync_parent(&tree_path, self.copy.durability).await
+        }
+        .await;
+        if saved.is_err() {
+            let _ = fs::remove_file(&tmp_path).await;
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        saved.with_context(|| anyhow!("Error writing {}", tree_path.display()))
+    }
+
+    /// Removes every object that none of `trees` nor the current tree refers to, returning
+    /// how many bytes they took up. This does blocking IO
+    fn collect_garbage(&self, trees: &[PathBuf], dry_run: bool) -> Result<u64> {
+        let objects_dir = self.objects_dir();
+        if !objects_dir.exists() {
+            return Ok(0);
+        }
+
+        let mut referenced: HashSet<Hash> = self
+            .tree
+            .lock()
+            .unwrap()
+            .values()
+            .map(|entry| entry.hash)
+            .collect();
+        for tree in trees {
+            referenced.extend(read_tree(tree)?.values().map(|entry| entry.hash));
+        }
+
+        let mut bytes = 0;
+        for file_info in walkdir::WalkDir::new(objects_dir).min_depth(2) {
+            let file_info = file_info?;
+            if !file_info.file_type().is_file() {
+                continue;
+            }
+            let path = file_info.path();
+            let hash = path
+                .parent()
+                .and_then(Path::file_name)
+                .zip(path.file_name())
+                .and_then(|(prefix, rest)| {
+                    Hash::from_hex(format!("{}{}", prefix.to_str()?, rest.to_str()?)).ok()
+                });
+            // Leftovers of interrupted copies go too
+            if hash.is_some_and(|hash| referenced.contains(&hash)) {
+                continue;
+            }
+            bytes += file_info.metadata()?.len();
+            if !dry_run {
+                std::fs::remove_file(path)
+                    .with_context(|| anyhow!("Error removing {}", path.display()))?;
+            }
+        }
+        Ok(bytes)
+    }
+}
+
+fn trees_dir(root: &Path) -> PathBuf {
+    root.join("trees")
+}
+
+/// The trees of the backup in `root`, oldest first. This does blocking IO
+fn list_trees(root: &Path) -> Result<Vec<PathBuf>> {
+    let trees_dir = trees_dir(root);
+    if !trees_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut trees = Vec::new();
+    for entry in std::fs::read_dir(&trees_dir)
+        .with_context(|| anyhow!("Error reading {}", trees_dir.display()))?
+    {
+        let entry = entry?;
+        let name = entry.file_name();
+        if entry.file_type()?.is_file() && !name.to_string_lossy().ends_with(TEMP_SUFFIX) {
+            trees.push(entry.path());
+        }
+    }
+    trees.sort();
+
+    Ok(trees)
+}
+
+/// This does blocking IO
+fn read_tree(path: &Path) -> Result<Tree> {
+    let contents =
+        std::fs::read(path).with_context(|| anyhow!("Error reading {}", path.display()))?;
+    serde_json::from_slice(&contents).with_context(|| anyhow!("Error parsing {}", path.display()))
+}
+
+impl fmt::Display for CasBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root.display())
+    }
+}
+
+#[async_trait]
+impl Backend for CasBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        self.put_verified(relative_path, source).await.map(|_| ())
+    }
+
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        if Self::is_sidecar(relative_path) {
+            return copy_file(source, &self.root.join(relative_path), &self.copy).await;
+        }
+
+        let _writing = self.writing.read().await;
+        let metadata = fs::metadata(source)
+            .await
+            .with_context(|| anyhow!("Error reading metadata of {}", source.display()))?;
+        let hash = self.store_object(source).await?;
+        self.set_entry(
+            relative_path,
+            Some(Entry {
+                hash,
+                metadata: FileMetadata {
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                },
+            }),
+        );
+        Ok((self.copy.verify_writes != VerifyWrites::Off).then_some(hash))
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let source = match Self::is_sidecar(relative_path) {
+            true => self.root.join(relative_path),
+            false => {
+                let entry = self
+                    .entry(relative_path)
+                    .ok_or_else(|| anyhow!("{} isn't in the backup", relative_path.display()))?;
+                self.object_path(&entry.hash)
+            }
+        };
+        let copy = CopyOptions {
+            bwlimit: None,
+            verify_writes: VerifyWrites::Off,
+            ..self.copy.clone()
+        };
+        copy_file(&source, destination, &copy).await.map(|_| ())
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        match Self::is_sidecar(relative_path) {
+            true => remove_and_prune(&self.root.join(relative_path), &self.root).await,
+            false => {
+                if self.entry(relative_path).is_some() {
+                    self.set_entry(relative_path, None);
+                }
+                Ok(())
+            }
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        if Self::is_sidecar(from) || Self::is_sidecar(to) {
+            return Ok(false);
+        }
+        let Some(entry) = self.entry(from) else {
+            return Ok(false);
+        };
+        self.set_entry(from, None);
+        self.set_entry(to, Some(entry));
+        Ok(true)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        Ok(self
+            .tree
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(path, entry)| (path.clone(), entry.metadata))
+            .collect())
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        if !Self::is_sidecar(relative_path) {
+            return Ok(self.entry(relative_path).map(|entry| entry.metadata));
+        }
+        match fs::metadata(self.root.join(relative_path)).await {
+            Ok(metadata) => Ok(Some(FileMetadata {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        match fs::metadata(&self.root).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(anyhow!("{} is not a directory", self.root.display())),
+            Err(err) => Err(err).with_context(|| anyhow!("Error reading {}", self.root.display())),
+        }
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.save_tree().await
+    }
+
+    /// The hash the tree has for `relative_path`, as long as its object is still there
+    async fn hash(&self, relative_path: &Path) -> Result<Hash> {
+        if Self::is_sidecar(relative_path) {
+            let path = self.root.join(relative_path);
+            return tokio::task::spawn_blocking(move || hash_file(&path)).await?;
+        }
+        let entry = self
+            .entry(relative_path)
+            .ok_or_else(|| anyhow!("{} isn't in the backup", relative_path.display()))?;
+        let object_path = self.object_path(&entry.hash);
+        match fs::try_exists(&object_path).await? {
+            true => Ok(entry.hash),
+            false => Err(anyhow!(
+                "The object of {} is missing from {}",
+                relative_path.display(),
+                object_path.display()
+            )),
+        }
+    }
+
+    /// Removes the trees `retention` doesn't keep, never the newest, then the objects that
+    /// are left unreferenced
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        retention.check()?;
+        // The newest tree might still be waiting to be written
+        if !dry_run {
+            self.save_tree().await?;
+        }
+        let _writing = self.writing.write().await;
+        let root = self.root.clone();
+        let pruned = tokio::task::block_in_place(|| {
+            let trees = list_trees(&root)?;
+            let newest = trees.last().cloned();
+            let mut removed = expired(trees.clone(), retention);
+            removed.retain(|tree| Some(tree) != newest.as_ref());
+            let mut bytes = 0;
+            for tree in &removed {
+                bytes += std::fs::metadata(tree)?.len();
+                if !dry_run {
+                    std::fs::remove_file(tree)
+                        .with_context(|| anyhow!("Error removing {}", tree.display()))?;
+                }
+            }
+
+            let kept: Vec<PathBuf> = trees
+                .into_iter()
+                .filter(|tree| !removed.contains(tree))
+                .collect();
+            bytes += self.collect_garbage(&kept, dry_run)?;
+            Ok::<_, anyhow::Error>(Pruned { removed, bytes })
+        })?;
+        Ok(Some(pruned))
+    }
+}
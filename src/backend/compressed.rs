@@ -0,0 +1,604 @@
+//! Compressing files on their way into another backend, which pays off for text heavy
+//! work trees.
+//!
+//! A compressed file is stored as `<name>.zst` (or `.gz`), while files that are compressed
+//! already, going by their extension, are stored as they are. Those get `.plain` appended
+//! when their names end in `.zst`, `.gz` or `.plain` themselves, so every stored name can
+//! only be read back one way. Names that aren't UTF-8 are always stored as they are.
+//! Listing the backup has to report the original sizes for files to be compared by their
+//! metadata, so those are kept in `.evilmount/compressed.json` along with the size each
+//! file was stored with, saved before every put returns. A stored file whose size doesn't
+//! match any more, or that the index doesn't know about after a crash, is listed with its
+//! own size, so it gets copied again
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::fs;
+
+use super::{temp_file, Backend, FileMetadata};
+use crate::{
+    copy::temp_path_for,
+    meta::METADATA_DIR_NAME,
+    prune::{Pruned, Retention},
+};
+
+/// Extensions of files that hardly get any smaller when compressed again
+const COMPRESSED_EXTENSIONS: &[&str] = &[
+    "7z", "apk", "avi", "avif", "br", "bz2", "docx", "flac", "gif", "gz", "heic", "jar", "jpeg",
+    "jpg", "lz4", "m4a", "mkv", "mov", "mp3", "mp4", "ogg", "opus", "png", "pptx", "rar", "tgz",
+    "webm", "webp", "whl", "woff", "woff2", "xlsx", "xz", "zip", "zst",
+];
+
+/// Appended to the names of files stored as they are that could be mistaken for compressed
+/// ones otherwise, see the module docs
+const PLAIN_SUFFIX: &str = ".plain";
+
+/// The formats files can be compressed in
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Compressor {
+    Zstd,
+    Gzip,
+}
+
+impl Compressor {
+    const ALL: [Self; 2] = [Self::Zstd, Self::Gzip];
+
+    fn name(self) -> &'static str {
+        match self {
+            Self::Zstd => "zstd",
+            Self::Gzip => "gzip",
+        }
+    }
+
+    /// What's appended to the names of compressed files
+    fn suffix(self) -> &'static str {
+        match self {
+            Self::Zstd => ".zst",
+            Self::Gzip => ".gz",
+        }
+    }
+
+    fn default_level(self) -> u32 {
+        match self {
+            Self::Zstd => 3,
+            Self::Gzip => 6,
+        }
+    }
+
+    fn max_level(self) -> u32 {
+        match self {
+            Self::Zstd => 19,
+            Self::Gzip => 9,
+        }
+    }
+}
+
+/// How files get compressed, `zstd` or `gzip`, optionally with a level like `zstd:9`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Compression {
+    pub compressor: Compressor,
+    pub level: u32,
+}
+
+impl FromStr for Compression {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (name, level) = match s.split_once(':') {
+            Some((name, level)) => (name, Some(level)),
+            None => (s, None),
+        };
+        let compressor = match name {
+            "zstd" => Compressor::Zstd,
+            "gzip" => Compressor::Gzip,
+            _ => return Err(format!("unknown compressor {name}, expected zstd or gzip")),
+        };
+        let level = match level {
+            Some(level) => level
+                .parse()
+                .ok()
+                .filter(|level| (1..=compressor.max_level()).contains(level))
+                .ok_or_else(|| {
+                    format!(
+                        "invalid level {level}, {name} takes 1 to {}",
+                        compressor.max_level()
+                    )
+                })?,
+            None => compressor.default_level(),
+        };
+        Ok(Self { compressor, level })
+    }
+}
+
+impl TryFrom<String> for Compression {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Compression {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}:{}", self.compressor.name(), self.level)
+    }
+}
+
+impl From<Compression> for String {
+    fn from(compression: Compression) -> Self {
+        compression.to_string()
+    }
+}
+
+/// A file that was stored compressed
+#[derive(Debug, Clone, Copy, Serialize, Deserialize)]
+struct Entry {
+    compressor: Compressor,
+    size: u64,
+    stored_size: u64,
+}
+
+impl Serialize for Compressor {
+    fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(self.name())
+    }
+}
+
+impl<'de> Deserialize<'de> for Compressor {
+    fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+        let name = String::deserialize(deserializer)?;
+        name.parse::<Compression>()
+            .map(|compression| compression.compressor)
+            .map_err(serde::de::Error::custom)
+    }
+}
+
+fn index_path() -> PathBuf {
+    Path::new(METADATA_DIR_NAME).join("compressed.json")
+}
+
+fn with_suffix(relative_path: &Path, suffix: &str) -> PathBuf {
+    let mut stored_path = OsString::from(relative_path.as_os_str());
+    stored_path.push(suffix);
+    PathBuf::from(stored_path)
+}
+
+/// Where `relative_path` is stored when it isn't compressed
+fn plain_path(relative_path: &Path) -> PathBuf {
+    let reserved = relative_path.to_str().is_some_and(|path| {
+        Compressor::ALL
+            .iter()
+            .map(|compressor| compressor.suffix())
+            .chain([PLAIN_SUFFIX])
+            .any(|suffix| path.ends_with(suffix))
+    });
+    match reserved {
+        true => with_suffix(relative_path, PLAIN_SUFFIX),
+        false => relative_path.to_path_buf(),
+    }
+}
+
+/// The path a file stored as `stored_path` was backed up from, and what it was compressed
+/// with, the other way around from [`plain_path`] and [`with_suffix`]
+fn original_path(stored_path: &Path) -> (PathBuf, Option<Compressor>) {
+    let Some(path) = stored_path.to_str() else {
+        return (stored_path.to_path_buf(), None);
+    };
+    if let Some(original) = path.strip_suffix(PLAIN_SUFFIX) {
+        return (PathBuf::from(original), None);
+    }
+    Compressor::ALL
+        .into_iter()
+        .find_map(|compressor| {
+            let original = path.strip_suffix(compressor.suffix())?;
+            Some((PathBuf::from(original), Some(compressor)))
+        })
+        .unwrap_or_else(|| (stored_path.to_path_buf(), None))
+}
+
+/// Whether `relative_path` is worth compressing
+fn compresses(relative_path: &Path) -> bool {
+    let Some(path) = relative_path.to_str() else {
+        return false;
+    };
+    let extension = Path::new(path)
+        .extension()
+        .map(|extension| extension.to_string_lossy().to_ascii_lowercase());
+    !extension.is_some_and(|extension| COMPRESSED_EXTENSIONS.contains(&extension.as_str()))
+}
+
+/// Compresses `source` into `destination`, or decompresses it with `compression` being
+/// `None`. This does blocking IO
+fn transcode(
+    compressor: Compressor,
+    compression: Option<Compression>,
+    source: &Path,
+    destination: &Path,
+) -> io::Result<()> {
+    let mut reader = std::fs::File::open(source)?;
+    let mut writer = std::fs::File::create(destination)?;
+    match (compressor, compression) {
+        (Compressor::Zstd, Some(compression)) => {
+            // The level is at most 19
+            zstd::stream::copy_encode(reader, &mut writer, compression.level as i32)?;
+        }
+        (Compressor::Zstd, None) => zstd::stream::copy_decode(reader, &mut writer)?,
+        (Compressor::Gzip, Some(compression)) => {
+            let level = flate2::Compression::new(compression.level);
+            let mut encoder = flate2::write::GzEncoder::new(&mut writer, level);
+            io::copy(&mut reader, &mut encoder)?;
+            encoder.finish()?;
+        }
+        (Compressor::Gzip, None) => {
+            io::copy(&mut flate2::read::MultiGzDecoder::new(reader), &mut writer)?;
+        }
+    }
+    Ok(())
+}
+
+/// [`transcode`] on the blocking thread pool
+async fn transcode_blocking(
+    compressor: Compressor,
+    compression: Option<Compression>,
+    source: &Path,
+    destination: &Path,
+) -> Result<()> {
+    let (source, destination) = (source.to_path_buf(), destination.to_path_buf());
+    tokio::task::spawn_blocking(move || transcode(compressor, compression, &source, &destination))
+        .await
+        .map_err(|_| anyhow!("Compressing panicked"))?
+        .map_err(anyhow::Error::from)
+}
+
+/// Another backend with files compressed on their way in, see the module docs
+#[derive(Debug)]
+pub struct CompressedBackend {
+    inner: Arc<dyn Backend>,
+    /// `None` when decompressing a backup that isn't compressed any more
+    compression: Option<Compression>,
+    index: Mutex<BTreeMap<PathBuf, Entry>>,
+    /// Whether the index changed since it was saved
+    dirty: AtomicBool,
+    /// Held while saving the index, so an older one can't be saved over a newer one
+    saving: tokio::sync::Mutex<()>,
+    /// Compressed files the index doesn't know about, left behind by a put that didn't
+    /// finish, as they were found by the last listing
+    leftovers: Mutex<BTreeMap<PathBuf, Compressor>>,
+}
+
+impl CompressedBackend {
+    /// Compresses what's stored in `inner` with `compression`. Backups that have compressed
+    /// files in them are decompressed even without one, so restoring and verifying them
+    /// works without repeating the setting, and `inner` is only returned as it is when there's
+    /// nothing to do
+    pub async fn wrap(
+        inner: Arc<dyn Backend>,
+        compression: Option<Compression>,
+    ) -> Result<Arc<dyn Backend>> {
+        let index = match inner.metadata(&index_path()).await? {
+            Some(_) => {
+                let tmp_path = temp_file();
+                let index = async {
+                    inner.get(&index_path(), &tmp_path).await?;
+                    let contents = fs::read(&tmp_path).await?;
+                    Ok::<_, anyhow::Error>(serde_json::from_slice(&contents)?)
+                }
+                .await;
+                let _ = fs::remove_file(&tmp_path).await;
+                index.with_context(|| anyhow!("Error reading the index of compressed files"))?
+            }
+            None if compression.is_none() => return Ok(inner),
+            None => BTreeMap::new(),
+        };
+        Ok(Arc::new(Self {
+            inner,
+            compression,
+            index: Mutex::new(index),
+            dirty: AtomicBool::new(false),
+            saving: tokio::sync::Mutex::new(()),
+            leftovers: Mutex::new(BTreeMap::new()),
+        }))
+    }
+
+    fn entry(&self, relative_path: &Path) -> Option<Entry> {
+        self.index.lock().unwrap().get(relative_path).copied()
+    }
+
+    /// What `relative_path` is stored compressed with, if it is
+    fn compressor(&self, relative_path: &Path) -> Option<Compressor> {
+        self.entry(relative_path)
+            .map(|entry| entry.compressor)
+            .or_else(|| self.leftovers.lock().unwrap().get(relative_path).copied())
+    }
+
+    /// Where `relative_path` is stored in the inner backend
+    fn stored_path(&self, relative_path: &Path) -> PathBuf {
+        match self.compressor(relative_path) {
+            Some(compressor) => with_suffix(relative_path, compressor.suffix()),
+            None => plain_path(relative_path),
+        }
+    }
+
+    fn set_entry(&self, relative_path: &Path, entry: Option<Entry>) {
+        self.leftovers.lock().unwrap().remove(relative_path);
+        let mut index = self.index.lock().unwrap();
+        match entry {
+            Some(entry) => index.insert(relative_path.to_path_buf(), entry),
+            None => index.remove(relative_path),
+        };
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn listed_metadata(entry: Option<Entry>, metadata: FileMetadata) -> FileMetadata {
+        match entry {
+            Some(entry) if entry.stored_size == metadata.size => FileMetadata {
+                size: entry.size,
+                ..metadata
+            },
+            _ => metadata,
+        }
+    }
+
+    async fn save_index(&self) -> Result<()> {
+        let _saving = self.saving.lock().await;
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&*self.index.lock().unwrap())?;
+        let tmp_path = temp_file();
+        let saved = async {
+            fs::write(&tmp_path, contents).await?;
+            self.inner.put(&index_path(), &tmp_path).await
+        }
+        .await;
+        let _ = fs::remove_file(&tmp_path).await;
+        if saved.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        saved.with_context(|| anyhow!("Error saving the index of compressed files"))
+    }
+}
+
+impl fmt::Display for CompressedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl Backend for CompressedBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let previous_path = self.stored_path(relative_path);
+        let compression = self.compression.filter(|_| compresses(relative_path));
+        let Some(compression) = compression else {
+            let stored_path = plain_path(relative_path);
+            self.inner.put(&stored_path, source).await?;
+            if previous_path != stored_path {
+                self.set_entry(relative_path, None);
+                self.save_index().await?;
+                self.inner.delete(&previous_path).await?;
+            }
+            return Ok(());
+        };
+
+        let stored_path = with_suffix(relative_path, compression.compressor.suffix());
+        let tmp_path = temp_file();
+        let stored = async {
+            transcode_blocking(compression.compressor, Some(compression), source, &tmp_path)
+                .await?;
+            self.inner.put(&stored_path, &tmp_path).await?;
+            Ok::<_, anyhow::Error>(Entry {
+                compressor: compression.compressor,
+                size: fs::metadata(source).await?.len(),
+                stored_size: fs::metadata(&tmp_path).await?.len(),
+            })
+        }
+        .await;
+        let _ = fs::remove_file(&tmp_path).await;
+        let entry = stored.with_context(|| anyhow!("Error compressing {}", source.display()))?;
+        self.set_entry(relative_path, Some(entry));
+        // A crash would leave a listing with the size of the stored file otherwise
+        self.save_index().await?;
+
+        // Whatever was stored before under another name
+        match previous_path != stored_path {
+            true => self.inner.delete(&previous_path).await,
+            false => Ok(()),
+        }
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let Some(compressor) = self.compressor(relative_path) else {
+            return self
+                .inner
+                .get(&plain_path(relative_path), destination)
+                .await;
+        };
+
+        let stored_path = with_suffix(relative_path, compressor.suffix());
+        let tmp_path = temp_file();
+        let decompressed = async {
+            self.inner.get(&stored_path, &tmp_path).await?;
+            let decompressed_path = temp_path_for(destination);
+            if let Some(parent) = destination.parent() {
+                fs::create_dir_all(parent).await?;
+            }
+            let transcoded =
+                transcode_blocking(compressor, None, &tmp_path, &decompressed_path).await;
+            let renamed = match transcoded {
+                Ok(()) => fs::rename(&decompressed_path, destination)
+                    .await
+                    .map_err(anyhow::Error::from),
+                Err(err) => Err(err),
+            };
+            if renamed.is_err() {
+                let _ = fs::remove_file(&decompressed_path).await;
+            }
+            renamed
+        }
+        .await;
+        let _ = fs::remove_file(&tmp_path).await;
+        decompressed.with_context(|| anyhow!("Error decompressing {}", stored_path.display()))
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let compressor = self.compressor(relative_path);
+        self.inner.delete(&self.stored_path(relative_path)).await?;
+        if compressor.is_some() {
+            self.set_entry(relative_path, None);
+        }
+        Ok(())
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner.create_dir(relative_path).await
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner.delete_dir(relative_path).await
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let entry = self.entry(from);
+        // Renaming would leave the file compressed or not under a name that says otherwise,
+        // and a leftover has no size in the index to move along
+        if entry.is_some() != (self.compression.is_some() && compresses(to))
+            || entry.is_some() != self.compressor(from).is_some()
+            || self.compressor(to).is_some()
+        {
+            return Ok(false);
+        }
+        let Some(entry) = entry else {
+            return self.inner.rename(&plain_path(from), &plain_path(to)).await;
+        };
+        let suffix = entry.compressor.suffix();
+        let renamed = self
+            .inner
+            .rename(&with_suffix(from, suffix), &with_suffix(to, suffix))
+            .await?;
+        if renamed {
+            self.set_entry(from, None);
+            self.set_entry(to, Some(entry));
+        }
+        Ok(renamed)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let stored = self.inner.list().await?;
+        let index = self.index.lock().unwrap();
+        let mut files = BTreeMap::new();
+        let mut leftovers = BTreeMap::new();
+        // Sorted, a file stored as it is comes before the compressed ones of the same name,
+        // which only replace it when the index says they're the current one
+        for (stored_path, metadata) in stored {
+            if stored_path.starts_with(METADATA_DIR_NAME) {
+                continue;
+            }
+            let (original, compressor) = original_path(&stored_path);
+            let Some(compressor) = compressor else {
+                files.entry(original).or_insert(metadata);
+                continue;
+            };
+            match index.get(&original) {
+                Some(entry) if entry.compressor == compressor => {
+                    files.insert(original, Self::listed_metadata(Some(*entry), metadata));
+                }
+                // Listed with the size it was stored with, so it's copied again
+                _ if !files.contains_key(&original) => {
+                    leftovers.insert(original.clone(), compressor);
+                    files.insert(original, metadata);
+                }
+                _ => {}
+            }
+        }
+        drop(index);
+        *self.leftovers.lock().unwrap() = leftovers;
+        Ok(files)
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        self.inner.list_dirs().await
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        let entry = self.entry(relative_path);
+        Ok(self
+            .inner
+            .metadata(&self.stored_path(relative_path))
+            .await?
+            .map(|metadata| Self::listed_metadata(entry, metadata)))
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.save_index().await?;
+        self.inner.flush().await
+    }
+
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        self.inner.prune(retention, dry_run).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{backend::LocalBackend, copy::CopyOptions};
+
+    #[tokio::test]
+    async fn names_of_compressed_and_plain_files_cant_collide() {
+        let dir = tempfile::tempdir().unwrap();
+        let (work, backup_dir) = (dir.path().join("work"), dir.path().join("backup"));
+        std::fs::create_dir_all(&work).unwrap();
+        std::fs::create_dir_all(&backup_dir).unwrap();
+        let inner = Arc::new(LocalBackend::new(&backup_dir, CopyOptions::default()).unwrap());
+        let compression = "zstd".parse().ok();
+        let backend = CompressedBackend::wrap(inner.clone(), compression)
+            .await
+            .unwrap();
+
+        let contents = [("x", "compressed ".repeat(100)), ("x.zst", "plain".into())];
+        for (name, contents) in &contents {
+            std::fs::write(work.join(name), contents).unwrap();
+            backend
+                .put(Path::new(name), &work.join(name))
+                .await
+                .unwrap();
+        }
+        // Without waiting for a flush
+        let reopened = CompressedBackend::wrap(inner, compression).await.unwrap();
+        let listed = reopened.list().await.unwrap();
+        let sizes = listed
+            .iter()
+            .map(|(path, metadata)| (path.to_str().unwrap(), metadata.size));
+        assert_eq!(sizes.collect::<Vec<_>>(), [("x", 1100), ("x.zst", 5)]);
+        for (name, contents) in &contents {
+            let restored = dir.path().join("restored");
+            reopened.get(Path::new(name), &restored).await.unwrap();
+            assert_eq!(&std::fs::read_to_string(&restored).unwrap(), contents);
+        }
+
+        // What a put that didn't get to save the index leaves behind
+        std::fs::rename(backup_dir.join("x.zst"), backup_dir.join("y.zst")).unwrap();
+        let listed = reopened.list().await.unwrap();
+        assert_ne!(listed[Path::new("y")].size, 1100);
+        reopened.delete(Path::new("y")).await.unwrap();
+        assert!(!backup_dir.join("y.zst").exists());
+    }
+}
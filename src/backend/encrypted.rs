@@ -0,0 +1,764 @@
+//! Encrypting everything stored in another backend, for backups kept somewhere that can't be
+//! fully trusted.
+//!
+//! Contents are encrypted with ChaCha20-Poly1305 in chunks of [`CHUNK_SIZE`], like age's
+//! STREAM: every file gets a key of its own, derived with HKDF-SHA256 from a random salt, and
+//! each chunk is sealed with its number and whether it's the last one as the nonce, and the
+//! file's path as the associated data. So chunks can't be reordered, dropped or cut off, nor
+//! files swapped for one another, without the file failing to decrypt, which is also why
+//! files can't be moved inside the backup and are stored again instead. Each stored file is
+//! [`MAGIC`], the salt, and the chunks with their tags. Names can be encrypted too, one path
+//! component at a time and deterministically, so the same name always ends up the same: the
+//! nonce is an HMAC-SHA256 of the name, as in SIV, and stored along with it. That makes them
+//! [`MAX_PLAIN_NAME_LEN`] bytes at most, for what's stored to fit in 255.
+//!
+//! The key comes from a key file or a passphrase, stretched with PBKDF2-HMAC-SHA256. The salt
+//! and a check value that tells a wrong key apart from a damaged file are kept unencrypted
+//! in `.evilmount/encryption.json` in the backup. All of the cryptography is `ring`'s
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use ring::{
+    aead::{self, Aad, LessSafeKey, Nonce, UnboundKey, CHACHA20_POLY1305, NONCE_LEN},
+    hkdf::{self, HKDF_SHA256},
+    hmac::{self, HMAC_SHA256},
+    pbkdf2,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    io::{self, Read, Write},
+    num::NonZeroU32,
+    path::{Component, Path, PathBuf},
+    sync::Arc,
+};
+use tokio::fs;
+use tracing::debug;
+
+use super::{temp_file, Backend, FileMetadata};
+use crate::{
+    copy::temp_path_for,
+    meta::METADATA_DIR_NAME,
+    prune::{Pruned, Retention},
+};
+
+/// What every encrypted file starts with
+pub const MAGIC: &[u8; 8] = b"evilenc3";
+
+/// How much of a file is sealed at a time
+pub const CHUNK_SIZE: usize = 64 * 1024;
+
+/// The salt the key of a file is derived with
+const SALT_LEN: usize = 32;
+const HEADER_LEN: usize = MAGIC.len() + SALT_LEN;
+const TAG_LEN: usize = aead::MAX_TAG_LEN;
+
+/// The version of [`Params`] this writes, and the only one it reads
+const VERSION: u32 = 3;
+
+/// How many rounds of PBKDF2 new backups stretch passphrases with
+const PBKDF2_ROUNDS: u32 = 600_000;
+
+/// Key files have to be at least this long
+const MIN_KEY_FILE_LEN: usize = 32;
+
+/// The longest name that still fits into 255 bytes encrypted, with its nonce and tag in
+/// base32
+pub const MAX_PLAIN_NAME_LEN: usize = 255 * 5 / 8 - NONCE_LEN - TAG_LEN;
+
+/// How big a file of `stored` encrypted bytes was before it was encrypted
+pub fn plaintext_size(stored: u64) -> u64 {
+    let body = stored.saturating_sub(HEADER_LEN as u64);
+    let chunks = body.div_ceil((CHUNK_SIZE + TAG_LEN) as u64).max(1);
+    body.saturating_sub(chunks * TAG_LEN as u64)
+}
+
+/// Where the key of an encrypted backup comes from
+#[derive(Clone)]
+pub enum KeySource {
+    /// A file whose contents are the key, e.g. 32 bytes from `/dev/urandom`
+    KeyFile(PathBuf),
+    Passphrase(String),
+}
+
+impl fmt::Debug for KeySource {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::KeyFile(path) => f.debug_tuple("KeyFile").field(path).finish(),
+            Self::Passphrase(_) => f.write_str("Passphrase(..)"),
+        }
+    }
+}
+
+/// How a backup gets encrypted
+#[derive(Debug, Clone)]
+pub struct Encryption {
+    pub key: KeySource,
+    /// Also encrypt file and directory names. Only used when the backup is first encrypted,
+    /// after that it's whatever it was encrypted with
+    pub encrypt_names: bool,
+}
+
+/// What's needed to derive the key again, stored unencrypted in the backup
+#[derive(Debug, Serialize, Deserialize)]
+struct Params {
+    /// Backups from before there was a version can't be read anymore
+    #[serde(default)]
+    version: u32,
+    /// Hex, only for passphrases
+    salt: Option<String>,
+    rounds: u32,
+    encrypt_names: bool,
+    /// Hex, derived from the key
+    check: String,
+}
+
+fn params_path() -> PathBuf {
+    Path::new(METADATA_DIR_NAME).join("encryption.json")
+}
+
+/// How many bytes of key material HKDF should expand to
+struct KeyLen(usize);
+
+impl hkdf::KeyType for KeyLen {
+    fn len(&self) -> usize {
+        self.0
+    }
+}
+
+/// 32 bytes for `purpose`, expanded from `prk`
+fn expand(prk: &hkdf::Prk, purpose: &[u8]) -> [u8; 32] {
+    let mut key = [0; 32];
+    prk.expand(&[b"evil_mount ", purpose], KeyLen(key.len()))
+        .and_then(|okm| okm.fill(&mut key))
+        .expect("HKDF-SHA256 expands to 32 bytes");
+    key
+}
+
+/// The keys for each purpose, derived from the one that was given
+struct Keys {
+    /// The keys of files are expanded from this and their salt
+    contents: hkdf::Prk,
+    names: LessSafeKey,
+    /// Makes the nonces of names
+    name_nonces: hmac::Key,
+    check: [u8; 32],
+}
+
+impl Keys {
+    /// Derives every key from `master`, the contents of a key file or a stretched passphrase
+    fn derive(master: &[u8]) -> Self {
+        let prk = hkdf::Salt::new(HKDF_SHA256, b"evil_mount encryption").extract(master);
+        let names = UnboundKey::new(&CHACHA20_POLY1305, &expand(&prk, b"names"))
+            .expect("ChaCha20-Poly1305 takes 32 byte keys");
+        Self {
+            contents: hkdf::Prk::new_less_safe(HKDF_SHA256, &expand(&prk, b"contents")),
+            names: LessSafeKey::new(names),
+            name_nonces: hmac::Key::new(HMAC_SHA256, &expand(&prk, b"name nonces")),
+            check: expand(&prk, b"key check"),
+        }
+    }
+
+    /// The key of the file with `salt`
+    fn file_key(&self, salt: &[u8]) -> LessSafeKey {
+        let info = [&b"evil_mount file"[..], salt];
+        let okm = self
+            .contents
+            .expand(&info, &CHACHA20_POLY1305)
+            .expect("HKDF-SHA256 expands to a ChaCha20-Poly1305 key");
+        LessSafeKey::new(UnboundKey::from(okm))
+    }
+}
+
+/// The nonce of chunk `index` of a file: the index in big endian, then 1 for the last chunk
+fn chunk_nonce(index: u64, last: bool) -> Nonce {
+    let mut nonce = [0; NONCE_LEN];
+    nonce[3..11].copy_from_slice(&index.to_be_bytes());
+    nonce[11] = last as u8;
+    Nonce::assume_unique_for_key(nonce)
+}
+
+/// PBKDF2 with HMAC-SHA256, to 32 bytes
+fn stretch(passphrase: &[u8], salt: &[u8], rounds: u32) -> Result<[u8; 32]> {
+    let rounds =
+        NonZeroU32::new(rounds).ok_or_else(|| anyhow!("The encryption settings have 0 rounds"))?;
+    let mut key = [0; 32];
+    pbkdf2::derive(
+        pbkdf2::PBKDF2_HMAC_SHA256,
+        rounds,
+        salt,
+        passphrase,
+        &mut key,
+    );
+    Ok(key)
+}
+
+/// Salts and check values are 32 bytes, like hashes, so they're written out the same way
+fn to_hex(bytes: &[u8; 32]) -> String {
+    blake3::Hash::from_bytes(*bytes).to_hex().to_string()
+}
+
+fn from_hex(hex: &str) -> Result<[u8; 32]> {
+    Ok(*blake3::Hash::from_hex(hex)?.as_bytes())
+}
+
+/// Lowercase RFC 4648 base32 without padding, which survives case insensitive filesystems
+const BASE32: &[u8; 32] = b"abcdefghijklmnopqrstuvwxyz234567";
+
+fn to_base32(bytes: &[u8]) -> String {
+    let mut out = String::with_capacity(bytes.len().div_ceil(5) * 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for &byte in bytes {
+        buffer = (buffer << 8) | u32::from(byte);
+        bits += 8;
+        while bits >= 5 {
+            bits -= 5;
+            out.push(BASE32[(buffer >> bits) as usize & 31] as char);
+        }
+    }
+    if bits > 0 {
+        out.push(BASE32[(buffer << (5 - bits)) as usize & 31] as char);
+    }
+    out
+}
+
+fn from_base32(text: &str) -> Option<Vec<u8>> {
+    let mut out = Vec::with_capacity(text.len() * 5 / 8);
+    let (mut buffer, mut bits) = (0u32, 0);
+    for char in text.bytes() {
+        let value = BASE32.iter().position(|&digit| digit == char)? as u32;
+        buffer = (buffer << 5) | value;
+        bits += 5;
+        if bits >= 8 {
+            bits -= 8;
+            out.push((buffer >> bits) as u8);
+        }
+    }
+    Some(out)
+}
+
+/// Reads into all of `buf` unless the end of `reader` comes first. Returns how much it read
+fn read_full(reader: &mut impl Read, buf: &mut [u8]) -> io::Result<usize> {
+    let mut filled = 0;
+    while filled < buf.len() {
+        match reader.read(&mut buf[filled..]) {
+            Ok(0) => break,
+            Ok(read) => filled += read,
+            Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+    Ok(filled)
+}
+
+/// What the contents of the file at `relative_path` are sealed along with: its components
+/// joined with `/`, the same on every platform
+fn path_aad(relative_path: &Path) -> Vec<u8> {
+    let mut aad = Vec::new();
+    for component in relative_path.components() {
+        if !aad.is_empty() {
+            aad.push(b'/');
+        }
+        aad.extend_from_slice(component.as_os_str().as_encoded_bytes());
+    }
+    aad
+}
+
+/// Encrypts the file at `source` into `destination`, sealed with `aad`. This does blocking IO
+fn encrypt_file(keys: &Keys, aad: &[u8], source: &Path, destination: &Path) -> Result<()> {
+    let mut salt = [0; SALT_LEN];
+    getrandom::fill(&mut salt).map_err(|err| anyhow!("Error generating a salt: {err}"))?;
+    let key = keys.file_key(&salt);
+
+    let mut reader = std::fs::File::open(source)
+        .with_context(|| anyhow!("Error opening {}", source.display()))?;
+    let mut writer = io::BufWriter::new(std::fs::File::create(destination)?);
+    writer.write_all(MAGIC)?;
+    writer.write_all(&salt)?;
+
+    // Reading a chunk ahead tells whether the current one is the last
+    let mut chunk = Vec::with_capacity(CHUNK_SIZE + TAG_LEN);
+    chunk.resize(CHUNK_SIZE, 0);
+    let read = read_full(&mut reader, &mut chunk)?;
+    chunk.truncate(read);
+    let mut next = vec![0; CHUNK_SIZE];
+    for index in 0.. {
+        let read = match chunk.len() {
+            CHUNK_SIZE => read_full(&mut reader, &mut next)?,
+            _ => 0,
+        };
+        let last = read == 0;
+        key.seal_in_place_append_tag(chunk_nonce(index, last), Aad::from(aad), &mut chunk)
+            .map_err(|_| anyhow!("Error encrypting {}", source.display()))?;
+        writer.write_all(&chunk)?;
+        if last {
+            break;
+        }
+        chunk.clear();
+        chunk.extend_from_slice(&next[..read]);
+    }
+    writer.flush()?;
+    Ok(())
+}
+
+/// Decrypts the file at `source` into `destination`, which is only created if the file
+/// wasn't changed since it was encrypted with `aad`. This does blocking IO
+fn decrypt_file(keys: &Keys, aad: &[u8], source: &Path, destination: &Path) -> Result<()> {
+    let mut reader = io::BufReader::new(std::fs::File::open(source)?);
+    let mut header = [0; HEADER_LEN];
+    if read_full(&mut reader, &mut header)? < HEADER_LEN || header[..MAGIC.len()] != MAGIC[..] {
+        return Err(anyhow!("Not an encrypted file"));
+    }
+    let key = keys.file_key(&header[MAGIC.len()..]);
+
+    let tmp_path = temp_path_for(destination);
+    if let Some(parent) = destination.parent() {
+        std::fs::create_dir_all(parent)?;
+    }
+    let decrypted = (|| {
+        let damaged = || anyhow!("It was changed or damaged after it was encrypted");
+        let mut writer = io::BufWriter::new(std::fs::File::create(&tmp_path)?);
+        let mut chunk = vec![0; CHUNK_SIZE + TAG_LEN];
+        let mut read = read_full(&mut reader, &mut chunk)?;
+        let mut next = vec![0; CHUNK_SIZE + TAG_LEN];
+        for index in 0.. {
+            let next_read = match read {
+                len if len == chunk.len() => read_full(&mut reader, &mut next)?,
+                _ => 0,
+            };
+            let last = next_read == 0;
+            let plaintext = key
+                .open_in_place(chunk_nonce(index, last), Aad::from(aad), &mut chunk[..read])
+                .map_err(|_| damaged())?;
+            writer.write_all(plaintext)?;
+            if last {
+                break;
+            }
+            std::mem::swap(&mut chunk, &mut next);
+            read = next_read;
+        }
+        writer.flush()?;
+        drop(writer);
+        std::fs::rename(&tmp_path, destination)?;
+        Ok(())
+    })();
+    if decrypted.is_err() {
+        let _ = std::fs::remove_file(&tmp_path);
+    }
+    decrypted
+}
+
+/// The nonce `name` is encrypted with
+fn name_nonce(keys: &Keys, name: &str) -> [u8; NONCE_LEN] {
+    let tag = hmac::sign(&keys.name_nonces, name.as_bytes());
+    tag.as_ref()[..NONCE_LEN].try_into().unwrap()
+}
+
+/// Another backend with everything in it encrypted, see the module docs
+pub struct EncryptedBackend {
+    inner: Arc<dyn Backend>,
+    keys: Arc<Keys>,
+    encrypt_names: bool,
+}
+
+impl fmt::Debug for EncryptedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("EncryptedBackend")
+            .field("inner", &self.inner)
+            .field("encrypt_names", &self.encrypt_names)
+            .finish_non_exhaustive()
+    }
+}
+
+impl EncryptedBackend {
+    /// Encrypts what's stored in `inner`. The first time, this saves what's needed to
+    /// derive the key again into it. After that, it fails if `encryption` has the wrong key
+    pub async fn open(inner: Arc<dyn Backend>, encryption: &Encryption) -> Result<Self> {
+        let params = match inner.metadata(&params_path()).await? {
+            Some(_) => {
+                let tmp_path = temp_file();
+                let params = async {
+                    inner.get(&params_path(), &tmp_path).await?;
+                    let contents = fs::read(&tmp_path).await?;
+                    Ok::<Params, anyhow::Error>(serde_json::from_slice(&contents)?)
+                }
+                .await;
+                let _ = fs::remove_file(&tmp_path).await;
+                Some(params.with_context(|| anyhow!("Error reading the encryption settings"))?)
+            }
+            None => None,
+        };
+        if let Some(params) = &params {
+            if params.version != VERSION {
+                return Err(anyhow!(
+                    "{inner} was encrypted in an earlier format, which can't be read anymore"
+                ));
+            }
+        }
+
+        let salt = match &params {
+            Some(params) => params.salt.as_deref().map(from_hex).transpose()?,
+            None => {
+                let mut salt = [0; 32];
+                getrandom::fill(&mut salt)
+                    .map_err(|err| anyhow!("Error generating a salt: {err}"))?;
+                Some(salt)
+            }
+        };
+        let rounds = params
+            .as_ref()
+            .map_or(PBKDF2_ROUNDS, |params| params.rounds);
+        let master: Vec<u8> = match &encryption.key {
+            KeySource::KeyFile(path) => {
+                let contents = fs::read(path)
+                    .await
+                    .with_context(|| anyhow!("Error reading the key file {}", path.display()))?;
+                if contents.len() < MIN_KEY_FILE_LEN {
+                    return Err(anyhow!(
+                        "The key file {} is shorter than {MIN_KEY_FILE_LEN} bytes",
+                        path.display()
+                    ));
+                }
+                contents
+            }
+            KeySource::Passphrase(passphrase) => {
+                let salt =
+                    salt.ok_or_else(|| anyhow!("The backup was encrypted with a key file"))?;
+                let passphrase = passphrase.clone();
+                tokio::task::spawn_blocking(move || stretch(passphrase.as_bytes(), &salt, rounds))
+                    .await??
+                    .to_vec()
+            }
+        };
+        let keys = Keys::derive(&master);
+
+        let encrypt_names = match params {
+            Some(params) => {
+                if from_hex(&params.check)? != keys.check {
+                    return Err(anyhow!("Wrong key or passphrase for {inner}"));
+                }
+                params.encrypt_names
+            }
+            None => {
+                let params = Params {
+                    version: VERSION,
+                    salt: match encryption.key {
+                        KeySource::Passphrase(_) => salt.as_ref().map(to_hex),
+                        KeySource::KeyFile(_) => None,
+                    },
+                    rounds,
+                    encrypt_names: encryption.encrypt_names,
+                    check: to_hex(&keys.check),
+                };
+                let tmp_path = temp_file();
+                let saved = async {
+                    fs::write(&tmp_path, serde_json::to_vec_pretty(&params)?).await?;
+                    inner.put(&params_path(), &tmp_path).await
+                }
+                .await;
+                let _ = fs::remove_file(&tmp_path).await;
+                saved.with_context(|| anyhow!("Error saving the encryption settings"))?;
+                debug!(backup = %inner, "Encrypting a new backup");
+                encryption.encrypt_names
+            }
+        };
+
+        Ok(Self {
+            inner,
+            keys: Arc::new(keys),
+            encrypt_names,
+        })
+    }
+
+    /// The path `relative_path` is stored under in the inner backend
+    fn encrypt_path(&self, relative_path: &Path) -> Result<PathBuf> {
+        if !self.encrypt_names {
+            return Ok(relative_path.to_path_buf());
+        }
+        relative_path
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => {
+                    let name = name.to_str().ok_or_else(|| {
+                        anyhow!(
+                            "Can't encrypt the non UTF-8 name {}",
+                            relative_path.display()
+                        )
+                    })?;
+                    self.encrypt_name(name)
+                }
+                _ => Err(anyhow!(
+                    "{} is not a relative path",
+                    relative_path.display()
+                )),
+            })
+            .collect()
+    }
+
+    /// The nonce, the encrypted name and its tag. The nonce is the start of an HMAC of the
+    /// name, so it only repeats for the same name
+    fn encrypt_name(&self, name: &str) -> Result<String> {
+        if name.len() > MAX_PLAIN_NAME_LEN {
+            return Err(anyhow!(
+                "Can't encrypt the name {name}, which is longer than {MAX_PLAIN_NAME_LEN} bytes"
+            ));
+        }
+        let nonce = name_nonce(&self.keys, name);
+        let mut encrypted = name.as_bytes().to_vec();
+        self.keys
+            .names
+            .seal_in_place_append_tag(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut encrypted,
+            )
+            .expect("Names are short enough to encrypt");
+        Ok(to_base32(&[&nonce[..], &encrypted].concat()))
+    }
+
+    /// The original path of `stored_path` in the inner backend, if it's one we stored
+    fn decrypt_path(&self, stored_path: &Path) -> Option<PathBuf> {
+        if !self.encrypt_names {
+            return Some(stored_path.to_path_buf());
+        }
+        stored_path
+            .components()
+            .map(|component| match component {
+                Component::Normal(name) => self.decrypt_name(name.to_str()?),
+                _ => None,
+            })
+            .collect()
+    }
+
+    fn decrypt_name(&self, stored_name: &str) -> Option<String> {
+        let mut decoded = from_base32(stored_name)?;
+        if decoded.len() < NONCE_LEN + TAG_LEN {
+            return None;
+        }
+        let nonce: [u8; NONCE_LEN] = decoded[..NONCE_LEN].try_into().ok()?;
+        let name = self
+            .keys
+            .names
+            .open_in_place(
+                Nonce::assume_unique_for_key(nonce),
+                Aad::empty(),
+                &mut decoded[NONCE_LEN..],
+            )
+            .ok()?;
+        let name = String::from_utf8(name.to_vec()).ok()?;
+        // Every name has a single way of being stored
+        (name_nonce(&self.keys, &name) == nonce).then_some(name)
+    }
+
+    fn plaintext_metadata(metadata: FileMetadata) -> FileMetadata {
+        FileMetadata {
+            size: plaintext_size(metadata.size),
+            ..metadata
+        }
+    }
+}
+
+impl fmt::Display for EncryptedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{} (encrypted)", self.inner)
+    }
+}
+
+#[async_trait]
+impl Backend for EncryptedBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let stored_path = self.encrypt_path(relative_path)?;
+        let tmp_path = temp_file();
+        let keys = self.keys.clone();
+        let encrypted = tokio::task::spawn_blocking({
+            let (source, tmp_path) = (source.to_path_buf(), tmp_path.clone());
+            let aad = path_aad(relative_path);
+            move || encrypt_file(&keys, &aad, &source, &tmp_path)
+        })
+        .await?;
+        let stored = match encrypted {
+            Ok(()) => self.inner.put(&stored_path, &tmp_path).await,
+            Err(err) => Err(err),
+        };
+        let _ = fs::remove_file(&tmp_path).await;
+        stored
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let stored_path = self.encrypt_path(relative_path)?;
+        let tmp_path = temp_file();
+        let decrypted = match self.inner.get(&stored_path, &tmp_path).await {
+            Ok(()) => {
+                let keys = self.keys.clone();
+                let (tmp_path, destination) = (tmp_path.clone(), destination.to_path_buf());
+                let aad = path_aad(relative_path);
+                tokio::task::spawn_blocking(move || {
+                    decrypt_file(&keys, &aad, &tmp_path, &destination)
+                })
+                .await?
+            }
+            Err(err) => Err(err),
+        };
+        let _ = fs::remove_file(&tmp_path).await;
+        decrypted.with_context(|| anyhow!("Error decrypting {}", relative_path.display()))
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        self.inner.delete(&self.encrypt_path(relative_path)?).await
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner
+            .create_dir(&self.encrypt_path(relative_path)?)
+            .await
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        self.inner
+            .delete_dir(&self.encrypt_path(relative_path)?)
+            .await
+    }
+
+    async fn rename(&self, _from: &Path, _to: &Path) -> Result<bool> {
+        // Files are sealed with their path, so a moved one wouldn't decrypt any more
+        Ok(false)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        Ok(self
+            .inner
+            .list()
+            .await?
+            .into_iter()
+            .filter(|(stored_path, _)| !stored_path.starts_with(METADATA_DIR_NAME))
+            .filter_map(|(stored_path, metadata)| {
+                let relative_path = self.decrypt_path(&stored_path);
+                if relative_path.is_none() {
+                    debug!(path = %stored_path.display(), "Skipping a file we didn't encrypt");
+                }
+                Some((relative_path?, Self::plaintext_metadata(metadata)))
+            })
+            .collect())
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        Ok(self.inner.list_dirs().await?.map(|dirs| {
+            dirs.iter()
+                .filter(|stored_path| !stored_path.starts_with(METADATA_DIR_NAME))
+                .filter_map(|stored_path| self.decrypt_path(stored_path))
+                .collect()
+        }))
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        Ok(self
+            .inner
+            .metadata(&self.encrypt_path(relative_path)?)
+            .await?
+            .map(Self::plaintext_metadata))
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.inner.flush().await
+    }
+
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        self.inner.prune(retention, dry_run).await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// Encrypts `len` bytes as `a/b`, returning them and the stored file
+    fn encrypted(dir: &Path, keys: &Keys, len: usize) -> (Vec<u8>, Vec<u8>) {
+        let contents: Vec<u8> = (0..len).map(|i| (i % 251) as u8).collect();
+        std::fs::write(dir.join("plain"), &contents).unwrap();
+        let aad = path_aad(Path::new("a/b"));
+        encrypt_file(keys, &aad, &dir.join("plain"), &dir.join("stored")).unwrap();
+        (contents, std::fs::read(dir.join("stored")).unwrap())
+    }
+
+    /// Decrypts `stored` as `path`
+    fn decrypted(dir: &Path, keys: &Keys, path: &str, stored: &[u8]) -> Result<Vec<u8>> {
+        std::fs::write(dir.join("stored"), stored).unwrap();
+        let aad = path_aad(Path::new(path));
+        decrypt_file(keys, &aad, &dir.join("stored"), &dir.join("restored"))?;
+        Ok(std::fs::read(dir.join("restored"))?)
+    }
+
+    #[test]
+    fn files_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Keys::derive(&[7; 32]);
+        for len in [
+            0,
+            1,
+            CHUNK_SIZE - 1,
+            CHUNK_SIZE,
+            CHUNK_SIZE + 1,
+            3 * CHUNK_SIZE,
+        ] {
+            let (contents, stored) = encrypted(dir.path(), &keys, len);
+            assert_eq!(plaintext_size(stored.len() as u64), len as u64);
+            assert_eq!(
+                decrypted(dir.path(), &keys, "a/b", &stored).unwrap(),
+                contents
+            );
+        }
+    }
+
+    #[test]
+    fn the_longest_names_fit_encrypted() {
+        let stored = NONCE_LEN + MAX_PLAIN_NAME_LEN + TAG_LEN;
+        assert_eq!(to_base32(&vec![0; stored]).len(), 255);
+        assert!(to_base32(&vec![0; stored + 1]).len() > 255);
+    }
+
+    #[test]
+    fn changed_files_dont_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Keys::derive(&[7; 32]);
+        let (_, stored) = encrypted(dir.path(), &keys, 2 * CHUNK_SIZE + 10);
+
+        for offset in [
+            MAGIC.len(),
+            HEADER_LEN,
+            HEADER_LEN + CHUNK_SIZE + 20,
+            stored.len() - 1,
+        ] {
+            let mut tampered = stored.clone();
+            tampered[offset] ^= 1;
+            assert!(decrypted(dir.path(), &keys, "a/b", &tampered).is_err());
+        }
+        // Swapped for another file
+        assert!(decrypted(dir.path(), &keys, "a/c", &stored).is_err());
+        assert!(decrypted(dir.path(), &Keys::derive(&[8; 32]), "a/b", &stored).is_err());
+        assert!(!dir.path().join("restored").exists());
+    }
+
+    #[test]
+    fn cut_off_files_dont_decrypt() {
+        let dir = tempfile::tempdir().unwrap();
+        let keys = Keys::derive(&[7; 32]);
+        let (_, stored) = encrypted(dir.path(), &keys, 2 * CHUNK_SIZE);
+
+        let chunk = CHUNK_SIZE + TAG_LEN;
+        // Dropping whole chunks leaves one that wasn't sealed as the last
+        for len in [
+            HEADER_LEN,
+            HEADER_LEN + chunk,
+            HEADER_LEN + chunk + 1,
+            stored.len() - 1,
+        ] {
+            assert!(decrypted(dir.path(), &keys, "a/b", &stored[..len]).is_err());
+        }
+        assert!(!dir.path().join("restored").exists());
+    }
+}
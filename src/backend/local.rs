@@ -0,0 +1,192 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+};
+use tracing::debug;
+
+use super::{Backend, FileMetadata};
+use crate::{
+    copy::{copy_file, prune_empty_parents, remove_and_prune, CopyOptions, VerifyWrites},
+    filter::{walk_dirs_in, walk_files_in, IgnoreSet, Symlinks},
+    hash::hash_file,
+    vfs::{RealFs, Vfs},
+};
+
+/// A backup in a directory on the local filesystem
+#[derive(Debug, Clone)]
+pub struct LocalBackend {
+    root: PathBuf,
+    copy: CopyOptions,
+}
+
+impl LocalBackend {
+    pub fn new(root: impl Into<PathBuf>, copy: CopyOptions) -> Result<Self> {
+        let root = root.into();
+        if !root.is_dir() {
+            return Err(anyhow!("backup_dir must be a directory!"));
+        }
+
+        // Watcher events use real paths, so everything is compared against those
+        let root = root
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", root.display()))?;
+
+        Ok(Self { root, copy })
+    }
+
+    pub fn root(&self) -> &Path {
+        &self.root
+    }
+}
+
+/// Every file in `dir` that isn't ignored, keyed by its path relative to `dir`. Links that
+/// aren't followed are listed with their own metadata. This does blocking IO
+pub fn list_files(
+    dir: &Path,
+    ignore: &IgnoreSet,
+    symlinks: Symlinks,
+) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+    let mut files = BTreeMap::new();
+    for file_info in walk_files_in(dir, dir, ignore, symlinks) {
+        let metadata = match file_info.metadata() {
+            Ok(metadata) => metadata,
+            // Deleted since the walk found it
+            Err(err)
+                if err
+                    .io_error()
+                    .is_some_and(|err| err.kind() == std::io::ErrorKind::NotFound) =>
+            {
+                debug!(path = %file_info.path().display(), "Vanished while listing");
+                continue;
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let relative_path = file_info.path().strip_prefix(dir)?.to_path_buf();
+        files.insert(
+            relative_path,
+            FileMetadata {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            },
+        );
+    }
+    ignore.walk_errors().check()?;
+    Ok(files)
+}
+
+/// Every directory in `dir` that isn't ignored, empty or not, relative to `dir`. This does
+/// blocking IO
+pub fn list_dirs(dir: &Path, ignore: &IgnoreSet, symlinks: Symlinks) -> Result<BTreeSet<PathBuf>> {
+    walk_dirs_in(dir, dir, ignore, symlinks)
+        .map(|file_info| Ok(file_info.path().strip_prefix(dir)?.to_path_buf()))
+        .collect()
+}
+
+impl fmt::Display for LocalBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.root.display())
+    }
+}
+
+#[async_trait]
+impl Backend for LocalBackend {
+    fn local_dir(&self) -> Option<&Path> {
+        Some(&self.root)
+    }
+
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        self.put_verified(relative_path, source).await.map(|_| ())
+    }
+
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        copy_file(source, &self.root.join(relative_path), &self.copy).await
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        // Only writes into the backup happen in the background and get verified
+        let copy = CopyOptions {
+            bwlimit: None,
+            verify_writes: VerifyWrites::Off,
+            ..self.copy.clone()
+        };
+        copy_file(&self.root.join(relative_path), destination, &copy)
+            .await
+            .map(|_| ())
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        remove_and_prune(&self.root.join(relative_path), &self.root).await
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        let path = self.root.join(relative_path);
+        tokio::fs::create_dir_all(&path)
+            .await
+            .with_context(|| anyhow!("Error creating {}", path.display()))
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        let path = self.root.join(relative_path);
+        match tokio::fs::remove_dir(&path).await {
+            Err(err)
+                if !matches!(
+                    err.kind(),
+                    std::io::ErrorKind::NotFound | std::io::ErrorKind::DirectoryNotEmpty
+                ) =>
+            {
+                Err(err).with_context(|| anyhow!("Error removing {}", path.display()))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let (from, to) = (self.root.join(from), self.root.join(to));
+        RealFs.rename(&from, &to).await?;
+        prune_empty_parents(&from, &self.root).await;
+        Ok(true)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let root = self.root.clone();
+        let symlinks = self.copy.symlinks;
+        tokio::task::spawn_blocking(move || list_files(&root, &IgnoreSet::default(), symlinks))
+            .await?
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        let root = self.root.clone();
+        let symlinks = self.copy.symlinks;
+        tokio::task::spawn_blocking(move || list_dirs(&root, &IgnoreSet::default(), symlinks))
+            .await?
+            .map(Some)
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        match tokio::fs::metadata(self.root.join(relative_path)).await {
+            Ok(metadata) => Ok(Some(FileMetadata {
+                size: metadata.len(),
+                modified: metadata.modified().ok(),
+            })),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err.into()),
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        match tokio::fs::metadata(&self.root).await {
+            Ok(metadata) if metadata.is_dir() => Ok(()),
+            Ok(_) => Err(anyhow!("{} is not a directory", self.root.display())),
+            Err(err) => Err(err).with_context(|| anyhow!("Error reading {}", self.root.display())),
+        }
+    }
+
+    async fn hash(&self, relative_path: &Path) -> Result<Hash> {
+        let path = self.root.join(relative_path);
+        tokio::task::spawn_blocking(move || hash_file(&path)).await?
+    }
+}
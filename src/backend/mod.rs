@@ -0,0 +1,426 @@
+//! Places that backups can be stored. Every backend stores files under paths relative to
+//! the root of the backup, mirroring the layout of work_dir
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc,
+    },
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    io::{AsyncRead, AsyncWriteExt},
+};
+
+use crate::{
+    copy::{temp_path_for, CopyOptions},
+    exit::{Exit, ExitWith},
+    hash::hash_file,
+    meta::TEMP_SUFFIX,
+    prune::{Pruned, Retention},
+};
+
+#[cfg(unix)]
+mod archive;
+mod audited;
+mod cas;
+mod compressed;
+mod encrypted;
+mod local;
+mod s3;
+mod sanitized;
+#[cfg(unix)]
+mod sftp;
+mod webdav;
+
+#[cfg(unix)]
+pub use archive::{ArchiveBackend, ArchiveFormat, REWRITE_EVERY};
+pub use audited::AuditedBackend;
+pub use cas::CasBackend;
+pub use compressed::{CompressedBackend, Compression, Compressor};
+pub use encrypted::{EncryptedBackend, Encryption, KeySource};
+pub use local::{list_dirs, list_files, LocalBackend};
+pub use s3::{S3Backend, MULTIPART_THRESHOLD};
+pub use sanitized::{SanitizeNames, SanitizedBackend};
+#[cfg(unix)]
+pub use sftp::SftpBackend;
+pub use webdav::{DavCredentials, WebDavBackend};
+
+/// The size and modification time of a file stored in a backend
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub struct FileMetadata {
+    pub size: u64,
+    /// Not every backend can tell us this, or keep the original modification time
+    pub modified: Option<SystemTime>,
+}
+
+/// Storage for the files in backup_dir
+#[async_trait]
+pub trait Backend: fmt::Debug + fmt::Display + Send + Sync {
+    /// The directory the backup lives in, if it's on the local filesystem. Snapshots and
+    /// the hash cache need one
+    fn local_dir(&self) -> Option<&Path> {
+        None
+    }
+
+    /// Stores the file at `source` as `relative_path`, replacing it if it already exists
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()>;
+
+    /// Like [`Backend::put`], also returning the hash of what was read from `source` if the
+    /// copy was checked against it, see [`CopyOptions::verify_writes`]
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        self.put(relative_path, source).await.map(|()| None)
+    }
+
+    /// Copies `relative_path` out of the backup to `destination`
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()>;
+
+    /// Removes `relative_path` from the backup. Removing a file that doesn't exist is fine
+    async fn delete(&self, relative_path: &Path) -> Result<()>;
+
+    /// Creates the directory `relative_path` in the backup, along with the directories above
+    /// it. Backends that only store files, like S3, have nothing to create
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        let _ = relative_path;
+        Ok(())
+    }
+
+    /// Removes the directory `relative_path` from the backup if it's empty. A directory that
+    /// isn't empty is kept
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        let _ = relative_path;
+        Ok(())
+    }
+
+    /// Moves `from` to `to` inside the backup, replacing `to` if it exists. Returns false if
+    /// this backend can't, and the file has to be stored again instead
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let _ = (from, to);
+        Ok(false)
+    }
+
+    /// Every file in the backup, keyed by its relative path
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>>;
+
+    /// Every directory in the backup, empty ones included, or `None` if this backend only
+    /// stores files
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        Ok(None)
+    }
+
+    /// The metadata of `relative_path`, or `None` if it isn't in the backup
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>>;
+
+    /// Fails if the backup can't be reached right now. By default this looks up a file that
+    /// doesn't need to exist
+    async fn check(&self) -> Result<()> {
+        self.metadata(Path::new(crate::meta::METADATA_DIR_NAME))
+            .await
+            .map(|_| ())
+    }
+
+    /// Writes out whatever was stored or deleted since the last call, for backends that hold
+    /// on to changes until the end of each sync cycle
+    async fn flush(&self) -> Result<()> {
+        Ok(())
+    }
+
+    /// Removes the snapshots `retention` doesn't keep from backends that keep their own, or
+    /// returns `None` for the others. With `dry_run` nothing is removed
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        let _ = (retention, dry_run);
+        Ok(None)
+    }
+
+    /// Hashes the contents of `relative_path`. By default the file is downloaded first
+    async fn hash(&self, relative_path: &Path) -> Result<Hash> {
+        let tmp_path = temp_file();
+        let hashed = match self.get(relative_path, &tmp_path).await {
+            Ok(()) => {
+                let tmp_path = tmp_path.clone();
+                tokio::task::spawn_blocking(move || hash_file(&tmp_path)).await?
+            }
+            Err(err) => Err(err),
+        };
+        let _ = fs::remove_file(&tmp_path).await;
+        hashed
+    }
+}
+
+/// How files are laid out in a local backup_dir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub enum BackupFormat {
+    /// The same layout as work_dir
+    #[default]
+    Mirror,
+    /// Every distinct file stored once under the hash of its contents, see [`CasBackend`]
+    Cas,
+}
+
+impl FromStr for BackupFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mirror" => Ok(Self::Mirror),
+            "cas" => Ok(Self::Cas),
+            _ => Err(format!("unknown backup format {s}, expected mirror or cas")),
+        }
+    }
+}
+
+impl TryFrom<String> for BackupFormat {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for BackupFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mirror => "mirror",
+            Self::Cas => "cas",
+        })
+    }
+}
+
+impl From<BackupFormat> for String {
+    fn from(format: BackupFormat) -> Self {
+        format.to_string()
+    }
+}
+
+/// Whether an SSH connection trusts a host it doesn't know yet
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Hash, Serialize, Deserialize)]
+pub enum HostKeyCheck {
+    /// Only connect to hosts in the known hosts file, with the key recorded there
+    #[default]
+    #[serde(rename = "strict")]
+    Strict,
+    /// Add unknown hosts to the known hosts file, but refuse hosts whose key changed
+    #[serde(rename = "accept-new")]
+    AcceptNew,
+    /// Connect to any host, whatever its key. Only for networks where nobody can pretend to
+    /// be the backup server
+    #[serde(rename = "off")]
+    Off,
+}
+
+impl FromStr for HostKeyCheck {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "strict" => Ok(Self::Strict),
+            "accept-new" => Ok(Self::AcceptNew),
+            "off" => Ok(Self::Off),
+            _ => Err(format!(
+                "unknown host key check {s}, expected strict, accept-new or off"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for HostKeyCheck {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Strict => "strict",
+            Self::AcceptNew => "accept-new",
+            Self::Off => "off",
+        })
+    }
+}
+
+/// How `sftp://` and `ssh://` backups connect. Anything not set here comes from
+/// `~/.ssh/config` as usual
+#[derive(Debug, Clone, Default, PartialEq, Eq, Hash)]
+pub struct SshOptions {
+    pub host_key_check: HostKeyCheck,
+    /// Used instead of `~/.ssh/known_hosts`
+    pub known_hosts_file: Option<PathBuf>,
+    /// The private key to log in with
+    pub key_file: Option<PathBuf>,
+}
+
+/// Whether `location` names a remote backend rather than a local directory
+pub fn is_remote(location: &str) -> bool {
+    ["sftp://", "ssh://", "s3://", "dav://", "dav+http://"]
+        .iter()
+        .any(|scheme| location.starts_with(scheme))
+}
+
+/// Opens the backend for `location`, which is either a local directory, an archive like
+/// `backup.tar.gz` or `backup.zip`, `sftp://[user@]host[:port]/path` or `s3://bucket/prefix`.
+/// `ssh://` works the same as `sftp://`, connecting with `ssh`, and `dav://host/path` is a
+/// WebDAV collection. Without a `format`, a local directory is opened in the one it already
+/// has
+pub async fn open(
+    location: &str,
+    copy: &CopyOptions,
+    ssh: &SshOptions,
+    dav: &DavCredentials,
+    format: Option<BackupFormat>,
+) -> Result<Arc<dyn Backend>> {
+    let only_local =
+        || anyhow!("Content addressed backups are only supported in local directories");
+    if format == Some(BackupFormat::Cas) && is_remote(location) {
+        return Err(only_local());
+    }
+
+    if let Some(rest) = location.strip_prefix("s3://") {
+        let (bucket, prefix) = rest.split_once('/').unwrap_or((rest, ""));
+        if bucket.is_empty() {
+            return Err(anyhow!("Missing bucket name in {location}"));
+        }
+        let backend = S3Backend::new(bucket, prefix)?.with_bwlimit(copy.bwlimit.clone());
+        return Ok(Arc::new(backend));
+    }
+
+    if location.starts_with("dav://") || location.starts_with("dav+http://") {
+        let backend = WebDavBackend::connect(location, dav)
+            .await
+            .with_context(|| anyhow!("Error connecting to {location}"))?;
+        return Ok(Arc::new(backend.with_bwlimit(copy.bwlimit.clone())));
+    }
+
+    let ssh_location = location
+        .strip_prefix("sftp://")
+        .or_else(|| location.strip_prefix("ssh://"));
+    if let Some(rest) = ssh_location {
+        #[cfg(unix)]
+        {
+            let (host, path) = rest.split_once('/').unwrap_or((rest, ""));
+            if host.is_empty() {
+                return Err(anyhow!("Missing host in {location}"));
+            }
+            let backend = SftpBackend::connect(host, Path::new("/").join(path), ssh)
+                .await
+                .with_context(|| anyhow!("Error connecting to {location}"))?;
+            return Ok(Arc::new(backend.with_bwlimit(copy.bwlimit.clone())));
+        }
+        #[cfg(not(unix))]
+        {
+            let _ = (rest, ssh);
+            return Err(anyhow!("SFTP backups are only supported on unix"));
+        }
+    }
+
+    #[cfg(unix)]
+    if let Some(archive_format) = ArchiveFormat::of(Path::new(location)) {
+        if format == Some(BackupFormat::Cas) {
+            return Err(only_local());
+        }
+        let backend = ArchiveBackend::open(Path::new(location), archive_format, copy.clone())
+            .await
+            .with_context(|| anyhow!("Error opening {location}"))?;
+        return Ok(Arc::new(backend));
+    }
+
+    let is_cas = CasBackend::is_cas(Path::new(location));
+    match format {
+        Some(BackupFormat::Cas) => {}
+        Some(BackupFormat::Mirror) if is_cas => {
+            return Err(anyhow!(
+                "{location} holds a content addressed backup, not a mirror of work_dir"
+            ))
+        }
+        _ if is_cas => {}
+        _ => return Ok(Arc::new(LocalBackend::new(location, copy.clone())?)),
+    }
+    let backend = CasBackend::open(location, copy.clone())
+        .await
+        .with_context(|| anyhow!("Error opening {location}"))?;
+    Ok(Arc::new(backend))
+}
+
+/// How the backend of every pair is opened, and the backends it's wrapped in
+#[derive(Debug, Clone, Default)]
+pub struct BackendOptions {
+    pub format: Option<BackupFormat>,
+    pub ssh: SshOptions,
+    pub dav: DavCredentials,
+    pub sanitize_names: Option<SanitizeNames>,
+    pub encryption: Option<Encryption>,
+    pub compress: Option<Compression>,
+    pub audit_log: bool,
+}
+
+/// Opens the backend for `location` like [`open`], wrapped in the ones that sanitize names,
+/// encrypt, compress and keep the audit log, as far as `options` asks for them
+pub async fn open_wrapped(
+    location: &str,
+    copy: &CopyOptions,
+    options: &BackendOptions,
+) -> Result<Arc<dyn Backend>> {
+    let backend = open(location, copy, &options.ssh, &options.dav, options.format).await?;
+    // Innermost, so the names that end up on disk are the ones checked
+    let backend = SanitizedBackend::wrap(backend, options.sanitize_names).await?;
+    let backend: Arc<dyn Backend> = match &options.encryption {
+        Some(encryption) => Arc::new(EncryptedBackend::open(backend, encryption).await?),
+        None => backend,
+    };
+    // Files are compressed before they're encrypted, since encrypted data doesn't compress
+    let backend = CompressedBackend::wrap(backend, options.compress).await?;
+    // Outermost, so the paths logged are the ones in work_dir
+    AuditedBackend::wrap(backend, options.audit_log).exit_with(Exit::Config)
+}
+
+/// A fresh path in the temp directory, for files on their way in or out of a backend
+pub(crate) fn temp_file() -> PathBuf {
+    static TEMP_FILES: AtomicUsize = AtomicUsize::new(0);
+
+    std::env::temp_dir().join(format!(
+        "evil_mount-{}-{}{TEMP_SUFFIX}",
+        std::process::id(),
+        TEMP_FILES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+/// Writes a downloaded file to `destination` through a temporary file, like local copies
+async fn save_download(
+    destination: &Path,
+    mut contents: impl AsyncRead + Unpin + Send,
+) -> Result<()> {
+    let tmp_path = temp_path_for(destination);
+    if let Some(parent) = destination.parent() {
+        fs::create_dir_all(parent).await?;
+    }
+
+    let written = async {
+        let mut file = fs::File::create(&tmp_path).await?;
+        tokio::io::copy(&mut contents, &mut file).await?;
+        file.flush().await?;
+        fs::rename(&tmp_path, destination).await
+    }
+    .await;
+    if let Err(err) = written {
+        let _ = fs::remove_file(&tmp_path).await;
+        return Err(err).with_context(|| anyhow!("Error writing {}", destination.display()));
+    }
+
+    Ok(())
+}
+
+/// Turns a relative path into a `/` separated key for remote backends
+fn remote_key(relative_path: &Path) -> String {
+    relative_path
+        .components()
+        .filter_map(|component| match component {
+            Component::Normal(part) => Some(part.to_string_lossy()),
+            _ => None,
+        })
+        .collect::<Vec<_>>()
+        .join("/")
+}
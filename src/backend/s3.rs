@@ -0,0 +1,483 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use reqwest::{header, Body, Client, StatusCode};
+use rusty_s3::{
+    actions::{CreateMultipartUpload, ListObjectsV2},
+    Bucket, Credentials, S3Action, UrlStyle,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::io::{AsyncReadExt, AsyncSeekExt};
+use tokio_util::io::{ReaderStream, StreamReader};
+use tracing::{debug, info, warn};
+use url::Url;
+
+use super::{remote_key, save_download, Backend, FileMetadata};
+use crate::throttle::Throttle;
+
+/// How long signed request URLs stay valid
+const SIGNATURE_EXPIRY: Duration = Duration::from_secs(60 * 60);
+
+/// Files at least this big are uploaded in parts, which are resumed after an interruption
+/// instead of starting over
+pub const MULTIPART_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// How big the parts of a multipart upload are, unless the file needs bigger ones to fit in
+/// the [`MAX_PARTS`] S3 allows
+const PART_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How many parts a multipart upload can have at most
+const MAX_PARTS: u64 = 10_000;
+
+/// A multipart upload that is under way, as recorded in its journal after every part.
+/// Resuming needs the same file, unchanged, and the same part size
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Journal {
+    key: String,
+    upload_id: String,
+    size: u64,
+    modified: Option<SystemTime>,
+    part_size: u64,
+    /// The ETags of the parts uploaded so far, in order
+    etags: Vec<String>,
+}
+
+/// A backup stored under a prefix in an S3 bucket. Credentials and the region are read from
+/// the usual `AWS_*` environment variables, and `AWS_ENDPOINT_URL` selects an S3 compatible
+/// service instead of AWS. Files of at least [`MULTIPART_THRESHOLD`] bytes are uploaded in
+/// parts, with a journal that lets a later run pick up where a killed one left off
+#[derive(Debug)]
+pub struct S3Backend {
+    bucket: Bucket,
+    credentials: Credentials,
+    /// Empty, or ends with a `/`
+    prefix: String,
+    client: Client,
+    /// Limits how fast files are uploaded
+    bwlimit: Option<Arc<Throttle>>,
+}
+
+impl S3Backend {
+    pub fn new(bucket: &str, prefix: &str) -> Result<Self> {
+        let credentials = Credentials::from_env().ok_or_else(|| {
+            anyhow!("AWS_ACCESS_KEY_ID and AWS_SECRET_ACCESS_KEY must be set for S3 backups")
+        })?;
+        let region = std::env::var("AWS_REGION")
+            .or_else(|_| std::env::var("AWS_DEFAULT_REGION"))
+            .unwrap_or_else(|_| "us-east-1".to_string());
+
+        // Self hosted services usually don't support virtual host style buckets
+        let (endpoint, url_style) = match std::env::var("AWS_ENDPOINT_URL") {
+            Ok(endpoint) => (endpoint, UrlStyle::Path),
+            Err(_) => (
+                format!("https://s3.{region}.amazonaws.com"),
+                UrlStyle::VirtualHost,
+            ),
+        };
+        let endpoint =
+            Url::parse(&endpoint).with_context(|| anyhow!("Invalid S3 endpoint {endpoint}"))?;
+        let bucket = Bucket::new(endpoint, url_style, bucket.to_string(), region)
+            .map_err(|err| anyhow!("Invalid S3 bucket {bucket}: {err}"))?;
+
+        let prefix = prefix.trim_matches('/');
+        let prefix = match prefix.is_empty() {
+            true => String::new(),
+            false => format!("{prefix}/"),
+        };
+
+        Ok(Self {
+            bucket,
+            credentials,
+            prefix,
+            client: Client::new(),
+            bwlimit: None,
+        })
+    }
+
+    /// Uploads no faster than `bwlimit` allows
+    pub fn with_bwlimit(mut self, bwlimit: Option<Arc<Throttle>>) -> Self {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    fn key_for(&self, relative_path: &Path) -> String {
+        format!("{}{}", self.prefix, remote_key(relative_path))
+    }
+
+    /// Where the journal of a multipart upload to `key` is kept, in
+    /// `$XDG_STATE_HOME/evil_mount/uploads` or `~/.local/state/evil_mount/uploads`
+    fn journal_path(&self, key: &str) -> Option<PathBuf> {
+        let state_dir = std::env::var_os("XDG_STATE_HOME")
+            .map(PathBuf::from)
+            .or_else(|| {
+                std::env::var_os("HOME").map(|home| PathBuf::from(home).join(".local/state"))
+            })
+            .or_else(|| std::env::var_os("LOCALAPPDATA").map(PathBuf::from))?;
+        let id = blake3::hash(format!("{}/{key}", self.bucket.name()).as_bytes());
+        Some(
+            state_dir
+                .join("evil_mount/uploads")
+                .join(format!("{}.json", id.to_hex())),
+        )
+    }
+
+    /// The journal of an unfinished upload to `key`, if there is one
+    async fn load_journal(&self, key: &str) -> Option<Journal> {
+        let contents = tokio::fs::read(self.journal_path(key)?).await.ok()?;
+        serde_json::from_slice(&contents)
+            .ok()
+            .filter(|journal: &Journal| journal.key == key)
+    }
+
+    /// Records `journal` through a temporary file, so it's never half written
+    async fn save_journal(&self, journal: &Journal) -> Result<()> {
+        let Some(path) = self.journal_path(&journal.key) else {
+            return Ok(());
+        };
+        if let Some(parent) = path.parent() {
+            tokio::fs::create_dir_all(parent).await?;
+        }
+        let tmp_path = path.with_extension("json.tmp");
+        tokio::fs::write(&tmp_path, serde_json::to_vec(journal)?).await?;
+        tokio::fs::rename(&tmp_path, &path)
+            .await
+            .with_context(|| anyhow!("Error writing {}", path.display()))
+    }
+
+    async fn remove_journal(&self, key: &str) {
+        if let Some(path) = self.journal_path(key) {
+            let _ = tokio::fs::remove_file(path).await;
+        }
+    }
+
+    /// Gives up on the upload in `journal`, so S3 doesn't keep its parts around
+    async fn abort(&self, journal: &Journal) {
+        let url = self
+            .bucket
+            .abort_multipart_upload(Some(&self.credentials), &journal.key, &journal.upload_id)
+            .sign(SIGNATURE_EXPIRY);
+        if let Err(err) = self
+            .client
+            .delete(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+        {
+            debug!(
+                key = journal.key,
+                "Error aborting an unfinished upload: {err}"
+            );
+        }
+        self.remove_journal(&journal.key).await;
+    }
+
+    /// Uploads `source` to `key` in parts, continuing the upload a journal recorded for the
+    /// same file if there is one
+    async fn put_multipart(&self, key: &str, source: &Path) -> Result<()> {
+        let mut file = tokio::fs::File::open(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?;
+        let metadata = file.metadata().await?;
+        let size = metadata.len();
+        let part_size = PART_SIZE.max(size.div_ceil(MAX_PARTS));
+        let modified = metadata.modified().ok();
+
+        let mut journal = match self.load_journal(key).await {
+            Some(journal)
+                if journal.size == size
+                    && journal.modified == modified
+                    && journal.part_size == part_size =>
+            {
+                info!(
+                    key,
+                    "Resuming the upload after {} of {} parts",
+                    journal.etags.len(),
+                    size.div_ceil(part_size)
+                );
+                journal
+            }
+            journal => {
+                // The file changed since, so what was uploaded of it is no use
+                if let Some(journal) = journal {
+                    self.abort(&journal).await;
+                }
+                let action = self
+                    .bucket
+                    .create_multipart_upload(Some(&self.credentials), key);
+                let body = self
+                    .client
+                    .post(action.sign(SIGNATURE_EXPIRY))
+                    .send()
+                    .await
+                    .and_then(|response| response.error_for_status())
+                    .with_context(|| anyhow!("Error starting the upload of {key}"))?
+                    .text()
+                    .await?;
+                let created = CreateMultipartUpload::parse_response(&body)
+                    .map_err(|err| anyhow!("Error parsing the upload of {key}: {err}"))?;
+                let journal = Journal {
+                    key: key.to_string(),
+                    upload_id: created.upload_id().to_string(),
+                    size,
+                    modified,
+                    part_size,
+                    etags: Vec::new(),
+                };
+                self.save_journal(&journal).await?;
+                journal
+            }
+        };
+
+        let mut buf = vec![0; part_size as usize];
+        while (journal.etags.len() as u64) * part_size < size {
+            let offset = journal.etags.len() as u64 * part_size;
+            let len = part_size.min(size - offset) as usize;
+            file.seek(SeekFrom::Start(offset)).await?;
+            file.read_exact(&mut buf[..len]).await?;
+            if let Some(bwlimit) = &self.bwlimit {
+                bwlimit.take(len).await;
+            }
+
+            let number = journal.etags.len() as u16 + 1;
+            let url = self
+                .bucket
+                .upload_part(Some(&self.credentials), key, number, &journal.upload_id)
+                .sign(SIGNATURE_EXPIRY);
+            let response = self
+                .client
+                .put(url)
+                .header(header::CONTENT_LENGTH, len)
+                .body(buf[..len].to_vec())
+                .send()
+                .await
+                .with_context(|| anyhow!("Error uploading part {number} of {key}"))?;
+            // The upload expired or someone aborted it, so the next try starts over
+            if response.status() == StatusCode::NOT_FOUND {
+                self.remove_journal(key).await;
+                return Err(anyhow!("The upload of {key} is gone, starting it over"));
+            }
+            let response = response
+                .error_for_status()
+                .with_context(|| anyhow!("Error uploading part {number} of {key}"))?;
+            let etag = response
+                .headers()
+                .get(header::ETAG)
+                .and_then(|etag| etag.to_str().ok())
+                .ok_or_else(|| anyhow!("No ETag for part {number} of {key}"))?;
+            journal.etags.push(etag.to_string());
+            self.save_journal(&journal).await?;
+        }
+
+        let action = self.bucket.complete_multipart_upload(
+            Some(&self.credentials),
+            key,
+            &journal.upload_id,
+            journal.etags.iter().map(String::as_str),
+        );
+        let url = action.sign(SIGNATURE_EXPIRY);
+        let body = self
+            .client
+            .post(url)
+            .body(action.body())
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| anyhow!("Error finishing the upload of {key}"))?
+            .text()
+            .await?;
+        // Completing can fail after the response started, with an error in the body
+        if body.contains("<Error>") {
+            warn!(key, "Error finishing the upload: {body}");
+            self.abort(&journal).await;
+            return Err(anyhow!("Error finishing the upload of {key}"));
+        }
+        self.remove_journal(key).await;
+
+        Ok(())
+    }
+}
+
+impl fmt::Display for S3Backend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "s3://{}/{}", self.bucket.name(), self.prefix)
+    }
+}
+
+#[async_trait]
+impl Backend for S3Backend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let key = self.key_for(relative_path);
+        let size = tokio::fs::metadata(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?
+            .len();
+        if size >= MULTIPART_THRESHOLD {
+            return self.put_multipart(&key, source).await;
+        }
+
+        let url = self
+            .bucket
+            .put_object(Some(&self.credentials), &key)
+            .sign(SIGNATURE_EXPIRY);
+
+        let file = tokio::fs::File::open(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?;
+        let bwlimit = self.bwlimit.clone();
+        let body = ReaderStream::new(file).then(move |chunk| {
+            let bwlimit = bwlimit.clone();
+            async move {
+                if let (Ok(chunk), Some(bwlimit)) = (&chunk, bwlimit) {
+                    bwlimit.take(chunk.len()).await;
+                }
+                chunk
+            }
+        });
+
+        self.client
+            .put(url)
+            .header(header::CONTENT_LENGTH, size)
+            .body(Body::wrap_stream(body))
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| anyhow!("Error uploading {key}"))?;
+
+        Ok(())
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let key = self.key_for(relative_path);
+        let url = self
+            .bucket
+            .get_object(Some(&self.credentials), &key)
+            .sign(SIGNATURE_EXPIRY);
+
+        let response = self
+            .client
+            .get(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| anyhow!("Error downloading {key}"))?;
+        let contents = StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
+
+        save_download(destination, contents).await
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let key = self.key_for(relative_path);
+        let url = self
+            .bucket
+            .delete_object(Some(&self.credentials), &key)
+            .sign(SIGNATURE_EXPIRY);
+
+        // An upload that was interrupted doesn't have to be resumed any more
+        if let Some(journal) = self.load_journal(&key).await {
+            self.abort(&journal).await;
+        }
+
+        // S3 doesn't complain about deleting something that isn't there
+        self.client
+            .delete(url)
+            .send()
+            .await
+            .and_then(|response| response.error_for_status())
+            .with_context(|| anyhow!("Error deleting {key}"))?;
+
+        Ok(())
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let mut files = BTreeMap::new();
+        let mut continuation_token = None;
+
+        loop {
+            let mut action = self.bucket.list_objects_v2(Some(&self.credentials));
+            if !self.prefix.is_empty() {
+                action.with_prefix(self.prefix.as_str());
+            }
+            if let Some(token) = &continuation_token {
+                action.with_continuation_token(String::clone(token));
+            }
+            let url = action.sign(SIGNATURE_EXPIRY);
+
+            let body = self
+                .client
+                .get(url)
+                .send()
+                .await
+                .and_then(|response| response.error_for_status())
+                .with_context(|| anyhow!("Error listing {self}"))?
+                .text()
+                .await?;
+            let listing = ListObjectsV2::parse_response(&body)
+                .map_err(|err| anyhow!("Error parsing the listing of {self}: {err}"))?;
+
+            for object in listing.contents {
+                let Some(relative_path) = object.key.strip_prefix(&self.prefix) else {
+                    continue;
+                };
+                // Folders created by other tools show up as empty keys ending in `/`
+                if relative_path.is_empty() || relative_path.ends_with('/') {
+                    continue;
+                }
+                files.insert(
+                    PathBuf::from(relative_path),
+                    FileMetadata {
+                        size: object.size,
+                        modified: chrono::DateTime::parse_from_rfc3339(&object.last_modified)
+                            .ok()
+                            .map(SystemTime::from),
+                    },
+                );
+            }
+
+            match listing.next_continuation_token {
+                Some(token) => continuation_token = Some(token),
+                None => return Ok(files),
+            }
+        }
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        let key = self.key_for(relative_path);
+        let url = self
+            .bucket
+            .head_object(Some(&self.credentials), &key)
+            .sign(SIGNATURE_EXPIRY);
+
+        let response = self
+            .client
+            .head(url)
+            .send()
+            .await
+            .with_context(|| anyhow!("Error checking {key}"))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(None);
+        }
+        let response = response
+            .error_for_status()
+            .with_context(|| anyhow!("Error checking {key}"))?;
+
+        let headers = response.headers();
+        let size = headers
+            .get(header::CONTENT_LENGTH)
+            .and_then(|size| size.to_str().ok()?.parse().ok())
+            .unwrap_or_default();
+        let modified = headers
+            .get(header::LAST_MODIFIED)
+            .and_then(|modified| chrono::DateTime::parse_from_rfc2822(modified.to_str().ok()?).ok())
+            .map(SystemTime::from);
+
+        Ok(Some(FileMetadata { size, modified }))
+    }
+}
@@ -0,0 +1,428 @@
+//! Names that Windows, FAT and NTFS can't store: ones with `<>:"\|?*` or control characters
+//! in them, ones ending in a dot or a space, device names like `CON` or `NUL`, and ones
+//! that don't leave room in 255 bytes for the suffix of copies in progress. With
+//! [`SanitizeNames`], files and directories are stored under a name that works there
+//! instead, and every such name is kept in `.evilmount/names.json` along with the original
+//! one, so listing the backup and restoring it give the original names back. Backups with
+//! sanitized names in them are translated whether this is set or not
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt::{self, Write},
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+};
+use tokio::fs;
+
+use super::{temp_file, Backend, FileMetadata};
+use crate::{
+    meta::{METADATA_DIR_NAME, TEMP_SUFFIX},
+    prune::{Pruned, Retention},
+};
+
+/// The longest name, in bytes, that every filesystem can store
+const MAX_NAME_LEN: usize = 255;
+
+/// Copies are written next to their destination under its name with [`TEMP_SUFFIX`], which
+/// has to fit too
+const MAX_STORED_LEN: usize = MAX_NAME_LEN - TEMP_SUFFIX.len();
+
+const INVALID_CHARS: &[char] = &['<', '>', ':', '"', '\\', '|', '?', '*'];
+
+const DEVICE_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8",
+    "COM9", "LPT1", "LPT2", "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// What happens to files whose names can't be stored on Windows, FAT or NTFS
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SanitizeNames {
+    /// Percent-encode what can't be stored, like `a%3Ab` for `a:b`
+    #[default]
+    #[serde(rename = "encode")]
+    Encode,
+    /// Replace what can't be stored with `_`
+    #[serde(rename = "replace")]
+    Replace,
+    /// Leave the file out of the backup, reporting it as an error
+    #[serde(rename = "skip")]
+    Skip,
+}
+
+impl FromStr for SanitizeNames {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "encode" => Ok(Self::Encode),
+            "replace" => Ok(Self::Replace),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "unknown name sanitization {s}, expected encode, replace or skip"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SanitizeNames {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Encode => "encode",
+            Self::Replace => "replace",
+            Self::Skip => "skip",
+        })
+    }
+}
+
+impl SanitizeNames {
+    /// `c` in a name that can store it
+    fn escape(self, c: char, sanitized: &mut String) {
+        match self {
+            Self::Replace => sanitized.push('_'),
+            _ => {
+                let mut bytes = [0; 4];
+                for byte in c.encode_utf8(&mut bytes).bytes() {
+                    let _ = write!(sanitized, "%{byte:02X}");
+                }
+            }
+        }
+    }
+
+    /// `name` as it can be stored, `None` if it can be stored as it is. Names that aren't
+    /// valid UTF-8 are left alone
+    fn name(self, name: &str) -> Option<String> {
+        let mut sanitized = String::with_capacity(name.len());
+        // Windows drops dots and spaces at the end of a name
+        let kept = name.trim_end_matches(['.', ' ']);
+        for c in kept.chars() {
+            match INVALID_CHARS.contains(&c) || c < ' ' {
+                true => self.escape(c, &mut sanitized),
+                false => sanitized.push(c),
+            }
+        }
+        for c in name[kept.len()..].chars() {
+            self.escape(c, &mut sanitized);
+        }
+
+        let stem = sanitized.split('.').next().unwrap_or_default();
+        if DEVICE_NAMES
+            .iter()
+            .any(|device| stem.eq_ignore_ascii_case(device))
+        {
+            sanitized = match self {
+                Self::Replace => format!("_{sanitized}"),
+                _ => {
+                    let mut chars = sanitized.chars();
+                    let mut escaped = String::new();
+                    self.escape(chars.next().unwrap_or_default(), &mut escaped);
+                    escaped + chars.as_str()
+                }
+            };
+        }
+
+        if sanitized.len() > MAX_STORED_LEN {
+            sanitized = shortened(&sanitized, name);
+        }
+        (sanitized != name).then_some(sanitized)
+    }
+
+    /// Where `relative_path` can be stored, `None` if it can't with [`SanitizeNames::Skip`]
+    fn path(self, relative_path: &Path) -> Option<PathBuf> {
+        let mut sanitized = PathBuf::new();
+        for component in relative_path.components() {
+            let sanitized_name = match component {
+                Component::Normal(name) => name.to_str().and_then(|name| self.name(name)),
+                _ => None,
+            };
+            match sanitized_name {
+                Some(_) if self == Self::Skip => return None,
+                Some(name) => sanitized.push(name),
+                None => sanitized.push(component),
+            }
+        }
+        Some(sanitized)
+    }
+}
+
+/// `sanitized` cut down to [`MAX_STORED_LEN`], keeping its extension. A hash of the `original`
+/// name keeps names that only differ after the cut apart
+fn shortened(sanitized: &str, original: &str) -> String {
+    let hash = blake3::hash(original.as_bytes()).to_hex();
+    let (stem, extension) = match sanitized.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.len() <= 16 => {
+            (stem, format!(".{extension}"))
+        }
+        _ => (sanitized, String::new()),
+    };
+    let suffix = format!("~{}{extension}", &hash[..8]);
+    let mut end = MAX_STORED_LEN - suffix.len();
+    while !stem.is_char_boundary(end) {
+        end -= 1;
+    }
+    // Not in the middle of a percent-encoded character
+    if let Some(escape) = stem.as_bytes()[end.saturating_sub(2)..end]
+        .iter()
+        .position(|&byte| byte == b'%')
+    {
+        end = end.saturating_sub(2) + escape;
+    }
+    format!("{}{suffix}", &stem[..end])
+}
+
+fn index_path() -> PathBuf {
+    Path::new(METADATA_DIR_NAME).join("names.json")
+}
+
+/// Another backend with names that can't be stored everywhere sanitized, see the module
+/// docs
+#[derive(Debug)]
+pub struct SanitizedBackend {
+    inner: Arc<dyn Backend>,
+    /// `None` when restoring a backup that doesn't get new sanitized names any more
+    sanitize: Option<SanitizeNames>,
+    /// The stored paths of files and directories whose names were sanitized
+    index: Mutex<BTreeMap<PathBuf, PathBuf>>,
+    /// Whether the index changed since it was saved
+    dirty: AtomicBool,
+}
+
+impl SanitizedBackend {
+    /// Sanitizes the names stored in `inner` with `sanitize`. Backups that have sanitized
+    /// names in them are translated even without one, and `inner` is only returned as it is
+    /// when there's nothing to do
+    pub async fn wrap(
+        inner: Arc<dyn Backend>,
+        sanitize: Option<SanitizeNames>,
+    ) -> Result<Arc<dyn Backend>> {
+        let index = match inner.metadata(&index_path()).await? {
+            Some(_) => {
+                let tmp_path = temp_file();
+                let index = async {
+                    inner.get(&index_path(), &tmp_path).await?;
+                    let contents = fs::read(&tmp_path).await?;
+                    Ok::<_, anyhow::Error>(serde_json::from_slice(&contents)?)
+                }
+                .await;
+                let _ = fs::remove_file(&tmp_path).await;
+                index.with_context(|| anyhow!("Error reading the index of sanitized names"))?
+            }
+            None if sanitize.is_none() => return Ok(inner),
+            None => BTreeMap::new(),
+        };
+        Ok(Arc::new(Self {
+            inner,
+            sanitize,
+            index: Mutex::new(index),
+            dirty: AtomicBool::new(false),
+        }))
+    }
+
+    /// Where `relative_path` is stored in the inner backend, `None` if it can't be
+    fn stored_path(&self, relative_path: &Path) -> Option<PathBuf> {
+        if relative_path.starts_with(METADATA_DIR_NAME) {
+            return Some(relative_path.to_path_buf());
+        }
+        if let Some(stored_path) = self.index.lock().unwrap().get(relative_path) {
+            return Some(stored_path.clone());
+        }
+        match self.sanitize {
+            Some(sanitize) => sanitize.path(relative_path),
+            None => Some(relative_path.to_path_buf()),
+        }
+    }
+
+    /// [`SanitizedBackend::stored_path`], failing for a path that can't be stored
+    fn required_path(&self, relative_path: &Path) -> Result<PathBuf> {
+        self.stored_path(relative_path).ok_or_else(|| {
+            anyhow!(
+                "{} has a name that can't be stored on Windows, FAT or NTFS, leaving it out of \
+                 {self}",
+                relative_path.display()
+            )
+        })
+    }
+
+    /// Remembers that `relative_path` is stored as `stored_path`, and so are the
+    /// directories it's in
+    fn record(&self, relative_path: &Path, stored_path: &Path) {
+        if relative_path == stored_path {
+            return;
+        }
+        let mut index = self.index.lock().unwrap();
+        for (original, stored) in relative_path.ancestors().zip(stored_path.ancestors()) {
+            if original != stored {
+                index.insert(original.to_path_buf(), stored.to_path_buf());
+            }
+        }
+        self.dirty.store(true, Ordering::Relaxed);
+    }
+
+    fn forget(&self, relative_path: &Path) {
+        if self.index.lock().unwrap().remove(relative_path).is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// The original paths of the stored paths in the index
+    fn originals(&self) -> HashMap<PathBuf, PathBuf> {
+        self.index
+            .lock()
+            .unwrap()
+            .iter()
+            .map(|(original, stored)| (stored.clone(), original.clone()))
+            .collect()
+    }
+
+    async fn save_index(&self) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let contents = serde_json::to_vec(&*self.index.lock().unwrap())?;
+        let tmp_path = temp_file();
+        let saved = async {
+            fs::write(&tmp_path, contents).await?;
+            self.inner.put(&index_path(), &tmp_path).await
+        }
+        .await;
+        let _ = fs::remove_file(&tmp_path).await;
+        if saved.is_err() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        saved.with_context(|| anyhow!("Error saving the index of sanitized names"))
+    }
+}
+
+impl fmt::Display for SanitizedBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        self.inner.fmt(f)
+    }
+}
+
+#[async_trait]
+impl Backend for SanitizedBackend {
+    fn local_dir(&self) -> Option<&Path> {
+        self.inner.local_dir()
+    }
+
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let stored_path = self.required_path(relative_path)?;
+        self.inner.put(&stored_path, source).await?;
+        self.record(relative_path, &stored_path);
+        Ok(())
+    }
+
+    async fn put_verified(&self, relative_path: &Path, source: &Path) -> Result<Option<Hash>> {
+        let stored_path = self.required_path(relative_path)?;
+        let hash = self.inner.put_verified(&stored_path, source).await?;
+        self.record(relative_path, &stored_path);
+        Ok(hash)
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let stored_path = self.required_path(relative_path)?;
+        self.inner.get(&stored_path, destination).await
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        // Never stored
+        let Some(stored_path) = self.stored_path(relative_path) else {
+            return Ok(());
+        };
+        self.inner.delete(&stored_path).await?;
+        self.forget(relative_path);
+        Ok(())
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        let stored_path = self.required_path(relative_path)?;
+        self.inner.create_dir(&stored_path).await?;
+        self.record(relative_path, &stored_path);
+        Ok(())
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        let Some(stored_path) = self.stored_path(relative_path) else {
+            return Ok(());
+        };
+        self.inner.delete_dir(&stored_path).await?;
+        self.forget(relative_path);
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let (Some(stored_from), Some(stored_to)) = (self.stored_path(from), self.stored_path(to))
+        else {
+            return Ok(false);
+        };
+        let renamed = self.inner.rename(&stored_from, &stored_to).await?;
+        if renamed {
+            self.forget(from);
+            self.record(to, &stored_to);
+        }
+        Ok(renamed)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let stored = self.inner.list().await?;
+        let originals = self.originals();
+        Ok(stored
+            .into_iter()
+            .filter(|(stored_path, _)| !stored_path.starts_with(METADATA_DIR_NAME))
+            .map(
+                |(stored_path, metadata)| match originals.get(&stored_path) {
+                    Some(original) => (original.clone(), metadata),
+                    None => (stored_path, metadata),
+                },
+            )
+            .collect())
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        let Some(stored) = self.inner.list_dirs().await? else {
+            return Ok(None);
+        };
+        let originals = self.originals();
+        Ok(Some(
+            stored
+                .into_iter()
+                .map(|stored_path| match originals.get(&stored_path) {
+                    Some(original) => original.clone(),
+                    None => stored_path,
+                })
+                .collect(),
+        ))
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        match self.stored_path(relative_path) {
+            Some(stored_path) => self.inner.metadata(&stored_path).await,
+            None => Ok(None),
+        }
+    }
+
+    async fn check(&self) -> Result<()> {
+        self.inner.check().await
+    }
+
+    async fn flush(&self) -> Result<()> {
+        self.save_index().await?;
+        self.inner.flush().await
+    }
+
+    async fn prune(&self, retention: Retention, dry_run: bool) -> Result<Option<Pruned>> {
+        self.inner.prune(retention, dry_run).await
+    }
+
+    async fn hash(&self, relative_path: &Path) -> Result<Hash> {
+        let stored_path = self.required_path(relative_path)?;
+        self.inner.hash(&stored_path).await
+    }
+}
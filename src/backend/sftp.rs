@@ -0,0 +1,489 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::{future::BoxFuture, TryStreamExt};
+use openssh::{KnownHosts, SessionBuilder};
+use openssh_sftp_client::{
+    error::SftpErrorKind, file::TokioCompatFile, Error as SftpError, Sftp, SftpOptions,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap},
+    fmt, io,
+    path::{Path, PathBuf},
+    sync::{Arc, LazyLock, Mutex, Weak},
+    time::Duration,
+};
+use tokio::{io::AsyncWriteExt, sync::RwLock};
+use tracing::warn;
+
+use super::{save_download, Backend, FileMetadata, HostKeyCheck, SshOptions};
+use crate::{
+    copy::temp_path_for,
+    throttle::{copy_throttled, Throttle},
+};
+
+/// How often a lost connection is made again before giving up on what it was doing
+const RECONNECTS: u32 = 3;
+
+/// How long to wait before the first reconnect, doubled for every one after it
+const RECONNECT_DELAY: Duration = Duration::from_secs(1);
+
+/// A backup in a directory on another machine, reached over SFTP. Connects through the
+/// system `ssh`, so keys, agents and `~/.ssh/config` all work as usual. Backups on the same
+/// host share one connection, which is made again when it's lost
+pub struct SftpBackend {
+    host: String,
+    root: PathBuf,
+    connection: Arc<Connection>,
+    /// Limits how fast files are uploaded
+    bwlimit: Option<Arc<Throttle>>,
+}
+
+impl fmt::Debug for SftpBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SftpBackend")
+            .field("host", &self.host)
+            .field("root", &self.root)
+            .finish_non_exhaustive()
+    }
+}
+
+/// An SFTP session with a host, shared by every backup on it
+struct Connection {
+    host: String,
+    options: SshOptions,
+    /// `None` once the session was lost, until it's made again
+    sftp: RwLock<Option<Arc<Sftp>>>,
+}
+
+/// The connections in use, by host and options, so backups on the same host share one
+type Connections = LazyLock<Mutex<HashMap<(String, SshOptions), Weak<Connection>>>>;
+
+impl Connection {
+    /// The connection to `host`, shared with other backups if there already is one
+    async fn open(host: &str, options: &SshOptions) -> Result<Arc<Self>> {
+        static CONNECTIONS: Connections = LazyLock::new(Mutex::default);
+
+        let key = (host.to_string(), options.clone());
+        let shared = CONNECTIONS
+            .lock()
+            .unwrap()
+            .get(&key)
+            .and_then(Weak::upgrade);
+        if let Some(connection) = shared {
+            return Ok(connection);
+        }
+
+        let connection = Arc::new(Self {
+            host: host.to_string(),
+            options: options.clone(),
+            sftp: RwLock::new(None),
+        });
+        connection.get().await?;
+        // Another pair might have connected in the meantime, that's only a wasted session
+        CONNECTIONS
+            .lock()
+            .unwrap()
+            .insert(key, Arc::downgrade(&connection));
+        Ok(connection)
+    }
+
+    /// The current session, connecting first if there is none
+    async fn get(&self) -> Result<Arc<Sftp>> {
+        if let Some(sftp) = &*self.sftp.read().await {
+            return Ok(sftp.clone());
+        }
+
+        let mut current = self.sftp.write().await;
+        if let Some(sftp) = &*current {
+            return Ok(sftp.clone());
+        }
+        let mut builder = SessionBuilder::default();
+        builder.known_hosts_check(match self.options.host_key_check {
+            HostKeyCheck::Strict => KnownHosts::Strict,
+            HostKeyCheck::AcceptNew => KnownHosts::Add,
+            HostKeyCheck::Off => KnownHosts::Accept,
+        });
+        if let Some(known_hosts_file) = &self.options.known_hosts_file {
+            builder.user_known_hosts_file(known_hosts_file);
+        }
+        if let Some(key_file) = &self.options.key_file {
+            builder.keyfile(key_file);
+        }
+        let session = builder
+            .connect_mux(format!("ssh://{}", self.host))
+            .await
+            .with_context(|| anyhow!("Error connecting to {}", self.host))?;
+        let sftp = Arc::new(Sftp::from_session(session, SftpOptions::default()).await?);
+        *current = Some(sftp.clone());
+        Ok(sftp)
+    }
+
+    /// Forgets `sftp` after it was lost, unless it was already replaced
+    async fn lost(&self, sftp: &Arc<Sftp>) {
+        let mut current = self.sftp.write().await;
+        if current
+            .as_ref()
+            .is_some_and(|current| Arc::ptr_eq(current, sftp))
+        {
+            *current = None;
+        }
+    }
+}
+
+/// Whether `err` came from losing the connection, rather than from the server refusing
+fn is_disconnect(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        if let Some(err) = cause.downcast_ref::<SftpError>() {
+            return matches!(
+                err,
+                SftpError::IOError(_)
+                    | SftpError::BackgroundTaskFailure(_)
+                    | SftpError::AwaitableError(_)
+                    | SftpError::RecursiveErrors(_)
+                    | SftpError::RecursiveErrors3(_)
+                    | SftpError::SftpServerFailure(_)
+            );
+        }
+        cause.downcast_ref::<io::Error>().is_some_and(|err| {
+            // Uploads and downloads wrap the errors of the session in IO errors
+            let inner = err
+                .get_ref()
+                .and_then(|inner| inner.downcast_ref::<SftpError>());
+            inner.is_some_and(|inner| !matches!(inner, SftpError::SftpError(..)))
+                || matches!(
+                    err.kind(),
+                    io::ErrorKind::BrokenPipe
+                        | io::ErrorKind::ConnectionReset
+                        | io::ErrorKind::ConnectionAborted
+                        | io::ErrorKind::UnexpectedEof
+                )
+        })
+    })
+}
+
+impl SftpBackend {
+    /// Connects to `host`, which is `[user@]hostname[:port]`
+    pub async fn connect(host: &str, root: PathBuf, options: &SshOptions) -> Result<Self> {
+        let connection = Connection::open(host, options).await?;
+
+        let mut fs = connection.get().await?.fs();
+        if !fs
+            .metadata(&root)
+            .await?
+            .file_type()
+            .is_some_and(|file_type| file_type.is_dir())
+        {
+            return Err(anyhow!("backup_dir must be a directory!"));
+        }
+
+        Ok(Self {
+            host: host.to_string(),
+            root,
+            connection,
+            bwlimit: None,
+        })
+    }
+
+    /// Uploads no faster than `bwlimit` allows
+    pub fn with_bwlimit(mut self, bwlimit: Option<Arc<Throttle>>) -> Self {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    fn remote_path(&self, relative_path: &Path) -> PathBuf {
+        self.root.join(relative_path)
+    }
+
+    /// Runs `op` with the session, connecting again and running it again when the
+    /// connection is lost along the way
+    async fn reconnecting<'a, T>(
+        &'a self,
+        op: impl Fn(Arc<Sftp>) -> BoxFuture<'a, Result<T>>,
+    ) -> Result<T> {
+        let mut delay = RECONNECT_DELAY;
+        let mut reconnects = 0;
+        loop {
+            let sftp = match self.connection.get().await {
+                Err(err) if reconnects > 0 && reconnects < RECONNECTS => {
+                    warn!("Error reconnecting to {}: {err:#}", self.host);
+                    reconnects += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                    continue;
+                }
+                sftp => sftp?,
+            };
+            match op(sftp.clone()).await {
+                Err(err) if reconnects < RECONNECTS && is_disconnect(&err) => {
+                    warn!(
+                        "Lost the connection to {}, reconnecting in {}s: {err:#}",
+                        self.host,
+                        delay.as_secs()
+                    );
+                    self.connection.lost(&sftp).await;
+                    reconnects += 1;
+                    tokio::time::sleep(delay).await;
+                    delay *= 2;
+                }
+                done => return done,
+            }
+        }
+    }
+
+    /// Creates every directory above `path` that doesn't exist yet
+    async fn create_parents(&self, sftp: &Sftp, path: &Path) -> Result<()> {
+        let mut fs = sftp.fs();
+        let mut missing = Vec::new();
+        for dir in path.ancestors().skip(1) {
+            if dir == self.root || fs.metadata(dir).await.is_ok() {
+                break;
+            }
+            missing.push(dir);
+        }
+
+        for dir in missing.into_iter().rev() {
+            fs.create_dir(dir)
+                .await
+                .with_context(|| anyhow!("Error creating {}:{}", self.host, dir.display()))?;
+        }
+
+        Ok(())
+    }
+
+    /// Every file and every directory under the root, relative to it
+    async fn walk(
+        &self,
+        sftp: &Sftp,
+    ) -> Result<(BTreeMap<PathBuf, FileMetadata>, BTreeSet<PathBuf>)> {
+        let mut fs = sftp.fs();
+        let mut files = BTreeMap::new();
+        let mut found_dirs = BTreeSet::new();
+        let mut dirs = vec![PathBuf::new()];
+
+        while let Some(relative_dir) = dirs.pop() {
+            let dir = self.remote_path(&relative_dir);
+            let entries = fs
+                .open_dir(&dir)
+                .await
+                .with_context(|| anyhow!("Error listing {}:{}", self.host, dir.display()))?
+                .read_dir();
+            tokio::pin!(entries);
+
+            while let Some(entry) = entries.try_next().await? {
+                let file_name = entry.filename();
+                if file_name == Path::new(".") || file_name == Path::new("..") {
+                    continue;
+                }
+
+                let relative_path = relative_dir.join(file_name);
+                let metadata = entry.metadata();
+                match entry.file_type() {
+                    Some(file_type) if file_type.is_dir() => {
+                        found_dirs.insert(relative_path.clone());
+                        dirs.push(relative_path);
+                    }
+                    Some(file_type) if file_type.is_file() => {
+                        files.insert(
+                            relative_path,
+                            FileMetadata {
+                                size: metadata.len().unwrap_or_default(),
+                                modified: metadata
+                                    .modified()
+                                    .map(|modified| modified.as_system_time()),
+                            },
+                        );
+                    }
+                    _ => {}
+                }
+            }
+        }
+
+        Ok((files, found_dirs))
+    }
+}
+
+fn is_not_found(err: &SftpError) -> bool {
+    matches!(err, SftpError::SftpError(SftpErrorKind::NoSuchFile, _))
+}
+
+impl fmt::Display for SftpBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "sftp://{}{}", self.host, self.root.display())
+    }
+}
+
+#[async_trait]
+impl Backend for SftpBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let dst_path = self.remote_path(relative_path);
+        let tmp_path = temp_path_for(&dst_path);
+        let (dst_path, tmp_path) = (&dst_path, &tmp_path);
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                self.create_parents(&sftp, dst_path).await?;
+
+                let mut local = tokio::fs::File::open(source)
+                    .await
+                    .with_context(|| anyhow!("Error opening {}", source.display()))?;
+
+                let uploaded = async {
+                    let mut remote = Box::pin(TokioCompatFile::new(sftp.create(tmp_path).await?));
+                    match &self.bwlimit {
+                        Some(bwlimit) => copy_throttled(&mut local, &mut remote, bwlimit).await?,
+                        None => tokio::io::copy(&mut local, &mut remote).await?,
+                    };
+                    remote.shutdown().await?;
+
+                    // Plain SFTP renames refuse to replace an existing file
+                    let mut fs = sftp.fs();
+                    if !sftp.support_posix_rename() {
+                        let _ = fs.remove_file(dst_path).await;
+                    }
+                    fs.rename(tmp_path, dst_path).await?;
+                    Ok::<_, anyhow::Error>(())
+                }
+                .await;
+
+                if let Err(err) = uploaded {
+                    let _ = sftp.fs().remove_file(tmp_path).await;
+                    return Err(err).with_context(|| {
+                        anyhow!("Error uploading to {}:{}", self.host, dst_path.display())
+                    });
+                }
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let path = &self.remote_path(relative_path);
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                let remote = sftp
+                    .open(path)
+                    .await
+                    .with_context(|| anyhow!("Error opening {}:{}", self.host, path.display()))?;
+
+                save_download(destination, Box::pin(TokioCompatFile::new(remote))).await
+            })
+        })
+        .await
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let path = &self.remote_path(relative_path);
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                let mut fs = sftp.fs();
+                match fs.remove_file(path).await {
+                    Err(err) if !is_not_found(&err) => {
+                        return Err(err).with_context(|| {
+                            anyhow!("Error removing {}:{}", self.host, path.display())
+                        });
+                    }
+                    _ => {}
+                }
+
+                // Stops at the first directory that still has something in it
+                for dir in path.ancestors().skip(1) {
+                    if dir == self.root || fs.remove_dir(dir).await.is_err() {
+                        break;
+                    }
+                }
+
+                Ok(())
+            })
+        })
+        .await
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        let path = &self.remote_path(relative_path);
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                let mut fs = sftp.fs();
+                if *path == self.root || fs.metadata(path).await.is_ok() {
+                    return Ok(());
+                }
+                self.create_parents(&sftp, path).await?;
+                fs.create_dir(path)
+                    .await
+                    .with_context(|| anyhow!("Error creating {}:{}", self.host, path.display()))
+            })
+        })
+        .await
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        let path = self.remote_path(relative_path);
+        // SFTP doesn't say why it failed, but a directory that's gone or not empty is fine
+        if path != self.root {
+            let _ = self.connection.get().await?.fs().remove_dir(&path).await;
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let (from, to) = (&self.remote_path(from), &self.remote_path(to));
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                self.create_parents(&sftp, to).await?;
+
+                let mut fs = sftp.fs();
+                if !sftp.support_posix_rename() {
+                    let _ = fs.remove_file(to).await;
+                }
+                fs.rename(from, to).await.with_context(|| {
+                    anyhow!(
+                        "Error renaming {}:{} to {}",
+                        self.host,
+                        from.display(),
+                        to.display()
+                    )
+                })?;
+
+                for dir in from.ancestors().skip(1) {
+                    if dir == self.root || fs.remove_dir(dir).await.is_err() {
+                        break;
+                    }
+                }
+
+                Ok(true)
+            })
+        })
+        .await
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        self.reconnecting(|sftp| Box::pin(async move { Ok(self.walk(&sftp).await?.0) }))
+            .await
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        self.reconnecting(|sftp| Box::pin(async move { Ok(Some(self.walk(&sftp).await?.1)) }))
+            .await
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        let path = &self.remote_path(relative_path);
+        self.reconnecting(|sftp| {
+            Box::pin(async move {
+                match sftp.fs().metadata(path).await {
+                    Ok(metadata) => Ok(Some(FileMetadata {
+                        size: metadata.len().unwrap_or_default(),
+                        modified: metadata
+                            .modified()
+                            .map(|modified| modified.as_system_time()),
+                    })),
+                    Err(err) if is_not_found(&err) => Ok(None),
+                    Err(err) => Err(err).with_context(|| {
+                        anyhow!("Error checking {}:{}", self.host, path.display())
+                    }),
+                }
+            })
+        })
+        .await
+    }
+}
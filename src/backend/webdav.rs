@@ -0,0 +1,649 @@
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use futures::{StreamExt, TryStreamExt};
+use percent_encoding::percent_decode_str;
+use quick_xml::{events::Event, Reader};
+use reqwest::{header, Body, Client, Method, RequestBuilder, Response, StatusCode};
+use std::{
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fmt,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::io::AsyncReadExt;
+use tokio_util::io::{ReaderStream, StreamReader};
+use url::Url;
+
+use super::{save_download, Backend, FileMetadata};
+use crate::throttle::Throttle;
+
+/// Files at least this big are uploaded to Nextcloud in chunks of this size, so a dropped
+/// connection only loses one chunk and no single request runs into the server's limits
+const CHUNK_SIZE: usize = 10 * 1024 * 1024;
+
+/// The properties asked for when listing
+const PROPFIND_BODY: &str = r#"<?xml version="1.0" encoding="utf-8"?>
+<d:propfind xmlns:d="DAV:">
+  <d:prop><d:resourcetype/><d:getcontentlength/><d:getlastmodified/><d:getetag/></d:prop>
+</d:propfind>"#;
+
+/// Who WebDAV backups log in as. Whatever isn't set here comes from the
+/// `EVILMOUNT_DAV_USER` and `EVILMOUNT_DAV_PASSWORD` environment variables, or the user in
+/// the URL
+#[derive(Debug, Clone, Default, PartialEq, Eq)]
+pub struct DavCredentials {
+    pub user: Option<String>,
+    pub password: Option<String>,
+}
+
+impl DavCredentials {
+    /// These credentials, with anything missing taken from the environment
+    fn or_env(&self) -> Self {
+        Self {
+            user: self
+                .user
+                .clone()
+                .or_else(|| std::env::var("EVILMOUNT_DAV_USER").ok()),
+            password: self
+                .password
+                .clone()
+                .or_else(|| std::env::var("EVILMOUNT_DAV_PASSWORD").ok()),
+        }
+    }
+}
+
+/// A backup in a WebDAV collection, like a folder in Nextcloud. `dav://host/path` connects
+/// over HTTPS and `dav+http://host/path` over plain HTTP.
+///
+/// Every upload is conditional on the ETag the file had when it was last listed or
+/// written, so a file someone changed on the server isn't overwritten. Nextcloud gets large
+/// files in chunks
+pub struct WebDavBackend {
+    /// The collection holding the backup, ending in a `/`
+    root: Url,
+    client: Client,
+    credentials: DavCredentials,
+    /// Where Nextcloud takes chunked uploads, if the backup is on Nextcloud
+    uploads: Option<Url>,
+    /// The ETag of every file as it was last listed or written, by relative path
+    etags: Mutex<HashMap<PathBuf, String>>,
+    /// Directories that are known to exist, so uploads don't create them again
+    dirs: Mutex<HashSet<PathBuf>>,
+    /// Limits how fast files are uploaded
+    bwlimit: Option<Arc<Throttle>>,
+}
+
+impl fmt::Debug for WebDavBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("WebDavBackend")
+            .field("root", &self.root.as_str())
+            .field("user", &self.credentials.user)
+            .finish_non_exhaustive()
+    }
+}
+
+/// A file or collection in a PROPFIND response
+#[derive(Debug, Default)]
+struct Entry {
+    href: String,
+    is_dir: bool,
+    size: u64,
+    modified: Option<SystemTime>,
+    etag: Option<String>,
+}
+
+impl WebDavBackend {
+    /// Connects to `location`, a `dav://` or `dav+http://` URL
+    pub async fn connect(location: &str, credentials: &DavCredentials) -> Result<Self> {
+        let url = match (
+            location.strip_prefix("dav://"),
+            location.strip_prefix("dav+http://"),
+        ) {
+            (Some(rest), _) => format!("https://{rest}"),
+            (_, Some(rest)) => format!("http://{rest}"),
+            _ => return Err(anyhow!("{location} isn't a dav:// URL")),
+        };
+        let mut root =
+            Url::parse(&url).with_context(|| anyhow!("Invalid WebDAV URL {location}"))?;
+
+        let mut credentials = credentials.or_env();
+        if !root.username().is_empty() {
+            let user = percent_decode_str(root.username()).decode_utf8_lossy();
+            credentials.user.get_or_insert_with(|| user.into_owned());
+        }
+        if let Some(password) = root.password() {
+            let password = percent_decode_str(password).decode_utf8_lossy();
+            credentials
+                .password
+                .get_or_insert_with(|| password.into_owned());
+        }
+        // Credentials go in a header, not in URLs that end up in logs
+        let _ = root.set_username("");
+        let _ = root.set_password(None);
+        if !root.path().ends_with('/') {
+            root.set_path(&format!("{}/", root.path()));
+        }
+
+        let backend = Self {
+            uploads: nextcloud_uploads(&root),
+            root,
+            client: Client::new(),
+            credentials,
+            etags: Mutex::default(),
+            dirs: Mutex::default(),
+            bwlimit: None,
+        };
+        match backend.propfind(&backend.root, 0).await?.first() {
+            Some(entry) if entry.is_dir => Ok(backend),
+            Some(_) => Err(anyhow!("backup_dir must be a directory!")),
+            None => Err(anyhow!("{backend} doesn't exist")),
+        }
+    }
+
+    /// Uploads no faster than `bwlimit` allows
+    pub fn with_bwlimit(mut self, bwlimit: Option<Arc<Throttle>>) -> Self {
+        self.bwlimit = bwlimit;
+        self
+    }
+
+    fn request(&self, method: Method, url: Url) -> RequestBuilder {
+        let request = self.client.request(method, url);
+        match &self.credentials.user {
+            Some(user) => request.basic_auth(user, self.credentials.password.as_ref()),
+            None => request,
+        }
+    }
+
+    /// The URL of `relative_path`, with a trailing `/` for a collection
+    fn url_for(&self, relative_path: &Path, is_dir: bool) -> Url {
+        url_under(&self.root, relative_path, is_dir)
+    }
+
+    /// The path relative to the root of what an `href` in a response points to
+    fn relative_path(&self, href: &str) -> Option<PathBuf> {
+        let url = self.root.join(href).ok()?;
+        let path = url.path().strip_prefix(self.root.path())?;
+        Some(
+            path.split('/')
+                .filter(|segment| !segment.is_empty())
+                .map(|segment| percent_decode_str(segment).decode_utf8_lossy().into_owned())
+                .collect(),
+        )
+    }
+
+    /// The entries of a PROPFIND on `url`, itself first. Empty if it doesn't exist
+    async fn propfind(&self, url: &Url, depth: u8) -> Result<Vec<Entry>> {
+        let response = self
+            .request(Method::from_bytes(b"PROPFIND")?, url.clone())
+            .header("Depth", depth.to_string())
+            .header(header::CONTENT_TYPE, "application/xml")
+            .body(PROPFIND_BODY)
+            .send()
+            .await
+            .with_context(|| anyhow!("Error listing {url}"))?;
+        if response.status() == StatusCode::NOT_FOUND {
+            return Ok(Vec::new());
+        }
+        let body = checked(response)
+            .with_context(|| anyhow!("Error listing {url}"))?
+            .text()
+            .await?;
+        parse_multistatus(&body).with_context(|| anyhow!("Error parsing the listing of {url}"))
+    }
+
+    /// Creates every directory above `relative_path` that isn't known to exist
+    async fn create_parents(&self, relative_path: &Path) -> Result<()> {
+        let mut missing = Vec::new();
+        {
+            let dirs = self.dirs.lock().unwrap();
+            for dir in relative_path.ancestors().skip(1) {
+                if dir.as_os_str().is_empty() || dirs.contains(dir) {
+                    break;
+                }
+                missing.push(dir.to_path_buf());
+            }
+        }
+
+        for dir in missing.into_iter().rev() {
+            let url = self.url_for(&dir, true);
+            let response = self
+                .request(Method::from_bytes(b"MKCOL")?, url.clone())
+                .send()
+                .await
+                .with_context(|| anyhow!("Error creating {url}"))?;
+            // Servers answer 405 for a collection that already exists
+            if response.status() != StatusCode::METHOD_NOT_ALLOWED {
+                checked(response).with_context(|| anyhow!("Error creating {url}"))?;
+            }
+            self.dirs.lock().unwrap().insert(dir);
+        }
+
+        Ok(())
+    }
+
+    /// Adds the headers that make a write to `relative_path` fail if the file changed on
+    /// the server since it was last listed or written
+    fn conditional(&self, request: RequestBuilder, relative_path: &Path) -> RequestBuilder {
+        match self.etags.lock().unwrap().get(relative_path) {
+            Some(etag) => request.header(header::IF_MATCH, etag),
+            None => request,
+        }
+    }
+
+    /// Remembers the ETag a write to `relative_path` left it with
+    fn written(&self, relative_path: &Path, response: &Response) {
+        let etag = ["oc-etag", "etag"].iter().find_map(|name| {
+            let etag = response.headers().get(*name)?.to_str().ok()?;
+            // Weak ETags aren't allowed in If-Match
+            (!etag.starts_with("W/")).then(|| etag.to_string())
+        });
+        let mut etags = self.etags.lock().unwrap();
+        match etag {
+            Some(etag) => etags.insert(relative_path.to_path_buf(), etag),
+            None => etags.remove(relative_path),
+        };
+    }
+
+    /// Uploads `source` to `url` in one request
+    async fn upload(&self, relative_path: &Path, source: &Path, url: Url) -> Result<Response> {
+        let file = tokio::fs::File::open(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?;
+        let metadata = file.metadata().await?;
+        let bwlimit = self.bwlimit.clone();
+        let body = ReaderStream::new(file).then(move |chunk| {
+            let bwlimit = bwlimit.clone();
+            async move {
+                if let (Ok(chunk), Some(bwlimit)) = (&chunk, bwlimit) {
+                    bwlimit.take(chunk.len()).await;
+                }
+                chunk
+            }
+        });
+
+        let request = self
+            .request(Method::PUT, url)
+            .header(header::CONTENT_LENGTH, metadata.len())
+            .body(Body::wrap_stream(body));
+        let request = with_mtime(request, &metadata);
+        Ok(self.conditional(request, relative_path).send().await?)
+    }
+
+    /// Uploads `source` to `url` through Nextcloud's chunked uploads into `uploads`
+    async fn upload_chunked(
+        &self,
+        relative_path: &Path,
+        source: &Path,
+        url: Url,
+        uploads: &Url,
+    ) -> Result<Response> {
+        static UPLOADS: AtomicUsize = AtomicUsize::new(0);
+
+        let mut file = tokio::fs::File::open(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?;
+        let metadata = file.metadata().await?;
+        let upload_id = format!(
+            "evil_mount-{}-{}",
+            std::process::id(),
+            UPLOADS.fetch_add(1, Ordering::Relaxed)
+        );
+        let upload = url_under(uploads, Path::new(&upload_id), true);
+        let destination = url.to_string();
+        let total_length = metadata.len().to_string();
+
+        let response = self
+            .request(Method::from_bytes(b"MKCOL")?, upload.clone())
+            .header("Destination", &destination)
+            .send()
+            .await?;
+        checked(response).context("Error starting a chunked upload")?;
+
+        let uploaded = async {
+            let mut buf = vec![0; CHUNK_SIZE];
+            // Nextcloud wants chunks numbered from 1
+            for number in 1.. {
+                let mut len = 0;
+                while len < CHUNK_SIZE {
+                    match file.read(&mut buf[len..]).await? {
+                        0 => break,
+                        read => len += read,
+                    }
+                }
+                if len == 0 {
+                    break;
+                }
+                if let Some(bwlimit) = &self.bwlimit {
+                    bwlimit.take(len).await;
+                }
+                let chunk = url_under(&upload, Path::new(&format!("{number:05}")), false);
+                let response = self
+                    .request(Method::PUT, chunk)
+                    .header("Destination", &destination)
+                    .header("OC-Total-Length", &total_length)
+                    .body(buf[..len].to_vec())
+                    .send()
+                    .await?;
+                checked(response).with_context(|| anyhow!("Error uploading chunk {number}"))?;
+            }
+
+            let request = self
+                .request(
+                    Method::from_bytes(b"MOVE")?,
+                    url_under(&upload, Path::new(".file"), false),
+                )
+                .header("Destination", &destination)
+                .header("OC-Total-Length", &total_length)
+                .header("Overwrite", "T");
+            let request = with_mtime(request, &metadata);
+            anyhow::Ok(self.conditional(request, relative_path).send().await?)
+        }
+        .await;
+
+        if !uploaded
+            .as_ref()
+            .is_ok_and(|response| response.status().is_success())
+        {
+            let _ = self.request(Method::DELETE, upload).send().await;
+        }
+        uploaded
+    }
+
+    /// Every file and every directory under the root, relative to it
+    async fn walk(&self) -> Result<(BTreeMap<PathBuf, FileMetadata>, BTreeSet<PathBuf>)> {
+        let mut files = BTreeMap::new();
+        let mut found_dirs = BTreeSet::new();
+        let mut etags = HashMap::new();
+        let mut dirs = vec![PathBuf::new()];
+
+        // Servers often refuse `Depth: infinity`, so every collection is listed on its own
+        while let Some(relative_dir) = dirs.pop() {
+            let url = self.url_for(&relative_dir, true);
+            for entry in self.propfind(&url, 1).await? {
+                let Some(relative_path) = self.relative_path(&entry.href) else {
+                    continue;
+                };
+                if relative_path == relative_dir {
+                    continue;
+                }
+                if entry.is_dir {
+                    found_dirs.insert(relative_path.clone());
+                    dirs.push(relative_path);
+                    continue;
+                }
+                if let Some(etag) = entry.etag {
+                    etags.insert(relative_path.clone(), etag);
+                }
+                files.insert(
+                    relative_path,
+                    FileMetadata {
+                        size: entry.size,
+                        modified: entry.modified,
+                    },
+                );
+            }
+        }
+
+        *self.etags.lock().unwrap() = etags;
+        *self.dirs.lock().unwrap() = found_dirs.iter().cloned().collect();
+        Ok((files, found_dirs))
+    }
+}
+
+/// `url`, a collection, with `relative_path` appended to it
+fn url_under(url: &Url, relative_path: &Path, is_dir: bool) -> Url {
+    let mut url = url.clone();
+    {
+        let mut segments = url.path_segments_mut().expect("http URLs have paths");
+        segments.pop_if_empty();
+        for component in relative_path.components() {
+            if let Component::Normal(part) = component {
+                segments.push(&part.to_string_lossy());
+            }
+        }
+        if is_dir {
+            segments.push("");
+        }
+    }
+    url
+}
+
+/// Where Nextcloud takes chunked uploads for files in `root`, if `root` is in the files of
+/// a Nextcloud user, `.../remote.php/dav/files/USER/...`
+fn nextcloud_uploads(root: &Url) -> Option<Url> {
+    let path = root.path();
+    let start = path.find("/remote.php/dav/files/")?;
+    let rest = &path[start + "/remote.php/dav/files/".len()..];
+    let user = rest.split('/').next().filter(|user| !user.is_empty())?;
+    let mut uploads = root.clone();
+    uploads.set_path(&format!(
+        "{}/remote.php/dav/uploads/{user}/",
+        &path[..start]
+    ));
+    Some(uploads)
+}
+
+/// Sends the modification time along, which Nextcloud keeps for the file
+fn with_mtime(request: RequestBuilder, metadata: &std::fs::Metadata) -> RequestBuilder {
+    let modified = metadata
+        .modified()
+        .ok()
+        .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok());
+    match modified {
+        Some(modified) => request.header("X-OC-Mtime", modified.as_secs()),
+        None => request,
+    }
+}
+
+/// `response`, or an error for a status that isn't a success
+fn checked(response: Response) -> Result<Response> {
+    match response.status() {
+        StatusCode::UNAUTHORIZED => Err(anyhow!(
+            "The server refused the credentials, set dav_user and dav_password or \
+             EVILMOUNT_DAV_USER and EVILMOUNT_DAV_PASSWORD"
+        )),
+        StatusCode::PRECONDITION_FAILED => Err(anyhow!(
+            "It was changed on the server since it was last synced, not overwriting it"
+        )),
+        _ => Ok(response.error_for_status()?),
+    }
+}
+
+/// The entries in the body of a `207 Multi-Status` response
+fn parse_multistatus(body: &str) -> Result<Vec<Entry>> {
+    let mut reader = Reader::from_str(body);
+    reader.config_mut().trim_text(true);
+    let mut entries = Vec::new();
+    let mut entry = None;
+    // The element whose text comes next
+    let mut element = Vec::new();
+
+    loop {
+        match reader.read_event()? {
+            Event::Start(start) => {
+                element = start.local_name().as_ref().to_vec();
+                match element.as_slice() {
+                    b"response" => entry = Some(Entry::default()),
+                    b"collection" => entry.iter_mut().for_each(|entry| entry.is_dir = true),
+                    _ => {}
+                }
+            }
+            Event::Empty(empty) if empty.local_name().as_ref() == b"collection" => {
+                entry.iter_mut().for_each(|entry| entry.is_dir = true);
+            }
+            Event::Text(text) => {
+                let Some(entry) = &mut entry else {
+                    continue;
+                };
+                let text = text.unescape()?;
+                match element.as_slice() {
+                    b"href" => entry.href = text.into_owned(),
+                    b"getcontentlength" => entry.size = text.parse().unwrap_or_default(),
+                    b"getlastmodified" => {
+                        entry.modified = chrono::DateTime::parse_from_rfc2822(&text)
+                            .ok()
+                            .map(SystemTime::from)
+                    }
+                    b"getetag" => entry.etag = Some(text.into_owned()),
+                    _ => {}
+                }
+            }
+            Event::End(end) => {
+                if end.local_name().as_ref() == b"response" {
+                    entries.extend(entry.take());
+                }
+                element.clear();
+            }
+            Event::Eof => return Ok(entries),
+            _ => {}
+        }
+    }
+}
+
+impl fmt::Display for WebDavBackend {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let scheme = match self.root.scheme() {
+            "http" => "dav+http",
+            _ => "dav",
+        };
+        let host = self.root.host_str().unwrap_or_default();
+        match self.root.port() {
+            Some(port) => write!(f, "{scheme}://{host}:{port}{}", self.root.path()),
+            None => write!(f, "{scheme}://{host}{}", self.root.path()),
+        }
+    }
+}
+
+#[async_trait]
+impl Backend for WebDavBackend {
+    async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+        let url = self.url_for(relative_path, false);
+        self.create_parents(relative_path).await?;
+
+        let size = tokio::fs::metadata(source)
+            .await
+            .with_context(|| anyhow!("Error opening {}", source.display()))?
+            .len();
+        let response = match &self.uploads {
+            Some(uploads) if size >= CHUNK_SIZE as u64 => {
+                self.upload_chunked(relative_path, source, url.clone(), uploads)
+                    .await
+            }
+            _ => self.upload(relative_path, source, url.clone()).await,
+        };
+        let response = response
+            .and_then(checked)
+            .with_context(|| anyhow!("Error uploading {url}"))?;
+        self.written(relative_path, &response);
+
+        Ok(())
+    }
+
+    async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+        let url = self.url_for(relative_path, false);
+        let response = self
+            .request(Method::GET, url.clone())
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(checked)
+            .with_context(|| anyhow!("Error downloading {url}"))?;
+        let contents = StreamReader::new(response.bytes_stream().map_err(std::io::Error::other));
+
+        save_download(destination, contents).await
+    }
+
+    async fn delete(&self, relative_path: &Path) -> Result<()> {
+        let url = self.url_for(relative_path, false);
+        let request = self.request(Method::DELETE, url.clone());
+        let response = self
+            .conditional(request, relative_path)
+            .send()
+            .await
+            .with_context(|| anyhow!("Error deleting {url}"))?;
+        if response.status() != StatusCode::NOT_FOUND {
+            checked(response).with_context(|| anyhow!("Error deleting {url}"))?;
+        }
+        self.etags.lock().unwrap().remove(relative_path);
+
+        Ok(())
+    }
+
+    async fn create_dir(&self, relative_path: &Path) -> Result<()> {
+        if relative_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        // The directory counts as one of its own parents
+        self.create_parents(&relative_path.join("_")).await
+    }
+
+    async fn delete_dir(&self, relative_path: &Path) -> Result<()> {
+        if relative_path.as_os_str().is_empty() {
+            return Ok(());
+        }
+        let url = self.url_for(relative_path, true);
+        // Deleting a collection deletes everything in it, so make sure there's nothing
+        if self.propfind(&url, 1).await?.len() == 1 {
+            let response = self
+                .request(Method::DELETE, url.clone())
+                .send()
+                .await
+                .with_context(|| anyhow!("Error deleting {url}"))?;
+            if response.status() != StatusCode::NOT_FOUND {
+                checked(response).with_context(|| anyhow!("Error deleting {url}"))?;
+            }
+            self.dirs.lock().unwrap().remove(relative_path);
+        }
+        Ok(())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<bool> {
+        let (from_url, to_url) = (self.url_for(from, false), self.url_for(to, false));
+        self.create_parents(to).await?;
+
+        let request = self
+            .request(Method::from_bytes(b"MOVE")?, from_url.clone())
+            .header("Destination", to_url.as_str())
+            .header("Overwrite", "T");
+        self.conditional(request, from)
+            .send()
+            .await
+            .map_err(anyhow::Error::from)
+            .and_then(checked)
+            .with_context(|| anyhow!("Error moving {from_url} to {to_url}"))?;
+
+        // Moving a file keeps its ETag
+        let mut etags = self.etags.lock().unwrap();
+        match etags.remove(from) {
+            Some(etag) => etags.insert(to.to_path_buf(), etag),
+            None => etags.remove(to),
+        };
+        Ok(true)
+    }
+
+    async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        Ok(self.walk().await?.0)
+    }
+
+    async fn list_dirs(&self) -> Result<Option<BTreeSet<PathBuf>>> {
+        Ok(Some(self.walk().await?.1))
+    }
+
+    async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+        let url = self.url_for(relative_path, false);
+        Ok(self
+            .propfind(&url, 0)
+            .await?
+            .into_iter()
+            .next()
+            .filter(|entry| !entry.is_dir)
+            .map(|entry| FileMetadata {
+                size: entry.size,
+                modified: entry.modified,
+            }))
+    }
+}
@@ -0,0 +1,414 @@
+//! Two-way syncing, where changes made directly in the backup flow back into work_dir.
+//!
+//! Both sides are scanned periodically and compared against what they looked like at the
+//! end of the last sync. A file that changed on one side is copied to the other, a file
+//! that changed on both sides is a conflict and is settled with a [`ConflictStrategy`]
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Duration,
+};
+use tokio::time::Instant;
+use tracing::{debug, debug_span, info, Instrument};
+
+use crate::{
+    backend::{list_files, FileMetadata},
+    copy::{remove_and_prune, set_mtime},
+    error::Operation,
+    hash::hash_file,
+    meta::metadata_dir,
+    snapshot::SNAPSHOT_FORMAT,
+    syncer::{SyncContext, SyncEvent},
+};
+
+/// What to do with a file that changed in both work_dir and the backup since the last sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ConflictStrategy {
+    /// Keep whichever copy was modified last
+    #[default]
+    #[serde(rename = "newer-wins")]
+    NewerWins,
+    /// Keep the copy in work_dir
+    #[serde(rename = "work-wins")]
+    WorkWins,
+    /// Keep the copy in the backup
+    #[serde(rename = "backup-wins")]
+    BackupWins,
+    /// Keep the copy in work_dir, and save the backup's copy next to it under a new name
+    #[serde(rename = "rename-conflict")]
+    RenameConflict,
+}
+
+impl FromStr for ConflictStrategy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "newer-wins" => Ok(Self::NewerWins),
+            "work-wins" => Ok(Self::WorkWins),
+            "backup-wins" => Ok(Self::BackupWins),
+            "rename-conflict" => Ok(Self::RenameConflict),
+            _ => Err(format!(
+                "unknown conflict strategy {s}, expected newer-wins, work-wins, backup-wins or rename-conflict"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ConflictStrategy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::NewerWins => "newer-wins",
+            Self::WorkWins => "work-wins",
+            Self::BackupWins => "backup-wins",
+            Self::RenameConflict => "rename-conflict",
+        })
+    }
+}
+
+/// What both copies of a file looked like at the end of the last sync
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+struct SyncedFile {
+    work: Option<FileMetadata>,
+    backup: Option<FileMetadata>,
+}
+
+/// How a file that changed gets brought back in sync
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Action {
+    /// Copy work_dir's version into the backup
+    Push,
+    /// Copy the backup's version into work_dir
+    Pull,
+    /// Save the backup's version under a conflict name, then push work_dir's version
+    PushKeepingBackup,
+    /// The file was deleted from work_dir
+    RemoveFromBackup,
+    /// The file was deleted from the backup
+    RemoveFromWork,
+    /// Both sides already match, only remember that
+    Record,
+}
+
+/// Where the state of the last sync is kept. It lives in work_dir because the backup
+/// might not be a local directory
+pub(crate) fn state_path(work_dir: &Path) -> PathBuf {
+    metadata_dir(work_dir).join("sync-state.json")
+}
+
+fn load_state(work_dir: &Path) -> Result<BTreeMap<PathBuf, SyncedFile>> {
+    let path = state_path(work_dir);
+    match std::fs::read(&path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing sync state {}", path.display())),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(BTreeMap::new()),
+        Err(err) => {
+            Err(err).with_context(|| anyhow!("Error reading sync state {}", path.display()))
+        }
+    }
+}
+
+fn save_state(work_dir: &Path, state: &BTreeMap<PathBuf, SyncedFile>) -> Result<()> {
+    let path = state_path(work_dir);
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+    }
+    std::fs::write(&path, serde_json::to_vec(state)?)
+        .with_context(|| anyhow!("Error writing sync state {}", path.display()))
+}
+
+/// Where the backup's side of a conflict is saved, e.g. `notes.conflict-<timestamp>.txt`
+fn conflict_path_for(path: &Path) -> PathBuf {
+    let timestamp = Utc::now().format(SNAPSHOT_FORMAT);
+    let stem = path.file_stem().unwrap_or_default().to_string_lossy();
+    let file_name = match path.extension() {
+        Some(extension) => format!(
+            "{stem}.conflict-{timestamp}.{}",
+            extension.to_string_lossy()
+        ),
+        None => format!("{stem}.conflict-{timestamp}"),
+    };
+    path.with_file_name(file_name)
+}
+
+/// Keeps work_dir and the backup in sync in both directions by periodically scanning both
+pub(crate) async fn sync_both_ways(ctx: Arc<SyncContext>) -> Result<()> {
+    let mut state = load_state(&ctx.work_dir)?;
+
+    info!(
+        "Syncing both ways, settling conflicts with {}...",
+        ctx.options.conflict
+    );
+
+    let mut requested = false;
+    loop {
+        // Not even for a last cycle or when asked to while backup_dir is gone, which would
+        // look like everything in the backup was deleted
+        if ctx.control.is_missing() {
+            if ctx.is_shutting_down() {
+                return Ok(());
+            }
+            ctx.wait(ctx.interval()).await;
+            continue;
+        }
+        if ctx.is_paused() && !requested && !ctx.is_shutting_down() {
+            requested = ctx.wait(ctx.interval()).await;
+            continue;
+        }
+
+        let span = debug_span!("sync_cycle", work_dir = %ctx.work_dir.display());
+        if let Err(error) = sync_cycle(&ctx, &mut state).instrument(span).await {
+            ctx.emit_error(Operation::SyncBothWays, ctx.work_dir.clone(), error);
+        }
+        // Nothing is really synced in a dry run, so the state is only kept in memory
+        let saved = match ctx.options.dry_run {
+            true => Ok(()),
+            false => save_state(&ctx.work_dir, &state),
+        };
+        if let Err(error) = saved {
+            ctx.emit_error(Operation::Save, ctx.work_dir.clone(), error);
+        }
+        ctx.options.ignore.walk_errors().check()?;
+
+        let changed = ctx.end_cycle().await;
+        ctx.schedule.scanned(changed);
+
+        if ctx.is_shutting_down() {
+            return Ok(());
+        }
+
+        // Shutting down cuts the wait short, for one last cycle
+        requested = ctx.wait(ctx.interval()).await;
+    }
+}
+
+async fn sync_cycle(
+    ctx: &Arc<SyncContext>,
+    state: &mut BTreeMap<PathBuf, SyncedFile>,
+) -> Result<()> {
+    let start = Instant::now();
+    let work_files = {
+        let work_dir = ctx.work_dir.clone();
+        let ignore = ctx.options.ignore.clone();
+        let symlinks = ctx.options.copy.symlinks;
+        tokio::task::spawn_blocking(move || list_files(&work_dir, &ignore, symlinks)).await??
+    };
+    let mut backup_files = ctx.backend.list().await?;
+    backup_files.retain(|relative_path, _| !ctx.options.ignore.is_ignored(relative_path, false));
+
+    let relative_paths: BTreeSet<PathBuf> = work_files
+        .keys()
+        .chain(backup_files.keys())
+        .chain(state.keys())
+        .cloned()
+        .collect();
+    ctx.metrics.scanned(relative_paths.len(), start.elapsed());
+
+    for relative_path in relative_paths {
+        let current = SyncedFile {
+            work: work_files.get(&relative_path).copied(),
+            backup: backup_files.get(&relative_path).copied(),
+        };
+        let last = state.get(&relative_path).copied().unwrap_or_default();
+
+        let work_changed = current.work != last.work;
+        // Still being written, the next cycle tries again
+        if work_changed
+            && current.work.is_some()
+            && ctx
+                .time_to_settle(&ctx.work_dir.join(&relative_path))
+                .is_some()
+        {
+            continue;
+        }
+
+        let action = match (work_changed, current.backup != last.backup) {
+            (false, false) => continue,
+            (true, false) => match current.work {
+                Some(_) => Action::Push,
+                None => Action::RemoveFromBackup,
+            },
+            (false, true) => match current.backup {
+                Some(_) => Action::Pull,
+                None => Action::RemoveFromWork,
+            },
+            (true, true) => match resolve_conflict(ctx, &relative_path, current).await {
+                Ok(action) => action,
+                Err(error) => {
+                    ctx.emit_error(
+                        Operation::SyncBothWays,
+                        ctx.work_dir.join(&relative_path),
+                        error,
+                    );
+                    continue;
+                }
+            },
+        };
+
+        match apply(ctx, &relative_path, action, current).await {
+            Ok(synced) if synced == SyncedFile::default() => {
+                state.remove(&relative_path);
+            }
+            Ok(synced) => {
+                state.insert(relative_path, synced);
+            }
+            Err(error) => ctx.emit_error(
+                Operation::SyncBothWays,
+                ctx.work_dir.join(&relative_path),
+                error,
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// Decides what to do with a file that changed on both sides
+async fn resolve_conflict(
+    ctx: &Arc<SyncContext>,
+    relative_path: &Path,
+    current: SyncedFile,
+) -> Result<Action> {
+    let (work, backup) = match (current.work, current.backup) {
+        (None, None) => return Ok(Action::Record),
+        // A file that was changed on one side and deleted on the other is kept
+        (Some(_), None) => return Ok(Action::Push),
+        (None, Some(_)) => return Ok(Action::Pull),
+        (Some(work), Some(backup)) => (work, backup),
+    };
+
+    // Both sides might have made the same change
+    if work.size == backup.size {
+        let path = ctx.work_dir.join(relative_path);
+        let work_hash = tokio::task::spawn_blocking(move || hash_file(&path)).await??;
+        if work_hash == ctx.backend.hash(relative_path).await? {
+            return Ok(Action::Record);
+        }
+    }
+
+    ctx.emit(SyncEvent::Conflict(ctx.work_dir.join(relative_path)));
+
+    Ok(match ctx.options.conflict {
+        ConflictStrategy::WorkWins => Action::Push,
+        ConflictStrategy::BackupWins => Action::Pull,
+        ConflictStrategy::NewerWins => match work.modified >= backup.modified {
+            true => Action::Push,
+            false => Action::Pull,
+        },
+        ConflictStrategy::RenameConflict => Action::PushKeepingBackup,
+    })
+}
+
+/// Carries out `action` and returns what both sides look like afterwards
+async fn apply(
+    ctx: &Arc<SyncContext>,
+    relative_path: &Path,
+    action: Action,
+    current: SyncedFile,
+) -> Result<SyncedFile> {
+    let path = ctx.work_dir.join(relative_path);
+    let deletions = ctx.options.delete_after.is_some();
+
+    if ctx.options.dry_run {
+        match action {
+            Action::Push | Action::PushKeepingBackup => {
+                println!("WOULD COPY {}", path.display());
+                ctx.emit(SyncEvent::Copied {
+                    path,
+                    bytes: current.work.as_ref().map_or(0, |work| work.size),
+                    duration: Duration::ZERO,
+                });
+            }
+            Action::Pull => {
+                println!("WOULD COPY {}", ctx.backup_location(relative_path));
+                ctx.emit(SyncEvent::Pulled(path));
+            }
+            Action::RemoveFromBackup if deletions => {
+                println!("WOULD DELETE {}", ctx.backup_location(relative_path));
+                ctx.emit(SyncEvent::Removed(path));
+                return Ok(SyncedFile::default());
+            }
+            Action::RemoveFromWork if deletions => {
+                println!("WOULD DELETE {}", path.display());
+                ctx.emit(SyncEvent::Removed(path));
+                return Ok(SyncedFile::default());
+            }
+            _ => {}
+        }
+        return Ok(current);
+    }
+
+    match action {
+        Action::Record => Ok(current),
+        Action::Push => {
+            // Remembering a skipped file as it is keeps it from being retried every cycle
+            let Some(copy) = ctx.put_file(&path).await? else {
+                return Ok(current);
+            };
+            ctx.emit(copy.event(path));
+            Ok(SyncedFile {
+                work: current.work,
+                backup: ctx.backend.metadata(relative_path).await?,
+            })
+        }
+        Action::PushKeepingBackup => {
+            let conflict_path = conflict_path_for(&path);
+            ctx.backend.get(relative_path, &conflict_path).await?;
+            info!(
+                "Saved the backup's version of {} as {}",
+                path.display(),
+                conflict_path.display()
+            );
+            // The conflict copy is picked up as a new file on the next cycle
+            Box::pin(apply(ctx, relative_path, Action::Push, current)).await
+        }
+        Action::Pull => {
+            if let Some(backup) = current
+                .backup
+                .filter(|backup| ctx.options.too_large(backup.size))
+            {
+                debug!(path = %path.display(), size = backup.size, "Too large, skipping it");
+                ctx.emit(SyncEvent::Skipped(path));
+                return Ok(current);
+            }
+            let start = Instant::now();
+            ctx.backend.get(relative_path, &path).await?;
+            ctx.metrics.copied(start.elapsed());
+            // Matching modification times keep the next initialization from copying it again
+            if let Some(modified) = current.backup.and_then(|backup| backup.modified) {
+                set_mtime(&path, modified).await?;
+            }
+            let metadata = tokio::fs::symlink_metadata(&path).await?;
+            ctx.metrics.add_bytes_copied(metadata.len());
+            ctx.emit(SyncEvent::Pulled(path));
+            Ok(SyncedFile {
+                work: Some(FileMetadata {
+                    size: metadata.len(),
+                    modified: metadata.modified().ok(),
+                }),
+                backup: current.backup,
+            })
+        }
+        // Without --delete the other copy stays, and becomes the only one
+        Action::RemoveFromBackup if !deletions => Ok(current),
+        Action::RemoveFromWork if !deletions => Ok(current),
+        Action::RemoveFromBackup => {
+            ctx.backend.delete(relative_path).await?;
+            ctx.emit(SyncEvent::Removed(path));
+            Ok(SyncedFile::default())
+        }
+        Action::RemoveFromWork => {
+            remove_and_prune(&path, &ctx.work_dir).await?;
+            ctx.emit(SyncEvent::Removed(path));
+            Ok(SyncedFile::default())
+        }
+    }
+}
@@ -0,0 +1,781 @@
+//! `evil_mount restore --interactive`, a browser over the backup and its snapshots for
+//! picking what to restore
+
+use anyhow::{anyhow, Result};
+use evil_mount::{backend::FileMetadata, Syncer};
+use indicatif::HumanBytes;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEvent, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, ListState, Paragraph, Wrap},
+    DefaultTerminal, Frame,
+};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    ffi::OsString,
+    path::{Path, PathBuf},
+};
+
+/// Diffs are only computed if the lines that differ, multiplied, are at most this many
+const MAX_DIFF_CELLS: usize = 4_000_000;
+
+/// Runs of unchanged lines longer than this are folded in diffs
+pub const CONTEXT_LINES: usize = 3;
+
+/// What was picked in the browser
+pub struct Selection {
+    /// The snapshot to restore from, or `None` for the current backup
+    pub at: Option<String>,
+    /// The paths to restore relative to each pair's work_dir, in the order of the syncers
+    pub paths: Vec<Vec<PathBuf>>,
+}
+
+/// The files of a pair, as a tree
+#[derive(Default)]
+struct Dir {
+    dirs: BTreeMap<OsString, Dir>,
+    files: BTreeMap<OsString, FileMetadata>,
+    /// How many files there are in here and below
+    len: usize,
+}
+
+impl Dir {
+    fn from_files(files: BTreeMap<PathBuf, FileMetadata>) -> Self {
+        let mut root = Self::default();
+        for (path, metadata) in files {
+            let Some(name) = path.file_name() else {
+                continue;
+            };
+            let mut dir = &mut root;
+            dir.len += 1;
+            for component in path.parent().into_iter().flat_map(Path::iter) {
+                dir = dir.dirs.entry(component.to_owned()).or_default();
+                dir.len += 1;
+            }
+            dir.files.insert(name.to_owned(), metadata);
+        }
+        root
+    }
+
+    fn find(&self, path: &Path) -> Option<&Self> {
+        path.iter()
+            .try_fold(self, |dir, component| dir.dirs.get(component))
+    }
+
+    /// Every file in here and below, with `prefix` in front of their paths
+    fn walk(&self, prefix: &Path, files: &mut Vec<(PathBuf, FileMetadata)>) {
+        for (name, dir) in &self.dirs {
+            dir.walk(&prefix.join(name), files);
+        }
+        for (name, metadata) in &self.files {
+            files.push((prefix.join(name), *metadata));
+        }
+    }
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+enum RowKind {
+    Pair,
+    Dir,
+    File(FileMetadata),
+}
+
+/// A line of the tree
+struct Row {
+    pair: usize,
+    /// Relative to the pair's work_dir, empty for the pair itself
+    path: PathBuf,
+    kind: RowKind,
+    label: String,
+    depth: usize,
+}
+
+/// The diff of a file against its copy in work_dir
+#[derive(Default)]
+struct Preview {
+    title: String,
+    lines: Vec<Line<'static>>,
+    scroll: u16,
+}
+
+struct Browser<'a> {
+    syncers: &'a [Syncer],
+    /// The snapshots each pair has
+    snapshots: Vec<Vec<String>>,
+    /// `None` for the current backup, then every snapshot, newest first
+    sources: Vec<Option<String>>,
+    source: usize,
+    /// The files of each pair in the current source
+    trees: Vec<Dir>,
+    /// Why a pair has nothing to show for the current source
+    errors: Vec<Option<String>>,
+    expanded: BTreeSet<(usize, PathBuf)>,
+    selected: BTreeSet<(usize, PathBuf)>,
+    search: String,
+    searching: bool,
+    rows: Vec<Row>,
+    list: ListState,
+    preview: Preview,
+    /// Whether restoring the selection is waiting for a yes
+    confirming: bool,
+    message: String,
+}
+
+/// Shows the browser until something is picked to restore or it's closed with q or Esc,
+/// which returns `None`. It starts on the newest snapshot matching `at`, if that's set
+pub async fn run(syncers: &[Syncer], at: Option<&str>) -> Result<Option<Selection>> {
+    let snapshots = syncers
+        .iter()
+        .map(Syncer::snapshots)
+        .collect::<Result<Vec<_>, _>>()?;
+    let names: BTreeSet<&String> = snapshots.iter().flatten().collect();
+    let sources: Vec<Option<String>> = std::iter::once(None)
+        .chain(names.into_iter().rev().map(|name| Some(name.clone())))
+        .collect();
+    let source = match at {
+        Some(at) => sources
+            .iter()
+            .position(|name| name.as_deref() == Some(at))
+            .or_else(|| {
+                sources
+                    .iter()
+                    .position(|name| name.as_deref().is_some_and(|name| name.starts_with(at)))
+            })
+            .ok_or_else(|| anyhow!("No snapshot matches {at}"))?,
+        None => 0,
+    };
+
+    let mut browser = Browser {
+        syncers,
+        snapshots,
+        sources,
+        source,
+        trees: Vec::new(),
+        errors: Vec::new(),
+        expanded: (0..syncers.len())
+            .map(|pair| (pair, PathBuf::new()))
+            .collect(),
+        selected: BTreeSet::new(),
+        search: String::new(),
+        searching: false,
+        rows: Vec::new(),
+        list: ListState::default().with_selected(Some(0)),
+        preview: Preview::default(),
+        confirming: false,
+        message: String::new(),
+    };
+    browser.load().await;
+
+    let mut terminal = ratatui::init();
+    let result = browser.show(&mut terminal).await;
+    ratatui::restore();
+    result
+}
+
+impl Browser<'_> {
+    fn at(&self) -> Option<&str> {
+        self.sources[self.source].as_deref()
+    }
+
+    /// Lists the files of every pair in the current source
+    async fn load(&mut self) {
+        self.trees.clear();
+        self.errors.clear();
+        for (syncer, snapshots) in self.syncers.iter().zip(&self.snapshots) {
+            let files = match self.at() {
+                Some(at) if !snapshots.iter().any(|name| name == at) => {
+                    Err(anyhow!("no snapshot {at}"))
+                }
+                at => syncer
+                    .restorable_files(at)
+                    .await
+                    .map_err(anyhow::Error::from),
+            };
+            match files {
+                Ok(files) => {
+                    self.trees.push(Dir::from_files(files));
+                    self.errors.push(None);
+                }
+                Err(err) => {
+                    self.trees.push(Dir::default());
+                    self.errors.push(Some(format!("{err:#}")));
+                }
+            }
+        }
+        self.refresh();
+    }
+
+    /// Works out the rows from the trees, what's expanded and the search
+    fn refresh(&mut self) {
+        let query = self.search.to_lowercase();
+        self.rows.clear();
+        for (pair, tree) in self.trees.iter().enumerate() {
+            let label = match &self.errors[pair] {
+                Some(error) => format!("{} ({error})", self.syncers[pair].work_dir().display()),
+                None => self.syncers[pair].work_dir().display().to_string(),
+            };
+            self.rows.push(Row {
+                pair,
+                path: PathBuf::new(),
+                kind: RowKind::Pair,
+                label,
+                depth: 0,
+            });
+            if !query.is_empty() {
+                let mut files = Vec::new();
+                tree.walk(Path::new(""), &mut files);
+                self.rows.extend(
+                    files
+                        .into_iter()
+                        .filter(|(path, _)| path.to_string_lossy().to_lowercase().contains(&query))
+                        .map(|(path, metadata)| Row {
+                            pair,
+                            label: path.display().to_string(),
+                            path,
+                            kind: RowKind::File(metadata),
+                            depth: 1,
+                        }),
+                );
+            } else if self.expanded.contains(&(pair, PathBuf::new())) {
+                push_rows(tree, pair, Path::new(""), 1, &self.expanded, &mut self.rows);
+            }
+        }
+        let last = self.rows.len().saturating_sub(1);
+        self.list
+            .select(Some(self.list.selected().unwrap_or(0).min(last)));
+    }
+
+    fn row(&self) -> Option<&Row> {
+        self.rows.get(self.list.selected()?)
+    }
+
+    /// The files of `row`, or those matching the search if it's a pair
+    fn files_of(&self, row: &Row) -> Vec<PathBuf> {
+        match row.kind {
+            RowKind::File(_) => vec![row.path.clone()],
+            RowKind::Pair if !self.search.is_empty() => self
+                .rows
+                .iter()
+                .filter(|other| other.pair == row.pair && matches!(other.kind, RowKind::File(_)))
+                .map(|other| other.path.clone())
+                .collect(),
+            RowKind::Pair | RowKind::Dir => {
+                let mut files = Vec::new();
+                if let Some(dir) = self.trees[row.pair].find(&row.path) {
+                    dir.walk(&row.path, &mut files);
+                }
+                files.into_iter().map(|(path, _)| path).collect()
+            }
+        }
+    }
+
+    /// How many of the files below `row` are selected
+    fn selected_below(&self, row: &Row) -> usize {
+        self.selected
+            .range((row.pair, row.path.clone())..)
+            .take_while(|(pair, path)| *pair == row.pair && path.starts_with(&row.path))
+            .count()
+    }
+
+    fn toggle_selected(&mut self) {
+        let Some(row) = self.row() else {
+            return;
+        };
+        let pair = row.pair;
+        let files = self.files_of(row);
+        let all = files
+            .iter()
+            .all(|path| self.selected.contains(&(pair, path.clone())));
+        for path in files {
+            match all {
+                true => self.selected.remove(&(pair, path)),
+                false => self.selected.insert((pair, path)),
+            };
+        }
+    }
+
+    fn set_expanded(&mut self, expanded: bool) {
+        let Some(row) = self.row() else {
+            return;
+        };
+        if !self.search.is_empty() {
+            return;
+        }
+        let key = (row.pair, row.path.clone());
+        match (row.kind, expanded) {
+            (RowKind::File(_), _) => return,
+            (_, true) => self.expanded.insert(key),
+            (_, false) => self.expanded.remove(&key),
+        };
+        self.refresh();
+    }
+
+    /// Collapses the directory of the current row, moving onto it
+    fn collapse_parent(&mut self) {
+        let Some(row) = self.row() else {
+            return;
+        };
+        if matches!(row.kind, RowKind::Dir | RowKind::Pair)
+            && self.expanded.contains(&(row.pair, row.path.clone()))
+        {
+            return self.set_expanded(false);
+        }
+        let (pair, parent) = match row.kind {
+            RowKind::Pair => return,
+            _ => (
+                row.pair,
+                row.path.parent().unwrap_or(Path::new("")).to_path_buf(),
+            ),
+        };
+        if let Some(index) = self.rows.iter().position(|row| {
+            row.pair == pair && row.path == parent && !matches!(row.kind, RowKind::File(_))
+        }) {
+            self.list.select(Some(index));
+            self.set_expanded(false);
+        }
+    }
+
+    async fn show_preview(&mut self) {
+        let Some(row) = self.row() else {
+            return;
+        };
+        if !matches!(row.kind, RowKind::File(_)) {
+            return;
+        }
+        let syncer = &self.syncers[row.pair];
+        let backup = syncer
+            .read_backup(self.at(), &row.path)
+            .await
+            .map_err(anyhow::Error::from);
+        let work = tokio::fs::read(syncer.work_dir().join(&row.path)).await;
+        self.preview = Preview {
+            title: row.path.display().to_string(),
+            lines: preview_lines(backup, work),
+            scroll: 0,
+        };
+    }
+
+    async fn switch_source(&mut self, forward: bool) {
+        let len = self.sources.len();
+        self.source = match forward {
+            true => (self.source + 1) % len,
+            false => (self.source + len - 1) % len,
+        };
+        self.message = match self.selected.is_empty() {
+            true => String::new(),
+            false => "Cleared the selection, it was from another snapshot".to_string(),
+        };
+        self.selected.clear();
+        self.preview = Preview::default();
+        self.load().await;
+    }
+
+    /// What restoring takes, the selection or else the current row
+    fn to_restore(&self) -> Vec<(usize, PathBuf)> {
+        if !self.selected.is_empty() {
+            return self.selected.iter().cloned().collect();
+        }
+        match self.row() {
+            Some(row) => self
+                .files_of(row)
+                .into_iter()
+                .map(|path| (row.pair, path))
+                .collect(),
+            None => Vec::new(),
+        }
+    }
+
+    async fn show(&mut self, terminal: &mut DefaultTerminal) -> Result<Option<Selection>> {
+        loop {
+            terminal.draw(|frame| self.draw(frame))?;
+
+            let Event::Key(key) = tokio::task::block_in_place(event::read)? else {
+                continue;
+            };
+            if key.kind != KeyEventKind::Press {
+                continue;
+            }
+            if self.confirming {
+                self.confirming = false;
+                if key.code != KeyCode::Char('y') {
+                    self.message = "Not restoring".to_string();
+                    continue;
+                }
+                let mut paths: Vec<Vec<PathBuf>> =
+                    self.syncers.iter().map(|_| Vec::new()).collect();
+                for (pair, path) in self.to_restore() {
+                    paths[pair].push(path);
+                }
+                return Ok(Some(Selection {
+                    at: self.at().map(str::to_string),
+                    paths,
+                }));
+            }
+            if self.searching {
+                self.search_key(key);
+                continue;
+            }
+
+            let selected = self.list.selected().unwrap_or(0);
+            match key.code {
+                KeyCode::Char('q') | KeyCode::Esc => return Ok(None),
+                KeyCode::Up | KeyCode::Char('k') => {
+                    self.list.select(Some(selected.saturating_sub(1)))
+                }
+                KeyCode::Down | KeyCode::Char('j') => self
+                    .list
+                    .select(Some((selected + 1).min(self.rows.len().saturating_sub(1)))),
+                KeyCode::Home | KeyCode::Char('g') => self.list.select(Some(0)),
+                KeyCode::End | KeyCode::Char('G') => {
+                    self.list.select(Some(self.rows.len().saturating_sub(1)))
+                }
+                KeyCode::Right | KeyCode::Char('l') => self.set_expanded(true),
+                KeyCode::Left | KeyCode::Char('h') => self.collapse_parent(),
+                KeyCode::Enter => match self.row().map(|row| row.kind) {
+                    Some(RowKind::File(_)) => self.show_preview().await,
+                    Some(_) => {
+                        let expanded = self.row().is_some_and(|row| {
+                            self.expanded.contains(&(row.pair, row.path.clone()))
+                        });
+                        self.set_expanded(!expanded);
+                    }
+                    None => {}
+                },
+                KeyCode::Char('d') => self.show_preview().await,
+                KeyCode::Char(' ') => {
+                    self.toggle_selected();
+                    self.list
+                        .select(Some((selected + 1).min(self.rows.len().saturating_sub(1))));
+                }
+                KeyCode::PageDown => self.preview.scroll = self.preview.scroll.saturating_add(10),
+                KeyCode::PageUp => self.preview.scroll = self.preview.scroll.saturating_sub(10),
+                KeyCode::Char('/') => {
+                    self.searching = true;
+                    self.message.clear();
+                }
+                KeyCode::Tab | KeyCode::Char(']') => self.switch_source(true).await,
+                KeyCode::BackTab | KeyCode::Char('[') => self.switch_source(false).await,
+                KeyCode::Char('r') => match self.to_restore().len() {
+                    0 => self.message = "Nothing to restore here".to_string(),
+                    _ => self.confirming = true,
+                },
+                _ => {}
+            }
+        }
+    }
+
+    fn search_key(&mut self, key: KeyEvent) {
+        match key.code {
+            KeyCode::Esc => {
+                self.searching = false;
+                self.search.clear();
+            }
+            KeyCode::Enter => self.searching = false,
+            KeyCode::Backspace => {
+                self.search.pop();
+            }
+            KeyCode::Char(c) => self.search.push(c),
+            _ => return,
+        }
+        self.list.select(Some(0));
+        self.refresh();
+    }
+
+    fn draw(&mut self, frame: &mut Frame) {
+        let [main_area, footer_area] =
+            Layout::vertical([Constraint::Min(3), Constraint::Length(1)]).areas(frame.area());
+        let [tree_area, preview_area] =
+            Layout::horizontal([Constraint::Fill(1), Constraint::Fill(1)]).areas(main_area);
+
+        let items: Vec<ListItem> = self
+            .rows
+            .iter()
+            .map(|row| {
+                let indent = "  ".repeat(row.depth);
+                let line = match row.kind {
+                    RowKind::File(metadata) => {
+                        let checked = match self.selected.contains(&(row.pair, row.path.clone())) {
+                            true => "[x]",
+                            false => "[ ]",
+                        };
+                        let work_path = self.syncers[row.pair].work_dir().join(&row.path);
+                        let mut line = Line::from(vec![
+                            format!("{indent}{checked} {}  ", row.label).into(),
+                            HumanBytes(metadata.size).to_string().dim(),
+                        ]);
+                        if !work_path.exists() {
+                            line.push_span("  not in work_dir".yellow());
+                        }
+                        line
+                    }
+                    RowKind::Pair | RowKind::Dir => {
+                        let len = match self.search.is_empty() {
+                            true => self.trees[row.pair]
+                                .find(&row.path)
+                                .map_or(0, |dir| dir.len),
+                            false => self.files_of(row).len(),
+                        };
+                        let checked = match self.selected_below(row) {
+                            0 => "[ ]",
+                            n if n == len => "[x]",
+                            _ => "[-]",
+                        };
+                        let expanded = match self.expanded.contains(&(row.pair, row.path.clone()))
+                            || !self.search.is_empty()
+                        {
+                            true => "▾",
+                            false => "▸",
+                        };
+                        let label = format!("{indent}{checked} {expanded} {}/", row.label);
+                        match row.kind {
+                            RowKind::Pair => Line::from(label.bold()),
+                            _ => Line::from(label),
+                        }
+                    }
+                };
+                ListItem::new(line)
+            })
+            .collect();
+        let title = match self.at() {
+            Some(at) => format!(" Snapshot {at} "),
+            None => " Backup ".to_string(),
+        };
+        let list = List::new(items)
+            .block(
+                Block::bordered()
+                    .title(title)
+                    .title_bottom(format!(" {} selected ", self.selected.len())),
+            )
+            .highlight_style(Style::new().reversed());
+        frame.render_stateful_widget(list, tree_area, &mut self.list);
+
+        let preview = Paragraph::new(self.preview.lines.clone())
+            .wrap(Wrap { trim: false })
+            .scroll((self.preview.scroll, 0))
+            .block(
+                Block::bordered().title(match self.preview.title.is_empty() {
+                    true => " Diff against work_dir ".to_string(),
+                    false => format!(" {} ", self.preview.title),
+                }),
+            );
+        frame.render_widget(preview, preview_area);
+
+        let footer = match (self.confirming, self.searching) {
+            (true, _) => Line::from(format!(
+                " Restore {} files into work_dir, replacing what's there? y/n",
+                self.to_restore().len()
+            ))
+            .bold(),
+            (false, true) => Line::from(format!(" /{}", self.search)),
+            (false, false) => Line::from(vec![
+                " q".bold(),
+                " quit  ".into(),
+                "space".bold(),
+                " select  ".into(),
+                "enter".bold(),
+                " open/diff  ".into(),
+                "/".bold(),
+                " search  ".into(),
+                "tab".bold(),
+                " snapshot  ".into(),
+                "r".bold(),
+                " restore  ".into(),
+                self.message.clone().yellow(),
+            ]),
+        };
+        frame.render_widget(Paragraph::new(footer), footer_area);
+    }
+}
+
+/// Adds the rows of the directories and files in `dir`, and of the expanded directories
+/// below, directories first
+fn push_rows(
+    dir: &Dir,
+    pair: usize,
+    path: &Path,
+    depth: usize,
+    expanded: &BTreeSet<(usize, PathBuf)>,
+    rows: &mut Vec<Row>,
+) {
+    for (name, child) in &dir.dirs {
+        let path = path.join(name);
+        let open = expanded.contains(&(pair, path.clone()));
+        rows.push(Row {
+            pair,
+            path: path.clone(),
+            kind: RowKind::Dir,
+            label: name.to_string_lossy().into_owned(),
+            depth,
+        });
+        if open {
+            push_rows(child, pair, &path, depth + 1, expanded, rows);
+        }
+    }
+    for (name, metadata) in &dir.files {
+        rows.push(Row {
+            pair,
+            path: path.join(name),
+            kind: RowKind::File(*metadata),
+            label: name.to_string_lossy().into_owned(),
+            depth,
+        });
+    }
+}
+
+/// How restoring would change the copy in work_dir: lines only there are `-`, lines only
+/// in the backup `+`
+fn preview_lines(backup: Result<Vec<u8>>, work: std::io::Result<Vec<u8>>) -> Vec<Line<'static>> {
+    let backup = match backup {
+        Ok(backup) => backup,
+        Err(err) => return vec![Line::from(format!("{err:#}").red())],
+    };
+    let work = match work {
+        Ok(work) => Some(work),
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+        Err(err) => return vec![Line::from(format!("Error reading work_dir: {err}").red())],
+    };
+    if work.as_ref() == Some(&backup) {
+        return vec![Line::from("Same as in work_dir".dim())];
+    }
+
+    let (Some(new), Some(old)) = (
+        text_lines(&backup),
+        work.as_deref().map_or(Some(Vec::new()), text_lines),
+    ) else {
+        return vec![Line::from(format!(
+            "Binary file, {} in the backup and {} in work_dir",
+            HumanBytes(backup.len() as u64),
+            match &work {
+                Some(work) => HumanBytes(work.len() as u64).to_string(),
+                None => "missing".to_string(),
+            }
+        ))];
+    };
+
+    let mut lines = vec![match work {
+        Some(_) => Line::from("- work_dir  + backup".dim()),
+        None => Line::from("Not in work_dir, restoring brings it back".yellow()),
+    }];
+    let Some(changes) = diff(&old, &new) else {
+        lines.push(Line::from("Too many changes to show"));
+        return lines;
+    };
+
+    // Unchanged lines are only shown around changes
+    let mut index = 0;
+    while index < changes.len() {
+        let same = changes[index..]
+            .iter()
+            .take_while(|(change, _)| *change == Change::Same)
+            .count();
+        if same > 0 {
+            let before = if index == 0 { 0 } else { CONTEXT_LINES };
+            let after = if index + same == changes.len() {
+                0
+            } else {
+                CONTEXT_LINES
+            };
+            match same > before + after + 1 {
+                true => {
+                    for (_, line) in &changes[index..index + before] {
+                        lines.push(Line::from(format!("  {line}").dim()));
+                    }
+                    lines.push(Line::from(
+                        format!("  ⋯ {} unchanged lines", same - before - after).dim(),
+                    ));
+                    for (_, line) in &changes[index + same - after..index + same] {
+                        lines.push(Line::from(format!("  {line}").dim()));
+                    }
+                }
+                false => {
+                    for (_, line) in &changes[index..index + same] {
+                        lines.push(Line::from(format!("  {line}").dim()));
+                    }
+                }
+            }
+            index += same;
+            continue;
+        }
+        let (change, line) = changes[index];
+        lines.push(match change {
+            Change::Removed => Line::from(format!("- {line}").red()),
+            _ => Line::from(format!("+ {line}").green()),
+        });
+        index += 1;
+    }
+    lines
+}
+
+/// The lines of `bytes`, or `None` if it isn't text
+pub fn text_lines(bytes: &[u8]) -> Option<Vec<&str>> {
+    std::str::from_utf8(bytes)
+        .ok()
+        .filter(|text| !text.contains('\0'))
+        .map(|text| text.lines().collect())
+}
+
+#[derive(Clone, Copy, PartialEq, Eq)]
+pub enum Change {
+    Same,
+    Removed,
+    Added,
+}
+
+/// The changes from `old` to `new` line by line, or `None` if they differ in too many lines
+/// to compare
+pub fn diff<'a>(old: &[&'a str], new: &[&'a str]) -> Option<Vec<(Change, &'a str)>> {
+    let prefix = old.iter().zip(new).take_while(|(a, b)| a == b).count();
+    let suffix = old[prefix..]
+        .iter()
+        .rev()
+        .zip(new[prefix..].iter().rev())
+        .take_while(|(a, b)| a == b)
+        .count();
+    let (old_middle, new_middle) = (
+        &old[prefix..old.len() - suffix],
+        &new[prefix..new.len() - suffix],
+    );
+    if old_middle.len().saturating_mul(new_middle.len()) > MAX_DIFF_CELLS {
+        return None;
+    }
+
+    // lengths[i][j] is the longest common subsequence of old_middle[i..] and new_middle[j..]
+    let width = new_middle.len() + 1;
+    let mut lengths = vec![0u32; (old_middle.len() + 1) * width];
+    for i in (0..old_middle.len()).rev() {
+        for j in (0..new_middle.len()).rev() {
+            lengths[i * width + j] = match old_middle[i] == new_middle[j] {
+                true => lengths[(i + 1) * width + j + 1] + 1,
+                false => lengths[(i + 1) * width + j].max(lengths[i * width + j + 1]),
+            };
+        }
+    }
+
+    let mut changes: Vec<(Change, &str)> = old[..prefix]
+        .iter()
+        .map(|line| (Change::Same, *line))
+        .collect();
+    let (mut i, mut j) = (0, 0);
+    while i < old_middle.len() || j < new_middle.len() {
+        if i < old_middle.len() && j < new_middle.len() && old_middle[i] == new_middle[j] {
+            changes.push((Change::Same, old_middle[i]));
+            (i, j) = (i + 1, j + 1);
+        } else if i < old_middle.len()
+            && (j == new_middle.len() || lengths[(i + 1) * width + j] >= lengths[i * width + j + 1])
+        {
+            changes.push((Change::Removed, old_middle[i]));
+            i += 1;
+        } else {
+            changes.push((Change::Added, new_middle[j]));
+            j += 1;
+        }
+    }
+    changes.extend(
+        old[old.len() - suffix..]
+            .iter()
+            .map(|line| (Change::Same, *line)),
+    );
+    Some(changes)
+}
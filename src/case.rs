@@ -0,0 +1,276 @@
+//! Paths that only differ in case, like `README.md` and `readme.md`. A case-insensitive
+//! backup on exFAT, NTFS or APFS stores them as one file, so copying the second would
+//! overwrite the first.
+//!
+//! Of the names in a directory of work_dir that only differ in case, the one that sorts
+//! first keeps its name in the backup. The others collide with it, and are handled by
+//! [`CaseCollisions`]. Renamed ones get ` (case 2)`, ` (case 3)` and so on before their
+//! extension, and are restored under that name
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::{OsStr, OsString},
+    fmt,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+};
+use tracing::warn;
+
+use crate::meta::TEMP_SUFFIX;
+
+/// What to do about a file whose path only differs in case from another one
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum CaseCollisions {
+    /// Log a warning and copy it anyway, overwriting the other one in the backup
+    #[default]
+    #[serde(rename = "warn")]
+    Warn,
+    /// Copy it under a name of its own
+    #[serde(rename = "rename")]
+    Rename,
+    /// Log a warning and leave it out of the backup
+    #[serde(rename = "skip")]
+    Skip,
+    /// Report it as an error and leave it out of the backup
+    #[serde(rename = "error")]
+    Error,
+}
+
+impl FromStr for CaseCollisions {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "rename" => Ok(Self::Rename),
+            "skip" => Ok(Self::Skip),
+            "error" => Ok(Self::Error),
+            _ => Err(format!(
+                "unknown case collision handling {s}, expected warn, rename, skip or error"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for CaseCollisions {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Rename => "rename",
+            Self::Skip => "skip",
+            Self::Error => "error",
+        })
+    }
+}
+
+/// Where a file of work_dir goes in the backup
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Destination {
+    /// Its own path
+    Same,
+    /// A path of its own, with [`CaseCollisions::Rename`]
+    Renamed(PathBuf),
+    /// The path of `with`, relative to work_dir, which keeps its name
+    Collides { with: PathBuf },
+}
+
+/// Finds the paths of one work_dir that collide in a case-insensitive backup
+#[derive(Debug)]
+pub(crate) struct CaseCheck {
+    work_dir: PathBuf,
+    handling: CaseCollisions,
+    /// The entries of the directories looked at so far, until [`CaseCheck::forget`]
+    names: Mutex<HashMap<PathBuf, Arc<Vec<OsString>>>>,
+    /// The colliding paths that were already warned about
+    warned: Mutex<HashSet<PathBuf>>,
+}
+
+impl CaseCheck {
+    pub fn new(work_dir: PathBuf, handling: CaseCollisions) -> Self {
+        Self {
+            work_dir,
+            handling,
+            names: Mutex::default(),
+            warned: Mutex::default(),
+        }
+    }
+
+    /// Where `relative_path` goes in the backup. It doesn't have to exist anymore, a
+    /// removed file is placed as if it still did. This does blocking IO
+    pub fn destination(&self, relative_path: &Path) -> Destination {
+        let mut work_prefix = PathBuf::new();
+        let mut backup_path = PathBuf::new();
+        let mut renamed = false;
+        for component in relative_path.components() {
+            let Component::Normal(name) = component else {
+                work_prefix.push(component);
+                backup_path.push(component);
+                continue;
+            };
+            let folded = fold(name);
+            let siblings = self.names(&self.work_dir.join(&work_prefix));
+            let mut colliding: Vec<&OsStr> = siblings
+                .iter()
+                .map(OsString::as_os_str)
+                .filter(|sibling| fold(sibling) == folded)
+                .chain([name])
+                .collect();
+            colliding.sort();
+            colliding.dedup();
+            let rank = colliding
+                .iter()
+                .position(|sibling| *sibling == name)
+                .unwrap_or(0);
+
+            match (rank, self.handling) {
+                (0, _) => backup_path.push(name),
+                (_, CaseCollisions::Rename) => {
+                    backup_path.push(renamed_name(name, rank + 1));
+                    renamed = true;
+                }
+                _ => {
+                    return Destination::Collides {
+                        with: work_prefix.join(colliding[0]),
+                    }
+                }
+            }
+            work_prefix.push(name);
+        }
+        match renamed {
+            true => Destination::Renamed(backup_path),
+            false => Destination::Same,
+        }
+    }
+
+    /// Where `relative_path` goes in the backup, `None` if it's left out. Warns about a
+    /// collision the first time it's seen, and fails for [`CaseCollisions::Error`]. This
+    /// does blocking IO
+    pub fn backup_path(&self, relative_path: &Path) -> Result<Option<PathBuf>> {
+        let with = match self.destination(relative_path) {
+            Destination::Same => return Ok(Some(relative_path.to_path_buf())),
+            Destination::Renamed(backup_path) => {
+                if self.first_warning(relative_path) {
+                    warn!(
+                        "{} only differs in case from another file, backing it up as {}",
+                        relative_path.display(),
+                        backup_path.display()
+                    );
+                }
+                return Ok(Some(backup_path));
+            }
+            Destination::Collides { with } => with,
+        };
+        match self.handling {
+            CaseCollisions::Error => Err(anyhow!(
+                "{} only differs in case from {}, which it would overwrite in a \
+                 case-insensitive backup",
+                relative_path.display(),
+                with.display()
+            )),
+            handling => {
+                if self.first_warning(relative_path) {
+                    let what = match handling {
+                        CaseCollisions::Skip => "leaving it out of the backup",
+                        _ => "they overwrite each other in the backup",
+                    };
+                    warn!(
+                        "{} only differs in case from {}, {what}",
+                        relative_path.display(),
+                        with.display()
+                    );
+                }
+                Ok((handling == CaseCollisions::Warn).then(|| relative_path.to_path_buf()))
+            }
+        }
+    }
+
+    fn first_warning(&self, relative_path: &Path) -> bool {
+        self.warned
+            .lock()
+            .unwrap()
+            .insert(relative_path.to_path_buf())
+    }
+
+    /// Looks at the directories of work_dir again, since they might have changed
+    pub fn forget(&self) {
+        self.names.lock().unwrap().clear();
+    }
+
+    fn names(&self, dir: &Path) -> Arc<Vec<OsString>> {
+        if let Some(names) = self.names.lock().unwrap().get(dir) {
+            return names.clone();
+        }
+        let names: Vec<OsString> = std::fs::read_dir(dir)
+            .map(|entries| entries.flatten().map(|entry| entry.file_name()).collect())
+            .unwrap_or_default();
+        let names = Arc::new(names);
+        self.names
+            .lock()
+            .unwrap()
+            .insert(dir.to_path_buf(), names.clone());
+        names
+    }
+}
+
+/// Paths that a case-insensitive backup takes to be the same are the same after this
+pub(crate) fn fold(name: &OsStr) -> String {
+    name.to_string_lossy().to_lowercase()
+}
+
+/// `name` with ` (case <n>)` before its extension
+fn renamed_name(name: &OsStr, n: usize) -> OsString {
+    let path = Path::new(name);
+    let mut renamed = path.file_stem().unwrap_or(name).to_os_string();
+    renamed.push(format!(" (case {n})"));
+    if let Some(extension) = path.extension() {
+        renamed.push(".");
+        renamed.push(extension);
+    }
+    renamed
+}
+
+/// The path in work_dir that a backup path with a renamed component belongs to, `None` if
+/// it has none
+pub(crate) fn original_path(backup_path: &Path) -> Option<PathBuf> {
+    let mut original = PathBuf::new();
+    let mut renamed = false;
+    for component in backup_path.components() {
+        let name = component.as_os_str();
+        match original_name(name) {
+            Some(name) => {
+                original.push(name);
+                renamed = true;
+            }
+            None => original.push(name),
+        }
+    }
+    renamed.then_some(original)
+}
+
+fn original_name(name: &OsStr) -> Option<String> {
+    let name = name.to_str()?;
+    let (stem, extension) = match name.rsplit_once('.') {
+        Some((stem, extension)) if stem.ends_with(')') => (stem, Some(extension)),
+        _ => (name, None),
+    };
+    let (stem, n) = stem.strip_suffix(')')?.rsplit_once(" (case ")?;
+    n.parse::<usize>().ok().filter(|&n| n >= 2)?;
+    Some(match extension {
+        Some(extension) => format!("{stem}.{extension}"),
+        None => stem.to_string(),
+    })
+}
+
+/// Whether `dir` can't tell names apart that only differ in case. This does blocking IO
+pub(crate) fn is_case_insensitive(dir: &Path) -> bool {
+    let probe = dir.join(format!("Case-Probe{TEMP_SUFFIX}"));
+    if std::fs::write(&probe, "").is_err() {
+        return false;
+    }
+    let insensitive = dir.join(format!("case-probe{TEMP_SUFFIX}")).exists();
+    let _ = std::fs::remove_file(&probe);
+    insensitive
+}
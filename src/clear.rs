@@ -0,0 +1,188 @@
+//! Removing everything inside work_dir before it's initialized from scratch.
+//!
+//! Clearing carries on past what it can't remove, so work_dir isn't left half cleared by
+//! the first immutable file, and reports all of it at the end. Write protection is lifted
+//! from what's removed anyway, and mount points inside work_dir are left alone unless told
+//! otherwise, so clearing never reaches into another filesystem by accident
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// Something clearing couldn't remove, with why
+#[derive(Debug)]
+pub struct Leftover {
+    pub path: PathBuf,
+    pub error: io::Error,
+}
+
+/// Removes everything inside `dir`, and returns what it couldn't. Mount points inside of it
+/// are only emptied with `cross_mounts`, their directories are always left. This does
+/// blocking IO
+pub fn clear_dir(dir: &Path, cross_mounts: bool) -> io::Result<Vec<Leftover>> {
+    let device = device(&fs::metadata(dir)?);
+    let mut leftovers = Vec::new();
+    for entry in fs::read_dir(dir)? {
+        remove(&entry?.path(), device, cross_mounts, &mut leftovers);
+    }
+    Ok(leftovers)
+}
+
+/// Whether `path`, directly inside `dir`, is a directory another filesystem is mounted on
+pub fn is_mount_point(dir: &Path, path: &Path) -> io::Result<bool> {
+    let metadata = fs::symlink_metadata(path)?;
+    Ok(metadata.is_dir() && device(&metadata) != device(&fs::metadata(dir)?))
+}
+
+/// Why something mounted inside work_dir wasn't cleared
+pub fn mount_point_error() -> io::Error {
+    io::Error::other("it's mounted inside work_dir, pass --cross-mounts to clear it too")
+}
+
+/// Removes the file at `path` inside `dir`, making it writable first if it has to. Fails
+/// for files on a filesystem mounted inside `dir`, unless `cross_mounts`. This does blocking
+/// IO
+pub fn remove_file_in(dir: &Path, path: &Path, cross_mounts: bool) -> io::Result<()> {
+    check_filesystem(dir, path, cross_mounts)?;
+    match retry_writable(path, || fs::remove_file(path)) {
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+        result => result,
+    }
+}
+
+/// Fails if `path` is on another filesystem than `dir`, unless `cross_mounts`
+pub fn check_filesystem(dir: &Path, path: &Path, cross_mounts: bool) -> io::Result<()> {
+    if cross_mounts {
+        return Ok(());
+    }
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err),
+    };
+    match device(&metadata) == device(&fs::metadata(dir)?) {
+        true => Ok(()),
+        false => Err(mount_point_error()),
+    }
+}
+
+/// Removes `path` and everything in it, adding what it couldn't to `leftovers`. Returns
+/// whether it's gone
+fn remove(
+    path: &Path,
+    parent_device: Option<u64>,
+    cross_mounts: bool,
+    leftovers: &mut Vec<Leftover>,
+) -> bool {
+    // Links are removed rather than followed, so clearing never touches their targets.
+    // FIFOs, sockets and devices are removed like files
+    let metadata = match fs::symlink_metadata(path) {
+        Ok(metadata) => metadata,
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return true,
+        Err(err) => return leave(leftovers, path, err),
+    };
+    if !metadata.is_dir() {
+        return match retry_writable(path, || fs::remove_file(path)) {
+            Ok(()) => true,
+            Err(err) => leave(leftovers, path, err),
+        };
+    }
+
+    let own_device = device(&metadata);
+    let is_mount = own_device != parent_device;
+    if is_mount && !cross_mounts {
+        return leave(leftovers, path, mount_point_error());
+    }
+    // A directory that can't be read or written to can be made so, it's going anyway
+    let entries = match retry_writable(path, || fs::read_dir(path)?.collect::<io::Result<Vec<_>>>())
+    {
+        Ok(entries) => entries,
+        Err(err) => return leave(leftovers, path, err),
+    };
+    // Removing entries needs write access to the directory they're in, which mount points
+    // keep as they are
+    if !is_mount {
+        let _ = make_writable(path);
+    }
+    let mut emptied = true;
+    for entry in entries {
+        emptied &= remove(&entry.path(), own_device, cross_mounts, leftovers);
+    }
+    // Mount points can't be removed, only emptied
+    if !emptied || is_mount {
+        return false;
+    }
+    match retry_writable(path, || fs::remove_dir(path)) {
+        Ok(()) => true,
+        Err(err) => leave(leftovers, path, err),
+    }
+}
+
+/// Adds `path` to `leftovers`, and returns that it isn't gone
+fn leave(leftovers: &mut Vec<Leftover>, path: &Path, error: io::Error) -> bool {
+    leftovers.push(Leftover {
+        path: path.to_path_buf(),
+        error,
+    });
+    false
+}
+
+/// Runs `op` on `path`, and again after making `path` and the directory it's in writable if
+/// that wasn't allowed. Immutable files still can't be removed then
+fn retry_writable<T>(path: &Path, op: impl Fn() -> io::Result<T>) -> io::Result<T> {
+    match op() {
+        Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {
+            let _ = make_writable(path);
+            if let Some(parent) = path.parent() {
+                let _ = make_writable(parent);
+            }
+            op()
+        }
+        result => result,
+    }
+}
+
+/// Lets the owner read, write and list `path`, or on Windows lifts its read-only attribute
+fn make_writable(path: &Path) -> io::Result<()> {
+    let metadata = fs::symlink_metadata(path)?;
+    if metadata.is_symlink() {
+        return Ok(());
+    }
+    let mut permissions = metadata.permissions();
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::PermissionsExt;
+        let mode = match metadata.is_dir() {
+            true => 0o700,
+            false => 0o600,
+        };
+        if permissions.mode() & mode == mode {
+            return Ok(());
+        }
+        permissions.set_mode(permissions.mode() | mode);
+    }
+    #[cfg(not(unix))]
+    {
+        if !permissions.readonly() {
+            return Ok(());
+        }
+        #[allow(clippy::permissions_set_readonly_false)]
+        permissions.set_readonly(false);
+    }
+    fs::set_permissions(path, permissions)
+}
+
+/// The filesystem `metadata` is on, where that can be told
+fn device(metadata: &fs::Metadata) -> Option<u64> {
+    #[cfg(unix)]
+    {
+        use std::os::unix::fs::MetadataExt;
+        Some(metadata.dev())
+    }
+    #[cfg(not(unix))]
+    {
+        let _ = metadata;
+        None
+    }
+}
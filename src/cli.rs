@@ -0,0 +1,1194 @@
+//! The command line arguments of evil_mount, which [`crate::config`] merges with the config
+//! file
+
+use clap::{builder::RangedU64ValueParser, Parser, Subcommand};
+use serde::{Deserialize, Serialize};
+use std::{fmt, net::SocketAddr, path::PathBuf, str::FromStr, time::Duration};
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+use crate::mount::Replicate;
+use crate::{
+    audit::AuditOp,
+    backend::{BackupFormat, Compression, HostKeyCheck, SanitizeNames},
+    control::ControlCommand,
+    copy::{Durability, Preserve, Reflink, Sparse, VerifyWrites},
+    filter::Symlinks,
+    merge::{InitMode, MergePolicy},
+    signing::PublicKey,
+    CaseCollisions, ConflictStrategy, DetectChanges, ErrorPolicy, GuardBackup, NormalizeUnicode,
+    NotifyTarget, QuietHours, QuotaPolicy, Rotate, SyncWindow,
+};
+
+/// A program to backup files to a different directory
+#[derive(Parser, Debug)]
+#[command(author, version, about, long_about = None)]
+pub struct Args {
+    #[command(subcommand)]
+    pub command: Command,
+
+    /// Log more, `-v` includes every copied file and `-vv` everything else
+    #[arg(short, long, action = clap::ArgAction::Count, global = true)]
+    pub verbose: u8,
+
+    /// Log less, `-q` only logs warnings and errors and `-qq` only errors
+    #[arg(short, long, action = clap::ArgAction::Count, global = true, conflicts_with = "verbose")]
+    pub quiet: u8,
+
+    /// How log lines are written: `text` or `json` (one object per line)
+    #[arg(long, value_name = "FORMAT", default_value_t, global = true)]
+    pub log_format: LogFormat,
+
+    /// Export traces of scans, diffs and copies to this OTLP/HTTP collector, like
+    /// `http://localhost:4318`. Defaults to `$OTEL_EXPORTER_OTLP_ENDPOINT`
+    #[arg(long, value_name = "URL", global = true)]
+    pub otel_endpoint: Option<String>,
+}
+
+/// How log lines are formatted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum LogFormat {
+    #[default]
+    Text,
+    Json,
+}
+
+impl FromStr for LogFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown log format {s}, expected text or json")),
+        }
+    }
+}
+
+impl fmt::Display for LogFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// The shells `evil_mount completions` writes scripts for
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Shell {
+    Bash,
+    Zsh,
+    Fish,
+    Powershell,
+}
+
+impl FromStr for Shell {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bash" => Ok(Self::Bash),
+            "zsh" => Ok(Self::Zsh),
+            "fish" => Ok(Self::Fish),
+            "powershell" => Ok(Self::Powershell),
+            _ => Err(format!(
+                "unknown shell {s}, expected bash, zsh, fish or powershell"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Shell {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Bash => "bash",
+            Self::Zsh => "zsh",
+            Self::Fish => "fish",
+            Self::Powershell => "powershell",
+        })
+    }
+}
+
+/// How `sync` reports what it does on stdout
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum OutputFormat {
+    /// Nothing but the log lines on stderr, and the summary of `--once`
+    #[default]
+    #[serde(rename = "text")]
+    Text,
+    /// One JSON object per event
+    #[serde(rename = "json")]
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(Self::Text),
+            "json" => Ok(Self::Json),
+            _ => Err(format!("unknown output format {s}, expected text or json")),
+        }
+    }
+}
+
+impl fmt::Display for OutputFormat {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Text => "text",
+            Self::Json => "json",
+        })
+    }
+}
+
+/// A number of bytes, parsed from a plain number or a human friendly size like `500M`,
+/// `1.5GiB` or `2GB`. `K`, `M`, `G` and `T` on their own and with `iB` are powers of 1024,
+/// with `B` they are powers of 1000
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawByteSize", into = "u64")]
+pub struct ByteSize(pub u64);
+
+impl FromStr for ByteSize {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let split = s
+            .find(|c: char| !c.is_ascii_digit() && c != '.')
+            .unwrap_or(s.len());
+        let (number, unit) = s.split_at(split);
+        let number: f64 = number
+            .parse()
+            .map_err(|_| format!("invalid size {s}, expected a number like 500M or 2GiB"))?;
+        let multiplier: u64 = match unit.trim().to_ascii_uppercase().as_str() {
+            "" | "B" => 1,
+            "K" | "KIB" => 1 << 10,
+            "M" | "MIB" => 1 << 20,
+            "G" | "GIB" => 1 << 30,
+            "T" | "TIB" => 1 << 40,
+            "KB" => 1_000,
+            "MB" => 1_000_000,
+            "GB" => 1_000_000_000,
+            "TB" => 1_000_000_000_000,
+            _ => return Err(format!("unknown unit {unit} in {s}, expected K, M, G or T")),
+        };
+        Ok(Self((number * multiplier as f64) as u64))
+    }
+}
+
+impl fmt::Display for ByteSize {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{}", self.0)
+    }
+}
+
+/// An address to listen on, where `:8080` is short for every interface
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct ListenAddr(pub SocketAddr);
+
+impl FromStr for ListenAddr {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let addr = match s.starts_with(':') {
+            true => format!("0.0.0.0{s}"),
+            false => s.to_string(),
+        };
+        addr.parse().map(Self).map_err(|_| {
+            format!("invalid address {s}, expected something like 0.0.0.0:8080 or :8080")
+        })
+    }
+}
+
+impl TryFrom<String> for ListenAddr {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl From<ListenAddr> for String {
+    fn from(addr: ListenAddr) -> Self {
+        addr.0.to_string()
+    }
+}
+
+/// A length of time, parsed from a plain number of seconds or a human friendly duration
+/// like `200ms`, `30s`, `5m` or `1h30m`
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "RawHumanDuration", into = "String")]
+pub struct HumanDuration(pub Duration);
+
+impl FromStr for HumanDuration {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let invalid = || format!("invalid duration {s}, expected something like 200ms, 30s or 5m");
+        if s.is_empty() {
+            return Err(invalid());
+        }
+        if let Ok(secs) = s.parse::<f64>() {
+            return Duration::try_from_secs_f64(secs)
+                .map(Self)
+                .map_err(|_| invalid());
+        }
+
+        let mut duration = Duration::ZERO;
+        let mut rest = s;
+        while !rest.is_empty() {
+            let split = rest
+                .find(|c: char| !c.is_ascii_digit() && c != '.')
+                .unwrap_or(rest.len());
+            let (number, unit) = rest.split_at(split);
+            let unit_len = unit
+                .find(|c: char| c.is_ascii_digit())
+                .unwrap_or(unit.len());
+            let (unit, next) = unit.split_at(unit_len);
+            let number: f64 = number.parse().map_err(|_| invalid())?;
+            let secs = match unit.trim() {
+                "ms" => number / 1000.0,
+                "s" | "sec" => number,
+                "m" | "min" => number * 60.0,
+                "h" => number * 60.0 * 60.0,
+                "d" => number * 24.0 * 60.0 * 60.0,
+                unit => {
+                    return Err(format!(
+                        "unknown unit {unit} in {s}, expected ms, s, m, h or d"
+                    ))
+                }
+            };
+            duration += Duration::try_from_secs_f64(secs).map_err(|_| invalid())?;
+            rest = next.trim_start();
+        }
+        Ok(Self(duration))
+    }
+}
+
+impl fmt::Display for HumanDuration {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self.0.subsec_millis() {
+            0 => write!(f, "{}s", self.0.as_secs()),
+            _ => write!(f, "{}ms", self.0.as_millis()),
+        }
+    }
+}
+
+/// Config files can give durations as a plain number of seconds or as a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawHumanDuration {
+    Secs(u64),
+    Human(String),
+}
+
+impl TryFrom<RawHumanDuration> for HumanDuration {
+    type Error = String;
+
+    fn try_from(raw: RawHumanDuration) -> Result<Self, Self::Error> {
+        match raw {
+            RawHumanDuration::Secs(secs) => Ok(Self(Duration::from_secs(secs))),
+            RawHumanDuration::Human(s) => s.parse(),
+        }
+    }
+}
+
+impl From<HumanDuration> for String {
+    fn from(duration: HumanDuration) -> Self {
+        duration.to_string()
+    }
+}
+
+impl From<HumanDuration> for Duration {
+    fn from(duration: HumanDuration) -> Self {
+        duration.0
+    }
+}
+
+/// Config files can give sizes as a plain number of bytes or as a string
+#[derive(Deserialize)]
+#[serde(untagged)]
+enum RawByteSize {
+    Bytes(u64),
+    Human(String),
+}
+
+impl TryFrom<RawByteSize> for ByteSize {
+    type Error = String;
+
+    fn try_from(raw: RawByteSize) -> Result<Self, Self::Error> {
+        match raw {
+            RawByteSize::Bytes(bytes) => Ok(Self(bytes)),
+            RawByteSize::Human(s) => s.parse(),
+        }
+    }
+}
+
+impl From<ByteSize> for u64 {
+    fn from(size: ByteSize) -> Self {
+        size.0
+    }
+}
+
+// Parsed once, so the size of the biggest variant doesn't matter
+#[allow(clippy::large_enum_variant)]
+#[derive(Subcommand, Debug)]
+pub enum Command {
+    /// Initialize work_dir from backup_dir, then keep copying changes back into backup_dir
+    Sync {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        #[command(flatten)]
+        init: InitArgs,
+
+        #[command(flatten)]
+        sync: SyncArgs,
+    },
+    /// Initialize work_dir from backup_dir and exit
+    Init {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        #[command(flatten)]
+        init: InitArgs,
+    },
+    /// Mount work_dir as a FUSE filesystem over backup_dir, or over a staging directory
+    /// initialized from it, and replicate every change made through it to backup_dir as it
+    /// happens. Runs until interrupted, then unmounts
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    Mount {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        #[command(flatten)]
+        init: InitArgs,
+
+        #[command(flatten)]
+        mount: MountArgs,
+    },
+    /// Copy everything in backup_dir into work_dir without clearing work_dir first, or only
+    /// the given files and directories
+    Restore {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// Files or directories in work_dir to restore, leaving everything else alone
+        #[arg(value_name = "PATH")]
+        paths: Vec<PathBuf>,
+
+        /// Restore from a snapshot instead of the current backup. Takes a snapshot name
+        /// like 2024-05-01T12-00-00Z, or a prefix of one to pick the newest match
+        #[arg(long, value_name = "TIMESTAMP")]
+        at: Option<String>,
+
+        /// Pick what to restore in a browser over the backup and its snapshots, which can
+        /// search, select several files and preview how they differ from work_dir. --at
+        /// picks the snapshot it starts on. With PATHs, show how each file in them differs
+        /// and ask whether to restore it instead
+        #[arg(long)]
+        interactive: bool,
+
+        /// Only show what restoring would change in work_dir: a diff of every text file
+        /// and the sizes and hashes of binary ones. Restoring from a snapshot also tells
+        /// whether the copy in work_dir is still in the current backup
+        #[arg(long, conflicts_with = "interactive")]
+        preview: bool,
+    },
+    /// Remove old snapshots and files set aside by --use-trash from backup_dir/.evilmount.
+    /// Anything matching either retention rule is kept
+    Prune {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// Keep the newest N snapshots, and the newest N sets of cleared files
+        #[arg(long, value_name = "N")]
+        keep_last: Option<usize>,
+
+        /// Keep everything younger than D days
+        #[arg(long, value_name = "D")]
+        keep_days: Option<u64>,
+    },
+    /// Compare the size and hash of every file in work_dir and backup_dir and write the hashes
+    /// to backup_dir/.evilmount/MANIFEST.b3. Exits with an error if they differ
+    Verify {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// Also check the chain of manifests signed with --sign-key: every signature, that
+        /// none were removed or reordered, and that the backup still matches the newest
+        #[arg(long)]
+        check_signature: bool,
+
+        /// The public key to check signatures with, as printed when signing started, for
+        /// checking without the --sign-key
+        #[arg(long, value_name = "HEX", requires = "check_signature")]
+        public_key: Option<PublicKey>,
+    },
+    /// Print the files that differ between work_dir and backup_dir, compared the way
+    /// initialization compares them: `A` for files only in work_dir, `M` for modified ones
+    /// and `D` for files only in backup_dir
+    Diff {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// Compare file contents instead of size and modification time
+        #[arg(long)]
+        hash: bool,
+
+        /// Also print the size of every file and how many bytes differ in total
+        #[arg(long)]
+        stat: bool,
+
+        /// Print the differences of every pair as JSON, sizes included
+        #[arg(long)]
+        json: bool,
+
+        /// Scan both trees even when a running sync keeps an index of them. The index only
+        /// has files that changed in work_dir once they're synced
+        #[arg(long)]
+        rescan: bool,
+    },
+    /// Print when backup_dir was last synced and whether it is up to date. With the
+    /// --control-socket of a running sync, print what it is doing instead: when it last
+    /// synced, how many files are pending, its latest errors and its throughput
+    Status {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// The --control-socket of a running sync, defaults to control_socket from the
+        /// config file
+        #[arg(short, long, value_name = "PATH")]
+        socket: Option<PathBuf>,
+
+        /// Show a dashboard of the running sync that updates live, until q is pressed
+        #[arg(long)]
+        tui: bool,
+
+        /// Scan both trees even when a running sync keeps an index of them
+        #[arg(long)]
+        rescan: bool,
+    },
+    /// Print the audit log --audit-log keeps of every copy, delete, rename and restore in
+    /// backup_dir, oldest first, or only the operations matching all of the filters given
+    Audit {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        /// Only operations on this file, or on files in this directory, relative to work_dir.
+        /// Renames match either of their paths
+        #[arg(long, value_name = "PATH")]
+        path: Option<PathBuf>,
+
+        /// Only `copy`, `delete`, `rename` or `restore`
+        #[arg(long, value_name = "OP")]
+        op: Option<AuditOp>,
+
+        /// Only operations in the last DURATION, like 30m or 7d
+        #[arg(long, value_name = "DURATION")]
+        since: Option<HumanDuration>,
+
+        /// Only operations that failed
+        #[arg(long)]
+        failed: bool,
+
+        /// Print the matching records as JSON, one per line as they are in the log
+        #[arg(long)]
+        json: bool,
+    },
+    /// Send `pause`, `resume`, `sync-now`, `reload` or `status` to a running `sync` and print
+    /// its reply. Resuming and `sync-now` copy everything that is newer than its backup, and
+    /// `reload` reads the config file again
+    Control {
+        /// The --control-socket of the running sync
+        #[arg(short, long, value_name = "PATH")]
+        socket: PathBuf,
+
+        command: ControlCommand,
+    },
+    /// Carry the hash cache and what every file looked like when it was last synced over to
+    /// another machine, so the first sync there doesn't hash or copy everything again
+    State {
+        #[command(subcommand)]
+        command: StateCommand,
+    },
+    /// Manage config files
+    Config {
+        #[command(subcommand)]
+        command: ConfigCommand,
+    },
+    /// Run evil_mount as a service
+    Service {
+        #[command(subcommand)]
+        command: ServiceCommand,
+    },
+    /// Print a completion script for `bash`, `zsh`, `fish` or `powershell`, like
+    /// `evil_mount completions bash > /etc/bash_completion.d/evil_mount`
+    Completions { shell: Shell },
+    /// Print the man page, like `evil_mount man > ~/.local/share/man/man1/evil_mount.1`
+    Man,
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ServiceCommand {
+    /// Write a systemd user unit, or a launchd agent on macOS, that runs `evil_mount sync`
+    /// with the arguments after `--`, from the current directory
+    Install {
+        /// What to call the service
+        #[arg(long, default_value = "evil_mount")]
+        name: String,
+
+        /// Print the unit instead of writing it
+        #[arg(long)]
+        print: bool,
+
+        /// Arguments for `evil_mount sync`, e.g. `-- -c evil_mount.toml`
+        #[arg(last = true, required = true, value_name = "ARGS")]
+        args: Vec<String>,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum StateCommand {
+    /// Write the state of every pair to FILE
+    Export {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        #[arg(value_name = "FILE")]
+        path: PathBuf,
+    },
+    /// Replace the state of every pair with the one exported to FILE, matched up with the
+    /// pairs in order. Files need to have kept their modification times on the way over for
+    /// the state to still match them
+    Import {
+        #[command(flatten)]
+        dirs: DirArgs,
+
+        #[arg(value_name = "FILE")]
+        path: PathBuf,
+
+        /// Import even if another evil_mount seems to be syncing backup_dir
+        #[arg(long)]
+        force_lock: bool,
+    },
+}
+
+#[derive(Subcommand, Debug)]
+pub enum ConfigCommand {
+    /// Write a commented config file template
+    Init {
+        /// Where to write the template, prints it to stdout if not given
+        path: Option<PathBuf>,
+    },
+}
+
+/// Options shared by every command that works on a work_dir and backup_dir pair
+#[derive(clap::Args, Debug, Clone)]
+pub struct DirArgs {
+    /// Read settings from a TOML config file, flags given here take precedence
+    #[arg(short, long, value_name = "FILE")]
+    pub config: Option<PathBuf>,
+
+    /// Layer the settings of the `[profiles.<NAME>]` section of the config file over the
+    /// ones at its top, like `laptop` or `workstation`
+    #[arg(long, value_name = "NAME", requires = "config")]
+    pub profile: Option<String>,
+
+    /// The directory that you will be working in, will be completely cleared. Can be
+    /// repeated along with --backup-dir to sync several pairs, matched up in order
+    #[arg(short, long)]
+    pub work_dir: Vec<PathBuf>,
+
+    /// The directory that will be copied to. Used to initialize source dir.
+    /// Can also be `sftp://[user@]host[:port]/path` or `ssh://...`, `s3://bucket/prefix`,
+    /// `dav://host/path` for WebDAV, or an archive ending in .tar, .tar.gz, .tar.zst or .zip.
+    /// Variables like `/mnt/nas/{hostname}/{profile}/{date}` are expanded: `{hostname}`,
+    /// `{user}`, `{profile}`, `{date}`, `{year}`, `{month}`, `{day}` and `{hour}`, with `{{`
+    /// and `}}` for braces. A sync moves on to the new directory once the date changes
+    #[arg(short, long)]
+    pub backup_dir: Vec<String>,
+
+    /// Skip paths matching this .gitignore-style glob, can be repeated.
+    /// Patterns are also read from a .evilmountignore file in work_dir
+    #[arg(short, long, value_name = "GLOB")]
+    pub exclude: Vec<String>,
+
+    /// Sync paths matching this glob even if they are excluded, can be repeated
+    #[arg(short, long, value_name = "GLOB")]
+    pub include: Vec<String>,
+
+    /// Change files matching GLOB on their way into the backup, like `.env=redact:^SECRET`,
+    /// can be repeated. TRANSFORM is `redact:REGEX`, which leaves out the lines matching
+    /// it, `crlf-to-lf` or `gzip`. Their backups are never copied back into work_dir
+    #[arg(long, value_name = "GLOB=TRANSFORM")]
+    pub transform: Vec<String>,
+
+    /// Hold a shared lock on every file while it's copied, so programs that lock the files
+    /// they write, like SQLite, wait for the copy instead of leaving it half written
+    #[arg(long)]
+    pub lock_before_copy: bool,
+
+    /// Copy files matching this glob atomically or not at all, can be repeated. They're
+    /// copied under a lock, and only if they didn't change while they were read.
+    /// Otherwise they're skipped until the next cycle
+    #[arg(long, value_name = "GLOB")]
+    pub atomic_copy: Vec<String>,
+
+    /// Don't copy files another program has open for writing, try them again next cycle
+    /// until it closes them. Found in /proc on Linux, with lsof on other Unixes, and by
+    /// sharing mode on Windows
+    #[arg(long)]
+    pub skip_open_files: bool,
+
+    /// Also skip what git ignores: what .gitignore files anywhere in work_dir say, along
+    /// with .git/info/exclude and the global excludes of git. --include still wins
+    #[arg(long)]
+    pub respect_gitignore: bool,
+
+    /// Only sync the paths listed in FILE, one per line and relative to work_dir, like the
+    /// output of `git diff --name-only`. Initialization and syncing leave everything else
+    /// alone, and listed directories are synced with everything in them. `-` reads the list
+    /// from stdin
+    #[arg(long, value_name = "FILE")]
+    pub files_from: Option<PathBuf>,
+
+    /// Paths in the --files-from list are separated by NUL characters instead of newlines,
+    /// like the output of `find -print0` or `git diff -z --name-only`
+    #[arg(long)]
+    pub from0: bool,
+
+    /// Only scan and sync this directory or file, relative to work_dir, can be repeated.
+    /// Nothing outside of them is copied or removed from the backup. With --files-from,
+    /// only the listed paths inside them are synced
+    #[arg(long, value_name = "PATH")]
+    pub only: Vec<PathBuf>,
+
+    /// Leave out paths more than this many directories deep, where the files directly in
+    /// work_dir are at depth 1
+    #[arg(long, value_name = "DEPTH")]
+    pub max_depth: Option<usize>,
+
+    /// Don't descend into directories on other filesystems, like mount points or links to /
+    #[arg(long)]
+    pub same_filesystem: bool,
+
+    /// Skip files and directories in work_dir that Windows marks as hidden
+    #[arg(long)]
+    pub skip_hidden: bool,
+
+    /// Skip files and directories in work_dir that Windows marks as system files
+    #[arg(long)]
+    pub skip_system: bool,
+
+    /// Stop when something in work_dir can't be read, like a directory without permission.
+    /// Otherwise it's left out with a warning, and counted in the statistics
+    #[arg(long)]
+    pub fail_on_walk_error: bool,
+
+    /// How many files to hash at once when verifying or detecting changes by hash
+    /// [default: one per core]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub hash_threads: Option<usize>,
+
+    /// Map big files into memory to hash them instead of reading them, which is faster on
+    /// fast disks. A file on a network mount that's truncated while it's hashed can crash
+    /// evil_mount
+    #[arg(long)]
+    pub mmap: bool,
+
+    #[command(flatten)]
+    pub copy: CopyArgs,
+
+    /// Allow work_dir and backup_dir to be the same directory or to be inside one another
+    #[arg(long)]
+    pub allow_overlap: bool,
+
+    /// Only sync files, leaving out directories that have none in them. By default every
+    /// directory is recreated, empty ones included
+    #[arg(long)]
+    pub no_empty_dirs: bool,
+
+    /// Print what would be copied and deleted without changing anything
+    #[arg(long)]
+    pub dry_run: bool,
+
+    /// Encrypt everything stored in backup_dir, with the key in --key-file or the passphrase
+    /// in the EVILMOUNT_PASSPHRASE environment variable. Restoring and verifying decrypt it
+    #[arg(long)]
+    pub encrypt: bool,
+
+    /// A file of at least 32 bytes holding the key for --encrypt, e.g. from
+    /// `head -c 32 /dev/urandom`. Implies --encrypt
+    #[arg(long, value_name = "FILE")]
+    pub key_file: Option<PathBuf>,
+
+    /// Also encrypt file and directory names, which can then be 131 bytes long at most. Only
+    /// takes effect when a backup is first encrypted
+    #[arg(long)]
+    pub encrypt_names: bool,
+
+    /// Sign a manifest of backup_dir's files with the ed25519 key derived from FILE, at
+    /// least 32 bytes like from `head -c 32 /dev/urandom`, after every cycle that changed
+    /// something. Manifests are chained, see `verify --check-signature`. Copies are hashed as
+    /// with `--verify-writes hash` unless told otherwise. Local backups only
+    #[arg(long, value_name = "FILE")]
+    pub sign_key: Option<PathBuf>,
+
+    /// Log every copy, delete, rename and restore in backup_dir/.evilmount/audit.log, with
+    /// its time, the size and hash of the file and whether it worked, see `evil_mount
+    /// audit`. Copies that aren't verified with a hash are hashed again for it. Local
+    /// backups only
+    #[arg(long)]
+    pub audit_log: bool,
+
+    /// Compress files stored in backup_dir with `zstd` or `gzip`, optionally at a level like
+    /// `zstd:9`, skipping ones that are compressed already. Restoring and verifying
+    /// decompress them with or without this
+    #[arg(long, value_name = "ALGO[:LEVEL]")]
+    pub compress: Option<Compression>,
+
+    /// Store files whose names Windows, FAT or NTFS can't, like ones with `:` or `?` in them
+    /// or too long, under a name that works everywhere: `encode` percent-encodes
+    /// what doesn't work, `replace` replaces it with `_`, and `skip` leaves the file out and
+    /// reports it. Restoring gives the original names back with or without this
+    #[arg(long, value_name = "MODE")]
+    pub sanitize_names: Option<SanitizeNames>,
+
+    /// How a local backup_dir is laid out: `mirror` copies work_dir as it is, `cas` stores
+    /// every distinct file once under its hash, with a tree of the files for every sync.
+    /// Without this, an existing backup keeps its format
+    #[arg(long, value_name = "FORMAT")]
+    pub backup_format: Option<BackupFormat>,
+
+    /// Whether sftp:// and ssh:// backups connect to hosts missing from the known hosts
+    /// file: `strict` refuses them, `accept-new` adds them and `off` trusts any key
+    #[arg(long, value_name = "MODE")]
+    pub ssh_host_key_check: Option<HostKeyCheck>,
+
+    /// The known hosts file sftp:// and ssh:// backups check hosts against, instead of
+    /// ~/.ssh/known_hosts
+    #[arg(long, value_name = "FILE")]
+    pub ssh_known_hosts: Option<PathBuf>,
+
+    /// The private key sftp:// and ssh:// backups log in with, instead of what ssh picks
+    #[arg(long, value_name = "FILE")]
+    pub ssh_key: Option<PathBuf>,
+}
+
+/// Options for how individual files are copied
+#[derive(clap::Args, Debug, Clone)]
+pub struct CopyArgs {
+    /// Flush every copied file to disk before moving it into place, the same as
+    /// --durability data
+    #[arg(long)]
+    pub fsync: bool,
+
+    /// How much of every copy is flushed to disk before it counts as done: `none`, `data`
+    /// to flush its data before it's moved into place, or `full` to also flush its metadata
+    /// and the directory it's moved into, so a power loss can't undo the move
+    #[arg(long, value_name = "MODE")]
+    pub durability: Option<Durability>,
+
+    /// Metadata to carry over to copies, a comma separated list of `mode`, `times`, `owner`
+    /// and `xattr`. Only applies to local copies
+    #[arg(long, value_name = "ATTRS")]
+    pub preserve: Option<Preserve>,
+
+    /// What to do with symbolic links: `follow` them, `recreate` the link itself, or `skip`
+    /// them [default: follow]
+    #[arg(long, value_name = "POLICY")]
+    pub symlinks: Option<Symlinks>,
+
+    /// Only follow links to directories that lead into this absolute path or glob, like
+    /// /mnt/photos or /home/*/notes, can be repeated. What they point to is backed up under
+    /// the path of the link, other links to directories are skipped, or recreated with
+    /// --symlinks recreate. Links to files are still handled by --symlinks
+    #[arg(long, value_name = "PATH")]
+    pub follow_into: Vec<String>,
+
+    /// Skip files larger than this, like `500M` or `2GiB`, when initializing and syncing
+    #[arg(long, value_name = "SIZE")]
+    pub max_file_size: Option<ByteSize>,
+
+    /// Only write the changed blocks of files of at least this many MiB when backup_dir
+    /// already has a copy, instead of copying them completely. Only applies to local copies
+    #[arg(long, value_name = "MIB")]
+    pub delta_min_size: Option<u64>,
+
+    /// Make copies as copy-on-write clones when work_dir and backup_dir share a Btrfs, XFS or
+    /// APFS filesystem: `auto` falls back to copying, `always` fails instead, `never` always
+    /// copies [default: auto]
+    #[arg(long, value_name = "MODE")]
+    pub reflink: Option<Reflink>,
+
+    /// Keep sparse files sparse in backup_dir: `auto` keeps the holes of files that have
+    /// any, `always` also turns runs of zeros into holes, `never` copies the data as it is
+    /// [default: auto]
+    #[arg(long, value_name = "MODE")]
+    pub sparse: Option<Sparse>,
+
+    /// Check every copy into backup_dir against what was read: `hash` hashes the data as it's
+    /// copied and checks that all of it was written, `read-back` also reads the copy back to
+    /// compare hashes. The hashes are recorded in backup_dir/.evilmount/MANIFEST.b3
+    /// [default: off, or hash without a MODE]
+    #[arg(long, value_name = "MODE", num_args = 0..=1, default_missing_value = "hash")]
+    pub verify_writes: Option<VerifyWrites>,
+
+    /// Copy files that other programs keep locked, and that stay locked after a few tries,
+    /// out of a Volume Shadow Copy. Only on Windows, and needs administrator rights
+    #[arg(long)]
+    pub shadow_copies: bool,
+}
+
+/// Options for mounting work_dir
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct MountArgs {
+    /// Keep the files in this directory and replicate them to backup_dir, instead of
+    /// mounting over backup_dir itself. Needed unless backup_dir is a local directory. It is
+    /// initialized from backup_dir like work_dir would be
+    #[arg(long, value_name = "DIR")]
+    pub staging_dir: Option<PathBuf>,
+
+    /// `sync` to have writes reach backup_dir before close or fsync returns, or `async` to
+    /// replicate them in the background [default: sync]
+    #[arg(long, value_name = "MODE")]
+    pub replicate: Option<Replicate>,
+}
+
+/// Options for initializing work_dir from backup_dir
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct InitArgs {
+    /// Clear work_dir completely and copy everything from backup_dir, even if they match
+    #[arg(long)]
+    pub force_init: bool,
+
+    /// Compare file contents instead of size and modification time when deciding which
+    /// files in work_dir need to be re-initialized
+    #[arg(long)]
+    pub init_hash: bool,
+
+    /// Don't ask before removing or replacing files in work_dir during initialization
+    #[arg(short, long)]
+    pub yes: bool,
+
+    /// Merge backup_dir into work_dir instead of removing files that aren't in backup_dir
+    #[arg(long)]
+    pub no_clear: bool,
+
+    /// `clear` makes work_dir match backup_dir. `merge` loses nothing on either side: files
+    /// only in work_dir are copied into backup_dir, files only in backup_dir into work_dir,
+    /// and files in both that differ are settled by --merge-policy [default: clear]
+    #[arg(long, value_name = "MODE", conflicts_with = "force_init")]
+    pub init_mode: Option<InitMode>,
+
+    /// Which copy of a file that differs is kept when merging: `backup-wins`, `work-wins`,
+    /// `newer-wins` or `prompt` to ask about every one [default: newer-wins]
+    #[arg(long, value_name = "POLICY")]
+    pub merge_policy: Option<MergePolicy>,
+
+    /// Initialize even when what's copied doesn't seem to fit on the filesystem it's copied
+    /// to. Otherwise initialization stops before touching anything
+    #[arg(long)]
+    pub ignore_space_check: bool,
+
+    /// Don't show progress bars while initializing. They are never shown when stderr isn't
+    /// a terminal
+    #[arg(long)]
+    pub no_progress: bool,
+
+    /// Files of at least this many MiB get a progress bar of their own while initializing
+    /// [default: 64]
+    #[arg(long, value_name = "MIB")]
+    pub file_progress_mib: Option<u64>,
+
+    /// Move files removed from work_dir during initialization to the trash. When that fails
+    /// they are moved to a `.evilmount/cleared-<timestamp>` directory in backup_dir
+    #[arg(long)]
+    pub use_trash: bool,
+
+    /// Clear whatever is mounted inside work_dir too. Otherwise mount points are left as
+    /// they are, and files on them aren't removed
+    #[arg(long)]
+    pub cross_mounts: bool,
+
+    /// Take over the lock of backup_dir even if the evil_mount holding it still seems to be
+    /// running, e.g. because another process got its PID
+    #[arg(long)]
+    pub force_lock: bool,
+}
+
+/// Options that only matter while continuously syncing
+#[derive(clap::Args, Debug, Clone, Default)]
+pub struct SyncArgs {
+    /// Periodically scan work_dir instead of using native filesystem events.
+    /// Useful for NFS and other filesystems that don't support change notifications
+    #[arg(long)]
+    pub poll: bool,
+
+    /// How often work_dir is scanned and its files are checked for changes when polling or
+    /// syncing both ways, like `200ms`, `30s` or `5m`. Scans back off to --max-interval
+    /// while nothing changes [default: 3s]
+    #[arg(long, value_name = "DURATION")]
+    pub interval: Option<HumanDuration>,
+
+    /// The longest scans back off to while nothing changes, the same as --interval to scan
+    /// at a fixed rate [default: 4 times --interval]
+    #[arg(long, value_name = "DURATION")]
+    pub max_interval: Option<HumanDuration>,
+
+    /// Pause syncing while running on battery or a metered connection, and sweep up what
+    /// changed in the meantime once plugged in again
+    #[arg(long)]
+    pub pause_on_battery: bool,
+
+    /// While running on battery or a metered connection, scan at most this often and copy
+    /// changed files only once they went this long without changing, like `5m`
+    #[arg(long, value_name = "DURATION")]
+    pub battery_interval: Option<HumanDuration>,
+
+    /// Hold changes back during these times of day, like `22:00-07:00` or
+    /// `12:00-13:00,22:00-07:00`, and copy them once they're over
+    #[arg(long, value_name = "RANGES")]
+    pub quiet_hours: Option<QuietHours>,
+
+    /// Only sync during the minutes a cron expression matches, like `* 9-17 * * mon-fri`,
+    /// holding changes back until the next one does
+    #[arg(long, value_name = "CRON")]
+    pub sync_window: Option<SyncWindow>,
+
+    /// Mirror deletions: files removed from work_dir are also removed from backup_dir. Renamed
+    /// and moved files then get their backup moved along instead of copied again
+    #[arg(long)]
+    pub delete: bool,
+
+    /// How long to wait before propagating a deletion, in case the file comes back
+    #[arg(long, value_name = "SECONDS")]
+    pub delete_after: Option<u64>,
+
+    /// How to tell that a file changed: `mtime`, `size+mtime` or `hash`.
+    /// Hashes are cached in backup_dir so unchanged files aren't re-read [default: mtime]
+    #[arg(long, value_name = "MODE")]
+    pub detect_changes: Option<DetectChanges>,
+
+    /// Don't trust modification times that are less than this many seconds apart, for NFS,
+    /// SMB or FAT mounts whose clocks are coarse or skewed. Files modified that recently are
+    /// hashed to tell whether they changed [default: 0]
+    #[arg(long, value_name = "SECONDS")]
+    pub mtime_tolerance: Option<u64>,
+
+    /// Snapshot backup_dir into backup_dir/.evilmount/snapshots after every sync cycle that
+    /// changed something, keeping the newest N snapshots. Unchanged files are hard linked
+    #[arg(long, value_name = "N")]
+    pub snapshots: Option<usize>,
+
+    /// At the end of every `daily` or `hourly` period, hard link backup_dir into
+    /// backup_dir/.evilmount/rotations/<period>, like 2024-05-01, and carry on mirroring.
+    /// Periods are in local time
+    #[arg(long, value_name = "PERIOD")]
+    pub rotate: Option<Rotate>,
+
+    /// Keep the newest N rotations of --rotate, removing older ones [default: all]
+    #[arg(long, value_name = "N", requires = "rotate")]
+    pub keep: Option<usize>,
+
+    /// Keep what backup_dir takes up, snapshots and cleared files included, under this
+    /// size, like `50G`. Copies that don't fit fail loudly, see --quota-policy
+    #[arg(long, value_name = "SIZE")]
+    pub backup_quota: Option<ByteSize>,
+
+    /// What to do about a copy that would exceed --backup-quota: `refuse` it, or `evict`
+    /// the oldest snapshots and cleared files until it fits [default: refuse]
+    #[arg(long, value_name = "POLICY", requires = "backup_quota")]
+    pub quota_policy: Option<QuotaPolicy>,
+
+    /// What to do about files whose paths only differ in case, like README.md and
+    /// readme.md, which a case-insensitive backup on exFAT, NTFS or APFS can't tell apart:
+    /// `warn` and let them overwrite each other, `rename` all but the first,
+    /// `skip` them or fail with an `error`. Local backups are checked for whether they are
+    /// case-insensitive, remote ones are only checked with this set [default: warn]
+    #[arg(long, value_name = "MODE")]
+    pub case_collisions: Option<CaseCollisions>,
+
+    /// Bring paths in the backup into one Unicode normalization form: nfc as on Linux and
+    /// Windows, nfd as on macOS, or none. Files named on both get one backup instead of two
+    /// [default: none]
+    #[arg(long, value_name = "FORM")]
+    pub normalize_unicode: Option<NormalizeUnicode>,
+
+    /// Also copy changes made directly in backup_dir back into work_dir. Both sides are
+    /// scanned every few seconds instead of being initialized from backup_dir
+    #[arg(long)]
+    pub bidirectional: bool,
+
+    /// How to settle a file that changed on both sides when syncing both ways: `newer-wins`,
+    /// `work-wins`, `backup-wins` or `rename-conflict` [default: newer-wins]
+    #[arg(long, value_name = "STRATEGY")]
+    pub conflict: Option<ConflictStrategy>,
+
+    /// Only copy a file once it hasn't been modified for this many milliseconds, so files
+    /// that are still being written aren't copied half-finished [default: 0]
+    #[arg(long, value_name = "MS")]
+    pub settle_ms: Option<u64>,
+
+    /// Copy a file once after it stops changing for this many milliseconds, instead of on
+    /// every write, so an editor saving in several steps only causes one copy [default: 0]
+    #[arg(long, value_name = "MS")]
+    pub debounce_ms: Option<u64>,
+
+    /// How many files to copy into backup_dir at once, the rest wait their turn [default: 16]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = RangedU64ValueParser::<usize>::new().range(1..)
+    )]
+    pub max_concurrent_copies: Option<usize>,
+
+    /// Skip scans of work_dir while more than this many files are waiting to be copied, so
+    /// a big change set that is still copying isn't checked all over again. Only applies
+    /// with --poll [default: 256]
+    #[arg(long, value_name = "FILES")]
+    pub max_pending: Option<u64>,
+
+    /// Copy files matching GLOB before the others when many changed at once, like
+    /// `src/=10` or `target/=-10`, can be repeated. Higher priorities go first, files no
+    /// glob matches have priority 0, and smaller files go before bigger ones
+    #[arg(long, value_name = "GLOB=PRIORITY", allow_hyphen_values = true)]
+    pub priority: Vec<String>,
+
+    /// How often a copy that failed is tried again, waiting twice as long every time, before
+    /// it's reported as an error [default: 5]
+    #[arg(long, value_name = "N")]
+    pub max_retries: Option<u32>,
+
+    /// How often syncing is restarted after it failed or crashed, waiting twice as long every
+    /// time, before evil_mount gives up and exits with an error [default: 5]
+    #[arg(long, value_name = "N")]
+    pub max_restarts: Option<u32>,
+
+    /// `abort` stops syncing and exits with code 4 once --max-errors copies failed for good,
+    /// after their retries. `continue` reports every one and carries on [default: continue,
+    /// abort with --max-errors]
+    #[arg(long, value_name = "POLICY")]
+    pub error_policy: Option<ErrorPolicy>,
+
+    /// How many copies may fail for good before syncing stops with --error-policy abort
+    /// [default: 1]
+    #[arg(
+        long,
+        value_name = "N",
+        value_parser = RangedU64ValueParser::<u64>::new().range(1..)
+    )]
+    pub max_errors: Option<u64>,
+
+    /// Cancel a copy into backup_dir that takes longer than this many seconds, like one that
+    /// hangs on a dying drive. backup_dir then counts as degraded: a warning and a
+    /// notification go out, and copies wait before trying it again, 5s and twice as long
+    /// after every further timeout, until one finishes in time. The copy is retried like
+    /// any other that failed
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = RangedU64ValueParser::<u64>::new().range(1..)
+    )]
+    pub copy_timeout: Option<u64>,
+
+    /// Cancel a scan that takes longer than this many seconds along with the copies it
+    /// started, the same way as --copy-timeout. The next scan copies what it didn't get to
+    #[arg(
+        long,
+        value_name = "SECS",
+        value_parser = RangedU64ValueParser::<u64>::new().range(1..)
+    )]
+    pub cycle_timeout: Option<u64>,
+
+    /// Also watch a local backup_dir for files that other programs change or delete: `warn`
+    /// logs a warning, `repair` also copies the file from work_dir again [default: warn
+    /// without a MODE]
+    #[arg(long, value_name = "MODE", num_args = 0..=1, default_missing_value = "warn")]
+    pub guard_backup: Option<GuardBackup>,
+
+    /// Write at most this many bytes per second into backup_dir, like `10M`, across every
+    /// copy and upload together. Initializing work_dir isn't limited
+    #[arg(long, value_name = "RATE")]
+    pub bwlimit: Option<ByteSize>,
+
+    /// Give the IO of evil_mount the lowest priority, so syncing only gets the disk when
+    /// nothing else wants it. Only supported on Linux and Windows
+    #[arg(long)]
+    pub nice_io: bool,
+
+    /// Copy whatever changed since the last sync and exit instead of watching for changes.
+    /// work_dir isn't initialized first, and a JSON summary is printed at the end
+    #[arg(long)]
+    pub once: bool,
+
+    /// `json` prints every event on stdout as it happens, one object per line like
+    /// `{"event":"copied","path":...,"bytes":...,"duration_ms":...}`, for jq and log
+    /// shippers. Logs stay on stderr [default: text]
+    #[arg(long, value_name = "FORMAT")]
+    pub output: Option<OutputFormat>,
+
+    /// Commit backup_dir to a git repository in backup_dir/.evilmount/git after every sync
+    /// cycle that changed it, for a history to restore old versions from
+    #[arg(long)]
+    pub git: bool,
+
+    /// Shell command to run after every sync cycle that changed something. It runs in
+    /// backup_dir, and EVILMOUNT_CHANGED_PATHS and EVILMOUNT_COPIED, _PULLED and _REMOVED
+    /// describe what changed
+    #[arg(long, value_name = "CMD")]
+    pub on_sync_complete: Option<String>,
+
+    /// Shell command to run after every sync cycle in which something couldn't be synced.
+    /// EVILMOUNT_ERRORS has the count and EVILMOUNT_ERROR_DETAILS a line per error
+    #[arg(long, value_name = "CMD")]
+    pub on_error: Option<String>,
+
+    /// Show a `desktop` notification when files can't be synced, keep failing, or the
+    /// backup can't be reached
+    #[arg(long, value_name = "TARGET")]
+    pub notify: Option<NotifyTarget>,
+
+    /// POST the same notifications as JSON to this URL
+    #[arg(long, value_name = "URL")]
+    pub notify_webhook: Option<String>,
+
+    /// Serve Prometheus metrics on this address, e.g. 0.0.0.0:9184, at /metrics
+    #[arg(long, value_name = "ADDR")]
+    pub metrics_addr: Option<SocketAddr>,
+
+    /// Serve liveness and readiness probes on this address, e.g. :8080, at /healthz and
+    /// /readyz. Ready means initialized, with a sync cycle in the last few scan intervals
+    #[arg(long, value_name = "ADDR")]
+    pub health_addr: Option<ListenAddr>,
+
+    /// Listen for `pause`, `resume`, `sync-now`, `reload` and `status` on this Unix socket,
+    /// sent with `evil_mount control`. SIGUSR1 and SIGUSR2 also pause and resume syncing, and
+    /// SIGHUP reloads the config file
+    #[arg(long, value_name = "PATH")]
+    pub control_socket: Option<PathBuf>,
+
+    /// Detach into the background once the command line is checked. Logs go to --log-file,
+    /// or nowhere without one. Not needed under systemd or launchd
+    #[arg(long)]
+    pub daemon: bool,
+
+    /// Write the process id to this file while syncing, refusing to start if it names an
+    /// evil_mount that is still running
+    #[arg(long, value_name = "FILE")]
+    pub pidfile: Option<PathBuf>,
+
+    /// Where --daemon writes its logs, appending to what's there
+    #[arg(long, value_name = "FILE")]
+    pub log_file: Option<PathBuf>,
+}
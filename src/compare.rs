@@ -0,0 +1,374 @@
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use futures::{stream, StreamExt, TryStreamExt};
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::SystemTime,
+};
+
+use crate::{
+    backend::{Backend, FileMetadata},
+    copy::temp_path_for,
+    filter::{walk_files, walk_files_in, IgnoreSet, Symlinks},
+    hash::{hash_file, HashPool},
+    meta::manifest_path,
+};
+
+/// How to decide whether two files are the same
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum CompareBy {
+    /// Same size and modification time. Cheap, but only works if mtimes were preserved
+    #[default]
+    Metadata,
+    /// Same size and content hash
+    Contents,
+}
+
+/// The differences between two directory trees. Paths are relative to the tree roots
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct TreeDiff {
+    /// Files that only exist in work_dir
+    pub only_in_work: Vec<PathBuf>,
+    /// Files that only exist in backup_dir
+    pub only_in_backup: Vec<PathBuf>,
+    /// Files that exist in both, but with different contents
+    pub different: Vec<PathBuf>,
+}
+
+impl TreeDiff {
+    pub fn is_empty(&self) -> bool {
+        self.only_in_work.is_empty() && self.only_in_backup.is_empty() && self.different.is_empty()
+    }
+
+    /// The total number of differing paths
+    pub fn len(&self) -> usize {
+        self.only_in_work.len() + self.only_in_backup.len() + self.different.len()
+    }
+}
+
+/// The size and blake3 hash of a file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checksum {
+    pub size: u64,
+    pub hash: Hash,
+}
+
+/// A comparison of every file's size and hash in work_dir and the backup
+#[derive(Debug, Default, Clone, PartialEq, Eq)]
+pub struct Verification {
+    pub diff: TreeDiff,
+    /// Every file in work_dir that isn't ignored
+    pub work: BTreeMap<PathBuf, Checksum>,
+    /// Every file in the backup that isn't ignored
+    pub backup: BTreeMap<PathBuf, Checksum>,
+}
+
+impl Verification {
+    /// The hashes of the backup's files in the format of `b3sum`, so running
+    /// `b3sum --check` from the root of the backup checks it without evil_mount
+    pub fn manifest(&self) -> String {
+        format_manifest(
+            self.backup
+                .iter()
+                .map(|(relative_path, checksum)| (relative_path, &checksum.hash)),
+        )
+    }
+}
+
+pub(crate) fn format_manifest<'a>(hashes: impl Iterator<Item = (&'a PathBuf, &'a Hash)>) -> String {
+    hashes
+        .map(|(relative_path, hash)| {
+            let path = relative_path
+                .components()
+                .map(|component| component.as_os_str().to_string_lossy())
+                .collect::<Vec<_>>()
+                .join("/");
+            format!("{}  {path}\n", hash.to_hex())
+        })
+        .collect()
+}
+
+/// The paths and hashes in a manifest written by [`format_manifest`]. Lines that can't be
+/// parsed are dropped
+pub(crate) fn parse_manifest(contents: &str) -> BTreeMap<PathBuf, Hash> {
+    contents
+        .lines()
+        .filter_map(|line| {
+            let (hash, path) = line.split_once("  ")?;
+            Some((PathBuf::from(path), Hash::from_hex(hash).ok()?))
+        })
+        .collect()
+}
+
+/// The manifest of a local backup, kept up to date by verified copies as they are made
+#[derive(Debug, Default)]
+pub(crate) struct Manifest {
+    hashes: Mutex<BTreeMap<PathBuf, Hash>>,
+    dirty: AtomicBool,
+}
+
+impl Manifest {
+    /// Reads the manifest of `backup_dir`, if it has one. Lines that can't be parsed are
+    /// dropped. This does blocking IO
+    pub fn load(backup_dir: &Path) -> Self {
+        let contents =
+            std::fs::read_to_string(backup_dir.join(manifest_path())).unwrap_or_default();
+        Self {
+            hashes: Mutex::new(parse_manifest(&contents)),
+            dirty: AtomicBool::new(false),
+        }
+    }
+
+    /// Records that the backup of `relative_path` has the contents that hash to `hash`
+    pub fn record(&self, relative_path: &Path, hash: Hash) {
+        let previous = self
+            .hashes
+            .lock()
+            .unwrap()
+            .insert(relative_path.to_path_buf(), hash);
+        if previous != Some(hash) {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the backup of `relative_path` is gone
+    pub fn remove(&self, relative_path: &Path) {
+        if self.hashes.lock().unwrap().remove(relative_path).is_some() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Records that the backup of `from` was moved to `to`
+    pub fn rename(&self, from: &Path, to: &Path) {
+        let mut hashes = self.hashes.lock().unwrap();
+        if let Some(hash) = hashes.remove(from) {
+            hashes.insert(to.to_path_buf(), hash);
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+    }
+
+    /// Brings the manifest in line with `files`, every file in the backup: files it doesn't
+    /// have a hash of yet are hashed in `backup_dir`, and files that are gone are dropped.
+    /// Returns every hash. This does blocking IO
+    pub fn reconcile(
+        &self,
+        backup_dir: &Path,
+        files: impl Iterator<Item = PathBuf>,
+    ) -> Result<BTreeMap<PathBuf, Hash>> {
+        let mut gone: BTreeSet<PathBuf> = self.hashes.lock().unwrap().keys().cloned().collect();
+        let mut hashed = Vec::new();
+        for relative_path in files {
+            if !gone.remove(&relative_path) {
+                let hash = hash_file(&backup_dir.join(&relative_path))?;
+                hashed.push((relative_path, hash));
+            }
+        }
+
+        // Copies made in the meantime recorded what they copied, which wins
+        let mut hashes = self.hashes.lock().unwrap();
+        if !hashed.is_empty() || !gone.is_empty() {
+            self.dirty.store(true, Ordering::Relaxed);
+        }
+        for (relative_path, hash) in hashed {
+            hashes.entry(relative_path).or_insert(hash);
+        }
+        hashes.retain(|relative_path, _| !gone.contains(relative_path));
+        Ok(hashes.clone())
+    }
+
+    /// Writes the manifest into `backup_dir` if anything changed since it was last written.
+    /// This does blocking IO
+    pub fn save(&self, backup_dir: &Path) -> Result<()> {
+        if !self.dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        let contents = format_manifest(self.hashes.lock().unwrap().iter());
+        let path = backup_dir.join(manifest_path());
+        let tmp_path = temp_path_for(&path);
+        if let Some(parent) = path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        }
+        std::fs::write(&tmp_path, contents)
+            .and_then(|()| std::fs::rename(&tmp_path, &path))
+            .with_context(|| anyhow!("Error writing the manifest {}", path.display()))
+    }
+}
+
+/// Lists every file in `dir` that isn't ignored, keyed by its path relative to `dir`
+fn relative_files(
+    dir: &Path,
+    ignore: &IgnoreSet,
+    symlinks: Symlinks,
+) -> BTreeMap<PathBuf, PathBuf> {
+    walk_files_in(dir, dir, ignore, symlinks)
+        .filter_map(|file_info| {
+            let relative_path = file_info.path().strip_prefix(dir).ok()?.to_path_buf();
+            Some((relative_path, file_info.into_path()))
+        })
+        .collect()
+}
+
+/// Compares the files in work_dir with the ones stored in `backend`
+pub async fn compare_trees(
+    work_dir: &Path,
+    backend: &dyn Backend,
+    ignore: &IgnoreSet,
+    symlinks: Symlinks,
+    compare_by: CompareBy,
+) -> Result<TreeDiff> {
+    let work_files = {
+        let work_dir = work_dir.to_path_buf();
+        let ignore = ignore.clone();
+        tokio::task::spawn_blocking(move || relative_files(&work_dir, &ignore, symlinks)).await?
+    };
+    // Files that couldn't be read would look like they are only in the backup
+    ignore.walk_errors().check()?;
+    let mut backup_files = backend.list().await?;
+    backup_files.retain(|relative_path, _| !ignore.is_ignored(relative_path, false));
+
+    let mut diff = TreeDiff::default();
+
+    for (relative_path, work_path) in work_files {
+        let Some(backup_metadata) = backup_files.remove(&relative_path) else {
+            diff.only_in_work.push(relative_path);
+            continue;
+        };
+
+        if !same_file(
+            &work_path,
+            &relative_path,
+            backup_metadata,
+            backend,
+            compare_by,
+        )
+        .await?
+        {
+            diff.different.push(relative_path);
+        }
+    }
+
+    diff.only_in_backup = backup_files.into_keys().collect();
+
+    Ok(diff)
+}
+
+/// Hashes every file in work_dir and in `backend`, and compares their sizes and hashes.
+/// Unlike [`compare_trees`] this reads every file on both sides, even ones that only exist
+/// on one of them. Work files are hashed on `pool`, and as many backup files are hashed at
+/// once as it has threads to spare
+pub async fn verify_trees(
+    work_dir: &Path,
+    backend: &dyn Backend,
+    ignore: &IgnoreSet,
+    symlinks: Symlinks,
+    pool: &HashPool,
+) -> Result<Verification> {
+    let work_files = {
+        let work_dir = work_dir.to_path_buf();
+        let ignore = ignore.clone();
+        tokio::task::spawn_blocking(move || relative_files(&work_dir, &ignore, symlinks)).await?
+    };
+    let mut backup_files = backend.list().await?;
+    backup_files.retain(|relative_path, _| !ignore.is_ignored(relative_path, false));
+
+    // Twice the threads, so the next file is already opened while one is hashed
+    let concurrency = pool.threads() * 2;
+    let work: BTreeMap<_, _> = stream::iter(work_files)
+        .map(|(relative_path, work_path)| async move {
+            let size = tokio::fs::metadata(&work_path).await?.len();
+            let hash = pool.hash(&work_path).await?;
+            anyhow::Ok((relative_path, Checksum { size, hash }))
+        })
+        .buffer_unordered(concurrency)
+        .try_collect()
+        .await?;
+
+    let backup: BTreeMap<_, _> = stream::iter(&backup_files)
+        .map(|(relative_path, metadata)| async move {
+            let checksum = Checksum {
+                size: metadata.size,
+                hash: backend.hash(relative_path).await?,
+            };
+            anyhow::Ok((relative_path.clone(), checksum))
+        })
+        .buffer_unordered(concurrency)
+        .try_collect()
+        .await?;
+
+    let mut verification = Verification {
+        work,
+        backup,
+        ..Verification::default()
+    };
+    for (relative_path, checksum) in &verification.work {
+        match verification.backup.get(relative_path) {
+            None => verification.diff.only_in_work.push(relative_path.clone()),
+            // A recreated symlink is listed with the size of the link, so the hash decides
+            Some(backup) if backup.hash != checksum.hash => {
+                verification.diff.different.push(relative_path.clone())
+            }
+            Some(_) => {}
+        }
+    }
+    verification.diff.only_in_backup = verification
+        .backup
+        .keys()
+        .filter(|relative_path| !verification.work.contains_key(*relative_path))
+        .cloned()
+        .collect();
+
+    Ok(verification)
+}
+
+async fn same_file(
+    path: &Path,
+    relative_path: &Path,
+    backup_metadata: FileMetadata,
+    backend: &dyn Backend,
+    compare_by: CompareBy,
+) -> Result<bool> {
+    let metadata = tokio::fs::symlink_metadata(path).await?;
+    let metadata = match metadata.is_symlink() {
+        true => match backend.local_dir() {
+            // A recreated link is the same if it points at the same place
+            Some(backup_dir) if compare_by == CompareBy::Contents => {
+                let target = tokio::fs::read_link(path).await?;
+                let backup_target = tokio::fs::read_link(backup_dir.join(relative_path)).await;
+                return Ok(backup_target.is_ok_and(|backup_target| backup_target == target));
+            }
+            Some(_) => metadata,
+            None => tokio::fs::metadata(path).await?,
+        },
+        false => metadata,
+    };
+
+    if metadata.len() != backup_metadata.size {
+        return Ok(false);
+    }
+
+    match compare_by {
+        // Only the sizes can be compared when either side has no modification times
+        CompareBy::Metadata => match (metadata.modified().ok(), backup_metadata.modified) {
+            (Some(modified), Some(backup_modified)) => Ok(modified == backup_modified),
+            _ => Ok(true),
+        },
+        CompareBy::Contents => {
+            let path = path.to_path_buf();
+            let hash = tokio::task::spawn_blocking(move || hash_file(&path)).await??;
+            Ok(hash == backend.hash(relative_path).await?)
+        }
+    }
+}
+
+/// The most recent modification time of any file in `dir` that isn't ignored
+pub fn last_modified(dir: &Path, ignore: &IgnoreSet) -> Option<SystemTime> {
+    walk_files(dir, ignore)
+        .filter_map(|file_info| file_info.metadata().ok()?.modified().ok())
+        .max()
+}
@@ -0,0 +1,33 @@
+//! Shell completion scripts and the man page, both generated from the clap definitions in
+//! [`evil_mount::cli`] so they never fall behind the flags
+
+use clap::Command;
+use clap_complete::shells;
+
+use evil_mount::cli::Shell;
+
+/// The completion script for `shell`
+pub fn completions(mut command: Command, shell: Shell) -> Vec<u8> {
+    let name = command.get_name().to_string();
+    // Generated into memory since clap_complete panics when it can't write, like to a closed
+    // pipe
+    let mut script = Vec::new();
+    match shell {
+        Shell::Bash => clap_complete::generate(shells::Bash, &mut command, name, &mut script),
+        Shell::Zsh => clap_complete::generate(shells::Zsh, &mut command, name, &mut script),
+        Shell::Fish => clap_complete::generate(shells::Fish, &mut command, name, &mut script),
+        Shell::Powershell => {
+            clap_complete::generate(shells::PowerShell, &mut command, name, &mut script)
+        }
+    }
+    script
+}
+
+/// The man page, in roff
+pub fn man_page(command: Command) -> Vec<u8> {
+    let mut page = Vec::new();
+    clap_mangen::Man::new(command)
+        .render(&mut page)
+        .expect("Writing into a Vec doesn't fail");
+    page
+}
@@ -0,0 +1,1660 @@
+//! The config file, and merging it with the command line into a [`Syncer`] for every pair
+//! of directories
+
+use anyhow::{anyhow, Context, Result};
+use notify::{Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    ffi::OsStr,
+    io::Read,
+    net::SocketAddr,
+    num::NonZeroU64,
+    path::{Component, Path, PathBuf},
+    sync::{Arc, OnceLock},
+    time::Duration,
+};
+use tracing::{info, warn};
+
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+use crate::mount::Replicate;
+use crate::{
+    backend::{
+        self, Backend, BackendOptions, BackupFormat, Compression, DavCredentials, Encryption,
+        HostKeyCheck, KeySource, SanitizeNames, SshOptions,
+    },
+    cli::{
+        ByteSize, CopyArgs, DirArgs, HumanDuration, InitArgs, ListenAddr, OutputFormat, SyncArgs,
+    },
+    copy::{Durability, Preserve, Reflink, Sparse, VerifyWrites},
+    exit::{Exit, ExitWith},
+    filter::{Symlinks, IGNORE_FILE_NAME},
+    merge::{InitMode, MergePolicy},
+    signing::SigningKey,
+    template::{self, PathVars},
+    throttle::Throttle,
+    AtomicCopies, CaseCollisions, CompareBy, ConflictStrategy, CopyOptions, DetectChanges,
+    EmailNotifications, ErrorPolicy, GuardBackup, HashOptions, HashPool, IgnoreSet,
+    NormalizeUnicode, NotifyTarget, Priorities, QuietHours, Quota, QuotaPolicy, Rotate, Rotation,
+    SyncOptions, SyncWindow, Syncer, Transforms,
+};
+
+/// Settings loaded from a TOML config file. Anything set on the command line takes
+/// precedence over the values in here
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct Config {
+    /// The directory that you will be working in, will be completely cleared
+    pub work_dir: Option<PathBuf>,
+    /// The directory that will be copied to, an archive, or an `sftp://`, `ssh://`, `dav://`
+    /// or `s3://` URL. Used to initialize work_dir
+    pub backup_dir: Option<String>,
+    /// Periodically scan work_dir instead of using native filesystem events
+    pub poll: bool,
+    /// How often work_dir is scanned when polling or syncing both ways
+    pub interval: Option<HumanDuration>,
+    /// The longest scans back off to while nothing changes
+    pub max_interval: Option<HumanDuration>,
+    /// Pause syncing while on battery or a metered connection
+    pub pause_on_battery: bool,
+    /// How often to scan and copy while on battery or a metered connection
+    pub battery_interval: Option<HumanDuration>,
+    /// Times of day when changes are held back
+    pub quiet_hours: Option<QuietHours>,
+    /// A cron expression for the minutes when syncing is allowed
+    pub sync_window: Option<SyncWindow>,
+    /// Mirror deletions from work_dir into backup_dir
+    pub delete: bool,
+    /// Seconds to wait before propagating a deletion
+    pub delete_after: Option<u64>,
+    /// .gitignore-style globs for paths that are never synced
+    pub exclude: Vec<String>,
+    /// Globs that are synced even if they match an exclude
+    pub include: Vec<String>,
+    /// Transforms applied to files matching a glob on their way into the backup
+    pub transform: Vec<String>,
+    /// Hold a shared lock on every file while it's copied
+    pub lock_before_copy: bool,
+    /// Globs for files that are copied atomically or skipped until the next cycle
+    pub atomic_copy: Vec<String>,
+    /// Don't copy files another program has open for writing
+    pub skip_open_files: bool,
+    /// Also skip what git ignores
+    pub respect_gitignore: bool,
+    /// Only sync the paths listed in this file
+    pub files_from: Option<PathBuf>,
+    /// The list in files_from is separated by NUL characters
+    pub from0: bool,
+    /// Only scan and sync these subtrees of work_dir
+    pub only: Vec<PathBuf>,
+    /// Leave out paths more than this many directories deep
+    pub max_depth: Option<usize>,
+    /// Don't descend into directories on other filesystems
+    pub same_filesystem: bool,
+    /// Skip files Windows marks as hidden
+    pub skip_hidden: bool,
+    /// Skip files Windows marks as system files
+    pub skip_system: bool,
+    /// Stop when something in work_dir can't be read
+    pub fail_on_walk_error: bool,
+    /// How many files are hashed at once
+    pub hash_threads: Option<usize>,
+    /// Map big files into memory to hash them
+    pub mmap: bool,
+    /// How to tell that a file changed
+    pub detect_changes: Option<DetectChanges>,
+    /// Seconds within which modification times can't be trusted
+    pub mtime_tolerance: Option<u64>,
+    /// Clear work_dir completely during initialization, even if it matches backup_dir
+    pub force_init: bool,
+    /// Compare file contents when deciding which files to re-initialize
+    pub init_hash: bool,
+    /// Never remove files from work_dir during initialization, only add and replace them
+    pub no_clear: bool,
+    /// Whether initialization makes work_dir match backup_dir or merges the two
+    pub init_mode: Option<InitMode>,
+    /// Which copy of a file that differs is kept when merging
+    pub merge_policy: Option<MergePolicy>,
+    /// Initialize even when what's copied doesn't seem to fit
+    pub ignore_space_check: bool,
+    /// Move files removed from work_dir during initialization to the trash
+    pub use_trash: bool,
+    /// Clear whatever is mounted inside work_dir too
+    pub cross_mounts: bool,
+    /// Don't show progress bars while initializing
+    pub no_progress: bool,
+    /// Files of at least this many MiB get a progress bar of their own while initializing
+    pub file_progress_mib: Option<u64>,
+    /// Flush every copied file to disk before moving it into place
+    pub fsync: bool,
+    /// How much of every copy is flushed to disk
+    pub durability: Option<Durability>,
+    /// Metadata to carry over to copies, e.g. "mode,times,owner,xattr"
+    pub preserve: Option<Preserve>,
+    /// What to do with symbolic links
+    pub symlinks: Option<Symlinks>,
+    /// The only targets links to directories are followed into
+    pub follow_into: Vec<String>,
+    /// Skip files larger than this when initializing and syncing
+    pub max_file_size: Option<ByteSize>,
+    /// Only write the changed blocks of files of at least this many MiB
+    pub delta_min_size: Option<u64>,
+    /// Whether copies are made as copy-on-write clones
+    pub reflink: Option<Reflink>,
+    /// Whether copies keep the holes of sparse files
+    pub sparse: Option<Sparse>,
+    /// How copies into backup_dir are checked against what was read
+    pub verify_writes: Option<VerifyWrites>,
+    /// Copy locked files out of a Volume Shadow Copy
+    pub shadow_copies: bool,
+    /// Encrypt everything stored in backup_dir
+    pub encrypt: bool,
+    /// The key for `encrypt`
+    pub key_file: Option<PathBuf>,
+    /// Also encrypt file and directory names
+    pub encrypt_names: bool,
+    /// Sign a manifest of backup_dir with the key derived from this file
+    pub sign_key: Option<PathBuf>,
+    /// Log every operation on backup_dir in backup_dir/.evilmount/audit.log
+    pub audit_log: bool,
+    /// Compress files stored in backup_dir
+    pub compress: Option<Compression>,
+    /// What happens to files whose names can't be stored on Windows, FAT or NTFS
+    pub sanitize_names: Option<SanitizeNames>,
+    /// How a local backup_dir is laid out
+    pub backup_format: Option<BackupFormat>,
+    /// Whether remote backups over SSH connect to unknown hosts
+    pub ssh_host_key_check: Option<HostKeyCheck>,
+    /// The known hosts file remote backups over SSH check hosts against
+    pub ssh_known_hosts: Option<PathBuf>,
+    /// The private key remote backups over SSH log in with
+    pub ssh_key: Option<PathBuf>,
+    /// Who WebDAV backups log in as
+    pub dav_user: Option<String>,
+    /// The password WebDAV backups log in with
+    pub dav_password: Option<String>,
+    /// Snapshot backup_dir after every sync cycle, keeping this many snapshots
+    pub snapshots: Option<usize>,
+    /// Hard link backup_dir into a dated directory at the end of every period
+    pub rotate: Option<Rotate>,
+    /// How many rotations are kept
+    pub keep: Option<usize>,
+    /// How big backup_dir may get, snapshots and cleared files included
+    pub backup_quota: Option<ByteSize>,
+    /// What to do about copies that don't fit in the quota
+    pub quota_policy: Option<QuotaPolicy>,
+    /// What to do about files whose paths only differ in case
+    pub case_collisions: Option<CaseCollisions>,
+    /// Which Unicode normalization form paths in the backup are brought into
+    pub normalize_unicode: Option<NormalizeUnicode>,
+    /// Also copy changes made in backup_dir back into work_dir
+    pub bidirectional: bool,
+    /// Allow work_dir and backup_dir to be the same directory or inside one another
+    pub allow_overlap: bool,
+    /// Only sync files, leaving out directories that have none in them
+    pub no_empty_dirs: bool,
+    /// How to settle files that changed on both sides when syncing both ways
+    pub conflict: Option<ConflictStrategy>,
+    /// How many snapshots and sets of cleared files `prune` keeps
+    pub keep_last: Option<usize>,
+    /// How many days of snapshots and cleared files `prune` keeps
+    pub keep_days: Option<u64>,
+    /// Milliseconds a file has to go unmodified before it is copied
+    pub settle_ms: Option<u64>,
+    /// Milliseconds changes to the same file are collected for before it is copied once
+    pub debounce_ms: Option<u64>,
+    /// How many files to copy into backup_dir at once
+    pub max_concurrent_copies: Option<usize>,
+    /// Scans are skipped while more files than this wait to be copied
+    pub max_pending: Option<u64>,
+    /// Priorities of files matching a glob, the higher ones are copied first
+    pub priority: Vec<String>,
+    /// How often a copy that failed is tried again before it's reported as an error
+    pub max_retries: Option<u32>,
+    /// How often syncing is restarted after it failed before giving up
+    pub max_restarts: Option<u32>,
+    /// Whether syncing stops once too many copies failed
+    pub error_policy: Option<ErrorPolicy>,
+    /// How many copies may fail before syncing stops with the abort policy, at least 1
+    pub max_errors: Option<NonZeroU64>,
+    /// Seconds a copy may take before it's cancelled and the backup counts as degraded
+    pub copy_timeout: Option<u64>,
+    /// Seconds a scan may take before it's cancelled the same way
+    pub cycle_timeout: Option<u64>,
+    /// What to do about files in backup_dir that other programs change or delete
+    pub guard_backup: Option<GuardBackup>,
+    /// Bytes per second copies into backup_dir may write together
+    pub bwlimit: Option<ByteSize>,
+    /// Give the IO of evil_mount the lowest priority
+    pub nice_io: bool,
+    /// How sync reports what it does on stdout
+    pub output: Option<OutputFormat>,
+    /// Commit backup_dir to git after every sync cycle that changed it
+    pub git: bool,
+    /// Shell command run after every sync cycle that changed something
+    pub on_sync_complete: Option<String>,
+    /// Shell command run after every sync cycle in which something couldn't be synced
+    pub on_error: Option<String>,
+    /// Where to show notifications about sync failures, and how to email them
+    pub notify: Option<Notify>,
+    /// URL to POST notifications about sync failures to
+    pub notify_webhook: Option<String>,
+    /// Where to serve Prometheus metrics
+    pub metrics_addr: Option<SocketAddr>,
+    /// Where to serve liveness and readiness probes
+    pub health_addr: Option<ListenAddr>,
+    /// Unix socket to listen for pause, resume, sync-now, reload and status on
+    pub control_socket: Option<PathBuf>,
+    /// Detach into the background
+    pub daemon: bool,
+    /// File to write the process id to while syncing
+    pub pidfile: Option<PathBuf>,
+    /// Where to write the logs when detached
+    pub log_file: Option<PathBuf>,
+    /// Where `mount` keeps the files it replicates to backup_dir
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    pub staging_dir: Option<PathBuf>,
+    /// When `mount` replicates changes to backup_dir
+    #[cfg(all(feature = "fuse", target_os = "linux"))]
+    pub replicate: Option<Replicate>,
+    /// More work_dir and backup_dir pairs to sync, with the same settings
+    pub mapping: Vec<Mapping>,
+}
+
+/// `notify = "desktop"`, or a `[notify]` section that can also set up emails
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(untagged, try_from = "toml::Value")]
+pub enum Notify {
+    Target(NotifyTarget),
+    Section(NotifySection),
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+#[serde(default, deny_unknown_fields)]
+pub struct NotifySection {
+    /// Show notifications on the desktop, like `notify = "desktop"`
+    pub desktop: bool,
+    pub email: Option<EmailNotifications>,
+}
+
+impl Notify {
+    pub fn target(&self) -> Option<NotifyTarget> {
+        match self {
+            Self::Target(target) => Some(*target),
+            Self::Section(section) => section.desktop.then_some(NotifyTarget::Desktop),
+        }
+    }
+
+    pub fn email(&self) -> Option<&EmailNotifications> {
+        match self {
+            Self::Target(_) => None,
+            Self::Section(section) => section.email.as_ref(),
+        }
+    }
+}
+
+impl TryFrom<toml::Value> for Notify {
+    type Error = String;
+
+    fn try_from(value: toml::Value) -> Result<Self, Self::Error> {
+        match value {
+            toml::Value::String(target) => target.parse().map(Self::Target),
+            toml::Value::Table(section) => NotifySection::deserialize(section)
+                .map(Self::Section)
+                .map_err(|err| err.message().to_string()),
+            _ => Err("notify must be \"desktop\" or a [notify] section".to_string()),
+        }
+    }
+}
+
+/// A work_dir and the backup_dir it is synced to
+#[derive(Debug, Clone, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct Mapping {
+    pub work_dir: PathBuf,
+    pub backup_dir: String,
+    /// Link targets followed into for this pair only, besides the ones of every pair
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub follow_into: Vec<String>,
+    /// What `backup_dir` was expanded from, if it has variables
+    #[serde(skip)]
+    pub template: Option<String>,
+}
+
+impl Config {
+    /// Reads the config file at `path`. With a `profile`, the settings of its
+    /// `[profiles.<name>]` section replace the ones at the top
+    pub fn load(path: &Path, profile: Option<&str>) -> Result<Self> {
+        let contents = std::fs::read_to_string(path)
+            .with_context(|| anyhow!("Error reading config file {}", path.display()))
+            .exit_with(Exit::Config)?;
+        let mut config = parse(&contents, profile)
+            .with_context(|| anyhow!("Error parsing config file {}", path.display()))
+            .exit_with(Exit::Config)?;
+
+        // Relative directories are relative to the config file, not to wherever we were started from
+        let base = path.parent().unwrap_or(Path::new(""));
+        let work_dirs = config.work_dir.iter_mut().chain(
+            config
+                .mapping
+                .iter_mut()
+                .map(|mapping| &mut mapping.work_dir),
+        );
+        for work_dir in work_dirs {
+            if work_dir.is_relative() {
+                *work_dir = base.join(&*work_dir);
+            }
+        }
+        let backup_dirs = config.backup_dir.iter_mut().chain(
+            config
+                .mapping
+                .iter_mut()
+                .map(|mapping| &mut mapping.backup_dir),
+        );
+        for backup_dir in backup_dirs {
+            if !backend::is_remote(backup_dir) && Path::new(backup_dir).is_relative() {
+                *backup_dir = base.join(&*backup_dir).to_string_lossy().into_owned();
+            }
+        }
+        let files = [
+            &mut config.key_file,
+            &mut config.sign_key,
+            &mut config.ssh_known_hosts,
+            &mut config.ssh_key,
+        ];
+        for file in files.into_iter().flatten() {
+            if file.is_relative() {
+                *file = base.join(&*file);
+            }
+        }
+        let files = [
+            &mut config.control_socket,
+            &mut config.pidfile,
+            &mut config.log_file,
+        ];
+        for file in files.into_iter().flatten() {
+            if file.is_relative() {
+                *file = base.join(&*file);
+            }
+        }
+
+        Ok(config)
+    }
+
+    /// The work_dir and backup_dir pairs to sync: `work_dir` and `backup_dir` if they are
+    /// set, followed by every `[[mapping]]`
+    pub fn mappings(&self) -> Result<Vec<Mapping>> {
+        let first = match (&self.work_dir, &self.backup_dir) {
+            (Some(work_dir), Some(backup_dir)) => Some(Mapping {
+                work_dir: work_dir.clone(),
+                backup_dir: backup_dir.clone(),
+                follow_into: Vec::new(),
+                template: None,
+            }),
+            (None, None) => None,
+            (Some(_), None) => return Err(anyhow!("work_dir is set without a backup_dir")),
+            (None, Some(_)) => return Err(anyhow!("backup_dir is set without a work_dir")),
+        };
+        Ok(first.into_iter().chain(self.mapping.clone()).collect())
+    }
+}
+
+/// Parses a config file, layering `profile` over the settings at the top. Every profile is
+/// checked, so a typo doesn't go unnoticed until the profile is used
+fn parse(contents: &str, profile: Option<&str>) -> Result<Config> {
+    let mut table: toml::Table = toml::from_str(contents)?;
+    let profiles = match table.remove("profiles") {
+        Some(toml::Value::Table(profiles)) => profiles,
+        Some(_) => {
+            return Err(anyhow!(
+                "profiles must be a table of [profiles.<name>] sections"
+            ))
+        }
+        None => toml::Table::new(),
+    };
+    for (name, settings) in &profiles {
+        Config::deserialize(settings.clone())
+            .with_context(|| anyhow!("Error in profile {name}"))?;
+    }
+    if let Some(name) = profile {
+        let Some(toml::Value::Table(settings)) = profiles.get(name) else {
+            let names: Vec<&str> = profiles.keys().map(String::as_str).collect();
+            return Err(match names.is_empty() {
+                true => anyhow!("There is no profile {name}, no profiles are defined"),
+                false => anyhow!(
+                    "There is no profile {name}, expected one of {}",
+                    names.join(", ")
+                ),
+            });
+        };
+        table.extend(settings.clone());
+    }
+    Ok(Config::deserialize(table)?)
+}
+
+/// Calls `changed` whenever the config file at `path` is written, created or replaced, until
+/// the returned watcher is dropped. Its directory is watched, since editors often save by
+/// renaming a new file over the old one
+pub fn watch(path: &Path, changed: impl Fn() + Send + 'static) -> Result<RecommendedWatcher> {
+    let path =
+        std::path::absolute(path).with_context(|| anyhow!("Error resolving {}", path.display()))?;
+    let dir = path.parent().unwrap_or(Path::new("/")).to_path_buf();
+    let name = path.file_name().map(OsStr::to_os_string);
+    let mut watcher = notify::recommended_watcher(move |res: notify::Result<Event>| {
+        let Ok(event) = res else {
+            return;
+        };
+        let written = matches!(
+            event.kind,
+            EventKind::Create(_) | EventKind::Modify(_) | EventKind::Any
+        );
+        if written
+            && event
+                .paths
+                .iter()
+                .any(|changed| changed.file_name() == name.as_deref())
+        {
+            changed();
+        }
+    })
+    .with_context(|| anyhow!("Error creating a watcher for {}", path.display()))?;
+    watcher
+        .watch(&dir, RecursiveMode::NonRecursive)
+        .with_context(|| anyhow!("Error watching {}", path.display()))?;
+    Ok(watcher)
+}
+
+/// What the arguments and the config file ask for, before any backend is opened
+struct Plan {
+    mappings: Vec<Mapping>,
+    /// Every pair gets these, with its own [`IgnoreRules::ignore_set`]
+    options: SyncOptions,
+    ignore: IgnoreRules,
+    backend: BackendOptions,
+}
+
+/// What decides which paths of a pair are ignored
+struct IgnoreRules {
+    exclude: Vec<String>,
+    include: Vec<String>,
+    skip_hidden: bool,
+    skip_system: bool,
+    max_depth: Option<usize>,
+    same_filesystem: bool,
+    respect_gitignore: bool,
+    /// The paths of --files-from and --only, if only those are synced
+    only: Option<Vec<PathBuf>>,
+    /// What links to directories are followed into in every pair
+    follow_into: Vec<String>,
+}
+
+impl IgnoreRules {
+    /// What's ignored in `work_dir`, whose backup is in `backend`, where links are also
+    /// followed into the targets the pair's own `follow_into` lists
+    fn ignore_set(
+        &self,
+        work_dir: &Path,
+        follow_into: &[String],
+        backend: &dyn Backend,
+    ) -> Result<IgnoreSet> {
+        // work_dir might be about to be replaced by backup_dir, so fall back to its ignore file
+        let ignore_file = [
+            Some(work_dir.join(IGNORE_FILE_NAME)),
+            backend
+                .local_dir()
+                .map(|backup_dir| backup_dir.join(IGNORE_FILE_NAME)),
+        ]
+        .into_iter()
+        .flatten()
+        .find(|ignore_file| ignore_file.is_file());
+        let mut ignore = IgnoreSet::new(ignore_file.as_deref(), &self.exclude, &self.include)?
+            .skipping(self.skip_hidden, self.skip_system)
+            .limiting(self.max_depth, self.same_filesystem)
+            .following_into(&[&self.follow_into[..], follow_into].concat())?;
+        if self.respect_gitignore {
+            ignore = ignore.respecting_gitignore(work_dir);
+        }
+        if let Some(only) = &self.only {
+            // Absolute paths only count for the pair they're in
+            let real_work_dir = work_dir.canonicalize().ok();
+            let paths = only.iter().filter_map(|path| match path.is_absolute() {
+                true => [Some(work_dir), real_work_dir.as_deref()]
+                    .into_iter()
+                    .flatten()
+                    .find_map(|work_dir| path.strip_prefix(work_dir).ok())
+                    .map(Path::to_path_buf),
+                false => Some(path.clone()),
+            });
+            ignore = ignore.restricted_to(paths);
+        }
+        Ok(ignore)
+    }
+}
+
+/// Merges the command line with the config file (if any) into a [`Syncer`] for every
+/// work_dir and backup_dir pair
+pub async fn build_syncers(dirs: DirArgs, init: InitArgs, sync: SyncArgs) -> Result<Vec<Syncer>> {
+    let Plan {
+        mappings,
+        options,
+        ignore,
+        backend,
+    } = plan(dirs, init, sync).exit_with(Exit::Config)?;
+
+    let mut syncers = Vec::with_capacity(mappings.len());
+    for Mapping {
+        work_dir,
+        backup_dir,
+        follow_into,
+        template,
+    } in mappings
+    {
+        let created = match &template {
+            Some(template) => create_templated_dir(&backup_dir, template)?,
+            None => false,
+        };
+        let backend = backend::open_wrapped(&backup_dir, &options.copy, &backend).await?;
+        let options = SyncOptions {
+            ignore: ignore.ignore_set(&work_dir, &follow_into, &*backend)?,
+            // There's nothing to initialize work_dir from, so it fills the new backup instead
+            init_mode: match created {
+                true => InitMode::Merge,
+                false => options.init_mode,
+            },
+            ..options.clone()
+        };
+        syncers.push(Syncer::with_backend(work_dir, backend, options).exit_with(Exit::Config)?);
+    }
+
+    Ok(syncers)
+}
+
+/// Creates the local `backup_dir` that `template` expanded to if it doesn't exist yet, like
+/// the one for a new day, or the directory of an archive. Only the directories from the
+/// first variable down are created, the ones before it have to exist, so nothing ends up in
+/// the mount point of a share that isn't mounted. Returns whether it did
+fn create_templated_dir(backup_dir: &str, template: &str) -> Result<bool> {
+    let path = Path::new(backup_dir);
+    if backend::is_remote(backup_dir) || path.exists() {
+        return Ok(false);
+    }
+    let prefix = template::fixed_prefix(template);
+    if !prefix.as_os_str().is_empty() && !prefix.is_dir() {
+        return Err(anyhow!(
+            "{} doesn't exist, so {backup_dir} isn't created in it. Is it mounted?",
+            prefix.display()
+        ));
+    }
+    #[cfg(unix)]
+    let path = match backend::ArchiveFormat::of(path) {
+        Some(_) => path.parent().unwrap_or(path),
+        None => path,
+    };
+    std::fs::create_dir_all(path).with_context(|| anyhow!("Error creating {}", path.display()))?;
+    info!("Created {backup_dir} for {template}, filling it from work_dir");
+    Ok(true)
+}
+
+/// Reads the config file again, then applies what can change while syncing to every pair,
+/// see [`Syncer::reload`]. Pairs can't be added or removed without a restart
+pub fn reload(args: &(DirArgs, InitArgs, SyncArgs), syncers: &[Syncer]) -> Result<()> {
+    let (dirs, init, sync) = args.clone();
+    let Plan {
+        mappings,
+        options,
+        ignore,
+        ..
+    } = plan(dirs, init, sync)?;
+
+    let mut reloaded = Vec::with_capacity(syncers.len());
+    for mapping in &mappings {
+        let work_dir = mapping
+            .work_dir
+            .canonicalize()
+            .unwrap_or_else(|_| mapping.work_dir.clone());
+        let Some(syncer) = syncers.iter().find(|syncer| *syncer.work_dir() == work_dir) else {
+            warn!(
+                "{} isn't synced yet, adding a pair needs a restart",
+                mapping.work_dir.display()
+            );
+            continue;
+        };
+        let options = SyncOptions {
+            ignore: ignore.ignore_set(
+                syncer.work_dir(),
+                &mapping.follow_into,
+                &**syncer.backend(),
+            )?,
+            ..options.clone()
+        };
+        reloaded.push((syncer, options));
+    }
+    for syncer in syncers {
+        if !reloaded
+            .iter()
+            .any(|(reloaded, _)| std::ptr::eq(*reloaded, syncer))
+        {
+            warn!(
+                "{} is still synced, removing a pair needs a restart",
+                syncer.work_dir().display()
+            );
+        }
+    }
+    for (syncer, options) in reloaded {
+        syncer.reload(options);
+    }
+    info!("Reloaded the settings");
+    Ok(())
+}
+
+/// Merges the arguments with the config file they point to
+fn plan(dirs: DirArgs, init: InitArgs, sync: SyncArgs) -> Result<Plan> {
+    let DirArgs {
+        config,
+        profile,
+        work_dir,
+        backup_dir,
+        mut exclude,
+        mut include,
+        mut transform,
+        lock_before_copy,
+        mut atomic_copy,
+        skip_open_files,
+        respect_gitignore,
+        files_from,
+        from0,
+        mut only,
+        max_depth,
+        same_filesystem,
+        skip_hidden,
+        skip_system,
+        fail_on_walk_error,
+        hash_threads,
+        mmap,
+        allow_overlap,
+        no_empty_dirs,
+        dry_run,
+        encrypt,
+        key_file,
+        encrypt_names,
+        sign_key,
+        audit_log,
+        compress,
+        sanitize_names,
+        backup_format,
+        ssh_host_key_check,
+        ssh_known_hosts,
+        ssh_key,
+        copy:
+            CopyArgs {
+                fsync,
+                durability,
+                preserve,
+                symlinks,
+                follow_into,
+                max_file_size,
+                delta_min_size,
+                reflink,
+                sparse,
+                verify_writes,
+                shadow_copies,
+            },
+    } = dirs;
+    let InitArgs {
+        force_init,
+        init_hash,
+        yes: _,
+        no_clear,
+        init_mode,
+        merge_policy,
+        ignore_space_check,
+        use_trash,
+        cross_mounts,
+        no_progress,
+        file_progress_mib,
+        force_lock: _,
+    } = init;
+    let SyncArgs {
+        poll,
+        interval,
+        max_interval,
+        pause_on_battery,
+        battery_interval,
+        quiet_hours,
+        sync_window,
+        delete,
+        delete_after,
+        detect_changes,
+        mtime_tolerance,
+        snapshots,
+        rotate,
+        keep,
+        backup_quota,
+        quota_policy,
+        case_collisions,
+        normalize_unicode,
+        bidirectional,
+        conflict,
+        settle_ms,
+        debounce_ms,
+        max_concurrent_copies,
+        max_pending,
+        mut priority,
+        max_retries,
+        max_restarts,
+        error_policy,
+        max_errors,
+        copy_timeout,
+        cycle_timeout,
+        guard_backup,
+        bwlimit,
+        nice_io: _,
+        once: _,
+        output: _,
+        git,
+        on_sync_complete,
+        on_error,
+        notify,
+        notify_webhook,
+        metrics_addr: _,
+        health_addr: _,
+        control_socket: _,
+        daemon: _,
+        pidfile: _,
+        log_file: _,
+    } = sync;
+
+    let config = match config {
+        Some(path) => Config::load(&path, profile.as_deref())?,
+        None => Config::default(),
+    };
+
+    let mappings = mappings(work_dir, backup_dir, &config, profile.as_deref())?;
+    let poll = poll || config.poll;
+    let delete = delete || config.delete;
+    let delete_after = delete_after.or(config.delete_after).unwrap_or(0);
+    let detect_changes = detect_changes.or(config.detect_changes).unwrap_or_default();
+    let force_init = force_init || config.force_init;
+    let init_compare = match init_hash || config.init_hash {
+        true => CompareBy::Contents,
+        false => CompareBy::Metadata,
+    };
+    exclude.splice(0..0, config.exclude);
+    include.splice(0..0, config.include);
+    transform.splice(0..0, config.transform);
+    atomic_copy.splice(0..0, config.atomic_copy);
+    priority.splice(0..0, config.priority);
+
+    let sign_key = sign_key
+        .or(config.sign_key)
+        .map(|path| SigningKey::load(&path).map(Arc::new))
+        .transpose()?;
+    let copy = CopyOptions {
+        durability: durability
+            .or(config.durability)
+            .unwrap_or(match fsync || config.fsync {
+                true => Durability::Data,
+                false => Durability::None,
+            }),
+        preserve: preserve.or(config.preserve).unwrap_or_default(),
+        symlinks: symlinks.or(config.symlinks).unwrap_or_default(),
+        delta_min_size: delta_min_size
+            .or(config.delta_min_size)
+            .map(|mib| mib * 1024 * 1024),
+        reflink: reflink.or(config.reflink).unwrap_or_default(),
+        sparse: sparse.or(config.sparse).unwrap_or_default(),
+        // Signed manifests are kept up to date with the hashes of copies
+        verify_writes: verify_writes
+            .or(config.verify_writes)
+            .unwrap_or(match sign_key {
+                Some(_) => VerifyWrites::Hash,
+                None => VerifyWrites::Off,
+            }),
+        bwlimit: bwlimit
+            .or(config.bwlimit)
+            .map(u64::from)
+            .filter(|&bytes_per_sec| bytes_per_sec > 0)
+            .map(|bytes_per_sec| Arc::new(Throttle::new(bytes_per_sec))),
+        shadow_copies: shadow_copies || config.shadow_copies,
+    };
+    let respect_gitignore = respect_gitignore || config.respect_gitignore;
+    let files_from = files_from
+        .or(config.files_from)
+        .map(|source| read_files_from(&source, from0 || config.from0))
+        .transpose()?;
+    only.splice(0..0, config.only);
+    let only = only
+        .iter()
+        .map(|path| selected_path(path, "given to --only"))
+        .collect::<Result<Vec<_>>>()?;
+    // Both restrict what's synced, so only what's listed inside the subtrees is left
+    let only = match (files_from, only.is_empty()) {
+        (files_from, true) => files_from,
+        (None, false) => Some(only),
+        (Some(files_from), false) => Some(
+            files_from
+                .into_iter()
+                .flat_map(|path| {
+                    only.iter().filter_map(move |subtree| {
+                        match (path.starts_with(subtree), subtree.starts_with(&path)) {
+                            (true, _) => Some(path.clone()),
+                            (false, true) => Some(subtree.clone()),
+                            (false, false) => None,
+                        }
+                    })
+                })
+                .collect(),
+        ),
+    };
+    let max_depth = max_depth.or(config.max_depth);
+    let same_filesystem = same_filesystem || config.same_filesystem;
+    let skip_hidden = skip_hidden || config.skip_hidden;
+    let skip_system = skip_system || config.skip_system;
+    if !cfg!(windows) && (skip_hidden || skip_system || copy.shadow_copies) {
+        warn!("Hidden and system files and shadow copies only exist on Windows");
+    }
+
+    let options = SyncOptions {
+        poll,
+        interval: interval.or(config.interval).map(Duration::from),
+        max_interval: max_interval.or(config.max_interval).map(Duration::from),
+        pause_on_battery: pause_on_battery || config.pause_on_battery,
+        battery_interval: battery_interval
+            .or(config.battery_interval)
+            .map(Duration::from),
+        quiet_hours: quiet_hours.or(config.quiet_hours),
+        sync_window: sync_window.or(config.sync_window),
+        delete_after: delete.then(|| Duration::from_secs(delete_after)),
+        ignore: IgnoreSet::default(),
+        fail_on_walk_error: fail_on_walk_error || config.fail_on_walk_error,
+        detect_changes,
+        mtime_tolerance: Duration::from_secs(
+            mtime_tolerance.or(config.mtime_tolerance).unwrap_or(0),
+        ),
+        hash_pool: HashPool::new(HashOptions {
+            threads: hash_threads.or(config.hash_threads),
+            mmap: mmap || config.mmap,
+        }),
+        force_init,
+        init_compare,
+        copy,
+        snapshots: snapshots.or(config.snapshots),
+        rotation: rotate.or(config.rotate).map(|every| Rotation {
+            every,
+            keep: keep.or(config.keep),
+        }),
+        quota: backup_quota.or(config.backup_quota).map(|bytes| Quota {
+            bytes: bytes.into(),
+            policy: quota_policy.or(config.quota_policy).unwrap_or_default(),
+        }),
+        case_collisions: case_collisions.or(config.case_collisions),
+        normalize_unicode: normalize_unicode
+            .or(config.normalize_unicode)
+            .unwrap_or_default(),
+        bidirectional: bidirectional || config.bidirectional,
+        conflict: conflict.or(config.conflict).unwrap_or_default(),
+        allow_overlap: allow_overlap || config.allow_overlap,
+        no_clear: no_clear || config.no_clear,
+        init_mode: init_mode.or(config.init_mode).unwrap_or_default(),
+        merge_policy: merge_policy.or(config.merge_policy).unwrap_or_default(),
+        ignore_space_check: ignore_space_check || config.ignore_space_check,
+        no_empty_dirs: no_empty_dirs || config.no_empty_dirs,
+        use_trash: use_trash || config.use_trash,
+        cross_mounts: cross_mounts || config.cross_mounts,
+        settle: Duration::from_millis(settle_ms.or(config.settle_ms).unwrap_or(0)),
+        debounce: Duration::from_millis(debounce_ms.or(config.debounce_ms).unwrap_or(0)),
+        max_concurrent_copies: max_concurrent_copies.or(config.max_concurrent_copies),
+        max_pending: max_pending.or(config.max_pending),
+        priorities: Priorities::parse(&priority)?,
+        max_retries: max_retries.or(config.max_retries),
+        max_restarts: max_restarts.or(config.max_restarts),
+        error_policy: error_policy.or(config.error_policy).unwrap_or(
+            match max_errors.or(config.max_errors.map(NonZeroU64::get)) {
+                Some(_) => ErrorPolicy::Abort,
+                None => ErrorPolicy::Continue,
+            },
+        ),
+        max_errors: max_errors.or(config.max_errors.map(NonZeroU64::get)),
+        copy_timeout: copy_timeout
+            .or(config.copy_timeout)
+            .map(Duration::from_secs),
+        cycle_timeout: cycle_timeout
+            .or(config.cycle_timeout)
+            .map(Duration::from_secs),
+        guard_backup: guard_backup.or(config.guard_backup),
+        sign_key,
+        transforms: Transforms::parse(&transform)?,
+        lock_before_copy: lock_before_copy || config.lock_before_copy,
+        atomic_copies: AtomicCopies::new(&atomic_copy)?,
+        skip_open_files: skip_open_files || config.skip_open_files,
+        dry_run,
+        progress: !(no_progress || config.no_progress),
+        max_file_size: max_file_size.or(config.max_file_size).map(u64::from),
+        git: git || config.git,
+        on_sync_complete: on_sync_complete.or(config.on_sync_complete),
+        on_error: on_error.or(config.on_error),
+        notify: notify.or(config.notify.as_ref().and_then(|notify| notify.target())),
+        notify_webhook: notify_webhook.or(config.notify_webhook),
+        notify_email: config
+            .notify
+            .as_ref()
+            .and_then(|notify| notify.email().cloned()),
+        file_progress_threshold: file_progress_mib
+            .or(config.file_progress_mib)
+            .map(|mib| mib * 1024 * 1024),
+    };
+
+    let key_file = key_file.or(config.key_file);
+    let encryption = match (encrypt || config.encrypt || key_file.is_some(), key_file) {
+        (false, _) => None,
+        (true, Some(key_file)) => Some(KeySource::KeyFile(key_file)),
+        (true, None) => match std::env::var("EVILMOUNT_PASSPHRASE") {
+            Ok(passphrase) if !passphrase.is_empty() => Some(KeySource::Passphrase(passphrase)),
+            _ => {
+                return Err(anyhow!(
+                    "--encrypt needs --key-file or a passphrase in EVILMOUNT_PASSPHRASE"
+                ))
+            }
+        },
+    }
+    .map(|key| Encryption {
+        key,
+        encrypt_names: encrypt_names || config.encrypt_names,
+    });
+
+    let audit_log = audit_log || config.audit_log;
+    if audit_log
+        && encryption
+            .as_ref()
+            .is_some_and(|encryption| encryption.encrypt_names)
+    {
+        return Err(anyhow!(
+            "The audit log would give away the names --encrypt-names hides, it can't be kept"
+        ));
+    }
+    let backend = BackendOptions {
+        format: backup_format.or(config.backup_format),
+        ssh: SshOptions {
+            host_key_check: ssh_host_key_check
+                .or(config.ssh_host_key_check)
+                .unwrap_or_default(),
+            known_hosts_file: ssh_known_hosts.or(config.ssh_known_hosts),
+            key_file: ssh_key.or(config.ssh_key),
+        },
+        dav: DavCredentials {
+            user: config.dav_user,
+            password: config.dav_password,
+        },
+        sanitize_names: sanitize_names.or(config.sanitize_names),
+        encryption,
+        compress: compress.or(config.compress),
+        audit_log,
+    };
+
+    Ok(Plan {
+        mappings,
+        options,
+        ignore: IgnoreRules {
+            exclude,
+            include,
+            skip_hidden,
+            skip_system,
+            max_depth,
+            same_filesystem,
+            respect_gitignore,
+            only,
+            follow_into: [follow_into, config.follow_into].concat(),
+        },
+        backend,
+    })
+}
+
+/// The paths listed in `source`, a file or `-` for stdin, separated by newlines or with
+/// `from0` by NUL characters. Stdin is only read once, reloading the settings keeps its list
+fn read_files_from(source: &Path, from0: bool) -> Result<Vec<PathBuf>> {
+    static STDIN: OnceLock<String> = OnceLock::new();
+
+    let list = match source == Path::new("-") {
+        true => match STDIN.get() {
+            Some(list) => list.clone(),
+            None => {
+                let mut list = String::new();
+                std::io::stdin()
+                    .read_to_string(&mut list)
+                    .with_context(|| anyhow!("Error reading the --files-from list from stdin"))?;
+                STDIN.get_or_init(|| list).clone()
+            }
+        },
+        false => std::fs::read_to_string(source)
+            .with_context(|| anyhow!("Error reading {}", source.display()))?,
+    };
+
+    let separator = if from0 { '\0' } else { '\n' };
+    list.split(separator)
+        .map(|line| line.strip_suffix('\r').unwrap_or(line))
+        .filter(|line| !line.is_empty())
+        .map(|line| selected_path(Path::new(line), "in the --files-from list"))
+        .collect()
+}
+
+/// `path` as given to --files-from or --only, which can't leave work_dir. `./src` and `src/`
+/// are both `src`
+fn selected_path(path: &Path, given: &str) -> Result<PathBuf> {
+    if path
+        .components()
+        .any(|component| component == Component::ParentDir)
+    {
+        return Err(anyhow!("{} {given} leaves work_dir", path.display()));
+    }
+    Ok(path
+        .components()
+        .filter(|component| *component != Component::CurDir)
+        .collect())
+}
+
+/// Pairs up the directories given on the command line in order. A side that is missing from
+/// the command line comes from the config file, and without either every pair comes from it
+pub fn mappings(
+    work_dirs: Vec<PathBuf>,
+    backup_dirs: Vec<String>,
+    config: &Config,
+    profile: Option<&str>,
+) -> Result<Vec<Mapping>> {
+    let mut mappings = match (work_dirs.is_empty(), backup_dirs.is_empty()) {
+        (true, true) => config.mappings()?,
+        _ => {
+            let work_dirs = match work_dirs.is_empty() {
+                true => config.work_dir.iter().cloned().collect(),
+                false => work_dirs,
+            };
+            let backup_dirs = match backup_dirs.is_empty() {
+                true => config.backup_dir.iter().cloned().collect(),
+                false => backup_dirs,
+            };
+            if work_dirs.is_empty() {
+                return Err(anyhow!(
+                    "work_dir must be set with --work-dir or in the config file"
+                ));
+            }
+            if backup_dirs.is_empty() {
+                return Err(anyhow!(
+                    "backup_dir must be set with --backup-dir or in the config file"
+                ));
+            }
+            if work_dirs.len() != backup_dirs.len() {
+                return Err(anyhow!(
+                    "Got {} work directories but {} backup directories, they are paired up in order",
+                    work_dirs.len(),
+                    backup_dirs.len()
+                ));
+            }
+            work_dirs
+                .into_iter()
+                .zip(backup_dirs)
+                .map(|(work_dir, backup_dir)| Mapping {
+                    work_dir,
+                    backup_dir,
+                    follow_into: Vec::new(),
+                    template: None,
+                })
+                .collect()
+        }
+    };
+    if mappings.is_empty() {
+        return Err(anyhow!(
+            "work_dir must be set with --work-dir or in the config file"
+        ));
+    }
+
+    let vars = PathVars::current(profile);
+    let now = chrono::Local::now();
+    for mapping in &mut mappings {
+        if template::is_template(&mapping.backup_dir) {
+            let expanded = template::expand(&mapping.backup_dir, &vars, &now)?;
+            mapping.template = Some(std::mem::replace(&mut mapping.backup_dir, expanded));
+        }
+    }
+
+    // Two pairs sharing a directory would fight over its contents
+    let mut work_dirs = HashSet::new();
+    let mut backup_dirs = HashSet::new();
+    for mapping in &mappings {
+        if !work_dirs.insert(&mapping.work_dir) {
+            return Err(anyhow!(
+                "{} is the work_dir of more than one pair",
+                mapping.work_dir.display()
+            ));
+        }
+        if !backup_dirs.insert(&mapping.backup_dir) {
+            return Err(anyhow!(
+                "{} is the backup_dir of more than one pair",
+                mapping.backup_dir
+            ));
+        }
+    }
+
+    Ok(mappings)
+}
+
+/// A commented config file with every option, emitted by `evil_mount config init`
+pub const TEMPLATE: &str = r#"# evil_mount configuration
+#
+# Every option can also be given on the command line, which takes precedence over
+# this file. Relative paths are resolved relative to this file. While syncing, saving this
+# file applies changes to excludes and includes, intervals, max_concurrent_copies, bwlimit,
+# hooks and notifications right away. Everything else needs a restart.
+
+# The directory that you will be working in, will be completely cleared
+# work_dir = "work"
+
+# The directory that will be copied to. Used to initialize work_dir. Backups can also be
+# stored remotely, with "sftp://[user@]host[:port]/path" or "ssh://..." (connects through
+# the system ssh), "dav://host/path" (a WebDAV collection like a Nextcloud folder, or
+# "dav+http://" without TLS) or "s3://bucket/prefix" (credentials, region and AWS_ENDPOINT_URL come from the usual
+# AWS_* environment variables). A path ending in .tar, .tar.gz, .tar.zst or .zip keeps the
+# backup in a single archive, updated at the end of every sync cycle with the tar or zip
+# commands. Snapshots need a local backup_dir
+#
+# backup_dir can have variables in it, like "/mnt/nas/{hostname}/{profile}/{date}":
+# {hostname}, {user}, {profile} (the --profile this file is read with), {date} (the local
+# date, like 2024-05-01) and its parts {year}, {month}, {day} and {hour}. Write {{ and }}
+# for braces. A local directory that doesn't exist yet is created and filled from work_dir.
+# Once the date variables change, a running sync finishes what it's copying and starts
+# over with the new directory
+# backup_dir = "backup"
+
+# Periodically scan work_dir instead of using native filesystem events.
+# Useful for NFS and other filesystems that don't support change notifications
+# poll = false
+
+# How often work_dir is scanned and its files are checked for changes when polling or
+# syncing both ways, like "200ms", "30s" or "5m". While nothing changes, scans back off
+# step by step to max_interval, and go back to interval as soon as something does.
+# max_interval defaults to 4 times interval, set it to interval to scan at a fixed rate
+# interval = "3s"
+# max_interval = "12s"
+
+# Pause syncing while running on battery or a metered connection (as NetworkManager tells),
+# and sweep up whatever changed in the meantime once plugged in again. Or, with
+# battery_interval, keep syncing but scan at most that often, and copy changed files only
+# once they went that long without changing. Power is looked at every 30 seconds
+# pause_on_battery = false
+# battery_interval = "5m"
+
+# Hold changes back during these times of day, in local time, and copy what changed once
+# they're over. Ranges that end before they start go past midnight, and more than one can
+# be given like "12:00-13:00,22:00-07:00". Or only sync during the minutes a cron
+# expression matches: minute, hour, day of the month, month and day of the week, like
+# "* 9-17 * * mon-fri" for office hours. Changes are still noticed, and `control sync-now`
+# syncs them anyway
+# quiet_hours = "22:00-07:00"
+# sync_window = "* 9-17 * * mon-fri"
+
+# Mirror deletions: files removed from work_dir are also removed from backup_dir. Files that
+# are renamed or moved inside work_dir then get their backup moved along instead of copied
+# delete = false
+
+# How many seconds to wait before propagating a deletion, in case the file comes back
+# delete_after = 0
+
+# Skip paths matching these .gitignore-style globs. Patterns are also read from a
+# .evilmountignore file in work_dir
+# exclude = ["target/", ".git/", "node_modules/", "*.swp"]
+
+# Sync paths matching these globs even if they are excluded
+# include = []
+
+# Change files matching a glob on their way into the backup, as "GLOB=TRANSFORM".
+# "redact:REGEX" leaves out the lines matching REGEX, "crlf-to-lf" turns CRLF line endings
+# into LF and "gzip" compresses the file. Their backups are never copied back into work_dir
+# transform = [".env=redact:^(SECRET|TOKEN|PASSWORD)", "*.bat=crlf-to-lf"]
+
+# Hold a shared lock on every file while it's copied, so programs that lock the files they
+# write, like SQLite, wait for the copy instead of leaving it half written
+# lock_before_copy = false
+
+# Copy files matching these globs atomically or not at all. They're copied under a lock, and
+# only if they didn't change while they were read. Otherwise they're skipped until the next
+# cycle
+# atomic_copy = ["*.sqlite", "*.db"]
+
+# Don't copy files another program has open for writing, like a database or a log that is
+# still being written, and try them again next cycle until that program closes them
+# skip_open_files = false
+
+# Also skip what git ignores: what .gitignore files anywhere in work_dir say, along with
+# .git/info/exclude and the global excludes of git. Globs in include still win
+# respect_gitignore = false
+
+# Only sync the paths listed in this file, one per line and relative to work_dir. Everything
+# else is left alone, both when initializing and while syncing, and listed directories are
+# synced with everything in them. With from0 the paths are separated by NUL characters
+# files_from = "/home/me/paths.txt"
+# from0 = false
+
+# Only scan and sync these directories or files, relative to work_dir. Nothing outside of
+# them is copied or removed from the backup, and walks don't descend anywhere else
+# only = ["src", "assets"]
+
+# Leave out paths more than this many directories deep, where the files directly in
+# work_dir are at depth 1. Directories on other filesystems, like mount points or links to
+# /, are left out with same_filesystem. Links that lead back to a directory above them are
+# always left out with a warning
+# max_depth = 10
+# same_filesystem = false
+
+# On Windows, skip files and directories marked as hidden, or as system files like
+# desktop.ini and Thumbs.db
+# skip_hidden = false
+# skip_system = false
+
+# Files and directories in work_dir that can't be read, like ones without permission, are
+# left out with a warning and counted by `status` and the metrics. This stops syncing
+# instead, since they would otherwise never be backed up
+# fail_on_walk_error = false
+
+# How many files to hash at once when verifying, or detecting changes by hash. Defaults to
+# one per core, fewer leaves the others to whatever else runs alongside
+# hash_threads = 4
+
+# Map big files into memory to hash them instead of reading them, which saves copying them
+# around on fast disks. A file on a network mount that's truncated while it's being hashed
+# can crash evil_mount, so leave this off for those
+# mmap = false
+
+# How to tell that a file changed: "mtime", "size+mtime" or "hash". Hashes are cached in
+# backup_dir/.evilmount so unchanged files aren't re-read on every scan
+# detect_changes = "mtime"
+
+# Don't trust modification times that are less than this many seconds apart. On NFS and SMB
+# mounts they can go backwards or only change every 2 seconds, so a save can look like
+# nothing happened. Any difference larger than this counts as a change, either way, and
+# files modified more recently than this are hashed to tell whether they changed
+# mtime_tolerance = 0
+
+# Clear work_dir completely and copy everything from backup_dir on startup, even if only a
+# few files differ
+# force_init = false
+
+# Compare file contents instead of size and modification time when deciding which files in
+# work_dir need to be re-initialized
+# init_hash = false
+
+# Merge backup_dir into work_dir during initialization instead of removing the files that
+# aren't in backup_dir. Files that exist in both are still replaced
+# no_clear = false
+
+# "merge" reconciles work_dir and backup_dir on startup instead of making work_dir match
+# backup_dir: files only in work_dir are copied into backup_dir, files only in backup_dir
+# into work_dir, and files in both that differ are settled by merge_policy. force_init and
+# no_clear don't apply then
+# init_mode = "clear"
+
+# Which copy of a file that differs is kept when merging: "backup-wins", "work-wins",
+# "newer-wins" or "prompt" to ask about every one
+# merge_policy = "newer-wins"
+
+# Before initializing, the free space (and the number of files left, on filesystems that
+# limit it) is checked against what's about to be copied, and initialization stops before
+# touching anything if it doesn't fit. This skips the check
+# ignore_space_check = false
+
+# Move the files removed from work_dir during initialization to the trash instead of deleting
+# them. If that fails they are moved to .evilmount/cleared-<timestamp> in backup_dir
+# use_trash = false
+
+# Clearing work_dir leaves mount points inside of it alone, along with everything on them.
+# This clears them too, keeping only the directories they're mounted on
+# cross_mounts = false
+
+# Don't show progress bars while initializing. They are never shown when stderr isn't a
+# terminal
+# no_progress = false
+
+# Files of at least this many MiB get a progress bar of their own while initializing
+# file_progress_mib = 64
+
+# Flush every copied file to disk before moving it into place. Slower, but a crash can't
+# leave a copy that was reported as done unwritten
+# fsync = false
+
+# Or choose how much of every copy is flushed: "none", "data" (the same as fsync) or
+# "full", which also flushes its metadata and the directory it's moved into, so a power
+# loss can't leave a copy that was reported as done empty or missing
+# durability = "none"
+
+# Carry metadata over to copies, a comma separated list of "mode" (permissions, including
+# the executable bit), "times" (access and modification times), "owner" (usually needs
+# root) and "xattr" (extended attributes). Applies to initialization and syncing alike,
+# but only for local backup directories
+# preserve = "mode,times"
+
+# What to do with symbolic links: "follow" copies whatever they point to, "recreate" copies
+# the link itself so it points at the same target, and "skip" leaves them out
+# symlinks = "follow"
+
+# Only follow links to directories that lead into one of these absolute paths or globs,
+# backing up what they point to under the path of the link. Other links to directories are
+# skipped, or recreated with symlinks = "recreate", and links to files are still handled by
+# symlinks. A [[mapping]] can have a follow_into of its own, which adds to this one
+# follow_into = ["/mnt/photos", "/home/*/notes"]
+
+# Skip files larger than this when initializing and syncing, either a number of bytes or a
+# size like "500M" or "2GiB". Initialization doesn't remove them from work_dir either,
+# unless force_init clears it
+# max_file_size = "2GiB"
+
+# Files of at least this many MiB only have their changed blocks written when backup_dir
+# already has a copy, like rsync does. Only applies to local copies
+# delta_min_size = 256
+
+# Make copies as copy-on-write clones, which share their data with the original until either
+# changes, when both sides are on the same Btrfs, XFS or APFS filesystem. "auto" clones when
+# it can and copies otherwise, "always" fails copies that can't be cloned, and "never"
+# always copies the data
+# reflink = "auto"
+
+# Keep sparse files like disk images sparse in backup_dir, instead of writing out their
+# holes. "auto" keeps the holes of files that have any, "always" also turns runs of zeros
+# into holes, and "never" copies the data as it is
+# sparse = "auto"
+
+# Check every copy into backup_dir against what was read from work_dir: "off", "hash" hashes
+# the data as it's copied and checks that all of it was written, "read-back" also reads the
+# copy back and compares hashes. The hashes of checked copies are recorded in
+# backup_dir/.evilmount/MANIFEST.b3, which `b3sum --check` understands
+# verify_writes = "off"
+
+# On Windows, copy files that other programs keep locked, like open Outlook or database
+# files, out of a Volume Shadow Copy once a few tries haven't got at them. Needs
+# administrator rights
+# shadow_copies = false
+
+# Encrypt everything stored in backup_dir, for backups on drives or servers you don't fully
+# trust. The key is read from key_file, at least 32 bytes such as from
+# `head -c 32 /dev/urandom`, or else stretched from the passphrase in the EVILMOUNT_PASSPHRASE
+# environment variable. Restoring and verifying decrypt it the same way. With encrypt_names,
+# file and directory names are encrypted too, and can be 131 bytes long at most, which only
+# takes effect for a new backup
+# encrypt = false
+# key_file = "backup.key"
+# encrypt_names = false
+
+# Sign a manifest of the files in a local backup_dir after every cycle that changed
+# something, with an ed25519 key derived from this file of at least 32 bytes. Manifests are
+# kept in backup_dir/.evilmount/manifests and chained, which
+# `evil_mount verify --check-signature` checks
+# sign_key = "signing.key"
+
+# Log every copy, delete, rename and restore in backup_dir/.evilmount/audit.log, one JSON
+# object per line with its time, the path, the size and BLAKE3 hash of the file and whether
+# it worked. The log is rotated at 16 MiB, keeping audit.log.1 to audit.log.5, and
+# `evil_mount audit` prints it. Copies that aren't verified with a hash are hashed again
+# for it. Local backups only, and not with encrypt_names, which would give the names away
+# audit_log = false
+
+# Compress files stored in backup_dir, as <name>.zst with "zstd" or <name>.gz with "gzip",
+# optionally at a level like "zstd:9". Files that are compressed already, like images,
+# videos and archives, are stored as they are, with .plain appended if their names end in
+# .zst, .gz or .plain. Restoring and verifying decompress the files whether this is set or
+# not
+# compress = "zstd"
+
+# Store files whose names Windows, FAT or NTFS can't, with characters like : or ? in them,
+# ending in a dot, named like the CON or NUL devices or too long, under a name
+# that works everywhere. "encode" percent-encodes what doesn't work, as in a%3Ab, "replace"
+# replaces it with _, and "skip" leaves the file out and reports it as an error. The
+# original names are kept in backup_dir/.evilmount/names.json, so restoring gives them back
+# whether this is set or not
+# sanitize_names = "encode"
+
+# How a local backup_dir is laid out: "mirror" copies work_dir as it is, "cas" stores every
+# distinct file once in backup_dir/objects, named by its hash, so identical files and
+# unchanged ones across syncs take no extra space. Every sync that changed something adds a
+# tree of the files to backup_dir/trees, which `evil_mount prune` expires along with the
+# objects no tree needs any more. Verifying compares work_dir with the newest tree. A new
+# cas backup needs an empty backup_dir, and an existing backup keeps its format
+# backup_format = "mirror"
+
+# How sftp:// and ssh:// backups connect. Backups on the same host share one connection,
+# which is made again when it drops. ssh_host_key_check decides about hosts missing from
+# the known hosts file: "strict" refuses them, "accept-new" adds them and "off" trusts any
+# key. ssh_known_hosts replaces ~/.ssh/known_hosts and ssh_key is the private key to log in
+# with. Anything else comes from ~/.ssh/config
+# ssh_host_key_check = "strict"
+# ssh_known_hosts = "known_hosts"
+# ssh_key = "backup_key"
+
+# Who "dav://host/path" backups log in as, like a Nextcloud user and an app password. Also
+# read from the EVILMOUNT_DAV_USER and EVILMOUNT_DAV_PASSWORD environment variables
+# dav_user = "me"
+# dav_password = "app-password"
+
+# Snapshot backup_dir into backup_dir/.evilmount/snapshots after every sync cycle that
+# changed something, keeping the newest N snapshots. Unchanged files are hard linked, and
+# `evil_mount restore --at <timestamp>` restores from a snapshot
+# snapshots = 10
+
+# At the end of every "daily" or "hourly" period, in local time, hard link backup_dir into
+# backup_dir/.evilmount/rotations/<period>, like 2024-05-01, and carry on mirroring. Nothing
+# is compared or copied, so this is cheap even for big backups. Keeps the newest `keep`
+# rotations, or all of them without it
+# rotate = "daily"
+# keep = 7
+
+# Keep what backup_dir takes up, snapshots and cleared files included, under this size.
+# It's measured on startup and after every sync cycle that changed the backup. A copy that
+# would go over it fails with an error, unless quota_policy is "evict": then the oldest
+# snapshots and cleared files of a local backup are removed until it fits
+# backup_quota = "50G"
+# quota_policy = "refuse"
+
+# What to do about files whose paths only differ in case, like README.md and readme.md,
+# which a case-insensitive backup on exFAT, NTFS or APFS would store as one file. Of those,
+# the name that sorts first keeps it. "warn" logs a warning and lets the others overwrite
+# it, "rename" backs them up as "readme (case 2).md" and so on, "skip" leaves them out and
+# "error" reports them as errors. A local backup_dir is checked for whether it is
+# case-insensitive, remote ones are only checked when this is set. Not when syncing both ways
+# case_collisions = "warn"
+
+# Bring paths in the backup into one Unicode normalization form, so a file named on macOS
+# (which decomposes accents, nfd) and on Linux (nfc) has one backup: "nfc", "nfd" or "none"
+# normalize_unicode = "none"
+
+# Also copy changes made directly in backup_dir back into work_dir. Both sides are scanned
+# every few seconds, and work_dir isn't initialized from backup_dir on startup. With
+# `delete`, deletions are mirrored in both directions
+# bidirectional = false
+
+# How to settle a file that changed on both sides since the last sync: "newer-wins",
+# "work-wins", "backup-wins" or "rename-conflict", which keeps work_dir's copy and saves
+# backup_dir's copy next to it as <name>.conflict-<timestamp>
+# conflict = "newer-wins"
+
+# What `evil_mount prune` keeps: the newest N snapshots and sets of cleared files, and
+# everything younger than D days. Anything matching either rule is kept
+# keep_last = 10
+# keep_days = 30
+
+# Only copy a file once it hasn't been modified for this many milliseconds, so files that
+# are still being saved or downloaded aren't copied half-written
+# settle_ms = 0
+
+# Collect changes to the same file for this many milliseconds and copy it once after the
+# last of them, instead of once for every write while it's being saved
+# debounce_ms = 0
+
+# How many files to copy into backup_dir at once. Further copies wait for one of these to
+# finish, so a burst of changes can't run out of file descriptors
+# max_concurrent_copies = 16
+
+# When polling, skip scans of work_dir while more than this many files are waiting to be
+# copied, so a big change set that is still copying isn't checked all over again. How long
+# the oldest of them has been waiting shows up as the sync lag in status and the metrics
+# max_pending = 256
+
+# When many files changed at once, copy the ones matching a glob first, as "GLOB=PRIORITY".
+# Higher priorities go first and files no glob matches have priority 0. The last matching
+# glob wins, and smaller files go before bigger ones of the same priority
+# priority = ["src/=10", "*.md=5", "target/=-10"]
+
+# How often a copy that failed, e.g. because the file was busy or the network dropped out,
+# is tried again before it's reported as an error. The first retry waits a second, and
+# every further one waits twice as long. Files that keep failing are listed by `status`
+# max_retries = 5
+
+# When syncing itself fails or crashes, it's restarted after 1 second, then 2, 4 and so on,
+# sweeping up whatever changed in the meantime. After this many failures in a row evil_mount
+# exits with an error instead, for a service manager to take over. A sync that ran for ten
+# minutes before failing starts counting again
+# max_restarts = 5
+
+# A copy that still fails after its retries is reported, and syncing carries on with
+# everything else. "abort" stops syncing instead once max_errors of them failed, and exits
+# with code 4 for a service manager or a script to notice. Setting max_errors alone aborts
+# too
+# error_policy = "continue"
+# max_errors = 1
+
+# Cancel copies into backup_dir that take longer than this many seconds, so a dying drive
+# whose writes hang doesn't stall syncing forever. backup_dir then counts as degraded: a
+# warning and a notification go out, and copies wait 5 seconds before trying it again, twice
+# as long after every further timeout, until one finishes in time. cycle_timeout does the
+# same for a whole scan along with the copies it started. Neither is set by default, as
+# big files on slow backups can take a while
+# copy_timeout = 300
+# cycle_timeout = 3600
+
+# Watch backup_dir for files that other programs change or delete, which would silently
+# leave the backup different from work_dir. "warn" logs a warning, "repair" also copies the
+# file from work_dir again. Only for backups in a local directory, and not when syncing both
+# ways, where changes made in the backup are copied into work_dir
+# guard_backup = "warn"
+
+# Write at most this many bytes per second into backup_dir, across every copy and upload
+# together, so syncing large files doesn't saturate the disk or network. Initializing
+# work_dir isn't limited
+# bwlimit = "10M"
+
+# Give the IO of evil_mount the lowest priority, so syncing only gets the disk when nothing
+# else wants it. Only supported on Linux and Windows
+# nice_io = false
+
+# Print every event on stdout as one JSON object per line, for jq and log shippers. event is
+# "copied" (with bytes and duration_ms), "pulled", "deleted", "renamed", "skipped",
+# "conflict", "snapshot", "error" (with message) or "cycle_complete" (with the counts of the
+# cycle), next to path, work_dir and time. Logs stay on stderr
+# output = "json"
+
+# Commit backup_dir to a git repository after every sync cycle that changed it, with the
+# changed paths in the message. The repository is kept in backup_dir/.evilmount/git so it
+# can't clash with a .git synced from work_dir, e.g. `git --git-dir backup/.evilmount/git log`
+# shows the history. Needs git installed and a local backup_dir
+# git = false
+
+# Shell commands to run after a sync cycle: on_sync_complete when it changed something, and
+# on_error when something couldn't be synced. They run in backup_dir (work_dir if the backup
+# isn't local) with these environment variables set:
+#   EVILMOUNT_HOOK                     "sync-complete" or "error"
+#   EVILMOUNT_WORK_DIR, _BACKUP_DIR    the pair that was synced
+#   EVILMOUNT_COPIED, _PULLED, _REMOVED, _ERRORS   how many files each
+#   EVILMOUNT_CHANGED_PATHS            the changed paths relative to work_dir, one per line
+#   EVILMOUNT_ERROR_DETAILS            a "path: error" line per error
+# on_sync_complete = "git add -A && git commit -qm 'evil_mount sync'"
+# on_error = "notify-send 'evil_mount' \"$EVILMOUNT_ERRORS files couldn't be synced\""
+
+# Send a notification when files can't be synced, when a file fails several cycles in a
+# row, and when the backup becomes unreachable or reachable again. notify = "desktop" shows
+# them on the desktop, and notify_webhook POSTs them as JSON like
+# {"event": "copy_failed", "work_dir": "...", "backup_dir": "...", "message": "...",
+# "errors": ["path: error"]}, where event can also be "repeated_failure", "unreachable" and
+# "reachable"
+# notify = "desktop"
+# notify_webhook = "https://example.com/hooks/evil_mount"
+#
+# Emails are set up in a [notify.email] section, see the end of this file
+
+# Serve Prometheus metrics at http://<metrics_addr>/metrics: files synced, bytes copied, copy
+# errors, when the last sync without errors finished, how long the last scan took and how
+# many files are waiting to be copied, labelled with the work_dir of each pair
+# metrics_addr = "0.0.0.0:9184"
+
+# Serve liveness and readiness probes for Kubernetes and the like. /healthz fails once syncing
+# a pair stopped, /readyz until initialization is done and whenever a pair went five scan
+# intervals without finishing a sync cycle. Both answer with JSON describing every pair
+# health_addr = ":8080"
+
+# Listen on a Unix socket for commands: `evil_mount control --socket <path> pause` stops syncing
+# changes, `resume` sweeps up what changed in the meantime and carries on, `sync-now` sweeps
+# right away even while paused, `reload` reads this file again, and `status` prints what each
+# pair is doing. SIGUSR1 and SIGUSR2 also pause and resume syncing, and SIGHUP reloads.
+# Shutting down still syncs whatever changed
+# control_socket = "/tmp/evil_mount.sock"
+
+# Detach into the background when syncing, writing logs to log_file (or nowhere without one).
+# A pidfile holds the process id while syncing, and keeps a second evil_mount from starting.
+# Under systemd or launchd, which `evil_mount service install -- -c <this file>` sets up,
+# neither daemon nor log_file is needed, and systemd is told when syncing has started
+# daemon = false
+# pidfile = "/tmp/evil_mount.pid"
+# log_file = "evil_mount.log"
+
+# `evil_mount mount` mounts work_dir as a FUSE filesystem over backup_dir, passing every
+# change straight through to it, so nothing needs to watch or poll work_dir. With a
+# staging_dir, the files are kept there instead, it is initialized from backup_dir like
+# work_dir would be, and changes are replicated to backup_dir as they're made, which works
+# for remote backups too. replicate = "sync" has close and fsync wait until backup_dir has the
+# change and fail if it couldn't be replicated, "async" replicates in the background. Needs
+# root, or fusermount3 from the fuse3 package. Linux only, with evil_mount built with
+# `--features fuse`
+# staging_dir = "/var/tmp/evil_mount-staging"
+# replicate = "sync"
+
+# More work_dir and backup_dir pairs to sync in the same process, with the same settings.
+# Each pair is synced independently, and --work-dir and --backup-dir replace all of them
+# [[mapping]]
+# work_dir = "other-work"
+# backup_dir = "other-backup"
+# follow_into = ["/mnt/shared"]
+
+# Allow work_dir and backup_dir to be the same directory or to be inside one another.
+# Without this evil_mount refuses to start, since initialization would clear the backup
+# allow_overlap = false
+
+# Only sync files, leaving out directories that have none in them. By default every
+# directory is recreated on the other side, empty ones like logs/ included, both when
+# initializing and when syncing. Syncing both ways only syncs files either way
+# no_empty_dirs = false
+
+# Email a summary of the day before at daily_summary, in local time, an alert when a cycle has
+# more errors than error_threshold, and one when the backup has been unreachable for
+# unreachable_minutes, checked every minute. Each is only sent if it's set. smtp_tls is
+# "starttls" (port 587 by default), "tls" (465) or "none" (25). The summary and the alert
+# about the backup are only sent while syncing keeps running, not with --once. Like
+# [[mapping]], this has to come after the settings at the top, and [notify] can also take
+# desktop = true in place of notify = "desktop"
+# [notify.email]
+# smtp_server = "smtp.example.com:587"
+# smtp_tls = "starttls"
+# username = "backups@example.com"
+# password = "app password"
+# from = "evil_mount <backups@example.com>"
+# to = ["me@example.com"]
+# daily_summary = "08:00"
+# error_threshold = 10
+# unreachable_minutes = 30
+
+# Named sets of settings, picked with --profile <name>. The settings of the profile replace
+# the ones above, and flags on the command line still take precedence over both. Like
+# [[mapping]], profiles have to come after everything else
+# [profiles.laptop]
+# interval = "30s"
+# max_interval = "5m"
+# bwlimit = "1M"
+# nice_io = true
+# battery_interval = "10m"
+#
+# [profiles.workstation]
+# interval = "500ms"
+# max_concurrent_copies = 32
+"#;
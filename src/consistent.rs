@@ -0,0 +1,253 @@
+//! Consistent copies of files that another program might be writing, like SQLite or
+//! LevelDB databases, see [`SyncOptions::lock_before_copy`](crate::SyncOptions::lock_before_copy)
+//! and [`SyncOptions::atomic_copies`](crate::SyncOptions::atomic_copies).
+//!
+//! A copy holds a shared lock on the original so writers that lock, like SQLite, wait for it
+//! to finish. That's a `flock` everywhere, and on Linux also a read lock on the whole file
+//! with `F_OFD_SETLK`, which is what SQLite's own locks conflict with. Windows locks with
+//! `LockFileEx`. Programs that don't lock aren't kept out, so atomic copies also check that
+//! the file didn't change while it was read.
+//!
+//! With [`SyncOptions::skip_open_files`](crate::SyncOptions::skip_open_files) files another
+//! program has open for writing aren't copied at all until it closes them. Linux finds
+//! those in `/proc/*/fdinfo`, other Unixes ask `lsof`, and Windows tries to open the file
+//! without letting anyone else write to it
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{
+    fmt,
+    fs::{File, Metadata, TryLockError},
+    io,
+    path::Path,
+    time::SystemTime,
+};
+use tracing::debug;
+
+/// A shared lock on a file in work_dir, released when dropped
+#[derive(Debug)]
+pub(crate) struct SourceLock {
+    _file: File,
+}
+
+impl SourceLock {
+    /// Takes a shared lock on `path` without waiting. `None` if a writer holds it. Where
+    /// the filesystem can't lock, nothing is locked and the copy goes ahead
+    pub fn try_shared(path: &Path) -> io::Result<Option<Self>> {
+        let file = File::open(path)?;
+        match file.try_lock_shared() {
+            Ok(()) => {}
+            Err(TryLockError::WouldBlock) => return Ok(None),
+            Err(TryLockError::Error(err)) if err.kind() == io::ErrorKind::Unsupported => {
+                debug!(path = %path.display(), "Can't lock, copying it unlocked");
+            }
+            Err(TryLockError::Error(err)) => return Err(err),
+        }
+        match lock_range(&file)? {
+            true => Ok(Some(Self { _file: file })),
+            false => Ok(None),
+        }
+    }
+}
+
+/// Takes a read lock on all of `file`, which SQLite's write locks conflict with. Open file
+/// description locks stay held when another handle on the same file is closed, unlike
+/// plain `fcntl` locks, so this is only done where those exist. Whether it was taken
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn lock_range(file: &File) -> io::Result<bool> {
+    use nix::{
+        errno::Errno,
+        fcntl::{fcntl, FcntlArg},
+        libc,
+    };
+    use std::os::fd::AsRawFd;
+
+    // SAFETY: `flock` is plain data, zero is a valid value for every field
+    let mut range: libc::flock = unsafe { std::mem::zeroed() };
+    range.l_type = libc::F_RDLCK as _;
+    range.l_whence = libc::SEEK_SET as _;
+    // A length of 0 reaches to the end of the file, however long it gets
+    match fcntl(file.as_raw_fd(), FcntlArg::F_OFD_SETLK(&range)) {
+        Ok(_) => Ok(true),
+        Err(Errno::EAGAIN | Errno::EACCES) => Ok(false),
+        Err(Errno::EINVAL | Errno::ENOLCK | Errno::EOPNOTSUPP) => Ok(true),
+        Err(err) => Err(err.into()),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android")))]
+fn lock_range(file: &File) -> io::Result<bool> {
+    let _ = file;
+    Ok(true)
+}
+
+/// Whether another process has `path` open for writing. Processes of other users can't be
+/// looked at without privileges, so their files count as closed
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn open_for_writing(path: &Path) -> io::Result<bool> {
+    let own_pid = std::process::id().to_string();
+    for process in std::fs::read_dir("/proc")? {
+        let process = process?.path();
+        let is_pid = process
+            .file_name()
+            .and_then(|name| name.to_str())
+            .is_some_and(|name| name.bytes().all(|b| b.is_ascii_digit()) && name != own_pid);
+        if !is_pid {
+            continue;
+        }
+        // Gone already, or someone else's
+        let Ok(fds) = std::fs::read_dir(process.join("fd")) else {
+            continue;
+        };
+        for fd in fds.flatten() {
+            if std::fs::read_link(fd.path()).is_ok_and(|target| target == path)
+                && fd_writes(&process.join("fdinfo").join(fd.file_name()))
+            {
+                return Ok(true);
+            }
+        }
+    }
+    Ok(false)
+}
+
+/// Whether the descriptor described by the `fdinfo` file at `path` was opened for writing
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn fd_writes(path: &Path) -> bool {
+    use nix::libc;
+
+    let Ok(info) = std::fs::read_to_string(path) else {
+        return false;
+    };
+    info.lines()
+        .find_map(|line| line.strip_prefix("flags:"))
+        .and_then(|flags| u32::from_str_radix(flags.trim(), 8).ok())
+        .is_some_and(|flags| {
+            let access = flags as libc::c_int & libc::O_ACCMODE;
+            access == libc::O_WRONLY || access == libc::O_RDWR
+        })
+}
+
+/// Asks `lsof` whether another process has `path` open for writing
+#[cfg(all(unix, not(any(target_os = "linux", target_os = "android"))))]
+pub(crate) fn open_for_writing(path: &Path) -> io::Result<bool> {
+    let output = std::process::Command::new("lsof")
+        .args(["-w", "-F", "pa", "--"])
+        .arg(path)
+        .stderr(std::process::Stdio::null())
+        .output()?;
+    let own_pid = format!("p{}", std::process::id());
+    // Every process starts with `p<pid>`, followed by `a<access>` for each of its files
+    let mut own = false;
+    for line in String::from_utf8_lossy(&output.stdout).lines() {
+        if line.starts_with('p') {
+            own = line == own_pid;
+        } else if !own && (line == "aw" || line == "au") {
+            return Ok(true);
+        }
+    }
+    Ok(false)
+}
+
+/// Whether another process has `path` open for writing, which makes opening it without
+/// sharing write access fail
+#[cfg(windows)]
+pub(crate) fn open_for_writing(path: &Path) -> io::Result<bool> {
+    use std::os::windows::fs::OpenOptionsExt;
+
+    const FILE_SHARE_READ: u32 = 1;
+    const FILE_SHARE_DELETE: u32 = 4;
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+
+    match std::fs::OpenOptions::new()
+        .read(true)
+        .share_mode(FILE_SHARE_READ | FILE_SHARE_DELETE)
+        .open(path)
+    {
+        Ok(_) => Ok(false),
+        Err(err) if err.raw_os_error() == Some(ERROR_SHARING_VIOLATION) => Ok(true),
+        Err(err) => Err(err),
+    }
+}
+
+/// Why a file that has to be copied atomically, or isn't copied while open, wasn't copied
+/// this cycle
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Busy {
+    /// A writer held a lock on it
+    Locked,
+    /// It changed while it was read
+    Changed,
+    /// Another program has it open for writing
+    OpenForWriting,
+}
+
+impl fmt::Display for Busy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Locked => "locked by a writer",
+            Self::Changed => "changed while it was copied",
+            Self::OpenForWriting => "open for writing",
+        })
+    }
+}
+
+impl std::error::Error for Busy {}
+
+/// The length and modification time of a file, which change when it's written to
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub(crate) struct Version {
+    len: u64,
+    modified: Option<SystemTime>,
+}
+
+impl From<&Metadata> for Version {
+    fn from(metadata: &Metadata) -> Self {
+        Self {
+            len: metadata.len(),
+            modified: metadata.modified().ok(),
+        }
+    }
+}
+
+/// The files that are copied atomically or not at all, by glob
+#[derive(Debug, Clone)]
+pub struct AtomicCopies {
+    matcher: Gitignore,
+}
+
+impl Default for AtomicCopies {
+    /// No file has to be copied atomically
+    fn default() -> Self {
+        Self {
+            matcher: Gitignore::empty(),
+        }
+    }
+}
+
+impl AtomicCopies {
+    /// Builds the set from .gitignore-style globs like `*.sqlite`
+    pub fn new(globs: &[String]) -> Result<Self> {
+        // Using `.` as the root disables prefix stripping, every path we match is relative
+        let mut builder = GitignoreBuilder::new(".");
+        for glob in globs {
+            builder
+                .add_line(None, glob)
+                .map_err(|err| anyhow!("Invalid atomic copy pattern {glob}: {err}"))?;
+        }
+        let matcher = builder
+            .build()
+            .map_err(|err| anyhow!("Error building atomic copy patterns: {err}"))?;
+        Ok(Self { matcher })
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.matcher.is_empty()
+    }
+
+    /// Whether `relative_path` is copied atomically or not at all
+    pub fn contains(&self, relative_path: &Path) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(relative_path, false)
+            .is_ignore()
+    }
+}
@@ -0,0 +1,551 @@
+//! Pausing and resuming syncing without stopping the process, e.g. during a large refactor
+//! or a `cargo clean`.
+//!
+//! SIGUSR1 pauses every pair and SIGUSR2 resumes them, and SIGHUP reads the config file
+//! again. A Unix control socket also takes one command per connection: `pause`, `resume`,
+//! `sync-now`, `reload` or `status`. While paused, changes in work_dir are left alone.
+//! Resuming and `sync-now` run a full sweep, which copies whatever is newer than its
+//! backup. Shutting down still copies whatever changed, paused or not
+
+use anyhow::{anyhow, Context, Result};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::Path,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{SystemTime, UNIX_EPOCH},
+};
+use tokio::sync::Notify;
+use tracing::info;
+
+use crate::metrics::{CycleStats, Metrics};
+
+/// Pauses, resumes and wakes up the runs of one [`Syncer`](crate::Syncer)
+#[derive(Debug, Default)]
+pub struct SyncControl {
+    paused: AtomicBool,
+    on_battery: AtomicBool,
+    quiet: AtomicBool,
+    degraded: AtomicBool,
+    missing: AtomicBool,
+    sync_requested: Notify,
+}
+
+impl SyncControl {
+    /// Stops syncing changes until [`SyncControl::resume`]. Returns whether it was running
+    pub fn pause(&self) -> bool {
+        !self.paused.swap(true, Ordering::Relaxed)
+    }
+
+    /// Syncs changes again, starting with a full sweep. Returns whether it was paused
+    pub fn resume(&self) -> bool {
+        let was_paused = self.paused.swap(false, Ordering::Relaxed);
+        if was_paused {
+            self.sync_now();
+        }
+        was_paused
+    }
+
+    pub fn is_paused(&self) -> bool {
+        self.paused.load(Ordering::Relaxed)
+    }
+
+    /// Records whether the machine runs on battery or a metered connection, see
+    /// [`PowerState::constrained`](crate::power::PowerState::constrained). Coming off it
+    /// runs a full sweep, for whatever was held back in the meantime
+    pub fn set_on_battery(&self, on_battery: bool) {
+        if self.on_battery.swap(on_battery, Ordering::Relaxed) && !on_battery {
+            self.sync_now();
+        }
+    }
+
+    pub fn on_battery(&self) -> bool {
+        self.on_battery.load(Ordering::Relaxed)
+    }
+
+    /// Records whether it's quiet hours or outside of the sync window, see
+    /// [`crate::window`]. Once they're over, a full sweep copies what was held back
+    pub fn set_quiet(&self, quiet: bool) {
+        if self.quiet.swap(quiet, Ordering::Relaxed) && !quiet {
+            self.sync_now();
+        }
+    }
+
+    pub fn is_quiet(&self) -> bool {
+        self.quiet.load(Ordering::Relaxed)
+    }
+
+    /// Records whether copies into the backup time out, see
+    /// [`SyncOptions::copy_timeout`](crate::SyncOptions::copy_timeout)
+    pub fn set_degraded(&self, degraded: bool) {
+        self.degraded.store(degraded, Ordering::Relaxed);
+    }
+
+    pub fn is_degraded(&self) -> bool {
+        self.degraded.load(Ordering::Relaxed)
+    }
+
+    /// Records whether backup_dir is gone or something else is mounted there, see
+    /// [`crate::vanished`]. Once it's back, a full sweep catches up with what changed
+    pub fn set_missing(&self, missing: bool) {
+        if self.missing.swap(missing, Ordering::Relaxed) && !missing {
+            self.sync_now();
+        }
+    }
+
+    pub fn is_missing(&self) -> bool {
+        self.missing.load(Ordering::Relaxed)
+    }
+
+    /// Runs a full sweep as soon as possible, even while paused. Requests made while one is
+    /// already waiting to run are merged into it
+    pub fn sync_now(&self) {
+        self.sync_requested.notify_one();
+    }
+
+    /// Waits for [`SyncControl::sync_now`]
+    pub(crate) async fn sync_requested(&self) {
+        self.sync_requested.notified().await
+    }
+}
+
+/// What can be sent to the control socket
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ControlCommand {
+    Pause,
+    Resume,
+    SyncNow,
+    /// Read the config file again and apply what can change while syncing, see
+    /// [`Syncer::reload`](crate::Syncer::reload)
+    Reload,
+    Status,
+    /// The same as `status`, as a JSON array of [`PairStatus`]
+    StatusJson,
+}
+
+impl FromStr for ControlCommand {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "pause" => Ok(Self::Pause),
+            "resume" => Ok(Self::Resume),
+            "sync-now" => Ok(Self::SyncNow),
+            "reload" => Ok(Self::Reload),
+            "status" => Ok(Self::Status),
+            "status-json" => Ok(Self::StatusJson),
+            _ => Err(format!(
+                "unknown command {s}, expected pause, resume, sync-now, reload, status or \
+                 status-json"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ControlCommand {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Pause => "pause",
+            Self::Resume => "resume",
+            Self::SyncNow => "sync-now",
+            Self::Reload => "reload",
+            Self::Status => "status",
+            Self::StatusJson => "status-json",
+        })
+    }
+}
+
+/// One work_dir and backup pair as the control socket sees it
+#[derive(Debug, Clone)]
+pub struct ControlledPair {
+    pub work_dir: String,
+    pub backup: String,
+    pub control: Arc<SyncControl>,
+    pub metrics: Arc<Metrics>,
+}
+
+impl ControlledPair {
+    pub fn status(&self) -> PairStatus {
+        PairStatus {
+            work_dir: self.work_dir.clone(),
+            backup: self.backup.clone(),
+            paused: self.control.is_paused(),
+            on_battery: self.control.on_battery(),
+            quiet: self.control.is_quiet(),
+            degraded: self.control.is_degraded(),
+            missing: self.control.is_missing(),
+            files_scanned: self.metrics.files_scanned(),
+            files_synced: self.metrics.files_synced(),
+            files_removed: self.metrics.files_removed(),
+            bytes_copied: self.metrics.bytes_copied(),
+            copy_errors: self.metrics.copy_errors(),
+            walk_errors: self.metrics.walk_errors(),
+            queue_depth: self.metrics.queue_depth(),
+            sync_lag_ms: self.metrics.sync_lag().map(|lag| lag.as_millis() as u64),
+            scan_ms: self.metrics.scan_time().as_millis() as u64,
+            copy_ms: self.metrics.copy_time().as_millis() as u64,
+            last_cycle: self.metrics.last_cycle(),
+            started: epoch_secs(self.metrics.started()),
+            last_sync: self.metrics.last_sync().map(epoch_secs),
+            recent_errors: self
+                .metrics
+                .recent_errors()
+                .into_iter()
+                .map(|(time, message)| RecentError {
+                    at: epoch_secs(time),
+                    message,
+                })
+                .collect(),
+            failing: self
+                .metrics
+                .failing_files()
+                .into_iter()
+                .map(|(path, error)| FailingFile {
+                    path: path.display().to_string(),
+                    error,
+                })
+                .collect(),
+            erroring: self
+                .metrics
+                .erroring_paths()
+                .into_iter()
+                .map(|erroring| ErroringFile {
+                    path: erroring.path.display().to_string(),
+                    count: erroring.count,
+                    error: erroring.error,
+                    first: epoch_secs(erroring.first),
+                    last: epoch_secs(erroring.last),
+                })
+                .collect(),
+            permission_denied: self
+                .metrics
+                .permission_denied_files()
+                .into_iter()
+                .map(|(path, error)| FailingFile {
+                    path: path.display().to_string(),
+                    error,
+                })
+                .collect(),
+        }
+    }
+}
+
+/// What a running sync reports about one of its pairs. Times are seconds since the epoch
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct PairStatus {
+    pub work_dir: String,
+    pub backup: String,
+    pub paused: bool,
+    /// Whether it runs on battery or a metered connection
+    #[serde(default)]
+    pub on_battery: bool,
+    /// Whether it's quiet hours or outside of the sync window
+    #[serde(default)]
+    pub quiet: bool,
+    /// Whether copies into the backup time out
+    #[serde(default)]
+    pub degraded: bool,
+    /// Whether backup_dir is gone or something else is mounted there
+    #[serde(default)]
+    pub missing: bool,
+    #[serde(default)]
+    pub files_scanned: u64,
+    pub files_synced: u64,
+    pub files_removed: u64,
+    pub bytes_copied: u64,
+    pub copy_errors: u64,
+    /// Entries that scans of work_dir couldn't read
+    #[serde(default)]
+    pub walk_errors: u64,
+    /// Files waiting to be copied or being copied
+    pub queue_depth: u64,
+    /// How long the one that has waited longest has been waiting, in milliseconds
+    #[serde(default)]
+    pub sync_lag_ms: Option<u64>,
+    /// Milliseconds spent scanning and copying since syncing started
+    #[serde(default)]
+    pub scan_ms: u64,
+    #[serde(default)]
+    pub copy_ms: u64,
+    /// What the last sync cycle did
+    #[serde(default)]
+    pub last_cycle: Option<CycleStats>,
+    /// When syncing started
+    pub started: u64,
+    /// When the last sync cycle without errors finished
+    pub last_sync: Option<u64>,
+    /// The latest errors, oldest first
+    pub recent_errors: Vec<RecentError>,
+    /// Files that still couldn't be copied after every retry
+    #[serde(default)]
+    pub failing: Vec<FailingFile>,
+    /// Every path with errors since it was last synced
+    #[serde(default)]
+    pub erroring: Vec<ErroringFile>,
+    /// Files that are skipped since they can't be read, until their metadata changes
+    #[serde(default)]
+    pub permission_denied: Vec<FailingFile>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct RecentError {
+    pub at: u64,
+    pub message: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct FailingFile {
+    pub path: String,
+    /// The last error copying or reading it
+    pub error: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ErroringFile {
+    pub path: String,
+    /// How many errors there were
+    pub count: u64,
+    /// The last one
+    pub error: String,
+    pub first: u64,
+    pub last: u64,
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Asks the sync listening on the control socket at `path` how each of its pairs is doing
+pub async fn query(path: &Path) -> Result<Vec<PairStatus>> {
+    let reply = send(path, ControlCommand::StatusJson).await?;
+    serde_json::from_str(&reply)
+        .with_context(|| anyhow!("Error parsing the status from {}", path.display()))
+}
+
+/// Runs `command` on every pair and returns the reply for whoever sent it. Reloading is up
+/// to whoever waits on `reloads`
+pub fn execute(command: ControlCommand, pairs: &[ControlledPair], reloads: &Notify) -> String {
+    match command {
+        ControlCommand::Pause => {
+            let paused = pairs.iter().filter(|pair| pair.control.pause()).count();
+            if paused > 0 {
+                info!("Paused syncing, resume it with SIGUSR2 or `resume`");
+            }
+            match paused {
+                0 => "Already paused\n".to_string(),
+                _ => "Paused\n".to_string(),
+            }
+        }
+        ControlCommand::Resume => {
+            let resumed = pairs.iter().filter(|pair| pair.control.resume()).count();
+            if resumed > 0 {
+                info!("Resumed syncing");
+            }
+            match resumed {
+                0 => "Not paused\n".to_string(),
+                _ => "Resumed\n".to_string(),
+            }
+        }
+        ControlCommand::SyncNow => {
+            for pair in pairs {
+                pair.control.sync_now();
+            }
+            "Syncing\n".to_string()
+        }
+        ControlCommand::Reload => {
+            reloads.notify_one();
+            "Reloading\n".to_string()
+        }
+        ControlCommand::Status => pairs.iter().map(status_line).collect(),
+        ControlCommand::StatusJson => {
+            let statuses: Vec<PairStatus> = pairs.iter().map(ControlledPair::status).collect();
+            // Serializing plain structs can't fail
+            serde_json::to_string(&statuses).unwrap_or_default() + "\n"
+        }
+    }
+}
+
+/// e.g. `work -> backup: syncing, 12 files synced, 340 scanned, 1.20 MiB copied, 0 errors,
+/// 0 failing, 0 denied, 0 queued, last sync 3s ago`
+fn status_line(pair: &ControlledPair) -> String {
+    let control = &pair.control;
+    let state = match (
+        control.is_paused(),
+        control.is_missing(),
+        control.is_quiet(),
+        control.on_battery(),
+    ) {
+        (true, _, _, _) => "paused",
+        (false, true, _, _) => "waiting for the backup",
+        (false, false, true, _) => "quiet",
+        (false, false, false, true) => "syncing on battery",
+        (false, false, false, false) => "syncing",
+    };
+    let state = match control.is_degraded() {
+        true => format!("{state} to a degraded backup"),
+        false => state.to_string(),
+    };
+    let last_sync = match pair.metrics.last_sync() {
+        Some(time) => {
+            let secs = SystemTime::now()
+                .duration_since(time)
+                .unwrap_or_default()
+                .as_secs();
+            format!("{secs}s ago")
+        }
+        None => "never".to_string(),
+    };
+    let queued = match pair.metrics.sync_lag() {
+        Some(lag) => format!(
+            "{} queued for up to {}s",
+            pair.metrics.queue_depth(),
+            lag.as_secs()
+        ),
+        None => format!("{} queued", pair.metrics.queue_depth()),
+    };
+    format!(
+        "{} -> {}: {state}, {} files synced, {} scanned, {} copied, {} errors on {} paths, {} \
+         unreadable, {} failing, {} denied, {queued}, last sync {last_sync}\n",
+        pair.work_dir,
+        pair.backup,
+        pair.metrics.files_synced(),
+        pair.metrics.files_scanned(),
+        HumanBytes(pair.metrics.bytes_copied()),
+        pair.metrics.copy_errors(),
+        pair.metrics.erroring_paths().len(),
+        pair.metrics.walk_errors(),
+        pair.metrics.failing_files().len(),
+        pair.metrics.permission_denied_files().len(),
+    )
+}
+
+/// Pauses every pair on SIGUSR1, resumes them on SIGUSR2 and asks for a reload on SIGHUP,
+/// until the task is dropped
+#[cfg(unix)]
+pub async fn handle_signals(pairs: Vec<ControlledPair>, reloads: Arc<Notify>) -> Result<()> {
+    use tokio::signal::unix::{signal, SignalKind};
+
+    let mut pause = signal(SignalKind::user_defined1())
+        .with_context(|| anyhow!("Error listening for SIGUSR1"))?;
+    let mut resume = signal(SignalKind::user_defined2())
+        .with_context(|| anyhow!("Error listening for SIGUSR2"))?;
+    let mut reload =
+        signal(SignalKind::hangup()).with_context(|| anyhow!("Error listening for SIGHUP"))?;
+    loop {
+        let command = tokio::select! {
+            _ = pause.recv() => ControlCommand::Pause,
+            _ = resume.recv() => ControlCommand::Resume,
+            _ = reload.recv() => ControlCommand::Reload,
+        };
+        execute(command, &pairs, &reloads);
+    }
+}
+
+#[cfg(unix)]
+pub use socket::{bind, send, serve};
+
+/// Sends `command` to the control socket at `path` and returns the reply
+#[cfg(not(unix))]
+pub async fn send(path: &Path, command: ControlCommand) -> Result<String> {
+    let _ = (path, command);
+    Err(anyhow!("Control sockets are only supported on Unix"))
+}
+
+#[cfg(unix)]
+mod socket {
+    use anyhow::{anyhow, Context, Result};
+    use std::{path::Path, sync::Arc};
+    use tokio::{
+        io::{AsyncBufReadExt, AsyncReadExt, AsyncWriteExt, BufReader},
+        net::{UnixListener, UnixStream},
+        sync::Notify,
+    };
+    use tracing::{debug, info};
+
+    use super::{execute, ControlCommand, ControlledPair};
+
+    /// Commands are only read up to this many bytes
+    const MAX_COMMAND_LEN: u64 = 1024;
+
+    /// Listens on `path`, replacing a socket left behind by a process that is gone
+    pub async fn bind(path: &Path) -> Result<UnixListener> {
+        if path.exists() {
+            if UnixStream::connect(path).await.is_ok() {
+                return Err(anyhow!(
+                    "{} is already used by another evil_mount",
+                    path.display()
+                ));
+            }
+            tokio::fs::remove_file(path)
+                .await
+                .with_context(|| anyhow!("Error removing {}", path.display()))?;
+        }
+        UnixListener::bind(path).with_context(|| anyhow!("Error listening on {}", path.display()))
+    }
+
+    /// Answers commands on `listener` until the task is dropped
+    pub async fn serve(
+        listener: UnixListener,
+        pairs: Vec<ControlledPair>,
+        reloads: Arc<Notify>,
+    ) -> Result<()> {
+        if let Ok(addr) = listener.local_addr() {
+            if let Some(path) = addr.as_pathname() {
+                info!("Listening for commands on {}", path.display());
+            }
+        }
+        let pairs = Arc::new(pairs);
+        loop {
+            let (stream, _) = listener.accept().await?;
+            let pairs = pairs.clone();
+            let reloads = reloads.clone();
+            tokio::spawn(async move {
+                if let Err(err) = respond(stream, &pairs, &reloads).await {
+                    debug!("Error answering a control command: {err}");
+                }
+            });
+        }
+    }
+
+    async fn respond(stream: UnixStream, pairs: &[ControlledPair], reloads: &Notify) -> Result<()> {
+        let (read, mut write) = stream.into_split();
+        let mut line = String::new();
+        BufReader::new(read.take(MAX_COMMAND_LEN))
+            .read_line(&mut line)
+            .await?;
+        let reply = match line.trim().parse::<ControlCommand>() {
+            Ok(command) => {
+                debug!(%command, "Received a control command");
+                execute(command, pairs, reloads)
+            }
+            Err(err) => format!("Error: {err}\n"),
+        };
+        write.write_all(reply.as_bytes()).await?;
+        write.shutdown().await?;
+        Ok(())
+    }
+
+    /// Sends `command` to the control socket at `path` and returns the reply
+    pub async fn send(path: &Path, command: ControlCommand) -> Result<String> {
+        let mut stream = UnixStream::connect(path).await.with_context(|| {
+            anyhow!(
+                "Error connecting to {}, is evil_mount running?",
+                path.display()
+            )
+        })?;
+        stream.write_all(format!("{command}\n").as_bytes()).await?;
+        let mut reply = String::new();
+        stream.read_to_string(&mut reply).await?;
+        match reply.strip_prefix("Error: ") {
+            Some(error) => Err(anyhow!("{}", error.trim_end())),
+            None => Ok(reply),
+        }
+    }
+}
@@ -0,0 +1,901 @@
+use anyhow::{anyhow, Context, Result};
+use blake3::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncWriteExt},
+};
+use tracing::debug;
+use walkdir::WalkDir;
+
+use crate::{
+    delta::{append_copy, delta_copy, DeltaCopy},
+    filter::Symlinks,
+    hash::hash_file,
+    meta::{METADATA_DIR_NAME, TEMP_SUFFIX},
+    reflink::clone_file,
+    resume::{can_resume, copy_resumable, is_resumable, remove_journal, RESUME_MIN_SIZE},
+    sparse::{copy_sparse, is_sparse},
+    throttle::Throttle,
+    vfs::{RealFs, Vfs},
+    winfs::{is_sharing_violation, long_path},
+};
+
+/// How much streaming copies read at a time
+const STREAM_CHUNK_SIZE: usize = 64 * 1024;
+
+/// How often a copy of a file that another program has locked is tried again, waiting twice
+/// as long each time
+const LOCKED_RETRIES: usize = 4;
+const LOCKED_RETRY_DELAY: Duration = Duration::from_millis(250);
+
+/// Maps a path inside work_dir to the same relative path inside backup_dir
+pub fn dst_path_for(path: &Path, work_dir: &Path, backup_dir: &Path) -> Result<PathBuf> {
+    let new_path = path.strip_prefix(work_dir).with_context(|| {
+        anyhow!(
+            "Error stripping prefix {} from {}",
+            work_dir.display(),
+            path.display()
+        )
+    })?;
+    let mut dst_path = backup_dir.to_path_buf();
+    dst_path.push(new_path);
+    Ok(dst_path)
+}
+
+/// Removes the backup of `path` from `vfs`, along with any directories that are left empty
+pub async fn remove_from_dst(
+    vfs: &dyn Vfs,
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+) -> Result<()> {
+    let dst_path = dst_path_for(path, work_dir, backup_dir)?;
+    vfs.remove(&dst_path).await?;
+    prune_empty_parents_in(vfs, &dst_path, backup_dir).await;
+    Ok(())
+}
+
+/// Moves the backup of `from` to where the backup of `to` goes in `vfs`, removing any
+/// directories that are left empty
+pub async fn rename_in_dst(
+    vfs: &dyn Vfs,
+    from: &Path,
+    to: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+) -> Result<()> {
+    let dst_from = dst_path_for(from, work_dir, backup_dir)?;
+    let dst_to = dst_path_for(to, work_dir, backup_dir)?;
+    vfs.rename(&dst_from, &dst_to).await?;
+    prune_empty_parents_in(vfs, &dst_from, backup_dir).await;
+    Ok(())
+}
+
+/// Removes `dst_path` and any directories above it that are left empty, up to `root`
+pub async fn remove_and_prune(dst_path: &Path, root: &Path) -> Result<()> {
+    // Already gone, the directories above it might still need cleaning up
+    RealFs.remove(dst_path).await?;
+    prune_empty_parents(dst_path, root).await;
+    Ok(())
+}
+
+/// Removes the directories above `path` that are empty, up to `root`. Stops at the first
+/// directory that still has something in it
+pub async fn prune_empty_parents(path: &Path, root: &Path) {
+    prune_empty_parents_in(&RealFs, path, root).await
+}
+
+async fn prune_empty_parents_in(vfs: &dyn Vfs, path: &Path, root: &Path) {
+    let mut dir = path.parent();
+    while let Some(parent) = dir {
+        if parent == root || !parent.starts_with(root) {
+            break;
+        }
+        if !vfs.remove_empty_dir(parent).await.unwrap_or(false) {
+            break;
+        }
+        dir = parent.parent();
+    }
+}
+
+/// Settings for how individual files are copied
+#[derive(Debug, Clone, Default)]
+pub struct CopyOptions {
+    /// How much of a copy is flushed to disk before it counts as done
+    pub durability: Durability,
+    /// Metadata that is carried over from the original file
+    pub preserve: Preserve,
+    /// Whether links are followed, recreated or skipped
+    pub symlinks: Symlinks,
+    /// Files at least this big only have their changed blocks written when an older copy
+    /// already exists
+    pub delta_min_size: Option<u64>,
+    /// Whether copies are made as copy-on-write clones
+    pub reflink: Reflink,
+    /// Shared by every copy into the backup, which never write more bytes per second than
+    /// it allows together. Delta copies only write the changed blocks, which aren't limited
+    pub bwlimit: Option<Arc<Throttle>>,
+    /// How copies are checked against what was read from the original
+    pub verify_writes: VerifyWrites,
+    /// Copy files that other programs keep locked out of a Volume Shadow Copy. Only does
+    /// anything on Windows, see [`crate::winfs`]
+    pub shadow_copies: bool,
+    /// Whether copies keep the holes of sparse files
+    pub sparse: Sparse,
+}
+
+/// How a copy is checked against what was read from the original. Either way, the data is
+/// hashed as it's read and a mismatch fails the copy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum VerifyWrites {
+    /// Copies aren't checked
+    #[default]
+    #[serde(rename = "off")]
+    Off,
+    /// Every byte read was written
+    #[serde(rename = "hash")]
+    Hash,
+    /// The copy is read back and hashes the same
+    #[serde(rename = "read-back")]
+    ReadBack,
+}
+
+impl FromStr for VerifyWrites {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "off" => Ok(Self::Off),
+            "hash" => Ok(Self::Hash),
+            "read-back" => Ok(Self::ReadBack),
+            _ => Err(format!(
+                "unknown write verification {s}, expected off, hash or read-back"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for VerifyWrites {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Off => "off",
+            Self::Hash => "hash",
+            Self::ReadBack => "read-back",
+        })
+    }
+}
+
+/// How much of a copy is flushed to disk before it counts as done. Without flushing, a
+/// power loss can leave copies that were reported as done empty or missing
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Durability {
+    /// Leave it to the operating system
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Flush the data of every copy before it's moved into place
+    #[serde(rename = "data")]
+    Data,
+    /// Also flush its metadata, and the directory it's moved into along with the
+    /// directories created for it, so the move itself survives
+    #[serde(rename = "full")]
+    Full,
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "data" => Ok(Self::Data),
+            "full" => Ok(Self::Full),
+            _ => Err(format!(
+                "unknown durability {s}, expected none, data or full"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Data => "data",
+            Self::Full => "full",
+        })
+    }
+}
+
+/// When a copy is made as a copy-on-write clone, which shares its data with the original
+/// until either changes. Only Btrfs, XFS and APFS can clone, and only within one filesystem
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Reflink {
+    /// Clone when possible, copy otherwise
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// Fail copies that can't be cloned
+    #[serde(rename = "always")]
+    Always,
+    /// Always copy the data
+    #[serde(rename = "never")]
+    Never,
+}
+
+impl FromStr for Reflink {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown reflink mode {s}, expected auto, always or never"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Reflink {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
+
+/// When a copy leaves holes where the original has nothing but zeros, instead of writing
+/// them out and taking up the space. Clones and delta copies keep whatever the original or
+/// the older copy has
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Sparse {
+    /// Keep the holes of files that have any
+    #[default]
+    #[serde(rename = "auto")]
+    Auto,
+    /// Also turn runs of zeros into holes in files that have none
+    #[serde(rename = "always")]
+    Always,
+    /// Copy the data as it is, which can fill in holes
+    #[serde(rename = "never")]
+    Never,
+}
+
+impl FromStr for Sparse {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "auto" => Ok(Self::Auto),
+            "always" => Ok(Self::Always),
+            "never" => Ok(Self::Never),
+            _ => Err(format!(
+                "unknown sparse mode {s}, expected auto, always or never"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Sparse {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Auto => "auto",
+            Self::Always => "always",
+            Self::Never => "never",
+        })
+    }
+}
+
+/// Which metadata of the original file a copy keeps, parsed from a comma separated list
+/// like `mode,times,owner,xattr`
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct Preserve {
+    /// Permission bits, including the executable bit
+    pub mode: bool,
+    /// Access and modification times
+    pub times: bool,
+    /// User and group, which usually needs root
+    pub owner: bool,
+    /// Extended attributes
+    pub xattr: bool,
+}
+
+impl Preserve {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+impl FromStr for Preserve {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut preserve = Self::default();
+        for attribute in s.split(',').map(str::trim).filter(|attr| !attr.is_empty()) {
+            match attribute {
+                "mode" => preserve.mode = true,
+                "times" => preserve.times = true,
+                "owner" => preserve.owner = true,
+                "xattr" => preserve.xattr = true,
+                _ => {
+                    return Err(format!(
+                        "unknown attribute {attribute}, expected mode, times, owner or xattr"
+                    ))
+                }
+            }
+        }
+        Ok(preserve)
+    }
+}
+
+impl TryFrom<String> for Preserve {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for Preserve {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let attributes: Vec<&str> = [
+            (self.mode, "mode"),
+            (self.times, "times"),
+            (self.owner, "owner"),
+            (self.xattr, "xattr"),
+        ]
+        .into_iter()
+        .filter_map(|(enabled, name)| enabled.then_some(name))
+        .collect();
+        f.write_str(&attributes.join(","))
+    }
+}
+
+impl From<Preserve> for String {
+    fn from(preserve: Preserve) -> Self {
+        preserve.to_string()
+    }
+}
+
+/// Where a copy to `dst_path` is written before it's renamed into place
+pub fn temp_path_for(dst_path: &Path) -> PathBuf {
+    let mut file_name = dst_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(TEMP_SUFFIX);
+    dst_path.with_file_name(file_name)
+}
+
+async fn remove_if_exists(path: &Path) -> io::Result<()> {
+    match fs::remove_file(path).await {
+        Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+        _ => Ok(()),
+    }
+}
+
+/// Copies `path` from work_dir into the same place in backup_dir, both in `vfs`. On a
+/// [`RealFs`] that's [`copy_file`], so an interrupted copy never leaves a truncated file
+/// behind
+pub async fn copy_to_dst(
+    vfs: &dyn Vfs,
+    path: &Path,
+    work_dir: &Path,
+    backup_dir: &Path,
+    options: &CopyOptions,
+) -> Result<()> {
+    let dst_path = dst_path_for(path, work_dir, backup_dir)?;
+    vfs.copy(path, &dst_path, options).await.map(|_| ())
+}
+
+/// Copies `path` to `dst_path` through a temporary file, creating the directories above
+/// `dst_path` if needed. When [`CopyOptions::verify_writes`] is on, returns the hash of
+/// what was read from `path`, which the copy was checked against
+pub async fn copy_file(
+    path: &Path,
+    dst_path: &Path,
+    options: &CopyOptions,
+) -> Result<Option<Hash>> {
+    let (path, dst_path) = (long_path(path), long_path(dst_path));
+    let mut delay = LOCKED_RETRY_DELAY;
+    for _ in 0..LOCKED_RETRIES {
+        match copy_file_once(&path, &dst_path, options).await {
+            Err(err) if is_sharing_violation(&err) => {
+                debug!("{} is locked, trying again in {delay:?}", path.display());
+                tokio::time::sleep(delay).await;
+                delay *= 2;
+            }
+            copied => return copied,
+        }
+    }
+    match copy_file_once(&path, &dst_path, options).await {
+        #[cfg(windows)]
+        Err(err) if options.shadow_copies && is_sharing_violation(&err) => {
+            crate::winfs::copy_from_shadow_copy(&path, &dst_path, options).await
+        }
+        copied => copied,
+    }
+}
+
+/// [`copy_file`] without trying again when another program has `path` locked
+pub(crate) async fn copy_file_once(
+    path: &Path,
+    dst_path: &Path,
+    options: &CopyOptions,
+) -> Result<Option<Hash>> {
+    let dst_path = dst_path.to_path_buf();
+    let tmp_path = temp_path_for(&dst_path);
+
+    if let Some(parent) = dst_path.parent() {
+        create_dirs(parent, options.durability).await?;
+    }
+
+    // Big copies that were cut off carry on where they stopped
+    let resuming = can_resume(path, &tmp_path).await;
+    if !resuming {
+        // A temp file left over from an earlier copy might be write protected
+        remove_if_exists(&tmp_path)
+            .await
+            .with_context(|| anyhow!("Error removing file {}", tmp_path.display()))?;
+        remove_journal(&tmp_path).await;
+    }
+
+    if options.symlinks == Symlinks::Recreate && fs::symlink_metadata(path).await?.is_symlink() {
+        // Links have no contents to verify
+        recreate_symlink(path, &dst_path, &tmp_path).await?;
+        sync_parent(&dst_path, options.durability).await?;
+        return Ok(None);
+    }
+
+    let cloned = match options.reflink {
+        _ if resuming => Ok(false),
+        Reflink::Never => Ok(false),
+        reflink => tokio::task::spawn_blocking({
+            let (path, tmp_path) = (path.to_path_buf(), tmp_path.clone());
+            move || clone_file(&path, &tmp_path)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|cloned| match (cloned, reflink) {
+            (Ok(false), Reflink::Always) => Err(anyhow!(
+                "{} can't be cloned, the filesystem doesn't support it or {} is on another one",
+                path.display(),
+                dst_path.display()
+            )),
+            (cloned, _) => cloned.map_err(anyhow::Error::from),
+        }),
+    };
+    let delta = match cloned {
+        // A clone is already as cheap as it gets
+        Ok(true) => Ok(DeltaCopy::Assembled),
+        Err(err) => Err(err),
+        Ok(false) if resuming => Ok(DeltaCopy::Skipped),
+        Ok(false) => tokio::task::spawn_blocking({
+            let (path, dst_path, tmp_path) =
+                (path.to_path_buf(), dst_path.clone(), tmp_path.clone());
+            let delta_min_size = options.delta_min_size;
+            move || match (append_copy(&path, &dst_path)?, delta_min_size) {
+                (DeltaCopy::Skipped, Some(min_size)) => {
+                    delta_copy(&path, &dst_path, &tmp_path, min_size)
+                }
+                (delta, _) => Ok(delta),
+            }
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|delta| delta),
+    };
+    let streams = options.bwlimit.is_some() || options.verify_writes != VerifyWrites::Off;
+    let sparse = match options.sparse {
+        Sparse::Never => false,
+        Sparse::Always => true,
+        Sparse::Auto => fs::metadata(path)
+            .await
+            .is_ok_and(|metadata| is_sparse(&metadata)),
+    };
+    let resumable = resuming
+        || (!sparse
+            && fs::metadata(path)
+                .await
+                .is_ok_and(|metadata| metadata.len() >= RESUME_MIN_SIZE));
+    let copied = match delta {
+        Ok(DeltaCopy::Skipped) if resumable => {
+            copy_resumable(path, &tmp_path, options.bwlimit.as_deref())
+                .await
+                .map(|read| (DeltaCopy::Skipped, Some(read)))
+                .map_err(anyhow::Error::from)
+        }
+        Ok(DeltaCopy::Skipped) if sparse => tokio::task::spawn_blocking({
+            let (path, tmp_path) = (path.to_path_buf(), tmp_path.clone());
+            let zeros = options.sparse == Sparse::Always;
+            let hash = options.verify_writes != VerifyWrites::Off;
+            let bwlimit = options.bwlimit.clone();
+            move || copy_sparse(&path, &tmp_path, zeros, hash, bwlimit)
+        })
+        .await
+        .map_err(anyhow::Error::from)
+        .and_then(|copied| {
+            let (hash, len) = copied?;
+            Ok((DeltaCopy::Skipped, hash.map(|hash| (hash, len))))
+        }),
+        Ok(DeltaCopy::Skipped) if streams => {
+            copy_streaming(path, &tmp_path, options.bwlimit.as_deref())
+                .await
+                .map(|read| (DeltaCopy::Skipped, Some(read)))
+                .map_err(anyhow::Error::from)
+        }
+        Ok(DeltaCopy::Skipped) => fs::copy(path, &tmp_path)
+            .await
+            .map(|_| (DeltaCopy::Skipped, None))
+            .map_err(anyhow::Error::from),
+        Ok(delta) => Ok((delta, None)),
+        Err(err) => Err(err),
+    };
+    let (delta, read) = match copied {
+        Ok(copied) => copied,
+        Err(err) => {
+            // What was written so far is picked up by the next copy
+            if !resumable {
+                let _ = remove_if_exists(&tmp_path).await;
+            }
+            return Err(err).with_context(|| {
+                anyhow!(
+                    "Error copying from {} to {}",
+                    path.display(),
+                    tmp_path.display()
+                )
+            });
+        }
+    };
+
+    if resumable {
+        remove_journal(&tmp_path).await;
+    }
+
+    // The changes were written straight into the destination, so there's nothing to move
+    if delta == DeltaCopy::InPlace {
+        let hash = match options.verify_writes {
+            VerifyWrites::Off => None,
+            verify => Some(verify_copy(path, &dst_path, read, verify).await?),
+        };
+        return finish_copy(path, &dst_path, options).await.map(|()| hash);
+    }
+
+    let hash = match options.verify_writes {
+        VerifyWrites::Off => Ok(None),
+        verify => verify_copy(path, &tmp_path, read, verify).await.map(Some),
+    };
+    let hash = match hash {
+        Ok(hash) => hash,
+        Err(err) => {
+            let _ = remove_if_exists(&tmp_path).await;
+            return Err(err);
+        }
+    };
+
+    if let Err(err) = finish_copy(path, &tmp_path, options).await {
+        let _ = remove_if_exists(&tmp_path).await;
+        return Err(err);
+    }
+
+    if let Err(err) = fs::rename(&tmp_path, &dst_path).await {
+        // Some platforms refuse to replace a write protected file, so get it out of the way
+        let renamed = match err.kind() {
+            io::ErrorKind::PermissionDenied => match remove_if_exists(&dst_path).await {
+                Ok(()) => fs::rename(&tmp_path, &dst_path).await,
+                Err(err) => Err(err),
+            },
+            _ => Err(err),
+        };
+        if let Err(err) = renamed {
+            let _ = remove_if_exists(&tmp_path).await;
+            return Err(err).with_context(|| {
+                anyhow!(
+                    "Error moving {} into place at {}",
+                    tmp_path.display(),
+                    dst_path.display()
+                )
+            });
+        }
+    }
+    sync_parent(&dst_path, options.durability).await?;
+
+    Ok(hash)
+}
+
+/// Creates `dir` and the directories above it that are missing. With [`Durability::Full`],
+/// each of them is flushed into the directory it was created in
+pub(crate) async fn create_dirs(dir: &Path, durability: Durability) -> Result<()> {
+    if durability != Durability::Full {
+        fs::create_dir_all(dir).await?;
+        return Ok(());
+    }
+
+    let mut missing = Vec::new();
+    for ancestor in dir.ancestors() {
+        if fs::try_exists(ancestor).await? {
+            break;
+        }
+        missing.push(ancestor);
+    }
+    fs::create_dir_all(dir).await?;
+    for created in missing.into_iter().rev() {
+        sync_parent(created, durability).await?;
+    }
+    Ok(())
+}
+
+/// Flushes the directory `path` is in with [`Durability::Full`], so a file that was just
+/// moved or created there is still there after a crash. Windows can't open directories
+/// for that, and NTFS journals them anyway
+pub(crate) async fn sync_parent(path: &Path, durability: Durability) -> Result<()> {
+    let Some(parent) = path
+        .parent()
+        .filter(|_| durability == Durability::Full && cfg!(unix))
+    else {
+        return Ok(());
+    };
+    let synced = match fs::File::open(parent).await {
+        Ok(dir) => dir.sync_all().await,
+        Err(err) => Err(err),
+    };
+    synced.with_context(|| anyhow!("Error syncing {}", parent.display()))
+}
+
+/// Like [`fs::copy`], but hashing the data on its way through, and no faster than `bwlimit`
+/// allows. Returns the hash and the length of what was read
+pub(crate) async fn copy_streaming(
+    path: &Path,
+    dst_path: &Path,
+    bwlimit: Option<&Throttle>,
+) -> io::Result<(Hash, u64)> {
+    let mut src = fs::File::open(path).await?;
+    let permissions = src.metadata().await?.permissions();
+    let mut dst = fs::File::create(dst_path).await?;
+
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; STREAM_CHUNK_SIZE];
+    let mut len = 0;
+    loop {
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        if let Some(bwlimit) = bwlimit {
+            bwlimit.take(read).await;
+        }
+        dst.write_all(&buf[..read]).await?;
+        len += read as u64;
+    }
+    dst.flush().await?;
+    drop(dst);
+
+    fs::set_permissions(dst_path, permissions).await?;
+    Ok((hasher.finalize(), len))
+}
+
+/// Checks that `copy_path` holds what was read from `path`, returning its hash. `read` is
+/// the hash and length of what a streaming copy read. Clones and delta copies didn't read
+/// everything, so both files are hashed for them
+pub(crate) async fn verify_copy(
+    path: &Path,
+    copy_path: &Path,
+    read: Option<(Hash, u64)>,
+    verify: VerifyWrites,
+) -> Result<Hash> {
+    let (path, copy_path) = (path.to_path_buf(), copy_path.to_path_buf());
+    tokio::task::spawn_blocking(move || {
+        let verified = match read {
+            Some((hash, len)) if verify == VerifyWrites::Hash => {
+                let written = std::fs::metadata(&copy_path)
+                    .with_context(|| anyhow!("Error reading metadata of {}", copy_path.display()))?
+                    .len();
+                (written == len).then_some(hash)
+            }
+            Some((hash, _)) => (hash_file(&copy_path)? == hash).then_some(hash),
+            None => {
+                let hash = hash_file(&path)?;
+                (hash_file(&copy_path)? == hash).then_some(hash)
+            }
+        };
+        verified.ok_or_else(|| {
+            anyhow!(
+                "{} doesn't match what was read from {}",
+                copy_path.display(),
+                path.display()
+            )
+        })
+    })
+    .await?
+}
+
+/// Carries the metadata of `path` over to its copy at `copy_path`, and flushes the copy to
+/// disk as [`CopyOptions::durability`] asks
+pub(crate) async fn finish_copy(
+    path: &Path,
+    copy_path: &Path,
+    options: &CopyOptions,
+) -> Result<()> {
+    if !options.preserve.is_empty() {
+        tokio::task::spawn_blocking({
+            let (path, copy_path) = (path.to_path_buf(), copy_path.to_path_buf());
+            let preserve = options.preserve;
+            move || preserve_metadata(&path, &copy_path, preserve)
+        })
+        .await??;
+    }
+
+    if options.durability != Durability::None {
+        let synced = match fs::OpenOptions::new().write(true).open(copy_path).await {
+            Ok(file) if options.durability == Durability::Data => file.sync_data().await,
+            Ok(file) => file.sync_all().await,
+            Err(err) => Err(err),
+        };
+        synced.with_context(|| anyhow!("Error syncing {}", copy_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Creates a link at `dst_path` that points wherever the link at `path` points
+async fn recreate_symlink(path: &Path, dst_path: &Path, tmp_path: &Path) -> Result<()> {
+    let target = fs::read_link(path)
+        .await
+        .with_context(|| anyhow!("Error reading link {}", path.display()))?;
+
+    #[cfg(unix)]
+    let created = fs::symlink(&target, tmp_path).await;
+    #[cfg(windows)]
+    let created = match path.is_dir() {
+        true => fs::symlink_dir(&target, tmp_path).await,
+        false => fs::symlink_file(&target, tmp_path).await,
+    };
+    created.with_context(|| anyhow!("Error creating link {}", tmp_path.display()))?;
+
+    if let Err(err) = fs::rename(tmp_path, dst_path).await {
+        let _ = remove_if_exists(tmp_path).await;
+        return Err(err).with_context(|| {
+            anyhow!(
+                "Error moving {} into place at {}",
+                tmp_path.display(),
+                dst_path.display()
+            )
+        });
+    }
+
+    Ok(())
+}
+
+/// Applies the metadata of `path` that `preserve` asks for to `dst_path`. Ownership goes
+/// first since changing it can clear the setuid bits. This does blocking IO
+fn preserve_metadata(path: &Path, dst_path: &Path, preserve: Preserve) -> Result<()> {
+    let metadata = std::fs::metadata(path)
+        .with_context(|| anyhow!("Error reading metadata of {}", path.display()))?;
+
+    #[cfg(unix)]
+    if preserve.owner {
+        use std::os::unix::fs::MetadataExt;
+        nix::unistd::chown(
+            dst_path,
+            Some(nix::unistd::Uid::from_raw(metadata.uid())),
+            Some(nix::unistd::Gid::from_raw(metadata.gid())),
+        )
+        .with_context(|| anyhow!("Error changing the owner of {}", dst_path.display()))?;
+    }
+
+    if preserve.mode {
+        std::fs::set_permissions(dst_path, metadata.permissions())
+            .with_context(|| anyhow!("Error setting permissions of {}", dst_path.display()))?;
+    }
+
+    #[cfg(unix)]
+    if preserve.xattr {
+        for name in xattr::list(path)
+            .with_context(|| anyhow!("Error listing extended attributes of {}", path.display()))?
+        {
+            if let Some(value) = xattr::get(path, &name)? {
+                xattr::set(dst_path, &name, &value).with_context(|| {
+                    anyhow!(
+                        "Error setting extended attribute {} of {}",
+                        name.to_string_lossy(),
+                        dst_path.display()
+                    )
+                })?;
+            }
+        }
+    }
+
+    if preserve.times {
+        filetime::set_file_times(
+            dst_path,
+            filetime::FileTime::from_last_access_time(&metadata),
+            filetime::FileTime::from_last_modification_time(&metadata),
+        )
+        .with_context(|| anyhow!("Error setting times of {}", dst_path.display()))?;
+    }
+
+    Ok(())
+}
+
+/// Removes the temp files left behind in `dir` by copies that were interrupted, returns how
+/// many were removed. Those of copies that can be resumed are kept. This does blocking IO
+pub fn clean_temp_files(dir: &Path) -> Result<usize> {
+    let mut removed = 0;
+
+    for file_info in WalkDir::new(dir)
+        .into_iter()
+        .filter_entry(|file_info| file_info.file_name() != METADATA_DIR_NAME)
+        .filter_map(|file_info| file_info.ok())
+        .filter(|file_info| file_info.file_type().is_file())
+        .filter(|file_info| {
+            file_info
+                .file_name()
+                .to_string_lossy()
+                .ends_with(TEMP_SUFFIX)
+        })
+        .filter(|file_info| !is_resumable(file_info.path()))
+    {
+        std::fs::remove_file(file_info.path())
+            .with_context(|| anyhow!("Error removing {}", file_info.path().display()))?;
+        removed += 1;
+    }
+
+    Ok(removed)
+}
+
+/// How much more the filesystem of a directory can hold
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct FreeSpace {
+    pub bytes: u64,
+    /// How many more files fit, if the filesystem limits that
+    pub files: Option<u64>,
+}
+
+/// The free space on the filesystem `path` is on, for unprivileged users. Always `None` on
+/// other platforms than unix
+#[cfg(unix)]
+// The types of the fields differ between platforms
+#[allow(clippy::useless_conversion)]
+pub(crate) fn free_space(path: &Path) -> Option<FreeSpace> {
+    let stat = nix::sys::statvfs::statvfs(path).ok()?;
+    Some(FreeSpace {
+        bytes: u64::from(stat.blocks_available()).saturating_mul(u64::from(stat.fragment_size())),
+        files: (stat.files() > 0).then(|| u64::from(stat.files_available())),
+    })
+}
+
+#[cfg(not(unix))]
+pub(crate) fn free_space(path: &Path) -> Option<FreeSpace> {
+    let _ = path;
+    None
+}
+
+/// Sets the modification time of `path`. Links recreated with [`Symlinks::Recreate`] get
+/// the time set on the link itself rather than on whatever it points to
+pub async fn set_mtime(path: &Path, modified: SystemTime) -> Result<()> {
+    let path = path.to_path_buf();
+
+    tokio::task::spawn_blocking(move || {
+        let mtime = filetime::FileTime::from_system_time(modified);
+        let is_symlink = std::fs::symlink_metadata(&path)
+            .map(|metadata| metadata.is_symlink())
+            .unwrap_or(false);
+        match is_symlink {
+            true => filetime::set_symlink_file_times(&path, mtime, mtime),
+            false => filetime::set_file_mtime(&path, mtime),
+        }
+        .with_context(|| anyhow!("Error setting modification time of {}", path.display()))
+    })
+    .await?
+}
@@ -0,0 +1,445 @@
+//! Block level delta copies, so a large file with a few changed blocks doesn't have to be
+//! rewritten completely.
+//!
+//! Works like rsync: the existing copy is split into blocks, and a rolling checksum finds
+//! those blocks anywhere in the new file. When every block that is still there sits at its
+//! old offset and the filesystem can clone files, the existing copy is cloned into a temp
+//! file and only the changed ranges are written to it. Otherwise the new file is assembled
+//! in the temp file from the old blocks and the changed ranges. Either way it's moved into
+//! place whole, so a copy that's cut off never leaves a half patched backup behind.
+//!
+//! Files that only grew, like logs, take a faster path first: when the existing copy is
+//! exactly the start of the new file, only what was added is appended to it. That's the one
+//! change made to the existing copy itself, since what it held before stays as it was
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use std::{
+    collections::HashMap,
+    fs::{File, OpenOptions},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+};
+
+use crate::reflink::clone_file;
+
+/// The size of the blocks that are compared
+const BLOCK_SIZE: usize = 64 * 1024;
+
+/// How a delta copy went
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum DeltaCopy {
+    /// What was added was appended straight to the destination
+    InPlace,
+    /// The new file was assembled in the temp file, which still has to be moved into place
+    Assembled,
+    /// A delta copy doesn't apply, and the file has to be copied as usual
+    Skipped,
+}
+
+/// A piece of the new file
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Op {
+    /// The bytes at `offset` in the new file match block `block` of the old one
+    Copy { block: u64, offset: u64 },
+    /// `len` bytes at `offset` in the new file don't match anything in the old one
+    Literal { offset: u64, len: u64 },
+}
+
+/// The weak checksum from rsync, which can be moved along a byte at a time
+#[derive(Debug, Clone, Copy)]
+struct Rolling {
+    a: u32,
+    b: u32,
+}
+
+impl Rolling {
+    fn new(block: &[u8]) -> Self {
+        let mut a: u32 = 0;
+        let mut b: u32 = 0;
+        for (i, &byte) in block.iter().enumerate() {
+            a = a.wrapping_add(byte as u32);
+            b = b.wrapping_add((block.len() - i) as u32 * byte as u32);
+        }
+        Self { a, b }
+    }
+
+    /// Drops `out` from the front of the window and adds `new` at the end
+    fn roll(&mut self, out: u8, new: u8) {
+        self.a = self.a.wrapping_sub(out as u32).wrapping_add(new as u32);
+        self.b = self
+            .b
+            .wrapping_sub(BLOCK_SIZE as u32 * out as u32)
+            .wrapping_add(self.a);
+    }
+
+    fn digest(&self) -> u32 {
+        (self.a & 0xffff) | (self.b << 16)
+    }
+}
+
+/// Assembles `src` in `tmp_path` out of the existing `dst` and what changed, if `src` is at
+/// least `min_size` bytes and `dst` exists. This does blocking IO
+pub fn delta_copy(src: &Path, dst: &Path, tmp_path: &Path, min_size: u64) -> Result<DeltaCopy> {
+    let src_len = std::fs::metadata(src)?.len();
+    let dst_metadata = match std::fs::symlink_metadata(dst) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(DeltaCopy::Skipped),
+    };
+    if src_len < min_size || dst_metadata.len() < BLOCK_SIZE as u64 {
+        return Ok(DeltaCopy::Skipped);
+    }
+
+    let signatures = signatures(dst).with_context(|| anyhow!("Error reading {}", dst.display()))?;
+    let ops = find_ops(src, src_len, &signatures)
+        .with_context(|| anyhow!("Error reading {}", src.display()))?;
+
+    let unmoved = ops.iter().all(|op| match op {
+        Op::Copy { block, offset } => block * BLOCK_SIZE as u64 == *offset,
+        Op::Literal { .. } => true,
+    });
+
+    let mut src_file = File::open(src)?;
+    let cloned = unmoved && clone_file(dst, tmp_path)?;
+    let mut old_file = File::open(dst)?;
+    let mut tmp_file = match cloned {
+        true => OpenOptions::new().write(true).open(tmp_path)?,
+        false => File::create(tmp_path)?,
+    };
+    for op in &ops {
+        match *op {
+            Op::Copy { .. } if cloned => {}
+            Op::Copy { block, offset } => copy_range(
+                &mut old_file,
+                block * BLOCK_SIZE as u64,
+                BLOCK_SIZE as u64,
+                &mut tmp_file,
+                offset,
+            )?,
+            Op::Literal { offset, len } => {
+                copy_range(&mut src_file, offset, len, &mut tmp_file, offset)?
+            }
+        }
+    }
+    if cloned {
+        tmp_file.set_len(src_len)?;
+    }
+    tmp_file.flush()?;
+    Ok(DeltaCopy::Assembled)
+}
+
+/// Appends what `src` has past the end of the existing `dst` to it, if `dst` is at least a
+/// block long and holds exactly the start of `src`. Comparing stops at the first block that
+/// differs, and the file has to be copied some other way then. This does blocking IO
+pub fn append_copy(src: &Path, dst: &Path) -> Result<DeltaCopy> {
+    let src_len = std::fs::metadata(src)?.len();
+    let dst_metadata = match std::fs::symlink_metadata(dst) {
+        Ok(metadata) if metadata.is_file() => metadata,
+        _ => return Ok(DeltaCopy::Skipped),
+    };
+    let dst_len = dst_metadata.len();
+    if dst_len < BLOCK_SIZE as u64 || dst_len >= src_len || !writable_in_place(&dst_metadata) {
+        return Ok(DeltaCopy::Skipped);
+    }
+
+    let mut src_file = File::open(src)?;
+    let prefix_matches = same_prefix(&mut src_file, dst, dst_len)
+        .with_context(|| anyhow!("Error comparing {} with {}", src.display(), dst.display()))?;
+    if !prefix_matches {
+        return Ok(DeltaCopy::Skipped);
+    }
+
+    let mut dst_file = OpenOptions::new().write(true).open(dst)?;
+    copy_range(
+        &mut src_file,
+        dst_len,
+        src_len - dst_len,
+        &mut dst_file,
+        dst_len,
+    )?;
+    Ok(DeltaCopy::InPlace)
+}
+
+/// Whether the first `len` bytes of `src` are what `dst` holds
+fn same_prefix(src: &mut File, dst: &Path, len: u64) -> io::Result<bool> {
+    let mut dst = File::open(dst)?;
+    let mut src_block = vec![0; BLOCK_SIZE];
+    let mut dst_block = vec![0; BLOCK_SIZE];
+    let mut compared = 0;
+    while compared < len {
+        let n = (len - compared).min(BLOCK_SIZE as u64) as usize;
+        src.read_exact(&mut src_block[..n])?;
+        dst.read_exact(&mut dst_block[..n])?;
+        if src_block[..n] != dst_block[..n] {
+            return Ok(false);
+        }
+        compared += n as u64;
+    }
+    Ok(true)
+}
+
+/// Whether the copy at `dst` may be changed instead of replaced. Snapshots hard link the
+/// backup, so a linked file has to be replaced, and a write protected one can still be
+/// replaced, but not written to
+fn writable_in_place(metadata: &std::fs::Metadata) -> bool {
+    #[cfg(unix)]
+    let linked = std::os::unix::fs::MetadataExt::nlink(metadata) > 1;
+    #[cfg(not(unix))]
+    let linked = true;
+    !linked && !metadata.permissions().readonly()
+}
+
+/// The weak and strong checksum of every whole block of `path`, keyed by the weak one
+fn signatures(path: &Path) -> io::Result<HashMap<u32, Vec<(u64, Hash)>>> {
+    let mut file = File::open(path)?;
+    let mut signatures: HashMap<u32, Vec<(u64, Hash)>> = HashMap::new();
+    let mut block = vec![0; BLOCK_SIZE];
+
+    for index in 0.. {
+        match file.read_exact(&mut block) {
+            Ok(()) => {}
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        }
+        signatures
+            .entry(Rolling::new(&block).digest())
+            .or_default()
+            .push((index, blake3::hash(&block)));
+    }
+
+    Ok(signatures)
+}
+
+/// Splits `src` into ranges that match blocks of the old file and ranges that don't
+fn find_ops(
+    src: &Path,
+    src_len: u64,
+    signatures: &HashMap<u32, Vec<(u64, Hash)>>,
+) -> io::Result<Vec<Op>> {
+    let mut file = File::open(src)?;
+    let mut ops = Vec::new();
+
+    // `buf` holds the file from `buf_offset` on, and always covers the window at `pos`
+    let mut buf: Vec<u8> = Vec::with_capacity(BLOCK_SIZE * 4);
+    let mut buf_offset = 0;
+    let mut pos = 0;
+    let mut literal_start = 0;
+    let mut rolling: Option<Rolling> = None;
+
+    while pos + BLOCK_SIZE as u64 <= src_len {
+        // One byte past the window is needed to roll
+        let needed = (pos - buf_offset) as usize + BLOCK_SIZE + 1;
+        if buf.len() < needed && buf_offset + (buf.len() as u64) < src_len {
+            let consumed = (pos - buf_offset) as usize;
+            buf.drain(..consumed);
+            buf_offset = pos;
+            let mut chunk = vec![0; BLOCK_SIZE * 4];
+            let read = file.read(&mut chunk)?;
+            if read == 0 {
+                // The file shrank, copying the rest fails with a clearer error
+                break;
+            }
+            buf.extend_from_slice(&chunk[..read]);
+            continue;
+        }
+
+        let start = (pos - buf_offset) as usize;
+        let window = &buf[start..start + BLOCK_SIZE];
+        let weak = rolling.get_or_insert_with(|| Rolling::new(window)).digest();
+
+        let matched = signatures.get(&weak).and_then(|candidates| {
+            let strong = blake3::hash(window);
+            let mut matches = candidates.iter().filter(|(_, hash)| *hash == strong);
+            // A block at its old offset lets the copy be done in place
+            let first = matches.next()?.0;
+            Some(
+                std::iter::once(first)
+                    .chain(matches.map(|(block, _)| *block))
+                    .find(|block| block * BLOCK_SIZE as u64 == pos)
+                    .unwrap_or(first),
+            )
+        });
+
+        match matched {
+            Some(block) => {
+                if literal_start < pos {
+                    ops.push(Op::Literal {
+                        offset: literal_start,
+                        len: pos - literal_start,
+                    });
+                }
+                ops.push(Op::Copy { block, offset: pos });
+                pos += BLOCK_SIZE as u64;
+                literal_start = pos;
+                rolling = None;
+            }
+            None => {
+                match buf.get(start + BLOCK_SIZE) {
+                    Some(&new) => rolling.as_mut().unwrap().roll(window[0], new),
+                    None => rolling = None,
+                }
+                pos += 1;
+            }
+        }
+    }
+
+    if literal_start < src_len {
+        ops.push(Op::Literal {
+            offset: literal_start,
+            len: src_len - literal_start,
+        });
+    }
+
+    Ok(ops)
+}
+
+fn copy_range(
+    from: &mut File,
+    from_offset: u64,
+    len: u64,
+    to: &mut File,
+    to_offset: u64,
+) -> io::Result<()> {
+    from.seek(SeekFrom::Start(from_offset))?;
+    to.seek(SeekFrom::Start(to_offset))?;
+    let copied = io::copy(&mut from.take(len), to)?;
+    match copied == len {
+        true => Ok(()),
+        false => Err(io::Error::new(
+            io::ErrorKind::UnexpectedEof,
+            "file shrank while it was being copied",
+        )),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    /// `len` bytes that don't repeat, so every block is different
+    fn contents(len: usize) -> Vec<u8> {
+        let mut state: u32 = 0x9e37_79b9;
+        (0..len)
+            .map(|_| {
+                state ^= state << 13;
+                state ^= state >> 17;
+                state ^= state << 5;
+                state as u8
+            })
+            .collect()
+    }
+
+    /// Delta copies `new` over `old`, returning how and the pieces `new` was made of
+    fn delta(old: &[u8], new: &[u8]) -> (DeltaCopy, Vec<Op>) {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst, tmp_path) = (
+            dir.path().join("src"),
+            dir.path().join("dst"),
+            dir.path().join("tmp"),
+        );
+        std::fs::write(&src, new).unwrap();
+        std::fs::write(&dst, old).unwrap();
+        let copied = delta_copy(&src, &dst, &tmp_path, 0).unwrap();
+        if copied == DeltaCopy::Assembled {
+            assert_eq!(std::fs::read(&tmp_path).unwrap(), new);
+        }
+        // The existing copy is never changed
+        assert_eq!(std::fs::read(&dst).unwrap(), old);
+        let ops = find_ops(&src, new.len() as u64, &signatures(&dst).unwrap()).unwrap();
+        (copied, ops)
+    }
+
+    const BLOCK: u64 = BLOCK_SIZE as u64;
+
+    fn copies(ops: &[Op]) -> Vec<(u64, u64)> {
+        let copies = ops.iter().filter_map(|op| match *op {
+            Op::Copy { block, offset } => Some((block, offset)),
+            Op::Literal { .. } => None,
+        });
+        copies.collect()
+    }
+
+    #[test]
+    fn unchanged_files_are_made_of_the_old_blocks() {
+        let old = contents(4 * BLOCK_SIZE);
+        let (copied, ops) = delta(&old, &old);
+        assert_eq!(copied, DeltaCopy::Assembled);
+        assert_eq!(
+            copies(&ops),
+            [(0, 0), (1, BLOCK), (2, 2 * BLOCK), (3, 3 * BLOCK)]
+        );
+    }
+
+    #[test]
+    fn only_a_changed_block_is_new() {
+        let old = contents(4 * BLOCK_SIZE);
+        let mut new = old.clone();
+        new[2 * BLOCK_SIZE + 10] ^= 1;
+        let (_, ops) = delta(&old, &new);
+        let literal = Op::Literal {
+            offset: 2 * BLOCK,
+            len: BLOCK,
+        };
+        assert_eq!(ops.iter().filter(|op| **op == literal).count(), 1);
+        assert_eq!(copies(&ops), [(0, 0), (1, BLOCK), (3, 3 * BLOCK)]);
+    }
+
+    #[test]
+    fn insertions_shift_the_blocks_after_them() {
+        let old = contents(3 * BLOCK_SIZE);
+        let new = [&old[..BLOCK_SIZE], b"inserted", &old[BLOCK_SIZE..]].concat();
+        let (_, ops) = delta(&old, &new);
+        assert_eq!(copies(&ops), [(0, 0), (1, BLOCK + 8), (2, 2 * BLOCK + 8)]);
+        assert!(ops.contains(&Op::Literal {
+            offset: BLOCK,
+            len: 8
+        }));
+    }
+
+    #[test]
+    fn truncated_files_keep_their_start() {
+        let old = contents(4 * BLOCK_SIZE);
+        let new = &old[..2 * BLOCK_SIZE + 100];
+        let (_, ops) = delta(&old, new);
+        assert_eq!(copies(&ops), [(0, 0), (1, BLOCK)]);
+    }
+
+    #[test]
+    fn grown_files_are_appended_to() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        let new = contents(3 * BLOCK_SIZE);
+        std::fs::write(&src, &new).unwrap();
+        std::fs::write(&dst, &new[..BLOCK_SIZE + 5]).unwrap();
+        assert_eq!(append_copy(&src, &dst).unwrap(), DeltaCopy::InPlace);
+        assert_eq!(std::fs::read(&dst).unwrap(), new);
+
+        let (_, ops) = delta(&new[..BLOCK_SIZE + 5], &new);
+        assert_eq!(copies(&ops), [(0, 0)]);
+    }
+
+    #[test]
+    fn small_files_are_copied_as_usual() {
+        let dir = tempfile::tempdir().unwrap();
+        let (src, dst) = (dir.path().join("src"), dir.path().join("dst"));
+        std::fs::write(&src, contents(BLOCK_SIZE - 1)).unwrap();
+        std::fs::write(&dst, contents(100)).unwrap();
+        let tmp_path = dir.path().join("tmp");
+        assert_eq!(
+            delta_copy(&src, &dst, &tmp_path, 0).unwrap(),
+            DeltaCopy::Skipped
+        );
+        assert_eq!(append_copy(&src, &dst).unwrap(), DeltaCopy::Skipped);
+
+        // Smaller than a block, the new file has nothing in common with the old one
+        let old = contents(2 * BLOCK_SIZE);
+        let (_, ops) = delta(&old, &old[..100]);
+        assert_eq!(
+            ops,
+            [Op::Literal {
+                offset: 0,
+                len: 100
+            }]
+        );
+    }
+}
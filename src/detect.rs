@@ -0,0 +1,804 @@
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    collections::HashMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    hash::{hash_file, HashPool},
+    unicode::NormalizeUnicode,
+    vfs::Entry,
+};
+
+/// How the sync loop decides that a file has changed and needs copying
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum DetectChanges {
+    /// The modification time moved forward, to the nanosecond, or the size changed
+    #[default]
+    #[serde(rename = "mtime")]
+    Mtime,
+    /// Either the size or the modification time is different
+    #[serde(rename = "size+mtime")]
+    SizeMtime,
+    /// The contents hash differently. Unchanged files aren't re-read thanks to a hash cache
+    #[serde(rename = "hash")]
+    Hash,
+}
+
+impl FromStr for DetectChanges {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "mtime" => Ok(Self::Mtime),
+            "size+mtime" => Ok(Self::SizeMtime),
+            "hash" => Ok(Self::Hash),
+            _ => Err(format!(
+                "unknown change detection mode {s}, expected hash, mtime or size+mtime"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for DetectChanges {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Mtime => "mtime",
+            Self::SizeMtime => "size+mtime",
+            Self::Hash => "hash",
+        })
+    }
+}
+
+/// What happened to a file since it was last seen
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Transition {
+    /// It wasn't seen before
+    Added,
+    Modified,
+    Unchanged,
+    /// It is gone, and isn't tracked any more
+    Removed,
+}
+
+/// The parts of a file's metadata that are cheap to read
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Stamp {
+    size: u64,
+    mtime_secs: u64,
+    mtime_nanos: u32,
+    /// Missing where files have none, and in what was saved before it was tracked
+    #[serde(default, flatten, skip_serializing_if = "Option::is_none")]
+    identity: Option<Identity>,
+}
+
+/// Which file is at a path, so one that was replaced by another one with the same size and
+/// modification time isn't taken for the one that was there before
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Identity {
+    dev: u64,
+    ino: u64,
+}
+
+impl From<&Entry> for Stamp {
+    fn from(entry: &Entry) -> Self {
+        let mtime = entry
+            .modified
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+        Self {
+            size: entry.size,
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            identity: entry.id.map(|id| Identity {
+                dev: id.device,
+                ino: id.inode,
+            }),
+        }
+    }
+}
+
+impl Stamp {
+    fn read(path: &Path) -> Result<Self> {
+        // Links that aren't followed might point nowhere, they are judged by the link itself
+        let metadata = std::fs::metadata(path)
+            .or_else(|_| std::fs::symlink_metadata(path))
+            .with_context(|| anyhow!("Error reading metadata of {}", path.display()))?;
+        // Without modification times, like on some FUSE and network filesystems, every stamp
+        // gets the epoch and only the size can tell a change
+        let mtime = metadata
+            .modified()
+            .ok()
+            .and_then(|modified| modified.duration_since(UNIX_EPOCH).ok())
+            .unwrap_or_default();
+
+        #[cfg(unix)]
+        let identity = {
+            use std::os::unix::fs::MetadataExt;
+            Some(Identity {
+                dev: metadata.dev(),
+                ino: metadata.ino(),
+            })
+        };
+        #[cfg(not(unix))]
+        let identity = None;
+
+        Ok(Self {
+            size: metadata.len(),
+            mtime_secs: mtime.as_secs(),
+            mtime_nanos: mtime.subsec_nanos(),
+            identity,
+        })
+    }
+
+    /// Since the epoch
+    fn modified(&self) -> Duration {
+        Duration::new(self.mtime_secs, self.mtime_nanos)
+    }
+
+    /// Whether the file was replaced by another one since `previous` was read. Inode numbers
+    /// only tell files apart on the same device, so a tree that was carried over from
+    /// another machine or whose device number changed isn't taken for replaced
+    fn replaced(&self, previous: &Stamp) -> bool {
+        match (self.identity, previous.identity) {
+            (Some(current), Some(previous)) => {
+                current.dev == previous.dev && current.ino != previous.ino
+            }
+            _ => false,
+        }
+    }
+
+    /// Whether the file is the same and looks the same as when `previous` was read
+    fn matches(&self, previous: &Stamp) -> bool {
+        self.size == previous.size
+            && self.modified() == previous.modified()
+            && !self.replaced(previous)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+enum Fingerprint {
+    /// State saved by versions that only tracked whole seconds doesn't load, and is taken
+    /// again from scratch
+    Mtime(Stamp),
+    SizeMtime(Stamp),
+    Hash(#[serde(with = "hex_hash")] Hash),
+    /// The size and modification time with a tolerance for coarse or skewed clocks, see
+    /// [`ChangeDetector::with_mtime_tolerance`]. Hashed when the modification time alone
+    /// can't tell a later write apart
+    Tolerant {
+        stamp: Stamp,
+        #[serde(default, with = "hex_hash_opt")]
+        hash: Option<Hash>,
+    },
+}
+
+/// Hashes are saved as hex, like in the hash cache
+pub(crate) mod hex_hash {
+    use blake3::Hash;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &Hash, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.serialize_str(&hash.to_hex())
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Hash, D::Error> {
+        Hash::from_hex(String::deserialize(deserializer)?).map_err(D::Error::custom)
+    }
+}
+
+mod hex_hash_opt {
+    use blake3::Hash;
+    use serde::{de::Error, Deserialize, Deserializer, Serializer};
+
+    pub fn serialize<S: Serializer>(hash: &Option<Hash>, serializer: S) -> Result<S::Ok, S::Error> {
+        match hash {
+            Some(hash) => serializer.serialize_some(hash.to_hex().as_str()),
+            None => serializer.serialize_none(),
+        }
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(
+        deserializer: D,
+    ) -> Result<Option<Hash>, D::Error> {
+        Option::<String>::deserialize(deserializer)?
+            .map(|hex| Hash::from_hex(hex).map_err(D::Error::custom))
+            .transpose()
+    }
+}
+
+/// What every file looked like when it was last seen, saved so a restart doesn't have to
+/// treat every file as new
+#[derive(Debug, Default, Serialize, Deserialize)]
+struct SavedState {
+    /// Fingerprints taken in another mode can't be compared, and are thrown away
+    mode: DetectChanges,
+    /// Keyed by the path relative to the root
+    files: HashMap<PathBuf, Fingerprint>,
+}
+
+impl Fingerprint {
+    fn differs_from(&self, previous: &Fingerprint, tolerance: Duration) -> bool {
+        match (self, previous) {
+            // Only moving forward counts, to match the original polling behavior. A different
+            // size catches a second write that the filesystem gave the same modification time
+            (Fingerprint::Mtime(current), Fingerprint::Mtime(previous)) => {
+                current.modified() > previous.modified()
+                    || current.size != previous.size
+                    || current.replaced(previous)
+            }
+            (Fingerprint::SizeMtime(current), Fingerprint::SizeMtime(previous)) => {
+                !current.matches(previous)
+            }
+            (
+                Fingerprint::Tolerant {
+                    stamp: current,
+                    hash: current_hash,
+                },
+                Fingerprint::Tolerant {
+                    stamp: previous,
+                    hash: previous_hash,
+                },
+            ) => {
+                // Moving backwards counts too, clocks that go back don't mean nothing changed
+                let apart = current.modified().abs_diff(previous.modified());
+                if current.size != previous.size || apart > tolerance || current.replaced(previous)
+                {
+                    return true;
+                }
+                match (current_hash, previous_hash) {
+                    (Some(current), Some(previous)) => current != previous,
+                    _ => !apart.is_zero(),
+                }
+            }
+            (current, previous) => current != previous,
+        }
+    }
+
+    fn hash(&self) -> Option<Hash> {
+        match self {
+            Fingerprint::Hash(hash)
+            | Fingerprint::Tolerant {
+                hash: Some(hash), ..
+            } => Some(*hash),
+            _ => None,
+        }
+    }
+}
+
+/// A hash remembered along with the metadata the file had when it was hashed
+#[derive(Debug, Clone, Serialize, Deserialize)]
+struct CachedHash {
+    #[serde(flatten)]
+    stamp: Stamp,
+    hash: String,
+}
+
+/// Remembers what every file looked like the last time it was seen, so it can tell
+/// whether it has changed since
+pub struct ChangeDetector {
+    mode: DetectChanges,
+    root: PathBuf,
+    seen: Mutex<HashMap<PathBuf, Fingerprint>>,
+    /// Hashes keyed by the path relative to `root`, persisted to `cache_path`
+    hashes: Mutex<HashMap<PathBuf, CachedHash>>,
+    cache_path: Option<PathBuf>,
+    cache_dirty: AtomicBool,
+    /// Where `seen` is persisted
+    state_path: Option<PathBuf>,
+    state_dirty: AtomicBool,
+    /// How far apart modification times can be and still be the same, zero to trust them
+    mtime_tolerance: Duration,
+    /// The form paths are remembered in
+    normalize: NormalizeUnicode,
+    /// Where files are hashed, on the calling thread without one
+    pool: Option<HashPool>,
+}
+
+impl ChangeDetector {
+    /// `root` is the directory the watched files live in. In hash mode, any hashes saved
+    /// in `cache_path` by a previous run are loaded. What files looked like when the
+    /// previous run last saw them is loaded from `state_path`, if it was the same mode
+    pub fn new(
+        mode: DetectChanges,
+        root: PathBuf,
+        cache_path: Option<PathBuf>,
+        state_path: Option<PathBuf>,
+    ) -> Self {
+        let seen = state_path
+            .as_ref()
+            .and_then(|state_path| std::fs::read(state_path).ok())
+            .and_then(|contents| serde_json::from_slice::<SavedState>(&contents).ok())
+            .filter(|state| state.mode == mode)
+            .map(|state| {
+                state
+                    .files
+                    .into_iter()
+                    .map(|(relative_path, fingerprint)| (root.join(relative_path), fingerprint))
+                    .collect()
+            })
+            .unwrap_or_default();
+        let hashes = match (mode, &cache_path) {
+            (DetectChanges::Hash, Some(cache_path)) => std::fs::read(cache_path)
+                .ok()
+                .and_then(|contents| serde_json::from_slice(&contents).ok())
+                .unwrap_or_default(),
+            _ => HashMap::new(),
+        };
+
+        Self {
+            mode,
+            root,
+            seen: Mutex::new(seen),
+            hashes: Mutex::new(hashes),
+            cache_path,
+            cache_dirty: AtomicBool::new(false),
+            state_path,
+            state_dirty: AtomicBool::new(false),
+            mtime_tolerance: Duration::ZERO,
+            normalize: NormalizeUnicode::None,
+            pool: None,
+        }
+    }
+
+    /// Stops trusting modification times that are less than `tolerance` apart, for mounts
+    /// like NFS or SMB where they can go backwards or only have 2 second granularity. Files
+    /// modified that recently are hashed, and their size counts in every mode. Doesn't
+    /// apply to [`DetectChanges::Hash`], which doesn't look at modification times
+    pub fn with_mtime_tolerance(mut self, tolerance: Duration) -> Self {
+        if self.mode != DetectChanges::Hash {
+            self.mtime_tolerance = tolerance;
+        }
+        self
+    }
+
+    /// Remembers paths in a Unicode normalization form, so a file that is renamed to the same
+    /// name in another form is still the same file
+    pub fn with_normalization(mut self, normalize: NormalizeUnicode) -> Self {
+        self.normalize = normalize;
+        let seen = std::mem::take(self.seen.get_mut().unwrap());
+        let seen = seen
+            .into_iter()
+            .map(|(path, fingerprint)| (self.key(&path).into_owned(), fingerprint))
+            .collect();
+        *self.seen.get_mut().unwrap() = seen;
+        let hashes = std::mem::take(self.hashes.get_mut().unwrap());
+        let hashes = hashes
+            .into_iter()
+            .map(|(relative_path, cached)| (normalize.path(&relative_path).into_owned(), cached))
+            .collect();
+        *self.hashes.get_mut().unwrap() = hashes;
+        self
+    }
+
+    /// Hashes files on the threads of `pool`. The calling thread then waits for them, so this
+    /// must not be used on the async runtime
+    pub fn with_hash_pool(mut self, pool: HashPool) -> Self {
+        self.pool = Some(pool);
+        self
+    }
+
+    fn hash(&self, path: &Path) -> Result<Hash> {
+        match &self.pool {
+            Some(pool) => pool.hash_blocking(path),
+            None => hash_file(path),
+        }
+    }
+
+    /// What `path` is remembered as
+    fn key<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        if self.normalize == NormalizeUnicode::None {
+            return Cow::Borrowed(path);
+        }
+        match path.strip_prefix(&self.root) {
+            Ok(relative_path) => match self.normalize.path(relative_path) {
+                Cow::Borrowed(_) => Cow::Borrowed(path),
+                Cow::Owned(relative_path) => Cow::Owned(self.root.join(relative_path)),
+            },
+            Err(_) => self.normalize.path(path),
+        }
+    }
+
+    pub fn mode(&self) -> DetectChanges {
+        self.mode
+    }
+
+    /// Whether reading a fingerprint might need to read the whole file
+    pub fn is_expensive(&self) -> bool {
+        self.mode == DetectChanges::Hash || !self.mtime_tolerance.is_zero()
+    }
+
+    /// What `path` looks like now. `previous` is what it looked like when it was last seen
+    fn fingerprint(
+        &self,
+        path: &Path,
+        stamp: Stamp,
+        previous: Option<&Fingerprint>,
+    ) -> Result<Fingerprint> {
+        if !self.mtime_tolerance.is_zero() {
+            let now = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default();
+            // A write within the tolerance might not move the modification time, and a hash
+            // taken last time can only be compared with another one
+            let suspicious = stamp.modified() + self.mtime_tolerance > now
+                || previous.and_then(Fingerprint::hash).is_some();
+            let hash = match suspicious {
+                true => Some(self.hash(path)?),
+                false => None,
+            };
+            return Ok(Fingerprint::Tolerant { stamp, hash });
+        }
+
+        match self.mode {
+            DetectChanges::Mtime => Ok(Fingerprint::Mtime(stamp)),
+            DetectChanges::SizeMtime => Ok(Fingerprint::SizeMtime(stamp)),
+            DetectChanges::Hash => {
+                let key = self.key(path);
+                let relative_path = key.strip_prefix(&self.root).unwrap_or(&key).to_path_buf();
+
+                if let Some(cached) = self.hashes.lock().unwrap().get_mut(&relative_path) {
+                    if stamp.matches(&cached.stamp) {
+                        if let Ok(hash) = Hash::from_hex(&cached.hash) {
+                            // Hashes cached before identities were tracked get one
+                            if cached.stamp != stamp {
+                                cached.stamp = stamp;
+                                self.cache_dirty.store(true, Ordering::Relaxed);
+                            }
+                            return Ok(Fingerprint::Hash(hash));
+                        }
+                    }
+                }
+
+                let hash = self.hash(path)?;
+                self.hashes.lock().unwrap().insert(
+                    relative_path,
+                    CachedHash {
+                        stamp,
+                        hash: hash.to_hex().to_string(),
+                    },
+                );
+                self.cache_dirty.store(true, Ordering::Relaxed);
+
+                Ok(Fingerprint::Hash(hash))
+            }
+        }
+    }
+
+    /// Remembers the current state of `path` without reporting it as changed
+    pub fn observe(&self, path: &Path) -> Result<()> {
+        self.update(path, Stamp::read(path)).map(|_| ())
+    }
+
+    /// Like [`ChangeDetector::observe`], with the metadata of `path` read through a
+    /// [`Vfs`](crate::vfs::Vfs). Only for modes that aren't
+    /// [`ChangeDetector::is_expensive`], which read the file
+    pub fn observe_entry(&self, path: &Path, entry: &Entry) -> Result<()> {
+        self.update(path, Ok(Stamp::from(entry))).map(|_| ())
+    }
+
+    /// Whether `path` was seen before, possibly by a previous run
+    pub fn knows(&self, path: &Path) -> bool {
+        self.seen.lock().unwrap().contains_key(&*self.key(path))
+    }
+
+    /// Whether `path` changed since it was last seen. Paths that were never seen count as
+    /// changed, paths that are gone don't. Either way, the current state is remembered for
+    /// next time
+    pub fn has_changed(&self, path: &Path) -> Result<bool> {
+        Ok(matches!(
+            self.transition(path)?,
+            Transition::Added | Transition::Modified
+        ))
+    }
+
+    /// Like [`ChangeDetector::has_changed`], with the metadata of `path` read through a
+    /// [`Vfs`](crate::vfs::Vfs), `None` if there's nothing there. Only for modes that
+    /// aren't [`ChangeDetector::is_expensive`]
+    pub fn entry_changed(&self, path: &Path, entry: Option<&Entry>) -> Result<bool> {
+        let Some(entry) = entry else {
+            self.forget(path);
+            return Ok(false);
+        };
+        Ok(matches!(
+            self.update(path, Ok(Stamp::from(entry)))?,
+            Transition::Added | Transition::Modified
+        ))
+    }
+
+    /// What happened to `path` since it was last seen, remembering its current state for
+    /// next time
+    pub fn transition(&self, path: &Path) -> Result<Transition> {
+        match self.update(path, Stamp::read(path)) {
+            Err(_) if std::fs::symlink_metadata(path).is_err() => {
+                self.forget(path);
+                Ok(Transition::Removed)
+            }
+            transition => transition,
+        }
+    }
+
+    /// Remembers `stamp`, what `path` looks like now, and tells what happened to it since
+    fn update(&self, path: &Path, stamp: Result<Stamp>) -> Result<Transition> {
+        let key = self.key(path);
+        let previous = self.seen.lock().unwrap().get(&*key).copied();
+        let fingerprint = self.fingerprint(path, stamp?, previous.as_ref())?;
+
+        let previous = self
+            .seen
+            .lock()
+            .unwrap()
+            .insert(key.into_owned(), fingerprint);
+        if previous != Some(fingerprint) {
+            self.state_dirty.store(true, Ordering::Relaxed);
+        }
+
+        Ok(match previous {
+            None => Transition::Added,
+            Some(previous) if fingerprint.differs_from(&previous, self.mtime_tolerance) => {
+                Transition::Modified
+            }
+            Some(_) => Transition::Unchanged,
+        })
+    }
+
+    /// Stops tracking a path that no longer exists
+    pub fn forget(&self, path: &Path) {
+        let path = self.key(path);
+        if self.seen.lock().unwrap().remove(&*path).is_some() {
+            self.state_dirty.store(true, Ordering::Relaxed);
+        }
+
+        if let Ok(relative_path) = path.strip_prefix(&self.root) {
+            if self.hashes.lock().unwrap().remove(relative_path).is_some() {
+                self.cache_dirty.store(true, Ordering::Relaxed);
+            }
+        }
+    }
+
+    /// Writes the hash cache and what every file looked like to disk, if anything changed
+    /// since they were last saved
+    pub fn save(&self) -> Result<()> {
+        if let Some(cache_path) = &self.cache_path {
+            if self.cache_dirty.swap(false, Ordering::Relaxed) {
+                let contents = serde_json::to_vec(&*self.hashes.lock().unwrap())?;
+                write_file(cache_path, &contents).with_context(|| {
+                    anyhow!("Error writing hash cache {}", cache_path.display())
+                })?;
+            }
+        }
+
+        if let Some(state_path) = &self.state_path {
+            if self.state_dirty.swap(false, Ordering::Relaxed) {
+                let state = SavedState {
+                    mode: self.mode,
+                    files: self
+                        .seen
+                        .lock()
+                        .unwrap()
+                        .iter()
+                        .filter_map(|(path, fingerprint)| {
+                            Some((
+                                path.strip_prefix(&self.root).ok()?.to_path_buf(),
+                                *fingerprint,
+                            ))
+                        })
+                        .collect(),
+                };
+                write_file(state_path, &serde_json::to_vec(&state)?).with_context(|| {
+                    anyhow!("Error writing sync state {}", state_path.display())
+                })?;
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn write_file(path: &Path, contents: &[u8]) -> Result<()> {
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+    }
+    Ok(std::fs::write(path, contents)?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use filetime::FileTime;
+
+    fn set_mtime(path: &Path, secs: i64, nanos: u32) {
+        filetime::set_file_mtime(path, FileTime::from_unix_time(secs, nanos)).unwrap();
+    }
+
+    #[test]
+    fn transitions() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None);
+
+        std::fs::write(&path, "one").unwrap();
+        set_mtime(&path, 1_000_000, 0);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Added);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+
+        std::fs::write(&path, "two").unwrap();
+        set_mtime(&path, 1_000_000, 1);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Modified);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+
+        std::fs::remove_file(&path).unwrap();
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Removed);
+        assert!(!detector.knows(&path));
+        assert!(!detector.has_changed(&path).unwrap());
+    }
+
+    #[test]
+    fn observed_files_are_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None);
+
+        std::fs::write(&path, "one").unwrap();
+        detector.observe(&path).unwrap();
+        assert!(detector.knows(&path));
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+    }
+
+    #[test]
+    fn same_mtime_different_size_is_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None);
+
+        std::fs::write(&path, "one").unwrap();
+        set_mtime(&path, 1_000_000, 0);
+        detector.observe(&path).unwrap();
+
+        std::fs::write(&path, "three").unwrap();
+        set_mtime(&path, 1_000_000, 0);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Modified);
+    }
+
+    #[test]
+    fn replaced_file_with_same_metadata_is_modified() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let replacement = dir.path().join("b");
+        for mode in [DetectChanges::Mtime, DetectChanges::SizeMtime] {
+            let detector = ChangeDetector::new(mode, dir.path().to_path_buf(), None, None);
+            std::fs::write(&path, "one").unwrap();
+            set_mtime(&path, 1_000_000, 0);
+            detector.observe(&path).unwrap();
+
+            // Written next to it first, so it can't get the inode that was freed
+            std::fs::write(&replacement, "two").unwrap();
+            set_mtime(&replacement, 1_000_000, 0);
+            std::fs::rename(&replacement, &path).unwrap();
+            let expected = match cfg!(unix) {
+                true => Transition::Modified,
+                false => Transition::Unchanged,
+            };
+            assert_eq!(detector.transition(&path).unwrap(), expected, "{mode}");
+        }
+    }
+
+    #[test]
+    fn mtime_moving_back_is_unchanged() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None);
+
+        std::fs::write(&path, "one").unwrap();
+        set_mtime(&path, 1_000_000, 0);
+        detector.observe(&path).unwrap();
+
+        set_mtime(&path, 999_999, 0);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+    }
+
+    #[test]
+    fn tolerance_catches_mtime_moving_back() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None)
+                .with_mtime_tolerance(Duration::from_secs(2));
+
+        std::fs::write(&path, "one").unwrap();
+        set_mtime(&path, 1_000_000, 0);
+        detector.observe(&path).unwrap();
+
+        // Without the tolerance, only moving forward counts
+        std::fs::write(&path, "two").unwrap();
+        set_mtime(&path, 999_000, 0);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Modified);
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+    }
+
+    #[test]
+    fn tolerance_hashes_recent_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a");
+        let detector =
+            ChangeDetector::new(DetectChanges::Mtime, dir.path().to_path_buf(), None, None)
+                .with_mtime_tolerance(Duration::from_secs(60));
+
+        std::fs::write(&path, "one").unwrap();
+        detector.observe(&path).unwrap();
+        let mtime = FileTime::from_last_modification_time(&std::fs::metadata(&path).unwrap());
+
+        // The same size and modification time, but not the same contents
+        std::fs::write(&path, "two").unwrap();
+        filetime::set_file_mtime(&path, mtime).unwrap();
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Modified);
+    }
+
+    #[test]
+    fn state_is_saved_and_loaded() {
+        let dir = tempfile::tempdir().unwrap();
+        let root = dir.path().join("work");
+        std::fs::create_dir(&root).unwrap();
+        let state_path = dir.path().join("state.json");
+        let path = root.join("a");
+        std::fs::write(&path, "one").unwrap();
+
+        let detector = ChangeDetector::new(
+            DetectChanges::Mtime,
+            root.clone(),
+            None,
+            Some(state_path.clone()),
+        );
+        detector.observe(&path).unwrap();
+        detector.save().unwrap();
+
+        let detector = ChangeDetector::new(
+            DetectChanges::Mtime,
+            root.clone(),
+            None,
+            Some(state_path.clone()),
+        );
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Unchanged);
+
+        // Fingerprints taken in another mode are thrown away
+        let detector = ChangeDetector::new(DetectChanges::SizeMtime, root, None, Some(state_path));
+        assert_eq!(detector.transition(&path).unwrap(), Transition::Added);
+    }
+
+    #[test]
+    fn normalized_names_are_the_same_file() {
+        let dir = tempfile::tempdir().unwrap();
+        let decomposed = dir.path().join("Cafe\u{301}");
+        let composed = dir.path().join("Caf\u{e9}");
+        std::fs::write(&decomposed, "one").unwrap();
+
+        let detector = ChangeDetector::new(DetectChanges::Mtime, dir.path().into(), None, None)
+            .with_normalization(NormalizeUnicode::Nfc);
+        detector.observe(&decomposed).unwrap();
+        std::fs::rename(&decomposed, &composed).unwrap();
+        assert!(detector.knows(&composed));
+        assert_eq!(
+            detector.transition(&composed).unwrap(),
+            Transition::Unchanged
+        );
+
+        let detector = ChangeDetector::new(DetectChanges::Mtime, dir.path().into(), None, None);
+        detector.observe(&composed).unwrap();
+        assert!(!detector.knows(&decomposed));
+    }
+}
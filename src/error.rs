@@ -0,0 +1,264 @@
+//! The errors [`Syncer`](crate::Syncer) reports, which say what it was doing and to which
+//! path, and sort out the failures a caller might want to handle differently: a full
+//! backup, a permission problem, or a file that was gone before it could be synced.
+//!
+//! Everything underneath still adds context to its errors as it goes, they're only sorted
+//! out once they leave the sync engine, by the operation that failed and the [`io::Error`]
+//! that caused it
+
+use serde::{Deserialize, Serialize};
+use std::{
+    error::Error,
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// What syncing was doing when it failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Operation {
+    /// Checking the options and the directories before syncing
+    SetUp,
+    Initialize,
+    /// Walking work_dir or checking files for changes
+    Scan,
+    /// Copying a file or creating a directory in the backup
+    Copy,
+    /// Removing a file or directory from the backup
+    Remove,
+    /// Syncing a file both ways, see [`SyncOptions::bidirectional`](crate::SyncOptions)
+    SyncBothWays,
+    /// Saving what syncing keeps track of: its state, the manifest, a git commit or usage
+    Save,
+    Snapshot,
+    /// Keeping a dated copy of the backup, see [`crate::rotate`]
+    Rotate,
+    /// Watching work_dir or the backup for changes
+    Watch,
+    Restore,
+    Compare,
+    Verify,
+    /// Removing what interrupted copies left behind
+    Clean,
+    /// Reading or replacing what syncing keeps track of, see [`crate::state`]
+    ExportState,
+    ImportState,
+}
+
+impl fmt::Display for Operation {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::SetUp => "setting up",
+            Self::Initialize => "initializing",
+            Self::Scan => "scanning",
+            Self::Copy => "copying",
+            Self::Remove => "removing",
+            Self::SyncBothWays => "syncing both ways",
+            Self::Save => "saving",
+            Self::Snapshot => "snapshotting",
+            Self::Rotate => "rotating",
+            Self::Watch => "watching",
+            Self::Restore => "restoring",
+            Self::Compare => "comparing",
+            Self::Verify => "verifying",
+            Self::Clean => "cleaning up",
+            Self::ExportState => "exporting state",
+            Self::ImportState => "importing state",
+        })
+    }
+}
+
+/// Whether syncing keeps going when copies keep failing, see
+/// [`SyncOptions::max_errors`](crate::SyncOptions)
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum ErrorPolicy {
+    /// Report every failed copy and carry on with everything else
+    #[default]
+    #[serde(rename = "continue")]
+    Continue,
+    /// Stop syncing once too many copies failed
+    #[serde(rename = "abort")]
+    Abort,
+}
+
+impl FromStr for ErrorPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "continue" => Ok(Self::Continue),
+            "abort" => Ok(Self::Abort),
+            _ => Err(format!(
+                "unknown error policy {s}, expected continue or abort"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for ErrorPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Continue => "continue",
+            Self::Abort => "abort",
+        })
+    }
+}
+
+/// A failure of syncing, with what it was doing and the path it failed on. Shown as the
+/// whole chain of what went wrong underneath, like
+/// `Error copying file: No space left on device`
+#[derive(Debug, thiserror::Error)]
+pub enum SyncError {
+    /// The filesystem of the backup, or the quota on it, is full
+    #[error("{error}")]
+    DestinationFull {
+        operation: Operation,
+        path: PathBuf,
+        error: Cause,
+    },
+    /// Reading the file or writing to the backup wasn't allowed, or the backup is read-only
+    #[error("{error}")]
+    PermissionDenied {
+        operation: Operation,
+        path: PathBuf,
+        error: Cause,
+    },
+    /// The file was removed or moved away from work_dir before it could be synced
+    #[error("{error}")]
+    SourceVanished {
+        operation: Operation,
+        path: PathBuf,
+        error: Cause,
+    },
+    #[error("{error}")]
+    Other {
+        operation: Operation,
+        path: PathBuf,
+        error: Cause,
+    },
+}
+
+impl SyncError {
+    /// Sorts out `error`, which `operation` on `path` failed with. Errors that already are
+    /// a [`SyncError`] are kept as they are
+    pub(crate) fn new(
+        operation: Operation,
+        path: impl Into<PathBuf>,
+        error: anyhow::Error,
+    ) -> Self {
+        let error = match error.downcast::<Self>() {
+            Ok(error) => return error,
+            Err(error) => error,
+        };
+        let path = path.into();
+        let kind = error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<io::Error>())
+            .map(io::Error::kind);
+        let error = Cause(error);
+        match kind {
+            Some(
+                io::ErrorKind::StorageFull
+                | io::ErrorKind::QuotaExceeded
+                | io::ErrorKind::FileTooLarge,
+            ) => Self::DestinationFull {
+                operation,
+                path,
+                error,
+            },
+            Some(io::ErrorKind::PermissionDenied | io::ErrorKind::ReadOnlyFilesystem) => {
+                Self::PermissionDenied {
+                    operation,
+                    path,
+                    error,
+                }
+            }
+            // Only if it's still gone, rather than something in the backup that's missing
+            Some(io::ErrorKind::NotFound)
+                if matches!(operation, Operation::Scan | Operation::Copy)
+                    && path.symlink_metadata().is_err() =>
+            {
+                Self::SourceVanished {
+                    operation,
+                    path,
+                    error,
+                }
+            }
+            _ => Self::Other {
+                operation,
+                path,
+                error,
+            },
+        }
+    }
+
+    pub fn operation(&self) -> Operation {
+        match self {
+            Self::DestinationFull { operation, .. }
+            | Self::PermissionDenied { operation, .. }
+            | Self::SourceVanished { operation, .. }
+            | Self::Other { operation, .. } => *operation,
+        }
+    }
+
+    /// The file or directory it failed on, in work_dir unless the failure was about the
+    /// backup as a whole
+    pub fn path(&self) -> &Path {
+        match self {
+            Self::DestinationFull { path, .. }
+            | Self::PermissionDenied { path, .. }
+            | Self::SourceVanished { path, .. }
+            | Self::Other { path, .. } => path,
+        }
+    }
+
+    pub fn cause(&self) -> &Cause {
+        match self {
+            Self::DestinationFull { error, .. }
+            | Self::PermissionDenied { error, .. }
+            | Self::SourceVanished { error, .. }
+            | Self::Other { error, .. } => error,
+        }
+    }
+}
+
+/// What went wrong underneath a [`SyncError`], shown as the whole chain of causes
+pub struct Cause(anyhow::Error);
+
+impl Cause {
+    /// Every error in the chain, starting with the outermost
+    pub fn chain(&self) -> impl Iterator<Item = &(dyn Error + 'static)> {
+        self.0.chain()
+    }
+
+    /// The innermost error of type `E`, like the [`io::Error`] that caused it
+    pub fn find<E: Error + 'static>(&self) -> Option<&E> {
+        self.0
+            .chain()
+            .filter_map(|cause| cause.downcast_ref())
+            .last()
+    }
+}
+
+impl fmt::Display for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:#}", self.0)
+    }
+}
+
+impl fmt::Debug for Cause {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.0, f)
+    }
+}
+
+/// Turns the errors of the sync engine into [`SyncError`]s as they leave it
+pub(crate) trait During<T> {
+    fn during(self, operation: Operation, path: &Path) -> Result<T, SyncError>;
+}
+
+impl<T> During<T> for anyhow::Result<T> {
+    fn during(self, operation: Operation, path: &Path) -> Result<T, SyncError> {
+        self.map_err(|error| SyncError::new(operation, path, error))
+    }
+}
@@ -0,0 +1,84 @@
+//! The exit codes evil_mount ends with, so scripts can tell why it stopped. Errors are
+//! tagged with one where they come up, anything untagged exits with 1
+
+use anyhow::Result;
+use std::{error::Error, fmt, process::ExitCode};
+
+/// Why evil_mount failed
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Exit {
+    /// Anything else, like a backup that can't be reached or files that couldn't be synced
+    /// with `--once`
+    Failed = 1,
+    /// The command line, the config file or the options in it are wrong. Clap exits with
+    /// the same code for the command line
+    Config = 2,
+    /// Initializing work_dir from backup_dir failed
+    Init = 3,
+    /// Syncing stopped on its own, because it failed too often in a row or too many copies
+    /// failed with `--error-policy abort`
+    SyncDied = 4,
+    /// `verify` found files that differ
+    Mismatch = 5,
+    /// Stopped by a second Ctrl-C before the last changes were synced
+    Interrupted = 130,
+}
+
+impl Exit {
+    /// The exit code `error` was tagged with
+    pub fn of(error: &anyhow::Error) -> Self {
+        error
+            .chain()
+            .find_map(|cause| cause.downcast_ref::<Tagged>())
+            .map_or(Self::Failed, |tagged| tagged.exit)
+    }
+}
+
+impl From<Exit> for ExitCode {
+    fn from(exit: Exit) -> Self {
+        ExitCode::from(exit as u8)
+    }
+}
+
+/// An error that exits with [`Tagged::exit`]. Shows as the error it wraps, and has the same
+/// causes, so printing it doesn't change
+struct Tagged {
+    exit: Exit,
+    error: anyhow::Error,
+}
+
+impl fmt::Display for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Display::fmt(&self.error, f)
+    }
+}
+
+impl fmt::Debug for Tagged {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        fmt::Debug::fmt(&self.error, f)
+    }
+}
+
+impl Error for Tagged {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        self.error.source()
+    }
+}
+
+/// Tags errors with the code evil_mount exits with if they end it
+pub trait ExitWith<T> {
+    fn exit_with(self, exit: Exit) -> Result<T>;
+}
+
+impl<T, E: Into<anyhow::Error>> ExitWith<T> for Result<T, E> {
+    fn exit_with(self, exit: Exit) -> Result<T> {
+        self.map_err(|error| {
+            let error = error.into();
+            // The innermost tag says what actually went wrong
+            match error.chain().any(|cause| cause.is::<Tagged>()) {
+                true => error,
+                false => anyhow::Error::new(Tagged { exit, error }),
+            }
+        })
+    }
+}
@@ -0,0 +1,519 @@
+use anyhow::{anyhow, Result};
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt, fs,
+    path::{Component, Path, PathBuf},
+    str::FromStr,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+};
+use tracing::{debug, warn};
+use walkdir::{DirEntry, WalkDir};
+
+use crate::{
+    gitignore::GitIgnores,
+    meta::{METADATA_DIR_NAME, TEMP_SUFFIX},
+    winfs::{has_attributes, FILE_ATTRIBUTE_HIDDEN, FILE_ATTRIBUTE_SYSTEM},
+};
+
+/// The name of the ignore file that is read from work_dir
+pub const IGNORE_FILE_NAME: &str = ".evilmountignore";
+
+/// Decides which paths are left out of syncing. Patterns use .gitignore syntax and are
+/// matched against paths relative to the directory being synced, so the same set can be
+/// used on both work_dir and backup_dir
+#[derive(Debug, Clone)]
+pub struct IgnoreSet {
+    matcher: Gitignore,
+    /// Files with any of these Windows file attributes are ignored as well
+    attributes: u32,
+    /// Paths more than this many directories deep are ignored as well
+    max_depth: Option<usize>,
+    /// Walks don't descend into directories on other filesystems
+    same_filesystem: bool,
+    /// What git ignores is ignored as well
+    git: Option<Arc<GitIgnores>>,
+    /// Everything else is ignored as well, see [`IgnoreSet::restricted_to`]
+    only: Option<Arc<Selected>>,
+    /// The only targets links to directories are followed into, see
+    /// [`IgnoreSet::following_into`]
+    follow_into: Option<Arc<Gitignore>>,
+    /// Where walks report what they couldn't read
+    walk_errors: WalkErrors,
+}
+
+/// The paths an [`IgnoreSet`] is restricted to
+#[derive(Debug, Default)]
+struct Selected {
+    paths: HashSet<PathBuf>,
+    /// The directories above them
+    parents: HashSet<PathBuf>,
+}
+
+impl Default for IgnoreSet {
+    /// A set that doesn't ignore anything
+    fn default() -> Self {
+        Self {
+            matcher: Gitignore::empty(),
+            attributes: 0,
+            max_depth: None,
+            same_filesystem: false,
+            git: None,
+            only: None,
+            follow_into: None,
+            walk_errors: WalkErrors::default(),
+        }
+    }
+}
+
+impl IgnoreSet {
+    /// Builds the set from an ignore file (if it exists) and the globs given on the command
+    /// line. Later rules win, so `includes` re-include anything the excludes would skip
+    pub fn new(
+        ignore_file: Option<&Path>,
+        excludes: &[String],
+        includes: &[String],
+    ) -> Result<Self> {
+        // Using `.` as the root disables prefix stripping, every path we match is relative
+        let mut builder = GitignoreBuilder::new(".");
+
+        if let Some(ignore_file) = ignore_file.filter(|ignore_file| ignore_file.is_file()) {
+            if let Some(err) = builder.add(ignore_file) {
+                return Err(anyhow!(
+                    "Error reading ignore file {}: {err}",
+                    ignore_file.display()
+                ));
+            }
+        }
+
+        for exclude in excludes {
+            builder
+                .add_line(None, exclude)
+                .map_err(|err| anyhow!("Invalid exclude pattern {exclude}: {err}"))?;
+        }
+
+        for include in includes {
+            builder
+                .add_line(None, &format!("!{include}"))
+                .map_err(|err| anyhow!("Invalid include pattern {include}: {err}"))?;
+        }
+
+        let matcher = builder
+            .build()
+            .map_err(|err| anyhow!("Error building ignore patterns: {err}"))?;
+
+        Ok(Self {
+            matcher,
+            attributes: 0,
+            max_depth: None,
+            same_filesystem: false,
+            git: None,
+            only: None,
+            follow_into: None,
+            walk_errors: WalkErrors::default(),
+        })
+    }
+
+    /// Walks with this set report the entries they couldn't read to `walk_errors`
+    pub fn reporting_to(mut self, walk_errors: WalkErrors) -> Self {
+        self.walk_errors = walk_errors;
+        self
+    }
+
+    pub fn walk_errors(&self) -> &WalkErrors {
+        &self.walk_errors
+    }
+
+    /// Also ignores files and directories that Windows marks as `hidden` or as belonging to
+    /// the `system`. Paths matched without looking at the files themselves, like the ones in
+    /// remote backups, aren't affected
+    pub fn skipping(mut self, hidden: bool, system: bool) -> Self {
+        if hidden {
+            self.attributes |= FILE_ATTRIBUTE_HIDDEN;
+        }
+        if system {
+            self.attributes |= FILE_ATTRIBUTE_SYSTEM;
+        }
+        self
+    }
+
+    /// Also ignores paths more than `max_depth` levels below the directory being synced,
+    /// where the files directly inside it are one level deep. With `same_filesystem`, walks
+    /// leave out directories on other filesystems than the one they start on, like mount
+    /// points or links to `/`
+    pub fn limiting(mut self, max_depth: Option<usize>, same_filesystem: bool) -> Self {
+        self.max_depth = max_depth;
+        self.same_filesystem = same_filesystem;
+        self
+    }
+
+    /// Also ignores what git would in `work_dir`: what the .gitignore files in it say, at
+    /// every level, as well as `.git/info/exclude` and the global excludes. Includes still
+    /// win over them
+    pub fn respecting_gitignore(mut self, work_dir: &Path) -> Self {
+        self.git = Some(Arc::new(GitIgnores::new(work_dir)));
+        self
+    }
+
+    /// Also ignores everything but `paths`, relative to the directory being synced, and what's
+    /// inside them. The directories above them are walked, but nothing else in them is synced
+    pub fn restricted_to(mut self, paths: impl IntoIterator<Item = PathBuf>) -> Self {
+        let mut selected = Selected::default();
+        for path in paths {
+            selected.parents.extend(
+                path.ancestors()
+                    .skip(1)
+                    .filter(|parent| !parent.as_os_str().is_empty())
+                    .map(Path::to_path_buf),
+            );
+            selected.paths.insert(path);
+        }
+        self.only = Some(Arc::new(selected));
+        self
+    }
+
+    /// Only follows links to directories whose real path, with every link resolved, is one
+    /// of `targets` or inside one. Targets are absolute paths or globs like
+    /// `/mnt/photos/*`. Links to other directories are left out, or recreated with
+    /// [`Symlinks::Recreate`]. Links to files are still handled by [`Symlinks`]
+    pub fn following_into(mut self, targets: &[String]) -> Result<Self> {
+        if targets.is_empty() {
+            return Ok(self);
+        }
+        let mut builder = GitignoreBuilder::new("/");
+        for target in targets {
+            if !Path::new(target).is_absolute() {
+                return Err(anyhow!(
+                    "Link targets to follow must be absolute, got {target}"
+                ));
+            }
+            // Paths with links in them are matched where they lead
+            let target = match fs::canonicalize(target) {
+                Ok(real_path) if !target.contains(['*', '?', '[']) => {
+                    real_path.to_string_lossy().into_owned()
+                }
+                _ => target.clone(),
+            };
+            builder
+                .add_line(None, &target)
+                .map_err(|err| anyhow!("Invalid link target {target}: {err}"))?;
+        }
+        let matcher = builder
+            .build()
+            .map_err(|err| anyhow!("Error building link targets: {err}"))?;
+        self.follow_into = Some(Arc::new(matcher));
+        Ok(self)
+    }
+
+    /// What happens to the link at `path` when links are handled by `symlinks`, which only
+    /// differs for links to directories with [`IgnoreSet::following_into`]
+    pub fn link_policy(&self, path: &Path, symlinks: Symlinks) -> Symlinks {
+        let Some(follow_into) = &self.follow_into else {
+            return symlinks;
+        };
+        match fs::canonicalize(path) {
+            Ok(target) if target.is_dir() => {
+                match follow_into.matched_path_or_any_parents(&target, true) {
+                    Match::Ignore(_) => Symlinks::Follow,
+                    _ if symlinks == Symlinks::Follow => Symlinks::Skip,
+                    _ => symlinks,
+                }
+            }
+            // Links to files, and links that lead nowhere
+            _ => symlinks,
+        }
+    }
+
+    /// Whether walks stay on the filesystem they start on
+    pub fn same_filesystem(&self) -> bool {
+        self.same_filesystem
+    }
+
+    /// Whether `relative_path` (or any directory above it) is ignored. Our own metadata
+    /// directory and temp files are always ignored
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        if relative_path.as_os_str().is_empty() {
+            return false;
+        }
+
+        if relative_path.components().next() == Some(Component::Normal(METADATA_DIR_NAME.as_ref()))
+        {
+            return true;
+        }
+
+        if relative_path
+            .file_name()
+            .is_some_and(|file_name| file_name.to_string_lossy().ends_with(TEMP_SUFFIX))
+        {
+            return true;
+        }
+
+        if let Some(only) = &self.only {
+            let selected = relative_path
+                .ancestors()
+                .any(|path| only.paths.contains(path));
+            if !selected && !only.parents.contains(relative_path) {
+                return true;
+            }
+        }
+
+        if self
+            .max_depth
+            .is_some_and(|max_depth| relative_path.components().count() > max_depth)
+        {
+            return true;
+        }
+
+        match self
+            .matcher
+            .matched_path_or_any_parents(relative_path, is_dir)
+        {
+            Match::Ignore(_) => true,
+            Match::Whitelist(_) => false,
+            Match::None => self
+                .git
+                .as_ref()
+                .is_some_and(|git| git.is_ignored(relative_path, is_dir)),
+        }
+    }
+
+    /// Whether `path`, which lives somewhere inside `root`, is ignored
+    pub fn is_ignored_in(&self, root: &Path, path: &Path, is_dir: bool) -> bool {
+        match path.strip_prefix(root) {
+            Ok(relative_path) if self.is_ignored(relative_path, is_dir) => true,
+            Ok(relative_path) => {
+                !relative_path.as_os_str().is_empty()
+                    && self.attributes != 0
+                    && has_attributes(path, self.attributes)
+            }
+            Err(_) => false,
+        }
+    }
+}
+
+/// What happens to symbolic links in work_dir and backup_dir
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Symlinks {
+    /// Treat links like the file or directory they point to
+    #[default]
+    #[serde(rename = "follow")]
+    Follow,
+    /// Copy the link itself, pointing at the same target
+    #[serde(rename = "recreate")]
+    Recreate,
+    /// Leave links out of syncing
+    #[serde(rename = "skip")]
+    Skip,
+}
+
+impl FromStr for Symlinks {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "follow" => Ok(Self::Follow),
+            "recreate" => Ok(Self::Recreate),
+            "skip" => Ok(Self::Skip),
+            _ => Err(format!(
+                "unknown symlink policy {s}, expected follow, recreate or skip"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Symlinks {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Follow => "follow",
+            Self::Recreate => "recreate",
+            Self::Skip => "skip",
+        })
+    }
+}
+
+/// Counts the entries that walks couldn't read, like directories without permission, which
+/// are left out of syncing. Each path is only logged as a warning the first time. Clones
+/// share the count
+#[derive(Debug, Clone, Default)]
+pub struct WalkErrors {
+    count: Arc<AtomicU64>,
+    /// Whether [`WalkErrors::check`] fails once anything couldn't be read
+    fail: bool,
+    logged: Arc<Mutex<HashSet<PathBuf>>>,
+    first: Arc<Mutex<Option<String>>>,
+}
+
+impl WalkErrors {
+    pub fn new(fail: bool) -> Self {
+        Self {
+            fail,
+            ..Self::default()
+        }
+    }
+
+    /// How many entries couldn't be read so far
+    pub fn count(&self) -> u64 {
+        self.count.load(Ordering::Relaxed)
+    }
+
+    /// Fails with the first entry that couldn't be read, if walk errors are fatal
+    pub fn check(&self) -> Result<()> {
+        match (self.fail, &*self.first.lock().unwrap()) {
+            (true, Some(message)) => {
+                Err(anyhow!("{message}, stopping since walk errors are fatal"))
+            }
+            _ => Ok(()),
+        }
+    }
+
+    /// The entry, or `None` after reporting why it couldn't be read
+    pub fn skip(&self, entry: walkdir::Result<DirEntry>) -> Option<DirEntry> {
+        let err = match entry {
+            Ok(entry) => return Some(entry),
+            Err(err) => err,
+        };
+        let path = err.path().map(Path::to_path_buf).unwrap_or_default();
+        match err.io_error() {
+            Some(io_err) => self.report(path, io_err),
+            None => self.report(path, &err),
+        }
+        None
+    }
+
+    /// Reports that `path` couldn't be read because of `cause`
+    pub fn report(&self, path: PathBuf, cause: &dyn fmt::Display) {
+        self.count.fetch_add(1, Ordering::Relaxed);
+        let message = format!("Couldn't read {}: {cause}", path.display());
+        self.first
+            .lock()
+            .unwrap()
+            .get_or_insert_with(|| message.clone());
+        match self.logged.lock().unwrap().insert(path) {
+            true => warn!("{message}, leaving it out"),
+            false => debug!("{message}, leaving it out"),
+        }
+    }
+}
+
+/// Walks every file inside `dir` that isn't ignored, skipping ignored directories entirely
+pub fn walk_files<'a>(dir: &'a Path, ignore: &'a IgnoreSet) -> impl Iterator<Item = DirEntry> + 'a {
+    walk_files_in(dir, dir, ignore, Symlinks::Follow)
+}
+
+/// Walks every file inside `start`, which lives inside `root`, that isn't ignored. With
+/// [`Symlinks::Recreate`] links are yielded as they are instead of being followed. FIFOs,
+/// sockets and devices are never yielded
+pub fn walk_files_in<'a>(
+    root: &'a Path,
+    start: &Path,
+    ignore: &'a IgnoreSet,
+    symlinks: Symlinks,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    walk(root, start, ignore, symlinks, true)
+        .filter_map(|file_info| ignore.walk_errors.skip(file_info))
+        .filter(move |file_info| match file_info.file_type().is_symlink() {
+            // Links that are followed into directories are walked on their own
+            true => match ignore.link_policy(file_info.path(), symlinks) {
+                Symlinks::Follow => file_info.path().is_file(),
+                Symlinks::Recreate => true,
+                Symlinks::Skip => false,
+            },
+            false => file_info.file_type().is_file(),
+        })
+}
+
+/// Walks every directory inside `start`, which lives inside `root`, that isn't ignored.
+/// `start` itself is included unless it is `root`
+pub fn walk_dirs_in<'a>(
+    root: &'a Path,
+    start: &Path,
+    ignore: &'a IgnoreSet,
+    symlinks: Symlinks,
+) -> impl Iterator<Item = DirEntry> + 'a {
+    walk(root, start, ignore, symlinks, false)
+        // The same entries are walked for their files, which already reports them
+        .filter_map(|file_info| file_info.ok())
+        .filter(move |file_info| file_info.file_type().is_dir() && file_info.path() != root)
+}
+
+/// Walks everything inside `start` that isn't ignored, following links as `symlinks` says.
+/// Links that lead back up are left out, and reported as walk errors if `report`
+fn walk<'a>(
+    root: &'a Path,
+    start: &Path,
+    ignore: &'a IgnoreSet,
+    symlinks: Symlinks,
+    report: bool,
+) -> Box<dyn Iterator<Item = walkdir::Result<DirEntry>> + Send + 'a> {
+    walk_following(root, start, ignore, symlinks, report, Vec::new())
+}
+
+/// [`walk`], below the links in `followed`, as the real directories they are in
+fn walk_following<'a>(
+    root: &'a Path,
+    start: &Path,
+    ignore: &'a IgnoreSet,
+    symlinks: Symlinks,
+    report: bool,
+    followed: Vec<PathBuf>,
+) -> Box<dyn Iterator<Item = walkdir::Result<DirEntry>> + Send + 'a> {
+    let entries = |follow: bool, follow_root: bool| {
+        WalkDir::new(start)
+            .follow_links(follow)
+            .follow_root_links(follow_root)
+            .same_file_system(ignore.same_filesystem)
+            .into_iter()
+            .filter_entry(move |file_info| {
+                !ignore.is_ignored_in(root, file_info.path(), file_info.file_type().is_dir())
+            })
+    };
+    if ignore.follow_into.is_none() {
+        let follow = symlinks == Symlinks::Follow;
+        return Box::new(entries(follow, follow));
+    }
+
+    // Only some links are followed, so each of those is walked on its own
+    let follow_root = ignore.link_policy(start, symlinks) == Symlinks::Follow;
+    Box::new(entries(false, follow_root).flat_map(move |file_info| {
+        let followed_into = |link: &DirEntry| {
+            link.depth() > 0
+                && link.file_type().is_symlink()
+                && link.path().is_dir()
+                && ignore.link_policy(link.path(), symlinks) == Symlinks::Follow
+        };
+        let link = match file_info {
+            Ok(link) if followed_into(&link) => link.into_path(),
+            file_info => {
+                return Box::new(std::iter::once(file_info)) as Box<dyn Iterator<Item = _> + Send>
+            }
+        };
+        let (Ok(target), Some(Ok(parent))) =
+            (fs::canonicalize(&link), link.parent().map(fs::canonicalize))
+        else {
+            return Box::new(std::iter::empty());
+        };
+        // Walks only see real directories below the links they followed on the way
+        if followed
+            .iter()
+            .chain([&parent])
+            .any(|dir| dir.starts_with(&target))
+        {
+            if report {
+                ignore
+                    .walk_errors
+                    .report(link, &"it links back to a directory above it");
+            }
+            return Box::new(std::iter::empty());
+        }
+        let mut followed = followed.clone();
+        followed.push(parent);
+        walk_following(root, &link, ignore, symlinks, report, followed)
+    }))
+}
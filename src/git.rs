@@ -0,0 +1,132 @@
+//! Keeping a history of the backup in git, committing after every sync cycle that changed
+//! something.
+//!
+//! The repository lives in `.evilmount/git` with backup_dir as its work tree, so it can't
+//! collide with a `.git` directory synced from work_dir. Old versions come back with e.g.
+//! `git --git-dir backup_dir/.evilmount/git log`, or by checking out a commit
+
+use anyhow::{anyhow, Context, Result};
+use std::path::{Path, PathBuf};
+use tokio::process::Command;
+use tracing::debug;
+
+use crate::meta::{metadata_dir, METADATA_DIR_NAME, TEMP_SUFFIX};
+
+/// Commit messages list at most this many of the changed paths
+const MAX_LISTED_PATHS: usize = 100;
+
+/// Where the repository of `backup_dir` is kept
+pub fn git_dir(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("git")
+}
+
+/// Commits everything in `backup_dir`, creating the repository first if needed. `changed`
+/// are the paths that changed this cycle, for the commit message. Returns whether anything
+/// was committed
+pub(crate) async fn commit_backup(backup_dir: &Path, changed: &[PathBuf]) -> Result<bool> {
+    let git_dir = git_dir(backup_dir);
+    if !git_dir.exists() {
+        init_repo(backup_dir, &git_dir).await?;
+    }
+
+    git(backup_dir, &["add", "--all"]).await?;
+    // Files can be copied again without their contents changing
+    let unchanged = git_status(backup_dir, &["diff", "--cached", "--quiet"]).await?;
+    if unchanged {
+        debug!("Nothing changed in the backup, not committing");
+        return Ok(false);
+    }
+
+    git(
+        backup_dir,
+        &["commit", "--quiet", "--message", &message(changed)],
+    )
+    .await?;
+    Ok(true)
+}
+
+/// Creates the repository, which keeps evil_mount's own files out of the history
+async fn init_repo(backup_dir: &Path, git_dir: &Path) -> Result<()> {
+    tokio::fs::create_dir_all(git_dir)
+        .await
+        .with_context(|| anyhow!("Error creating {}", git_dir.display()))?;
+    git(backup_dir, &["init", "--quiet"]).await?;
+    // Lets plain `git --git-dir <git_dir>` find the work tree
+    let work_tree = backup_dir.to_string_lossy();
+    git(backup_dir, &["config", "core.worktree", &work_tree]).await?;
+
+    let exclude = git_dir.join("info").join("exclude");
+    tokio::fs::create_dir_all(git_dir.join("info")).await?;
+    tokio::fs::write(&exclude, format!("/{METADATA_DIR_NAME}/\n*{TEMP_SUFFIX}\n"))
+        .await
+        .with_context(|| anyhow!("Error writing {}", exclude.display()))?;
+
+    // Committing fails without an identity, so make one up rather than stop syncing
+    if !git_status(backup_dir, &["config", "user.email"]).await? {
+        git(backup_dir, &["config", "user.name", "evil_mount"]).await?;
+        git(
+            backup_dir,
+            &["config", "user.email", "evil_mount@localhost"],
+        )
+        .await?;
+    }
+
+    debug!(git_dir = %git_dir.display(), "Created a repository for the backup");
+    Ok(())
+}
+
+/// A summary line followed by the changed paths
+fn message(changed: &[PathBuf]) -> String {
+    let mut message = match changed.len() {
+        1 => "Sync 1 file\n\n".to_string(),
+        count => format!("Sync {count} files\n\n"),
+    };
+    for path in changed.iter().take(MAX_LISTED_PATHS) {
+        message.push_str(&path.display().to_string());
+        message.push('\n');
+    }
+    if changed.len() > MAX_LISTED_PATHS {
+        message.push_str(&format!(
+            "...and {} more\n",
+            changed.len() - MAX_LISTED_PATHS
+        ));
+    }
+    message
+}
+
+fn command(backup_dir: &Path, args: &[&str]) -> Command {
+    let mut command = Command::new("git");
+    command
+        .arg("--git-dir")
+        .arg(git_dir(backup_dir))
+        .arg("--work-tree")
+        .arg(backup_dir)
+        .args(args)
+        .current_dir(backup_dir);
+    command
+}
+
+/// Runs git, failing with its output if it fails
+async fn git(backup_dir: &Path, args: &[&str]) -> Result<()> {
+    let output = command(backup_dir, args)
+        .output()
+        .await
+        .with_context(|| anyhow!("Error running git, is it installed?"))?;
+    match output.status.success() {
+        true => Ok(()),
+        false => Err(anyhow!(
+            "git {} failed: {}",
+            args.first().unwrap_or(&""),
+            String::from_utf8_lossy(&output.stderr).trim()
+        )),
+    }
+}
+
+/// Runs git for its exit status
+async fn git_status(backup_dir: &Path, args: &[&str]) -> Result<bool> {
+    let output = command(backup_dir, args)
+        .output()
+        .await
+        .with_context(|| anyhow!("Error running git, is it installed?"))?;
+    Ok(output.status.success())
+}
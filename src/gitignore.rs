@@ -0,0 +1,136 @@
+//! The .gitignore files in work_dir, along with `.git/info/exclude` and the global excludes
+//! of git, see [`IgnoreSet::respecting_gitignore`](crate::IgnoreSet::respecting_gitignore).
+//!
+//! Like git, a .gitignore applies to the directory it's in and everything below, rules in
+//! deeper files win, and nothing inside an ignored directory can be re-included. Files are
+//! read when first needed and read again once they change
+
+use ignore::{
+    gitignore::{Gitignore, GitignoreBuilder},
+    Match,
+};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+    time::{Duration, Instant, SystemTime},
+};
+use tracing::warn;
+
+/// How long a .gitignore is trusted before checking whether it changed
+const RECHECK: Duration = Duration::from_secs(2);
+
+/// A .gitignore as it was when it was last read
+#[derive(Debug)]
+struct Cached {
+    checked: Instant,
+    modified: Option<SystemTime>,
+    matcher: Option<Arc<Gitignore>>,
+}
+
+#[derive(Debug)]
+pub(crate) struct GitIgnores {
+    root: PathBuf,
+    /// `.git/info/exclude` and the global excludes, which lose to every .gitignore
+    fallbacks: Vec<Gitignore>,
+    /// The .gitignore of every directory looked at so far, by path relative to the root
+    dirs: Mutex<HashMap<PathBuf, Cached>>,
+}
+
+impl GitIgnores {
+    pub fn new(root: &Path) -> Self {
+        let mut fallbacks = Vec::new();
+        let exclude = root.join(".git/info/exclude");
+        if exclude.is_file() {
+            fallbacks.extend(read(&exclude));
+        }
+        let (global, err) = Gitignore::global();
+        if let Some(err) = err {
+            warn!("Error reading the global git excludes: {err}");
+        }
+        fallbacks.push(global);
+
+        Self {
+            root: root.to_path_buf(),
+            fallbacks,
+            dirs: Mutex::default(),
+        }
+    }
+
+    /// Whether git would ignore `relative_path`, or a directory above it
+    pub fn is_ignored(&self, relative_path: &Path, is_dir: bool) -> bool {
+        let mut prefix = PathBuf::new();
+        let mut components = relative_path.components().peekable();
+        while let Some(component) = components.next() {
+            prefix.push(component);
+            let prefix_is_dir = is_dir || components.peek().is_some();
+            if self.matched(&prefix, prefix_is_dir).is_ignore() {
+                return true;
+            }
+        }
+        false
+    }
+
+    /// How the closest rule matches `relative_path`, not looking at the directories above
+    fn matched(&self, relative_path: &Path, is_dir: bool) -> Match<()> {
+        let mut dir = relative_path.parent();
+        while let Some(current) = dir {
+            if let Some(matcher) = self.gitignore(current) {
+                let within = relative_path.strip_prefix(current).unwrap_or(relative_path);
+                match matcher.matched(within, is_dir) {
+                    Match::None => {}
+                    matched => return matched.map(|_| ()),
+                }
+            }
+            dir = current.parent();
+        }
+        self.fallbacks
+            .iter()
+            .map(|fallback| fallback.matched(relative_path, is_dir).map(|_| ()))
+            .find(|matched| !matched.is_none())
+            .unwrap_or(Match::None)
+    }
+
+    /// The .gitignore in `relative_dir`, if it has one
+    fn gitignore(&self, relative_dir: &Path) -> Option<Arc<Gitignore>> {
+        let mut dirs = self.dirs.lock().unwrap();
+        if let Some(cached) = dirs.get(relative_dir) {
+            if cached.checked.elapsed() < RECHECK {
+                return cached.matcher.clone();
+            }
+        }
+
+        let path = self.root.join(relative_dir).join(".gitignore");
+        let modified = std::fs::metadata(&path)
+            .and_then(|metadata| metadata.modified())
+            .ok();
+        let checked = Instant::now();
+        let cached = match dirs.remove(relative_dir) {
+            Some(cached) if cached.modified == modified => Cached { checked, ..cached },
+            _ => Cached {
+                checked,
+                modified,
+                matcher: modified.and_then(|_| read(&path)).map(Arc::new),
+            },
+        };
+        let matcher = cached.matcher.clone();
+        dirs.insert(relative_dir.to_path_buf(), cached);
+        matcher
+    }
+}
+
+/// The rules in the ignore file at `path`, with a warning about the ones that are broken
+fn read(path: &Path) -> Option<Gitignore> {
+    // Using `.` as the root disables prefix stripping, every path we match is relative
+    let mut builder = GitignoreBuilder::new(".");
+    if let Some(err) = builder.add(path) {
+        warn!("Error reading {}: {err}", path.display());
+    }
+    match builder.build() {
+        Ok(matcher) => Some(matcher),
+        Err(err) => {
+            warn!("Error reading {}: {err}", path.display());
+            None
+        }
+    }
+}
@@ -0,0 +1,202 @@
+//! Watching a local backup for files that something other than evil_mount changed or
+//! deleted, see [`SyncOptions::guard_backup`](crate::SyncOptions::guard_backup)
+
+use anyhow::{anyhow, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fmt,
+    fs::Metadata,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    time::Duration,
+};
+use tokio::{fs, time::Instant};
+use tracing::{info, warn};
+
+use crate::{
+    backend::FileMetadata,
+    filter::Symlinks,
+    syncer::SyncContext,
+    watcher::{DirWatcher, FsEvent},
+};
+
+/// How long a path in the backup has to go without events before it's checked, so copies
+/// that are still being finished aren't mistaken for tampering
+const QUIET: Duration = Duration::from_secs(1);
+
+/// What to do about backup files that were changed or deleted behind our back
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum GuardBackup {
+    /// Log a warning
+    #[serde(rename = "warn")]
+    Warn,
+    /// Log a warning and copy the file from work_dir again
+    #[serde(rename = "repair")]
+    Repair,
+}
+
+impl FromStr for GuardBackup {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "warn" => Ok(Self::Warn),
+            "repair" => Ok(Self::Repair),
+            _ => Err(format!(
+                "unknown backup guard mode {s}, expected warn or repair"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for GuardBackup {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Warn => "warn",
+            Self::Repair => "repair",
+        })
+    }
+}
+
+/// What the backup of every file looked like right after we wrote it, keyed by the path
+/// relative to the backup
+#[derive(Debug, Default)]
+pub(crate) struct Written(Mutex<HashMap<PathBuf, FileMetadata>>);
+
+impl Written {
+    /// Remembers what the backup of `relative_path` in `backup_dir` looks like now
+    pub fn record(&self, backup_dir: &Path, relative_path: &Path) {
+        if let Ok(metadata) = std::fs::symlink_metadata(backup_dir.join(relative_path)) {
+            let mut written = self.0.lock().unwrap();
+            written.insert(relative_path.to_path_buf(), file_metadata(&metadata));
+        }
+    }
+
+    pub fn forget(&self, relative_path: &Path) {
+        self.0.lock().unwrap().remove(relative_path);
+    }
+
+    pub fn rename(&self, from: &Path, to: &Path) {
+        let mut written = self.0.lock().unwrap();
+        if let Some(metadata) = written.remove(from) {
+            written.insert(to.to_path_buf(), metadata);
+        }
+    }
+
+    fn get(&self, relative_path: &Path) -> Option<FileMetadata> {
+        self.0.lock().unwrap().get(relative_path).copied()
+    }
+}
+
+fn file_metadata(metadata: &Metadata) -> FileMetadata {
+    FileMetadata {
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+    }
+}
+
+/// Watches `backup_dir` until syncing stops, warning about every file in it that no longer
+/// looks like what was copied from work_dir, and copying it again with
+/// [`GuardBackup::Repair`]
+pub(crate) async fn guard_backup(
+    ctx: Arc<SyncContext>,
+    backup_dir: PathBuf,
+    guard: GuardBackup,
+) -> Result<()> {
+    let mut watcher = DirWatcher::new(&backup_dir)?;
+    info!(
+        "Guarding {} against changes made by others",
+        backup_dir.display()
+    );
+
+    // Paths with events, and when the last one came in
+    let mut pending: HashMap<PathBuf, Instant> = HashMap::new();
+    let mut ticks = tokio::time::interval(QUIET);
+    loop {
+        tokio::select! {
+            event = watcher.next() => match event {
+                Some(FsEvent::Changed(path) | FsEvent::Removed(path)) => {
+                    pending.insert(path, Instant::now());
+                }
+                None => return Err(anyhow!("Watching {} stopped unexpectedly", backup_dir.display())),
+            },
+            _ = ticks.tick() => {
+                let quiet: Vec<PathBuf> = pending
+                    .iter()
+                    .filter(|(_, last_event)| last_event.elapsed() >= QUIET)
+                    .map(|(path, _)| path.clone())
+                    .collect();
+                for path in quiet {
+                    pending.remove(&path);
+                    check(&ctx, &backup_dir, &path, guard).await;
+                }
+            }
+            () = ctx.shut_down() => return Ok(()),
+        }
+    }
+}
+
+/// Warns if the backup at `path` was changed or deleted by something else
+async fn check(ctx: &Arc<SyncContext>, backup_dir: &Path, path: &Path, guard: GuardBackup) {
+    let Ok(relative_path) = path.strip_prefix(backup_dir) else {
+        return;
+    };
+    let options = &ctx.options;
+    if ctx.is_paused() || options.ignore.is_ignored(relative_path, path.is_dir()) {
+        return;
+    }
+
+    let work_path = ctx.work_dir.join(relative_path);
+    let work = match options.copy.symlinks {
+        Symlinks::Follow => fs::metadata(&work_path).await,
+        Symlinks::Recreate | Symlinks::Skip => fs::symlink_metadata(&work_path).await,
+    };
+    // Without a file in work_dir there's nothing the backup has to match
+    let Some(work) = work.ok().filter(|work| !work.is_dir()) else {
+        return;
+    };
+    let what = match fs::symlink_metadata(path).await {
+        Err(_) => "deleted",
+        Ok(backup) if backup.is_dir() => return,
+        Ok(backup) => {
+            let (work, backup) = (file_metadata(&work), file_metadata(&backup));
+            // Whatever a copy wrote, unless modification times aren't kept
+            let matches_work = work.size == backup.size
+                && match options.copy.preserve.times {
+                    true => work.modified == backup.modified,
+                    false => backup.modified >= work.modified,
+                };
+            let tampered = match ctx
+                .written
+                .as_ref()
+                .and_then(|written| written.get(relative_path))
+            {
+                Some(written) => written != backup,
+                // A work file changed since the backup was made is waiting to be copied
+                None => work.modified <= backup.modified,
+            };
+            if matches_work || !tampered {
+                return;
+            }
+            "changed"
+        }
+    };
+
+    match guard {
+        GuardBackup::Warn => warn!(
+            path = %path.display(),
+            "The backup was {what} by something other than evil_mount, and no longer matches {}",
+            work_path.display()
+        ),
+        GuardBackup::Repair => {
+            warn!(
+                path = %path.display(),
+                "The backup was {what} by something other than evil_mount, copying {} again",
+                work_path.display()
+            );
+            ctx.sync_file(work_path).await;
+        }
+    }
+}
@@ -0,0 +1,242 @@
+//! Hashing files with BLAKE3. [`HashPool`] hashes many of them at once on threads of its
+//! own, for verifying and hash-based change detection on big trees
+
+use anyhow::{anyhow, Context, Result};
+use blake3::{Hash, Hasher};
+use std::{
+    fmt,
+    fs::File,
+    io::Read,
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex, OnceLock},
+    thread,
+};
+use tokio::sync::oneshot;
+use tracing::debug;
+
+use crate::filter::{walk_files, IgnoreSet};
+
+/// How much of a file is read at once, enough for BLAKE3 to hash a lot of chunks of it
+/// side by side with SIMD
+const READ_CHUNK: usize = 1024 * 1024;
+
+/// Files smaller than this are read even with [`HashOptions::mmap`], mapping them costs
+/// more than it saves
+#[cfg(unix)]
+const MMAP_THRESHOLD: u64 = 64 * 1024;
+
+/// Hashes the contents of every file in `dir` that isn't ignored, in path order
+pub fn hash_directory(dir: PathBuf, ignore: &IgnoreSet) -> Result<Hash> {
+    if !dir.exists() {
+        return Err(anyhow!(
+            "Directory {} does not exist for hashing",
+            dir.display()
+        ));
+    }
+
+    if !dir.is_dir() {
+        return Err(anyhow!("Path {} is not a direectory!", dir.display()));
+    }
+
+    let hasher: Arc<Mutex<Hasher>> = Arc::new(Mutex::new(Hasher::new()));
+
+    let mut file_paths: Vec<_> = walk_files(&dir, ignore).collect();
+
+    file_paths.sort_by(|file_info, file_info2| {
+        file_info
+            .path()
+            .to_string_lossy()
+            .to_lowercase()
+            .cmp(&file_info2.path().to_string_lossy().to_lowercase())
+    });
+
+    for file_info in file_paths.into_iter() {
+        let hasher = hasher.clone();
+
+        let mut file = std::fs::File::open(file_info.path())?;
+        std::io::copy(&mut file, &mut *hasher.lock().unwrap())?;
+    }
+
+    let hasher = &hasher.lock().unwrap();
+    Ok(hasher.finalize())
+}
+
+/// Hashes the contents of a single file
+pub fn hash_file(path: &Path) -> Result<Hash> {
+    hash_file_with(path, false)
+}
+
+/// Hashes the contents of a single file, mapped into memory if `mmap` is set and the file
+/// is big enough for that to be worth it
+fn hash_file_with(path: &Path, mmap: bool) -> Result<Hash> {
+    let mut file = File::open(path)
+        .with_context(|| anyhow!("Error opening {} for hashing", path.display()))?;
+    #[cfg(unix)]
+    if mmap {
+        if let Some(hash) =
+            hash_mapped(&file).with_context(|| anyhow!("Error hashing {}", path.display()))?
+        {
+            return Ok(hash);
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = mmap;
+
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; READ_CHUNK];
+    loop {
+        match file.read(&mut buf) {
+            Ok(0) => break,
+            Ok(len) => {
+                hasher.update(&buf[..len]);
+            }
+            Err(err) if err.kind() == std::io::ErrorKind::Interrupted => {}
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Error hashing {}", path.display()))
+            }
+        }
+    }
+    Ok(hasher.finalize())
+}
+
+/// Hashes `file` by mapping it into memory, or returns `None` if it isn't a regular file
+/// big enough for that
+#[cfg(unix)]
+fn hash_mapped(file: &File) -> Result<Option<Hash>> {
+    use nix::sys::mman::{madvise, mmap, munmap, MapFlags, MmapAdvise, ProtFlags};
+    use std::num::NonZeroUsize;
+
+    let metadata = file.metadata()?;
+    if !metadata.is_file() || metadata.len() < MMAP_THRESHOLD {
+        return Ok(None);
+    }
+    let Some(len) = usize::try_from(metadata.len())
+        .ok()
+        .and_then(NonZeroUsize::new)
+    else {
+        return Ok(None);
+    };
+    // SAFETY: the mapping is private and only read while it's mapped. A file that is
+    // truncated meanwhile can still fault, like for every program hashing mapped files
+    unsafe {
+        let ptr = mmap(
+            None,
+            len,
+            ProtFlags::PROT_READ,
+            MapFlags::MAP_PRIVATE,
+            file,
+            0,
+        )?;
+        let _ = madvise(ptr, len.get(), MmapAdvise::MADV_SEQUENTIAL);
+        let contents = std::slice::from_raw_parts(ptr.as_ptr() as *const u8, len.get());
+        let hash = Hasher::new().update(contents).finalize();
+        munmap(ptr, len.get())?;
+        Ok(Some(hash))
+    }
+}
+
+/// How a [`HashPool`] hashes files
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct HashOptions {
+    /// How many files are hashed at once, one per core if not set
+    pub threads: Option<usize>,
+    /// Map big files into memory to hash them instead of reading them, which saves copying
+    /// them around on fast disks
+    pub mmap: bool,
+}
+
+/// A file to hash, and where the hash goes
+struct Job {
+    path: PathBuf,
+    done: oneshot::Sender<Result<Hash>>,
+}
+
+/// Threads that do nothing but hash files, so hashing saturates every core without taking
+/// up the blocking threads of the runtime. Clones share the threads, which are only started
+/// once the first file is hashed and stop when the last clone is gone
+#[derive(Clone, Default)]
+pub struct HashPool {
+    options: HashOptions,
+    jobs: Arc<OnceLock<mpsc::Sender<Job>>>,
+}
+
+impl fmt::Debug for HashPool {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("HashPool")
+            .field("options", &self.options)
+            .finish_non_exhaustive()
+    }
+}
+
+impl HashPool {
+    pub fn new(options: HashOptions) -> Self {
+        Self {
+            options,
+            jobs: Arc::default(),
+        }
+    }
+
+    /// How many files are hashed at once
+    pub fn threads(&self) -> usize {
+        self.options
+            .threads
+            .unwrap_or_else(|| thread::available_parallelism().map_or(1, Into::into))
+            .max(1)
+    }
+
+    fn jobs(&self) -> &mpsc::Sender<Job> {
+        self.jobs.get_or_init(|| {
+            let (sender, receiver) = mpsc::channel::<Job>();
+            let receiver = Arc::new(Mutex::new(receiver));
+            let threads = self.threads();
+            debug!("Hashing with {threads} threads");
+            for index in 0..threads {
+                let receiver = receiver.clone();
+                let mmap = self.options.mmap;
+                let spawned =
+                    thread::Builder::new()
+                        .name(format!("hash-{index}"))
+                        .spawn(move || loop {
+                            let job = receiver.lock().unwrap().recv();
+                            let Ok(Job { path, done }) = job else {
+                                return;
+                            };
+                            let _ = done.send(hash_file_with(&path, mmap));
+                        });
+                // The threads that did start carry on with fewer
+                if let Err(err) = spawned {
+                    debug!("Error starting a hashing thread: {err}");
+                }
+            }
+            sender
+        })
+    }
+
+    fn submit(&self, path: &Path) -> oneshot::Receiver<Result<Hash>> {
+        let (done, hashed) = oneshot::channel();
+        let job = Job {
+            path: path.to_path_buf(),
+            done,
+        };
+        // If every thread failed to start, the hash is left to whoever asked for it
+        if let Err(mpsc::SendError(job)) = self.jobs().send(job) {
+            let _ = job.done.send(hash_file_with(&job.path, self.options.mmap));
+        }
+        hashed
+    }
+
+    /// Hashes the contents of `path` on one of the threads
+    pub async fn hash(&self, path: &Path) -> Result<Hash> {
+        self.submit(path)
+            .await
+            .map_err(|_| anyhow!("Hashing {} stopped", path.display()))?
+    }
+
+    /// Like [`HashPool::hash`], waiting for the hash on a thread that may block. Must not be
+    /// called on the async runtime
+    pub fn hash_blocking(&self, path: &Path) -> Result<Hash> {
+        self.submit(path)
+            .blocking_recv()
+            .map_err(|_| anyhow!("Hashing {} stopped", path.display()))?
+    }
+}
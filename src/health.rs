@@ -0,0 +1,147 @@
+//! Liveness and readiness probes for container orchestrators like Kubernetes.
+//!
+//! `GET /healthz` answers 200 while the process is initializing or the sync task of every
+//! pair is running, and 503 once one of them stopped. `GET /readyz` answers 200 once
+//! initialization finished and every pair finished a sync cycle within
+//! [`STALE_CYCLES`] times its longest scan interval. Both describe each pair in a JSON body
+
+use anyhow::Result;
+use serde::Serialize;
+use std::{
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::net::{TcpListener, TcpStream};
+use tracing::{debug, info};
+
+use crate::metrics::{read_request, write_response, Metrics};
+
+/// How many scan intervals can go by without a finished cycle before a pair isn't ready
+pub const STALE_CYCLES: u32 = 5;
+
+/// One work_dir and backup pair as the probes see it
+#[derive(Debug, Clone)]
+pub struct HealthPair {
+    pub work_dir: String,
+    pub metrics: Arc<Metrics>,
+    /// The longest a scan interval gets, cycles finish at least this often
+    pub interval: Duration,
+}
+
+/// What the probes answer with
+#[derive(Debug, Clone, Serialize)]
+pub struct HealthStatus {
+    /// Whether the probe passed
+    pub ok: bool,
+    pub initialized: bool,
+    pub pairs: Vec<PairHealth>,
+}
+
+/// How one pair is doing. Times are seconds since the epoch
+#[derive(Debug, Clone, Serialize)]
+pub struct PairHealth {
+    pub work_dir: String,
+    /// Whether its sync task is running
+    pub running: bool,
+    /// When its last cycle finished
+    pub last_cycle: Option<u64>,
+    /// Whether that was recently enough
+    pub ready: bool,
+}
+
+impl HealthPair {
+    fn health(&self, now: SystemTime) -> PairHealth {
+        let running_since = self.metrics.running_since();
+        let last_cycle = self.metrics.last_cycle_end();
+        // A sync that just started gets as long for its first cycle as for any other
+        let ready = match last_cycle.max(running_since) {
+            Some(last) if running_since.is_some() => {
+                now.duration_since(last).unwrap_or_default() <= self.interval * STALE_CYCLES
+            }
+            _ => false,
+        };
+        PairHealth {
+            work_dir: self.work_dir.clone(),
+            running: running_since.is_some(),
+            last_cycle: last_cycle.map(epoch_secs),
+            ready,
+        }
+    }
+}
+
+/// Whether the process counts as alive: initializing, or syncing every pair
+pub fn liveness(pairs: &[HealthPair], initialized: bool) -> HealthStatus {
+    let pairs: Vec<PairHealth> = pairs
+        .iter()
+        .map(|pair| pair.health(SystemTime::now()))
+        .collect();
+    HealthStatus {
+        ok: !initialized || pairs.iter().all(|pair| pair.running),
+        initialized,
+        pairs,
+    }
+}
+
+/// Whether the process is ready: done initializing, and syncing every pair without stalling
+pub fn readiness(pairs: &[HealthPair], initialized: bool) -> HealthStatus {
+    let pairs: Vec<PairHealth> = pairs
+        .iter()
+        .map(|pair| pair.health(SystemTime::now()))
+        .collect();
+    HealthStatus {
+        ok: initialized && pairs.iter().all(|pair| pair.ready),
+        initialized,
+        pairs,
+    }
+}
+
+fn epoch_secs(time: SystemTime) -> u64 {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Answers `/healthz` and `/readyz` on `listener` until the task is dropped. Initializing
+/// is over once `initialized` is set
+pub async fn serve(
+    listener: TcpListener,
+    pairs: Vec<HealthPair>,
+    initialized: Arc<AtomicBool>,
+) -> Result<()> {
+    info!(
+        "Serving health checks on http://{}/healthz and /readyz",
+        listener.local_addr()?
+    );
+    let pairs = Arc::new(pairs);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let pairs = pairs.clone();
+        let initialized = initialized.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &pairs, initialized.load(Ordering::Relaxed)).await {
+                debug!(%peer, "Error answering a health check: {err}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, pairs: &[HealthPair], initialized: bool) -> Result<()> {
+    let (method, path) = read_request(&mut stream).await?;
+    let status = match (method.as_str(), path.as_str()) {
+        ("GET", "/healthz") => liveness(pairs, initialized),
+        ("GET", "/readyz") => readiness(pairs, initialized),
+        _ => {
+            return write_response(&mut stream, "404 Not Found", "text/plain", "Not found\n").await
+        }
+    };
+    let code = match status.ok {
+        true => "200 OK",
+        false => "503 Service Unavailable",
+    };
+    // Serializing plain structs can't fail
+    let body = serde_json::to_string(&status).unwrap_or_default() + "\n";
+    write_response(&mut stream, code, "application/json", &body).await
+}
@@ -0,0 +1,102 @@
+//! External commands run after a sync cycle, like `git commit` in the backup or a script
+//! that sends a notification when something couldn't be synced.
+//!
+//! Hooks run through the shell in backup_dir, or in work_dir if the backup isn't local, and
+//! learn what happened from `EVILMOUNT_*` environment variables
+
+use std::{
+    path::{Path, PathBuf},
+    process::Stdio,
+};
+use tokio::process::Command;
+use tracing::{debug, warn};
+
+/// Lists passed through the environment are cut off at this many bytes, since the OS limits
+/// how long a single variable can be
+const MAX_LIST_LEN: usize = 64 * 1024;
+
+/// What a sync cycle did
+#[derive(Debug, Default)]
+pub(crate) struct CycleReport {
+    pub copied: usize,
+    pub pulled: usize,
+    pub removed: usize,
+    /// Paths relative to work_dir that were copied, pulled or removed
+    pub changed: Vec<PathBuf>,
+    /// A `path: error` line for everything that couldn't be synced
+    pub errors: Vec<String>,
+    /// The paths of those errors, relative to work_dir where possible
+    pub failed: Vec<PathBuf>,
+}
+
+/// Where a hook runs, and which pair it reports on
+pub(crate) struct HookContext<'a> {
+    pub dir: &'a Path,
+    pub work_dir: &'a Path,
+    pub backup: String,
+}
+
+/// Runs `command` with `report` in its environment and waits for it to finish. A hook that
+/// fails is only logged, syncing carries on regardless
+pub(crate) async fn run_hook(
+    name: &str,
+    command: &str,
+    hook: &HookContext<'_>,
+    report: &CycleReport,
+) {
+    debug!(hook = name, "Running {command}");
+
+    let changed: Vec<String> = report
+        .changed
+        .iter()
+        .map(|path| path.display().to_string())
+        .collect();
+    let status = shell(command)
+        .current_dir(hook.dir)
+        .env("EVILMOUNT_HOOK", name)
+        .env("EVILMOUNT_WORK_DIR", hook.work_dir)
+        .env("EVILMOUNT_BACKUP_DIR", &hook.backup)
+        .env("EVILMOUNT_COPIED", report.copied.to_string())
+        .env("EVILMOUNT_PULLED", report.pulled.to_string())
+        .env("EVILMOUNT_REMOVED", report.removed.to_string())
+        .env("EVILMOUNT_ERRORS", report.errors.len().to_string())
+        .env("EVILMOUNT_CHANGED_PATHS", join_capped(&changed))
+        .env("EVILMOUNT_ERROR_DETAILS", join_capped(&report.errors))
+        .stdin(Stdio::null())
+        .status()
+        .await;
+
+    match status {
+        Ok(status) if status.success() => {}
+        Ok(status) => warn!(hook = name, "{command} failed with {status}"),
+        Err(err) => warn!(hook = name, "Error running {command}: {err}"),
+    }
+}
+
+#[cfg(unix)]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("sh");
+    shell.arg("-c").arg(command);
+    shell
+}
+
+#[cfg(not(unix))]
+fn shell(command: &str) -> Command {
+    let mut shell = Command::new("cmd");
+    shell.arg("/C").arg(command);
+    shell
+}
+
+/// Joins `lines` with newlines, leaving out whatever doesn't fit in [`MAX_LIST_LEN`]
+fn join_capped(lines: &[String]) -> String {
+    let mut joined = String::new();
+    for (index, line) in lines.iter().enumerate() {
+        if joined.len() + line.len() + 1 > MAX_LIST_LEN {
+            joined.push_str(&format!("...and {} more\n", lines.len() - index));
+            break;
+        }
+        joined.push_str(line);
+        joined.push('\n');
+    }
+    joined
+}
@@ -0,0 +1,263 @@
+//! An index of what work_dir and a local backup hold, which a running sync keeps up to date
+//! in `backup_dir/.evilmount/index.json`, so `status` and `diff` can answer from it instead
+//! of scanning both trees again.
+//!
+//! The sync lists both trees when it starts and on every sweep, and patches the index with
+//! every file it copies, removes or renames in between. Files that changed in work_dir but
+//! weren't copied yet are only in it once they are. The index is replaced atomically, so
+//! readers never see half of it, and only the sync holding the lock of the backup writes
+//! it. It's only trusted while the process that wrote it is still running, nothing keeps
+//! it up to date after that
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, HashSet},
+    io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+
+use crate::{
+    backend::FileMetadata,
+    compare::TreeDiff,
+    filter::IgnoreSet,
+    lock::holder,
+    meta::{metadata_dir, TEMP_SUFFIX},
+};
+
+/// The format the index is written in. Indexes in any other format are ignored
+pub const INDEX_FORMAT: u32 = 1;
+
+/// The index is written at most this often while syncing, and once more when syncing stops
+const SAVE_INTERVAL: Duration = Duration::from_secs(10);
+
+/// Where the index of `backup_dir` is kept
+pub fn index_path(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("index.json")
+}
+
+/// Both trees as the sync that wrote the index last saw them, paths relative to their roots
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct ScanIndex {
+    pub format: u32,
+    /// The process that keeps it up to date
+    pub pid: u32,
+    /// When it was written, in seconds since the epoch
+    pub updated: u64,
+    pub work: BTreeMap<PathBuf, FileMetadata>,
+    pub backup: BTreeMap<PathBuf, FileMetadata>,
+}
+
+impl ScanIndex {
+    /// The index of `backup_dir`, if a sync that is still running keeps one
+    pub fn read_live(backup_dir: &Path) -> Result<Option<Self>> {
+        let path = index_path(backup_dir);
+        let contents = match std::fs::read(&path) {
+            Ok(contents) => contents,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => {
+                return Err(err).with_context(|| anyhow!("Error reading {}", path.display()))
+            }
+        };
+        // A newer format or a cut off index just isn't used
+        let Ok(index) = serde_json::from_slice::<Self>(&contents) else {
+            return Ok(None);
+        };
+        // Locks are released when syncing stops, PIDs that are reused keep running
+        let live = index.format == INDEX_FORMAT
+            && index.pid != std::process::id()
+            && holder(backup_dir) == Some(index.pid);
+        Ok(live.then_some(index))
+    }
+
+    /// When it was written
+    pub fn updated(&self) -> SystemTime {
+        UNIX_EPOCH + Duration::from_secs(self.updated)
+    }
+
+    /// The files that differ between both trees, compared by size and modification time
+    /// like [`CompareBy::Metadata`](crate::CompareBy), leaving out what `ignore` ignores now.
+    /// Backups that don't keep the times of their files are only compared by size `!times`
+    pub fn diff(&self, ignore: &IgnoreSet, times: bool) -> TreeDiff {
+        let included = |relative_path: &PathBuf| !ignore.is_ignored(relative_path, false);
+        let mut diff = TreeDiff::default();
+        for (relative_path, work) in self.work.iter().filter(|(path, _)| included(path)) {
+            match self.backup.get(relative_path) {
+                None => diff.only_in_work.push(relative_path.clone()),
+                Some(backup) if !same_metadata(work, backup, times) => {
+                    diff.different.push(relative_path.clone())
+                }
+                Some(_) => {}
+            }
+        }
+        diff.only_in_backup = self
+            .backup
+            .keys()
+            .filter(|relative_path| {
+                included(relative_path) && !self.work.contains_key(*relative_path)
+            })
+            .cloned()
+            .collect();
+        diff
+    }
+
+    /// When the newest file in the backup was written, which is the last thing synced
+    pub fn last_synced(&self, ignore: &IgnoreSet) -> Option<SystemTime> {
+        self.backup
+            .iter()
+            .filter(|(relative_path, _)| !ignore.is_ignored(relative_path, false))
+            .filter_map(|(_, metadata)| metadata.modified)
+            .max()
+    }
+}
+
+/// Only the sizes can be compared when either side has no modification time
+fn same_metadata(work: &FileMetadata, backup: &FileMetadata, times: bool) -> bool {
+    work.size == backup.size
+        && match (work.modified, backup.modified) {
+            _ if !times => true,
+            (Some(modified), Some(backup_modified)) => modified == backup_modified,
+            _ => true,
+        }
+}
+
+/// The index a sync keeps up to date while it runs
+#[derive(Debug)]
+pub(crate) struct LiveIndex {
+    path: PathBuf,
+    state: Mutex<IndexState>,
+}
+
+#[derive(Debug)]
+struct IndexState {
+    index: ScanIndex,
+    /// Whether both trees were listed at least once, the index isn't written before
+    complete: bool,
+    /// The paths changed while both trees are being listed, which the listing might have
+    /// seen before they changed
+    rebuilding: Option<HashSet<PathBuf>>,
+    dirty: bool,
+    saved: Option<Instant>,
+}
+
+/// Which side of the index an update is for
+#[derive(Debug, Clone, Copy)]
+pub(crate) enum Side {
+    Work,
+    Backup,
+}
+
+impl LiveIndex {
+    pub fn new(backup_dir: &Path) -> Self {
+        Self {
+            path: index_path(backup_dir),
+            state: Mutex::new(IndexState {
+                index: ScanIndex {
+                    format: INDEX_FORMAT,
+                    pid: std::process::id(),
+                    ..ScanIndex::default()
+                },
+                complete: false,
+                rebuilding: None,
+                dirty: false,
+                saved: None,
+            }),
+        }
+    }
+
+    /// Starts collecting the paths that change until [`LiveIndex::rebuilt`]
+    pub fn rebuilding(&self) {
+        self.state.lock().unwrap().rebuilding = Some(HashSet::new());
+    }
+
+    /// Replaces both trees with what was listed since [`LiveIndex::rebuilding`]. Paths that
+    /// changed in the meantime keep what they were updated to
+    pub fn rebuilt(
+        &self,
+        mut work: BTreeMap<PathBuf, FileMetadata>,
+        mut backup: BTreeMap<PathBuf, FileMetadata>,
+    ) {
+        let mut state = self.state.lock().unwrap();
+        let changed = state.rebuilding.take().unwrap_or_default();
+        for relative_path in changed {
+            for (tree, listed) in [
+                (&state.index.work, &mut work),
+                (&state.index.backup, &mut backup),
+            ] {
+                match tree.get(&relative_path) {
+                    Some(metadata) => listed.insert(relative_path.clone(), *metadata),
+                    None => listed.remove(&relative_path),
+                };
+            }
+        }
+        state.index.work = work;
+        state.index.backup = backup;
+        state.complete = true;
+        state.dirty = true;
+    }
+
+    /// Records what `relative_path` on `side` looks like now, `None` if it's gone
+    pub fn update(&self, side: Side, relative_path: &Path, metadata: Option<FileMetadata>) {
+        let mut state = self.state.lock().unwrap();
+        let tree = match side {
+            Side::Work => &mut state.index.work,
+            Side::Backup => &mut state.index.backup,
+        };
+        match metadata {
+            Some(metadata) => tree.insert(relative_path.to_path_buf(), metadata),
+            None => tree.remove(relative_path),
+        };
+        if let Some(changed) = &mut state.rebuilding {
+            changed.insert(relative_path.to_path_buf());
+        }
+        state.dirty = true;
+    }
+
+    /// Writes the index if it changed, at most every [`SAVE_INTERVAL`] unless `now`. This
+    /// does blocking IO
+    pub fn save(&self, now: bool) -> Result<()> {
+        let contents = {
+            let mut state = self.state.lock().unwrap();
+            let due = now
+                || state
+                    .saved
+                    .is_none_or(|saved| saved.elapsed() >= SAVE_INTERVAL);
+            if !state.complete || !state.dirty || !due {
+                return Ok(());
+            }
+            state.index.updated = SystemTime::now()
+                .duration_since(UNIX_EPOCH)
+                .unwrap_or_default()
+                .as_secs();
+            state.dirty = false;
+            state.saved = Some(Instant::now());
+            serde_json::to_vec(&state.index)?
+        };
+
+        if let Some(parent) = self.path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        }
+        let mut tmp_path = self.path.clone().into_os_string();
+        tmp_path.push(TEMP_SUFFIX);
+        let tmp_path = PathBuf::from(tmp_path);
+        std::fs::write(&tmp_path, contents)
+            .with_context(|| anyhow!("Error writing {}", tmp_path.display()))?;
+        std::fs::rename(&tmp_path, &self.path)
+            .with_context(|| anyhow!("Error writing {}", self.path.display()))
+    }
+}
+
+/// What the file at `path` looks like now, `None` if it's gone
+pub(crate) fn file_metadata(path: &Path) -> Option<FileMetadata> {
+    // Links that aren't followed might point nowhere
+    let metadata = std::fs::metadata(path)
+        .or_else(|_| std::fs::symlink_metadata(path))
+        .ok()?;
+    Some(FileMetadata {
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+    })
+}
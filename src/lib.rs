@@ -0,0 +1,89 @@
+//! Keeps a backup directory in sync with a work directory.
+//!
+//! [`Syncer::initialize`] seeds the work directory from the backup, after which
+//! [`Syncer::run`] copies every change made in the work directory back into the backup.
+
+pub mod audit;
+pub mod backend;
+pub mod bidir;
+pub mod case;
+mod clear;
+pub mod cli;
+pub mod compare;
+pub mod config;
+pub mod consistent;
+pub mod control;
+pub mod copy;
+mod delta;
+pub mod detect;
+pub mod error;
+pub mod exit;
+pub mod filter;
+pub mod git;
+mod gitignore;
+mod guard;
+mod hash;
+pub mod health;
+mod hooks;
+pub mod index;
+pub mod lock;
+pub mod merge;
+pub mod meta;
+pub mod metrics;
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+pub mod mount;
+mod moves;
+mod notifications;
+mod poll;
+pub mod power;
+pub mod priority;
+mod progress;
+pub mod prune;
+pub mod quota;
+mod reflink;
+mod resume;
+pub mod rotate;
+mod scan;
+pub mod signing;
+mod smtp;
+pub mod snapshot;
+mod sparse;
+pub mod state;
+mod syncer;
+pub mod template;
+pub mod throttle;
+pub mod transform;
+mod trash;
+pub mod unicode;
+pub mod vanished;
+pub mod vfs;
+pub mod watchdog;
+pub mod watcher;
+pub mod window;
+pub mod winfs;
+
+pub use backend::Backend;
+pub use bidir::ConflictStrategy;
+pub use case::CaseCollisions;
+pub use compare::{Checksum, CompareBy, TreeDiff, Verification};
+pub use consistent::AtomicCopies;
+pub use copy::CopyOptions;
+pub use detect::DetectChanges;
+pub use error::{ErrorPolicy, Operation, SyncError};
+pub use filter::IgnoreSet;
+pub use guard::GuardBackup;
+pub use hash::{hash_directory, HashOptions, HashPool};
+pub use index::ScanIndex;
+pub use notifications::{EmailNotifications, NotifyTarget};
+pub use priority::Priorities;
+pub use progress::DEFAULT_FILE_PROGRESS_THRESHOLD;
+pub use quota::{Quota, QuotaPolicy};
+pub use rotate::{Rotate, Rotation};
+pub use smtp::SmtpTls;
+pub use syncer::{
+    SyncEvent, SyncEvents, SyncOptions, Syncer, DEFAULT_INTERVAL, DEFAULT_MAX_CONCURRENT_COPIES,
+    DEFAULT_MAX_PENDING, DEFAULT_MAX_RESTARTS, DEFAULT_MAX_RETRIES,
+};
+pub use transform::{Transform, Transforms};
+pub use unicode::NormalizeUnicode;
+pub use window::{QuietHours, SyncWindow, TimeOfDay};
@@ -0,0 +1,288 @@
+//! Keeping two evil_mounts from syncing the same backup at once. The second one would
+//! initialize work_dir while the first one is still copying changes out of it.
+//!
+//! Whoever syncs a backup holds `backup_dir/.evilmount/lock`, which records its PID and when
+//! it started. The lock is written to a file of its own first and then linked into place,
+//! so nobody sees it half written. A lock whose process is gone, or whose PID was taken by
+//! a process that started later, was left behind by a crash and is taken over. Only Linux
+//! tells when a process started though, so elsewhere a lock whose PID was reused only looks
+//! held, and can be stolen with `--force-lock`
+
+use anyhow::{anyhow, Context, Result};
+use serde::{Deserialize, Serialize};
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    thread,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tracing::warn;
+
+use crate::meta::{metadata_dir, TEMP_SUFFIX};
+
+/// How far apart two readings of when a process started can be, since the time the system
+/// booted at shifts a little with the clock
+const START_SLACK: u64 = 2;
+
+/// Locks that can't be read yet are only taken over once they're this old, for where they
+/// have to be written in place
+const WRITE_GRACE: Duration = Duration::from_secs(5);
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_secs()
+}
+
+/// Who holds a lock, as written to the lock file
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+struct Holder {
+    pid: u32,
+    /// When the process started, in seconds since the epoch. Where that isn't known, when it
+    /// took the lock
+    started: u64,
+}
+
+impl Holder {
+    fn this_process() -> Self {
+        let pid = std::process::id();
+        Self {
+            pid,
+            started: process_started(pid).unwrap_or_else(now),
+        }
+    }
+
+    fn read(path: &Path) -> Option<Self> {
+        serde_json::from_str(&fs::read_to_string(path).ok()?).ok()
+    }
+
+    /// Whether the process that took the lock is still running, and not just its PID
+    fn is_running(&self) -> bool {
+        is_running(self.pid)
+            && process_started(self.pid)
+                .is_none_or(|started| started.abs_diff(self.started) <= START_SLACK)
+    }
+}
+
+/// The lock of one backup directory, released when dropped
+#[derive(Debug)]
+pub struct BackupLock {
+    path: PathBuf,
+    holder: Holder,
+}
+
+impl BackupLock {
+    /// Takes the lock of `backup_dir`. Fails if another process that is still running holds
+    /// it, unless `force` is set
+    pub fn acquire(backup_dir: &Path, force: bool) -> Result<Self> {
+        let dir = metadata_dir(backup_dir);
+        fs::create_dir_all(&dir).with_context(|| anyhow!("Error creating {}", dir.display()))?;
+        let path = dir.join("lock");
+        let holder = Holder::this_process();
+
+        loop {
+            match create(&dir, &path, &holder) {
+                Ok(()) => return Ok(Self { path, holder }),
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {}
+                Err(err) => {
+                    return Err(err).with_context(|| anyhow!("Error creating {}", path.display()))
+                }
+            }
+
+            // Unreadable locks were most likely cut short by a crash while writing them
+            let other = Holder::read(&path);
+            match other {
+                Some(other) if other.is_running() && !force => {
+                    return Err(anyhow!(
+                        "{} is being synced by another evil_mount (pid {}, started {} seconds \
+                         ago). If it isn't running any more, start with --force-lock",
+                        backup_dir.display(),
+                        other.pid,
+                        now().saturating_sub(other.started)
+                    ));
+                }
+                Some(other) if other.is_running() => {
+                    warn!(
+                        pid = other.pid,
+                        "Stealing the lock of {}",
+                        backup_dir.display()
+                    );
+                }
+                None if is_being_written(&path) => {
+                    thread::sleep(Duration::from_millis(100));
+                    continue;
+                }
+                _ => warn!("Taking over the stale lock {}", path.display()),
+            }
+            take_over(&dir, &path, other, &holder)?;
+        }
+    }
+}
+
+/// Creates the lock at `path` for `holder`, failing with [`io::ErrorKind::AlreadyExists`]
+/// if someone holds it
+fn create(dir: &Path, path: &Path, holder: &Holder) -> io::Result<()> {
+    // Serializing a plain struct can't fail
+    let contents = serde_json::to_string(holder).unwrap_or_default();
+    let tmp_path = dir.join(format!("lock.{}{TEMP_SUFFIX}", holder.pid));
+    fs::write(&tmp_path, &contents)?;
+    let linked = fs::hard_link(&tmp_path, path);
+    let _ = fs::remove_file(&tmp_path);
+    match linked {
+        Err(err) if err.kind() != io::ErrorKind::AlreadyExists => {}
+        linked => return linked,
+    }
+
+    // Filesystems like FAT have no hard links, so it's written in place there
+    let mut file = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(path)?;
+    let written = file.write_all(contents.as_bytes());
+    if written.is_err() {
+        let _ = fs::remove_file(path);
+    }
+    written
+}
+
+/// Whether the lock at `path` that can't be read may still be being written
+fn is_being_written(path: &Path) -> bool {
+    fs::metadata(path)
+        .and_then(|metadata| metadata.modified())
+        .is_ok_and(|modified| modified.elapsed().is_ok_and(|age| age < WRITE_GRACE))
+}
+
+/// Removes the lock at `path` that was found held by `other`. It's moved aside first, since
+/// whoever else is taking it over too may have made a new one in the meantime, which is put
+/// back then
+fn take_over(dir: &Path, path: &Path, other: Option<Holder>, holder: &Holder) -> Result<()> {
+    let aside = dir.join(format!("lock.{}.stale{TEMP_SUFFIX}", holder.pid));
+    match fs::rename(path, &aside) {
+        Ok(()) => {}
+        Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(()),
+        Err(err) => return Err(err).with_context(|| anyhow!("Error removing {}", path.display())),
+    }
+    if Holder::read(&aside) != other {
+        // Failing means yet another process holds it by now
+        let _ = fs::hard_link(&aside, path);
+    }
+    let _ = fs::remove_file(&aside);
+    Ok(())
+}
+
+/// The PID of whoever holds the lock of `backup_dir`, if anyone that's still running does
+pub fn holder(backup_dir: &Path) -> Option<u32> {
+    let holder = Holder::read(&metadata_dir(backup_dir).join("lock"))?;
+    holder.is_running().then_some(holder.pid)
+}
+
+impl Drop for BackupLock {
+    fn drop(&mut self) {
+        // Unless it was stolen
+        if Holder::read(&self.path) == Some(self.holder) {
+            let _ = fs::remove_file(&self.path);
+        }
+    }
+}
+
+/// When the process with `pid` started, in seconds since the epoch, from its start in clock
+/// ticks after the system booted
+#[cfg(target_os = "linux")]
+fn process_started(pid: u32) -> Option<u64> {
+    use nix::unistd::{sysconf, SysconfVar};
+
+    let stat = fs::read_to_string(format!("/proc/{pid}/stat")).ok()?;
+    // The name in parentheses can have spaces in it, the start time is the 22nd field
+    let (_, fields) = stat.rsplit_once(')')?;
+    let ticks: u64 = fields.split_whitespace().nth(19)?.parse().ok()?;
+    let per_second = u64::try_from(sysconf(SysconfVar::CLK_TCK).ok()??).ok()?;
+    let booted: u64 = fs::read_to_string("/proc/stat")
+        .ok()?
+        .lines()
+        .find_map(|line| line.strip_prefix("btime "))?
+        .trim()
+        .parse()
+        .ok()?;
+    Some(booted + ticks / per_second.max(1))
+}
+
+/// Other systems don't tell
+#[cfg(not(target_os = "linux"))]
+fn process_started(_pid: u32) -> Option<u64> {
+    None
+}
+
+/// Whether a process with `pid` exists
+#[cfg(unix)]
+pub fn is_running(pid: u32) -> bool {
+    use nix::{errno::Errno, sys::signal::kill, unistd::Pid};
+
+    let Ok(pid) = i32::try_from(pid) else {
+        return false;
+    };
+    // Signal 0 only checks whether it could be sent
+    matches!(kill(Pid::from_raw(pid), None), Ok(()) | Err(Errno::EPERM))
+}
+
+/// Without a way to check, every process counts as running
+#[cfg(not(unix))]
+pub fn is_running(_pid: u32) -> bool {
+    true
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write_lock(backup_dir: &Path, holder: Holder) {
+        let path = metadata_dir(backup_dir).join("lock");
+        fs::write(path, serde_json::to_string(&holder).unwrap()).unwrap();
+    }
+
+    #[test]
+    fn only_one_holds_the_lock() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = BackupLock::acquire(dir.path(), false).unwrap();
+        assert!(BackupLock::acquire(dir.path(), false).is_err());
+        assert_eq!(holder(dir.path()), Some(std::process::id()));
+        drop(lock);
+        assert_eq!(holder(dir.path()), None);
+        BackupLock::acquire(dir.path(), false).unwrap();
+    }
+
+    #[test]
+    fn stolen_locks_are_kept() {
+        let dir = tempfile::tempdir().unwrap();
+        let lock = BackupLock::acquire(dir.path(), false).unwrap();
+        let thief = Holder { pid: 1, started: 0 };
+        write_lock(dir.path(), thief);
+        drop(lock);
+        let path = metadata_dir(dir.path()).join("lock");
+        assert_eq!(Holder::read(&path), Some(thief));
+    }
+
+    #[test]
+    fn stale_locks_are_taken_over() {
+        let dir = tempfile::tempdir().unwrap();
+        fs::create_dir_all(metadata_dir(dir.path())).unwrap();
+        let gone = Holder {
+            pid: i32::MAX as u32,
+            started: now(),
+        };
+        write_lock(dir.path(), gone);
+        drop(BackupLock::acquire(dir.path(), false).unwrap());
+
+        if cfg!(target_os = "linux") {
+            // Our PID, but taken by a process that started earlier
+            let reused = Holder {
+                started: Holder::this_process().started - 60,
+                ..Holder::this_process()
+            };
+            write_lock(dir.path(), reused);
+            assert_eq!(holder(dir.path()), None);
+            BackupLock::acquire(dir.path(), false).unwrap();
+        }
+    }
+}
@@ -1,374 +1,1681 @@
 use anyhow::{anyhow, Context, Result};
-use blake3::{Hash, Hasher};
+use clap::{CommandFactory, Parser};
+use evil_mount::{
+    audit::{read_audit_log, AuditQuery},
+    backend::FileMetadata,
+    cli::{
+        Args, Command, ConfigCommand, DirArgs, InitArgs, ListenAddr, LogFormat, OutputFormat,
+        ServiceCommand, StateCommand, SyncArgs,
+    },
+    config::{self, build_syncers, mappings, reload, Config},
+    control::{self, ControlledPair, PairStatus},
+    exit::{Exit, ExitWith},
+    health::{self, HealthPair},
+    lock::BackupLock,
+    merge::Keep,
+    metrics,
+    prune::{prune, Retention},
+    state::ExportedState,
+    template::{self, PathVars},
+    throttle::lower_io_priority,
+    CompareBy, ScanIndex, SyncEvent, SyncEvents, Syncer, TreeDiff, Verification, DEFAULT_INTERVAL,
+};
+use futures::{future, stream, Stream, StreamExt};
+use indicatif::HumanBytes;
+use serde::Serialize;
 use std::{
-    collections::HashMap,
-    path::PathBuf,
+    io::{IsTerminal, Write},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    process::ExitCode,
     sync::{
-        atomic::{AtomicU64, Ordering, AtomicBool},
-        Arc, Mutex,
+        atomic::{AtomicBool, Ordering},
+        Arc,
     },
-    time::{Duration, UNIX_EPOCH},
-};
-use tokio::{
-    fs::{self, remove_dir_all, remove_file},
-    io,
-    task::JoinHandle,
-    time::Instant,
+    time::{Duration, Instant, SystemTime},
 };
-use walkdir::WalkDir;
+use tokio::sync::Notify;
+use tracing::{error, info, info_span, level_filters::LevelFilter, warn, Instrument, Span};
+use tracing_subscriber::{layer::SubscriberExt, util::SubscriberInitExt, EnvFilter, Layer};
 
-use clap::Parser;
+mod browse;
+mod completions;
+mod otel;
+mod preview;
+mod service;
+mod tui;
 
-/// A program to backup files to a different directory
-#[derive(Parser, Debug)]
-#[command(author, version, about, long_about = None)]
-struct Args {
-    /// The directory that you will be working in, will be completely cleared
-    #[arg(short, long)]
-    work_dir: PathBuf,
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+use evil_mount::{
+    cli::MountArgs,
+    mount::{Mount, Replicator},
+};
+use service::PidFile;
 
-    /// The directory that will be copied to. Used to initialize source dir
-    #[arg(short, long)]
-    backup_dir: PathBuf,
+fn main() -> ExitCode {
+    let args = Args::parse();
+    match start(args) {
+        Ok(()) => ExitCode::SUCCESS,
+        Err(err) => {
+            eprintln!("Error: {err:?}");
+            Exit::of(&err).into()
+        }
+    }
 }
 
-static SHOULD_SHUTDOWN: AtomicBool = AtomicBool::new(false);
+fn start(args: Args) -> Result<()> {
+    // Forking only keeps the thread that forks, so it has to happen before the runtime starts
+    if let Command::Sync { dirs, sync, .. } = &args.command {
+        let config = match &dirs.config {
+            Some(path) => Config::load(path, dirs.profile.as_deref())?,
+            None => Config::default(),
+        };
+        if sync.daemon || config.daemon {
+            service::daemonize(sync.log_file.as_deref().or(config.log_file.as_deref()))?;
+        }
+    }
+
+    tokio::runtime::Builder::new_multi_thread()
+        .enable_all()
+        .build()?
+        .block_on(run(args))
+}
 
-#[tokio::main]
-async fn main() -> Result<()> {
+async fn run(args: Args) -> Result<()> {
     let Args {
-        work_dir,
-        backup_dir,
-    } = Args::parse();
+        command,
+        verbose,
+        quiet,
+        log_format,
+        otel_endpoint,
+    } = args;
+    let otel_endpoint = otel_endpoint.or_else(|| std::env::var("OTEL_EXPORTER_OTLP_ENDPOINT").ok());
+    let exporter = init_logging(verbose, quiet, log_format, otel_endpoint.as_deref())?;
 
-    // Ensure that source_dir and backup_dir are folders
-    if !work_dir.is_dir() {
-        return Err(anyhow!("work_dir must be a directory!"));
-    }
-    if !backup_dir.is_dir() {
-        return Err(anyhow!("backup_dir must be a directory!"));
+    let result = execute(command).await;
+    if let Some(exporter) = exporter {
+        exporter.flush().await;
     }
+    result
+}
 
-    println!(
-        "Checking if {} and {} are equal",
-        work_dir.display(),
-        backup_dir.display()
-    );
+async fn execute(command: Command) -> Result<()> {
+    match command {
+        Command::Sync { dirs, init, sync } => {
+            let yes = init.yes;
+            let force_lock = init.force_lock;
+            let once = sync.once;
+            let config = match &dirs.config {
+                Some(path) => Config::load(path, dirs.profile.as_deref())?,
+                None => Config::default(),
+            };
+            let dated = DatedBackupDirs::new(&dirs, &config).exit_with(Exit::Config)?;
+            let metrics_addr = sync.metrics_addr.or(config.metrics_addr);
+            let health_addr = sync.health_addr.or(config.health_addr);
+            let control_socket = sync.control_socket.clone().or(config.control_socket);
+            let nice_io = sync.nice_io || config.nice_io;
+            let output = sync.output.or(config.output).unwrap_or_default();
+            let _pidfile = match sync.pidfile.as_deref().or(config.pidfile.as_deref()) {
+                Some(path) => Some(PidFile::create(path)?),
+                None => None,
+            };
+            let config_path = dirs.config.clone();
+            let reload_args = (dirs.clone(), init.clone(), sync.clone());
+            let syncers = build_syncers(dirs, init, sync).await?;
+            let locks = lock_backups(&syncers, force_lock)?;
+            if nice_io {
+                if let Err(err) = lower_io_priority() {
+                    warn!("{err:#}");
+                }
+            }
+            if let Some(addr) = metrics_addr {
+                serve_metrics(addr, &syncers).await?;
+            }
+            let initialized = Arc::new(AtomicBool::new(once));
+            if let Some(ListenAddr(addr)) = health_addr {
+                serve_health(addr, &syncers, initialized.clone()).await?;
+            }
+            if once {
+                return run_once(&syncers, output).await;
+            }
+            // Initializing would throw away changes made in work_dir while we weren't running
+            for syncer in syncers
+                .iter()
+                .filter(|syncer| !syncer.options().bidirectional)
+            {
+                syncer
+                    .initialize_asking(|paths| confirm_removal(paths, yes), ask_which_to_keep)
+                    .instrument(pair_span(syncer, &syncers))
+                    .await
+                    .exit_with(Exit::Init)?;
+            }
+            initialized.store(true, Ordering::Relaxed);
+            let reloads = Arc::new(Notify::new());
+            serve_control(control_socket.as_deref(), &syncers, reloads.clone()).await?;
+            let _config_watcher = match &config_path {
+                Some(path) => {
+                    let reloads = reloads.clone();
+                    Some(config::watch(path, move || reloads.notify_one())?)
+                }
+                None => None,
+            };
+            service::notify("READY=1");
+            service::spawn_watchdog();
+            let result = run_sync(&syncers, output, &reloads, &reload_args, &dated).await;
+            service::notify("STOPPING=1");
+            if let Some(socket) = control_socket {
+                let _ = std::fs::remove_file(socket);
+            }
+            match result? {
+                Stopped::Interrupted => Ok(()),
+                Stopped::Moved => {
+                    drop(locks);
+                    restart()
+                }
+            }
+        }
+        Command::Init { dirs, init } => {
+            let yes = init.yes;
+            let force_lock = init.force_lock;
+            let syncers = build_syncers(dirs, init, SyncArgs::default()).await?;
+            let _locks = lock_backups(&syncers, force_lock)?;
+            for syncer in &syncers {
+                syncer
+                    .initialize_asking(|paths| confirm_removal(paths, yes), ask_which_to_keep)
+                    .instrument(pair_span(syncer, &syncers))
+                    .await
+                    .exit_with(Exit::Init)?;
+            }
+            Ok(())
+        }
+        #[cfg(all(feature = "fuse", target_os = "linux"))]
+        Command::Mount { dirs, init, mount } => run_mount(dirs, init, mount).await,
+        Command::Restore {
+            dirs,
+            paths,
+            at,
+            interactive,
+            preview,
+        } => {
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            let (at, mut selected) = match interactive && paths.is_empty() {
+                true => match browse::run(&syncers, at.as_deref()).await? {
+                    Some(selection) => (selection.at, selection.paths),
+                    None => return Ok(()),
+                },
+                false => {
+                    // Every path goes to the pair whose work_dir it's in
+                    let mut selected: Vec<Vec<PathBuf>> =
+                        syncers.iter().map(|_| Vec::new()).collect();
+                    for path in &paths {
+                        let path = resolve_path(path)?;
+                        let (index, relative_path) = syncers
+                            .iter()
+                            .enumerate()
+                            .find_map(|(index, syncer)| {
+                                let relative_path = path.strip_prefix(syncer.work_dir()).ok()?;
+                                Some((index, relative_path.to_path_buf()))
+                            })
+                            .ok_or_else(|| anyhow!("{} isn't in a work_dir", path.display()))?;
+                        selected[index].push(relative_path);
+                    }
+                    (at, selected)
+                }
+            };
+            let picked = interactive || !paths.is_empty();
+            if preview || (interactive && !paths.is_empty()) {
+                for (syncer, selected) in syncers.iter().zip(&mut selected) {
+                    if picked && selected.is_empty() {
+                        continue;
+                    }
+                    match preview {
+                        true => preview::print_preview(syncer, at.as_deref(), selected).await?,
+                        false => {
+                            *selected = preview::ask_each(syncer, at.as_deref(), selected).await?
+                        }
+                    }
+                }
+                if preview {
+                    return Ok(());
+                }
+            }
+            for (syncer, selected) in syncers.iter().zip(&selected) {
+                if picked && selected.is_empty() {
+                    continue;
+                }
+                info!(
+                    "Restoring {} from {}...",
+                    syncer.work_dir().display(),
+                    syncer.backend()
+                );
+                let restored = match &at {
+                    Some(at) => syncer.restore_snapshot(at, selected).await?,
+                    None => syncer.restore(selected).await?,
+                };
+                info!("Restored {restored} files!");
+            }
+            Ok(())
+        }
+        Command::Prune {
+            dirs,
+            keep_last,
+            keep_days,
+        } => {
+            let config = match &dirs.config {
+                Some(path) => Config::load(path, dirs.profile.as_deref())?,
+                None => Config::default(),
+            };
+            let retention = Retention {
+                keep_last: keep_last.or(config.keep_last),
+                keep_for: keep_days
+                    .or(config.keep_days)
+                    .map(|days| Duration::from_secs(days * 24 * 60 * 60)),
+            };
+            let dry_run = dirs.dry_run;
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            let action = match dry_run {
+                true => "WOULD DELETE",
+                false => "Removed",
+            };
+            let mut bytes = 0;
+            for syncer in &syncers {
+                let pruned = match syncer.backend().prune(retention, dry_run).await? {
+                    Some(pruned) => pruned,
+                    None => {
+                        let backup_dir = syncer.backend().local_dir().ok_or_else(|| {
+                            anyhow!("Pruning is only supported for local backup directories")
+                        })?;
+                        prune(backup_dir, retention, dry_run)?
+                    }
+                };
+                for dir in &pruned.removed {
+                    println!("{action} {}", dir.display());
+                }
+                bytes += pruned.bytes;
+            }
+            match dry_run {
+                true => println!("Would reclaim {}", HumanBytes(bytes)),
+                false => println!("Reclaimed {}", HumanBytes(bytes)),
+            }
+            Ok(())
+        }
+        Command::Verify {
+            dirs,
+            check_signature,
+            public_key,
+        } => {
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            let mut differences = 0;
+            for syncer in &syncers {
+                let verification = syncer.verify().await?;
+                match verification.diff.is_empty() {
+                    true => println!(
+                        "{} matches {} ({} files)",
+                        syncer.backend(),
+                        syncer.work_dir().display(),
+                        verification.backup.len()
+                    ),
+                    false => print_verification(syncer, &verification),
+                }
+                differences += verification.diff.len();
 
-    let work_dir_clone = work_dir.clone();
-    let backup_dir_clone = backup_dir.clone();
+                if check_signature {
+                    let check = syncer.check_signatures(public_key, &verification).await?;
+                    println!(
+                        "{} has {} signed manifests, signed by {}",
+                        syncer.backend(),
+                        check.manifests,
+                        check.public_key
+                    );
+                    for path in &check.unsigned {
+                        println!("  changed since it was signed: {}", path.display());
+                    }
+                    differences += check.unsigned.len();
+                }
+            }
+            match differences {
+                0 => Ok(()),
+                differences => {
+                    Err(anyhow!("Found {differences} differences")).exit_with(Exit::Mismatch)
+                }
+            }
+        }
+        Command::Diff {
+            dirs,
+            hash,
+            stat,
+            json,
+            rescan,
+        } => {
+            let compare_by = match hash {
+                true => CompareBy::Contents,
+                false => CompareBy::Metadata,
+            };
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            let mut diffs = Vec::with_capacity(syncers.len());
+            for syncer in &syncers {
+                // The index only knows sizes and modification times
+                let index = match hash || rescan {
+                    true => None,
+                    false => syncer.live_index()?,
+                };
+                diffs.push(match index {
+                    Some(index) => {
+                        info!(
+                            "Using the index of the running sync of {}, updated {}. Pass --rescan to scan instead",
+                            syncer.backend(),
+                            format_age(index.updated())
+                        );
+                        PairDiff::from_index(syncer, &index)
+                    }
+                    None => PairDiff::new(syncer, compare_by).await?,
+                });
+            }
+            match json {
+                true => println!("{}", serde_json::to_string_pretty(&diffs)?),
+                false => {
+                    for diff in &diffs {
+                        if diffs.len() > 1 {
+                            println!("{} vs {}:", diff.work_dir, diff.backup_dir);
+                        }
+                        diff.print(stat);
+                    }
+                }
+            }
+            Ok(())
+        }
+        Command::Status {
+            dirs,
+            socket,
+            tui,
+            rescan,
+        } => {
+            let explicit = socket.is_some() || tui;
+            let socket = match (socket, &dirs.config) {
+                (Some(socket), _) => Some(socket),
+                (None, Some(path)) => Config::load(path, dirs.profile.as_deref())?.control_socket,
+                (None, None) => None,
+            };
+            if let Some(socket) = socket {
+                match control::query(&socket).await {
+                    Ok(_) if tui => return tui::run(&socket).await,
+                    Ok(statuses) => {
+                        print_live_status(&statuses);
+                        return Ok(());
+                    }
+                    Err(err) if explicit => return Err(err),
+                    // Nothing running, so look at the directories instead
+                    Err(_) => {}
+                }
+            }
+            if tui {
+                return Err(anyhow!("--tui needs the --socket of a running sync"));
+            }
 
-    let start = Instant::now();
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            for (index, syncer) in syncers.iter().enumerate() {
+                if index > 0 {
+                    println!();
+                }
+                print_status(syncer, rescan).await?;
+            }
+            Ok(())
+        }
+        Command::Audit {
+            dirs,
+            path,
+            op,
+            since,
+            failed,
+            json,
+        } => {
+            let since = since.map(|since| {
+                let since = SystemTime::now()
+                    .checked_sub(since.into())
+                    .unwrap_or(SystemTime::UNIX_EPOCH);
+                chrono::DateTime::<chrono::Utc>::from(since)
+                    .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+            });
+            let query = AuditQuery {
+                path,
+                op,
+                since,
+                failed,
+            };
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            for (index, syncer) in syncers.iter().enumerate() {
+                if syncers.len() > 1 && !json {
+                    if index > 0 {
+                        println!();
+                    }
+                    println!("{}:", syncer.backend());
+                }
+                print_audit_log(syncer, &query, json)?;
+            }
+            Ok(())
+        }
+        Command::Completions { shell } => {
+            print_bytes(&completions::completions(Args::command(), shell))
+        }
+        Command::Man => print_bytes(&completions::man_page(Args::command())),
+        Command::Control { socket, command } => {
+            print!("{}", control::send(&socket, command).await?);
+            Ok(())
+        }
+        Command::Service {
+            command: ServiceCommand::Install { name, print, args },
+        } => {
+            // Mistakes are better caught now than when the service starts
+            let sync_args = ["evil_mount", "sync"]
+                .into_iter()
+                .chain(args.iter().map(String::as_str));
+            if let Command::Sync { sync, .. } = Args::try_parse_from(sync_args)?.command {
+                if sync.daemon {
+                    return Err(anyhow!(
+                        "Services run in the foreground, leave out --daemon"
+                    ));
+                }
+            }
+            service::install(&name, &args, print)
+        }
+        Command::State {
+            command: StateCommand::Export { dirs, path },
+        } => {
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            let mut pairs = Vec::with_capacity(syncers.len());
+            for syncer in &syncers {
+                pairs.push(syncer.export_state().await?);
+            }
+            ExportedState::new(pairs).write(&path)?;
+            info!(
+                "Exported the state of {} pairs to {}",
+                syncers.len(),
+                path.display()
+            );
+            Ok(())
+        }
+        Command::State {
+            command:
+                StateCommand::Import {
+                    dirs,
+                    path,
+                    force_lock,
+                },
+        } => {
+            let state = ExportedState::read(&path)?;
+            let syncers = build_syncers(dirs, InitArgs::default(), SyncArgs::default()).await?;
+            if state.pairs.len() != syncers.len() {
+                return Err(anyhow!(
+                    "{} has the state of {} pairs, but there are {} to import it into",
+                    path.display(),
+                    state.pairs.len(),
+                    syncers.len()
+                ));
+            }
+            let _locks = lock_backups(&syncers, force_lock)?;
+            for (syncer, pair) in syncers.iter().zip(state.pairs) {
+                info!(
+                    "Importing the state of {} into {}",
+                    pair.work_dir.display(),
+                    syncer.work_dir().display()
+                );
+                syncer.import_state(pair).await?;
+            }
+            Ok(())
+        }
+        Command::Config {
+            command: ConfigCommand::Init { path },
+        } => match path {
+            Some(path) => {
+                if path.exists() {
+                    return Err(anyhow!("{} already exists!", path.display()));
+                }
+                std::fs::write(&path, config::TEMPLATE)
+                    .with_context(|| anyhow!("Error writing {}", path.display()))
+            }
+            None => {
+                print!("{}", config::TEMPLATE);
+                Ok(())
+            }
+        },
+    }
+}
 
-    let (work_dir_hash, backup_dir_hash) = tokio::join!(
-        tokio::task::spawn_blocking(move || hash_directory(work_dir_clone)),
-        tokio::task::spawn_blocking(move || hash_directory(backup_dir_clone)),
-    );
+/// Makes `path` absolute with symlinks resolved, like work_dir is. What `path` points at
+/// doesn't need to exist
+fn resolve_path(path: &Path) -> Result<PathBuf> {
+    let path =
+        std::path::absolute(path).with_context(|| anyhow!("Error resolving {}", path.display()))?;
+    // The nearest directory above it that exists decides where it really is
+    let mut missing = Vec::new();
+    let mut existing = path.as_path();
+    loop {
+        if let Ok(resolved) = existing.canonicalize() {
+            return Ok(missing
+                .iter()
+                .rev()
+                .fold(resolved, |path, name| path.join(name)));
+        }
+        match (existing.parent(), existing.file_name()) {
+            (Some(parent), Some(name)) => {
+                missing.push(name);
+                existing = parent;
+            }
+            _ => return Ok(path),
+        }
+    }
+}
+
+/// Locks every local backup directory for as long as the locks are kept, so another
+/// evil_mount can't sync them at the same time. Dry runs don't change anything, and don't
+/// need a lock
+fn lock_backups(syncers: &[Syncer], force: bool) -> Result<Vec<BackupLock>> {
+    syncers
+        .iter()
+        .filter(|syncer| !syncer.options().dry_run)
+        .filter_map(|syncer| syncer.backend().local_dir())
+        .map(|backup_dir| BackupLock::acquire(backup_dir, force))
+        .collect()
+}
 
-    let work_dir_hash = work_dir_hash??;
-    let backup_dir_hash = backup_dir_hash??;
+/// Asks on the terminal whether the files in work_dir that initialization would remove or
+/// replace may go. Without a terminal to ask on, `--yes` is required
+fn confirm_removal(paths: &[PathBuf], yes: bool) -> Result<bool> {
+    const SAMPLE_SIZE: usize = 10;
+
+    if yes {
+        return Ok(true);
+    }
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "Initialization would remove or replace {} files in work_dir, pass --yes to allow it",
+            paths.len()
+        ));
+    }
 
     println!(
-        "Done! Took {} seconds",
-        Instant::now().duration_since(start).as_secs_f32()
+        "Initialization will remove or replace {} files in work_dir:",
+        paths.len()
     );
+    for path in paths.iter().take(SAMPLE_SIZE) {
+        println!("  {}", path.display());
+    }
+    if paths.len() > SAMPLE_SIZE {
+        println!("  ...and {} more", paths.len() - SAMPLE_SIZE);
+    }
+    print!("Continue? [y/N] ");
+    std::io::stdout().flush()?;
 
-    if work_dir_hash == backup_dir_hash {
-        println!(
-            "{} == {}, skipping initialization",
-            work_dir.display(),
-            backup_dir.display()
-        );
-    } else {
-        println!("Clearing {}...", work_dir.display());
-        while let Ok(Some(file_info)) = fs::read_dir(&work_dir)
-            .await
-            .with_context(|| anyhow!("Error reading the source directory"))?
-            .next_entry()
-            .await
-        {
-            let path = file_info.path();
-            match path.is_dir() {
-                true => remove_dir_all(&path).await?,
-                false => match path.is_file() {
-                    true => remove_file(&path).await?,
-                    // not really sure what to do here
-                    false => todo!(),
-                },
-            };
-        }
-        println!("Cleared {}!", work_dir.display());
-
-        println!(
-            "Initializing {} with the contents of {}...",
-            work_dir.display(),
-            backup_dir.display()
-        );
-        for file_info in WalkDir::new(&backup_dir)
-            .follow_links(true)
-            .into_iter()
-            .filter(|file_info| match file_info {
-                Ok(file_info) => file_info.path().is_file(),
-                Err(_) => false,
-            })
-            .into_iter()
-        {
-            let file_info = file_info?;
-            let path = file_info.path();
-            copy_to_dst(path.to_path_buf(), backup_dir.clone(), work_dir.clone())
-                .await
-                .with_context(|| anyhow!("Error copying file for initialization"))?;
-        }
+    let mut answer = String::new();
+    std::io::stdin().read_line(&mut answer)?;
+    Ok(matches!(answer.trim(), "y" | "Y" | "yes"))
+}
 
-        println!("Initialized {}!", work_dir.display());
+/// Asks which copy of a file that differs between work_dir and the backup a merge keeps
+fn ask_which_to_keep(
+    relative_path: &Path,
+    work: &FileMetadata,
+    backup: &FileMetadata,
+) -> Result<Keep> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "{} differs between work_dir and the backup, and --merge-policy prompt needs a \
+             terminal to ask which to keep",
+            relative_path.display()
+        ));
     }
 
-    tokio::task::spawn(async move { copy_files(work_dir, backup_dir).await.unwrap() });
-    tokio::signal::ctrl_c().await?;
-
-    SHOULD_SHUTDOWN.store(true, Ordering::Relaxed);
-    println!("Waiting 5 seconds for tokio tasks to shutdown...");
+    let describe = |metadata: &FileMetadata| {
+        let modified = metadata.modified.map_or("unknown".to_string(), |modified| {
+            chrono::DateTime::<chrono::Local>::from(modified)
+                .format("%Y-%m-%d %H:%M:%S")
+                .to_string()
+        });
+        format!("{}, modified {modified}", HumanBytes(metadata.size))
+    };
+    println!("{} differs:", relative_path.display());
+    println!("  work_dir: {}", describe(work));
+    println!("  backup:   {}", describe(backup));
+    loop {
+        print!("Keep the copy in [w]ork_dir or the [b]ackup? ");
+        std::io::stdout().flush()?;
 
-    tokio::time::sleep(Duration::from_secs(5)).await;
+        let mut answer = String::new();
+        if std::io::stdin().read_line(&mut answer)? == 0 {
+            return Err(anyhow!("Initialization cancelled"));
+        }
+        match answer.trim() {
+            "w" | "W" | "work" => return Ok(Keep::Work),
+            "b" | "B" | "backup" => return Ok(Keep::Backup),
+            _ => {}
+        }
+    }
+}
 
-    println!("Done!");
+/// Sends log lines to stderr, keeping stdout for the output of commands like `verify`.
+/// `RUST_LOG` overrides the level picked with `-v` and `-q`. With an `otel_endpoint`, spans
+/// are exported there too, by the returned exporter
+fn init_logging(
+    verbose: u8,
+    quiet: u8,
+    log_format: LogFormat,
+    otel_endpoint: Option<&str>,
+) -> Result<Option<otel::Exporter>> {
+    let level = match (verbose, quiet) {
+        (0, 0) => LevelFilter::INFO,
+        (1, _) => LevelFilter::DEBUG,
+        (_, 0) => LevelFilter::TRACE,
+        (_, 1) => LevelFilter::WARN,
+        _ => LevelFilter::ERROR,
+    };
+    let filter = EnvFilter::builder()
+        .with_default_directive(level.into())
+        .from_env_lossy();
 
-    Ok(())
+    let logger = tracing_subscriber::fmt::layer()
+        .with_writer(std::io::stderr)
+        // Log files and journals don't want colors
+        .with_ansi(std::io::stderr().is_terminal())
+        .with_target(false);
+    let logger = match log_format {
+        LogFormat::Text => logger.boxed(),
+        LogFormat::Json => logger.json().boxed(),
+    };
+    let (spans, exporter) = match otel_endpoint {
+        Some(endpoint) => {
+            let (spans, exporter) = otel::layer(endpoint)?;
+            (Some(spans.with_filter(otel::filter())), Some(exporter))
+        }
+        None => (None, None),
+    };
+    tracing_subscriber::registry()
+        .with(logger.with_filter(filter))
+        .with(spans)
+        .init();
+    Ok(exporter)
 }
 
-async fn backup_files() {
-    todo!()
+/// Mounts the work_dir of the only pair over its backup_dir, or over a staging directory
+/// that's replicated to it, until interrupted
+#[cfg(all(feature = "fuse", target_os = "linux"))]
+async fn run_mount(mut dirs: DirArgs, init: InitArgs, mount: MountArgs) -> Result<()> {
+    let config = match &dirs.config {
+        Some(path) => Config::load(path, dirs.profile.as_deref())?,
+        None => Config::default(),
+    };
+    let staging_dir = mount.staging_dir.or(config.staging_dir.clone());
+    let replicate = mount.replicate.or(config.replicate).unwrap_or_default();
+    let mapping = match &mappings(
+        std::mem::take(&mut dirs.work_dir),
+        std::mem::take(&mut dirs.backup_dir),
+        &config,
+        dirs.profile.as_deref(),
+    )?[..]
+    {
+        [mapping] => mapping.clone(),
+        _ => return Err(anyhow!("mount only takes one work_dir and backup_dir")),
+    };
+    let mountpoint = mapping.work_dir;
+    if staging_dir.is_none()
+        && (dirs.sanitize_names.or(config.sanitize_names).is_some()
+            || !dirs.transform.is_empty()
+            || !config.transform.is_empty())
+    {
+        return Err(anyhow!(
+            "Mounting over backup_dir can't sanitize names or transform files, use --staging-dir"
+        ));
+    }
+    // Syncing works on the staging directory, the mount over backup_dir has nothing to sync
+    dirs.work_dir = vec![staging_dir.clone().unwrap_or_else(|| mountpoint.clone())];
+    dirs.backup_dir = vec![mapping.backup_dir];
+
+    let yes = init.yes;
+    let force_lock = init.force_lock;
+    let syncers = build_syncers(dirs, init, SyncArgs::default()).await?;
+    let _locks = lock_backups(&syncers, force_lock)?;
+    let syncer = &syncers[0];
+    if let Some(backup_dir) = syncer.backend().local_dir() {
+        let resolved = resolve_path(&mountpoint)?;
+        if resolved.starts_with(backup_dir) || backup_dir.starts_with(&resolved) {
+            return Err(anyhow!(
+                "The mount at {} can't be inside backup_dir ({}) or the other way around",
+                mountpoint.display(),
+                backup_dir.display()
+            ));
+        }
+    }
+    let mounted = match staging_dir {
+        Some(_) => {
+            syncer
+                .initialize_asking(|paths| confirm_removal(paths, yes), ask_which_to_keep)
+                .await
+                .exit_with(Exit::Init)?;
+            let replicator = Replicator::new(syncer, replicate);
+            Mount::new(&mountpoint, syncer.work_dir(), Some(replicator))?
+        }
+        None => {
+            let backup_dir = syncer.backend().local_dir().ok_or_else(|| {
+                anyhow!(
+                    "{} isn't a local directory, mount it with --staging-dir",
+                    syncer.backend()
+                )
+            })?;
+            Mount::new(&mountpoint, backup_dir, None)?
+        }
+    };
+    service::notify("READY=1");
+    tokio::signal::ctrl_c().await?;
+    info!("Unmounting {}", mounted.mountpoint().display());
+    service::notify("STOPPING=1");
+    mounted.unmount().await
 }
 
-struct FileSyncInfo {
-    /// The time the file was last modified to in Unix time
-    modify_time: Arc<AtomicU64>,
-    /// The tokio task running in a loop that ensures the time is kept in sync
-    sync_task: JoinHandle<()>,
+/// Serves the metrics of every pair in the background
+async fn serve_metrics(addr: SocketAddr, syncers: &[Syncer]) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| anyhow!("Error listening on {addr} for metrics"))?;
+    let metrics = syncers
+        .iter()
+        .map(|syncer| {
+            (
+                syncer.work_dir().display().to_string(),
+                syncer.metrics().clone(),
+            )
+        })
+        .collect();
+    tokio::spawn(async move {
+        if let Err(err) = metrics::serve(listener, metrics).await {
+            error!("Error serving metrics: {err:#}");
+        }
+    });
+    Ok(())
 }
 
-// TODO: gitignore
-async fn copy_files(work_dir: PathBuf, backup_dir: PathBuf) -> Result<()> {
-    println!("Watching for file changes...");
+/// Serves the liveness and readiness of every pair in the background
+async fn serve_health(
+    addr: SocketAddr,
+    syncers: &[Syncer],
+    initialized: Arc<AtomicBool>,
+) -> Result<()> {
+    let listener = tokio::net::TcpListener::bind(addr)
+        .await
+        .with_context(|| anyhow!("Error listening on {addr} for health checks"))?;
+    let pairs = syncers
+        .iter()
+        .map(|syncer| {
+            let options = syncer.options();
+            let interval = options.interval.unwrap_or(DEFAULT_INTERVAL);
+            HealthPair {
+                work_dir: syncer.work_dir().display().to_string(),
+                metrics: syncer.metrics().clone(),
+                interval: options.max_interval.unwrap_or(interval * 4).max(interval),
+            }
+        })
+        .collect();
+    tokio::spawn(async move {
+        if let Err(err) = health::serve(listener, pairs, initialized).await {
+            error!("Error serving health checks: {err:#}");
+        }
+    });
+    Ok(())
+}
 
-    let mut handles: HashMap<PathBuf, FileSyncInfo> = HashMap::new();
+/// Pauses and resumes every pair on SIGUSR1 and SIGUSR2, asks for a reload on SIGHUP, and
+/// answers commands on `socket`
+async fn serve_control(
+    socket: Option<&Path>,
+    syncers: &[Syncer],
+    reloads: Arc<Notify>,
+) -> Result<()> {
+    let pairs: Vec<ControlledPair> = syncers
+        .iter()
+        .map(|syncer| ControlledPair {
+            work_dir: syncer.work_dir().display().to_string(),
+            backup: syncer.backend().to_string(),
+            control: syncer.control().clone(),
+            metrics: syncer.metrics().clone(),
+        })
+        .collect();
 
-    // Starts any handles that are necessary
-    loop {
-        for file_info in WalkDir::new(&work_dir)
-            .follow_links(true)
-            .into_iter()
-            .filter(|file_info| match file_info {
-                Ok(file_info) => file_info.path().is_file(),
-                Err(_) => false,
-            })
-        {
-            //FIXME: unwrap
-            let file_info = file_info.unwrap();
-
-            match handles.get(file_info.path()) {
-                Some(FileSyncInfo {
-                    modify_time: _,
-                    sync_task,
-                }) => {
-                    // Respawn the sync task next loop iteration if it's crashed or finished
-                    if sync_task.is_finished() {
-                        handles.remove(file_info.path());
-                    }
+    #[cfg(unix)]
+    {
+        let signal_pairs = pairs.clone();
+        let signal_reloads = reloads.clone();
+        tokio::spawn(async move {
+            if let Err(err) = control::handle_signals(signal_pairs, signal_reloads).await {
+                error!("Error handling signals: {err:#}");
+            }
+        });
+        if let Some(socket) = socket {
+            let listener = control::bind(socket).await?;
+            tokio::spawn(async move {
+                if let Err(err) = control::serve(listener, pairs, reloads).await {
+                    error!("Error answering control commands: {err:#}");
                 }
-                None => {
-                    let metadata = fs::metadata(file_info.path()).await.unwrap();
-                    let modify_time = Arc::new(AtomicU64::new(
-                        metadata
-                            .modified()
-                            .unwrap()
-                            .duration_since(UNIX_EPOCH)
-                            .unwrap()
-                            .as_secs(),
-                    ));
+            });
+        }
+        Ok(())
+    }
+    #[cfg(not(unix))]
+    match (socket, reloads) {
+        (Some(_), _) => Err(anyhow!("Control sockets are only supported on Unix")),
+        (None, _) => Ok(()),
+    }
+}
 
-                    let modify_time_clone = modify_time.clone();
-                    let path = file_info.path().to_path_buf();
-                    let work_dir = work_dir.clone();
-                    let backup_dir = backup_dir.clone();
+/// Tags the log lines of one pair with its work_dir, when there is more than one
+fn pair_span(syncer: &Syncer, syncers: &[Syncer]) -> Span {
+    match syncers.len() {
+        1 => Span::none(),
+        _ => info_span!("pair", work_dir = %syncer.work_dir().display()),
+    }
+}
 
-                    let sync_task = tokio::task::spawn(spawn_sync_task(
-                        path,
-                        work_dir,
-                        backup_dir,
-                        modify_time_clone,
-                    ));
+/// How many of each kind of event a run produced
+#[derive(Debug, Default, Serialize)]
+struct Totals {
+    copied: usize,
+    pulled: usize,
+    removed: usize,
+    renamed: usize,
+    skipped: usize,
+    errors: usize,
+    /// How many copies failed when syncing stopped because of them
+    #[serde(skip)]
+    aborted: Option<u64>,
+}
 
-                    handles.insert(
-                        file_info.into_path(),
-                        FileSyncInfo {
-                            modify_time,
-                            sync_task,
-                        },
-                    );
+impl Totals {
+    /// Counts `event` of `syncer`, logging the ones worth logging, and prints it first with
+    /// `--output json`
+    fn record(&mut self, syncer: &Syncer, event: SyncEvent, output: OutputFormat) {
+        if output == OutputFormat::Json {
+            println!("{}", event_json(syncer, &event));
+        }
+        match event {
+            SyncEvent::Copied { .. } => self.copied += 1,
+            SyncEvent::Pulled(_) => self.pulled += 1,
+            SyncEvent::Removed(_) => self.removed += 1,
+            SyncEvent::Renamed { .. } => self.renamed += 1,
+            SyncEvent::Skipped(_) => self.skipped += 1,
+            SyncEvent::Error(error) => {
+                let (path, message) = (error.path(), error.to_string());
+                if syncer.metrics().should_log_error(path, &message) {
+                    error!(path = %path.display(), "Error syncing: {message}");
                 }
+                self.errors += 1;
+            }
+            SyncEvent::Aborted { failed } => {
+                error!("{failed} copies failed, stopping as --max-errors says");
+                self.aborted = Some(failed);
+            }
+            // Logged when it happens, along with how long copies wait
+            SyncEvent::Degraded(_) => {}
+            SyncEvent::Responding => info!("{} responds again", syncer.backend()),
+            // Logged when it happens
+            SyncEvent::Vanished(_) => {}
+            SyncEvent::Returned => info!(
+                "{} is back, syncing what changed in the meantime",
+                syncer.backend()
+            ),
+            SyncEvent::Snapshot(snapshot_dir) => {
+                info!("Took snapshot {}", snapshot_dir.display());
+            }
+            SyncEvent::Rotated(rotation_dir) => {
+                info!("Rotated the backup into {}", rotation_dir.display());
+            }
+            SyncEvent::ManifestSigned(manifest_path) => {
+                info!("Signed manifest {}", manifest_path.display());
+            }
+            SyncEvent::Conflict(path) => {
+                warn!(path = %path.display(), "Changed on both sides");
             }
+            SyncEvent::CycleComplete { .. } => {}
         }
+    }
+}
 
-        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
-            return Ok(());
+/// `event` as a line of `--output json`
+fn event_json(syncer: &Syncer, event: &SyncEvent) -> serde_json::Value {
+    let path = |path: &Path| path.display().to_string();
+    let mut json = match event {
+        SyncEvent::Copied {
+            path: copied,
+            bytes,
+            duration,
+        } => serde_json::json!({
+            "event": "copied",
+            "path": path(copied),
+            "bytes": bytes,
+            "duration_ms": duration.as_millis() as u64,
+        }),
+        SyncEvent::Pulled(pulled) => serde_json::json!({"event": "pulled", "path": path(pulled)}),
+        SyncEvent::Removed(removed) => {
+            serde_json::json!({"event": "deleted", "path": path(removed)})
+        }
+        SyncEvent::Renamed { from, to } => serde_json::json!({
+            "event": "renamed",
+            "from": path(from),
+            "path": path(to),
+        }),
+        SyncEvent::Skipped(skipped) => {
+            serde_json::json!({"event": "skipped", "path": path(skipped)})
+        }
+        SyncEvent::Conflict(conflict) => {
+            serde_json::json!({"event": "conflict", "path": path(conflict)})
+        }
+        SyncEvent::Snapshot(snapshot_dir) => {
+            serde_json::json!({"event": "snapshot", "path": path(snapshot_dir)})
+        }
+        SyncEvent::Rotated(rotation_dir) => {
+            serde_json::json!({"event": "rotated", "path": path(rotation_dir)})
         }
+        SyncEvent::ManifestSigned(manifest_path) => {
+            serde_json::json!({"event": "manifest_signed", "path": path(manifest_path)})
+        }
+        SyncEvent::Error(error) => serde_json::json!({
+            "event": "error",
+            "path": path(error.path()),
+            "message": error.to_string(),
+        }),
+        SyncEvent::Aborted { failed } => serde_json::json!({
+            "event": "aborted",
+            "failed": failed,
+        }),
+        SyncEvent::Degraded(timed_out) => serde_json::json!({
+            "event": "degraded",
+            "message": timed_out.to_string(),
+        }),
+        SyncEvent::Responding => serde_json::json!({"event": "responding"}),
+        SyncEvent::Vanished(vanished) => serde_json::json!({
+            "event": "vanished",
+            "message": vanished.to_string(),
+        }),
+        SyncEvent::Returned => serde_json::json!({"event": "returned"}),
+        SyncEvent::CycleComplete {
+            copied,
+            pulled,
+            removed,
+            errors,
+        } => serde_json::json!({
+            "event": "cycle_complete",
+            "copied": copied,
+            "pulled": pulled,
+            "removed": removed,
+            "errors": errors,
+        }),
+    };
+    json["work_dir"] = path(syncer.work_dir()).into();
+    json["time"] = chrono::Utc::now()
+        .to_rfc3339_opts(chrono::SecondsFormat::Millis, true)
+        .into();
+    json
+}
+
+/// The events of every pair's sync merged into one stream, tagged with the index of their
+/// pair. Each pair's events end with `None`, so one that stops can be told apart
+fn merge_events<'a>(
+    syncers: &'a [Syncer],
+    run: impl Fn(&Syncer) -> SyncEvents,
+) -> impl Stream<Item = (usize, Option<SyncEvent>)> + Unpin + 'a {
+    stream::select_all(syncers.iter().enumerate().map(|(index, syncer)| {
+        // The span carries over to the background task
+        let events = {
+            let _span = pair_span(syncer, syncers).entered();
+            run(syncer)
+        };
+        events
+            .map(Some)
+            .chain(stream::once(future::ready(None)))
+            .map(move |event| (index, event))
+    }))
+}
+
+/// How long a reload waits for the config file to be written completely
+const RELOAD_DELAY: Duration = Duration::from_millis(200);
+
+/// How often backup_dirs with date variables are checked for whether they moved on
+const DATED_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// Why [`run_sync`] stopped
+enum Stopped {
+    /// By Ctrl-C
+    Interrupted,
+    /// A backup_dir with date variables expands to another directory now, see
+    /// [`DatedBackupDirs`]
+    Moved,
+}
+
+/// The backup_dirs with date variables in them, which syncing moves on from once the date
+/// changes, see [`evil_mount::template`]
+struct DatedBackupDirs {
+    vars: PathVars,
+    /// Every template and the backup_dir it expanded to at startup
+    dirs: Vec<(String, String)>,
+}
+
+impl DatedBackupDirs {
+    fn new(dirs: &DirArgs, config: &Config) -> Result<Self> {
+        let profile = dirs.profile.as_deref();
+        let mappings = mappings(
+            dirs.work_dir.clone(),
+            dirs.backup_dir.clone(),
+            config,
+            profile,
+        )?;
+        Ok(Self {
+            vars: PathVars::current(profile),
+            dirs: mappings
+                .into_iter()
+                .filter_map(|mapping| {
+                    let template = mapping.template?;
+                    template::depends_on_time(&template).then_some((template, mapping.backup_dir))
+                })
+                .collect(),
+        })
+    }
 
-        tokio::time::sleep(Duration::from_secs(5)).await;
+    /// A template that expands to another backup_dir by `now`, with what that is
+    fn moved(&self, now: &chrono::DateTime<chrono::Local>) -> Option<(&str, String)> {
+        self.dirs.iter().find_map(|(template, backup_dir)| {
+            let expanded = template::expand(template, &self.vars, now).ok()?;
+            (expanded != *backup_dir).then_some((template.as_str(), expanded))
+        })
     }
 }
 
-// FIXME: return and handle errors
-async fn spawn_sync_task(
-    path: PathBuf,
-    work_dir: PathBuf,
-    backup_dir: PathBuf,
-    modify_time: Arc<AtomicU64>,
-) {
+/// Runs evil_mount again with the same arguments, in place of this process so the PID and
+/// service manager stay the same
+#[cfg(unix)]
+fn restart() -> Result<()> {
+    use std::os::unix::process::CommandExt;
+
+    let exe = std::env::current_exe().context("Error finding the evil_mount executable")?;
+    let err = std::process::Command::new(&exe)
+        .args(std::env::args_os().skip(1))
+        .exec();
+    Err(err).with_context(|| anyhow!("Error starting {} again", exe.display()))
+}
+
+#[cfg(not(unix))]
+fn restart() -> Result<()> {
+    Err(anyhow!(
+        "backup_dir moved to a new directory, start evil_mount again to sync into it"
+    ))
+    .exit_with(Exit::SyncDied)
+}
+
+/// Copies changes into backup_dir until Ctrl-C is pressed or a pair stops because too many
+/// copies failed, then copies whatever changed since the last scan before returning. A
+/// second Ctrl-C stops without waiting. The settings are reloaded from `reload_args`
+/// whenever `reloads` asks for it. Stops the same way once one of the `dated` backup_dirs
+/// moves on to another directory
+async fn run_sync(
+    syncers: &[Syncer],
+    output: OutputFormat,
+    reloads: &Notify,
+    reload_args: &(DirArgs, InitArgs, SyncArgs),
+    dated: &DatedBackupDirs,
+) -> Result<Stopped> {
+    let mut totals: Vec<Totals> = syncers.iter().map(|_| Totals::default()).collect();
+    let mut events = merge_events(syncers, Syncer::run);
+    let mut summaries = tokio::time::interval_at(
+        tokio::time::Instant::now() + metrics::ERROR_LOG_INTERVAL,
+        metrics::ERROR_LOG_INTERVAL,
+    );
+    let mut dated_checks = tokio::time::interval(DATED_CHECK_INTERVAL);
+    let mut stopped = Stopped::Interrupted;
+    // Why syncing stopped before Ctrl-C was pressed
+    let mut died = None;
     loop {
-        match fs::metadata(path.clone()).await {
-            Ok(metadata) => {
-                //FIXME: unwrap
-                let current_modify_time = metadata
-                    .modified()
-                    .unwrap()
-                    .duration_since(UNIX_EPOCH)
-                    .unwrap()
-                    .as_secs();
-
-                if current_modify_time > modify_time.load(Ordering::Relaxed) {
-                    modify_time.store(current_modify_time, Ordering::Relaxed);
-
-                    if let Err(err) =
-                        copy_to_dst(path.clone(), work_dir.clone(), backup_dir.clone()).await
-                    {
-                        if let Ok(err) = err.downcast::<io::Error>() {
-                            if err.kind() == io::ErrorKind::NotFound {
-                                return;
-                            } else {
-                                Err(err)
-                                    .with_context(|| anyhow!("Error syncing file"))
-                                    .unwrap()
-                            }
-                        }
-                    }
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                break;
+            }
+            _ = dated_checks.tick(), if !dated.dirs.is_empty() => {
+                if let Some((template, backup_dir)) = dated.moved(&chrono::Local::now()) {
+                    info!("{template} is {backup_dir} from now on, starting over to sync into it");
+                    stopped = Stopped::Moved;
+                    break;
                 }
             }
-            Err(err) => {
-                match err.kind() {
-                    io::ErrorKind::NotFound => {
-                        if let Err(err) =
-                            copy_to_dst(path.clone(), work_dir.clone(), backup_dir.clone()).await
-                        {
-                            match err.downcast_ref::<io::Error>() {
-                                Some(err) => {
-                                    // Ignore file not found errors
-                                    if err.kind() != io::ErrorKind::NotFound {
-                                        Err(anyhow!(
-                                            "Error initializing file in {} due to io::Error: {err}",
-                                            backup_dir.display()
-                                        ))
-                                        .unwrap()
-                                    }
-                                }
-                                None => Err(anyhow!(
-                                    "Error initializing file in {}: {err}",
-                                    backup_dir.display()
-                                ))
-                                .unwrap(),
-                            }
-                        }
+            _ = reloads.notified() => {
+                // Editors save in several steps, the last one is what counts
+                tokio::time::sleep(RELOAD_DELAY).await;
+                if let Err(err) = reload(reload_args, syncers) {
+                    error!("Error reloading the settings, keeping the current ones: {err:#}");
+                }
+            }
+            _ = summaries.tick() => {
+                for syncer in syncers {
+                    let suppressed = syncer.metrics().take_suppressed_errors();
+                    if suppressed > 0 {
+                        let _span = pair_span(syncer, syncers).entered();
+                        warn!(
+                            "{suppressed} repeated errors suppressed in the last {}s, `evil_mount status` lists every path with errors",
+                            metrics::ERROR_LOG_INTERVAL.as_secs()
+                        );
                     }
-                    _ => todo!(),
                 }
             }
+            event = events.next() => match event {
+                Some((index, Some(event))) => {
+                    let _span = pair_span(&syncers[index], syncers).entered();
+                    totals[index].record(&syncers[index], event, output);
+                }
+                // The others still get to finish what they're doing
+                Some((index, None)) if totals[index].aborted.is_some() => {
+                    died = Some(anyhow!(
+                        "Syncing {} stopped after {} copies failed",
+                        syncers[index].work_dir().display(),
+                        totals[index].aborted.unwrap_or_default()
+                    ));
+                    break;
+                }
+                Some((index, None)) => {
+                    return Err(anyhow!(
+                        "Syncing {} stopped unexpectedly",
+                        syncers[index].work_dir().display()
+                    ))
+                    .exit_with(Exit::SyncDied)
+                }
+                None => return Err(anyhow!("Syncing stopped unexpectedly")).exit_with(Exit::SyncDied),
+            },
+        }
+    }
+
+    info!("Shutting down, press Ctrl-C again to stop immediately...");
+    for syncer in syncers {
+        syncer.shutdown();
+    }
+    loop {
+        tokio::select! {
+            result = tokio::signal::ctrl_c() => {
+                result?;
+                return Err(anyhow!("Stopped before the last changes were synced"))
+                    .exit_with(Exit::Interrupted);
+            }
+            event = events.next() => match event {
+                Some((index, Some(event))) => {
+                    let _span = pair_span(&syncers[index], syncers).entered();
+                    totals[index].record(&syncers[index], event, output);
+                }
+                Some((_, None)) => {}
+                None => break,
+            },
+        }
+    }
+
+    for (syncer, totals) in syncers.iter().zip(&totals) {
+        let Totals {
+            copied,
+            pulled,
+            removed,
+            renamed,
+            skipped,
+            errors,
+            aborted: _,
+        } = totals;
+        let done = match syncers.len() {
+            1 => "Done!".to_string(),
+            _ => format!(
+                "Done with {} -> {}!",
+                syncer.work_dir().display(),
+                syncer.backend()
+            ),
         };
+        let metrics = syncer.metrics();
+        info!(
+            "{done} Synced {copied} files, pulled {pulled}, removed {removed}, renamed {renamed}, \
+             skipped {skipped}, {errors} errors. Scanned {} files in {:.2}s, {} unreadable, \
+             and copied {} in {:.2}s",
+            metrics.files_scanned(),
+            metrics.scan_time().as_secs_f64(),
+            metrics.walk_errors(),
+            HumanBytes(metrics.bytes_copied()),
+            metrics.copy_time().as_secs_f64(),
+        );
+        for (path, error) in metrics.permission_denied_files() {
+            warn!(path = %path.display(), "Skipped, permission denied: {error}");
+        }
+    }
 
-        if SHOULD_SHUTDOWN.load(Ordering::Relaxed) {
-            return;
+    match died {
+        Some(error) => Err(error).exit_with(Exit::SyncDied),
+        None => Ok(stopped),
+    }
+}
+
+/// Syncs once, then prints what happened as a single line of JSON, with the totals of every
+/// pair under `pairs`. Fails if anything couldn't be synced, so scripts can tell. With
+/// `--output json` the events come first, and the summary is the `summary` event
+async fn run_once(syncers: &[Syncer], output: OutputFormat) -> Result<()> {
+    let start = Instant::now();
+    let mut totals: Vec<Totals> = syncers.iter().map(|_| Totals::default()).collect();
+    let mut events = merge_events(syncers, Syncer::run_once);
+    while let Some((index, event)) = events.next().await {
+        if let Some(event) = event {
+            let _span = pair_span(&syncers[index], syncers).entered();
+            totals[index].record(&syncers[index], event, output);
         }
+    }
+
+    let sum = totals.iter().fold(Totals::default(), |sum, totals| Totals {
+        copied: sum.copied + totals.copied,
+        pulled: sum.pulled + totals.pulled,
+        removed: sum.removed + totals.removed,
+        renamed: sum.renamed + totals.renamed,
+        skipped: sum.skipped + totals.skipped,
+        errors: sum.errors + totals.errors,
+        aborted: sum.aborted.or(totals.aborted),
+    });
+    let mut summary = serde_json::to_value(&sum)?;
+    if output == OutputFormat::Json {
+        summary["event"] = "summary".into();
+    }
+    summary["seconds"] = start.elapsed().as_secs_f64().into();
+    summary["pairs"] = syncers
+        .iter()
+        .zip(&totals)
+        .map(|(syncer, totals)| {
+            let mut pair = serde_json::to_value(totals)?;
+            let metrics = syncer.metrics();
+            pair["files_scanned"] = metrics.files_scanned().into();
+            pair["walk_errors"] = metrics.walk_errors().into();
+            pair["bytes_copied"] = metrics.bytes_copied().into();
+            pair["scan_ms"] = (metrics.scan_time().as_millis() as u64).into();
+            pair["copy_ms"] = (metrics.copy_time().as_millis() as u64).into();
+            pair["work_dir"] = syncer.work_dir().display().to_string().into();
+            pair["backup_dir"] = syncer.backend().to_string().into();
+            Ok(pair)
+        })
+        .collect::<Result<Vec<_>>>()?
+        .into();
+    println!("{summary}");
 
-        tokio::time::sleep(Duration::from_secs(3)).await;
+    if let Some(failed) = sum.aborted {
+        return Err(anyhow!("Syncing stopped after {failed} copies failed"))
+            .exit_with(Exit::SyncDied);
+    }
+    match sum.errors {
+        0 => Ok(()),
+        errors => Err(anyhow!("{errors} files couldn't be synced")),
     }
 }
 
-async fn copy_to_dst(path: PathBuf, work_dir: PathBuf, backup_dir: PathBuf) -> Result<()> {
-    let new_path = path.strip_prefix(&work_dir).with_context(|| {
-        anyhow!(
-            "Error stripping prefix {} from {}",
-            work_dir.display(),
-            path.display()
+/// What `evil_mount diff` found for one pair
+#[derive(Debug, Serialize)]
+struct PairDiff {
+    work_dir: String,
+    backup_dir: String,
+    /// Files only in work_dir
+    added: Vec<DiffEntry>,
+    modified: Vec<DiffEntry>,
+    /// Files only in backup_dir
+    deleted: Vec<DiffEntry>,
+}
+
+#[derive(Debug, Serialize)]
+struct DiffEntry {
+    path: PathBuf,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    work_size: Option<u64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    backup_size: Option<u64>,
+}
+
+impl PairDiff {
+    async fn new(syncer: &Syncer, compare_by: CompareBy) -> Result<Self> {
+        let diff = syncer.compare_by(compare_by).await?;
+        let backup = syncer.backend().list().await?;
+        Ok(Self::with_sizes(
+            syncer,
+            diff,
+            |path| {
+                std::fs::metadata(syncer.work_dir().join(path))
+                    .ok()
+                    .map(|metadata| metadata.len())
+            },
+            |path| backup.get(path).map(|metadata| metadata.size),
+        ))
+    }
+
+    /// What the index of the sync that is running says, instead of scanning both trees
+    fn from_index(syncer: &Syncer, index: &ScanIndex) -> Self {
+        Self::with_sizes(
+            syncer,
+            index.diff(&syncer.options().ignore, true),
+            |path| index.work.get(path).map(|metadata| metadata.size),
+            |path| index.backup.get(path).map(|metadata| metadata.size),
         )
-    })?;
-    let mut dst_path = backup_dir.clone();
-    dst_path.push(new_path);
+    }
 
-    let backup_dir = {
-        let mut dst_path = dst_path.clone();
-        dst_path.pop();
-        dst_path
-    };
+    fn with_sizes(
+        syncer: &Syncer,
+        diff: TreeDiff,
+        work_size: impl Fn(&Path) -> Option<u64>,
+        backup_size: impl Fn(&Path) -> Option<u64>,
+    ) -> Self {
+        let entry = |path: PathBuf, work: bool, backup_side: bool| DiffEntry {
+            work_size: work.then(|| work_size(&path)).flatten(),
+            backup_size: backup_side.then(|| backup_size(&path)).flatten(),
+            path,
+        };
+        Self {
+            work_dir: syncer.work_dir().display().to_string(),
+            backup_dir: syncer.backend().to_string(),
+            added: diff
+                .only_in_work
+                .into_iter()
+                .map(|path| entry(path, true, false))
+                .collect(),
+            modified: diff
+                .different
+                .into_iter()
+                .map(|path| entry(path, true, true))
+                .collect(),
+            deleted: diff
+                .only_in_backup
+                .into_iter()
+                .map(|path| entry(path, false, true))
+                .collect(),
+        }
+    }
 
-    fs::create_dir_all(&backup_dir).await?;
+    /// Prints one line per file, ordered by path, and with `stat` their sizes and totals
+    fn print(&self, stat: bool) {
+        let size = |size: Option<u64>| HumanBytes(size.unwrap_or(0));
+        let mut lines: Vec<(&Path, char, String)> = Vec::new();
+        for entry in &self.added {
+            lines.push((&entry.path, 'A', format!(" ({})", size(entry.work_size))));
+        }
+        for entry in &self.modified {
+            let sizes = format!(
+                " ({} -> {})",
+                size(entry.backup_size),
+                size(entry.work_size)
+            );
+            lines.push((&entry.path, 'M', sizes));
+        }
+        for entry in &self.deleted {
+            lines.push((&entry.path, 'D', format!(" ({})", size(entry.backup_size))));
+        }
+        lines.sort_by_key(|(path, ..)| *path);
+        for (path, status, sizes) in lines {
+            match stat {
+                true => println!("{status} {}{sizes}", path.display()),
+                false => println!("{status} {}", path.display()),
+            }
+        }
 
-    // Becuase of potential write errors when trying to overwrite a write protected file, we simply remove it before copying to it
-    if let Err(err) = fs::remove_file(&dst_path).await {
-        // We can ignore not found errors, that just means there won't be any conflict
-        if err.kind() != io::ErrorKind::NotFound {
-            return Err(anyhow!("error removing file {}: {err}", dst_path.display()));
+        if stat {
+            let total = |entries: &[DiffEntry], size: fn(&DiffEntry) -> Option<u64>| {
+                HumanBytes(entries.iter().filter_map(size).sum())
+            };
+            println!(
+                "{} added ({}), {} modified ({}), {} deleted ({})",
+                self.added.len(),
+                total(&self.added, |entry| entry.work_size),
+                self.modified.len(),
+                total(&self.modified, |entry| entry.work_size),
+                self.deleted.len(),
+                total(&self.deleted, |entry| entry.backup_size),
+            );
         }
     }
+}
 
-    fs::copy(&path, &dst_path).await.with_context(|| {
-        anyhow!(
-            "Error copying from {} to {}",
+/// Prints the differences like `diff` would, with `-` for files missing from the backup, `+`
+/// for files only in the backup and `!` for files whose contents differ
+/// Writes `bytes` to stdout. Stops quietly if it's closed early, like by `| head`
+fn print_bytes(bytes: &[u8]) -> Result<()> {
+    let mut stdout = std::io::stdout().lock();
+    match stdout.write_all(bytes).and_then(|()| stdout.flush()) {
+        Err(err) if err.kind() != std::io::ErrorKind::BrokenPipe => {
+            Err(err).context("Error writing to stdout")
+        }
+        _ => Ok(()),
+    }
+}
+
+fn print_verification(syncer: &Syncer, verification: &Verification) {
+    println!("--- {}", syncer.work_dir().display());
+    println!("+++ {}", syncer.backend());
+    for path in &verification.diff.only_in_work {
+        println!("-{}", path.display());
+    }
+    for path in &verification.diff.only_in_backup {
+        println!("+{}", path.display());
+    }
+    for path in &verification.diff.different {
+        let (Some(work), Some(backup)) =
+            (verification.work.get(path), verification.backup.get(path))
+        else {
+            continue;
+        };
+        println!(
+            "!{} ({} bytes, blake3 {} vs {} bytes, blake3 {})",
             path.display(),
-            dst_path.display()
+            work.size,
+            &work.hash.to_hex()[..16],
+            backup.size,
+            &backup.hash.to_hex()[..16]
+        );
+    }
+}
+
+/// Prints the records of the audit log of the backup of `syncer` that `query` matches
+fn print_audit_log(syncer: &Syncer, query: &AuditQuery, json: bool) -> Result<()> {
+    let backup_dir = syncer.backend().local_dir().ok_or_else(|| {
+        anyhow!(
+            "{} isn't a local directory, which audit logs are only kept in",
+            syncer.backend()
         )
     })?;
-
+    let records = read_audit_log(backup_dir)?;
+    if records.is_empty() {
+        eprintln!(
+            "{} has no audit log, sync with --audit-log to keep one",
+            backup_dir.display()
+        );
+        return Ok(());
+    }
+    for record in records.iter().filter(|record| query.matches(record)) {
+        if json {
+            println!("{}", serde_json::to_string(record)?);
+            continue;
+        }
+        let mut line = format!("{} {:<7} ", record.at, record.op.to_string());
+        if let Some(from) = &record.from {
+            line += &format!("{} -> ", from.display());
+        }
+        line += &record.path.display().to_string();
+        if let Some(size) = record.size {
+            line += &format!(", {}", HumanBytes(size));
+        }
+        if let Some(hash) = &record.hash {
+            line += &format!(", BLAKE3 {}", &hash[..hash.len().min(16)]);
+        }
+        if let Some(error) = &record.error {
+            line += &format!(", failed: {error}");
+        }
+        println!("{line}");
+    }
     Ok(())
 }
 
-pub fn hash_directory(dir: PathBuf) -> Result<Hash> {
-    if !dir.exists() {
-        return Err(anyhow!(
-            "Directory {} does not exist for hashing",
-            dir.display()
-        ));
-    }
+async fn print_status(syncer: &Syncer, rescan: bool) -> Result<()> {
+    println!("Work dir:   {}", syncer.work_dir().display());
+    println!("Backup dir: {}", syncer.backend());
 
-    if !dir.is_dir() {
-        return Err(anyhow!("Path {} is not a direectory!", dir.display()));
+    let index = match rescan {
+        true => None,
+        false => syncer.live_index()?,
+    };
+    if let Some(index) = index {
+        let ignore = &syncer.options().ignore;
+        match index.last_synced(ignore) {
+            Some(modified) => println!("Last synced: {}", format_age(modified)),
+            None => println!("Last synced: never"),
+        }
+        // Status compares contents when it scans, which copies without their times match
+        let diff = index.diff(ignore, syncer.options().copy.preserve.times);
+        match diff.is_empty() {
+            true => println!("Status: up to date"),
+            false => println!("Status: {} files out of sync", diff.len()),
+        }
+        println!(
+            "Index: from the running sync, updated {}. Pass --rescan to scan instead",
+            format_age(index.updated())
+        );
+        return Ok(());
     }
 
-    let hasher: Arc<Mutex<Hasher>> = Arc::new(Mutex::new(Hasher::new()));
-
-    let mut file_paths: Vec<_> = WalkDir::new(&dir)
-        .follow_links(true)
+    // Copies get a fresh mtime, so the newest file in the backup is the last thing synced
+    let last_synced = syncer
+        .backend()
+        .list()
+        .await?
         .into_iter()
-        .filter(|file_info| match file_info {
-            Ok(file_info) => file_info.path().is_file(),
-            Err(_) => false,
-        })
-        .filter_map(|file_info| file_info.ok())
-        .collect();
+        .filter(|(relative_path, _)| !syncer.options().ignore.is_ignored(relative_path, false))
+        .filter_map(|(_, metadata)| metadata.modified)
+        .max();
+    match last_synced {
+        Some(modified) => println!("Last synced: {}", format_age(modified)),
+        None => println!("Last synced: never"),
+    }
 
-    file_paths.sort_by(|file_info, file_info2| {
-        file_info
-            .path()
-            .to_string_lossy()
-            .to_lowercase()
-            .cmp(&file_info2.path().to_string_lossy().to_lowercase())
-    });
+    let diff = syncer.compare().await?;
+    match diff.is_empty() {
+        true => println!("Status: up to date"),
+        false => println!("Status: {} files out of sync", diff.len()),
+    }
 
-    for file_info in file_paths.into_iter() {
-        let hasher = hasher.clone();
+    Ok(())
+}
 
-        let mut file = std::fs::File::open(file_info.path())?;
-        std::io::copy(&mut file, &mut *hasher.lock().unwrap())?;
+/// Prints what a running sync reports about each of its pairs
+fn print_live_status(statuses: &[PairStatus]) {
+    for (index, status) in statuses.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        println!("Work dir:    {}", status.work_dir);
+        println!("Backup dir:  {}", status.backup);
+        match (status.paused, status.missing, status.degraded) {
+            (true, _, _) => println!("Status:      paused"),
+            (false, true, _) => println!("Status:      waiting for the backup dir to come back"),
+            (false, false, true) => {
+                println!("Status:      syncing, copies into the backup time out")
+            }
+            (false, false, false) => println!("Status:      syncing"),
+        }
+        match status.last_sync {
+            Some(secs) => println!("Last synced: {}", format_age(epoch(secs))),
+            None => println!("Last synced: never"),
+        }
+        match status.sync_lag_ms {
+            Some(lag) => println!(
+                "Pending:     {} files, the oldest waiting for {:.1}s",
+                status.queue_depth,
+                lag as f64 / 1000.0
+            ),
+            None => println!("Pending:     {} files", status.queue_depth),
+        }
+        println!(
+            "Synced:      {} files, removed {}, {} copied",
+            status.files_synced,
+            status.files_removed,
+            HumanBytes(status.bytes_copied)
+        );
+        println!(
+            "Scanned:     {} files in {:.2}s, copying took {:.2}s",
+            status.files_scanned,
+            status.scan_ms as f64 / 1000.0,
+            status.copy_ms as f64 / 1000.0
+        );
+        if let Some(cycle) = &status.last_cycle {
+            println!(
+                "Last cycle:  scanned {} files in {:.2}s, copied {} files ({}) in {:.2}s, \
+                 removed {}, {} errors",
+                cycle.files_scanned,
+                cycle.scan_ms as f64 / 1000.0,
+                cycle.files_copied,
+                HumanBytes(cycle.bytes_copied),
+                cycle.copy_ms as f64 / 1000.0,
+                cycle.files_removed,
+                cycle.errors
+            );
+        }
+        let running = SystemTime::now()
+            .duration_since(epoch(status.started))
+            .unwrap_or_default()
+            .as_secs_f64()
+            .max(1.0);
+        println!(
+            "Throughput:  {}/s on average since it started {}",
+            HumanBytes((status.bytes_copied as f64 / running) as u64),
+            format_age(epoch(status.started))
+        );
+        println!("Errors:      {}", status.copy_errors);
+        if status.walk_errors > 0 {
+            println!(
+                "Unreadable:  {} times something in work_dir couldn't be read",
+                status.walk_errors
+            );
+        }
+        for error in &status.recent_errors {
+            println!("  {} {}", format_age(epoch(error.at)), error.message);
+        }
+        if !status.failing.is_empty() {
+            println!(
+                "Failing:     {} files, even after retrying",
+                status.failing.len()
+            );
+            for failing in &status.failing {
+                println!("  {}: {}", failing.path, failing.error);
+            }
+        }
+        if !status.permission_denied.is_empty() {
+            println!(
+                "Skipped:     {} files, permission denied",
+                status.permission_denied.len()
+            );
+            for denied in &status.permission_denied {
+                println!("  {}: {}", denied.path, denied.error);
+            }
+        }
+        if !status.erroring.is_empty() {
+            println!("Erroring:    {} paths", status.erroring.len());
+            for erroring in &status.erroring {
+                println!(
+                    "  {}: {} errors, the first {}, the last {}: {}",
+                    erroring.path,
+                    erroring.count,
+                    format_age(epoch(erroring.first)),
+                    format_age(epoch(erroring.last)),
+                    erroring.error
+                );
+            }
+        }
     }
+}
 
-    let hasher = &hasher.lock().unwrap();
-    Ok(hasher.finalize())
+fn epoch(secs: u64) -> SystemTime {
+    SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+}
+
+/// Formats how long ago `time` was, e.g. `3m 12s ago`
+fn format_age(time: SystemTime) -> String {
+    let secs = SystemTime::now()
+        .duration_since(time)
+        .unwrap_or_default()
+        .as_secs();
+    match secs {
+        0..=59 => format!("{secs}s ago"),
+        60..=3599 => format!("{}m {}s ago", secs / 60, secs % 60),
+        _ => format!("{}h {}m ago", secs / 3600, (secs % 3600) / 60),
+    }
 }
@@ -0,0 +1,110 @@
+//! Initializing by reconciling work_dir with the backup instead of making work_dir match it,
+//! see [`InitMode::Merge`]
+
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+use crate::backend::FileMetadata;
+
+/// How initialization brings work_dir in line with the backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum InitMode {
+    /// The backup is the source of truth: files only in work_dir are removed and files
+    /// that differ are replaced, see [`SyncOptions::no_clear`](crate::SyncOptions::no_clear)
+    #[default]
+    #[serde(rename = "clear")]
+    Clear,
+    /// Nothing is lost on either side: files only in work_dir are copied into the backup,
+    /// files only in the backup into work_dir, and files in both that differ are settled
+    /// by a [`MergePolicy`]
+    #[serde(rename = "merge")]
+    Merge,
+}
+
+impl FromStr for InitMode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "clear" => Ok(Self::Clear),
+            "merge" => Ok(Self::Merge),
+            _ => Err(format!("unknown init mode {s}, expected clear or merge")),
+        }
+    }
+}
+
+impl fmt::Display for InitMode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Clear => "clear",
+            Self::Merge => "merge",
+        })
+    }
+}
+
+/// Which copy of a file that differs between work_dir and the backup a merge keeps
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum MergePolicy {
+    /// Keep the copy in the backup
+    #[serde(rename = "backup-wins")]
+    BackupWins,
+    /// Keep the copy in work_dir
+    #[serde(rename = "work-wins")]
+    WorkWins,
+    /// Keep whichever copy was modified last, the backup's when that can't be told
+    #[default]
+    #[serde(rename = "newer-wins")]
+    NewerWins,
+    /// Ask about every file
+    #[serde(rename = "prompt")]
+    Prompt,
+}
+
+impl FromStr for MergePolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "backup-wins" => Ok(Self::BackupWins),
+            "work-wins" => Ok(Self::WorkWins),
+            "newer-wins" => Ok(Self::NewerWins),
+            "prompt" => Ok(Self::Prompt),
+            _ => Err(format!(
+                "unknown merge policy {s}, expected backup-wins, work-wins, newer-wins or prompt"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for MergePolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::BackupWins => "backup-wins",
+            Self::WorkWins => "work-wins",
+            Self::NewerWins => "newer-wins",
+            Self::Prompt => "prompt",
+        })
+    }
+}
+
+/// The copy of a file a merge keeps
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Keep {
+    Work,
+    Backup,
+}
+
+impl MergePolicy {
+    /// Which copy to keep of a file that differs, or `None` if the user has to be asked
+    pub(crate) fn keep(self, work: &FileMetadata, backup: &FileMetadata) -> Option<Keep> {
+        match self {
+            Self::BackupWins => Some(Keep::Backup),
+            Self::WorkWins => Some(Keep::Work),
+            Self::NewerWins => match (work.modified, backup.modified) {
+                (Some(work), Some(backup)) if work > backup => Some(Keep::Work),
+                _ => Some(Keep::Backup),
+            },
+            Self::Prompt => None,
+        }
+    }
+}
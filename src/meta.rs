@@ -0,0 +1,31 @@
+use std::path::{Path, PathBuf};
+
+/// The directory inside backup_dir where evil_mount keeps its own bookkeeping. It is
+/// never synced in either direction
+pub const METADATA_DIR_NAME: &str = ".evilmount";
+
+/// Copies are written to a file with this suffix next to their destination, then renamed
+/// into place. Files with this suffix are never synced
+pub const TEMP_SUFFIX: &str = ".evilmount.tmp";
+
+/// Where the bookkeeping for `backup_dir` lives
+pub fn metadata_dir(backup_dir: &Path) -> PathBuf {
+    backup_dir.join(METADATA_DIR_NAME)
+}
+
+/// The `b3sum` style manifest that `evil_mount verify` writes into the metadata directory of
+/// the backup, relative to the root of the backup
+pub fn manifest_path() -> PathBuf {
+    Path::new(METADATA_DIR_NAME).join("MANIFEST.b3")
+}
+
+/// Where change detection keeps the hashes of the files in work_dir, for a local backup
+pub fn hash_cache_path(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("hash-cache.json")
+}
+
+/// Where change detection keeps what every file looked like when it was last synced, for a
+/// local backup
+pub fn tracking_path(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("state.json")
+}
@@ -0,0 +1,610 @@
+//! Counters for how syncing is going, served in the Prometheus text format so stalled or
+//! failing syncs can be alerted on
+
+use anyhow::Result;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::{BTreeMap, VecDeque},
+    fmt::Write,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    io::{AsyncReadExt, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tracing::{debug, info};
+
+use crate::filter::WalkErrors;
+
+/// Requests are only read up to this many bytes, the path is all that matters
+const MAX_REQUEST_LEN: usize = 8 * 1024;
+
+/// How many of the latest errors are kept for `evil_mount status`
+const RECENT_ERRORS: usize = 10;
+
+/// The same error about the same path is logged at most this often, see
+/// [`Metrics::should_log_error`]
+pub const ERROR_LOG_INTERVAL: Duration = Duration::from_secs(60);
+
+/// What one sync cycle did, from the scan to the last copy
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct CycleStats {
+    /// Files looked at by the scans of work_dir, and of the backup when syncing both ways
+    pub files_scanned: u64,
+    /// Files copied into the backup or pulled out of it
+    pub files_copied: u64,
+    pub files_removed: u64,
+    pub bytes_copied: u64,
+    pub errors: u64,
+    /// Milliseconds spent scanning
+    pub scan_ms: u64,
+    /// Milliseconds spent copying, adding up copies that ran at the same time
+    pub copy_ms: u64,
+    /// Entries that scans couldn't read, and left out
+    #[serde(default)]
+    pub walk_errors: u64,
+}
+
+/// A path that syncing keeps failing on
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ErroringPath {
+    pub path: PathBuf,
+    /// How many errors there were about it
+    pub count: u64,
+    /// The last one
+    pub error: String,
+    pub first: SystemTime,
+    pub last: SystemTime,
+}
+
+/// The errors about one path, and when the last one was logged
+#[derive(Debug)]
+struct Erroring {
+    path: ErroringPath,
+    logged: Option<(Instant, String)>,
+}
+
+/// What one [`Syncer`](crate::Syncer) has done since it was created
+#[derive(Debug)]
+pub struct Metrics {
+    started: SystemTime,
+    files_scanned: AtomicU64,
+    files_synced: AtomicU64,
+    files_removed: AtomicU64,
+    bytes_copied: AtomicU64,
+    copy_errors: AtomicU64,
+    /// In milliseconds, over every scan and copy
+    scan_time: AtomicU64,
+    copy_time: AtomicU64,
+    /// Seconds since the epoch, 0 until the first cycle without errors
+    last_sync: AtomicU64,
+    /// Milliseconds since the epoch, 0 until the first cycle
+    last_cycle_end: AtomicU64,
+    /// Since when a sync task is running, if one is
+    running_since: Mutex<Option<SystemTime>>,
+    /// In milliseconds
+    scan_duration: AtomicU64,
+    /// Files waiting to settle, waiting for a copy slot, or being copied
+    queue_depth: AtomicU64,
+    /// When each of them was queued, with how many were queued at that instant
+    queued: Mutex<BTreeMap<Instant, u64>>,
+    /// The newest last
+    recent_errors: Mutex<VecDeque<(SystemTime, String)>>,
+    /// Files that still couldn't be copied after every retry, with the last error
+    failing: Mutex<BTreeMap<PathBuf, String>>,
+    /// Files that are skipped since they can't be read, with the error reading them
+    permission_denied: Mutex<BTreeMap<PathBuf, String>>,
+    /// Every path with errors since it was last synced
+    erroring: Mutex<BTreeMap<PathBuf, Erroring>>,
+    /// Repeated errors that weren't logged since the last [`Metrics::take_suppressed_errors`]
+    suppressed_errors: AtomicU64,
+    /// What the cycle that is running has done so far
+    cycle: Mutex<CycleStats>,
+    last_cycle: Mutex<Option<CycleStats>>,
+    walk_errors: WalkErrors,
+    /// How many walk errors there were when the last cycle ended
+    walk_errors_before: AtomicU64,
+}
+
+impl Default for Metrics {
+    fn default() -> Self {
+        Self {
+            started: SystemTime::now(),
+            files_scanned: AtomicU64::new(0),
+            files_synced: AtomicU64::new(0),
+            files_removed: AtomicU64::new(0),
+            bytes_copied: AtomicU64::new(0),
+            copy_errors: AtomicU64::new(0),
+            scan_time: AtomicU64::new(0),
+            copy_time: AtomicU64::new(0),
+            last_sync: AtomicU64::new(0),
+            last_cycle_end: AtomicU64::new(0),
+            running_since: Mutex::default(),
+            scan_duration: AtomicU64::new(0),
+            queue_depth: AtomicU64::new(0),
+            queued: Mutex::default(),
+            recent_errors: Mutex::new(VecDeque::with_capacity(RECENT_ERRORS)),
+            failing: Mutex::new(BTreeMap::new()),
+            permission_denied: Mutex::new(BTreeMap::new()),
+            erroring: Mutex::new(BTreeMap::new()),
+            suppressed_errors: AtomicU64::new(0),
+            cycle: Mutex::default(),
+            last_cycle: Mutex::default(),
+            walk_errors: WalkErrors::default(),
+            walk_errors_before: AtomicU64::new(0),
+        }
+    }
+}
+
+impl Metrics {
+    /// Metrics that include what `walk_errors` counts
+    pub(crate) fn counting(walk_errors: WalkErrors) -> Self {
+        Self {
+            walk_errors,
+            ..Self::default()
+        }
+    }
+
+    pub(crate) fn file_synced(&self) {
+        self.files_synced.fetch_add(1, Ordering::Relaxed);
+        self.cycle.lock().unwrap().files_copied += 1;
+    }
+
+    pub(crate) fn add_bytes_copied(&self, bytes: u64) {
+        self.bytes_copied.fetch_add(bytes, Ordering::Relaxed);
+        self.cycle.lock().unwrap().bytes_copied += bytes;
+    }
+
+    /// Records that copying a file took `duration`
+    pub(crate) fn copied(&self, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        self.copy_time.fetch_add(millis, Ordering::Relaxed);
+        self.cycle.lock().unwrap().copy_ms += millis;
+    }
+
+    pub(crate) fn file_removed(&self) {
+        self.files_removed.fetch_add(1, Ordering::Relaxed);
+        self.cycle.lock().unwrap().files_removed += 1;
+    }
+
+    /// Records an error about `path`. Repeats of an error that is already among the latest
+    /// ones only move it to the end
+    pub(crate) fn copy_error(&self, path: &Path, message: String) {
+        let now = SystemTime::now();
+        self.copy_errors.fetch_add(1, Ordering::Relaxed);
+        self.cycle.lock().unwrap().errors += 1;
+
+        let mut erroring = self.erroring.lock().unwrap();
+        let entry = erroring
+            .entry(path.to_path_buf())
+            .or_insert_with(|| Erroring {
+                path: ErroringPath {
+                    path: path.to_path_buf(),
+                    count: 0,
+                    error: String::new(),
+                    first: now,
+                    last: now,
+                },
+                logged: None,
+            });
+        entry.path.count += 1;
+        entry.path.error = message.clone();
+        entry.path.last = now;
+        drop(erroring);
+
+        let message = format!("{}: {message}", path.display());
+        let mut recent_errors = self.recent_errors.lock().unwrap();
+        recent_errors.retain(|(_, recent)| *recent != message);
+        if recent_errors.len() == RECENT_ERRORS {
+            recent_errors.pop_front();
+        }
+        recent_errors.push_back((now, message));
+    }
+
+    /// Whether the error `message` about `path` is worth logging: the first time, when it
+    /// says something else than the one logged last, and otherwise once every
+    /// [`ERROR_LOG_INTERVAL`]. The others are counted for [`Metrics::take_suppressed_errors`]
+    pub fn should_log_error(&self, path: &Path, message: &str) -> bool {
+        let mut erroring = self.erroring.lock().unwrap();
+        let Some(entry) = erroring.get_mut(path) else {
+            return true;
+        };
+        let repeated = entry
+            .logged
+            .as_ref()
+            .is_some_and(|(logged, last)| last == message && logged.elapsed() < ERROR_LOG_INTERVAL);
+        match repeated {
+            true => {
+                self.suppressed_errors.fetch_add(1, Ordering::Relaxed);
+                false
+            }
+            false => {
+                entry.logged = Some((Instant::now(), message.to_string()));
+                true
+            }
+        }
+    }
+
+    /// How many errors [`Metrics::should_log_error`] held back since this was last called
+    pub fn take_suppressed_errors(&self) -> u64 {
+        self.suppressed_errors.swap(0, Ordering::Relaxed)
+    }
+
+    /// Records that `path` gave up after failing every retry
+    pub(crate) fn failing(&self, path: &Path, message: String) {
+        self.failing
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), message);
+    }
+
+    /// Records that reading `path` was denied, so it's skipped until its metadata changes
+    pub(crate) fn permission_denied(&self, path: &Path, message: String) {
+        self.permission_denied
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), message);
+    }
+
+    /// Records that `path` was copied or removed, so it isn't failing any more
+    pub(crate) fn stopped_failing(&self, path: &Path) {
+        self.failing.lock().unwrap().remove(path);
+        self.permission_denied.lock().unwrap().remove(path);
+        self.erroring.lock().unwrap().remove(path);
+    }
+
+    /// Records that a cycle just finished without errors
+    pub(crate) fn synced(&self) {
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_sync.store(now.as_secs(), Ordering::Relaxed);
+    }
+
+    /// Records that a scan looked at `files` files in `duration`
+    pub(crate) fn scanned(&self, files: usize, duration: Duration) {
+        let millis = duration.as_millis() as u64;
+        self.scan_duration.store(millis, Ordering::Relaxed);
+        self.scan_time.fetch_add(millis, Ordering::Relaxed);
+        self.files_scanned
+            .fetch_add(files as u64, Ordering::Relaxed);
+        let mut cycle = self.cycle.lock().unwrap();
+        cycle.files_scanned += files as u64;
+        cycle.scan_ms += millis;
+    }
+
+    /// Records that a cycle just finished, and returns what it did
+    pub(crate) fn end_cycle(&self) -> CycleStats {
+        let mut cycle = std::mem::take(&mut *self.cycle.lock().unwrap());
+        let walk_errors = self.walk_errors.count();
+        cycle.walk_errors =
+            walk_errors - self.walk_errors_before.swap(walk_errors, Ordering::Relaxed);
+        *self.last_cycle.lock().unwrap() = Some(cycle);
+        let now = SystemTime::now()
+            .duration_since(UNIX_EPOCH)
+            .unwrap_or_default();
+        self.last_cycle_end
+            .store(now.as_millis() as u64, Ordering::Relaxed);
+        cycle
+    }
+
+    /// Records whether the sync task is running
+    pub(crate) fn set_running(&self, running: bool) {
+        *self.running_since.lock().unwrap() = running.then(SystemTime::now);
+    }
+
+    /// Records a file that waits to be copied. Returns when, which
+    /// [`Metrics::dequeue`] takes once it doesn't anymore
+    pub(crate) fn enqueue(&self) -> Instant {
+        let now = Instant::now();
+        self.queue_depth.fetch_add(1, Ordering::Relaxed);
+        *self.queued.lock().unwrap().entry(now).or_default() += 1;
+        now
+    }
+
+    pub(crate) fn dequeue(&self, queued: Instant) {
+        self.queue_depth.fetch_sub(1, Ordering::Relaxed);
+        let mut waiting = self.queued.lock().unwrap();
+        if let Some(count) = waiting.get_mut(&queued) {
+            *count -= 1;
+            if *count == 0 {
+                waiting.remove(&queued);
+            }
+        }
+    }
+
+    /// When the [`Syncer`](crate::Syncer) was created
+    pub fn started(&self) -> SystemTime {
+        self.started
+    }
+
+    pub fn files_scanned(&self) -> u64 {
+        self.files_scanned.load(Ordering::Relaxed)
+    }
+
+    pub fn files_synced(&self) -> u64 {
+        self.files_synced.load(Ordering::Relaxed)
+    }
+
+    pub fn files_removed(&self) -> u64 {
+        self.files_removed.load(Ordering::Relaxed)
+    }
+
+    pub fn bytes_copied(&self) -> u64 {
+        self.bytes_copied.load(Ordering::Relaxed)
+    }
+
+    pub fn copy_errors(&self) -> u64 {
+        self.copy_errors.load(Ordering::Relaxed)
+    }
+
+    /// How many entries walks of work_dir couldn't read
+    pub fn walk_errors(&self) -> u64 {
+        self.walk_errors.count()
+    }
+
+    pub fn queue_depth(&self) -> u64 {
+        self.queue_depth.load(Ordering::Relaxed)
+    }
+
+    /// How far the backup is behind: how long the file that has waited longest to be copied
+    /// has been waiting, `None` while nothing is
+    pub fn sync_lag(&self) -> Option<Duration> {
+        let queued = self.queued.lock().unwrap();
+        queued.keys().next().map(Instant::elapsed)
+    }
+
+    /// How long every scan took together
+    pub fn scan_time(&self) -> Duration {
+        Duration::from_millis(self.scan_time.load(Ordering::Relaxed))
+    }
+
+    /// How long every copy took together, adding up copies that ran at the same time
+    pub fn copy_time(&self) -> Duration {
+        Duration::from_millis(self.copy_time.load(Ordering::Relaxed))
+    }
+
+    /// What the last cycle that finished did
+    pub fn last_cycle(&self) -> Option<CycleStats> {
+        *self.last_cycle.lock().unwrap()
+    }
+
+    /// When the last cycle finished, with or without errors
+    pub fn last_cycle_end(&self) -> Option<SystemTime> {
+        match self.last_cycle_end.load(Ordering::Relaxed) {
+            0 => None,
+            millis => Some(UNIX_EPOCH + Duration::from_millis(millis)),
+        }
+    }
+
+    /// Since when the sync task is running, `None` while it isn't
+    pub fn running_since(&self) -> Option<SystemTime> {
+        *self.running_since.lock().unwrap()
+    }
+
+    /// When the last cycle without errors finished
+    pub fn last_sync(&self) -> Option<SystemTime> {
+        match self.last_sync.load(Ordering::Relaxed) {
+            0 => None,
+            secs => Some(UNIX_EPOCH + Duration::from_secs(secs)),
+        }
+    }
+
+    /// The latest errors and when they happened, oldest first
+    pub fn recent_errors(&self) -> Vec<(SystemTime, String)> {
+        self.recent_errors.lock().unwrap().iter().cloned().collect()
+    }
+
+    /// The files that couldn't be copied even after retrying, with their last error
+    pub fn failing_files(&self) -> Vec<(PathBuf, String)> {
+        let failing = self.failing.lock().unwrap();
+        failing
+            .iter()
+            .map(|(path, message)| (path.clone(), message.clone()))
+            .collect()
+    }
+
+    /// The files that are skipped since reading them was denied, with the error reading them
+    pub fn permission_denied_files(&self) -> Vec<(PathBuf, String)> {
+        let permission_denied = self.permission_denied.lock().unwrap();
+        permission_denied
+            .iter()
+            .map(|(path, message)| (path.clone(), message.clone()))
+            .collect()
+    }
+
+    /// Every path with errors since it was last synced, and how many it had
+    pub fn erroring_paths(&self) -> Vec<ErroringPath> {
+        let erroring = self.erroring.lock().unwrap();
+        erroring.values().map(|entry| entry.path.clone()).collect()
+    }
+
+    /// Every metric as its name, type, help text and current value
+    fn samples(&self) -> [(&'static str, &'static str, &'static str, f64); 13] {
+        [
+            (
+                "evilmount_files_scanned_total",
+                "counter",
+                "Files looked at by scans",
+                self.files_scanned.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_files_synced_total",
+                "counter",
+                "Files copied into the backup or pulled out of it",
+                self.files_synced.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_files_removed_total",
+                "counter",
+                "Files removed because they were deleted on the other side",
+                self.files_removed.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_bytes_copied_total",
+                "counter",
+                "Bytes copied into the backup or pulled out of it",
+                self.bytes_copied.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_copy_errors_total",
+                "counter",
+                "Files that couldn't be synced, and other errors while syncing",
+                self.copy_errors.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_walk_errors_total",
+                "counter",
+                "Entries that scans of work_dir couldn't read, and left out",
+                self.walk_errors.count() as f64,
+            ),
+            (
+                "evilmount_last_sync_timestamp_seconds",
+                "gauge",
+                "When the last sync cycle without errors finished, 0 if none has",
+                self.last_sync.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_scan_duration_seconds",
+                "gauge",
+                "How long the last scan of work_dir took",
+                self.scan_duration.load(Ordering::Relaxed) as f64 / 1000.0,
+            ),
+            (
+                "evilmount_copy_duration_seconds_total",
+                "counter",
+                "Time spent copying files, adding up copies that ran at the same time",
+                self.copy_time.load(Ordering::Relaxed) as f64 / 1000.0,
+            ),
+            (
+                "evilmount_queue_depth",
+                "gauge",
+                "Files waiting to be copied or being copied",
+                self.queue_depth.load(Ordering::Relaxed) as f64,
+            ),
+            (
+                "evilmount_sync_lag_seconds",
+                "gauge",
+                "How long the file that has waited longest to be copied has been waiting, 0 if \
+                 none is",
+                self.sync_lag().unwrap_or_default().as_secs_f64(),
+            ),
+            (
+                "evilmount_failing_files",
+                "gauge",
+                "Files that still couldn't be copied after every retry",
+                self.failing.lock().unwrap().len() as f64,
+            ),
+            (
+                "evilmount_permission_denied_files",
+                "gauge",
+                "Files that are skipped since they can't be read",
+                self.permission_denied.lock().unwrap().len() as f64,
+            ),
+        ]
+    }
+}
+
+/// Renders the metrics of every pair in the Prometheus text format, labelled with their
+/// work_dir
+pub fn render(metrics: &[(String, Arc<Metrics>)]) -> String {
+    let samples: Vec<_> = metrics
+        .iter()
+        .map(|(work_dir, metrics)| (escape_label(work_dir), metrics.samples()))
+        .collect();
+
+    let mut out = String::new();
+    let Some((_, first)) = samples.first() else {
+        return out;
+    };
+    // Prometheus wants the samples of a metric together, after its HELP and TYPE lines
+    for (index, (name, kind, help, _)) in first.iter().enumerate() {
+        let _ = writeln!(out, "# HELP {name} {help}");
+        let _ = writeln!(out, "# TYPE {name} {kind}");
+        for (work_dir, samples) in &samples {
+            let _ = writeln!(
+                out,
+                "{name}{{work_dir=\"{work_dir}\"}} {}",
+                samples[index].3
+            );
+        }
+    }
+    out
+}
+
+/// Answers `GET /metrics` on `listener` with the metrics of every pair until the task is
+/// dropped
+pub async fn serve(listener: TcpListener, metrics: Vec<(String, Arc<Metrics>)>) -> Result<()> {
+    info!(
+        "Serving metrics on http://{}/metrics",
+        listener.local_addr()?
+    );
+    let metrics = Arc::new(metrics);
+    loop {
+        let (stream, peer) = listener.accept().await?;
+        let metrics = metrics.clone();
+        tokio::spawn(async move {
+            if let Err(err) = respond(stream, &metrics).await {
+                debug!(%peer, "Error answering a metrics request: {err}");
+            }
+        });
+    }
+}
+
+async fn respond(mut stream: TcpStream, metrics: &[(String, Arc<Metrics>)]) -> Result<()> {
+    let (method, path) = read_request(&mut stream).await?;
+    let (status, content_type, body) = match (method.as_str(), path.as_str()) {
+        ("GET", "/metrics") => ("200 OK", "text/plain; version=0.0.4", render(metrics)),
+        _ => ("404 Not Found", "text/plain", "Not found\n".to_string()),
+    };
+    write_response(&mut stream, status, content_type, &body).await
+}
+
+/// Reads an HTTP request far enough to return its method and path
+pub(crate) async fn read_request(stream: &mut TcpStream) -> Result<(String, String)> {
+    let mut request = Vec::new();
+    let mut buf = [0; 1024];
+    while !request.windows(4).any(|window| window == b"\r\n\r\n") && request.len() < MAX_REQUEST_LEN
+    {
+        let read = stream.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        request.extend_from_slice(&buf[..read]);
+    }
+
+    let request = String::from_utf8_lossy(&request);
+    let mut request_line = request.lines().next().unwrap_or_default().split(' ');
+    let method = request_line.next().unwrap_or_default().to_string();
+    let path = request_line.next().unwrap_or_default().to_string();
+    Ok((method, path))
+}
+
+/// Answers with `status` and `body`, then closes the connection
+pub(crate) async fn write_response(
+    stream: &mut TcpStream,
+    status: &str,
+    content_type: &str,
+    body: &str,
+) -> Result<()> {
+    let response = format!(
+        "HTTP/1.1 {status}\r\nContent-Type: {content_type}\r\nContent-Length: {}\r\nConnection: close\r\n\r\n{body}",
+        body.len()
+    );
+    stream.write_all(response.as_bytes()).await?;
+    stream.shutdown().await?;
+    Ok(())
+}
+
+/// Escapes a label value as the text format requires
+fn escape_label(value: &str) -> String {
+    value
+        .replace('\\', "\\\\")
+        .replace('"', "\\\"")
+        .replace('\n', "\\n")
+}
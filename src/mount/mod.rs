@@ -0,0 +1,359 @@
+//! Mounting work_dir as a FUSE filesystem over the backup, or over a staging directory that
+//! mirrors it. Every change made through the mount is applied to the directory underneath
+//! and replicated to the backup as it happens, so nothing has to watch or poll work_dir.
+//!
+//! The filesystem is served with [`fuser`], behind the `fuse` feature. Mounting needs root
+//! or `fusermount3` from the fuse3 package, which is what unprivileged users get
+
+mod passthrough;
+
+use anyhow::{anyhow, Context, Result};
+use fuser::{MountOption, Session, SessionUnmounter};
+use nix::{errno::Errno, mount::MntFlags};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::{Arc, Mutex},
+    thread::{self, JoinHandle},
+};
+use tokio::{runtime::Handle, sync::mpsc, task};
+use tracing::{debug, error, info, warn};
+
+use crate::{
+    backend::Backend,
+    filter::{walk_dirs_in, walk_files_in, IgnoreSet, Symlinks},
+    Syncer,
+};
+use passthrough::Passthrough;
+
+/// How many requests are answered at once
+const THREADS: usize = 4;
+
+/// When changes made through the mount reach the backup
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Replicate {
+    /// Before `close`, `fsync` or the call that made the change returns, which fails if
+    /// the backup couldn't be updated
+    #[default]
+    #[serde(rename = "sync")]
+    Sync,
+    /// In the background, in the order they were made. Failures are only logged
+    #[serde(rename = "async")]
+    Async,
+}
+
+impl FromStr for Replicate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "sync" => Ok(Self::Sync),
+            "async" => Ok(Self::Async),
+            _ => Err(format!(
+                "unknown replication mode {s}, expected sync or async"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Replicate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Sync => "sync",
+            Self::Async => "async",
+        })
+    }
+}
+
+/// A change made through the mount, by paths relative to its root
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Change {
+    /// A file was created or written to, or its metadata changed
+    Write(PathBuf),
+    Remove(PathBuf),
+    CreateDir(PathBuf),
+    RemoveDir(PathBuf),
+    Rename {
+        from: PathBuf,
+        to: PathBuf,
+    },
+}
+
+/// Applies the changes made through the mount to the backup
+#[derive(Debug)]
+pub struct Replicator {
+    root: PathBuf,
+    backend: Arc<dyn Backend>,
+    ignore: IgnoreSet,
+    symlinks: Symlinks,
+    replicate: Replicate,
+    runtime: Handle,
+    /// Where [`Replicate::Async`] queues changes, closed when the mount is gone
+    queue: Mutex<Option<mpsc::UnboundedSender<Change>>>,
+    worker: Mutex<Option<task::JoinHandle<()>>>,
+}
+
+impl Replicator {
+    /// Replicates what changes in the work_dir of `syncer`, the staging directory, to its
+    /// backup. Has to be called from within the runtime
+    pub fn new(syncer: &Syncer, replicate: Replicate) -> Arc<Self> {
+        let replicator = Arc::new(Self {
+            root: syncer.work_dir().clone(),
+            backend: syncer.backend().clone(),
+            ignore: syncer.options().ignore.clone(),
+            symlinks: syncer.options().copy.symlinks,
+            replicate,
+            runtime: Handle::current(),
+            queue: Mutex::default(),
+            worker: Mutex::default(),
+        });
+        if replicate == Replicate::Async {
+            let (sender, mut receiver) = mpsc::unbounded_channel();
+            let worker = replicator.clone();
+            *replicator.worker.lock().unwrap() = Some(tokio::spawn(async move {
+                while let Some(change) = receiver.recv().await {
+                    let _ = worker.apply_logged(change).await;
+                }
+            }));
+            *replicator.queue.lock().unwrap() = Some(sender);
+        }
+        replicator
+    }
+
+    /// Replicates `change`, or queues it. An error was logged already
+    fn replicate(&self, change: Change) -> Result<(), ()> {
+        match self.replicate {
+            Replicate::Sync => self.runtime.block_on(self.apply_logged(change)),
+            Replicate::Async => match &*self.queue.lock().unwrap() {
+                Some(queue) => queue.send(change).map_err(|_| ()),
+                None => Err(()),
+            },
+        }
+    }
+
+    /// Waits for the queued changes to reach the backup
+    async fn finish(&self) {
+        self.queue.lock().unwrap().take();
+        let worker = self.worker.lock().unwrap().take();
+        if let Some(worker) = worker {
+            let _ = worker.await;
+        }
+    }
+
+    async fn apply_logged(&self, change: Change) -> Result<(), ()> {
+        match self.apply(&change).await {
+            Ok(()) => {
+                debug!("Replicated {change:?}");
+                Ok(())
+            }
+            Err(err) => {
+                error!("Error replicating {change:?} to {}: {err:#}", self.backend);
+                Err(())
+            }
+        }
+    }
+
+    fn is_ignored(&self, relative_path: &Path) -> bool {
+        let is_dir = self.root.join(relative_path).is_dir();
+        self.ignore.is_ignored(relative_path, is_dir)
+    }
+
+    async fn apply(&self, change: &Change) -> Result<()> {
+        match change {
+            Change::Write(relative_path) if !self.is_ignored(relative_path) => {
+                self.put(relative_path).await?
+            }
+            Change::CreateDir(relative_path) if !self.is_ignored(relative_path) => {
+                self.backend.create_dir(relative_path).await?
+            }
+            // Nothing that's ignored is in the backup, so removing it does no harm
+            Change::Remove(relative_path) => self.backend.delete(relative_path).await?,
+            Change::RemoveDir(relative_path) => self.backend.delete_dir(relative_path).await?,
+            Change::Rename { from, to } => match (self.is_ignored(from), self.is_ignored(to)) {
+                (_, true) => self.delete_all(from).await?,
+                (true, false) => self.put_all(to).await?,
+                (false, false) => {
+                    if !self.backend.rename(from, to).await? {
+                        self.delete_all(from).await?;
+                        self.put_all(to).await?;
+                    }
+                }
+            },
+            Change::Write(_) | Change::CreateDir(_) => return Ok(()),
+        }
+        self.backend.flush().await
+    }
+
+    async fn put(&self, relative_path: &Path) -> Result<()> {
+        let path = self.root.join(relative_path);
+        match path.symlink_metadata() {
+            Ok(metadata) if metadata.is_dir() => self.backend.create_dir(relative_path).await,
+            Ok(_) => self.backend.put(relative_path, &path).await,
+            // Removed since, which is replicated on its own
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err).with_context(|| anyhow!("Error reading {}", path.display())),
+        }
+    }
+
+    /// Stores `relative_path` and everything in it that isn't ignored
+    async fn put_all(&self, relative_path: &Path) -> Result<()> {
+        let (root, start) = (self.root.clone(), self.root.join(relative_path));
+        if !start.is_dir() {
+            return self.put(relative_path).await;
+        }
+        let (ignore, symlinks) = (self.ignore.clone(), self.symlinks);
+        let (dirs, files) = task::spawn_blocking(move || {
+            let relative =
+                |entry: walkdir::DirEntry| entry.path().strip_prefix(&root).map(Path::to_path_buf);
+            let dirs: Result<Vec<_>, _> = walk_dirs_in(&root, &start, &ignore, symlinks)
+                .map(relative)
+                .collect();
+            let files: Result<Vec<_>, _> = walk_files_in(&root, &start, &ignore, symlinks)
+                .map(relative)
+                .collect();
+            dirs.and_then(|dirs| Ok((dirs, files?)))
+        })
+        .await??;
+        self.backend.create_dir(relative_path).await?;
+        for dir in dirs {
+            self.backend.create_dir(&dir).await?;
+        }
+        for file in files {
+            self.put(&file).await?;
+        }
+        Ok(())
+    }
+
+    /// Removes `relative_path` from the backup, with everything in it if it's a directory
+    async fn delete_all(&self, relative_path: &Path) -> Result<()> {
+        self.backend.delete(relative_path).await?;
+        let inside: Vec<_> = self
+            .backend
+            .list()
+            .await?
+            .into_keys()
+            .filter(|path| path.starts_with(relative_path))
+            .collect();
+        for path in &inside {
+            self.backend.delete(path).await?;
+        }
+        // The deepest directories first, so every one of them is empty by its turn
+        if let Some(dirs) = self.backend.list_dirs().await? {
+            for dir in dirs
+                .iter()
+                .rev()
+                .filter(|dir| dir.starts_with(relative_path))
+            {
+                self.backend.delete_dir(dir).await?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A mounted filesystem, unmounted with [`Mount::unmount`]
+#[derive(Debug)]
+pub struct Mount {
+    mountpoint: PathBuf,
+    unmounter: SessionUnmounter,
+    /// Answers requests until the filesystem is unmounted
+    session: JoinHandle<io::Result<()>>,
+    replicator: Option<Arc<Replicator>>,
+}
+
+impl Mount {
+    /// Mounts a filesystem at `mountpoint` that passes everything through to `root`, along
+    /// with replicating what changes to wherever `replicator` goes. Without one `root` has
+    /// to be the backup itself
+    pub fn new(
+        mountpoint: &Path,
+        root: &Path,
+        replicator: Option<Arc<Replicator>>,
+    ) -> Result<Self> {
+        let mountpoint = mountpoint
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", mountpoint.display()))?;
+        let root = root
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", root.display()))?;
+        if !mountpoint.is_dir() {
+            return Err(anyhow!("{} must be a directory!", mountpoint.display()));
+        }
+        // Every request would end up going through the mount again
+        if root.starts_with(&mountpoint) || mountpoint.starts_with(&root) {
+            return Err(anyhow!(
+                "The mount at {} can't be inside {} or the other way around",
+                mountpoint.display(),
+                root.display()
+            ));
+        }
+
+        let mut config = fuser::Config::default();
+        config.mount_options = vec![
+            MountOption::FSName("evil_mount".to_string()),
+            MountOption::Subtype("evil_mount".to_string()),
+            MountOption::DefaultPermissions,
+            MountOption::NoSuid,
+            MountOption::NoDev,
+        ];
+        config.n_threads = Some(THREADS);
+        let filesystem = Passthrough::new(root.clone(), replicator.clone());
+        let mut session = Session::new(filesystem, &mountpoint, &config).with_context(|| {
+            anyhow!(
+                "Error mounting at {}, which needs root or fusermount3 from fuse3",
+                mountpoint.display()
+            )
+        })?;
+        let unmounter = session.unmount_callable();
+        let session = thread::Builder::new()
+            .name("fuse".to_string())
+            .spawn(move || session.run())
+            .context("Error starting a thread for the mount")?;
+        info!("Mounted {} at {}", root.display(), mountpoint.display());
+        Ok(Self {
+            mountpoint,
+            unmounter,
+            session,
+            replicator,
+        })
+    }
+
+    pub fn mountpoint(&self) -> &Path {
+        &self.mountpoint
+    }
+
+    /// Unmounts the filesystem, then waits for what's left to replicate. Files that are
+    /// still open keep it going until they're closed
+    pub async fn unmount(mut self) -> Result<()> {
+        let mountpoint = self.mountpoint.clone();
+        // Without root, fuser has fusermount3 unmount lazily
+        match self.unmounter.unmount() {
+            Ok(()) => {}
+            Err(err) if err.raw_os_error() == Some(Errno::EBUSY as i32) => {
+                warn!(
+                    "{} is still in use, it's unmounted once nothing uses it anymore",
+                    mountpoint.display()
+                );
+                nix::mount::umount2(&mountpoint, MntFlags::MNT_DETACH)
+                    .with_context(|| anyhow!("Error unmounting {}", mountpoint.display()))?;
+            }
+            Err(err) => {
+                return Err(err)
+                    .with_context(|| anyhow!("Error unmounting {}", mountpoint.display()))
+            }
+        }
+        let session = self.session;
+        match task::spawn_blocking(move || session.join()).await? {
+            Ok(Ok(())) => {}
+            Ok(Err(err)) => error!("Error serving the mount at {}: {err}", mountpoint.display()),
+            Err(_) => error!("Serving the mount at {} panicked", mountpoint.display()),
+        }
+        if let Some(replicator) = self.replicator {
+            replicator.finish().await;
+        }
+        info!("Unmounted {}", mountpoint.display());
+        Ok(())
+    }
+}
@@ -0,0 +1,916 @@
+//! Answers FUSE requests by doing the same to the files in the mounted directory, and
+//! hands every change that has to reach the backup to the [`Replicator`]
+
+use fuser::{
+    Errno, FileAttr, FileHandle, FileType, Filesystem, FopenFlags, Generation, INodeNo, OpenFlags,
+    RenameFlags, ReplyAttr, ReplyCreate, ReplyData, ReplyDirectory, ReplyEmpty, ReplyEntry,
+    ReplyOpen, ReplyStatfs, ReplyWrite, ReplyXattr, Request, TimeOrNow, WriteFlags,
+};
+use nix::{
+    fcntl::{self, OFlag},
+    libc,
+    sys::{stat::Mode, statvfs::statvfs},
+};
+use std::{
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs::{self, File, Metadata},
+    io,
+    os::{
+        fd::{FromRawFd, RawFd},
+        unix::{
+            ffi::OsStrExt,
+            fs::{DirBuilderExt, DirEntryExt, FileExt, MetadataExt, PermissionsExt},
+        },
+    },
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use super::{Change, Replicator};
+use crate::meta::METADATA_DIR_NAME;
+
+/// How long the kernel may cache names and attributes. Kept short, since files can still
+/// change under the mount
+const TTL: Duration = Duration::from_secs(1);
+
+fn errno(err: nix::Error) -> Errno {
+    Errno::from_i32(err as i32)
+}
+
+/// A [`File`] owning what [`fcntl::open`] opened
+fn owned(fd: RawFd) -> File {
+    // SAFETY: the descriptor was just opened and nothing else owns it
+    unsafe { File::from_raw_fd(fd) }
+}
+
+/// A file the kernel looked up, by the path it has now
+#[derive(Debug)]
+struct Inode {
+    path: PathBuf,
+    lookups: u64,
+}
+
+/// The node IDs handed to the kernel and the paths they stand for, relative to the root
+#[derive(Debug, Default)]
+struct Inodes {
+    by_ino: HashMap<u64, Inode>,
+    by_path: HashMap<PathBuf, u64>,
+    next: u64,
+}
+
+impl Inodes {
+    fn path(&self, ino: u64) -> Option<PathBuf> {
+        match INodeNo(ino) {
+            INodeNo::ROOT => Some(PathBuf::new()),
+            _ => self.by_ino.get(&ino).map(|inode| inode.path.clone()),
+        }
+    }
+
+    /// The path of `ino`, unless it was removed since
+    fn linked_path(&self, ino: u64) -> Option<PathBuf> {
+        self.path(ino)
+            .filter(|path| INodeNo(ino) == INodeNo::ROOT || self.by_path.get(path) == Some(&ino))
+    }
+
+    /// The node ID of `path`, counting one more lookup of it
+    fn look_up(&mut self, path: &Path) -> u64 {
+        if let Some(&ino) = self.by_path.get(path) {
+            self.by_ino.get_mut(&ino).unwrap().lookups += 1;
+            return ino;
+        }
+        self.next = self.next.max(INodeNo::ROOT.0) + 1;
+        let ino = self.next;
+        self.by_ino.insert(
+            ino,
+            Inode {
+                path: path.to_path_buf(),
+                lookups: 1,
+            },
+        );
+        self.by_path.insert(path.to_path_buf(), ino);
+        ino
+    }
+
+    fn forget(&mut self, ino: u64, lookups: u64) {
+        let Some(inode) = self.by_ino.get_mut(&ino) else {
+            return;
+        };
+        inode.lookups = inode.lookups.saturating_sub(lookups);
+        if inode.lookups == 0 {
+            let inode = self.by_ino.remove(&ino).unwrap();
+            if self.by_path.get(&inode.path) == Some(&ino) {
+                self.by_path.remove(&inode.path);
+            }
+        }
+    }
+
+    /// `path` is gone. Its node ID keeps working for whoever still has it open
+    fn unlink(&mut self, path: &Path) {
+        self.by_path.remove(path);
+    }
+
+    /// Moves `from` and everything in it to `to`
+    fn rename(&mut self, from: &Path, to: &Path) {
+        self.unlink(to);
+        let moved: Vec<_> = self
+            .by_path
+            .keys()
+            .filter(|path| path.starts_with(from))
+            .cloned()
+            .collect();
+        for path in moved {
+            let ino = self.by_path.remove(&path).unwrap();
+            // Joining an empty path would leave a trailing slash on `to` itself
+            let new_path = match path.strip_prefix(from).unwrap() {
+                inside if inside.as_os_str().is_empty() => to.to_path_buf(),
+                inside => to.join(inside),
+            };
+            self.by_ino.get_mut(&ino).unwrap().path = new_path.clone();
+            self.by_path.insert(new_path, ino);
+        }
+    }
+}
+
+#[derive(Debug)]
+enum Handle {
+    File {
+        file: File,
+        ino: u64,
+        /// Changed since the backup last got it
+        dirty: AtomicBool,
+    },
+    /// The entries of a directory as it was when it was opened: their inode numbers,
+    /// types and names
+    Dir(Vec<(u64, FileType, OsString)>),
+}
+
+/// A filesystem passing everything through to `root`
+#[derive(Debug)]
+pub struct Passthrough {
+    root: PathBuf,
+    inodes: Mutex<Inodes>,
+    handles: Mutex<HashMap<u64, Arc<Handle>>>,
+    next_fh: AtomicU64,
+    /// `None` when `root` is the backup itself, which then already has every change
+    replicator: Option<Arc<Replicator>>,
+}
+
+impl Passthrough {
+    pub fn new(root: PathBuf, replicator: Option<Arc<Replicator>>) -> Self {
+        Self {
+            root,
+            inodes: Mutex::default(),
+            handles: Mutex::default(),
+            next_fh: AtomicU64::new(1),
+            replicator,
+        }
+    }
+
+    fn path(&self, ino: INodeNo) -> Result<PathBuf, Errno> {
+        self.inodes.lock().unwrap().path(ino.0).ok_or(Errno::ESTALE)
+    }
+
+    /// The path of `name` in the directory `parent`, relative to the root
+    fn child(&self, parent: INodeNo, name: &OsStr) -> Result<PathBuf, Errno> {
+        let parent = self.path(parent)?;
+        // What syncing keeps in the backup isn't for editing
+        if parent.as_os_str().is_empty() && name == METADATA_DIR_NAME {
+            return Err(Errno::EACCES);
+        }
+        Ok(parent.join(name))
+    }
+
+    fn metadata(&self, relative_path: &Path) -> Result<Metadata, Errno> {
+        Ok(fs::symlink_metadata(self.root.join(relative_path))?)
+    }
+
+    /// Looks up `relative_path`, returning what the kernel is told about it
+    fn entry(&self, relative_path: &Path) -> Result<FileAttr, Errno> {
+        let metadata = self.metadata(relative_path)?;
+        let ino = self.inodes.lock().unwrap().look_up(relative_path);
+        Ok(attr(ino, &metadata))
+    }
+
+    fn replicate(&self, change: Change) -> Result<(), Errno> {
+        match &self.replicator {
+            Some(replicator) => replicator.replicate(change).map_err(|()| Errno::EIO),
+            None => Ok(()),
+        }
+    }
+
+    fn getattr(&self, ino: INodeNo) -> Result<FileAttr, Errno> {
+        let metadata = self.metadata(&self.path(ino)?)?;
+        Ok(attr(ino.0, &metadata))
+    }
+
+    fn setattr(
+        &self,
+        ino: INodeNo,
+        mode: Option<u32>,
+        owner: (Option<u32>, Option<u32>),
+        size: Option<u64>,
+        times: (Option<TimeOrNow>, Option<TimeOrNow>),
+        fh: Option<FileHandle>,
+    ) -> Result<FileAttr, Errno> {
+        let relative_path = self.path(ino)?;
+        let path = self.root.join(&relative_path);
+        if let Some(mode) = mode {
+            fs::set_permissions(&path, fs::Permissions::from_mode(mode & 0o7777))?;
+        }
+        if owner != (None, None) {
+            std::os::unix::fs::lchown(&path, owner.0, owner.1)?;
+        }
+        if let Some(size) = size {
+            let handle = fh.and_then(|fh| self.handles.lock().unwrap().get(&fh.0).cloned());
+            match handle.as_deref() {
+                Some(Handle::File { file, .. }) => file.set_len(size)?,
+                _ => fs::OpenOptions::new()
+                    .write(true)
+                    .open(&path)
+                    .and_then(|file| file.set_len(size))?,
+            }
+        }
+        if times != (None, None) {
+            let metadata = self.metadata(&relative_path)?;
+            let time = |time| match time {
+                TimeOrNow::Now => filetime::FileTime::now(),
+                TimeOrNow::SpecificTime(time) => filetime::FileTime::from_system_time(time),
+            };
+            let atime = times.0.map_or_else(
+                || filetime::FileTime::from_last_access_time(&metadata),
+                time,
+            );
+            let mtime = times.1.map_or_else(
+                || filetime::FileTime::from_last_modification_time(&metadata),
+                time,
+            );
+            filetime::set_symlink_file_times(&path, atime, mtime)?;
+        }
+
+        if !self.metadata(&relative_path)?.is_dir() {
+            // Files that are open get replicated when they're flushed instead
+            let open: Vec<_> = self
+                .handles
+                .lock()
+                .unwrap()
+                .values()
+                .filter(
+                    |handle| matches!(***handle, Handle::File { ino: open, .. } if open == ino.0),
+                )
+                .cloned()
+                .collect();
+            for handle in &open {
+                if let Handle::File { dirty, .. } = &**handle {
+                    dirty.store(true, Ordering::Relaxed);
+                }
+            }
+            if open.is_empty() {
+                self.replicate(Change::Write(relative_path))?;
+            }
+        }
+        self.getattr(ino)
+    }
+
+    fn symlink(&self, parent: INodeNo, name: &OsStr, target: &Path) -> Result<FileAttr, Errno> {
+        let path = self.child(parent, name)?;
+        std::os::unix::fs::symlink(target, self.root.join(&path))?;
+        self.replicate(Change::Write(path.clone()))?;
+        self.entry(&path)
+    }
+
+    fn mknod(&self, parent: INodeNo, name: &OsStr, mode: u32) -> Result<FileAttr, Errno> {
+        // Devices, pipes and sockets don't go into backups
+        if mode & libc::S_IFMT != libc::S_IFREG {
+            return Err(Errno::EPERM);
+        }
+        let path = self.child(parent, name)?;
+        fcntl::open(
+            &self.root.join(&path),
+            OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_WRONLY | OFlag::O_CLOEXEC,
+            Mode::from_bits_truncate(mode),
+        )
+        .map(owned)
+        .map_err(errno)?;
+        self.replicate(Change::Write(path.clone()))?;
+        self.entry(&path)
+    }
+
+    fn mkdir(&self, parent: INodeNo, name: &OsStr, mode: u32) -> Result<FileAttr, Errno> {
+        let path = self.child(parent, name)?;
+        fs::DirBuilder::new()
+            .mode(mode & 0o7777)
+            .create(self.root.join(&path))?;
+        self.replicate(Change::CreateDir(path.clone()))?;
+        self.entry(&path)
+    }
+
+    fn unlink(&self, parent: INodeNo, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child(parent, name)?;
+        fs::remove_file(self.root.join(&path))?;
+        self.inodes.lock().unwrap().unlink(&path);
+        self.replicate(Change::Remove(path))
+    }
+
+    fn rmdir(&self, parent: INodeNo, name: &OsStr) -> Result<(), Errno> {
+        let path = self.child(parent, name)?;
+        fs::remove_dir(self.root.join(&path))?;
+        self.inodes.lock().unwrap().unlink(&path);
+        self.replicate(Change::RemoveDir(path))
+    }
+
+    fn rename(
+        &self,
+        (parent, name): (INodeNo, &OsStr),
+        (new_parent, new_name): (INodeNo, &OsStr),
+        flags: RenameFlags,
+    ) -> Result<(), Errno> {
+        let from = self.child(parent, name)?;
+        let to = self.child(new_parent, new_name)?;
+        let full_to = self.root.join(&to);
+        if flags == RenameFlags::RENAME_NOREPLACE {
+            if full_to.symlink_metadata().is_ok() {
+                return Err(Errno::EEXIST);
+            }
+        } else if !flags.is_empty() {
+            // Exchanging two files isn't something the backup can do in one go
+            return Err(Errno::EINVAL);
+        }
+        fs::rename(self.root.join(&from), &full_to)?;
+        self.inodes.lock().unwrap().rename(&from, &to);
+        self.replicate(Change::Rename { from, to })
+    }
+
+    fn add_handle(&self, handle: Handle) -> FileHandle {
+        let fh = self.next_fh.fetch_add(1, Ordering::Relaxed);
+        self.handles.lock().unwrap().insert(fh, Arc::new(handle));
+        FileHandle(fh)
+    }
+
+    fn handle_of(&self, fh: FileHandle) -> Result<Arc<Handle>, Errno> {
+        let handles = self.handles.lock().unwrap();
+        handles.get(&fh.0).cloned().ok_or(Errno::EBADF)
+    }
+
+    fn open(&self, ino: INodeNo, flags: OpenFlags) -> Result<FileHandle, Errno> {
+        let path = self.root.join(self.path(ino)?);
+        let flags = OFlag::from_bits_truncate(flags.0)
+            & !(OFlag::O_CREAT | OFlag::O_EXCL | OFlag::O_NOCTTY)
+            | OFlag::O_CLOEXEC;
+        let file = fcntl::open(&path, flags, Mode::empty())
+            .map(owned)
+            .map_err(errno)?;
+        Ok(self.add_handle(Handle::File {
+            file,
+            ino: ino.0,
+            dirty: AtomicBool::new(flags.contains(OFlag::O_TRUNC)),
+        }))
+    }
+
+    fn create(
+        &self,
+        parent: INodeNo,
+        name: &OsStr,
+        flags: i32,
+        mode: u32,
+    ) -> Result<(FileAttr, FileHandle), Errno> {
+        let path = self.child(parent, name)?;
+        let flags =
+            OFlag::from_bits_truncate(flags) & !OFlag::O_NOCTTY | OFlag::O_CREAT | OFlag::O_CLOEXEC;
+        let file = fcntl::open(
+            &self.root.join(&path),
+            flags,
+            Mode::from_bits_truncate(mode),
+        )
+        .map(owned)
+        .map_err(errno)?;
+        let metadata = file.metadata()?;
+        let ino = self.inodes.lock().unwrap().look_up(&path);
+        // Even a file that's never written to has to reach the backup
+        let fh = self.add_handle(Handle::File {
+            file,
+            ino,
+            dirty: AtomicBool::new(true),
+        });
+        Ok((attr(ino, &metadata), fh))
+    }
+
+    fn read(&self, fh: FileHandle, offset: u64, size: u32) -> Result<Vec<u8>, Errno> {
+        let Handle::File { file, .. } = &*self.handle_of(fh)? else {
+            return Err(Errno::EISDIR);
+        };
+        let mut buf = vec![0; size as usize];
+        let mut read = 0;
+        while read < buf.len() {
+            match file.read_at(&mut buf[read..], offset + read as u64) {
+                Ok(0) => break,
+                Ok(len) => read += len,
+                Err(err) if err.kind() == io::ErrorKind::Interrupted => {}
+                Err(err) => return Err(err.into()),
+            }
+        }
+        buf.truncate(read);
+        Ok(buf)
+    }
+
+    fn write(&self, fh: FileHandle, offset: u64, data: &[u8]) -> Result<u32, Errno> {
+        let Handle::File { file, dirty, .. } = &*self.handle_of(fh)? else {
+            return Err(Errno::EISDIR);
+        };
+        file.write_all_at(data, offset)?;
+        dirty.store(true, Ordering::Relaxed);
+        Ok(data.len() as u32)
+    }
+
+    /// Replicates the file of `handle` if it changed since it last was
+    fn replicate_handle(&self, handle: &Handle) -> Result<(), Errno> {
+        let Handle::File { ino, dirty, .. } = handle else {
+            return Ok(());
+        };
+        if !dirty.swap(false, Ordering::Relaxed) {
+            return Ok(());
+        }
+        // Removed while it was open, which was replicated already
+        let Some(path) = self.inodes.lock().unwrap().linked_path(*ino) else {
+            return Ok(());
+        };
+        self.replicate(Change::Write(path)).inspect_err(|_| {
+            // Tried again when it's flushed or closed the next time
+            dirty.store(true, Ordering::Relaxed);
+        })
+    }
+
+    fn fsync(&self, fh: FileHandle, datasync: bool) -> Result<(), Errno> {
+        let handle = self.handle_of(fh)?;
+        if let Handle::File { file, .. } = &*handle {
+            match datasync {
+                false => file.sync_all()?,
+                true => file.sync_data()?,
+            }
+        }
+        self.replicate_handle(&handle)
+    }
+
+    fn opendir(&self, ino: INodeNo) -> Result<FileHandle, Errno> {
+        let relative_path = self.path(ino)?;
+        let mut entries = vec![
+            (ino.0, FileType::Directory, OsString::from(".")),
+            (ino.0, FileType::Directory, OsString::from("..")),
+        ];
+        for entry in fs::read_dir(self.root.join(&relative_path))? {
+            let entry = entry?;
+            let name = entry.file_name();
+            if relative_path.as_os_str().is_empty() && name == METADATA_DIR_NAME {
+                continue;
+            }
+            let kind = entry
+                .file_type()
+                .ok()
+                .and_then(FileType::from_std)
+                .unwrap_or(FileType::RegularFile);
+            entries.push((entry.ino(), kind, name));
+        }
+        Ok(self.add_handle(Handle::Dir(entries)))
+    }
+}
+
+/// What the kernel is told about the file `ino`
+fn attr(ino: u64, metadata: &Metadata) -> FileAttr {
+    let time = |secs: i64, nsecs: i64| match u64::try_from(secs) {
+        Ok(secs) => UNIX_EPOCH + Duration::new(secs, nsecs as u32),
+        Err(_) => {
+            UNIX_EPOCH - Duration::from_secs(secs.unsigned_abs())
+                + Duration::from_nanos(nsecs as u64)
+        }
+    };
+    let mtime = time(metadata.mtime(), metadata.mtime_nsec());
+    FileAttr {
+        ino: INodeNo(ino),
+        size: metadata.size(),
+        blocks: metadata.blocks(),
+        atime: time(metadata.atime(), metadata.atime_nsec()),
+        mtime,
+        ctime: time(metadata.ctime(), metadata.ctime_nsec()),
+        crtime: metadata.created().unwrap_or(mtime),
+        kind: FileType::from_std(metadata.file_type()).unwrap_or(FileType::RegularFile),
+        perm: (metadata.mode() & 0o7777) as u16,
+        nlink: metadata.nlink() as u32,
+        uid: metadata.uid(),
+        gid: metadata.gid(),
+        rdev: metadata.rdev() as u32,
+        blksize: metadata.blksize() as u32,
+        flags: 0,
+    }
+}
+
+impl Filesystem for Passthrough {
+    fn lookup(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEntry) {
+        match self
+            .child(parent, name)
+            .map_err(|_| Errno::ENOENT)
+            .and_then(|path| self.entry(&path))
+        {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn forget(&self, _req: &Request, ino: INodeNo, nlookup: u64) {
+        self.inodes.lock().unwrap().forget(ino.0, nlookup);
+    }
+
+    fn getattr(&self, _req: &Request, ino: INodeNo, _fh: Option<FileHandle>, reply: ReplyAttr) {
+        match self.getattr(ino) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn setattr(
+        &self,
+        _req: &Request,
+        ino: INodeNo,
+        mode: Option<u32>,
+        uid: Option<u32>,
+        gid: Option<u32>,
+        size: Option<u64>,
+        atime: Option<TimeOrNow>,
+        mtime: Option<TimeOrNow>,
+        _ctime: Option<SystemTime>,
+        fh: Option<FileHandle>,
+        _crtime: Option<SystemTime>,
+        _chgtime: Option<SystemTime>,
+        _bkuptime: Option<SystemTime>,
+        _flags: Option<fuser::BsdFileFlags>,
+        reply: ReplyAttr,
+    ) {
+        match self.setattr(ino, mode, (uid, gid), size, (atime, mtime), fh) {
+            Ok(attr) => reply.attr(&TTL, &attr),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readlink(&self, _req: &Request, ino: INodeNo, reply: ReplyData) {
+        match self
+            .path(ino)
+            .and_then(|path| Ok(fs::read_link(self.root.join(path))?))
+        {
+            Ok(target) => reply.data(target.as_os_str().as_bytes()),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mknod(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        _rdev: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.mknod(parent, name, mode & !umask) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn mkdir(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        reply: ReplyEntry,
+    ) {
+        match self.mkdir(parent, name, mode & !umask) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn unlink(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        match self.unlink(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rmdir(&self, _req: &Request, parent: INodeNo, name: &OsStr, reply: ReplyEmpty) {
+        match self.rmdir(parent, name) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn symlink(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        link_name: &OsStr,
+        target: &Path,
+        reply: ReplyEntry,
+    ) {
+        match self.symlink(parent, link_name, target) {
+            Ok(attr) => reply.entry(&TTL, &attr, Generation(0)),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn rename(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        newparent: INodeNo,
+        newname: &OsStr,
+        flags: RenameFlags,
+        reply: ReplyEmpty,
+    ) {
+        match self.rename((parent, name), (newparent, newname), flags) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Hard links would give one file two paths in the backup
+    fn link(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _newparent: INodeNo,
+        _newname: &OsStr,
+        reply: ReplyEntry,
+    ) {
+        reply.error(Errno::EPERM);
+    }
+
+    fn open(&self, _req: &Request, ino: INodeNo, flags: OpenFlags, reply: ReplyOpen) {
+        match self.open(ino, flags) {
+            Ok(fh) => reply.opened(fh, FopenFlags::empty()),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn read(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        size: u32,
+        _flags: OpenFlags,
+        _lock_owner: Option<fuser::LockOwner>,
+        reply: ReplyData,
+    ) {
+        match self.read(fh, offset, size) {
+            Ok(data) => reply.data(&data),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn write(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        data: &[u8],
+        _write_flags: WriteFlags,
+        _flags: OpenFlags,
+        _lock_owner: Option<fuser::LockOwner>,
+        reply: ReplyWrite,
+    ) {
+        match self.write(fh, offset, data) {
+            Ok(written) => reply.written(written),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    /// Sent for every `close` of a file, which waits for the answer. This is where writes
+    /// are replicated, so with [`Replicate::Sync`](super::Replicate::Sync) they're in the
+    /// backup by the time `close` returns
+    fn flush(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _lock_owner: fuser::LockOwner,
+        reply: ReplyEmpty,
+    ) {
+        match self
+            .handle_of(fh)
+            .and_then(|handle| self.replicate_handle(&handle))
+        {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn release(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        _lock_owner: Option<fuser::LockOwner>,
+        _flush: bool,
+        reply: ReplyEmpty,
+    ) {
+        let handle = self.handles.lock().unwrap().remove(&fh.0);
+        // Nobody waits for the answer, a failure was logged by the replicator
+        if let Some(handle) = handle {
+            let _ = self.replicate_handle(&handle);
+        }
+        reply.ok();
+    }
+
+    fn fsync(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        match self.fsync(fh, datasync) {
+            Ok(()) => reply.ok(),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn opendir(&self, _req: &Request, ino: INodeNo, _flags: OpenFlags, reply: ReplyOpen) {
+        match self.opendir(ino) {
+            Ok(fh) => reply.opened(fh, FopenFlags::empty()),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    fn readdir(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        offset: u64,
+        mut reply: ReplyDirectory,
+    ) {
+        let handle = match self.handle_of(fh) {
+            Ok(handle) => handle,
+            Err(err) => return reply.error(err),
+        };
+        let Handle::Dir(entries) = &*handle else {
+            return reply.error(Errno::ENOTDIR);
+        };
+        for (index, (ino, kind, name)) in entries.iter().enumerate().skip(offset as usize) {
+            if reply.add(INodeNo(*ino), index as u64 + 1, *kind, name) {
+                break;
+            }
+        }
+        reply.ok();
+    }
+
+    fn releasedir(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        fh: FileHandle,
+        _flags: OpenFlags,
+        reply: ReplyEmpty,
+    ) {
+        self.handles.lock().unwrap().remove(&fh.0);
+        reply.ok();
+    }
+
+    fn fsyncdir(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _datasync: bool,
+        reply: ReplyEmpty,
+    ) {
+        reply.ok();
+    }
+
+    // The counts are only 32 bits on some platforms
+    #[allow(clippy::useless_conversion)]
+    fn statfs(&self, _req: &Request, _ino: INodeNo, reply: ReplyStatfs) {
+        match statvfs(&self.root) {
+            Ok(stat) => reply.statfs(
+                u64::from(stat.blocks()),
+                u64::from(stat.blocks_free()),
+                u64::from(stat.blocks_available()),
+                u64::from(stat.files()),
+                u64::from(stat.files_free()),
+                stat.block_size() as u32,
+                stat.name_max() as u32,
+                stat.fragment_size() as u32,
+            ),
+            Err(err) => reply.error(errno(err)),
+        }
+    }
+
+    // Permissions are checked by the kernel, the mount uses `default_permissions`
+    fn access(&self, _req: &Request, _ino: INodeNo, _mask: fuser::AccessFlags, reply: ReplyEmpty) {
+        reply.ok();
+    }
+
+    fn create(
+        &self,
+        _req: &Request,
+        parent: INodeNo,
+        name: &OsStr,
+        mode: u32,
+        umask: u32,
+        flags: i32,
+        reply: ReplyCreate,
+    ) {
+        match self.create(parent, name, flags, mode & !umask) {
+            Ok((attr, fh)) => reply.created(&TTL, &attr, Generation(0), fh, FopenFlags::empty()),
+            Err(err) => reply.error(err),
+        }
+    }
+
+    // Extended attributes, fallocate and the rest aren't supported. The kernel either does
+    // without them or tells the caller, and these are answered without fuser's warnings
+    // since the kernel tries some of them on its own
+
+    fn setxattr(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _name: &OsStr,
+        _value: &[u8],
+        _flags: i32,
+        _position: u32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn getxattr(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _name: &OsStr,
+        _size: u32,
+        reply: ReplyXattr,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn listxattr(&self, _req: &Request, _ino: INodeNo, _size: u32, reply: ReplyXattr) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn removexattr(&self, _req: &Request, _ino: INodeNo, _name: &OsStr, reply: ReplyEmpty) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn fallocate(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _offset: u64,
+        _length: u64,
+        _mode: i32,
+        reply: ReplyEmpty,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn lseek(
+        &self,
+        _req: &Request,
+        _ino: INodeNo,
+        _fh: FileHandle,
+        _offset: i64,
+        _whence: i32,
+        reply: fuser::ReplyLseek,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+
+    fn copy_file_range(
+        &self,
+        _req: &Request,
+        _ino_in: INodeNo,
+        _fh_in: FileHandle,
+        _offset_in: u64,
+        _ino_out: INodeNo,
+        _fh_out: FileHandle,
+        _offset_out: u64,
+        _len: u64,
+        _flags: fuser::CopyFileRangeFlags,
+        reply: ReplyWrite,
+    ) {
+        reply.error(Errno::ENOSYS);
+    }
+}
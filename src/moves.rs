@@ -0,0 +1,106 @@
+//! Recognizing files that were renamed or moved inside work_dir, so their backup can be
+//! renamed along with them instead of being copied all over again.
+//!
+//! Files are told apart by their device and inode numbers, remembered with their size and
+//! modification time whenever they are synced. A file that turns up under a new path with
+//! the same identity, size and modification time while its old path is gone was moved.
+//! Only Unix has inode numbers, elsewhere moved files are copied as usual
+
+use std::{
+    collections::{HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime},
+};
+
+use crate::vfs::{Entry, FileId};
+
+/// Deletions wait at least this long when moves are detected, so the backup of a moved
+/// file is still there to be renamed when its new path shows up
+pub(crate) const MOVE_GRACE: Duration = Duration::from_secs(1);
+
+/// What [`SyncContext::move_backup`](crate::syncer::SyncContext::move_backup) did
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub(crate) enum Moved {
+    /// The backup was moved here from this path in work_dir
+    From(PathBuf),
+    /// The backup was already moved here, and the file hasn't changed since
+    Already,
+}
+
+/// A file as it was when it was last synced
+#[derive(Debug)]
+struct Known {
+    relative_path: PathBuf,
+    size: u64,
+    modified: Option<SystemTime>,
+    /// Whether its backup got there by being moved rather than copied
+    moved: bool,
+}
+
+/// Where every synced file was, by identity
+#[derive(Debug, Default)]
+pub(crate) struct MoveTracker {
+    known: Mutex<HashMap<FileId, Known>>,
+    /// Paths whose backup was moved away, so there is nothing left to remove there
+    moved_away: Mutex<HashSet<PathBuf>>,
+}
+
+impl MoveTracker {
+    /// Remembers that the file at `relative_path` was synced as it is described by `entry`
+    pub fn record(&self, relative_path: &Path, entry: &Entry) {
+        self.insert(relative_path, entry, false);
+    }
+
+    fn insert(&self, relative_path: &Path, entry: &Entry, moved: bool) {
+        let Some(id) = entry.id else {
+            return;
+        };
+        self.moved_away.lock().unwrap().remove(relative_path);
+        self.known.lock().unwrap().insert(
+            id,
+            Known {
+                relative_path: relative_path.to_path_buf(),
+                size: entry.size,
+                modified: entry.modified,
+                moved,
+            },
+        );
+    }
+
+    /// The path the file at `relative_path` was synced under, if that is another path and
+    /// the file hasn't changed since. Whether the old path is gone is up to the caller
+    pub fn moved_from(&self, relative_path: &Path, entry: &Entry) -> Option<PathBuf> {
+        let id = entry.id?;
+        let known = self.known.lock().unwrap();
+        let known = known.get(&id)?;
+        let unchanged = known.size == entry.size && known.modified == entry.modified;
+        (unchanged && known.relative_path != relative_path).then(|| known.relative_path.clone())
+    }
+
+    /// Whether the backup of the file at `relative_path` was moved there and the file hasn't
+    /// changed since. Watchers report a move more than once, which doesn't call for a copy
+    pub fn moved_here(&self, relative_path: &Path, entry: &Entry) -> bool {
+        let Some(id) = entry.id else {
+            return false;
+        };
+        self.known.lock().unwrap().get(&id).is_some_and(|known| {
+            known.moved
+                && known.relative_path == relative_path
+                && known.size == entry.size
+                && known.modified == entry.modified
+        })
+    }
+
+    /// Records that the backup of `from` was moved to `to`
+    pub fn moved(&self, from: &Path, to: &Path, entry: &Entry) {
+        self.insert(to, entry, true);
+        self.moved_away.lock().unwrap().insert(from.to_path_buf());
+    }
+
+    /// Whether the backup of `relative_path` was moved away since a file was last synced
+    /// there, in which case there is nothing left to remove
+    pub fn moved_away(&self, relative_path: &Path) -> bool {
+        self.moved_away.lock().unwrap().contains(relative_path)
+    }
+}
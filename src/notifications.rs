@@ -0,0 +1,540 @@
+//! Telling someone when syncing goes wrong, since log lines go unseen when evil_mount runs
+//! in the background.
+//!
+//! Notifications are sent at the end of a sync cycle: once for the files that couldn't be
+//! synced, once when the same file has failed several cycles in a row, and whenever the
+//! backup becomes unreachable or reachable again. Emails are sent for fewer things, so they
+//! can go to someone who doesn't look at a screen: a daily summary, an alert when a cycle
+//! has more errors than a threshold, and one when the backup has been unreachable for a while
+
+use chrono::{DateTime, Local, NaiveDate};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use std::{
+    collections::HashMap,
+    fmt,
+    path::PathBuf,
+    str::FromStr,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tracing::{debug, warn};
+
+use crate::{
+    backend::Backend,
+    hooks::CycleReport,
+    metrics::Metrics,
+    smtp::{Mailer, SmtpTls},
+    window::TimeOfDay,
+};
+
+/// A file that fails this many cycles in a row gets a notification of its own
+const REPEATED_FAILURES: usize = 3;
+
+/// How many errors a notification lists
+const MAX_LISTED_ERRORS: usize = 5;
+
+/// How often [`Notifier::check_email`] is called
+pub(crate) const EMAIL_CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+/// How many errors an email lists
+const MAX_EMAILED_ERRORS: usize = 20;
+
+/// How long a webhook gets to answer
+const WEBHOOK_TIMEOUT: Duration = Duration::from_secs(10);
+
+/// Where notifications are shown, besides a webhook
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NotifyTarget {
+    /// The desktop's notification center
+    #[serde(rename = "desktop")]
+    Desktop,
+}
+
+impl FromStr for NotifyTarget {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "desktop" => Ok(Self::Desktop),
+            _ => Err(format!("unknown notification target {s}, expected desktop")),
+        }
+    }
+}
+
+impl fmt::Display for NotifyTarget {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Desktop => "desktop",
+        })
+    }
+}
+
+/// Sending notifications by email, the `[notify.email]` section of the config file
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(deny_unknown_fields)]
+pub struct EmailNotifications {
+    /// The SMTP server to send through, `host` or `host:port`
+    pub smtp_server: String,
+    #[serde(default)]
+    pub smtp_tls: SmtpTls,
+    pub username: Option<String>,
+    pub password: Option<String>,
+    pub from: String,
+    pub to: Vec<String>,
+    /// When to send a summary of the day before, in local time
+    pub daily_summary: Option<TimeOfDay>,
+    /// Send an alert when a cycle has more errors than this
+    pub error_threshold: Option<usize>,
+    /// Send an alert when the backup couldn't be reached for this many minutes
+    pub unreachable_minutes: Option<u64>,
+}
+
+impl EmailNotifications {
+    fn mailer(&self) -> Mailer<'_> {
+        Mailer {
+            server: &self.smtp_server,
+            tls: self.smtp_tls,
+            username: self.username.as_deref(),
+            password: self.password.as_deref(),
+            from: &self.from,
+            to: &self.to,
+        }
+    }
+}
+
+/// What emails were sent about, kept across runs so a reload doesn't send them again
+#[derive(Debug, Default)]
+pub(crate) struct EmailState {
+    summary: Mutex<Option<Summarized>>,
+    /// Since when the backup can't be reached, and whether that was sent yet
+    unreachable: Mutex<Option<(Instant, bool)>>,
+    /// Whether the last cycle had more errors than the threshold
+    over_threshold: AtomicBool,
+}
+
+/// The day the last summary was for, and where the counts stood then
+#[derive(Debug)]
+struct Summarized {
+    day: NaiveDate,
+    at: DateTime<Local>,
+    synced: u64,
+    removed: u64,
+    bytes_copied: u64,
+    copy_errors: u64,
+}
+
+impl Summarized {
+    fn new(day: NaiveDate, metrics: &Metrics) -> Self {
+        Self {
+            day,
+            at: Local::now(),
+            synced: metrics.files_synced(),
+            removed: metrics.files_removed(),
+            bytes_copied: metrics.bytes_copied(),
+            copy_errors: metrics.copy_errors(),
+        }
+    }
+}
+
+/// What a notification is about, sent to webhooks as `event`
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    CopyFailed,
+    RepeatedFailure,
+    Unreachable,
+    Reachable,
+    Degraded,
+    Responding,
+}
+
+impl Kind {
+    fn name(self) -> &'static str {
+        match self {
+            Self::CopyFailed => "copy_failed",
+            Self::RepeatedFailure => "repeated_failure",
+            Self::Unreachable => "unreachable",
+            Self::Reachable => "reachable",
+            Self::Degraded => "degraded",
+            Self::Responding => "responding",
+        }
+    }
+}
+
+/// Sends notifications for one [`Syncer::run`](crate::Syncer::run), remembering what was
+/// already reported so the same problem isn't announced every cycle
+pub(crate) struct Notifier {
+    desktop: bool,
+    webhook: Option<String>,
+    email: Option<EmailNotifications>,
+    email_state: Arc<EmailState>,
+    client: reqwest::Client,
+    /// How many cycles in a row each path failed in
+    failures: Mutex<HashMap<PathBuf, usize>>,
+    unreachable: AtomicBool,
+}
+
+impl Notifier {
+    pub fn new(
+        target: Option<NotifyTarget>,
+        webhook: Option<String>,
+        email: Option<EmailNotifications>,
+        email_state: Arc<EmailState>,
+    ) -> Self {
+        Self {
+            desktop: target == Some(NotifyTarget::Desktop),
+            webhook,
+            email,
+            email_state,
+            client: reqwest::Client::new(),
+            failures: Mutex::new(HashMap::new()),
+            unreachable: AtomicBool::new(false),
+        }
+    }
+
+    fn is_enabled(&self) -> bool {
+        self.desktop || self.webhook.is_some() || self.email.is_some()
+    }
+
+    /// Whether emails are sent on a schedule, which [`Notifier::check_email`] does
+    pub fn emails_on_schedule(&self) -> bool {
+        self.email.as_ref().is_some_and(|email| {
+            email.daily_summary.is_some() || email.unreachable_minutes.is_some()
+        })
+    }
+
+    /// Sends whatever notifications the cycle described by `report` calls for
+    pub async fn end_cycle(&self, work_dir: &str, backend: &dyn Backend, report: &CycleReport) {
+        if !self.is_enabled() {
+            return;
+        }
+
+        let repeated: Vec<PathBuf> = {
+            let mut failures = self.failures.lock().unwrap();
+            for path in &report.changed {
+                failures.remove(path);
+            }
+            let mut repeated = Vec::new();
+            for path in &report.failed {
+                let count = failures.entry(path.clone()).or_default();
+                *count += 1;
+                // Only once per streak, rather than every cycle it keeps failing
+                if *count == REPEATED_FAILURES {
+                    repeated.push(path.clone());
+                }
+            }
+            repeated
+        };
+
+        // Nothing to check if everything went fine, unless the backup was unreachable
+        if report.errors.is_empty() && !self.unreachable.load(Ordering::Relaxed) {
+            self.email_state
+                .over_threshold
+                .store(false, Ordering::Relaxed);
+            return;
+        }
+        let backup = backend.to_string();
+        match backend.check().await {
+            Err(error) => {
+                if !self.unreachable.swap(true, Ordering::Relaxed) {
+                    let message = format!("{backup} can't be reached: {error:#}");
+                    self.send(Kind::Unreachable, work_dir, &backup, &message, &[])
+                        .await;
+                }
+                // Every copy failing is a consequence, not worth hearing about separately
+                return;
+            }
+            Ok(()) => {
+                if self.unreachable.swap(false, Ordering::Relaxed) {
+                    let message = format!("{backup} can be reached again");
+                    self.send(Kind::Reachable, work_dir, &backup, &message, &[])
+                        .await;
+                }
+            }
+        }
+
+        if !report.errors.is_empty() {
+            let message = match report.errors.len() {
+                1 => "1 file couldn't be synced".to_string(),
+                count => format!("{count} files couldn't be synced"),
+            };
+            self.send(
+                Kind::CopyFailed,
+                work_dir,
+                &backup,
+                &message,
+                &report.errors,
+            )
+            .await;
+        }
+        if let Some(threshold) = self.email.as_ref().and_then(|email| email.error_threshold) {
+            let over = report.errors.len() > threshold;
+            let was_over = self
+                .email_state
+                .over_threshold
+                .swap(over, Ordering::Relaxed);
+            // Once until a cycle is back under it, rather than every cycle
+            if over && !was_over {
+                let subject = format!(
+                    "evil_mount: {} files in {work_dir} couldn't be synced",
+                    report.errors.len()
+                );
+                let mut body = format!(
+                    "{} files in {work_dir} couldn't be synced to {backup}, more than the \
+                     threshold of {threshold}:\n\n",
+                    report.errors.len()
+                );
+                push_errors(&mut body, &report.errors);
+                self.send_email(&subject, &body).await;
+            }
+        }
+        for path in repeated {
+            let message = format!(
+                "{} failed to sync {REPEATED_FAILURES} times in a row",
+                path.display()
+            );
+            let errors: Vec<String> = report
+                .failed
+                .iter()
+                .zip(&report.errors)
+                .filter(|(failed, _)| **failed == path)
+                .map(|(_, error)| error.clone())
+                .collect();
+            self.send(Kind::RepeatedFailure, work_dir, &backup, &message, &errors)
+                .await;
+        }
+    }
+
+    /// Announces that copies into the backup time out, see
+    /// [`SyncOptions::copy_timeout`](crate::SyncOptions::copy_timeout)
+    pub async fn degraded(&self, work_dir: &str, backend: &dyn Backend, message: &str) {
+        if self.is_enabled() {
+            let backup = backend.to_string();
+            self.send(Kind::Degraded, work_dir, &backup, message, &[])
+                .await;
+        }
+    }
+
+    /// Announces that copies into a degraded backup finish in time again
+    pub async fn responding(&self, work_dir: &str, backend: &dyn Backend, message: &str) {
+        if self.is_enabled() {
+            let backup = backend.to_string();
+            self.send(Kind::Responding, work_dir, &backup, message, &[])
+                .await;
+        }
+    }
+
+    /// Announces that backup_dir is gone or something else is mounted there, see
+    /// [`crate::vanished`]
+    pub async fn vanished(&self, work_dir: &str, backend: &dyn Backend, message: &str) {
+        if self.is_enabled() && !self.unreachable.swap(true, Ordering::Relaxed) {
+            let backup = backend.to_string();
+            self.send(Kind::Unreachable, work_dir, &backup, message, &[])
+                .await;
+        }
+    }
+
+    /// Announces that a backup_dir that was gone is back
+    pub async fn returned(&self, work_dir: &str, backend: &dyn Backend, message: &str) {
+        if self.is_enabled() && self.unreachable.swap(false, Ordering::Relaxed) {
+            let backup = backend.to_string();
+            self.send(Kind::Reachable, work_dir, &backup, message, &[])
+                .await;
+        }
+    }
+
+    /// Sends the daily summary once it's due, and checks whether the backup can be reached
+    /// when an alert about that is wanted. Called every [`EMAIL_CHECK_INTERVAL`]
+    pub async fn check_email(&self, work_dir: &str, backend: &dyn Backend, metrics: &Metrics) {
+        let Some(email) = &self.email else {
+            return;
+        };
+        let backup = backend.to_string();
+
+        if let Some(time) = email.daily_summary {
+            let day = time.last_day(&Local::now());
+            let previous = {
+                let mut summary = self.email_state.summary.lock().unwrap();
+                match &*summary {
+                    // Starting up isn't a reason to send one
+                    None => {
+                        *summary = Some(Summarized::new(day, metrics));
+                        None
+                    }
+                    Some(summarized) if summarized.day < day => {
+                        summary.replace(Summarized::new(day, metrics))
+                    }
+                    Some(_) => None,
+                }
+            };
+            if let Some(previous) = previous {
+                let subject = format!("evil_mount: daily summary of {work_dir}");
+                let body = summary(work_dir, &backup, &previous, metrics);
+                self.send_email(&subject, &body).await;
+            }
+        }
+
+        let Some(minutes) = email.unreachable_minutes else {
+            return;
+        };
+        match backend.check().await {
+            Err(error) => {
+                let due = {
+                    let mut unreachable = self.email_state.unreachable.lock().unwrap();
+                    let (since, sent) = unreachable.get_or_insert((Instant::now(), false));
+                    let due = !*sent && since.elapsed() >= Duration::from_secs(minutes * 60);
+                    *sent |= due;
+                    due
+                };
+                if due {
+                    let subject = format!("evil_mount: {backup} can't be reached");
+                    let body = format!(
+                        "{backup}, the backup of {work_dir}, couldn't be reached for {minutes} \
+                         minutes. Nothing is backed up until it can be.\n\n{error:#}\n"
+                    );
+                    self.send_email(&subject, &body).await;
+                }
+            }
+            Ok(()) => {
+                let unreachable = self.email_state.unreachable.lock().unwrap().take();
+                if let Some((since, true)) = unreachable {
+                    let subject = format!("evil_mount: {backup} can be reached again");
+                    let body = format!(
+                        "{backup}, the backup of {work_dir}, can be reached again after {} \
+                         minutes.\n",
+                        since.elapsed().as_secs() / 60
+                    );
+                    self.send_email(&subject, &body).await;
+                }
+            }
+        }
+    }
+
+    async fn send_email(&self, subject: &str, body: &str) {
+        let Some(email) = &self.email else {
+            return;
+        };
+        debug!("Emailing {}: {subject}", email.to.join(", "));
+        if let Err(err) = email.mailer().send(subject, body).await {
+            warn!("Error sending a notification email: {err:#}");
+        }
+    }
+
+    async fn send(
+        &self,
+        kind: Kind,
+        work_dir: &str,
+        backup: &str,
+        message: &str,
+        errors: &[String],
+    ) {
+        debug!(event = kind.name(), "Notifying: {message}");
+
+        if self.desktop {
+            let mut body = message.to_string();
+            for error in errors.iter().take(MAX_LISTED_ERRORS) {
+                body.push('\n');
+                body.push_str(error);
+            }
+            if errors.len() > MAX_LISTED_ERRORS {
+                body.push_str(&format!(
+                    "\n...and {} more",
+                    errors.len() - MAX_LISTED_ERRORS
+                ));
+            }
+            let summary = format!("evil_mount: {work_dir}");
+            let shown = tokio::task::spawn_blocking(move || {
+                notify_rust::Notification::new()
+                    .summary(&summary)
+                    .body(&body)
+                    .show()
+                    .map(|_| ())
+            })
+            .await;
+            match shown {
+                Ok(Ok(())) => {}
+                Ok(Err(err)) => warn!("Error showing a desktop notification: {err}"),
+                Err(err) => warn!("Error showing a desktop notification: {err}"),
+            }
+        }
+
+        if let Some(url) = &self.webhook {
+            let payload = json!({
+                "event": kind.name(),
+                "work_dir": work_dir,
+                "backup_dir": backup,
+                "message": message,
+                "errors": errors,
+            });
+            let sent = self
+                .client
+                .post(url)
+                .timeout(WEBHOOK_TIMEOUT)
+                .header("content-type", "application/json")
+                .body(payload.to_string())
+                .send()
+                .await
+                .and_then(|response| response.error_for_status());
+            if let Err(err) = sent {
+                warn!("Error calling the notification webhook: {err}");
+            }
+        }
+    }
+}
+
+/// The body of the daily summary, about what happened since `previous`
+fn summary(work_dir: &str, backup: &str, previous: &Summarized, metrics: &Metrics) -> String {
+    let mut body = format!(
+        "Syncing {work_dir} to {backup} since {}:\n\n\
+         {} files copied ({}), {} removed, {} errors\n",
+        previous.at.format("%Y-%m-%d %H:%M"),
+        metrics.files_synced().saturating_sub(previous.synced),
+        HumanBytes(metrics.bytes_copied().saturating_sub(previous.bytes_copied)),
+        metrics.files_removed().saturating_sub(previous.removed),
+        metrics.copy_errors().saturating_sub(previous.copy_errors),
+    );
+    match metrics.last_sync() {
+        Some(time) => body.push_str(&format!(
+            "Last synced without errors at {}\n",
+            DateTime::<Local>::from(time).format("%Y-%m-%d %H:%M")
+        )),
+        None => body.push_str("Not synced without errors yet\n"),
+    }
+    let failing: Vec<String> = metrics
+        .failing_files()
+        .into_iter()
+        .map(|(path, error)| format!("{}: {error}", path.display()))
+        .collect();
+    if !failing.is_empty() {
+        body.push_str("\nFiles that still can't be synced:\n");
+        push_errors(&mut body, &failing);
+    }
+    let permission_denied: Vec<String> = metrics
+        .permission_denied_files()
+        .into_iter()
+        .map(|(path, error)| format!("{}: {error}", path.display()))
+        .collect();
+    if !permission_denied.is_empty() {
+        body.push_str("\nSkipped, permission denied:\n");
+        push_errors(&mut body, &permission_denied);
+    }
+    body
+}
+
+/// Adds up to [`MAX_EMAILED_ERRORS`] of `errors` to `body`, a line each
+fn push_errors(body: &mut String, errors: &[String]) {
+    for error in errors.iter().take(MAX_EMAILED_ERRORS) {
+        body.push_str(error);
+        body.push('\n');
+    }
+    if errors.len() > MAX_EMAILED_ERRORS {
+        body.push_str(&format!(
+            "...and {} more\n",
+            errors.len() - MAX_EMAILED_ERRORS
+        ));
+    }
+}
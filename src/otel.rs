@@ -0,0 +1,398 @@
+//! Sends the spans of scans, diffs and copies to an OpenTelemetry collector, so slow syncs
+//! can be looked at in Jaeger, Tempo or whatever else takes OTLP.
+//!
+//! Every sweep, scan, diff, sync cycle and copy outside of those is a trace of its own.
+//! Spans are batched and POSTed as OTLP/JSON to `<endpoint>/v1/traces`. Warnings and
+//! errors logged inside a span become events of it, and errors mark it as failed
+
+use anyhow::{Context as _, Result};
+use serde_json::{json, Value};
+use std::{
+    fmt,
+    sync::atomic::{AtomicU64, Ordering},
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+use tokio::{
+    sync::{mpsc, oneshot},
+    time::MissedTickBehavior,
+};
+use tracing::{
+    field::{Field, Visit},
+    span, warn, Event, Level, Metadata, Subscriber,
+};
+use tracing_subscriber::{
+    filter::{filter_fn, FilterFn},
+    layer::Context,
+    registry::LookupSpan,
+    Layer,
+};
+
+/// Finished spans are sent at least this often
+const EXPORT_INTERVAL: Duration = Duration::from_secs(5);
+
+/// Or as soon as this many are waiting
+const BATCH_SIZE: usize = 512;
+
+/// How long exiting waits for the last spans to be sent
+const FLUSH_TIMEOUT: Duration = Duration::from_secs(5);
+
+const SERVICE_NAME: &str = "evil_mount";
+
+/// The spans and events worth exporting: the debug spans of the library, and the warnings
+/// and errors logged in them. The `pair` spans of `main` are left out, they last as long
+/// as the process and would make all of it one trace
+pub fn filter() -> FilterFn<impl Fn(&Metadata<'_>) -> bool> {
+    filter_fn(|metadata| match metadata.is_span() {
+        true => {
+            metadata
+                .target()
+                .starts_with(concat!(env!("CARGO_CRATE_NAME"), "::"))
+                && *metadata.level() <= Level::DEBUG
+        }
+        false => {
+            metadata.target().starts_with(env!("CARGO_CRATE_NAME"))
+                && *metadata.level() <= Level::WARN
+        }
+    })
+}
+
+/// Creates the layer collecting spans and the exporter sending them to `endpoint`. Must be
+/// called within the runtime
+pub fn layer(endpoint: &str) -> Result<(SpanLayer, Exporter)> {
+    let endpoint = match endpoint.trim_end_matches('/') {
+        url if url.ends_with("/v1/traces") => url.to_string(),
+        url => format!("{url}/v1/traces"),
+    };
+    let client = reqwest::Client::builder()
+        .timeout(Duration::from_secs(10))
+        .build()?;
+    let mut seed = [0; 32];
+    getrandom::fill(&mut seed).context("Can't generate trace ids")?;
+
+    let (sender, receiver) = mpsc::unbounded_channel();
+    tokio::spawn(export(client, endpoint, receiver));
+    let layer = SpanLayer {
+        sender: sender.clone(),
+        seed,
+        next_id: AtomicU64::new(0),
+    };
+    Ok((layer, Exporter { sender }))
+}
+
+/// Sends the spans the [`SpanLayer`] collects
+pub struct Exporter {
+    sender: mpsc::UnboundedSender<Message>,
+}
+
+impl Exporter {
+    /// Sends every span that ended so far, giving up after [`FLUSH_TIMEOUT`]
+    pub async fn flush(&self) {
+        let (done, flushed) = oneshot::channel();
+        if self.sender.send(Message::Flush(done)).is_ok() {
+            let _ = tokio::time::timeout(FLUSH_TIMEOUT, flushed).await;
+        }
+    }
+}
+
+enum Message {
+    Span(SpanData),
+    Flush(oneshot::Sender<()>),
+}
+
+/// Records spans as they run and hands them to the [`Exporter`] once they close
+pub struct SpanLayer {
+    sender: mpsc::UnboundedSender<Message>,
+    seed: [u8; 32],
+    next_id: AtomicU64,
+}
+
+impl SpanLayer {
+    /// A new random id, derived from a random seed so spans don't each need a syscall
+    fn random_id(&self) -> [u8; 32] {
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed);
+        *blake3::keyed_hash(&self.seed, &id.to_le_bytes()).as_bytes()
+    }
+}
+
+/// A span as it is kept in the extensions of the registry until it closes
+struct SpanData {
+    trace_id: [u8; 16],
+    span_id: [u8; 8],
+    parent_id: Option<[u8; 8]>,
+    name: &'static str,
+    start: SystemTime,
+    end: SystemTime,
+    attributes: Vec<(&'static str, Value)>,
+    events: Vec<SpanEvent>,
+    /// Why the span failed, if it did
+    error: Option<String>,
+}
+
+struct SpanEvent {
+    time: SystemTime,
+    message: String,
+    attributes: Vec<(&'static str, Value)>,
+}
+
+impl<S> Layer<S> for SpanLayer
+where
+    S: Subscriber + for<'a> LookupSpan<'a>,
+{
+    fn on_new_span(&self, attrs: &span::Attributes<'_>, id: &span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let parent = span.parent().and_then(|parent| {
+            let extensions = parent.extensions();
+            let data = extensions.get::<SpanData>()?;
+            Some((data.trace_id, data.span_id))
+        });
+        let random = self.random_id();
+        let mut data = SpanData {
+            trace_id: match parent {
+                Some((trace_id, _)) => trace_id,
+                None => random[8..24].try_into().unwrap(),
+            },
+            span_id: random[..8].try_into().unwrap(),
+            parent_id: parent.map(|(_, span_id)| span_id),
+            name: attrs.metadata().name(),
+            start: SystemTime::now(),
+            end: SystemTime::now(),
+            attributes: Vec::new(),
+            events: Vec::new(),
+            error: None,
+        };
+        attrs.record(&mut Fields(&mut data.attributes));
+        data.error = error_field(&data.attributes);
+        span.extensions_mut().insert(data);
+    }
+
+    fn on_record(&self, id: &span::Id, values: &span::Record<'_>, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(id) else {
+            return;
+        };
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            values.record(&mut Fields(&mut data.attributes));
+            data.error = data.error.take().or_else(|| error_field(&data.attributes));
+        }
+    }
+
+    fn on_event(&self, event: &Event<'_>, ctx: Context<'_, S>) {
+        // Only what is logged in a span has a trace to go with
+        let Some(span) = ctx.event_span(event) else {
+            return;
+        };
+        let mut attributes = Vec::new();
+        event.record(&mut Fields(&mut attributes));
+        let message = match attributes.iter().position(|(key, _)| *key == "message") {
+            Some(index) => match attributes.remove(index).1 {
+                Value::String(message) => message,
+                message => message.to_string(),
+            },
+            None => event.metadata().name().to_string(),
+        };
+        let level = *event.metadata().level();
+        attributes.push(("level", Value::String(level.to_string())));
+
+        let mut extensions = span.extensions_mut();
+        if let Some(data) = extensions.get_mut::<SpanData>() {
+            if level == Level::ERROR && data.error.is_none() {
+                data.error = Some(message.clone());
+            }
+            data.events.push(SpanEvent {
+                time: SystemTime::now(),
+                message,
+                attributes,
+            });
+        }
+    }
+
+    fn on_close(&self, id: span::Id, ctx: Context<'_, S>) {
+        let Some(span) = ctx.span(&id) else {
+            return;
+        };
+        let data = span.extensions_mut().remove::<SpanData>();
+        if let Some(mut data) = data {
+            data.end = SystemTime::now();
+            // Only fails once the exporter is gone, when nothing is exported anymore anyway
+            let _ = self.sender.send(Message::Span(data));
+        }
+    }
+}
+
+/// A span with an `error` field recorded failed with it
+fn error_field(attributes: &[(&'static str, Value)]) -> Option<String> {
+    attributes
+        .iter()
+        .find(|(key, _)| *key == "error")
+        .map(|(_, error)| match error {
+            Value::String(error) => error.clone(),
+            error => error.to_string(),
+        })
+}
+
+/// Collects fields as attributes, replacing the earlier value of a field recorded twice
+struct Fields<'a>(&'a mut Vec<(&'static str, Value)>);
+
+impl Fields<'_> {
+    fn insert(&mut self, field: &Field, value: Value) {
+        match self.0.iter_mut().find(|(key, _)| *key == field.name()) {
+            Some((_, old)) => *old = value,
+            None => self.0.push((field.name(), value)),
+        }
+    }
+}
+
+impl Visit for Fields<'_> {
+    fn record_debug(&mut self, field: &Field, value: &dyn fmt::Debug) {
+        self.insert(field, Value::String(format!("{value:?}")));
+    }
+
+    fn record_str(&mut self, field: &Field, value: &str) {
+        self.insert(field, Value::String(value.to_string()));
+    }
+
+    fn record_i64(&mut self, field: &Field, value: i64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_u64(&mut self, field: &Field, value: u64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_f64(&mut self, field: &Field, value: f64) {
+        self.insert(field, value.into());
+    }
+
+    fn record_bool(&mut self, field: &Field, value: bool) {
+        self.insert(field, value.into());
+    }
+}
+
+/// Sends batches of spans until every sender is gone
+async fn export(
+    client: reqwest::Client,
+    endpoint: String,
+    mut messages: mpsc::UnboundedReceiver<Message>,
+) {
+    let mut batch = Vec::new();
+    let mut ticks = tokio::time::interval(EXPORT_INTERVAL);
+    ticks.set_missed_tick_behavior(MissedTickBehavior::Delay);
+    let mut failing = false;
+    loop {
+        let flushed = tokio::select! {
+            message = messages.recv() => match message {
+                Some(Message::Span(span)) => {
+                    batch.push(span);
+                    if batch.len() < BATCH_SIZE {
+                        continue;
+                    }
+                    None
+                }
+                Some(Message::Flush(done)) => Some(done),
+                None => return,
+            },
+            _ = ticks.tick() => None,
+        };
+        if !batch.is_empty() {
+            let body = request(&batch);
+            batch.clear();
+            match send(&client, &endpoint, body).await {
+                Ok(()) => failing = false,
+                // Warning each time would flood the log while the collector is down
+                Err(err) if !failing => {
+                    warn!("Can't export traces to {endpoint}: {err:#}");
+                    failing = true;
+                }
+                Err(_) => {}
+            }
+        }
+        if let Some(done) = flushed {
+            let _ = done.send(());
+        }
+    }
+}
+
+async fn send(client: &reqwest::Client, endpoint: &str, body: Value) -> Result<()> {
+    client
+        .post(endpoint)
+        .header("content-type", "application/json")
+        .body(body.to_string())
+        .send()
+        .await?
+        .error_for_status()?;
+    Ok(())
+}
+
+/// An OTLP/JSON `ExportTraceServiceRequest` with `spans`
+fn request(spans: &[SpanData]) -> Value {
+    json!({
+        "resourceSpans": [{
+            "resource": {
+                "attributes": attributes(&[("service.name", SERVICE_NAME.into())]),
+            },
+            "scopeSpans": [{
+                "scope": {
+                    "name": SERVICE_NAME,
+                    "version": env!("CARGO_PKG_VERSION"),
+                },
+                "spans": spans.iter().map(span).collect::<Vec<_>>(),
+            }],
+        }],
+    })
+}
+
+fn span(span: &SpanData) -> Value {
+    let mut value = json!({
+        "traceId": hex(&span.trace_id),
+        "spanId": hex(&span.span_id),
+        "name": span.name,
+        // Internal
+        "kind": 1,
+        "startTimeUnixNano": unix_nanos(span.start),
+        "endTimeUnixNano": unix_nanos(span.end),
+        "attributes": attributes(&span.attributes),
+        "events": span.events.iter().map(|event| json!({
+            "timeUnixNano": unix_nanos(event.time),
+            "name": event.message,
+            "attributes": attributes(&event.attributes),
+        })).collect::<Vec<_>>(),
+        "status": match &span.error {
+            Some(message) => json!({ "code": 2, "message": message }),
+            None => json!({ "code": 0 }),
+        },
+    });
+    if let Some(parent_id) = &span.parent_id {
+        value["parentSpanId"] = hex(parent_id).into();
+    }
+    value
+}
+
+/// OTLP key-value pairs, where integers are strings as JSON can't hold every 64 bit one
+fn attributes(attributes: &[(&'static str, Value)]) -> Value {
+    attributes
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                Value::Bool(value) => json!({ "boolValue": value }),
+                Value::Number(number) if number.is_f64() => json!({ "doubleValue": number }),
+                Value::Number(number) => json!({ "intValue": number.to_string() }),
+                Value::String(value) => json!({ "stringValue": value }),
+                value => json!({ "stringValue": value.to_string() }),
+            };
+            json!({ "key": key, "value": value })
+        })
+        .collect()
+}
+
+fn unix_nanos(time: SystemTime) -> String {
+    time.duration_since(UNIX_EPOCH)
+        .unwrap_or_default()
+        .as_nanos()
+        .to_string()
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
@@ -0,0 +1,573 @@
+use anyhow::{anyhow, Result};
+use futures::{future, stream, stream::FuturesUnordered, StreamExt};
+use std::{
+    cmp::Reverse,
+    collections::{BinaryHeap, HashMap, HashSet},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU32, AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
+};
+use tokio::{io, sync::mpsc, time::Instant};
+use tracing::{debug, debug_span, info, Instrument};
+
+use crate::{
+    error::Operation,
+    moves::Moved,
+    priority::CopyOrder,
+    scan::{Found, Scanner},
+    syncer::{
+        SyncContext, SyncEvent, SyncOptions, DEFAULT_INTERVAL, DEFAULT_MAX_CONCURRENT_COPIES,
+        DEFAULT_MAX_PENDING,
+    },
+};
+
+/// Scans only back off once this many in a row found nothing to sync
+const IDLE_SCANS: u32 = 3;
+
+/// How long to wait between scans of work_dir and between checks of each file. The wait
+/// doubles while nothing changes, up to [`SyncOptions::max_interval`], and goes back to
+/// [`SyncOptions::interval`] as soon as something does
+#[derive(Debug)]
+pub(crate) struct Schedule {
+    interval: Duration,
+    max_interval: Duration,
+    /// In milliseconds
+    current: AtomicU64,
+    idle_scans: AtomicU32,
+}
+
+impl Schedule {
+    pub fn new(options: &SyncOptions) -> Self {
+        let interval = options.interval.unwrap_or(DEFAULT_INTERVAL);
+        let max_interval = options.max_interval.unwrap_or(interval * 4).max(interval);
+        Self {
+            interval,
+            max_interval,
+            current: AtomicU64::new(interval.as_millis() as u64),
+            idle_scans: AtomicU32::new(0),
+        }
+    }
+
+    /// How long to wait now
+    pub fn interval(&self) -> Duration {
+        Duration::from_millis(self.current.load(Ordering::Relaxed))
+    }
+
+    /// Records whether the scan that just finished found anything to sync
+    pub fn scanned(&self, changed: bool) {
+        if changed {
+            self.idle_scans.store(0, Ordering::Relaxed);
+            self.current
+                .store(self.interval.as_millis() as u64, Ordering::Relaxed);
+            return;
+        }
+
+        if self.idle_scans.fetch_add(1, Ordering::Relaxed) + 1 < IDLE_SCANS {
+            return;
+        }
+        let current = self.interval();
+        let backed_off = (current * 2).min(self.max_interval);
+        if backed_off != current {
+            debug!("Nothing changed for a while, scanning every {backed_off:?}");
+            self.current
+                .store(backed_off.as_millis() as u64, Ordering::Relaxed);
+        }
+    }
+}
+
+/// How many files are checked for changes at once, which only matters when that reads them.
+/// More if there are more hashing threads to keep busy
+const CONCURRENT_CHECKS: usize = 16;
+
+/// How many changed files can wait for a copy worker before checking waits too
+const COPY_QUEUE: usize = 256;
+
+/// Copies files to backup_dir by periodically scanning work_dir for changes. Every scan
+/// streams what it finds through checking each file against what the detector saw last,
+/// and the files that changed on to a bounded number of copy workers
+pub(crate) async fn copy_files(ctx: Arc<SyncContext>) -> Result<()> {
+    let SyncContext {
+        work_dir,
+        options,
+        vfs,
+        ..
+    } = &*ctx;
+
+    info!("Watching for file changes...");
+
+    // Every file found so far, with the last scan that found it
+    let mut tracked: HashMap<Arc<Path>, u64> = HashMap::new();
+    // The directories seen by the last scan, `None` until the first one
+    let mut dirs: Option<HashSet<PathBuf>> = None;
+    let mut first_scan = true;
+    let scanner = Arc::new(Scanner::new(
+        vfs.clone(),
+        work_dir,
+        &options.ignore,
+        options.copy.symlinks,
+    ));
+    let mut scan = 0;
+    let concurrent_checks = CONCURRENT_CHECKS.max(options.hash_pool.threads() * 2);
+
+    let (changed, copy_queue) =
+        mpsc::channel::<(CopyOrder, Arc<Path>, std::time::Instant)>(COPY_QUEUE);
+    let copying = tokio::task::spawn(copy_changes(ctx.clone(), copy_queue).in_current_span());
+    let mut behind = false;
+
+    loop {
+        if ctx.is_paused() && !ctx.is_shutting_down() {
+            if ctx.wait(ctx.interval()).await {
+                ctx.sweep_and_report(true).await;
+                ctx.end_cycle().await;
+            }
+            continue;
+        }
+
+        // Scanning while copies are behind only finds the same changes again
+        let pending = ctx.metrics.queue_depth();
+        let max_pending = options.max_pending.unwrap_or(DEFAULT_MAX_PENDING);
+        if pending > max_pending && !ctx.is_shutting_down() {
+            if !behind {
+                info!(
+                    "{pending} files are waiting to be copied, skipping scans until \
+                     {max_pending} or fewer are"
+                );
+                behind = true;
+            }
+            if ctx.wait(ctx.interval()).await {
+                ctx.sweep_and_report(true).await;
+                ctx.end_cycle().await;
+            }
+            continue;
+        }
+        if std::mem::take(&mut behind) {
+            info!("Copies are catching up, scanning again");
+        }
+
+        let start = Instant::now();
+        scan += 1;
+        let mut files = 0;
+        let mut seen_dirs = HashSet::new();
+        let mut unreadable = Vec::new();
+        let (mut found, scanning) = scanner.scan();
+
+        async {
+            let found = stream::poll_fn(|cx| found.poll_recv(cx))
+                .filter_map(|found| {
+                    let path = match found {
+                        Found::File(path) => Arc::<Path>::from(path),
+                        Found::Dir(dir) => {
+                            if options.syncs_dirs() {
+                                seen_dirs.insert(dir);
+                            }
+                            return future::ready(None);
+                        }
+                        Found::Unreadable(path) => {
+                            unreadable.push(path);
+                            return future::ready(None);
+                        }
+                    };
+                    files += 1;
+                    tracked.insert(path.clone(), scan);
+                    future::ready(Some(path))
+                })
+                .map(|path| async {
+                    let path = check_file(&ctx, path, first_scan).await?;
+                    Some((ctx.copy_order(&path).await, path, ctx.metrics.enqueue()))
+                })
+                .buffer_unordered(concurrent_checks)
+                .filter_map(future::ready);
+            futures::pin_mut!(found);
+            while let Some(found) = found.next().await {
+                // Only fails once the copy workers are gone, which they aren't before this ends
+                let _ = changed.send(found).await;
+            }
+        }
+        .instrument(debug_span!("scan", scan))
+        .await;
+
+        // Whatever couldn't be read would look deleted, and so would whatever a scan that
+        // died didn't get to
+        let scanned = match scanning.await {
+            Ok(()) => ctx.options.ignore.walk_errors().check(),
+            Err(err) => Err(anyhow!("Scanning {} failed: {err}", work_dir.display())),
+        };
+        if let Err(err) = scanned {
+            drop(changed);
+            let _ = copying.await;
+            return Err(err);
+        }
+
+        // Anything we were tracking that didn't show up in the scan has been deleted, unless
+        // it's in a directory that couldn't be read this time
+        let is_unreadable = |path: &Path| unreadable.iter().any(|dir| path.starts_with(dir));
+        let mut removed = Vec::new();
+        tracked.retain(|path, seen| {
+            if *seen != scan && is_unreadable(path) {
+                *seen = scan;
+            }
+            if *seen != scan {
+                removed.push(path.to_path_buf());
+            }
+            *seen == scan
+        });
+        for path in removed {
+            ctx.schedule_removal(path);
+        }
+
+        if options.syncs_dirs() {
+            match &dirs {
+                // Whatever changed while we weren't running
+                None => {
+                    if let Err(error) = ctx.sync_dirs(false).await {
+                        let path = work_dir.clone();
+                        ctx.emit_error(Operation::Scan, path, error);
+                    }
+                }
+                Some(dirs) => {
+                    seen_dirs.extend(dirs.iter().filter(|dir| is_unreadable(dir)).cloned());
+                    for dir in seen_dirs.difference(dirs) {
+                        ctx.sync_dir(dir).await;
+                    }
+                    for dir in dirs.difference(&seen_dirs) {
+                        ctx.schedule_dir_removal(dir.clone());
+                    }
+                }
+            }
+            dirs = Some(seen_dirs);
+        }
+
+        first_scan = false;
+        ctx.metrics.scanned(files, start.elapsed());
+        let changed = ctx.end_cycle().await;
+        ctx.schedule.scanned(changed);
+
+        if ctx.wait(ctx.interval()).await {
+            ctx.sweep_and_report(true).await;
+            ctx.end_cycle().await;
+        }
+
+        if ctx.is_shutting_down() {
+            break;
+        }
+    }
+
+    // The copies that are queued still finish
+    drop(changed);
+    let _ = copying.await;
+    Ok(())
+}
+
+/// `path` if it needs copying. Files that were there before the first scan were just
+/// initialized or swept, so only changes from here on get copied, unless they were moved
+async fn check_file(
+    ctx: &Arc<SyncContext>,
+    path: Arc<Path>,
+    before_first_scan: bool,
+) -> Option<Arc<Path>> {
+    if !ctx.detector.knows(&path) {
+        let moved = match ctx.move_backup(&path).await {
+            Some(Moved::From(from)) => {
+                let to = path.to_path_buf();
+                ctx.emit(SyncEvent::Renamed { from, to });
+                true
+            }
+            Some(Moved::Already) => true,
+            None => false,
+        };
+        if moved || before_first_scan {
+            if let Err(err) = ctx.observe_file(&path).await {
+                report_check_failed(ctx, &path, err).await;
+            }
+            return None;
+        }
+    }
+
+    match ctx.file_changed(&path).await {
+        Ok(changed) => changed.then_some(path),
+        Err(err) => {
+            report_check_failed(ctx, &path, err).await;
+            None
+        }
+    }
+}
+
+/// Copies the files that changed, a bounded number at a time, until the queue is closed.
+/// Up to [`COPY_QUEUE`] of them are taken off the queue as they come in, and the one that
+/// goes first by [`CopyOrder`] is copied whenever a worker is free
+async fn copy_changes(
+    ctx: Arc<SyncContext>,
+    mut queue: mpsc::Receiver<(CopyOrder, Arc<Path>, std::time::Instant)>,
+) {
+    let workers = ctx
+        .options
+        .max_concurrent_copies
+        .unwrap_or(DEFAULT_MAX_CONCURRENT_COPIES)
+        .max(1);
+    // A file that changes again while it's copied waits for the next scan, so the copy that
+    // is running isn't raced
+    let copying = Mutex::new(HashSet::new());
+    // Files of the same order are copied in the order they were found
+    let mut waiting = BinaryHeap::new();
+    let mut found = 0u64;
+    let mut copies = FuturesUnordered::new();
+    let mut open = true;
+    loop {
+        while open && waiting.len() < COPY_QUEUE {
+            match queue.try_recv() {
+                Ok((order, path, queued)) => {
+                    waiting.push((order, Reverse(found), path, queued));
+                    found += 1;
+                }
+                Err(mpsc::error::TryRecvError::Empty) => break,
+                Err(mpsc::error::TryRecvError::Disconnected) => open = false,
+            }
+        }
+        if copies.len() < workers {
+            if let Some((_, _, path, queued)) = waiting.pop() {
+                // From here on it's queued for its copy slot instead
+                ctx.metrics.dequeue(queued);
+                copies.push(copy_change(&ctx, &copying, path));
+                continue;
+            }
+        }
+        tokio::select! {
+            Some(()) = copies.next(), if !copies.is_empty() => {}
+            next = queue.recv(), if open && waiting.len() < COPY_QUEUE => match next {
+                Some((order, path, queued)) => {
+                    waiting.push((order, Reverse(found), path, queued));
+                    found += 1;
+                }
+                None => open = false,
+            },
+            else => break,
+        }
+    }
+}
+
+async fn copy_change(ctx: &Arc<SyncContext>, copying: &Mutex<HashSet<Arc<Path>>>, path: Arc<Path>) {
+    if !copying.lock().unwrap().insert(path.clone()) {
+        // Forgetting it makes the next scan count it as changed
+        ctx.detector.forget(&path);
+        return;
+    }
+    ctx.sync_file(path.to_path_buf()).await;
+    copying.lock().unwrap().remove(&path);
+}
+
+/// Whether `err` comes from a file that doesn't exist, like one that vanished since the walk
+fn is_not_found(err: &anyhow::Error) -> bool {
+    err.chain().any(|cause| {
+        cause
+            .downcast_ref::<io::Error>()
+            .is_some_and(|err| err.kind() == io::ErrorKind::NotFound)
+    })
+}
+
+/// Logs that checking `path` for changes failed. Files that vanished since the walk aren't
+/// worth more than a debug line, the next scan notices that they're gone, and ones that
+/// can't be read are skipped
+async fn report_check_failed(ctx: &SyncContext, path: &Path, err: anyhow::Error) {
+    if ctx.skip_unreadable(path, &err).await {
+        return;
+    }
+    match is_not_found(&err) {
+        true => debug!(path = %path.display(), "Vanished before it could be checked"),
+        false => ctx.emit_error(
+            Operation::Scan,
+            path.to_path_buf(),
+            err.context("Error checking for changes"),
+        ),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        backend::{Backend, FileMetadata},
+        copy::CopyOptions,
+        syncer::Shared,
+        vfs::{MemoryFs, Vfs},
+    };
+    use async_trait::async_trait;
+    use std::{collections::BTreeMap, fmt};
+    use tokio_util::sync::CancellationToken;
+
+    const WORK: &str = "/work";
+    const BACKUP: &str = "/backup";
+
+    /// A backup in `/backup` of the same [`MemoryFs`] work_dir is in
+    #[derive(Debug)]
+    struct MemoryBackend(Arc<MemoryFs>);
+
+    impl fmt::Display for MemoryBackend {
+        fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+            write!(f, "memory:{BACKUP}")
+        }
+    }
+
+    #[async_trait]
+    impl Backend for MemoryBackend {
+        async fn put(&self, relative_path: &Path, source: &Path) -> Result<()> {
+            let destination = Path::new(BACKUP).join(relative_path);
+            self.0
+                .copy(source, &destination, &CopyOptions::default())
+                .await?;
+            Ok(())
+        }
+
+        async fn get(&self, relative_path: &Path, destination: &Path) -> Result<()> {
+            let source = Path::new(BACKUP).join(relative_path);
+            self.0
+                .copy(&source, destination, &CopyOptions::default())
+                .await?;
+            Ok(())
+        }
+
+        async fn delete(&self, relative_path: &Path) -> Result<()> {
+            self.0.remove(&Path::new(BACKUP).join(relative_path)).await
+        }
+
+        async fn list(&self) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+            let mut files = BTreeMap::new();
+            for relative_path in self.0.files(BACKUP).into_keys() {
+                if let Some(metadata) = self.metadata(&relative_path).await? {
+                    files.insert(relative_path, metadata);
+                }
+            }
+            Ok(files)
+        }
+
+        async fn metadata(&self, relative_path: &Path) -> Result<Option<FileMetadata>> {
+            let entry = self
+                .0
+                .metadata(&Path::new(BACKUP).join(relative_path))
+                .await?;
+            Ok(entry.map(|entry| FileMetadata {
+                size: entry.size,
+                modified: entry.modified,
+            }))
+        }
+    }
+
+    /// Waits for `done`, failing the test if that takes too long
+    async fn wait_until(mut done: impl FnMut() -> bool) {
+        let waiting = async {
+            while !done() {
+                tokio::time::sleep(Duration::from_millis(5)).await;
+            }
+        };
+        tokio::time::timeout(Duration::from_secs(10), waiting)
+            .await
+            .expect("Timed out");
+    }
+
+    fn backup(vfs: &MemoryFs) -> BTreeMap<PathBuf, Vec<u8>> {
+        vfs.files(BACKUP)
+    }
+
+    fn files<const N: usize>(files: [(&str, &str); N]) -> BTreeMap<PathBuf, Vec<u8>> {
+        files
+            .into_iter()
+            .map(|(path, contents)| (PathBuf::from(path), contents.into()))
+            .collect()
+    }
+
+    #[tokio::test]
+    async fn copies_what_scans_of_memory_find() {
+        let vfs = Arc::new(MemoryFs::new());
+        vfs.write("/work/unchanged", "one");
+        vfs.write("/work/dir/modified", "two");
+        vfs.write("/work/removed", "three");
+        vfs.create_dir_all(BACKUP);
+
+        let options = SyncOptions {
+            interval: Some(Duration::from_millis(10)),
+            delete_after: Some(Duration::ZERO),
+            ..SyncOptions::default()
+        };
+        let (events, _events) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let ctx = Arc::new(SyncContext::new(
+            WORK.into(),
+            Arc::new(MemoryBackend(vfs.clone())),
+            vfs.clone(),
+            options,
+            events,
+            shutdown.clone(),
+            &Shared::default(),
+        ));
+        let polling = tokio::spawn(copy_files(ctx.clone()));
+
+        // The first scan only takes note of what's there, which was just initialized
+        let known = ["/work/unchanged", "/work/dir/modified", "/work/removed"];
+        wait_until(|| known.iter().all(|path| ctx.detector.knows(Path::new(path)))).await;
+        assert!(backup(&vfs).is_empty());
+
+        vfs.write("/work/dir/modified", "four");
+        vfs.write("/work/dir/nested/added", "five");
+        wait_until(|| backup(&vfs).len() == 2).await;
+        assert_eq!(
+            backup(&vfs),
+            files([("dir/modified", "four"), ("dir/nested/added", "five")])
+        );
+
+        // Deleting it from work_dir deletes its backup
+        vfs.write("/work/removed", "six");
+        wait_until(|| backup(&vfs).contains_key(Path::new("removed"))).await;
+        vfs.remove(Path::new("/work/removed")).await.unwrap();
+        wait_until(|| !backup(&vfs).contains_key(Path::new("removed"))).await;
+
+        shutdown.cancel();
+        polling.await.unwrap().unwrap();
+        assert_eq!(
+            backup(&vfs),
+            files([("dir/modified", "four"), ("dir/nested/added", "five")])
+        );
+    }
+
+    #[tokio::test]
+    async fn keeps_the_backups_of_what_cant_be_read() {
+        let vfs = Arc::new(MemoryFs::new());
+        vfs.write("/work/locked/file", "one");
+        vfs.write("/backup/locked/file", "one");
+
+        let options = SyncOptions {
+            interval: Some(Duration::from_millis(10)),
+            delete_after: Some(Duration::ZERO),
+            ..SyncOptions::default()
+        };
+        let walk_errors = options.ignore.walk_errors().clone();
+        let (events, _events) = mpsc::unbounded_channel();
+        let shutdown = CancellationToken::new();
+        let ctx = Arc::new(SyncContext::new(
+            WORK.into(),
+            Arc::new(MemoryBackend(vfs.clone())),
+            vfs.clone(),
+            options,
+            events,
+            shutdown.clone(),
+            &Shared::default(),
+        ));
+        let polling = tokio::spawn(copy_files(ctx.clone()));
+        wait_until(|| ctx.detector.knows(Path::new("/work/locked/file"))).await;
+
+        // A few scans that can't look inside
+        vfs.deny("/work/locked");
+        wait_until(|| walk_errors.count() >= 3).await;
+        assert!(ctx.detector.knows(Path::new("/work/locked/file")));
+        assert_eq!(backup(&vfs), files([("locked/file", "one")]));
+
+        // Once it's readable again, a deletion is one
+        vfs.allow("/work/locked");
+        vfs.remove(Path::new("/work/locked/file")).await.unwrap();
+        wait_until(|| backup(&vfs).is_empty()).await;
+
+        shutdown.cancel();
+        polling.await.unwrap().unwrap();
+    }
+}
@@ -0,0 +1,119 @@
+//! Whether the machine runs on battery or over a metered connection, so syncing can pause or
+//! slow down to save power and data. See
+//! [`SyncOptions::pause_on_battery`](crate::SyncOptions::pause_on_battery) and
+//! [`SyncOptions::battery_interval`](crate::SyncOptions::battery_interval)
+
+use std::{fmt, time::Duration};
+
+/// How often the power and network state is looked at while syncing
+pub const POWER_CHECK_INTERVAL: Duration = Duration::from_secs(30);
+
+/// What the platform says about power and the network. Anything that can't be told counts
+/// as plugged in and unmetered
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub struct PowerState {
+    pub on_battery: bool,
+    pub metered: bool,
+}
+
+impl PowerState {
+    /// Looks at the current state. This does blocking IO, and may run a command
+    pub fn current() -> Self {
+        Self {
+            on_battery: on_battery(),
+            metered: metered(),
+        }
+    }
+
+    /// Whether syncing should back off
+    pub fn constrained(&self) -> bool {
+        self.on_battery || self.metered
+    }
+}
+
+impl fmt::Display for PowerState {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match (self.on_battery, self.metered) {
+            (false, false) => "on AC power",
+            (true, false) => "on battery",
+            (false, true) => "on a metered connection",
+            (true, true) => "on battery and a metered connection",
+        })
+    }
+}
+
+/// Whether a system battery is discharging. Batteries of mice and other devices have a
+/// scope of `Device` and don't count
+#[cfg(any(target_os = "linux", target_os = "android"))]
+fn on_battery() -> bool {
+    let Ok(supplies) = std::fs::read_dir("/sys/class/power_supply") else {
+        return false;
+    };
+    let read = |supply: &std::path::Path, name: &str| {
+        std::fs::read_to_string(supply.join(name))
+            .map(|value| value.trim().to_string())
+            .unwrap_or_default()
+    };
+    supplies.flatten().any(|supply| {
+        let supply = supply.path();
+        read(&supply, "type") == "Battery"
+            && read(&supply, "scope") != "Device"
+            && read(&supply, "status") == "Discharging"
+    })
+}
+
+#[cfg(target_os = "macos")]
+fn on_battery() -> bool {
+    std::process::Command::new("pmset")
+        .args(["-g", "batt"])
+        .output()
+        .is_ok_and(|output| String::from_utf8_lossy(&output.stdout).contains("'Battery Power'"))
+}
+
+#[cfg(windows)]
+fn on_battery() -> bool {
+    use windows_sys::Win32::System::Power::{GetSystemPowerStatus, SYSTEM_POWER_STATUS};
+
+    // SAFETY: an all zero SYSTEM_POWER_STATUS is valid, and it's only written to
+    let mut status: SYSTEM_POWER_STATUS = unsafe { std::mem::zeroed() };
+    // SAFETY: status is a valid pointer for the duration of the call
+    unsafe { GetSystemPowerStatus(&mut status) != 0 && status.ACLineStatus == 0 }
+}
+
+#[cfg(not(any(
+    target_os = "linux",
+    target_os = "android",
+    target_os = "macos",
+    windows
+)))]
+fn on_battery() -> bool {
+    false
+}
+
+/// Asks NetworkManager whether the primary connection is metered, which it also guesses for
+/// tethering to a phone
+#[cfg(target_os = "linux")]
+fn metered() -> bool {
+    let output = std::process::Command::new("busctl")
+        .args([
+            "get-property",
+            "org.freedesktop.NetworkManager",
+            "/org/freedesktop/NetworkManager",
+            "org.freedesktop.NetworkManager",
+            "Metered",
+        ])
+        .stderr(std::process::Stdio::null())
+        .output();
+    // Prints `u 1`, with 1 for yes and 3 for a guessed yes
+    output.is_ok_and(|output| {
+        matches!(
+            String::from_utf8_lossy(&output.stdout).trim(),
+            "u 1" | "u 3"
+        )
+    })
+}
+
+#[cfg(not(target_os = "linux"))]
+fn metered() -> bool {
+    false
+}
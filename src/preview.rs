@@ -0,0 +1,256 @@
+//! `evil_mount restore --preview`, which shows what restoring would overwrite in work_dir,
+//! and `restore --interactive PATH...`, which asks about every file after showing it.
+//!
+//! Text files are shown as a unified diff from the copy in work_dir to the one restored, and
+//! binary files as their sizes and hashes. Restoring from a snapshot also compares the copy
+//! in work_dir with the current backup, the third version, to tell whether restoring loses
+//! anything that isn't backed up anywhere else
+
+use anyhow::{anyhow, Result};
+use evil_mount::Syncer;
+use indicatif::HumanBytes;
+use std::{
+    io::{IsTerminal, Write},
+    path::PathBuf,
+};
+
+use crate::browse::{diff, text_lines, Change, CONTEXT_LINES};
+
+/// How restoring one file would change work_dir
+struct FilePreview {
+    relative_path: PathBuf,
+    /// The contents it's restored with
+    restored: Vec<u8>,
+    /// `None` if it isn't in work_dir
+    work: Option<Vec<u8>>,
+    /// Whether the copy in work_dir is the one in the current backup, when restoring from a
+    /// snapshot
+    in_backup: Option<bool>,
+}
+
+/// Prints what restoring `paths` of `syncer`, or everything without any, would change
+pub async fn print_preview(syncer: &Syncer, at: Option<&str>, paths: &[PathBuf]) -> Result<()> {
+    let mut unchanged = 0;
+    for relative_path in restorable(syncer, at, paths).await? {
+        let preview = FilePreview::read(syncer, at, relative_path).await?;
+        match preview.is_unchanged() {
+            true => unchanged += 1,
+            false => preview.print(at),
+        }
+    }
+    if unchanged > 0 {
+        println!("{unchanged} files are the same in work_dir and aren't shown");
+    }
+    Ok(())
+}
+
+/// Shows what restoring each of `paths`, or of everything without any, would change and asks
+/// whether to. Returns the files to restore
+pub async fn ask_each(
+    syncer: &Syncer,
+    at: Option<&str>,
+    paths: &[PathBuf],
+) -> Result<Vec<PathBuf>> {
+    if !std::io::stdin().is_terminal() {
+        return Err(anyhow!(
+            "restore --interactive needs a terminal to ask about each file, pass --preview to \
+             only show them"
+        ));
+    }
+
+    let mut accepted = Vec::new();
+    let mut all = false;
+    for relative_path in restorable(syncer, at, paths).await? {
+        let preview = FilePreview::read(syncer, at, relative_path).await?;
+        if preview.is_unchanged() {
+            continue;
+        }
+        preview.print(at);
+        if all {
+            accepted.push(preview.relative_path);
+            continue;
+        }
+        loop {
+            print!(
+                "Restore {}? [y]es, [n]o, [a]ll the rest, [q]uit without the rest ",
+                preview.relative_path.display()
+            );
+            std::io::stdout().flush()?;
+
+            let mut answer = String::new();
+            if std::io::stdin().read_line(&mut answer)? == 0 {
+                return Err(anyhow!("Restoring cancelled"));
+            }
+            match answer.trim() {
+                "y" | "Y" | "yes" => accepted.push(preview.relative_path.clone()),
+                "n" | "N" | "no" => {}
+                "a" | "A" | "all" => {
+                    accepted.push(preview.relative_path.clone());
+                    all = true;
+                }
+                "q" | "Q" | "quit" => return Ok(accepted),
+                _ => continue,
+            }
+            break;
+        }
+    }
+    Ok(accepted)
+}
+
+/// The files restoring `paths` would restore, which have to be in the backup
+async fn restorable(syncer: &Syncer, at: Option<&str>, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    let files = syncer.restorable_files(at).await?;
+    if paths.is_empty() {
+        return Ok(files.into_keys().collect());
+    }
+    let mut selected = Vec::new();
+    for path in paths {
+        let before = selected.len();
+        selected.extend(
+            files
+                .keys()
+                .filter(|relative_path| relative_path.starts_with(path))
+                .cloned(),
+        );
+        if selected.len() == before {
+            return Err(anyhow!("{} isn't in the backup", path.display()));
+        }
+    }
+    selected.sort();
+    selected.dedup();
+    Ok(selected)
+}
+
+impl FilePreview {
+    async fn read(syncer: &Syncer, at: Option<&str>, relative_path: PathBuf) -> Result<Self> {
+        let restored = syncer.read_backup(at, &relative_path).await?;
+        let work_path = syncer.work_dir().join(&relative_path);
+        let work = match tokio::fs::read(&work_path).await {
+            Ok(work) => Some(work),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => {
+                return Err(anyhow!("Error reading {}: {err}", work_path.display()));
+            }
+        };
+        // Only worth reading when restoring could lose it
+        let in_backup = match (at, &work) {
+            (Some(_), Some(work)) if *work != restored => Some(
+                syncer
+                    .read_backup(None, &relative_path)
+                    .await
+                    .is_ok_and(|backup| backup == *work),
+            ),
+            _ => None,
+        };
+        Ok(Self {
+            relative_path,
+            restored,
+            work,
+            in_backup,
+        })
+    }
+
+    fn is_unchanged(&self) -> bool {
+        self.work.as_ref() == Some(&self.restored)
+    }
+
+    fn print(&self, at: Option<&str>) {
+        let path = self.relative_path.display();
+        let source = match at {
+            Some(at) => format!("snapshot {at}"),
+            None => "backup".to_string(),
+        };
+        match (&self.work, self.in_backup) {
+            (None, _) => println!("{path}: not in work_dir, restoring brings it back"),
+            (Some(_), Some(true)) => println!(
+                "{path}: the copy in work_dir is in the current backup, restoring the {source} \
+                 doesn't lose it"
+            ),
+            (Some(_), Some(false)) => println!(
+                "{path}: the copy in work_dir isn't in the current backup, restoring the \
+                 {source} loses it"
+            ),
+            (Some(_), None) => println!("{path}: restoring overwrites the copy in work_dir"),
+        }
+
+        let old = match &self.work {
+            Some(work) => text_lines(work),
+            None => Some(Vec::new()),
+        };
+        let (Some(old), Some(new)) = (old, text_lines(&self.restored)) else {
+            let describe = |bytes: &[u8]| {
+                let hash = blake3::hash(bytes).to_hex();
+                format!("{}, BLAKE3 {}", HumanBytes(bytes.len() as u64), &hash[..16])
+            };
+            println!("Binary file");
+            println!("  {source}: {}", describe(&self.restored));
+            match &self.work {
+                Some(work) => println!("  work_dir: {}", describe(work)),
+                None => println!("  work_dir: missing"),
+            }
+            println!();
+            return;
+        };
+
+        match &self.work {
+            Some(_) => println!("--- work_dir/{path}"),
+            None => println!("--- /dev/null"),
+        }
+        println!("+++ {source}/{path}");
+        match diff(&old, &new) {
+            Some(changes) => print_hunks(&changes),
+            None => println!("Too many changes to show"),
+        }
+        println!();
+    }
+}
+
+/// Prints `changes` as the hunks of a unified diff, with [`CONTEXT_LINES`] of unchanged
+/// lines around every change
+fn print_hunks(changes: &[(Change, &str)]) {
+    let changed: Vec<usize> = (0..changes.len())
+        .filter(|&index| changes[index].0 != Change::Same)
+        .collect();
+    // Changes this close together share a hunk
+    let mut hunks: Vec<(usize, usize)> = Vec::new();
+    for &index in &changed {
+        let start = index.saturating_sub(CONTEXT_LINES);
+        let end = (index + CONTEXT_LINES + 1).min(changes.len());
+        match hunks.last_mut() {
+            Some(last) if start <= last.1 => last.1 = end,
+            _ => hunks.push((start, end)),
+        }
+    }
+
+    let lines = |range: &[(Change, &str)], skip: Change| {
+        range.iter().filter(|(change, _)| *change != skip).count()
+    };
+    for (start, end) in hunks {
+        let (old_start, new_start) = (
+            lines(&changes[..start], Change::Added),
+            lines(&changes[..start], Change::Removed),
+        );
+        let (old_len, new_len) = (
+            lines(&changes[start..end], Change::Added),
+            lines(&changes[start..end], Change::Removed),
+        );
+        // Ranges without lines start at the line before them
+        let from = |start: usize, len: usize| match len {
+            0 => start,
+            _ => start + 1,
+        };
+        println!(
+            "@@ -{},{old_len} +{},{new_len} @@",
+            from(old_start, old_len),
+            from(new_start, new_len)
+        );
+        for (change, line) in &changes[start..end] {
+            let marker = match change {
+                Change::Same => ' ',
+                Change::Removed => '-',
+                Change::Added => '+',
+            };
+            println!("{marker}{line}");
+        }
+    }
+}
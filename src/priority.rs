@@ -0,0 +1,87 @@
+//! Which of the files that changed at once are copied first, see
+//! [`SyncOptions::priorities`](crate::SyncOptions::priorities).
+//!
+//! Every file gets the priority of the last rule whose glob matches it, or 0. Files with a
+//! higher priority are copied first, and smaller ones before bigger ones of the same
+//! priority, so a change to the source code doesn't wait behind a pile of build artifacts
+
+use anyhow::{anyhow, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{cmp::Reverse, path::Path};
+
+/// A priority and the files it applies to
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: Gitignore,
+    priority: i32,
+}
+
+/// The priorities of files matching their globs, where later rules win
+#[derive(Debug, Clone, Default)]
+pub struct Priorities {
+    rules: Vec<Rule>,
+}
+
+impl Priorities {
+    /// Parses rules like `src/**=10` or `*.o=-5`, a glob and a priority
+    pub fn parse(rules: &[String]) -> Result<Self> {
+        let mut priorities = Self::default();
+        for rule in rules {
+            let (glob, priority) = rule
+                .rsplit_once('=')
+                .ok_or_else(|| anyhow!("Invalid priority {rule}, expected GLOB=PRIORITY"))?;
+            let priority = priority
+                .trim()
+                .parse()
+                .map_err(|_| anyhow!("Invalid priority {rule}, {priority} isn't a number"))?;
+            priorities.add(glob, priority)?;
+        }
+        Ok(priorities)
+    }
+
+    /// Gives the files matching `glob` `priority`, over the rules added before
+    pub fn add(&mut self, glob: &str, priority: i32) -> Result<()> {
+        // Using `.` as the root disables prefix stripping, every path we match is relative
+        let mut builder = GitignoreBuilder::new(".");
+        builder
+            .add_line(None, glob)
+            .map_err(|err| anyhow!("Invalid priority pattern {glob}: {err}"))?;
+        let matcher = builder
+            .build()
+            .map_err(|err| anyhow!("Error building priority pattern {glob}: {err}"))?;
+        self.rules.push(Rule { matcher, priority });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// The priority of `relative_path`, 0 unless a rule matches it
+    pub fn of(&self, relative_path: &Path) -> i32 {
+        self.rules
+            .iter()
+            .rev()
+            .find(|rule| {
+                rule.matcher
+                    .matched_path_or_any_parents(relative_path, false)
+                    .is_ignore()
+            })
+            .map_or(0, |rule| rule.priority)
+    }
+
+    /// Where `relative_path`, `size` bytes big, goes among the files waiting to be copied
+    pub(crate) fn order(&self, relative_path: &Path, size: u64) -> CopyOrder {
+        CopyOrder {
+            priority: self.of(relative_path),
+            smaller: Reverse(size),
+        }
+    }
+}
+
+/// The order files are copied in, the greatest first
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord)]
+pub(crate) struct CopyOrder {
+    priority: i32,
+    smaller: Reverse<u64>,
+}
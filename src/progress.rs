@@ -0,0 +1,105 @@
+//! Progress bars for initialization, which can copy a lot of data before syncing starts
+
+use anyhow::Result;
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use std::{future::Future, path::Path, time::Duration};
+
+use crate::copy::temp_path_for;
+
+/// Files at least this big get a progress bar of their own unless
+/// [`SyncOptions::file_progress_threshold`](crate::SyncOptions::file_progress_threshold)
+/// says otherwise
+pub const DEFAULT_FILE_PROGRESS_THRESHOLD: u64 = 64 * 1024 * 1024;
+
+/// How often the bar of a large file is updated
+const FILE_PROGRESS_INTERVAL: Duration = Duration::from_millis(100);
+
+/// Shows how much of an initialization has been copied, along with a bar for every large
+/// file while it is being copied. Nothing is drawn when stderr isn't a terminal
+pub(crate) struct InitProgress {
+    bars: MultiProgress,
+    total: ProgressBar,
+    files: u64,
+    files_done: u64,
+    file_threshold: u64,
+}
+
+impl InitProgress {
+    /// Progress of copying `files` files adding up to `bytes`. Hidden unless `enabled`
+    pub fn new(enabled: bool, files: u64, bytes: u64, file_threshold: u64) -> Self {
+        let bars = MultiProgress::with_draw_target(match enabled {
+            true => ProgressDrawTarget::stderr(),
+            false => ProgressDrawTarget::hidden(),
+        });
+        let total = bars.add(
+            ProgressBar::new(bytes).with_style(
+                ProgressStyle::with_template(
+                    "[{elapsed_precise}] {wide_bar} {bytes}/{total_bytes} {msg}, ETA {eta}",
+                )
+                .expect("valid progress template"),
+            ),
+        );
+        total.set_message(format!("0/{files} files"));
+
+        Self {
+            bars,
+            total,
+            files,
+            files_done: 0,
+            file_threshold,
+        }
+    }
+
+    /// Runs `copy`, which copies `size` bytes of `relative_path` to `dst_path`. Copies write
+    /// to a temp file next to `dst_path` first, and the bar of a large file follows its size
+    pub async fn copy(
+        &mut self,
+        relative_path: &Path,
+        size: u64,
+        dst_path: &Path,
+        copy: impl Future<Output = Result<()>>,
+    ) -> Result<()> {
+        let result = match size >= self.file_threshold {
+            true => {
+                let file_bar = self.bars.add(
+                    ProgressBar::new(size)
+                        .with_style(
+                            ProgressStyle::with_template(
+                                "  {wide_bar} {bytes}/{total_bytes} {msg}",
+                            )
+                            .expect("valid progress template"),
+                        )
+                        .with_message(relative_path.display().to_string()),
+                );
+                let tmp_path = temp_path_for(dst_path);
+
+                tokio::pin!(copy);
+                let result = loop {
+                    tokio::select! {
+                        result = &mut copy => break result,
+                        _ = tokio::time::sleep(FILE_PROGRESS_INTERVAL) => {
+                            if let Ok(metadata) = tokio::fs::metadata(&tmp_path).await {
+                                file_bar.set_position(metadata.len());
+                            }
+                        }
+                    }
+                };
+                file_bar.finish_and_clear();
+                self.bars.remove(&file_bar);
+                result
+            }
+            false => copy.await,
+        };
+
+        self.files_done += 1;
+        self.total.inc(size);
+        self.total
+            .set_message(format!("{}/{} files", self.files_done, self.files));
+
+        result
+    }
+
+    pub fn finish(&self) {
+        self.total.finish_and_clear();
+    }
+}
@@ -0,0 +1,166 @@
+//! Removing old snapshots and the files that initialization set aside, which would
+//! otherwise fill up the disk over time
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{NaiveDateTime, Utc};
+use std::{
+    collections::HashMap,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    meta::metadata_dir,
+    snapshot::{list_snapshots, snapshots_dir, SNAPSHOT_FORMAT},
+};
+
+/// Which snapshots and cleared directories survive a prune. Anything matching either rule
+/// is kept
+#[derive(Debug, Clone, Copy, Default)]
+pub struct Retention {
+    /// Keep the newest this many
+    pub keep_last: Option<usize>,
+    /// Keep everything younger than this
+    pub keep_for: Option<Duration>,
+}
+
+impl Retention {
+    /// Fails unless at least one rule is set, since pruning without one would remove everything
+    pub(crate) fn check(&self) -> Result<()> {
+        match self.keep_last.is_none() && self.keep_for.is_none() {
+            true => Err(anyhow!(
+                "Pruning needs at least one of --keep-last or --keep-days"
+            )),
+            false => Ok(()),
+        }
+    }
+}
+
+/// What a prune removed, or would have removed in a dry run
+#[derive(Debug, Default)]
+pub struct Pruned {
+    pub removed: Vec<PathBuf>,
+    /// Disk space freed. Files that are still hard linked from elsewhere don't count
+    pub bytes: u64,
+}
+
+/// Removes the snapshots and `cleared-<timestamp>` directories of `backup_dir` that
+/// `retention` doesn't keep. With `dry_run` nothing is removed. This does blocking IO
+pub fn prune(backup_dir: &Path, retention: Retention, dry_run: bool) -> Result<Pruned> {
+    retention.check()?;
+
+    let snapshots_dir = snapshots_dir(backup_dir);
+    let snapshots = list_snapshots(backup_dir)?
+        .into_iter()
+        .map(|name| snapshots_dir.join(name));
+    let mut to_remove = expired(snapshots.collect(), retention);
+    to_remove.extend(expired(list_cleared(backup_dir)?, retention));
+
+    let bytes = reclaimable_bytes(&to_remove)?;
+    if !dry_run {
+        for dir in &to_remove {
+            std::fs::remove_dir_all(dir)
+                .with_context(|| anyhow!("Error removing {}", dir.display()))?;
+        }
+    }
+
+    Ok(Pruned {
+        removed: to_remove,
+        bytes,
+    })
+}
+
+/// The `cleared-<timestamp>` directories of `backup_dir`, oldest first
+pub(crate) fn list_cleared(backup_dir: &Path) -> Result<Vec<PathBuf>> {
+    let metadata_dir = metadata_dir(backup_dir);
+    if !metadata_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut dirs = Vec::new();
+    for entry in std::fs::read_dir(&metadata_dir)
+        .with_context(|| anyhow!("Error reading {}", metadata_dir.display()))?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir()
+            && entry.file_name().to_string_lossy().starts_with("cleared-")
+        {
+            dirs.push(entry.path());
+        }
+    }
+    dirs.sort();
+
+    Ok(dirs)
+}
+
+/// When a snapshot or cleared directory was made, going by the timestamp in its name
+pub(crate) fn created_at(dir: &Path) -> Option<NaiveDateTime> {
+    let name = dir.file_name()?.to_string_lossy();
+    let timestamp = name.strip_prefix("cleared-").unwrap_or(&name);
+    // Snapshots taken within the same second end in a counter
+    let timestamp = timestamp.split('.').next()?;
+    NaiveDateTime::parse_from_str(timestamp, SNAPSHOT_FORMAT).ok()
+}
+
+/// The directories in `dirs`, which are sorted oldest first, that `retention` doesn't keep
+pub(crate) fn expired(dirs: Vec<PathBuf>, retention: Retention) -> Vec<PathBuf> {
+    let now = Utc::now().naive_utc();
+    let newest = dirs.len().saturating_sub(retention.keep_last.unwrap_or(0));
+
+    dirs.into_iter()
+        .enumerate()
+        .filter(|(index, dir)| {
+            let kept_by_count = retention.keep_last.is_some() && *index >= newest;
+            let kept_by_age = retention.keep_for.is_some_and(|keep_for| {
+                // Directories whose age can't be told are kept to be safe
+                created_at(dir).is_none_or(|created_at| {
+                    (now - created_at).to_std().unwrap_or_default() < keep_for
+                })
+            });
+            !kept_by_count && !kept_by_age
+        })
+        .map(|(_, dir)| dir)
+        .collect()
+}
+
+/// How much space removing `dirs` frees. Snapshots hard link unchanged files, so a file
+/// only counts once every one of its links is being removed
+pub(crate) fn reclaimable_bytes(dirs: &[PathBuf]) -> Result<u64> {
+    let mut bytes = 0;
+    // How many links to each file were seen, how many it has, and its size
+    let mut linked: HashMap<(u64, u64), (u64, u64, u64)> = HashMap::new();
+
+    for dir in dirs {
+        for file_info in WalkDir::new(dir) {
+            let file_info = file_info?;
+            if file_info.file_type().is_dir() {
+                continue;
+            }
+            let metadata = file_info.metadata()?;
+
+            #[cfg(unix)]
+            {
+                use std::os::unix::fs::MetadataExt;
+                if metadata.nlink() > 1 {
+                    let (seen, _, _) = linked.entry((metadata.dev(), metadata.ino())).or_insert((
+                        0,
+                        metadata.nlink(),
+                        metadata.len(),
+                    ));
+                    *seen += 1;
+                    continue;
+                }
+            }
+            bytes += metadata.len();
+        }
+    }
+
+    bytes += linked
+        .values()
+        .filter(|(seen, links, _)| seen >= links)
+        .map(|(_, _, size)| size)
+        .sum::<u64>();
+
+    Ok(bytes)
+}
@@ -0,0 +1,141 @@
+//! Keeping the backup under a size limit.
+//!
+//! The space the backup takes up, snapshots and cleared files included, is measured when
+//! syncing starts and after every cycle that changed it, and copies count towards it as
+//! they happen. A copy that doesn't fit is refused, unless the oldest snapshots and cleared
+//! directories of a local backup can be evicted to make room for it
+
+use anyhow::{anyhow, Context, Result};
+use indicatif::HumanBytes;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashSet,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use walkdir::WalkDir;
+
+use crate::{
+    prune::{created_at, list_cleared, reclaimable_bytes},
+    snapshot::{list_snapshots, snapshots_dir},
+};
+
+/// How big the backup may get
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Quota {
+    pub bytes: u64,
+    pub policy: QuotaPolicy,
+}
+
+/// What to do about a copy that doesn't fit in the [`Quota`]
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum QuotaPolicy {
+    /// Refuse the copy, reporting it as an error
+    #[default]
+    #[serde(rename = "refuse")]
+    Refuse,
+    /// Remove the oldest snapshots and cleared directories until it fits, and refuse it
+    /// once there are none left
+    #[serde(rename = "evict")]
+    Evict,
+}
+
+impl FromStr for QuotaPolicy {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "refuse" => Ok(Self::Refuse),
+            "evict" => Ok(Self::Evict),
+            _ => Err(format!(
+                "unknown quota policy {s}, expected refuse or evict"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for QuotaPolicy {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Refuse => "refuse",
+            Self::Evict => "evict",
+        })
+    }
+}
+
+/// The error of a copy that would take the backup over its [`Quota`]
+#[derive(Debug, Clone, Copy)]
+pub struct QuotaExceeded {
+    pub quota: u64,
+    /// What the backup takes up without the copy
+    pub used: u64,
+    /// How much the copy would add
+    pub needed: u64,
+}
+
+impl fmt::Display for QuotaExceeded {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "The backup quota of {} is exceeded: {} are used and the copy needs {} more",
+            HumanBytes(self.quota),
+            HumanBytes(self.used),
+            HumanBytes(self.needed)
+        )
+    }
+}
+
+impl std::error::Error for QuotaExceeded {}
+
+/// How much space the files in `backup_dir` take up, counting hard links to the same file
+/// once. This does blocking IO
+pub(crate) fn disk_usage(backup_dir: &Path) -> Result<u64> {
+    let mut bytes = 0;
+    // Files with more than one link that were already counted
+    let mut seen: HashSet<(u64, u64)> = HashSet::new();
+    for file_info in WalkDir::new(backup_dir) {
+        let file_info =
+            file_info.with_context(|| anyhow!("Error measuring {}", backup_dir.display()))?;
+        if file_info.file_type().is_dir() {
+            continue;
+        }
+        let metadata = file_info.metadata()?;
+        #[cfg(unix)]
+        {
+            use std::os::unix::fs::MetadataExt;
+            if metadata.nlink() > 1 && !seen.insert((metadata.dev(), metadata.ino())) {
+                continue;
+            }
+        }
+        bytes += metadata.len();
+    }
+    Ok(bytes)
+}
+
+/// Removes the oldest snapshots and cleared directories of `backup_dir` until at least
+/// `bytes` are freed or there are none left. Returns the removed directories and how much
+/// they freed. This does blocking IO
+pub(crate) fn evict(backup_dir: &Path, bytes: u64) -> Result<(Vec<PathBuf>, u64)> {
+    let snapshots_dir = snapshots_dir(backup_dir);
+    let mut dirs: Vec<PathBuf> = list_snapshots(backup_dir)?
+        .into_iter()
+        .map(|name| snapshots_dir.join(name))
+        .chain(list_cleared(backup_dir)?)
+        .collect();
+    // Oldest first, directories whose age can't be told go last
+    dirs.sort_by_key(|dir| (created_at(dir).is_none(), created_at(dir)));
+
+    let mut evicted = Vec::new();
+    let mut freed = 0;
+    for dir in dirs {
+        if freed >= bytes {
+            break;
+        }
+        freed += reclaimable_bytes(std::slice::from_ref(&dir))?;
+        std::fs::remove_dir_all(&dir)
+            .with_context(|| anyhow!("Error removing {}", dir.display()))?;
+        evicted.push(dir);
+    }
+    Ok((evicted, freed))
+}
@@ -0,0 +1,61 @@
+//! Copy-on-write clones, which share their data with the original until either is changed.
+//!
+//! Btrfs and XFS clone files with the `FICLONE` ioctl, APFS with `clonefile`. Both only
+//! work when the original and the clone are on the same filesystem, and cloning a file
+//! takes about as long however big it is
+
+use std::{io, path::Path};
+
+/// Clones `path` to `dst_path`, which mustn't exist yet. Returns false, leaving nothing
+/// behind, when the filesystem or platform can't clone it. This does blocking IO
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub(crate) fn clone_file(path: &Path, dst_path: &Path) -> io::Result<bool> {
+    use nix::libc;
+    use std::{fs, os::fd::AsRawFd};
+
+    let src = fs::File::open(path)?;
+    let dst = fs::OpenOptions::new()
+        .write(true)
+        .create_new(true)
+        .open(dst_path)?;
+    // SAFETY: both descriptors stay open for the duration of the call
+    let cloned = unsafe { libc::ioctl(dst.as_raw_fd(), libc::FICLONE, src.as_raw_fd()) };
+    if cloned == 0 {
+        dst.set_permissions(src.metadata()?.permissions())?;
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    drop(dst);
+    let _ = fs::remove_file(dst_path);
+    match err.raw_os_error() {
+        Some(libc::EXDEV | libc::EOPNOTSUPP | libc::EINVAL | libc::ENOTTY | libc::ENOSYS) => {
+            Ok(false)
+        }
+        _ => Err(err),
+    }
+}
+
+#[cfg(target_os = "macos")]
+pub(crate) fn clone_file(path: &Path, dst_path: &Path) -> io::Result<bool> {
+    use nix::libc;
+    use std::{ffi::CString, os::unix::ffi::OsStrExt};
+
+    let src = CString::new(path.as_os_str().as_bytes())?;
+    let dst = CString::new(dst_path.as_os_str().as_bytes())?;
+    // SAFETY: both are valid NUL terminated paths
+    if unsafe { libc::clonefile(src.as_ptr(), dst.as_ptr(), 0) } == 0 {
+        return Ok(true);
+    }
+
+    let err = io::Error::last_os_error();
+    match err.raw_os_error() {
+        Some(libc::EXDEV | libc::ENOTSUP) => Ok(false),
+        _ => Err(err),
+    }
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "macos")))]
+pub(crate) fn clone_file(_path: &Path, _dst_path: &Path) -> io::Result<bool> {
+    Ok(false)
+}
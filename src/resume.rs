@@ -0,0 +1,406 @@
+//! Resuming big copies that were cut off, e.g. because the process was killed halfway
+//! through a 20 GB file.
+//!
+//! Copies of files of at least [`RESUME_MIN_SIZE`] keep a journal next to their temp file,
+//! which says how much of it was written and flushed, with the hash of that prefix and what
+//! the source looked like. The next copy of the same file picks up from there, once the
+//! prefix of both the temp file and the source still hash to what the journal says. Temp
+//! files with a journal whose source didn't change survive the clean up of leftover temp
+//! files, anything else starts from scratch
+
+use blake3::{Hash, Hasher};
+use serde::{Deserialize, Serialize};
+use std::{
+    io::SeekFrom,
+    path::{Path, PathBuf},
+    time::SystemTime,
+};
+use tokio::{
+    fs,
+    io::{self, AsyncReadExt, AsyncSeekExt, AsyncWriteExt},
+};
+use tracing::debug;
+
+use crate::{meta::TEMP_SUFFIX, throttle::Throttle};
+
+/// Copies of files at least this big can be resumed
+pub const RESUME_MIN_SIZE: u64 = 64 * 1024 * 1024;
+
+/// How much is written between two checkpoints of the journal, each of which flushes the
+/// temp file
+const CHECKPOINT_SIZE: u64 = 16 * 1024 * 1024;
+
+/// How much is read at a time
+const CHUNK_SIZE: usize = 1024 * 1024;
+
+/// Journals are named after their temp file with this in front of [`TEMP_SUFFIX`], so they
+/// are never synced either
+const JOURNAL_INFIX: &str = ".evilmount.journal";
+
+/// Where the copy into `tmp_path` keeps its journal
+fn journal_path(tmp_path: &Path) -> PathBuf {
+    journal_path_with(tmp_path, "")
+}
+
+/// The journal of `tmp_path` with `infix` in its name, which a new journal is written to
+/// before it replaces the old one
+fn journal_path_with(tmp_path: &Path, infix: &str) -> PathBuf {
+    let mut file_name = tmp_path.file_name().unwrap_or_default().to_os_string();
+    file_name.push(JOURNAL_INFIX);
+    file_name.push(infix);
+    file_name.push(TEMP_SUFFIX);
+    tmp_path.with_file_name(file_name)
+}
+
+/// The temp file `path` keeps the journal of, if it's a journal
+fn journaled_temp_file(path: &Path) -> Option<PathBuf> {
+    let file_name = path.file_name()?.to_str()?;
+    let tmp_name = file_name
+        .strip_suffix(TEMP_SUFFIX)?
+        .strip_suffix(JOURNAL_INFIX)?;
+    Some(path.with_file_name(tmp_name))
+}
+
+/// How far the copy into a temp file got
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+struct Journal {
+    source: PathBuf,
+    /// What the source looked like when the copy started
+    size: u64,
+    modified: Option<SystemTime>,
+    /// How many bytes of the temp file are written and flushed
+    offset: u64,
+    /// The hash of those bytes, in hex
+    hash: String,
+}
+
+impl Journal {
+    async fn read(tmp_path: &Path) -> Option<Self> {
+        let contents = fs::read(journal_path(tmp_path)).await.ok()?;
+        serde_json::from_slice(&contents).ok()
+    }
+
+    /// Replaces the journal of `tmp_path` with this one
+    async fn write(&self, tmp_path: &Path) -> io::Result<()> {
+        let new_path = journal_path_with(tmp_path, ".new");
+        fs::write(&new_path, serde_json::to_vec(self)?).await?;
+        fs::rename(&new_path, journal_path(tmp_path)).await
+    }
+
+    /// Whether it's about the copy of `source`, which still looks like `metadata`
+    fn is_for(&self, source: &Path, metadata: &std::fs::Metadata) -> bool {
+        self.source == source
+            && self.size == metadata.len()
+            && self.modified == metadata.modified().ok()
+    }
+}
+
+/// Whether the temp file at `path`, or the journal at `path`, belongs to a copy that can
+/// still be resumed. This does blocking IO
+pub fn is_resumable(path: &Path) -> bool {
+    let tmp_path = journaled_temp_file(path).unwrap_or_else(|| path.to_path_buf());
+    let Ok(contents) = std::fs::read(journal_path(&tmp_path)) else {
+        return false;
+    };
+    let Ok(journal) = serde_json::from_slice::<Journal>(&contents) else {
+        return false;
+    };
+    tmp_path.exists()
+        && std::fs::metadata(&journal.source).is_ok_and(|metadata| {
+            journal.size == metadata.len() && journal.modified == metadata.modified().ok()
+        })
+}
+
+/// Whether a copy of `path` into `tmp_path` was cut off and can be resumed
+pub async fn can_resume(path: &Path, tmp_path: &Path) -> bool {
+    let (Some(journal), Ok(metadata)) = (Journal::read(tmp_path).await, fs::metadata(path).await)
+    else {
+        return false;
+    };
+    journal.is_for(path, &metadata)
+}
+
+/// Removes the journal of `tmp_path`, if it has one
+pub async fn remove_journal(tmp_path: &Path) {
+    let _ = fs::remove_file(journal_path(tmp_path)).await;
+}
+
+/// Copies `path` into `tmp_path` like
+/// [`copy_streaming`](crate::copy::copy_streaming), picking up where an earlier copy into
+/// it stopped if it can. Returns the hash and length of all of `path`
+pub async fn copy_resumable(
+    path: &Path,
+    tmp_path: &Path,
+    bwlimit: Option<&Throttle>,
+) -> io::Result<(Hash, u64)> {
+    copy_resumable_with(path, tmp_path, bwlimit, CHECKPOINT_SIZE).await
+}
+
+/// [`copy_resumable`] with a checkpoint every `checkpoint_size` bytes
+async fn copy_resumable_with(
+    path: &Path,
+    tmp_path: &Path,
+    bwlimit: Option<&Throttle>,
+    checkpoint_size: u64,
+) -> io::Result<(Hash, u64)> {
+    let mut src = fs::File::open(path).await?;
+    let metadata = src.metadata().await?;
+    let mut dst = fs::OpenOptions::new()
+        .read(true)
+        .write(true)
+        .create(true)
+        .truncate(false)
+        .open(tmp_path)
+        .await?;
+
+    let mut hasher = Hasher::new();
+    let mut len = resume_point(path, tmp_path, &mut src, &mut dst, &mut hasher).await?;
+    if len > 0 {
+        debug!(
+            "Resuming the copy of {} at byte {len} of {}",
+            path.display(),
+            metadata.len()
+        );
+    } else {
+        hasher.reset();
+        src.seek(SeekFrom::Start(0)).await?;
+    }
+    dst.set_len(len).await?;
+    dst.seek(SeekFrom::Start(len)).await?;
+
+    let mut journal = Journal {
+        source: path.to_path_buf(),
+        size: metadata.len(),
+        modified: metadata.modified().ok(),
+        offset: len,
+        hash: hasher.finalize().to_hex().to_string(),
+    };
+    journal.write(tmp_path).await?;
+
+    let mut buf = vec![0; CHUNK_SIZE];
+    loop {
+        let read = src.read(&mut buf).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        if let Some(bwlimit) = bwlimit {
+            bwlimit.take(read).await;
+        }
+        dst.write_all(&buf[..read]).await?;
+        len += read as u64;
+        if len - journal.offset >= checkpoint_size {
+            // The journal never gets ahead of what is on disk
+            dst.sync_data().await?;
+            journal.offset = len;
+            journal.hash = hasher.finalize().to_hex().to_string();
+            journal.write(tmp_path).await?;
+        }
+    }
+    dst.flush().await?;
+    drop(dst);
+
+    fs::set_permissions(tmp_path, metadata.permissions()).await?;
+    Ok((hasher.finalize(), len))
+}
+
+/// How much of `tmp_path` an earlier copy of `path` into it left that can be kept, reading
+/// that much of `src` into `hasher`. 0 unless its journal is there and checks out
+async fn resume_point(
+    path: &Path,
+    tmp_path: &Path,
+    src: &mut fs::File,
+    dst: &mut fs::File,
+    hasher: &mut Hasher,
+) -> io::Result<u64> {
+    let metadata = src.metadata().await?;
+    match Journal::read(tmp_path).await {
+        Some(journal) if journal.is_for(path, &metadata) => {
+            match resume_at(&journal, src, dst, hasher).await? {
+                true => Ok(journal.offset),
+                false => Ok(0),
+            }
+        }
+        _ => Ok(0),
+    }
+}
+
+/// Checks that the first [`Journal::offset`] bytes of both `src` and `dst` hash to what
+/// `journal` says, reading those of `src` into `hasher`. Leaves `src` right after them
+async fn resume_at(
+    journal: &Journal,
+    src: &mut fs::File,
+    dst: &mut fs::File,
+    hasher: &mut Hasher,
+) -> io::Result<bool> {
+    if dst.metadata().await?.len() < journal.offset {
+        return Ok(false);
+    }
+    let written = hash_prefix(dst, journal.offset).await?;
+    if written.to_hex().as_str() != journal.hash {
+        return Ok(false);
+    }
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut left = journal.offset;
+    while left > 0 {
+        let read = src.read(&mut buf[..CHUNK_SIZE.min(left as usize)]).await?;
+        if read == 0 {
+            return Ok(false);
+        }
+        hasher.update(&buf[..read]);
+        left -= read as u64;
+    }
+    Ok(hasher.finalize() == written)
+}
+
+/// The hash of the first `len` bytes of `file`
+async fn hash_prefix(file: &mut fs::File, len: u64) -> io::Result<Hash> {
+    file.seek(SeekFrom::Start(0)).await?;
+    let mut hasher = Hasher::new();
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut left = len;
+    while left > 0 {
+        let read = file.read(&mut buf[..CHUNK_SIZE.min(left as usize)]).await?;
+        if read == 0 {
+            break;
+        }
+        hasher.update(&buf[..read]);
+        left -= read as u64;
+    }
+    Ok(hasher.finalize())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const MIB: usize = 1024 * 1024;
+
+    /// A source of 3.5 MiB and where it's copied to, in `dir`
+    fn set_up(dir: &Path) -> (PathBuf, PathBuf, Vec<u8>) {
+        let (path, tmp_path) = (dir.join("big"), dir.join(format!("big{TEMP_SUFFIX}")));
+        let contents: Vec<u8> = (0..7 * MIB / 2).map(|i| (i % 253) as u8).collect();
+        std::fs::write(&path, &contents).unwrap();
+        (path, tmp_path, contents)
+    }
+
+    /// Copies with a checkpoint every MiB
+    async fn copy(path: &Path, tmp_path: &Path) -> (Hash, u64) {
+        copy_resumable_with(path, tmp_path, None, MIB as u64)
+            .await
+            .unwrap()
+    }
+
+    /// Where the next copy of `path` into `tmp_path` would start
+    async fn resume_point_of(path: &Path, tmp_path: &Path) -> u64 {
+        let mut src = fs::File::open(path).await.unwrap();
+        let mut dst = fs::File::open(tmp_path).await.unwrap();
+        resume_point(path, tmp_path, &mut src, &mut dst, &mut Hasher::new())
+            .await
+            .unwrap()
+    }
+
+    /// Cuts the copy into `tmp_path` off past its last checkpoint, with garbage after that
+    fn cut_off(tmp_path: &Path) {
+        let mut contents = std::fs::read(tmp_path).unwrap();
+        contents.truncate(3 * MIB + 100);
+        contents[3 * MIB..].fill(0xff);
+        std::fs::write(tmp_path, contents).unwrap();
+    }
+
+    #[tokio::test]
+    async fn journals_round_trip() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, tmp_path, _) = set_up(dir.path());
+        let metadata = std::fs::metadata(&path).unwrap();
+        let journal = Journal {
+            source: path.clone(),
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            offset: 5,
+            hash: blake3::hash(b"12345").to_hex().to_string(),
+        };
+        journal.write(&tmp_path).await.unwrap();
+        assert_eq!(Journal::read(&tmp_path).await, Some(journal));
+        assert_eq!(
+            journaled_temp_file(&journal_path(&tmp_path)),
+            Some(tmp_path.clone())
+        );
+
+        // Both the journal and its temp file are kept once there is one
+        assert!(!is_resumable(&tmp_path));
+        std::fs::write(&tmp_path, "12345").unwrap();
+        assert!(is_resumable(&tmp_path));
+        assert!(is_resumable(&journal_path(&tmp_path)));
+        assert!(can_resume(&path, &tmp_path).await);
+        assert!(!can_resume(&dir.path().join("other"), &tmp_path).await);
+
+        remove_journal(&tmp_path).await;
+        assert!(!is_resumable(&tmp_path));
+        assert!(!can_resume(&path, &tmp_path).await);
+    }
+
+    #[tokio::test]
+    async fn copies_pick_up_after_the_last_checkpoint() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, tmp_path, contents) = set_up(dir.path());
+        let expected = (blake3::hash(&contents), contents.len() as u64);
+        assert_eq!(copy(&path, &tmp_path).await, expected);
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), contents);
+        assert_eq!(
+            Journal::read(&tmp_path).await.unwrap().offset,
+            3 * MIB as u64
+        );
+
+        cut_off(&tmp_path);
+        assert_eq!(resume_point_of(&path, &tmp_path).await, 3 * MIB as u64);
+        assert_eq!(copy(&path, &tmp_path).await, expected);
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), contents);
+    }
+
+    #[tokio::test]
+    async fn changed_temp_files_are_copied_from_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, tmp_path, contents) = set_up(dir.path());
+        copy(&path, &tmp_path).await;
+        cut_off(&tmp_path);
+
+        let mut written = std::fs::read(&tmp_path).unwrap();
+        written[10] ^= 1;
+        std::fs::write(&tmp_path, written).unwrap();
+        assert_eq!(resume_point_of(&path, &tmp_path).await, 0);
+        copy(&path, &tmp_path).await;
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), contents);
+
+        // Shorter than what the journal says was written
+        std::fs::write(&tmp_path, &contents[..MIB]).unwrap();
+        assert_eq!(resume_point_of(&path, &tmp_path).await, 0);
+    }
+
+    #[tokio::test]
+    async fn changed_sources_are_copied_from_the_start() {
+        let dir = tempfile::tempdir().unwrap();
+        let (path, tmp_path, mut contents) = set_up(dir.path());
+        copy(&path, &tmp_path).await;
+        cut_off(&tmp_path);
+
+        // Changed without its size or modification time showing it
+        let modified =
+            filetime::FileTime::from_last_modification_time(&std::fs::metadata(&path).unwrap());
+        contents[10] ^= 1;
+        std::fs::write(&path, &contents).unwrap();
+        filetime::set_file_mtime(&path, modified).unwrap();
+        assert!(can_resume(&path, &tmp_path).await);
+        assert_eq!(resume_point_of(&path, &tmp_path).await, 0);
+        copy(&path, &tmp_path).await;
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), contents);
+
+        cut_off(&tmp_path);
+        contents.push(0);
+        std::fs::write(&path, &contents).unwrap();
+        assert!(!can_resume(&path, &tmp_path).await);
+        assert_eq!(resume_point_of(&path, &tmp_path).await, 0);
+        copy(&path, &tmp_path).await;
+        assert_eq!(std::fs::read(&tmp_path).unwrap(), contents);
+    }
+}
@@ -0,0 +1,202 @@
+//! Keeping dated copies of the backup, one per day or hour.
+//!
+//! At every boundary the backup as it was during the period that just ended is hard linked
+//! into a directory named after it, like `.evilmount/rotations/2024-05-01`, and the backup
+//! carries on as a mirror. Copies replace files instead of writing into them, and the ones
+//! that do write in place replace linked files first, so rotations never change afterwards.
+//! Unlike snapshots nothing is compared or copied, making this cheap for big backups
+
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local};
+use serde::{Deserialize, Serialize};
+use std::{
+    fmt, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+use tracing::debug;
+
+use crate::{
+    filter::{walk_files, IgnoreSet},
+    meta::metadata_dir,
+};
+
+/// How often the backup is rotated, and how many rotations are kept
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Rotation {
+    pub every: Rotate,
+    /// Every rotation is kept without this
+    pub keep: Option<usize>,
+}
+
+/// The periods the backup is rotated at the end of, in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+pub enum Rotate {
+    #[serde(rename = "daily")]
+    Daily,
+    #[serde(rename = "hourly")]
+    Hourly,
+}
+
+impl FromStr for Rotate {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "daily" => Ok(Self::Daily),
+            "hourly" => Ok(Self::Hourly),
+            _ => Err(format!("unknown rotation {s}, expected daily or hourly")),
+        }
+    }
+}
+
+impl fmt::Display for Rotate {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Daily => "daily",
+            Self::Hourly => "hourly",
+        })
+    }
+}
+
+impl Rotate {
+    /// The name of the period `time` is in, like 2024-05-01 or 2024-05-01T13, which sorts
+    /// chronologically
+    pub fn period(self, time: &DateTime<Local>) -> String {
+        let format = match self {
+            Self::Daily => "%Y-%m-%d",
+            Self::Hourly => "%Y-%m-%dT%H",
+        };
+        time.format(format).to_string()
+    }
+}
+
+/// Where the rotations of `backup_dir` are kept
+pub fn rotations_dir(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("rotations")
+}
+
+/// Holds the period the backup mirrors, which is what it's rotated into once that's over
+fn period_path(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("rotation")
+}
+
+/// Rotates `backup_dir` if the period it mirrors is over by `now`, and removes the oldest
+/// rotations past [`Rotation::keep`]. Returns the new rotation, if there is one. The first
+/// call only remembers the period. This does blocking IO
+pub fn rotate_if_due(
+    backup_dir: &Path,
+    ignore: &IgnoreSet,
+    rotation: Rotation,
+    now: &DateTime<Local>,
+) -> Result<Option<PathBuf>> {
+    let current = rotation.every.period(now);
+    let period_path = period_path(backup_dir);
+    let mirrored = match std::fs::read_to_string(&period_path) {
+        Ok(mirrored) => mirrored.trim().to_string(),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => String::new(),
+        Err(err) => {
+            return Err(err).with_context(|| anyhow!("Error reading {}", period_path.display()))
+        }
+    };
+    if mirrored == current {
+        return Ok(None);
+    }
+
+    let rotated = match mirrored.is_empty() {
+        true => None,
+        false => Some(link_tree(backup_dir, ignore, &mirrored)?),
+    };
+    if let Some(parent) = period_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+    }
+    std::fs::write(&period_path, &current)
+        .with_context(|| anyhow!("Error writing {}", period_path.display()))?;
+    if let Some(keep) = rotation.keep {
+        prune_rotations(backup_dir, keep)?;
+    }
+
+    Ok(rotated)
+}
+
+/// Hard links every file in `backup_dir` into a rotation called `name`, copying the ones
+/// that can't be linked
+fn link_tree(backup_dir: &Path, ignore: &IgnoreSet, name: &str) -> Result<PathBuf> {
+    let rotations_dir = rotations_dir(backup_dir);
+    // Going back in time, like when the clock is set back, rotates the same period twice
+    let mut rotation_dir = rotations_dir.join(name);
+    let mut counter = 1;
+    while rotation_dir.exists() {
+        rotation_dir = rotations_dir.join(format!("{name}.{counter}"));
+        counter += 1;
+    }
+
+    let (mut linked, mut copied) = (0, 0);
+    for file_info in walk_files(backup_dir, ignore) {
+        let relative_path = file_info.path().strip_prefix(backup_dir)?;
+        let dst_path = rotation_dir.join(relative_path);
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        }
+        // Not every filesystem supports hard links
+        if std::fs::hard_link(file_info.path(), &dst_path).is_ok() {
+            linked += 1;
+            continue;
+        }
+        std::fs::copy(file_info.path(), &dst_path).with_context(|| {
+            anyhow!(
+                "Error copying {} into rotation {}",
+                file_info.path().display(),
+                rotation_dir.display()
+            )
+        })?;
+        copied += 1;
+    }
+
+    std::fs::create_dir_all(&rotation_dir)
+        .with_context(|| anyhow!("Error creating {}", rotation_dir.display()))?;
+    debug!(
+        "Rotation {} links {linked} files and copies {copied}",
+        rotation_dir.display()
+    );
+
+    Ok(rotation_dir)
+}
+
+/// The names of every rotation of `backup_dir`, oldest first
+pub fn list_rotations(backup_dir: &Path) -> Result<Vec<String>> {
+    let rotations_dir = rotations_dir(backup_dir);
+    if !rotations_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&rotations_dir)
+        .with_context(|| anyhow!("Error reading {}", rotations_dir.display()))?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+/// Removes all but the newest `keep` rotations. This does blocking IO
+fn prune_rotations(backup_dir: &Path, keep: usize) -> Result<()> {
+    let names = list_rotations(backup_dir)?;
+    let rotations_dir = rotations_dir(backup_dir);
+
+    for name in &names[..names.len().saturating_sub(keep)] {
+        let rotation_dir = rotations_dir.join(name);
+        std::fs::remove_dir_all(&rotation_dir)
+            .with_context(|| anyhow!("Error removing rotation {}", rotation_dir.display()))?;
+        debug!("Removed rotation {}", rotation_dir.display());
+    }
+
+    Ok(())
+}
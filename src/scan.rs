@@ -0,0 +1,286 @@
+//! Scanning work_dir several directories at a time, for trees too big to walk one directory
+//! after the other between two polls. Everything is read through a [`Vfs`].
+//!
+//! A directory whose modification time hasn't changed since it was last read still holds
+//! the same entries, so it isn't read again. Its subdirectories are still looked at, since
+//! a change deep down doesn't touch the directories above it. What's found is streamed to
+//! the caller while the scan is still running
+
+use anyhow::Result;
+use futures::{stream::FuturesUnordered, StreamExt};
+use std::{
+    collections::HashMap,
+    ffi::OsString,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::{sync::mpsc, task::JoinHandle};
+use tracing::{debug, Instrument};
+
+use crate::{
+    filter::{IgnoreSet, Symlinks},
+    vfs::{Entry, EntryKind, Vfs},
+};
+
+/// At most this many directories are read at the same time
+const MAX_CONCURRENT_READS: usize = 8;
+
+/// How many entries can be found ahead of the caller handling them
+const FOUND_BUFFER: usize = 1024;
+
+/// Directories changed more recently than this are read again on the next scan, since a
+/// change made in the same tick of the clock wouldn't change the modification time
+const SETTLED: Duration = Duration::from_secs(2);
+
+/// Something a scan found that isn't ignored
+#[derive(Debug)]
+pub(crate) enum Found {
+    File(PathBuf),
+    Dir(PathBuf),
+    /// What's at or under it can't be told this scan, so it's kept as it was
+    Unreadable(PathBuf),
+}
+
+/// The names in a directory, and what they are without following links
+type Entries = Arc<[(OsString, EntryKind)]>;
+
+/// What a directory held when it was last read
+struct Listing {
+    modified: SystemTime,
+    entries: Entries,
+    /// The last scan that found the directory
+    scan: u64,
+}
+
+/// Scans one directory over and over, remembering what its directories held
+pub(crate) struct Scanner {
+    vfs: Arc<dyn Vfs>,
+    root: PathBuf,
+    ignore: IgnoreSet,
+    symlinks: Symlinks,
+    listings: Mutex<HashMap<PathBuf, Listing>>,
+    scans: AtomicU64,
+}
+
+impl Scanner {
+    pub fn new(vfs: Arc<dyn Vfs>, root: &Path, ignore: &IgnoreSet, symlinks: Symlinks) -> Self {
+        Self {
+            vfs,
+            root: root.to_path_buf(),
+            ignore: ignore.clone(),
+            symlinks,
+            listings: Mutex::default(),
+            scans: AtomicU64::new(0),
+        }
+    }
+
+    /// Starts a scan, which ends when everything was found or the receiver is dropped. What
+    /// couldn't be read is reported to the walk errors of the [`IgnoreSet`]. The task fails
+    /// if the scan didn't get to the end, when whatever wasn't found might still be there
+    pub fn scan(self: &Arc<Self>) -> (mpsc::Receiver<Found>, JoinHandle<()>) {
+        let (found, receiver) = mpsc::channel(FOUND_BUFFER);
+        let scanner = self.clone();
+        let task = tokio::task::spawn(async move { scanner.run(found).await }.in_current_span());
+        (receiver, task)
+    }
+
+    async fn run(&self, found: mpsc::Sender<Found>) {
+        let scan = self.scans.fetch_add(1, Ordering::Relaxed) + 1;
+        // The filesystem a scan stays on, if it has to
+        let device = match self.ignore.same_filesystem() {
+            true => self.device(&self.root).await,
+            false => None,
+        };
+        let mut waiting = vec![Dir {
+            path: self.root.clone(),
+            parent: None,
+        }];
+        let mut reading = FuturesUnordered::new();
+        loop {
+            while reading.len() < MAX_CONCURRENT_READS {
+                let Some(dir) = waiting.pop() else {
+                    break;
+                };
+                reading.push(self.scan_dir(dir, scan, device, &found));
+            }
+            match reading.next().await {
+                Some(Some(dirs)) => waiting.extend(dirs),
+                // Nobody is listening anymore
+                Some(None) => return,
+                None => break,
+            }
+        }
+
+        // Directories that are gone would otherwise be remembered forever
+        self.listings
+            .lock()
+            .unwrap()
+            .retain(|_, listing| listing.scan == scan);
+    }
+
+    /// Reads `dir`, returning the directories in it to read next. `None` once nobody is
+    /// listening anymore
+    async fn scan_dir(
+        &self,
+        dir: Dir,
+        scan: u64,
+        device: Option<u64>,
+        found: &mpsc::Sender<Found>,
+    ) -> Option<Vec<Dir>> {
+        let walk_errors = self.ignore.walk_errors();
+        let (entries, id) = match self.read_dir(&dir.path, scan).await {
+            Ok(read) => read,
+            Err(err) => {
+                walk_errors.report(dir.path.clone(), &err.root_cause().to_string());
+                found.send(Found::Unreadable(dir.path)).await.ok()?;
+                return Some(Vec::new());
+            }
+        };
+        let this = Arc::new(Ancestor {
+            id,
+            parent: dir.parent,
+        });
+
+        let mut dirs = Vec::new();
+        for (name, kind) in entries.iter() {
+            let path = dir.path.join(name);
+            let is_link = *kind == EntryKind::Symlink;
+            let symlinks = match is_link {
+                true => self.ignore.link_policy(&path, self.symlinks),
+                false => self.symlinks,
+            };
+            let (is_dir, link_id) = match (is_link, symlinks) {
+                (true, Symlinks::Follow) => match self.vfs.follow(&path).await {
+                    Ok(Some(entry)) => (entry.kind == EntryKind::Dir, dir_id(&path, &entry)),
+                    Ok(None) => {
+                        walk_errors.report(path, &"it links to something that doesn't exist");
+                        continue;
+                    }
+                    Err(err) => {
+                        walk_errors.report(path.clone(), &err.root_cause().to_string());
+                        found.send(Found::Unreadable(path)).await.ok()?;
+                        continue;
+                    }
+                },
+                (true, Symlinks::Recreate) => (false, None),
+                (true, Symlinks::Skip) => continue,
+                (false, _) => match kind {
+                    EntryKind::Dir => (true, None),
+                    EntryKind::File => (false, None),
+                    // FIFOs, sockets and devices
+                    _ => continue,
+                },
+            };
+            if self.ignore.is_ignored_in(&self.root, &path, is_dir) {
+                continue;
+            }
+            if !is_dir {
+                found.send(Found::File(path)).await.ok()?;
+                continue;
+            }
+
+            if link_id.is_some() && this.has(&link_id) {
+                walk_errors.report(path, &"it links back to a directory above it");
+                continue;
+            }
+            if device.is_some() && self.device(&path).await != device {
+                debug!(path = %path.display(), "On another filesystem, leaving it out");
+                continue;
+            }
+            found.send(Found::Dir(path.clone())).await.ok()?;
+            dirs.push(Dir {
+                path,
+                parent: Some(this.clone()),
+            });
+        }
+        Some(dirs)
+    }
+
+    /// The entries of `dir`, remembered from the last scan if it didn't change since
+    async fn read_dir(&self, dir: &Path, scan: u64) -> Result<(Entries, Option<DirId>)> {
+        let Some(entry) = self.vfs.follow(dir).await? else {
+            return Err(io::Error::from(io::ErrorKind::NotFound).into());
+        };
+        let id = dir_id(dir, &entry);
+        if let Some(modified) = entry.modified {
+            if let Some(listing) = self.listings.lock().unwrap().get_mut(dir) {
+                if listing.modified == modified {
+                    listing.scan = scan;
+                    return Ok((listing.entries.clone(), id));
+                }
+            }
+        }
+
+        let read_at = SystemTime::now();
+        let entries: Entries = self.vfs.read_dir(dir).await?.into();
+        if let Some(modified) = entry.modified.filter(|modified| {
+            read_at
+                .duration_since(*modified)
+                .is_ok_and(|age| age >= SETTLED)
+        }) {
+            let listing = Listing {
+                modified,
+                entries: entries.clone(),
+                scan,
+            };
+            self.listings
+                .lock()
+                .unwrap()
+                .insert(dir.to_path_buf(), listing);
+        }
+        Ok((entries, id))
+    }
+
+    /// The filesystem `path` is on, where that can be told
+    async fn device(&self, path: &Path) -> Option<u64> {
+        let entry = self.vfs.follow(path).await.ok()??;
+        entry.id.map(|id| id.device)
+    }
+}
+
+/// What tells directories apart when links lead to the same one
+#[cfg(unix)]
+type DirId = crate::vfs::FileId;
+#[cfg(not(unix))]
+type DirId = PathBuf;
+
+#[cfg(unix)]
+fn dir_id(_path: &Path, entry: &Entry) -> Option<DirId> {
+    entry.id
+}
+
+#[cfg(not(unix))]
+fn dir_id(path: &Path, _entry: &Entry) -> Option<DirId> {
+    path.canonicalize().ok()
+}
+
+/// A directory on the way from the root to the one being read, to notice links that lead
+/// back up, which would be walked forever
+struct Ancestor {
+    id: Option<DirId>,
+    parent: Option<Arc<Ancestor>>,
+}
+
+impl Ancestor {
+    fn has(&self, id: &Option<DirId>) -> bool {
+        let mut ancestor = Some(self);
+        while let Some(current) = ancestor {
+            if current.id.is_some() && current.id == *id {
+                return true;
+            }
+            ancestor = current.parent.as_deref();
+        }
+        false
+    }
+}
+
+/// A directory waiting to be read
+struct Dir {
+    path: PathBuf,
+    parent: Option<Arc<Ancestor>>,
+}
@@ -0,0 +1,289 @@
+//! Running as a service: detaching with `--daemon`, a pidfile, telling systemd when syncing
+//! has started and that it's still alive, and `evil_mount service install`
+
+use anyhow::{anyhow, Context, Result};
+use std::{
+    fmt::Write as _,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+use tracing::debug;
+
+use evil_mount::lock::is_running;
+
+/// Detaches from the terminal into the background, where stdout and stderr go to `log_file`
+/// or nowhere. Has to happen before any threads are started, since only the calling thread
+/// survives the fork. The working directory is kept, so relative paths still work
+#[cfg(unix)]
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    use nix::unistd::{dup2, fork, setsid, ForkResult};
+    use std::os::fd::AsRawFd;
+
+    // Opened first, so a bad path is reported on the terminal
+    let log = match log_file {
+        Some(log_file) => std::fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .with_context(|| anyhow!("Error opening log file {}", log_file.display()))?,
+        None => std::fs::OpenOptions::new().write(true).open("/dev/null")?,
+    };
+    let null = std::fs::File::open("/dev/null")?;
+
+    // SAFETY: nothing but this thread is running yet
+    match unsafe { fork() }.context("Error forking into the background")? {
+        ForkResult::Parent { child } => {
+            match log_file {
+                Some(log_file) => println!(
+                    "Running in the background as process {child}, logging to {}",
+                    log_file.display()
+                ),
+                None => println!("Running in the background as process {child}"),
+            }
+            std::process::exit(0);
+        }
+        ForkResult::Child => {}
+    }
+
+    setsid().context("Error starting a new session")?;
+    dup2(null.as_raw_fd(), 0)?;
+    dup2(log.as_raw_fd(), 1)?;
+    dup2(log.as_raw_fd(), 2)?;
+    Ok(())
+}
+
+#[cfg(not(unix))]
+pub fn daemonize(log_file: Option<&Path>) -> Result<()> {
+    let _ = log_file;
+    Err(anyhow!(
+        "--daemon is only supported on unix, run evil_mount as a service instead"
+    ))
+}
+
+/// A file holding the id of this process, removed again when dropped
+#[derive(Debug)]
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Fails if the pidfile names another process that is still running
+    pub fn create(path: &Path) -> Result<Self> {
+        if let Some(pid) = std::fs::read_to_string(path)
+            .ok()
+            .and_then(|pid| pid.trim().parse::<u32>().ok())
+        {
+            if pid != std::process::id() && is_running(pid) {
+                return Err(anyhow!(
+                    "evil_mount is already running as process {pid}, going by {}",
+                    path.display()
+                ));
+            }
+        }
+        std::fs::write(path, format!("{}\n", std::process::id()))
+            .with_context(|| anyhow!("Error writing pidfile {}", path.display()))?;
+        Ok(Self(path.to_path_buf()))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = std::fs::remove_file(&self.0);
+    }
+}
+
+/// Tells systemd about `state`, like `READY=1`, when it started us with `Type=notify`.
+/// Does nothing otherwise
+pub fn notify(state: &str) {
+    #[cfg(unix)]
+    {
+        use std::os::unix::net::UnixDatagram;
+
+        let Some(socket) = std::env::var_os("NOTIFY_SOCKET") else {
+            return;
+        };
+        let sent = UnixDatagram::unbound().and_then(|datagram| {
+            match socket.as_encoded_bytes().strip_prefix(b"@") {
+                // Abstract sockets start with @
+                #[cfg(target_os = "linux")]
+                Some(name) => {
+                    use std::os::{linux::net::SocketAddrExt, unix::net::SocketAddr};
+                    let addr = SocketAddr::from_abstract_name(name)?;
+                    datagram.send_to_addr(state.as_bytes(), &addr)
+                }
+                _ => datagram.send_to(state.as_bytes(), &socket),
+            }
+        });
+        if let Err(err) = sent {
+            debug!("Error notifying systemd: {err}");
+        }
+    }
+    #[cfg(not(unix))]
+    let _ = state;
+}
+
+/// Keeps telling systemd that we're alive, if it asked to be told with `WatchdogSec=`
+pub fn spawn_watchdog() {
+    let Some(interval) = std::env::var("WATCHDOG_USEC")
+        .ok()
+        .and_then(|usec| usec.parse().ok())
+        .map(Duration::from_micros)
+    else {
+        return;
+    };
+    // The watchdog might be meant for another process
+    if std::env::var("WATCHDOG_PID")
+        .ok()
+        .and_then(|pid| pid.parse::<u32>().ok())
+        .is_some_and(|pid| pid != std::process::id())
+    {
+        return;
+    }
+
+    tokio::spawn(async move {
+        // Twice as often as needed, so a busy moment doesn't get us killed
+        let mut ticks = tokio::time::interval(interval / 2);
+        loop {
+            ticks.tick().await;
+            notify("WATCHDOG=1");
+        }
+    });
+}
+
+/// Writes a systemd user unit, or a launchd agent on macOS, that runs `evil_mount sync`
+/// with `args` from the current directory. With `print` it's printed instead
+pub fn install(name: &str, args: &[String], print: bool) -> Result<()> {
+    let exe = std::env::current_exe().context("Error finding the evil_mount executable")?;
+    let working_dir = std::env::current_dir()?;
+    let home = std::env::var_os("HOME")
+        .map(PathBuf::from)
+        .ok_or_else(|| anyhow!("HOME isn't set"));
+
+    let (path, contents, start) = match cfg!(target_os = "macos") {
+        true => {
+            let home = home?;
+            let path = home
+                .join("Library/LaunchAgents")
+                .join(format!("{name}.plist"));
+            let log = home.join("Library/Logs").join(format!("{name}.log"));
+            let contents = launchd_agent(name, &exe, args, &working_dir, &log);
+            let start = format!("launchctl load -w {}", path.display());
+            (path, contents, start)
+        }
+        false => {
+            let config_dir = match std::env::var_os("XDG_CONFIG_HOME") {
+                Some(config_dir) => PathBuf::from(config_dir),
+                None => home?.join(".config"),
+            };
+            let path = config_dir
+                .join("systemd/user")
+                .join(format!("{name}.service"));
+            let contents = systemd_unit(&exe, args, &working_dir);
+            let start =
+                format!("systemctl --user daemon-reload && systemctl --user enable --now {name}");
+            (path, contents, start)
+        }
+    };
+
+    if print {
+        print!("{contents}");
+        return Ok(());
+    }
+    if path.exists() {
+        return Err(anyhow!("{} already exists!", path.display()));
+    }
+    if let Some(parent) = path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+    }
+    std::fs::write(&path, contents).with_context(|| anyhow!("Error writing {}", path.display()))?;
+    println!("Wrote {}, start it with:\n  {start}", path.display());
+    Ok(())
+}
+
+fn systemd_unit(exe: &Path, args: &[String], working_dir: &Path) -> String {
+    // systemd splits the command line itself, and expands % and $
+    let quote = |arg: &str| {
+        let arg = arg
+            .replace('\\', "\\\\")
+            .replace('"', "\\\"")
+            .replace('%', "%%")
+            .replace('$', "$$");
+        format!("\"{arg}\"")
+    };
+    let mut command = quote(&exe.to_string_lossy());
+    for arg in std::iter::once("sync").chain(args.iter().map(String::as_str)) {
+        command.push(' ');
+        command.push_str(&quote(arg));
+    }
+
+    format!(
+        "[Unit]
+Description=evil_mount sync
+After=network-online.target
+
+[Service]
+Type=notify
+NotifyAccess=main
+ExecStart={command}
+WorkingDirectory={}
+Restart=on-failure
+WatchdogSec=60
+
+[Install]
+WantedBy=default.target
+",
+        working_dir.to_string_lossy().replace('%', "%%")
+    )
+}
+
+fn launchd_agent(
+    label: &str,
+    exe: &Path,
+    args: &[String],
+    working_dir: &Path,
+    log: &Path,
+) -> String {
+    let escape = |text: &str| {
+        text.replace('&', "&amp;")
+            .replace('<', "&lt;")
+            .replace('>', "&gt;")
+    };
+    let mut arguments = String::new();
+    let program = exe.to_string_lossy();
+    for arg in [program.as_ref(), "sync"]
+        .into_iter()
+        .chain(args.iter().map(String::as_str))
+    {
+        let _ = writeln!(arguments, "\t\t<string>{}</string>", escape(arg));
+    }
+
+    format!(
+        r#"<?xml version="1.0" encoding="UTF-8"?>
+<!DOCTYPE plist PUBLIC "-//Apple//DTD PLIST 1.0//EN" "http://www.apple.com/DTDs/PropertyList-1.0.dtd">
+<plist version="1.0">
+<dict>
+	<key>Label</key>
+	<string>{}</string>
+	<key>ProgramArguments</key>
+	<array>
+{arguments}	</array>
+	<key>WorkingDirectory</key>
+	<string>{}</string>
+	<key>RunAtLoad</key>
+	<true/>
+	<key>KeepAlive</key>
+	<dict>
+		<key>SuccessfulExit</key>
+		<false/>
+	</dict>
+	<key>StandardOutPath</key>
+	<string>{log}</string>
+	<key>StandardErrorPath</key>
+	<string>{log}</string>
+</dict>
+</plist>
+"#,
+        escape(label),
+        escape(&working_dir.to_string_lossy()),
+        log = escape(&log.to_string_lossy()),
+    )
+}
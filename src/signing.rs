@@ -0,0 +1,272 @@
+//! Signed manifests, which prove a local backup wasn't changed behind evil_mount's back.
+//! After every cycle that changed the backup, the hashes of its files are written to
+//! `.evilmount/manifests/<timestamp>.b3` in the format of `b3sum`, next to a `.sig` file
+//! with an ed25519 signature over it. Every signature also covers the hash of the manifest
+//! before it, so manifests can't be removed, reordered or swapped without breaking the chain
+
+use anyhow::{anyhow, Context, Result};
+use blake3::Hash;
+use chrono::Utc;
+use ring::signature::{Ed25519KeyPair, KeyPair, UnparsedPublicKey, ED25519};
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::BTreeMap,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+use crate::{
+    compare::{format_manifest, parse_manifest},
+    copy::temp_path_for,
+    meta::metadata_dir,
+    snapshot::SNAPSHOT_FORMAT,
+};
+
+/// Key files have to be at least this long, like the ones for encryption
+const MIN_KEY_FILE_LEN: usize = 32;
+
+/// What's signed is this, the hash of the manifest and the hash of the one before it
+const SIGNED_CONTEXT: &str = "evil_mount signed manifest 1";
+
+/// Where the signed manifests of `backup_dir` are kept
+pub fn manifests_dir(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("manifests")
+}
+
+/// The key manifests are signed with, derived from a file of at least 32 bytes
+pub struct SigningKey(Ed25519KeyPair);
+
+impl SigningKey {
+    /// Reads the key from `path`, e.g. made with `head -c 32 /dev/urandom`. This does
+    /// blocking IO
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = std::fs::read(path)
+            .with_context(|| anyhow!("Error reading the signing key {}", path.display()))?;
+        if contents.len() < MIN_KEY_FILE_LEN {
+            return Err(anyhow!(
+                "The signing key {} is shorter than {MIN_KEY_FILE_LEN} bytes",
+                path.display()
+            ));
+        }
+        let seed = blake3::derive_key("evil_mount 2026-10-14 manifest signing key", &contents);
+        Ed25519KeyPair::from_seed_unchecked(&seed)
+            .map(Self)
+            .map_err(|err| anyhow!("Invalid signing key {}: {err}", path.display()))
+    }
+
+    pub fn public_key(&self) -> PublicKey {
+        let mut bytes = [0; 32];
+        bytes.copy_from_slice(self.0.public_key().as_ref());
+        PublicKey(bytes)
+    }
+}
+
+impl fmt::Debug for SigningKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_tuple("SigningKey")
+            .field(&self.public_key().to_string())
+            .finish()
+    }
+}
+
+/// The key signatures are checked with, written as 64 hex digits
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PublicKey([u8; 32]);
+
+impl FromStr for PublicKey {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        Hash::from_hex(s.trim())
+            .map(|hash| Self(*hash.as_bytes()))
+            .map_err(|_| format!("invalid public key {s}, expected 64 hex digits"))
+    }
+}
+
+impl fmt::Display for PublicKey {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&Hash::from_bytes(self.0).to_hex())
+    }
+}
+
+/// The `.sig` file next to a manifest
+#[derive(Debug, Serialize, Deserialize)]
+struct Signature {
+    /// blake3 of the manifest
+    manifest: String,
+    /// blake3 of the manifest before it, if there is one
+    previous: Option<String>,
+    public_key: String,
+    /// Over [`signed_message`]
+    signature: String,
+}
+
+fn signed_message(manifest: &str, previous: Option<&str>) -> String {
+    format!(
+        "{SIGNED_CONTEXT}\n{manifest}\n{}\n",
+        previous.unwrap_or("none")
+    )
+}
+
+/// The names of the signed manifests in `backup_dir`, oldest first. This does blocking IO
+fn manifest_names(backup_dir: &Path) -> Result<Vec<String>> {
+    let dir = manifests_dir(backup_dir);
+    let entries = match std::fs::read_dir(&dir) {
+        Ok(entries) => entries,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(Vec::new()),
+        Err(err) => return Err(err).with_context(|| anyhow!("Error reading {}", dir.display())),
+    };
+    let mut names = Vec::new();
+    for entry in entries {
+        let name = entry?.file_name().to_string_lossy().into_owned();
+        if let Some(name) = name.strip_suffix(".b3") {
+            names.push(name.to_string());
+        }
+    }
+    names.sort();
+    Ok(names)
+}
+
+/// Writes `hashes`, the files of `backup_dir`, as its newest manifest and signs it with
+/// `key`, chained to the manifest before it. Nothing is written if they're the same as in
+/// the newest manifest. This does blocking IO
+pub fn sign_manifest(
+    backup_dir: &Path,
+    hashes: &BTreeMap<PathBuf, Hash>,
+    key: &SigningKey,
+) -> Result<Option<PathBuf>> {
+    let dir = manifests_dir(backup_dir);
+    let contents = format_manifest(hashes.iter());
+    let previous = match manifest_names(backup_dir)?.pop() {
+        Some(name) => {
+            let path = dir.join(format!("{name}.b3"));
+            let previous = std::fs::read(&path)
+                .with_context(|| anyhow!("Error reading {}", path.display()))?;
+            if previous == contents.as_bytes() {
+                return Ok(None);
+            }
+            Some(blake3::hash(&previous).to_hex().to_string())
+        }
+        None => None,
+    };
+
+    let name = Utc::now().format(SNAPSHOT_FORMAT).to_string();
+    // Two manifests within the same second get a counter, like snapshots
+    let mut manifest_path = dir.join(format!("{name}.b3"));
+    let mut counter = 1;
+    while manifest_path.exists() {
+        manifest_path = dir.join(format!("{name}.{counter}.b3"));
+        counter += 1;
+    }
+
+    let manifest = blake3::hash(contents.as_bytes()).to_hex().to_string();
+    let message = signed_message(&manifest, previous.as_deref());
+    let signature = Signature {
+        manifest,
+        previous,
+        public_key: key.public_key().to_string(),
+        signature: hex(key.0.sign(message.as_bytes()).as_ref()),
+    };
+
+    std::fs::create_dir_all(&dir).with_context(|| anyhow!("Error creating {}", dir.display()))?;
+    // The signature goes first, a manifest without one would break the chain
+    let signature_path = manifest_path.with_extension("sig");
+    write_atomically(&signature_path, &serde_json::to_vec_pretty(&signature)?)?;
+    write_atomically(&manifest_path, contents.as_bytes())?;
+    Ok(Some(manifest_path))
+}
+
+fn write_atomically(path: &Path, contents: &[u8]) -> Result<()> {
+    let tmp_path = temp_path_for(path);
+    std::fs::write(&tmp_path, contents)
+        .and_then(|()| std::fs::rename(&tmp_path, path))
+        .with_context(|| anyhow!("Error writing {}", path.display()))
+}
+
+/// What checking the signed manifests of a backup found, once their chain checked out
+#[derive(Debug)]
+pub struct SignatureCheck {
+    /// How many manifests there are
+    pub manifests: usize,
+    /// What they were checked with
+    pub public_key: PublicKey,
+    /// Files that were changed, added or removed since the newest manifest was signed
+    pub unsigned: Vec<PathBuf>,
+}
+
+/// The signed manifests of a backup, after their chain checked out
+#[derive(Debug)]
+pub struct CheckedChain {
+    /// How many manifests there are
+    pub manifests: usize,
+    /// The files of the newest manifest and their hashes
+    pub latest: BTreeMap<PathBuf, Hash>,
+}
+
+/// Checks every manifest of `backup_dir` against its signature by `public_key`, and that
+/// each one is chained to the one before it. This does blocking IO
+pub fn check_chain(backup_dir: &Path, public_key: &PublicKey) -> Result<CheckedChain> {
+    let dir = manifests_dir(backup_dir);
+    let names = manifest_names(backup_dir)?;
+    if names.is_empty() {
+        return Err(anyhow!("{} has no signed manifests", backup_dir.display()));
+    }
+    let key = UnparsedPublicKey::new(&ED25519, public_key.0);
+
+    let mut previous: Option<String> = None;
+    let mut latest = String::new();
+    for name in &names {
+        let manifest_path = dir.join(format!("{name}.b3"));
+        let signature_path = manifest_path.with_extension("sig");
+        let contents = std::fs::read_to_string(&manifest_path)
+            .with_context(|| anyhow!("Error reading {}", manifest_path.display()))?;
+        let signature: Signature = std::fs::read(&signature_path)
+            .map_err(anyhow::Error::from)
+            .and_then(|signature| Ok(serde_json::from_slice(&signature)?))
+            .with_context(|| anyhow!("Error reading {}", signature_path.display()))?;
+
+        let manifest = blake3::hash(contents.as_bytes()).to_hex().to_string();
+        let signed = unhex(&signature.signature).and_then(|bytes| {
+            let message = signed_message(&signature.manifest, signature.previous.as_deref());
+            key.verify(message.as_bytes(), &bytes).ok()
+        });
+        if signed.is_none() {
+            return Err(anyhow!(
+                "The signature of manifest {name} wasn't made with public key {public_key}"
+            ));
+        }
+        if signature.manifest != manifest {
+            return Err(anyhow!("Manifest {name} was changed after it was signed"));
+        }
+        if signature.previous != previous {
+            return Err(anyhow!(
+                "Manifest {name} doesn't follow the one before it, manifests were removed, \
+                 replaced or reordered"
+            ));
+        }
+        previous = Some(manifest);
+        latest = contents;
+    }
+
+    Ok(CheckedChain {
+        manifests: names.len(),
+        latest: parse_manifest(&latest),
+    })
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{byte:02x}")).collect()
+}
+
+fn unhex(hex: &str) -> Option<Vec<u8>> {
+    hex.len()
+        .is_multiple_of(2)
+        .then(|| {
+            (0..hex.len())
+                .step_by(2)
+                .map(|i| u8::from_str_radix(hex.get(i..i + 2)?, 16).ok())
+                .collect()
+        })
+        .flatten()
+}
@@ -0,0 +1,262 @@
+//! Just enough SMTP to send a plain text email: EHLO, STARTTLS or TLS from the start,
+//! AUTH PLAIN, then a single message to every recipient
+
+use anyhow::{anyhow, Context, Result};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use chrono::Local;
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr, sync::Arc, time::Duration};
+use tokio::{
+    io::{AsyncBufReadExt, AsyncRead, AsyncWrite, AsyncWriteExt, BufReader},
+    net::TcpStream,
+};
+use tokio_rustls::{
+    rustls::{self, pki_types::ServerName},
+    TlsConnector,
+};
+
+/// How long the server gets for the whole conversation
+const SMTP_TIMEOUT: Duration = Duration::from_secs(60);
+
+/// How the connection to the SMTP server is encrypted
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum SmtpTls {
+    /// Connect in plain text and switch to TLS with STARTTLS, on port 587 by default
+    #[default]
+    #[serde(rename = "starttls")]
+    StartTls,
+    /// TLS from the start, on port 465 by default
+    #[serde(rename = "tls")]
+    Tls,
+    /// Never encrypt, on port 25 by default. Only for servers on the same machine or network
+    #[serde(rename = "none")]
+    None,
+}
+
+impl SmtpTls {
+    fn default_port(self) -> u16 {
+        match self {
+            Self::StartTls => 587,
+            Self::Tls => 465,
+            Self::None => 25,
+        }
+    }
+}
+
+impl FromStr for SmtpTls {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "starttls" => Ok(Self::StartTls),
+            "tls" => Ok(Self::Tls),
+            "none" => Ok(Self::None),
+            _ => Err(format!(
+                "unknown SMTP encryption {s}, expected starttls, tls or none"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for SmtpTls {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::StartTls => "starttls",
+            Self::Tls => "tls",
+            Self::None => "none",
+        })
+    }
+}
+
+/// The server to send through and who to send to
+#[derive(Debug, Clone)]
+pub(crate) struct Mailer<'a> {
+    /// `host` or `host:port`
+    pub server: &'a str,
+    pub tls: SmtpTls,
+    pub username: Option<&'a str>,
+    pub password: Option<&'a str>,
+    pub from: &'a str,
+    pub to: &'a [String],
+}
+
+impl Mailer<'_> {
+    /// Sends one email with `subject` and the plain text `body`
+    pub async fn send(&self, subject: &str, body: &str) -> Result<()> {
+        tokio::time::timeout(SMTP_TIMEOUT, self.send_now(subject, body))
+            .await
+            .map_err(|_| anyhow!("Timed out talking to {}", self.server))?
+            .with_context(|| anyhow!("Error sending an email through {}", self.server))
+    }
+
+    async fn send_now(&self, subject: &str, body: &str) -> Result<()> {
+        let (host, port) = match self.server.rsplit_once(':') {
+            Some((host, port)) => (
+                host,
+                port.parse()
+                    .map_err(|_| anyhow!("Invalid port in SMTP server {}", self.server))?,
+            ),
+            None => (self.server, self.tls.default_port()),
+        };
+        let tcp = TcpStream::connect((host, port)).await?;
+        match self.tls {
+            SmtpTls::Tls => {
+                let tls = connect_tls(host, tcp).await?;
+                let mut smtp = Smtp::new(tls);
+                smtp.reply(220).await?;
+                smtp.ehlo().await?;
+                self.deliver(&mut smtp, subject, body).await
+            }
+            SmtpTls::StartTls => {
+                let mut smtp = Smtp::new(tcp);
+                smtp.reply(220).await?;
+                smtp.ehlo().await?;
+                smtp.command("STARTTLS", 220).await?;
+                let tls = connect_tls(host, smtp.stream.into_inner()).await?;
+                let mut smtp = Smtp::new(tls);
+                smtp.ehlo().await?;
+                self.deliver(&mut smtp, subject, body).await
+            }
+            SmtpTls::None => {
+                let mut smtp = Smtp::new(tcp);
+                smtp.reply(220).await?;
+                smtp.ehlo().await?;
+                self.deliver(&mut smtp, subject, body).await
+            }
+        }
+    }
+
+    async fn deliver<S: AsyncRead + AsyncWrite + Unpin>(
+        &self,
+        smtp: &mut Smtp<S>,
+        subject: &str,
+        body: &str,
+    ) -> Result<()> {
+        if let Some(username) = self.username {
+            let password = self.password.unwrap_or_default();
+            let credentials = STANDARD.encode(format!("\0{username}\0{password}"));
+            smtp.command(&format!("AUTH PLAIN {credentials}"), 235)
+                .await
+                .context("The SMTP server didn't accept the username and password")?;
+        }
+        smtp.command(&format!("MAIL FROM:<{}>", address(self.from)), 250)
+            .await?;
+        for to in self.to {
+            smtp.command(&format!("RCPT TO:<{}>", address(to)), 250)
+                .await?;
+        }
+        smtp.command("DATA", 354).await?;
+        smtp.write(&self.message(subject, body)).await?;
+        smtp.reply(250).await?;
+        // The email is sent, whatever the server makes of QUIT
+        let _ = smtp.command("QUIT", 221).await;
+        Ok(())
+    }
+
+    /// The headers and body of the email, dot-stuffed and ended with a line of just a `.`
+    fn message(&self, subject: &str, body: &str) -> String {
+        let subject = match subject.is_ascii() {
+            true => subject.to_string(),
+            false => format!("=?utf-8?b?{}?=", STANDARD.encode(subject)),
+        };
+        let mut message = format!(
+            "From: {}\r\nTo: {}\r\nSubject: {subject}\r\nDate: {}\r\nMIME-Version: 1.0\r\n\
+             Content-Type: text/plain; charset=utf-8\r\nContent-Transfer-Encoding: 8bit\r\n\r\n",
+            self.from,
+            self.to.join(", "),
+            Local::now().to_rfc2822(),
+        );
+        for line in body.lines() {
+            if line.starts_with('.') {
+                message.push('.');
+            }
+            message.push_str(line);
+            message.push_str("\r\n");
+        }
+        message.push_str(".\r\n");
+        message
+    }
+}
+
+/// The address in `Name <address>`, or all of it
+fn address(mailbox: &str) -> &str {
+    match (mailbox.rfind('<'), mailbox.rfind('>')) {
+        (Some(start), Some(end)) if start < end => &mailbox[start + 1..end],
+        _ => mailbox.trim(),
+    }
+}
+
+async fn connect_tls(
+    host: &str,
+    tcp: TcpStream,
+) -> Result<tokio_rustls::client::TlsStream<TcpStream>> {
+    let roots = rustls::RootCertStore {
+        roots: webpki_roots::TLS_SERVER_ROOTS.to_vec(),
+    };
+    let config = rustls::ClientConfig::builder_with_provider(Arc::new(
+        rustls::crypto::ring::default_provider(),
+    ))
+    .with_safe_default_protocol_versions()?
+    .with_root_certificates(roots)
+    .with_no_client_auth();
+    let name = ServerName::try_from(host.to_string())
+        .map_err(|_| anyhow!("Invalid SMTP server name {host}"))?;
+    Ok(TlsConnector::from(Arc::new(config))
+        .connect(name, tcp)
+        .await?)
+}
+
+/// A connection to an SMTP server
+struct Smtp<S> {
+    stream: BufReader<S>,
+}
+
+impl<S: AsyncRead + AsyncWrite + Unpin> Smtp<S> {
+    fn new(stream: S) -> Self {
+        Self {
+            stream: BufReader::new(stream),
+        }
+    }
+
+    async fn ehlo(&mut self) -> Result<()> {
+        self.command("EHLO evil_mount", 250).await
+    }
+
+    async fn command(&mut self, command: &str, expected: u16) -> Result<()> {
+        self.write(&format!("{command}\r\n")).await?;
+        self.reply(expected).await.with_context(|| {
+            // Credentials stay out of error messages
+            let verb = command.split(' ').take(2).collect::<Vec<_>>().join(" ");
+            anyhow!("The SMTP server refused {verb}")
+        })
+    }
+
+    async fn write(&mut self, data: &str) -> Result<()> {
+        let stream = self.stream.get_mut();
+        stream.write_all(data.as_bytes()).await?;
+        stream.flush().await?;
+        Ok(())
+    }
+
+    /// Reads a reply, which can span several `250-...` lines, and checks its code
+    async fn reply(&mut self, expected: u16) -> Result<()> {
+        loop {
+            let mut line = String::new();
+            if self.stream.read_line(&mut line).await? == 0 {
+                return Err(anyhow!("The SMTP server closed the connection"));
+            }
+            let line = line.trim_end();
+            let code: u16 = line
+                .get(..3)
+                .and_then(|code| code.parse().ok())
+                .ok_or_else(|| anyhow!("Invalid reply from the SMTP server: {line}"))?;
+            if line.as_bytes().get(3) == Some(&b'-') {
+                continue;
+            }
+            return match code == expected {
+                true => Ok(()),
+                false => Err(anyhow!("{line}")),
+            };
+        }
+    }
+}
@@ -0,0 +1,162 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use tracing::debug;
+
+use crate::{
+    filter::{walk_files, IgnoreSet},
+    meta::metadata_dir,
+};
+
+/// Snapshot directory names are UTC timestamps in this format, which sorts chronologically
+pub const SNAPSHOT_FORMAT: &str = "%Y-%m-%dT%H-%M-%SZ";
+
+/// Where the snapshots of `backup_dir` are kept
+pub fn snapshots_dir(backup_dir: &Path) -> PathBuf {
+    metadata_dir(backup_dir).join("snapshots")
+}
+
+/// Records the current contents of `backup_dir` as a new snapshot and returns its path.
+///
+/// Files that have the same size and modification time as in the previous snapshot are
+/// hard linked to it, so every snapshot is a full tree but only changed files take up space.
+/// Those are copied rather than linked to backup_dir, as delta and append copies write into
+/// the backup files in place, which would change the snapshots sharing them. The first
+/// snapshot is a full copy. This does blocking IO
+pub fn take_snapshot(backup_dir: &Path, ignore: &IgnoreSet) -> Result<PathBuf> {
+    let snapshots_dir = snapshots_dir(backup_dir);
+    let previous_dir = list_snapshots(backup_dir)?
+        .pop()
+        .map(|name| snapshots_dir.join(name));
+    let name = Utc::now().format(SNAPSHOT_FORMAT).to_string();
+
+    // Two snapshots within the same second get a counter
+    let mut snapshot_dir = snapshots_dir.join(&name);
+    let mut counter = 1;
+    while snapshot_dir.exists() {
+        snapshot_dir = snapshots_dir.join(format!("{name}.{counter}"));
+        counter += 1;
+    }
+
+    let (mut linked, mut copied) = (0, 0);
+    for file_info in walk_files(backup_dir, ignore) {
+        let relative_path = file_info.path().strip_prefix(backup_dir)?;
+        let dst_path = snapshot_dir.join(relative_path);
+
+        if let Some(parent) = dst_path.parent() {
+            std::fs::create_dir_all(parent)
+                .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        }
+
+        let unchanged = previous_dir
+            .as_ref()
+            .map(|previous_dir| previous_dir.join(relative_path))
+            .filter(|previous_path| same_file_info(file_info.path(), previous_path));
+        // Not every filesystem supports hard links
+        if let Some(previous_path) = unchanged {
+            if std::fs::hard_link(&previous_path, &dst_path).is_ok() {
+                linked += 1;
+                continue;
+            }
+        }
+
+        copy_into_snapshot(file_info.path(), &dst_path).with_context(|| {
+            anyhow!(
+                "Error copying {} into snapshot {}",
+                file_info.path().display(),
+                snapshot_dir.display()
+            )
+        })?;
+        copied += 1;
+    }
+
+    std::fs::create_dir_all(&snapshot_dir)
+        .with_context(|| anyhow!("Error creating {}", snapshot_dir.display()))?;
+    debug!(
+        "Snapshot {} links {linked} unchanged files and copies {copied}",
+        snapshot_dir.display()
+    );
+
+    Ok(snapshot_dir)
+}
+
+/// Whether `path` and `previous_path` have the same size and modification time
+fn same_file_info(path: &Path, previous_path: &Path) -> bool {
+    let (Ok(metadata), Ok(previous)) = (
+        std::fs::metadata(path),
+        std::fs::symlink_metadata(previous_path),
+    ) else {
+        return false;
+    };
+    previous.is_file()
+        && metadata.len() == previous.len()
+        && metadata.modified().ok() == previous.modified().ok()
+}
+
+/// Copies `src` to `dst` with its modification time, which the next snapshot compares
+fn copy_into_snapshot(src: &Path, dst: &Path) -> std::io::Result<()> {
+    std::fs::copy(src, dst)?;
+    let metadata = std::fs::metadata(src)?;
+    filetime::set_file_mtime(
+        dst,
+        filetime::FileTime::from_last_modification_time(&metadata),
+    )
+}
+
+/// The names of every snapshot of `backup_dir`, oldest first
+pub fn list_snapshots(backup_dir: &Path) -> Result<Vec<String>> {
+    let snapshots_dir = snapshots_dir(backup_dir);
+    if !snapshots_dir.exists() {
+        return Ok(Vec::new());
+    }
+
+    let mut names = Vec::new();
+    for entry in std::fs::read_dir(&snapshots_dir)
+        .with_context(|| anyhow!("Error reading {}", snapshots_dir.display()))?
+    {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            names.push(entry.file_name().to_string_lossy().into_owned());
+        }
+    }
+    names.sort();
+
+    Ok(names)
+}
+
+/// Finds the snapshot called `at`, or the newest one whose name starts with `at`, so
+/// `2024-05-01` picks the last snapshot of that day
+pub fn find_snapshot(backup_dir: &Path, at: &str) -> Result<PathBuf> {
+    let names = list_snapshots(backup_dir)?;
+
+    let name = names
+        .iter()
+        .find(|name| name.as_str() == at)
+        .or_else(|| names.iter().rev().find(|name| name.starts_with(at)))
+        .ok_or_else(|| match names.is_empty() {
+            true => anyhow!("{} has no snapshots", backup_dir.display()),
+            false => anyhow!(
+                "No snapshot matches {at}, available snapshots are:\n{}",
+                names.join("\n")
+            ),
+        })?;
+
+    Ok(snapshots_dir(backup_dir).join(name))
+}
+
+/// Removes all but the newest `keep` snapshots, returns the ones that were removed. This
+/// does blocking IO
+pub fn prune_snapshots(backup_dir: &Path, keep: usize) -> Result<Vec<PathBuf>> {
+    let names = list_snapshots(backup_dir)?;
+    let snapshots_dir = snapshots_dir(backup_dir);
+
+    let mut removed = Vec::new();
+    for name in &names[..names.len().saturating_sub(keep)] {
+        let snapshot_dir = snapshots_dir.join(name);
+        std::fs::remove_dir_all(&snapshot_dir)
+            .with_context(|| anyhow!("Error removing snapshot {}", snapshot_dir.display()))?;
+        removed.push(snapshot_dir);
+    }
+
+    Ok(removed)
+}
@@ -0,0 +1,179 @@
+//! Copies of sparse files, like disk images and databases, that keep their holes instead of
+//! writing them out as zeros, see [`CopyOptions::sparse`](crate::CopyOptions::sparse).
+//!
+//! Linux and the BSDs find the data in a file with `SEEK_DATA` and `SEEK_HOLE`, so holes
+//! aren't even read. Elsewhere the whole file is read and runs of zeros are left out of the
+//! copy. Windows copies are marked sparse with `FSCTL_SET_SPARSE` first, since NTFS fills
+//! in whatever is skipped otherwise
+
+use blake3::{Hash, Hasher};
+use std::{
+    fs::{File, Metadata},
+    io::{self, Read, Seek, SeekFrom, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use crate::throttle::Throttle;
+
+/// How much is read, and checked for zeros, at a time
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Whether the file takes up less space on disk than it's long
+#[cfg(unix)]
+pub(crate) fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    metadata.blocks().saturating_mul(512) < metadata.len()
+}
+
+#[cfg(windows)]
+pub(crate) fn is_sparse(metadata: &Metadata) -> bool {
+    use std::os::windows::fs::MetadataExt;
+    const FILE_ATTRIBUTE_SPARSE_FILE: u32 = 0x200;
+    metadata.file_attributes() & FILE_ATTRIBUTE_SPARSE_FILE != 0
+}
+
+#[cfg(not(any(unix, windows)))]
+pub(crate) fn is_sparse(metadata: &Metadata) -> bool {
+    let _ = metadata;
+    false
+}
+
+/// Copies `path` to `dst_path`, which is created, leaving holes where `path` has them. With
+/// `zeros` every run of zeros becomes a hole as well. Returns the length of what was read,
+/// and its hash if asked for. Data written is limited by `bwlimit`, holes aren't. This
+/// does blocking IO, and has to run inside the runtime for `bwlimit`
+pub(crate) fn copy_sparse(
+    path: &Path,
+    dst_path: &Path,
+    zeros: bool,
+    hash: bool,
+    bwlimit: Option<Arc<Throttle>>,
+) -> io::Result<(Option<Hash>, u64)> {
+    let mut src = File::open(path)?;
+    let metadata = src.metadata()?;
+    let len = metadata.len();
+    let mut dst = File::create(dst_path)?;
+    mark_sparse(&dst)?;
+    let runtime = bwlimit.as_ref().map(|_| tokio::runtime::Handle::current());
+
+    let mut hasher = hash.then(Hasher::new);
+    let mut buf = vec![0; CHUNK_SIZE];
+    // Where the last range of data ended
+    let mut end_of_data = 0;
+    for (start, end) in data_ranges(&src, len)? {
+        if let Some(hasher) = &mut hasher {
+            hash_zeros(hasher, start - end_of_data);
+        }
+        src.seek(SeekFrom::Start(start))?;
+        let mut offset = start;
+        while offset < end {
+            let chunk = &mut buf[..(end - offset).min(CHUNK_SIZE as u64) as usize];
+            let read = src.read(chunk)?;
+            if read == 0 {
+                // The file got shorter while it was being copied
+                break;
+            }
+            let chunk = &chunk[..read];
+            if let Some(hasher) = &mut hasher {
+                hasher.update(chunk);
+            }
+            if !(zeros && chunk.iter().all(|&byte| byte == 0)) {
+                if let (Some(bwlimit), Some(runtime)) = (&bwlimit, &runtime) {
+                    runtime.block_on(bwlimit.take(read));
+                }
+                dst.seek(SeekFrom::Start(offset))?;
+                dst.write_all(chunk)?;
+            }
+            offset += read as u64;
+        }
+        end_of_data = offset;
+    }
+    if let Some(hasher) = &mut hasher {
+        hash_zeros(hasher, len.saturating_sub(end_of_data));
+    }
+    // Holes at the end aren't written, so the length has to be set
+    dst.set_len(end_of_data.max(len))?;
+    drop(dst);
+
+    std::fs::set_permissions(dst_path, metadata.permissions())?;
+    Ok((hasher.map(|hasher| hasher.finalize()), end_of_data.max(len)))
+}
+
+fn hash_zeros(hasher: &mut Hasher, mut len: u64) {
+    static ZEROS: [u8; CHUNK_SIZE] = [0; CHUNK_SIZE];
+    while len > 0 {
+        let chunk = len.min(CHUNK_SIZE as u64) as usize;
+        hasher.update(&ZEROS[..chunk]);
+        len -= chunk as u64;
+    }
+}
+
+/// Where `file`, which is `len` bytes long, has data, as start and end offsets. Everything
+/// else is a hole
+#[cfg(any(target_os = "linux", target_os = "android", target_os = "freebsd"))]
+fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    use nix::{
+        errno::Errno,
+        unistd::{lseek, Whence},
+    };
+    use std::os::fd::AsRawFd;
+
+    let fd = file.as_raw_fd();
+    let mut ranges = Vec::new();
+    let mut offset = 0;
+    while offset < len {
+        let start = match lseek(fd, offset as i64, Whence::SeekData) {
+            Ok(start) => start as u64,
+            // Only holes are left
+            Err(Errno::ENXIO) => break,
+            // The filesystem can't tell, so everything counts as data
+            Err(Errno::EINVAL | Errno::EOPNOTSUPP) if ranges.is_empty() => {
+                return Ok(vec![(0, len)])
+            }
+            Err(err) => return Err(err.into()),
+        };
+        let end = (lseek(fd, start as i64, Whence::SeekHole)? as u64).min(len);
+        ranges.push((start, end));
+        offset = end;
+    }
+    Ok(ranges)
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", target_os = "freebsd")))]
+fn data_ranges(file: &File, len: u64) -> io::Result<Vec<(u64, u64)>> {
+    let _ = file;
+    Ok(vec![(0, len)])
+}
+
+/// Lets NTFS leave out the parts of `file` that are never written
+#[cfg(windows)]
+fn mark_sparse(file: &File) -> io::Result<()> {
+    use std::{os::windows::io::AsRawHandle, ptr};
+    use windows_sys::Win32::System::{Ioctl::FSCTL_SET_SPARSE, IO::DeviceIoControl};
+
+    let mut returned = 0;
+    // SAFETY: the handle is open for as long as `file` is, and no buffers are passed
+    let marked = unsafe {
+        DeviceIoControl(
+            file.as_raw_handle(),
+            FSCTL_SET_SPARSE,
+            ptr::null(),
+            0,
+            ptr::null_mut(),
+            0,
+            &mut returned,
+            ptr::null_mut(),
+        )
+    };
+    match marked {
+        0 => Err(io::Error::last_os_error()),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(not(windows))]
+fn mark_sparse(file: &File) -> io::Result<()> {
+    let _ = file;
+    Ok(())
+}
@@ -0,0 +1,114 @@
+//! Carrying what syncing keeps track of over to another machine: the hash cache and what
+//! every file looked like when it was last synced, so the first sync there doesn't hash or
+//! copy everything again.
+//!
+//! Exports are JSON with a `format` version. Newer formats only add fields, which older
+//! versions ignore, and raise `readable_from` when they change something an older version
+//! would misread. What each part holds is saved as it is on disk, every path in it relative
+//! to work_dir
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use serde::{Deserialize, Serialize};
+use serde_json::Value;
+use std::{
+    io,
+    path::{Path, PathBuf},
+};
+
+use crate::snapshot::SNAPSHOT_FORMAT;
+
+/// The format exports are written in
+pub const STATE_FORMAT: u32 = 1;
+
+/// The state of every pair, in the order of the config file
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct ExportedState {
+    pub format: u32,
+    /// The oldest format that can still import this
+    pub readable_from: u32,
+    /// When it was exported, like 2024-05-01T12-00-00Z
+    pub exported_at: String,
+    pub pairs: Vec<PairState>,
+}
+
+/// What syncing keeps track of for one work_dir and backup_dir pair. A part is missing if
+/// there was nothing saved for it
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct PairState {
+    /// Where it was exported from, for telling pairs apart
+    pub work_dir: PathBuf,
+    pub backup_dir: String,
+    /// Hashes of the files in work_dir, see [`DetectChanges::Hash`](crate::DetectChanges)
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub hash_cache: Option<Value>,
+    /// What every file looked like when it was last synced
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub tracking: Option<Value>,
+    /// What both copies of every file looked like after the last sync both ways
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub both_ways: Option<Value>,
+}
+
+impl ExportedState {
+    pub fn new(pairs: Vec<PairState>) -> Self {
+        Self {
+            format: STATE_FORMAT,
+            readable_from: STATE_FORMAT,
+            exported_at: Utc::now().format(SNAPSHOT_FORMAT).to_string(),
+            pairs,
+        }
+    }
+
+    pub fn read(path: &Path) -> Result<Self> {
+        let contents =
+            std::fs::read(path).with_context(|| anyhow!("Error reading {}", path.display()))?;
+        let state: Self = serde_json::from_slice(&contents)
+            .with_context(|| anyhow!("Error parsing exported state {}", path.display()))?;
+        if state.readable_from > STATE_FORMAT {
+            return Err(anyhow!(
+                "{} was exported in format {} by a newer evil_mount, which needs format {} to import. This one only reads up to {STATE_FORMAT}",
+                path.display(),
+                state.format,
+                state.readable_from
+            ));
+        }
+        Ok(state)
+    }
+
+    pub fn write(&self, path: &Path) -> Result<()> {
+        let contents = serde_json::to_vec_pretty(self)?;
+        std::fs::write(path, contents).with_context(|| anyhow!("Error writing {}", path.display()))
+    }
+}
+
+/// The JSON saved at `path`, or `None` if nothing is
+pub(crate) fn read_part(path: &Path) -> Result<Option<Value>> {
+    match std::fs::read(path) {
+        Ok(contents) => serde_json::from_slice(&contents)
+            .map(Some)
+            .with_context(|| anyhow!("Error parsing {}", path.display())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| anyhow!("Error reading {}", path.display())),
+    }
+}
+
+/// Saves `part` at `path`, or removes what's there if there's nothing to save
+pub(crate) fn write_part(path: &Path, part: Option<&Value>) -> Result<()> {
+    match part {
+        Some(part) => {
+            if let Some(parent) = path.parent() {
+                std::fs::create_dir_all(parent)
+                    .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+            }
+            std::fs::write(path, serde_json::to_vec(part)?)
+                .with_context(|| anyhow!("Error writing {}", path.display()))
+        }
+        None => match std::fs::remove_file(path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => {
+                Err(err).with_context(|| anyhow!("Error removing {}", path.display()))
+            }
+            _ => Ok(()),
+        },
+    }
+}
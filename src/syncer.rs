@@ -0,0 +1,3871 @@
+use anyhow::{anyhow, Context, Result};
+use chrono::{DateTime, Local, Timelike};
+use futures::Stream;
+use indicatif::HumanBytes;
+use std::{
+    borrow::Cow,
+    cmp::Reverse,
+    collections::{BTreeMap, BTreeSet, HashMap, HashSet},
+    fs::{Metadata, Permissions},
+    io,
+    path::{Path, PathBuf},
+    pin::Pin,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    task::{self, Poll},
+    time::{Duration, SystemTime},
+};
+use tokio::{
+    fs::{self, remove_file},
+    sync::{
+        mpsc::{self, UnboundedReceiver, UnboundedSender},
+        Semaphore,
+    },
+    task::JoinHandle,
+    time::Instant,
+};
+use tokio_util::sync::CancellationToken;
+use tracing::{debug, debug_span, field, info, warn, Instrument, Span};
+use walkdir::WalkDir;
+
+use crate::{
+    backend::{list_dirs, list_files, temp_file, Backend, FileMetadata, LocalBackend},
+    bidir::{self, sync_both_ways, ConflictStrategy},
+    case::{fold, is_case_insensitive, original_path, CaseCheck, CaseCollisions, Destination},
+    clear::{
+        check_filesystem, clear_dir, is_mount_point, mount_point_error, remove_file_in, Leftover,
+    },
+    compare::{compare_trees, verify_trees, CompareBy, Manifest, TreeDiff, Verification},
+    consistent::{open_for_writing, AtomicCopies, Busy, SourceLock, Version},
+    control::SyncControl,
+    copy::{
+        clean_temp_files, copy_to_dst, free_space, prune_empty_parents, set_mtime, CopyOptions,
+        VerifyWrites,
+    },
+    detect::{ChangeDetector, DetectChanges},
+    error::{During, ErrorPolicy, Operation, SyncError},
+    filter::{walk_files, walk_files_in, IgnoreSet, Symlinks, WalkErrors},
+    git::{commit_backup, git_dir},
+    guard::{guard_backup, GuardBackup, Written},
+    hash::HashPool,
+    hooks::{run_hook, CycleReport, HookContext},
+    index::{file_metadata, LiveIndex, ScanIndex, Side},
+    merge::{InitMode, Keep, MergePolicy},
+    meta::{hash_cache_path, manifest_path, tracking_path},
+    metrics::Metrics,
+    moves::{MoveTracker, Moved, MOVE_GRACE},
+    notifications::{EmailNotifications, EmailState, Notifier, NotifyTarget, EMAIL_CHECK_INTERVAL},
+    poll::{copy_files, Schedule},
+    power::{PowerState, POWER_CHECK_INTERVAL},
+    priority::{CopyOrder, Priorities},
+    progress::{InitProgress, DEFAULT_FILE_PROGRESS_THRESHOLD},
+    quota::{disk_usage, evict, Quota, QuotaExceeded, QuotaPolicy},
+    rotate::{rotate_if_due, Rotation},
+    signing::{check_chain, manifests_dir, sign_manifest, PublicKey, SignatureCheck, SigningKey},
+    snapshot::{find_snapshot, list_snapshots, prune_snapshots, take_snapshot},
+    state::{read_part, write_part, PairState},
+    transform::Transforms,
+    trash::{discard, quarantine_dir_for},
+    unicode::NormalizeUnicode,
+    vanished::{BackupRoot, Vanished, MOUNT_CHECK_INTERVAL},
+    vfs::{not_found, Entry, EntryKind, RealFs, Vfs},
+    watchdog::{TimedOut, Watchdog},
+    watcher::watch_files,
+    window::{QuietHours, SyncWindow},
+};
+
+/// Settings that control how changes in work_dir are applied to backup_dir
+#[derive(Debug, Clone, Default)]
+pub struct SyncOptions {
+    /// Periodically scan work_dir instead of using native filesystem events
+    pub poll: bool,
+    /// How often work_dir is scanned when polling or syncing both ways. Defaults to
+    /// [`DEFAULT_INTERVAL`]
+    pub interval: Option<Duration>,
+    /// While nothing changes scans back off, up to this long apart. Defaults to four times
+    /// [`SyncOptions::interval`]
+    pub max_interval: Option<Duration>,
+    /// Pause syncing while the machine runs on battery or a metered connection, see
+    /// [`PowerState`]. Whatever changed in the meantime is swept up once that changes
+    pub pause_on_battery: bool,
+    /// Hold changes back during these times of day, see [`crate::window`]
+    pub quiet_hours: Option<QuietHours>,
+    /// Only sync during the minutes this matches, see [`crate::window`]
+    pub sync_window: Option<SyncWindow>,
+    /// While on battery or a metered connection, scan at most this often, and copy files
+    /// that changed at least this long after their last change
+    pub battery_interval: Option<Duration>,
+    /// When set, deletions are propagated to backup_dir after waiting this long, and files
+    /// moved inside work_dir have their backup moved along with them
+    pub delete_after: Option<Duration>,
+    /// Paths that are never synced
+    pub ignore: IgnoreSet,
+    /// Stop syncing when a walk of work_dir can't read something, instead of leaving it out
+    /// with a warning
+    pub fail_on_walk_error: bool,
+    /// How to decide whether a file changed and needs copying again
+    pub detect_changes: DetectChanges,
+    /// Modification times less than this far apart could be the same, so files modified
+    /// that recently are hashed to tell. Zero trusts modification times completely
+    pub mtime_tolerance: Duration,
+    /// The threads files are hashed on, for verifying and for detecting changes by hash.
+    /// Syncers can share one so they don't start more threads than there are cores
+    pub hash_pool: HashPool,
+    /// Wipe work_dir during initialization instead of only replacing files that differ
+    pub force_init: bool,
+    /// Whether initialization makes work_dir match the backup or merges the two. Merging
+    /// ignores [`SyncOptions::force_init`] and [`SyncOptions::no_clear`]
+    pub init_mode: InitMode,
+    /// Which copy of a file that differs a merge keeps
+    pub merge_policy: MergePolicy,
+    /// How initialization decides which files in work_dir differ from backup_dir
+    pub init_compare: CompareBy,
+    /// How individual files are copied
+    pub copy: CopyOptions,
+    /// Snapshot backup_dir after every sync cycle that changed something, keeping this
+    /// many snapshots around
+    pub snapshots: Option<usize>,
+    /// Hard link a local backup into a dated directory at the end of every day or hour, see
+    /// [`crate::rotate`]
+    pub rotation: Option<Rotation>,
+    /// Keep what the backup takes up, snapshots and cleared files included, under this
+    /// size, see [`crate::quota`]
+    pub quota: Option<Quota>,
+    /// What to do about files whose paths only differ in case, which the backup can't tell
+    /// apart. Local backups are checked for whether they can, so this only needs to be set
+    /// for remote ones, or to do something else than warn. Not when syncing both ways
+    pub case_collisions: Option<CaseCollisions>,
+    /// Which Unicode normalization form paths in the backup are brought into, so a file
+    /// named on macOS and on Linux has one backup
+    pub normalize_unicode: NormalizeUnicode,
+    /// Also copy changes made in the backup back into work_dir
+    pub bidirectional: bool,
+    /// How files changed on both sides are settled when syncing both ways
+    pub conflict: ConflictStrategy,
+    /// Allow work_dir and backup_dir to be the same directory or inside one another
+    pub allow_overlap: bool,
+    /// Initialize by merging the backup into work_dir, never removing files from work_dir
+    pub no_clear: bool,
+    /// Only sync files, leaving out directories that have none in them. Otherwise every
+    /// directory is recreated on the other side, empty or not, unless syncing both ways
+    pub no_empty_dirs: bool,
+    /// Move files that initialization removes from work_dir to the trash instead of
+    /// deleting them
+    pub use_trash: bool,
+    /// Clear what's mounted inside work_dir when initializing too, instead of leaving it
+    pub cross_mounts: bool,
+    /// How long a file has to go unmodified before it is copied. Files modified more
+    /// recently are still being written, and are tried again once they should have settled
+    pub settle: Duration,
+    /// Changes to the same file this close together are copied once, after the last of
+    /// them, instead of once each. Zero copies on every change
+    pub debounce: Duration,
+    /// How many files are copied into the backup at once, further copies wait their turn.
+    /// Defaults to [`DEFAULT_MAX_CONCURRENT_COPIES`]
+    pub max_concurrent_copies: Option<usize>,
+    /// Scans of work_dir are skipped while more files than this wait to be copied, so a
+    /// big change set that is still copying isn't checked again. Defaults to
+    /// [`DEFAULT_MAX_PENDING`]
+    pub max_pending: Option<u64>,
+    /// Which of the files a scan or sweep found changed are copied first. Without any,
+    /// smaller files still go before bigger ones
+    pub priorities: Priorities,
+    /// How often a copy that failed is tried again before the failure is reported as an
+    /// [`SyncEvent::Error`]. Defaults to [`DEFAULT_MAX_RETRIES`]
+    pub max_retries: Option<u32>,
+    /// How often syncing is restarted after failing or panicking, waiting twice as long
+    /// every time, before it gives up and the stream of events ends. Defaults to
+    /// [`DEFAULT_MAX_RESTARTS`]
+    pub max_restarts: Option<u32>,
+    /// Whether syncing stops once [`SyncOptions::max_errors`] copies failed for good, after
+    /// their retries
+    pub error_policy: ErrorPolicy,
+    /// How many copies may fail for good before syncing stops with
+    /// [`ErrorPolicy::Abort`], 1 without this
+    pub max_errors: Option<u64>,
+    /// Cancel copies into the backup that take longer than this, and count it as degraded
+    /// until one finishes in time again, see [`crate::watchdog`]. Waiting for a copy slot
+    /// doesn't count
+    pub copy_timeout: Option<Duration>,
+    /// Cancel sweeps that take longer than this the same way. What they didn't get to is
+    /// copied by the next one
+    pub cycle_timeout: Option<Duration>,
+    /// What's done to files matching a glob before they're copied into the backup. Their
+    /// backups are never copied back into work_dir
+    pub transforms: Transforms,
+    /// Hold a shared lock on every file while it's copied, so writers that lock, like
+    /// SQLite, wait for the copy. A file a writer has locked is copied again later
+    pub lock_before_copy: bool,
+    /// Files that are copied atomically or not at all: under a lock, and only if they
+    /// didn't change while they were read. Otherwise they're copied on the next cycle
+    pub atomic_copies: AtomicCopies,
+    /// Leave files another program has open for writing alone until it closes them, so
+    /// half-written databases and logs never reach the backup. Looking costs a scan of
+    /// every process for each copy
+    pub skip_open_files: bool,
+    /// Also watch a local backup for files that something else changes or deletes, and warn
+    /// about them or copy them again
+    pub guard_backup: Option<GuardBackup>,
+    /// Sign a manifest of a local backup's files after every cycle that changed it, see
+    /// [`crate::signing`]. Copies have to be verified, at least with [`VerifyWrites::Hash`],
+    /// which keeps it up to date
+    pub sign_key: Option<Arc<SigningKey>>,
+    /// Initialize even when the files to copy don't seem to fit on the filesystem they're
+    /// copied to
+    pub ignore_space_check: bool,
+    /// Don't write anything, only print what would have been copied or deleted. Events
+    /// are still produced for what would have happened
+    pub dry_run: bool,
+    /// Show progress bars while initializing
+    pub progress: bool,
+    /// Files at least this many bytes big get a progress bar of their own while they are
+    /// copied during initialization. Defaults to [`DEFAULT_FILE_PROGRESS_THRESHOLD`]
+    pub file_progress_threshold: Option<u64>,
+    /// Files larger than this many bytes are never copied in either direction. Initializing
+    /// doesn't remove them from work_dir either, unless [`SyncOptions::force_init`] is set
+    pub max_file_size: Option<u64>,
+    /// Commit backup_dir to a git repository in its metadata directory after every sync
+    /// cycle that changed it. Needs a local backup
+    pub git: bool,
+    /// Shell command run after every sync cycle that changed something
+    pub on_sync_complete: Option<String>,
+    /// Shell command run after every sync cycle in which something couldn't be synced
+    pub on_error: Option<String>,
+    /// Where to show notifications about files that couldn't be synced and a backup that
+    /// can't be reached
+    pub notify: Option<NotifyTarget>,
+    /// POST those notifications to this URL as JSON
+    pub notify_webhook: Option<String>,
+    /// Email a daily summary, and alerts about errors and a backup that can't be reached
+    pub notify_email: Option<EmailNotifications>,
+}
+
+impl SyncOptions {
+    /// Whether a file of `size` bytes is over [`SyncOptions::max_file_size`]
+    pub(crate) fn too_large(&self, size: u64) -> bool {
+        self.max_file_size
+            .is_some_and(|max_file_size| size > max_file_size)
+    }
+
+    /// Whether directories are synced along with the files in them, see
+    /// [`SyncOptions::no_empty_dirs`]
+    pub(crate) fn syncs_dirs(&self) -> bool {
+        !self.no_empty_dirs && !self.bidirectional
+    }
+}
+
+/// How often work_dir is scanned unless [`SyncOptions::interval`] says otherwise
+pub const DEFAULT_INTERVAL: Duration = Duration::from_secs(3);
+
+/// How many files are copied at once unless [`SyncOptions::max_concurrent_copies`] says
+/// otherwise
+pub const DEFAULT_MAX_CONCURRENT_COPIES: usize = 16;
+
+/// How many files can wait to be copied before scans are skipped, unless
+/// [`SyncOptions::max_pending`] says otherwise
+pub const DEFAULT_MAX_PENDING: u64 = 256;
+
+/// How often a failed copy is retried unless [`SyncOptions::max_retries`] says otherwise
+pub const DEFAULT_MAX_RETRIES: u32 = 5;
+
+/// How often syncing is restarted unless [`SyncOptions::max_restarts`] says otherwise
+pub const DEFAULT_MAX_RESTARTS: u32 = 5;
+
+/// How long the first retry of a failed copy waits, every further one waits twice as long
+const RETRY_DELAY: Duration = Duration::from_secs(1);
+
+/// Syncing that ran this long before failing counts as healthy, and starts counting its
+/// restarts from scratch
+const HEALTHY_RUN: Duration = Duration::from_secs(10 * 60);
+
+/// Retries never wait longer than this
+const MAX_RETRY_DELAY: Duration = Duration::from_secs(5 * 60);
+
+/// Something that happened while syncing work_dir to backup_dir
+#[derive(Debug)]
+pub enum SyncEvent {
+    /// A file in work_dir was copied into backup_dir, `bytes` of it in `duration`
+    Copied {
+        path: PathBuf,
+        bytes: u64,
+        duration: Duration,
+    },
+    /// A file deleted from work_dir was also removed from backup_dir, or the other way
+    /// around when syncing both ways
+    Removed(PathBuf),
+    /// A file changed in the backup was copied into work_dir
+    Pulled(PathBuf),
+    /// A file changed on both sides since the last sync, and was settled with the
+    /// configured [`ConflictStrategy`]
+    Conflict(PathBuf),
+    /// A file was moved inside work_dir, and its backup was moved along with it instead of
+    /// being copied again. Only detected when deletions are synced
+    Renamed { from: PathBuf, to: PathBuf },
+    /// A file wasn't copied because it is larger than [`SyncOptions::max_file_size`], or
+    /// because it couldn't be copied atomically this cycle, see [`SyncOptions::atomic_copies`],
+    /// or was open for writing, see [`SyncOptions::skip_open_files`]
+    Skipped(PathBuf),
+    /// A snapshot of backup_dir was taken after a sync cycle changed something
+    Snapshot(PathBuf),
+    /// backup_dir was linked into a dated directory at the end of a day or hour, see
+    /// [`SyncOptions::rotation`]
+    Rotated(PathBuf),
+    /// A manifest of backup_dir was signed after a sync cycle changed something, see
+    /// [`SyncOptions::sign_key`]
+    ManifestSigned(PathBuf),
+    /// Syncing a path failed, syncing carries on with everything else
+    Error(SyncError),
+    /// `failed` copies failed for good, so syncing stopped as [`ErrorPolicy::Abort`] says.
+    /// The stream of events ends after this
+    Aborted { failed: u64 },
+    /// A copy or a sweep timed out, so the backup counts as degraded and copies wait a while
+    /// before trying it again, see [`SyncOptions::copy_timeout`]
+    Degraded(TimedOut),
+    /// A copy into the degraded backup finished in time again
+    Responding,
+    /// backup_dir went away, so syncing pauses until it's back, see [`crate::vanished`]
+    Vanished(Vanished),
+    /// backup_dir is back, and a full sweep catches up with what changed in the meantime
+    Returned,
+    /// A sync cycle that changed something or had errors finished. When polling that's a
+    /// scan, when watching a quiet moment after a burst of changes
+    CycleComplete {
+        copied: usize,
+        pulled: usize,
+        removed: usize,
+        errors: usize,
+    },
+}
+
+/// How a file was copied by [`SyncContext::put_file`]
+#[derive(Debug, Clone, Copy)]
+pub(crate) struct CopyStats {
+    pub bytes: u64,
+    pub duration: Duration,
+}
+
+impl CopyStats {
+    /// The event reporting that `path` was copied like this
+    pub fn event(self, path: PathBuf) -> SyncEvent {
+        SyncEvent::Copied {
+            path,
+            bytes: self.bytes,
+            duration: self.duration,
+        }
+    }
+}
+
+/// Keeps a backup in sync with a work directory
+pub struct Syncer {
+    work_dir: PathBuf,
+    backend: Arc<dyn Backend>,
+    options: SyncOptions,
+    /// Cancelled to stop the current [`Syncer::run`]
+    shutdown: Mutex<CancellationToken>,
+    metrics: Arc<Metrics>,
+    control: Arc<SyncControl>,
+    reloaded: Arc<Reloaded>,
+    email_state: Arc<EmailState>,
+}
+
+/// The options of the current and every later run, as [`Syncer::reload`] changed them
+struct Reloaded {
+    options: Mutex<SyncOptions>,
+    /// Whether the current run has to restart with them
+    pending: AtomicBool,
+    /// Cancelled to stop the current run, without shutting syncing down
+    run: Mutex<CancellationToken>,
+}
+
+impl Syncer {
+    /// Syncs `work_dir` to a backup in the local directory `backup_dir`
+    pub fn new(
+        work_dir: impl Into<PathBuf>,
+        backup_dir: impl Into<PathBuf>,
+        options: SyncOptions,
+    ) -> Result<Self, SyncError> {
+        let backup_dir = backup_dir.into();
+        let backend = LocalBackend::new(&backup_dir, options.copy.clone())
+            .during(Operation::SetUp, &backup_dir)?;
+        Self::with_backend(work_dir, Arc::new(backend), options)
+    }
+
+    /// Syncs `work_dir` to a backup stored in `backend`
+    pub fn with_backend(
+        work_dir: impl Into<PathBuf>,
+        backend: Arc<dyn Backend>,
+        options: SyncOptions,
+    ) -> Result<Self, SyncError> {
+        let work_dir = work_dir.into();
+        Self::set_up(work_dir.clone(), backend, options).during(Operation::SetUp, &work_dir)
+    }
+
+    fn set_up(
+        work_dir: PathBuf,
+        backend: Arc<dyn Backend>,
+        mut options: SyncOptions,
+    ) -> Result<Self> {
+        if !work_dir.is_dir() {
+            return Err(anyhow!("work_dir must be a directory!"));
+        }
+        if options.snapshots.is_some() && backend.local_dir().is_none() {
+            return Err(anyhow!(
+                "Snapshots are only supported for local backup directories"
+            ));
+        }
+        if options.rotation.is_some() && backend.local_dir().is_none() {
+            return Err(anyhow!(
+                "Rotations are only supported for local backup directories"
+            ));
+        }
+        if options.bidirectional && !options.transforms.is_empty() {
+            return Err(anyhow!(
+                "Transforms can't be used when syncing both ways, they'd be copied back"
+            ));
+        }
+        if options.sign_key.is_some() {
+            if backend.local_dir().is_none() {
+                return Err(anyhow!(
+                    "Signed manifests are only supported for local backup directories"
+                ));
+            }
+            // The backend only hashes what it copies when verifying them
+            if options.copy.verify_writes == VerifyWrites::Off {
+                return Err(anyhow!(
+                    "Signed manifests need copies to be verified, with VerifyWrites::Hash or more"
+                ));
+            }
+        }
+        if options
+            .notify_email
+            .as_ref()
+            .is_some_and(|email| email.to.is_empty())
+        {
+            return Err(anyhow!(
+                "Email notifications need an address to send them to"
+            ));
+        }
+        if let Some(key) = &options.sign_key {
+            debug!("Signing manifests with public key {}", key.public_key());
+        }
+        if options.git && backend.local_dir().is_none() {
+            return Err(anyhow!(
+                "Git history is only supported for local backup directories"
+            ));
+        }
+
+        // Watcher events use real paths, so everything is compared against those
+        let work_dir = work_dir
+            .canonicalize()
+            .with_context(|| anyhow!("Error resolving {}", work_dir.display()))?;
+
+        // Initialization would clear the backup, and syncing would copy the backup into itself
+        if let Some(backup_dir) = backend.local_dir() {
+            if !options.allow_overlap {
+                if work_dir == backup_dir {
+                    return Err(anyhow!(
+                        "work_dir and backup_dir are the same directory ({}), pass --allow-overlap if you really mean it",
+                        work_dir.display()
+                    ));
+                }
+                if backup_dir.starts_with(&work_dir) || work_dir.starts_with(backup_dir) {
+                    return Err(anyhow!(
+                        "work_dir ({}) and backup_dir ({}) are inside one another, pass --allow-overlap if you really mean it",
+                        work_dir.display(),
+                        backup_dir.display()
+                    ));
+                }
+            }
+        }
+
+        // Every pair counts its own walk errors
+        let walk_errors = WalkErrors::new(options.fail_on_walk_error);
+        options.ignore = options.ignore.reporting_to(walk_errors.clone());
+
+        Ok(Self {
+            work_dir,
+            backend,
+            reloaded: Arc::new(Reloaded {
+                options: Mutex::new(options.clone()),
+                pending: AtomicBool::new(false),
+                run: Mutex::default(),
+            }),
+            options,
+            shutdown: Mutex::new(CancellationToken::new()),
+            metrics: Arc::new(Metrics::counting(walk_errors)),
+            control: Arc::default(),
+            email_state: Arc::default(),
+        })
+    }
+
+    pub fn work_dir(&self) -> &PathBuf {
+        &self.work_dir
+    }
+
+    /// Where the backup is stored
+    pub fn backend(&self) -> &Arc<dyn Backend> {
+        &self.backend
+    }
+
+    /// The options syncing started with, [`Syncer::reload`] doesn't change these
+    pub fn options(&self) -> &SyncOptions {
+        &self.options
+    }
+
+    /// Changes what can be changed without initializing again: what's ignored, the scan
+    /// intervals, how many files are copied at once and in which order, how many may wait
+    /// for it, the bandwidth limit, hooks and notifications. The rest of `options` is left
+    /// out. A running sync copies what changed so far, then restarts with them
+    pub fn reload(&self, options: SyncOptions) {
+        {
+            let mut current = self.reloaded.options.lock().unwrap();
+            current.ignore = options
+                .ignore
+                .reporting_to(self.options.ignore.walk_errors().clone());
+            current.interval = options.interval;
+            current.max_interval = options.max_interval;
+            current.max_concurrent_copies = options.max_concurrent_copies;
+            current.max_pending = options.max_pending;
+            current.priorities = options.priorities;
+            current.on_sync_complete = options.on_sync_complete;
+            current.on_error = options.on_error;
+            current.notify = options.notify;
+            current.notify_webhook = options.notify_webhook;
+            current.notify_email = options.notify_email;
+            // Copies only go through a throttle when there was a limit to begin with
+            match (&current.copy.bwlimit, options.copy.bwlimit) {
+                (Some(throttle), limit) => throttle
+                    .set_bytes_per_sec(limit.map_or(u64::MAX, |limit| limit.bytes_per_sec())),
+                (None, Some(_)) => {
+                    warn!("Syncing started without a bandwidth limit, adding one needs a restart")
+                }
+                (None, None) => {}
+            }
+        }
+        self.reloaded.pending.store(true, Ordering::Relaxed);
+        self.reloaded.run.lock().unwrap().cancel();
+    }
+
+    /// What syncing has done so far, across every run
+    pub fn metrics(&self) -> &Arc<Metrics> {
+        &self.metrics
+    }
+
+    /// Pauses and resumes syncing, across every run
+    pub fn control(&self) -> &Arc<SyncControl> {
+        &self.control
+    }
+
+    /// Makes work_dir match backup_dir, only touching the files that differ. With
+    /// [`SyncOptions::force_init`] set, work_dir is wiped and re-copied instead
+    pub async fn initialize(&self) -> Result<(), SyncError> {
+        self.initialize_with(|_| Ok(true)).await
+    }
+
+    /// Like [`Syncer::initialize`], but first asks `confirm` whether the files in work_dir
+    /// that are about to be removed or replaced may go. Paths are relative to work_dir, and
+    /// `confirm` isn't called if nothing would be lost
+    pub async fn initialize_with(
+        &self,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+    ) -> Result<(), SyncError> {
+        self.initialize_asking(confirm, |relative_path, _, _| {
+            Err(anyhow!(
+                "Can't ask which copy of {} to keep",
+                relative_path.display()
+            ))
+        })
+        .await
+    }
+
+    /// Like [`Syncer::initialize_with`], but with [`InitMode::Merge`] and
+    /// [`MergePolicy::Prompt`] `ask` decides which copy to keep of every file that differs.
+    /// It gets the path relative to work_dir, then the metadata of the copy in work_dir and
+    /// of the one in the backup
+    pub async fn initialize_asking(
+        &self,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+        ask: impl FnMut(&Path, &FileMetadata, &FileMetadata) -> Result<Keep>,
+    ) -> Result<(), SyncError> {
+        self.initialize_work_dir(confirm, ask)
+            .await
+            .during(Operation::Initialize, &self.work_dir)
+    }
+
+    async fn initialize_work_dir(
+        &self,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+        ask: impl FnMut(&Path, &FileMetadata, &FileMetadata) -> Result<Keep>,
+    ) -> Result<()> {
+        let Self {
+            work_dir,
+            backend,
+            options,
+            ..
+        } = self;
+
+        self.clean_temp_files().await?;
+
+        if options.init_mode == InitMode::Merge {
+            return self.merge(confirm, ask).await;
+        }
+        if options.force_init {
+            return self.initialize_from_scratch(confirm).await;
+        }
+
+        info!("Checking if {} and {backend} are equal", work_dir.display());
+
+        let start = Instant::now();
+        let diff = self.compare_for_init().await?;
+
+        info!(
+            "Done! Took {} seconds",
+            Instant::now().duration_since(start).as_secs_f32()
+        );
+
+        if diff.is_empty() {
+            info!(
+                "{} == {backend}, skipping initialization",
+                work_dir.display()
+            );
+            return self.initialize_dirs(!options.no_clear).await;
+        }
+
+        let backup_files = backend.list().await?;
+        // Files that are too large were never backed up, so removing them would lose them
+        let to_remove: Vec<PathBuf> = match options.no_clear {
+            true => Vec::new(),
+            false => diff
+                .only_in_work
+                .iter()
+                .filter(|relative_path| !self.too_large_in_work(relative_path))
+                .cloned()
+                .collect(),
+        };
+        let to_copy = self.skip_too_large(
+            diff.only_in_backup.iter().chain(&diff.different),
+            &backup_files,
+        );
+        if options.dry_run {
+            self.print_dry_run(&to_remove, &to_copy);
+            return Ok(());
+        }
+
+        let copying: HashSet<&PathBuf> = to_copy.iter().copied().collect();
+        let replaced = diff.different.iter().filter(|path| copying.contains(path));
+        let at_risk: Vec<PathBuf> = to_remove.iter().chain(replaced).cloned().collect();
+        self.check_space(work_dir, &to_copy, &backup_files, &at_risk)?;
+        if !at_risk.is_empty() && !confirm(&at_risk)? {
+            return Err(anyhow!("Initialization cancelled"));
+        }
+
+        info!(
+            "Initializing {} files in {} with the contents of {backend}...",
+            to_remove.len() + to_copy.len(),
+            work_dir.display()
+        );
+
+        let quarantine = self.quarantine_dir();
+        let mut leftovers = Vec::new();
+        for relative_path in &to_remove {
+            let path = work_dir.join(relative_path);
+            match self.remove_for_init(&path, quarantine.as_deref()).await {
+                Ok(()) => prune_empty_parents(&path, work_dir).await,
+                Err(err) => leftovers.push((path, err)),
+            }
+        }
+        self.report_leftovers(&leftovers);
+
+        self.initialize_files(&to_copy, &backup_files).await?;
+        self.initialize_dirs(!options.no_clear).await?;
+
+        info!("Initialized {}!", work_dir.display());
+
+        Ok(())
+    }
+
+    /// Reconciles work_dir with the backup without losing the files only one of them has.
+    /// Files in both that differ are settled by [`SyncOptions::merge_policy`]
+    async fn merge(
+        &self,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+        mut ask: impl FnMut(&Path, &FileMetadata, &FileMetadata) -> Result<Keep>,
+    ) -> Result<()> {
+        let Self {
+            work_dir,
+            backend,
+            options,
+            ..
+        } = self;
+
+        info!("Merging {} and {backend}", work_dir.display());
+        let diff = self.compare_for_init().await?;
+        if diff.is_empty() {
+            info!("{} == {backend}, nothing to merge", work_dir.display());
+            return self.initialize_dirs(false).await;
+        }
+
+        let backup_files = backend.list().await?;
+        let mut pull: Vec<&PathBuf> = diff.only_in_backup.iter().collect();
+        let mut push: Vec<&PathBuf> = diff.only_in_work.iter().collect();
+        // Replaced in work_dir without asking
+        let mut replaced = Vec::new();
+        for relative_path in &diff.different {
+            let Some(backup) = backup_files.get(relative_path) else {
+                continue;
+            };
+            let path = work_dir.join(relative_path);
+            let work = fs::metadata(&path)
+                .await
+                .with_context(|| anyhow!("Error reading metadata of {}", path.display()))?;
+            let work = FileMetadata {
+                size: work.len(),
+                modified: work.modified().ok(),
+            };
+            let keep = match options.merge_policy.keep(&work, backup) {
+                Some(keep) => {
+                    if keep == Keep::Backup {
+                        replaced.push(relative_path.clone());
+                    }
+                    keep
+                }
+                None => ask(relative_path, &work, backup)?,
+            };
+            match keep {
+                Keep::Work => push.push(relative_path),
+                Keep::Backup => pull.push(relative_path),
+            }
+        }
+        let pull = self.skip_too_large(pull, &backup_files);
+        push.retain(|relative_path| !self.too_large_in_work(relative_path));
+
+        if options.dry_run {
+            for relative_path in &pull {
+                println!("WOULD COPY {}", work_dir.join(relative_path).display());
+            }
+            for relative_path in &push {
+                println!(
+                    "WOULD COPY {} to {backend}",
+                    work_dir.join(relative_path).display()
+                );
+            }
+            println!(
+                "Would copy {} files into work_dir and {} files into the backup",
+                pull.len(),
+                push.len()
+            );
+            return Ok(());
+        }
+        let pulled: HashSet<&PathBuf> = pull.iter().copied().collect();
+        let overwritten: Vec<PathBuf> = diff
+            .different
+            .iter()
+            .filter(|relative_path| pulled.contains(relative_path))
+            .cloned()
+            .collect();
+        self.check_space(work_dir, &pull, &backup_files, &overwritten)?;
+        if let Some(backup_dir) = backend.local_dir() {
+            let work_files = push
+                .iter()
+                .filter_map(|relative_path| {
+                    let metadata = std::fs::metadata(work_dir.join(relative_path)).ok()?;
+                    Some((
+                        (*relative_path).clone(),
+                        FileMetadata {
+                            size: metadata.len(),
+                            modified: metadata.modified().ok(),
+                        },
+                    ))
+                })
+                .collect();
+            let pushed: Vec<PathBuf> = diff
+                .different
+                .iter()
+                .filter(|relative_path| !pulled.contains(relative_path))
+                .map(|relative_path| backup_dir.join(relative_path))
+                .collect();
+            self.check_space(backup_dir, &push, &work_files, &pushed)?;
+        }
+        if !replaced.is_empty() && !confirm(&replaced)? {
+            return Err(anyhow!("Initialization cancelled"));
+        }
+
+        info!(
+            "Copying {} files into {} and {} files into {backend}...",
+            pull.len(),
+            work_dir.display(),
+            push.len()
+        );
+        self.initialize_files(&pull, &backup_files).await?;
+        for relative_path in push {
+            let path = work_dir.join(relative_path);
+            backend
+                .put(relative_path, &path)
+                .await
+                .with_context(|| anyhow!("Error copying {} into {backend}", path.display()))?;
+            // Like initialized files, so the next initialization can tell the two are the same
+            if let (Some(backup_dir), Ok(modified)) = (
+                backend.local_dir(),
+                fs::metadata(&path)
+                    .await
+                    .and_then(|metadata| metadata.modified()),
+            ) {
+                set_mtime(&backup_dir.join(relative_path), modified).await?;
+            }
+        }
+        self.initialize_dirs(false).await?;
+
+        info!("Merged {} and {backend}!", work_dir.display());
+
+        Ok(())
+    }
+
+    /// How work_dir differs from the backup by [`SyncOptions::init_compare`], leaving out
+    /// the files whose backups are transformed, which differ on purpose
+    async fn compare_for_init(&self) -> Result<TreeDiff> {
+        let mut diff = self
+            .compare_by(self.options.init_compare)
+            .instrument(debug_span!("diff", work_dir = %self.work_dir.display()))
+            .await?;
+        let transforms = &self.options.transforms;
+        diff.different
+            .retain(|relative_path| !transforms.applies_to(relative_path));
+        // A file whose backup has its name in another form is in both, and whichever is
+        // newer is copied by the first sweep
+        let normalize = self.options.normalize_unicode;
+        if normalize != NormalizeUnicode::None {
+            let only_in_backup: HashSet<PathBuf> = diff.only_in_backup.iter().cloned().collect();
+            let normalized: HashSet<PathBuf> = diff
+                .only_in_work
+                .iter()
+                .map(|relative_path| normalize.path(relative_path).into_owned())
+                .filter(|backup_path| only_in_backup.contains(backup_path))
+                .collect();
+            diff.only_in_work.retain(|relative_path| {
+                !normalized.contains(&normalize.path(relative_path).into_owned())
+            });
+            diff.only_in_backup
+                .retain(|backup_path| !normalized.contains(backup_path));
+        }
+        Ok(diff)
+    }
+
+    /// Clears work_dir completely, then copies all of backup_dir into it. With
+    /// [`SyncOptions::no_clear`] the copy is merged into work_dir instead
+    async fn initialize_from_scratch(
+        &self,
+        confirm: impl FnOnce(&[PathBuf]) -> Result<bool>,
+    ) -> Result<()> {
+        let Self {
+            work_dir,
+            backend,
+            options,
+            ..
+        } = self;
+
+        let backup_files = backend.list().await?;
+        let at_risk: Vec<PathBuf> = match options.no_clear {
+            true => backup_files
+                .keys()
+                .filter(|relative_path| !options.ignore.is_ignored(relative_path, false))
+                .filter(|relative_path| {
+                    std::fs::symlink_metadata(work_dir.join(relative_path)).is_ok()
+                })
+                .cloned()
+                .collect(),
+            false => {
+                let work_dir = work_dir.clone();
+                let walk_errors = options.ignore.walk_errors().clone();
+                let cross_mounts = options.cross_mounts;
+                let at_risk = tokio::task::spawn_blocking(move || {
+                    WalkDir::new(&work_dir)
+                        .min_depth(1)
+                        .same_file_system(!cross_mounts)
+                        .into_iter()
+                        .filter_map(|file_info| walk_errors.skip(file_info))
+                        .filter(|file_info| !file_info.file_type().is_dir())
+                        .filter_map(|file_info| {
+                            Some(file_info.path().strip_prefix(&work_dir).ok()?.to_path_buf())
+                        })
+                        .collect()
+                })
+                .await?;
+                options.ignore.walk_errors().check()?;
+                at_risk
+            }
+        };
+        if options.dry_run {
+            let to_remove: &[PathBuf] = match options.no_clear {
+                true => &[],
+                false => &at_risk,
+            };
+            let to_copy = self.skip_too_large(
+                backup_files
+                    .keys()
+                    .filter(|relative_path| !options.ignore.is_ignored(relative_path, false)),
+                &backup_files,
+            );
+            self.print_dry_run(to_remove, &to_copy);
+            return Ok(());
+        }
+        let to_copy = self.skip_too_large(
+            backup_files
+                .keys()
+                .filter(|relative_path| !options.ignore.is_ignored(relative_path, false)),
+            &backup_files,
+        );
+        self.check_space(work_dir, &to_copy, &backup_files, &at_risk)?;
+        if !at_risk.is_empty() && !confirm(&at_risk)? {
+            return Err(anyhow!("Initialization cancelled"));
+        }
+
+        if !options.no_clear {
+            self.clear_work_dir().await?;
+        }
+
+        info!(
+            "Initializing {} with the contents of {backend}...",
+            work_dir.display()
+        );
+        self.initialize_files(&to_copy, &backup_files).await?;
+        self.initialize_dirs(!options.no_clear).await?;
+
+        info!("Initialized {}!", work_dir.display());
+
+        Ok(())
+    }
+
+    /// Fails unless the filesystem of `dir` has room for the files in `to_copy`, as big as
+    /// they are in `files`, once the files at `freed` are gone. Paths in `freed` that aren't
+    /// absolute are relative to work_dir. Skipped with [`SyncOptions::ignore_space_check`]
+    fn check_space(
+        &self,
+        dir: &Path,
+        to_copy: &[&PathBuf],
+        files: &BTreeMap<PathBuf, FileMetadata>,
+        freed: &[PathBuf],
+    ) -> Result<()> {
+        if self.options.ignore_space_check || to_copy.is_empty() {
+            return Ok(());
+        }
+        let Some(free) = free_space(dir) else {
+            debug!("Can't tell how much space is free in {}", dir.display());
+            return Ok(());
+        };
+
+        let needed: u64 = to_copy
+            .iter()
+            .filter_map(|relative_path| files.get(*relative_path))
+            .map(|metadata| metadata.size)
+            .sum();
+        let freed_bytes: u64 = freed
+            .iter()
+            .filter_map(|path| std::fs::symlink_metadata(self.work_dir.join(path)).ok())
+            .map(|metadata| metadata.len())
+            .sum();
+        let needed = needed.saturating_sub(freed_bytes);
+        if needed > free.bytes {
+            return Err(anyhow!(
+                "Copying {} files needs {} more in {}, but only {} are free. Make room, or pass --ignore-space-check to try anyway",
+                to_copy.len(),
+                HumanBytes(needed),
+                dir.display(),
+                HumanBytes(free.bytes)
+            ));
+        }
+        let new_files = to_copy.len().saturating_sub(freed.len()) as u64;
+        if let Some(free_files) = free.files.filter(|&free_files| new_files > free_files) {
+            return Err(anyhow!(
+                "Copying {} files needs room for {new_files} more files in {}, but the filesystem only has room for {free_files}. Make room, or pass --ignore-space-check to try anyway",
+                to_copy.len(),
+                dir.display()
+            ));
+        }
+        Ok(())
+    }
+
+    /// Removes everything inside work_dir, and warns about whatever couldn't be removed
+    /// instead of stopping at it. Mount points inside work_dir are only cleared with
+    /// [`SyncOptions::cross_mounts`]
+    async fn clear_work_dir(&self) -> Result<()> {
+        let work_dir = &self.work_dir;
+
+        info!("Clearing {}...", work_dir.display());
+        let leftovers = match self.options.use_trash {
+            true => self.clear_work_dir_into_trash().await?,
+            false => {
+                let dir = work_dir.clone();
+                let cross_mounts = self.options.cross_mounts;
+                tokio::task::spawn_blocking(move || clear_dir(&dir, cross_mounts))
+                    .await?
+                    .with_context(|| anyhow!("Error clearing {}", work_dir.display()))?
+                    .into_iter()
+                    .map(|Leftover { path, error }| (path, anyhow::Error::new(error)))
+                    .collect()
+            }
+        };
+        match leftovers.is_empty() {
+            true => info!("Cleared {}!", work_dir.display()),
+            false => self.report_leftovers(&leftovers),
+        }
+
+        Ok(())
+    }
+
+    /// Removes or discards a file of work_dir that isn't in the backup
+    async fn remove_for_init(&self, path: &Path, quarantine: Option<&Path>) -> Result<()> {
+        let work_dir = self.work_dir.clone();
+        let cross_mounts = self.options.cross_mounts;
+        let path_buf = path.to_path_buf();
+        if self.options.use_trash {
+            tokio::task::spawn_blocking(move || {
+                check_filesystem(&work_dir, &path_buf, cross_mounts)
+            })
+            .await??;
+            return self.discard(path, quarantine).await;
+        }
+        Ok(
+            tokio::task::spawn_blocking(move || remove_file_in(&work_dir, &path_buf, cross_mounts))
+                .await??,
+        )
+    }
+
+    /// Warns about what initialization couldn't remove from work_dir, which is initialized
+    /// anyway
+    fn report_leftovers(&self, leftovers: &[(PathBuf, anyhow::Error)]) {
+        if leftovers.is_empty() {
+            return;
+        }
+        for (path, err) in leftovers {
+            warn!("Couldn't remove {} from work_dir: {err:#}", path.display());
+        }
+        warn!(
+            "Left {} entries in {} that aren't in the backup, initializing anyway",
+            leftovers.len(),
+            self.work_dir.display()
+        );
+    }
+
+    /// Moves everything inside work_dir to the trash, and returns what couldn't be moved.
+    /// The contents of mount points are moved instead of the mount points themselves
+    async fn clear_work_dir_into_trash(&self) -> Result<Vec<(PathBuf, anyhow::Error)>> {
+        let quarantine = self.quarantine_dir();
+        let mut leftovers = Vec::new();
+        let mut entries = fs::read_dir(&self.work_dir)
+            .await
+            .with_context(|| anyhow!("Error reading the source directory"))?;
+        while let Ok(Some(file_info)) = entries.next_entry().await {
+            let path = file_info.path();
+            let is_mount_point = match is_mount_point(&self.work_dir, &path) {
+                Ok(is_mount_point) => is_mount_point,
+                Err(err) => {
+                    leftovers.push((path, err.into()));
+                    continue;
+                }
+            };
+            if !is_mount_point {
+                if let Err(err) = self.discard(&path, quarantine.as_deref()).await {
+                    leftovers.push((path, err));
+                }
+                continue;
+            }
+            if !self.options.cross_mounts {
+                leftovers.push((path, mount_point_error().into()));
+                continue;
+            }
+            let mut mounted = fs::read_dir(&path)
+                .await
+                .with_context(|| anyhow!("Error reading {}", path.display()))?;
+            while let Ok(Some(file_info)) = mounted.next_entry().await {
+                let path = file_info.path();
+                if let Err(err) = self.discard(&path, quarantine.as_deref()).await {
+                    leftovers.push((path, err));
+                }
+            }
+        }
+
+        Ok(leftovers)
+    }
+
+    /// Prints what initialization would remove from and copy into work_dir
+    fn print_dry_run(&self, to_remove: &[PathBuf], to_copy: &[&PathBuf]) {
+        for relative_path in to_remove {
+            println!(
+                "WOULD DELETE {}",
+                self.work_dir.join(relative_path).display()
+            );
+        }
+        for relative_path in to_copy {
+            println!("WOULD COPY {}", self.work_dir.join(relative_path).display());
+        }
+        println!(
+            "Would delete {} files and copy {} files",
+            to_remove.len(),
+            to_copy.len()
+        );
+    }
+
+    /// The paths in `relative_paths` whose backup isn't too large to initialize
+    fn skip_too_large<'a>(
+        &self,
+        relative_paths: impl IntoIterator<Item = &'a PathBuf>,
+        backup_files: &BTreeMap<PathBuf, FileMetadata>,
+    ) -> Vec<&'a PathBuf> {
+        let mut skipped = 0;
+        let to_copy = relative_paths
+            .into_iter()
+            .filter(|relative_path| {
+                let size = backup_files
+                    .get(*relative_path)
+                    .map_or(0, |metadata| metadata.size);
+                let too_large = self.options.too_large(size);
+                if too_large {
+                    debug!(path = %relative_path.display(), size, "Too large, skipping it");
+                    skipped += 1;
+                }
+                !too_large
+            })
+            .collect();
+        if skipped > 0 {
+            info!("Skipped {skipped} files larger than the maximum file size");
+        }
+        to_copy
+    }
+
+    /// Whether `relative_path` in work_dir is too large to have been backed up
+    fn too_large_in_work(&self, relative_path: &Path) -> bool {
+        std::fs::metadata(self.work_dir.join(relative_path))
+            .is_ok_and(|metadata| self.options.too_large(metadata.len()))
+    }
+
+    /// Where files that can't be moved to the trash go instead, if the backup is local
+    fn quarantine_dir(&self) -> Option<PathBuf> {
+        self.backend.local_dir().map(quarantine_dir_for)
+    }
+
+    /// Moves a file or directory in work_dir to the trash
+    async fn discard(&self, path: &Path, quarantine: Option<&Path>) -> Result<()> {
+        let path = path.to_path_buf();
+        let work_dir = self.work_dir.clone();
+        let quarantine = quarantine.map(Path::to_path_buf);
+        tokio::task::spawn_blocking(move || discard(&path, &work_dir, quarantine.as_deref()))
+            .await?
+    }
+
+    /// Creates the directories of the backup that are missing from work_dir, empty ones
+    /// included. With `remove_extra`, empty directories that aren't in the backup are removed
+    async fn initialize_dirs(&self, remove_extra: bool) -> Result<()> {
+        if !self.options.syncs_dirs() {
+            return Ok(());
+        }
+        let Some(backup_dirs) = self.backend.list_dirs().await? else {
+            return Ok(());
+        };
+        let work_dirs = self.list_work_dirs().await?;
+
+        for relative_path in backup_dirs.difference(&work_dirs) {
+            if self.options.ignore.is_ignored(relative_path, true) {
+                continue;
+            }
+            let path = self.work_dir.join(relative_path);
+            fs::create_dir_all(&path)
+                .await
+                .with_context(|| anyhow!("Error creating {}", path.display()))?;
+        }
+
+        if remove_extra {
+            // Deepest first, so directories that only had empty ones in them go too. Anything
+            // with files left in it is kept
+            for relative_path in work_dirs.iter().rev() {
+                if backup_dirs.contains(relative_path) {
+                    continue;
+                }
+                let _ = fs::remove_dir(self.work_dir.join(relative_path)).await;
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Every directory in work_dir that isn't ignored, relative to it
+    async fn list_work_dirs(&self) -> Result<BTreeSet<PathBuf>> {
+        let work_dir = self.work_dir.clone();
+        let ignore = self.options.ignore.clone();
+        let symlinks = self.options.copy.symlinks;
+        tokio::task::spawn_blocking(move || list_dirs(&work_dir, &ignore, symlinks)).await?
+    }
+
+    /// Copies files from the backup into work_dir, showing the progress if enabled
+    async fn initialize_files(
+        &self,
+        relative_paths: &[&PathBuf],
+        backup_files: &BTreeMap<PathBuf, FileMetadata>,
+    ) -> Result<()> {
+        let size_of = |relative_path: &Path| {
+            backup_files
+                .get(relative_path)
+                .map_or(0, |metadata| metadata.size)
+        };
+        let mut progress = InitProgress::new(
+            self.options.progress,
+            relative_paths.len() as u64,
+            relative_paths.iter().map(|path| size_of(path)).sum(),
+            self.options
+                .file_progress_threshold
+                .unwrap_or(DEFAULT_FILE_PROGRESS_THRESHOLD),
+        );
+
+        for relative_path in relative_paths {
+            progress
+                .copy(
+                    relative_path,
+                    size_of(relative_path),
+                    &self.work_dir.join(relative_path),
+                    self.initialize_file(relative_path, backup_files.get(*relative_path)),
+                )
+                .await?;
+        }
+        progress.finish();
+
+        Ok(())
+    }
+
+    /// Copies a single file from the backup into work_dir. The modification time is kept so
+    /// the next initialization can tell the two copies are the same without reading them
+    async fn initialize_file(
+        &self,
+        relative_path: &Path,
+        metadata: Option<&FileMetadata>,
+    ) -> Result<()> {
+        let dst_path = self.work_dir.join(relative_path);
+        self.backend
+            .get(relative_path, &dst_path)
+            .await
+            .with_context(|| anyhow!("Error copying file for initialization"))?;
+
+        match metadata.and_then(|metadata| metadata.modified) {
+            Some(modified) => set_mtime(&dst_path, modified).await,
+            None => Ok(()),
+        }
+    }
+
+    /// Copies every file in the backup into work_dir, overwriting files that exist in both
+    /// but leaving everything else in work_dir alone. With `paths`, which are relative to
+    /// work_dir, only the files at or under them are restored, and each one has to be in the
+    /// backup. Returns the number of files restored
+    pub async fn restore(&self, paths: &[PathBuf]) -> Result<usize, SyncError> {
+        self.restore_backup(paths)
+            .await
+            .during(Operation::Restore, &self.work_dir)
+    }
+
+    async fn restore_backup(&self, paths: &[PathBuf]) -> Result<usize> {
+        let files: Vec<PathBuf> = self
+            .backend
+            .list()
+            .await?
+            .into_keys()
+            .filter(|relative_path| !self.options.ignore.is_ignored(relative_path, false))
+            .collect();
+        let files = select_paths(files, paths)?;
+
+        let mut restored = 0;
+        for relative_path in files {
+            self.backend
+                .get(&relative_path, &self.work_dir.join(&relative_path))
+                .await
+                .with_context(|| anyhow!("Error restoring file"))?;
+            restored += 1;
+        }
+        if paths.is_empty() {
+            self.initialize_dirs(false).await?;
+        }
+
+        Ok(restored)
+    }
+
+    /// Like [`Syncer::restore`], but restores from the snapshot matching `at` instead of the
+    /// current backup. See [`find_snapshot`] for how `at` is matched
+    pub async fn restore_snapshot(&self, at: &str, paths: &[PathBuf]) -> Result<usize, SyncError> {
+        let snapshot_dir = self
+            .snapshot_dir(at)
+            .during(Operation::Restore, &self.work_dir)?;
+        info!("Restoring from snapshot {}", snapshot_dir.display());
+        self.restore_from(&snapshot_dir, paths)
+            .await
+            .during(Operation::Restore, &self.work_dir)
+    }
+
+    async fn restore_from(&self, source_dir: &Path, paths: &[PathBuf]) -> Result<usize> {
+        let files: Vec<PathBuf> = walk_files(source_dir, &self.options.ignore)
+            .filter_map(|file_info| {
+                Some(
+                    file_info
+                        .path()
+                        .strip_prefix(source_dir)
+                        .ok()?
+                        .to_path_buf(),
+                )
+            })
+            .collect();
+        let files = select_paths(files, paths)?;
+
+        let mut restored = 0;
+        for relative_path in files {
+            copy_to_dst(
+                &RealFs,
+                &source_dir.join(relative_path),
+                source_dir,
+                &self.work_dir,
+                &self.options.copy,
+            )
+            .await
+            .with_context(|| anyhow!("Error restoring file"))?;
+            restored += 1;
+        }
+
+        Ok(restored)
+    }
+
+    /// The names of the snapshots [`Syncer::restore_snapshot`] can restore from, oldest
+    /// first. Only local backups have them
+    pub fn snapshots(&self) -> Result<Vec<String>, SyncError> {
+        match self.backend.local_dir() {
+            Some(backup_dir) => list_snapshots(backup_dir).during(Operation::Restore, backup_dir),
+            None => Ok(Vec::new()),
+        }
+    }
+
+    /// The files [`Syncer::restore`] can restore, or [`Syncer::restore_snapshot`] if `at` is
+    /// set
+    pub async fn restorable_files(
+        &self,
+        at: Option<&str>,
+    ) -> Result<BTreeMap<PathBuf, FileMetadata>, SyncError> {
+        self.list_restorable(at)
+            .await
+            .during(Operation::Restore, &self.work_dir)
+    }
+
+    async fn list_restorable(&self, at: Option<&str>) -> Result<BTreeMap<PathBuf, FileMetadata>> {
+        let Some(at) = at else {
+            let mut files = self.backend.list().await?;
+            files.retain(|relative_path, _| !self.options.ignore.is_ignored(relative_path, false));
+            return Ok(files);
+        };
+
+        // Like restore_from, which walks the snapshot following links
+        let snapshot_dir = self.snapshot_dir(at)?;
+        let ignore = self.options.ignore.clone();
+        tokio::task::spawn_blocking(move || list_files(&snapshot_dir, &ignore, Symlinks::Follow))
+            .await?
+    }
+
+    /// The contents of `relative_path` in the backup, or in the snapshot matching `at`
+    pub async fn read_backup(
+        &self,
+        at: Option<&str>,
+        relative_path: &Path,
+    ) -> Result<Vec<u8>, SyncError> {
+        self.read_from_backup(at, relative_path)
+            .await
+            .during(Operation::Restore, &self.work_dir.join(relative_path))
+    }
+
+    async fn read_from_backup(&self, at: Option<&str>, relative_path: &Path) -> Result<Vec<u8>> {
+        if let Some(at) = at {
+            let path = self.snapshot_dir(at)?.join(relative_path);
+            return fs::read(&path)
+                .await
+                .with_context(|| anyhow!("Error reading {}", path.display()));
+        }
+
+        let tmp_path = temp_file();
+        let contents = match self.backend.get(relative_path, &tmp_path).await {
+            Ok(()) => fs::read(&tmp_path).await.map_err(anyhow::Error::from),
+            Err(err) => Err(err),
+        };
+        let _ = remove_file(&tmp_path).await;
+        contents.with_context(|| {
+            anyhow!(
+                "Error reading {} from {}",
+                relative_path.display(),
+                self.backend
+            )
+        })
+    }
+
+    fn snapshot_dir(&self, at: &str) -> Result<PathBuf> {
+        let backup_dir = self
+            .backend
+            .local_dir()
+            .ok_or_else(|| anyhow!("Snapshots are only supported for local backup directories"))?;
+        find_snapshot(backup_dir, at)
+    }
+
+    /// Removes temp files left behind by copies that were interrupted, e.g. because the
+    /// process was killed
+    pub async fn clean_temp_files(&self) -> Result<(), SyncError> {
+        self.remove_temp_files()
+            .await
+            .during(Operation::Clean, &self.work_dir)
+    }
+
+    async fn remove_temp_files(&self) -> Result<()> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+
+        let backup_dir = self.backend.local_dir().map(Path::to_path_buf);
+        for dir in [Some(self.work_dir.clone()), backup_dir]
+            .into_iter()
+            .flatten()
+        {
+            let removed = tokio::task::spawn_blocking({
+                let dir = dir.clone();
+                move || clean_temp_files(&dir)
+            })
+            .await??;
+            if removed > 0 {
+                info!("Removed {removed} unfinished copies from {}", dir.display());
+            }
+        }
+
+        Ok(())
+    }
+
+    /// What syncing keeps track of for this pair, for carrying it over to another machine
+    /// with [`Syncer::import_state`]
+    pub async fn export_state(&self) -> Result<PairState, SyncError> {
+        self.read_state()
+            .await
+            .during(Operation::ExportState, &self.work_dir)
+    }
+
+    async fn read_state(&self) -> Result<PairState> {
+        let work_dir = self.work_dir.clone();
+        let backup_dir = self.backend.local_dir().map(Path::to_path_buf);
+        let mut state = PairState {
+            work_dir: work_dir.clone(),
+            backup_dir: self.backend.to_string(),
+            ..PairState::default()
+        };
+        tokio::task::spawn_blocking(move || {
+            // Only a local backup has room for the hash cache and the tracking state
+            if let Some(backup_dir) = &backup_dir {
+                state.hash_cache = read_part(&hash_cache_path(backup_dir))?;
+                state.tracking = read_part(&tracking_path(backup_dir))?;
+            }
+            state.both_ways = read_part(&bidir::state_path(&work_dir))?;
+            Ok(state)
+        })
+        .await?
+    }
+
+    /// Replaces what syncing keeps track of for this pair with `state`, exported on another
+    /// machine by [`Syncer::export_state`]. Parts `state` doesn't have are removed, and
+    /// nothing should sync this pair meanwhile, it would save its own state over it
+    pub async fn import_state(&self, state: PairState) -> Result<(), SyncError> {
+        self.write_state(state)
+            .await
+            .during(Operation::ImportState, &self.work_dir)
+    }
+
+    async fn write_state(&self, state: PairState) -> Result<()> {
+        if self.options.dry_run {
+            return Ok(());
+        }
+        let work_dir = self.work_dir.clone();
+        let backup_dir = self.backend.local_dir().map(Path::to_path_buf);
+        if backup_dir.is_none() && (state.hash_cache.is_some() || state.tracking.is_some()) {
+            warn!(
+                "{} isn't a local directory, which the hash cache and tracking state are kept in, leaving them out",
+                self.backend
+            );
+        }
+        tokio::task::spawn_blocking(move || {
+            if let Some(backup_dir) = &backup_dir {
+                write_part(&hash_cache_path(backup_dir), state.hash_cache.as_ref())?;
+                write_part(&tracking_path(backup_dir), state.tracking.as_ref())?;
+            }
+            write_part(&bidir::state_path(&work_dir), state.both_ways.as_ref())
+        })
+        .await?
+    }
+
+    /// Compares the contents of work_dir and the backup
+    pub async fn compare(&self) -> Result<TreeDiff, SyncError> {
+        self.compare_by(CompareBy::Contents).await
+    }
+
+    /// Compares work_dir and the backup, deciding whether files match with `compare_by`
+    pub async fn compare_by(&self, compare_by: CompareBy) -> Result<TreeDiff, SyncError> {
+        compare_trees(
+            &self.work_dir,
+            &*self.backend,
+            &self.options.ignore,
+            self.options.copy.symlinks,
+            compare_by,
+        )
+        .await
+        .during(Operation::Compare, &self.work_dir)
+    }
+
+    /// The index another evil_mount that is syncing the same local backup keeps of both
+    /// trees, see [`crate::index`]
+    pub fn live_index(&self) -> Result<Option<ScanIndex>, SyncError> {
+        let Some(backup_dir) = self.backend.local_dir() else {
+            return Ok(None);
+        };
+        ScanIndex::read_live(backup_dir).during(Operation::Compare, &self.work_dir)
+    }
+
+    /// Compares the size and hash of every file in work_dir and the backup, then writes the
+    /// hashes of the backup's files to its manifest
+    pub async fn verify(&self) -> Result<Verification, SyncError> {
+        self.verify_backup()
+            .await
+            .during(Operation::Verify, &self.work_dir)
+    }
+
+    async fn verify_backup(&self) -> Result<Verification> {
+        let verification = verify_trees(
+            &self.work_dir,
+            &*self.backend,
+            &self.options.ignore,
+            self.options.copy.symlinks,
+            &self.options.hash_pool,
+        )
+        .await?;
+
+        let tmp_path = std::env::temp_dir().join(format!("evil_mount-{}.b3", std::process::id()));
+        fs::write(&tmp_path, verification.manifest())
+            .await
+            .with_context(|| anyhow!("Error writing {}", tmp_path.display()))?;
+        let written = self.backend.put(&manifest_path(), &tmp_path).await;
+        let _ = remove_file(&tmp_path).await;
+        written.with_context(|| anyhow!("Error writing the manifest to {}", self.backend))?;
+
+        Ok(verification)
+    }
+
+    /// Checks the chain of signed manifests of a local backup with `public_key`, or with the
+    /// public half of [`SyncOptions::sign_key`], and which files of `verification` changed
+    /// since the newest one was signed
+    pub async fn check_signatures(
+        &self,
+        public_key: Option<PublicKey>,
+        verification: &Verification,
+    ) -> Result<SignatureCheck, SyncError> {
+        self.check_manifests(public_key, verification)
+            .await
+            .during(Operation::Verify, &self.work_dir)
+    }
+
+    async fn check_manifests(
+        &self,
+        public_key: Option<PublicKey>,
+        verification: &Verification,
+    ) -> Result<SignatureCheck> {
+        let backup_dir = self
+            .backend
+            .local_dir()
+            .ok_or_else(|| {
+                anyhow!("Signed manifests are only supported for local backup directories")
+            })?
+            .to_path_buf();
+        let public_key = public_key
+            .or_else(|| self.options.sign_key.as_ref().map(|key| key.public_key()))
+            .ok_or_else(|| anyhow!("Checking signatures needs a public key or the signing key"))?;
+        let chain =
+            tokio::task::spawn_blocking(move || check_chain(&backup_dir, &public_key)).await??;
+
+        let mut unsigned: Vec<PathBuf> = verification
+            .backup
+            .iter()
+            .filter(|(relative_path, checksum)| {
+                chain.latest.get(*relative_path) != Some(&checksum.hash)
+            })
+            .map(|(relative_path, _)| relative_path.clone())
+            .chain(
+                chain
+                    .latest
+                    .keys()
+                    .filter(|relative_path| !verification.backup.contains_key(*relative_path))
+                    .cloned(),
+            )
+            .collect();
+        unsigned.sort();
+
+        Ok(SignatureCheck {
+            manifests: chain.manifests,
+            public_key,
+            unsigned,
+        })
+    }
+
+    /// Starts syncing changes from work_dir into backup_dir in the background. The returned
+    /// stream ends once syncing has stopped
+    pub fn run(&self) -> SyncEvents {
+        self.start(false)
+    }
+
+    /// Copies every file in work_dir that is missing from the backup or newer than its copy
+    /// there, then stops. Syncing both ways runs a single cycle instead. With
+    /// [`SyncOptions::delete_after`] set, files missing from work_dir are removed from the
+    /// backup right away
+    pub fn run_once(&self) -> SyncEvents {
+        self.start(true)
+    }
+
+    fn start(&self, once: bool) -> SyncEvents {
+        // A previous run might have been shut down
+        let shutdown = CancellationToken::new();
+        *self.shutdown.lock().unwrap() = shutdown.clone();
+        if once {
+            shutdown.cancel();
+        }
+        self.reloaded.pending.store(false, Ordering::Relaxed);
+
+        let (events_tx, events) = mpsc::unbounded_channel();
+        let context = {
+            let work_dir = self.work_dir.clone();
+            let backend = self.backend.clone();
+            let shared = Shared {
+                metrics: self.metrics.clone(),
+                control: self.control.clone(),
+                email_state: self.email_state.clone(),
+            };
+            let reloaded = self.reloaded.clone();
+            move |shutdown: &CancellationToken| {
+                let options = reloaded.options.lock().unwrap().clone();
+                // Restarting for a reload only stops this run
+                let run = shutdown.child_token();
+                *reloaded.run.lock().unwrap() = run.clone();
+                Arc::new(SyncContext::new(
+                    work_dir.clone(),
+                    backend.clone(),
+                    Arc::new(RealFs),
+                    options,
+                    events_tx.clone(),
+                    run,
+                    &shared,
+                ))
+            }
+        };
+
+        // Log lines of the background task belong to whatever span this was started in
+        let reloaded = self.reloaded.clone();
+        let metrics = self.metrics.clone();
+        let task = async move {
+            metrics.set_running(true);
+            let mut ctx = context(&shutdown);
+            loop {
+                run_context(&ctx, once).await;
+                if shutdown.is_cancelled() || !reloaded.pending.swap(false, Ordering::Relaxed) {
+                    break;
+                }
+                info!("Syncing with the reloaded settings");
+                ctx = context(&shutdown);
+                // Whatever changed while nothing was watching is swept up first
+                ctx.control.sync_now();
+            }
+            metrics.set_running(false);
+        }
+        .instrument(Span::current());
+        let task = tokio::task::spawn(task);
+
+        SyncEvents {
+            events,
+            _task: task,
+        }
+    }
+
+    /// Tells the background sync tasks to stop. Files changed since the last scan are still
+    /// copied, and the stream returned by [`Syncer::run`] ends once that is done
+    pub fn shutdown(&self) {
+        self.shutdown.lock().unwrap().cancel();
+    }
+}
+
+/// A [`CaseCheck`] for a backup that can't tell names apart that only differ in case, see
+/// [`SyncOptions::case_collisions`]
+fn case_check(work_dir: &Path, backend: &dyn Backend, options: &SyncOptions) -> Option<CaseCheck> {
+    let insensitive = match backend.local_dir() {
+        Some(backup_dir) => is_case_insensitive(backup_dir),
+        None => options.case_collisions.is_some(),
+    };
+    if !insensitive {
+        return None;
+    }
+    if options.bidirectional {
+        warn!("{backend} is case-insensitive, files that only differ in case overwrite each other");
+        return None;
+    }
+    let handling = options.case_collisions.unwrap_or_default();
+    Some(CaseCheck::new(work_dir.to_path_buf(), handling))
+}
+
+/// One run of syncing, until it's shut down or restarted for a reload
+async fn run_context(ctx: &Arc<SyncContext>, once: bool) {
+    if !ctx.options.dry_run {
+        let ctx_clean = ctx.clone();
+        let cleaned = tokio::task::spawn_blocking(move || {
+            clean_temp_files(&ctx_clean.work_dir)?;
+            match ctx_clean.backend.local_dir() {
+                Some(backup_dir) => clean_temp_files(backup_dir),
+                None => Ok(0),
+            }
+        })
+        .await;
+        if let Ok(Err(error)) = cleaned {
+            ctx.emit_error(Operation::Clean, ctx.work_dir.clone(), error);
+        }
+    }
+    if !once && ctx.detects_moves() {
+        let ctx_moves = ctx.clone();
+        let _ = tokio::task::spawn_blocking(move || ctx_moves.remember_files()).await;
+    }
+
+    if !once {
+        ctx.start_index();
+    }
+    if let Some(rotation) = ctx.options.rotation {
+        // What the backup held during a period that ended while nothing ran
+        ctx.rotate(rotation).await;
+        if !once {
+            ctx.start_rotation(rotation);
+        }
+    }
+    if let (false, Some(guard)) = (once, ctx.options.guard_backup) {
+        ctx.start_guard(guard);
+    }
+    if !once && (ctx.options.pause_on_battery || ctx.options.battery_interval.is_some()) {
+        ctx.start_power_monitor();
+    }
+    if !once && (ctx.options.quiet_hours.is_some() || ctx.options.sync_window.is_some()) {
+        ctx.start_quiet_monitor();
+    }
+    if let (false, Some(backup_dir)) = (once, ctx.backend.local_dir()) {
+        ctx.start_mount_monitor(backup_dir);
+    }
+    if !once && ctx.notifier.emails_on_schedule() {
+        ctx.start_email_reports();
+    }
+
+    if let Err(error) = ctx.measure_usage().await {
+        ctx.emit_error(Operation::Save, ctx.work_dir.clone(), error);
+    }
+
+    let result = supervise(ctx, once).await;
+    if let Err(error) = result {
+        ctx.emit_error(Operation::Watch, ctx.work_dir.clone(), error);
+    }
+
+    // Changes made since the last scan would otherwise only be copied on the next run.
+    // Syncing both ways already finishes with a full cycle
+    if ctx.is_shutting_down() && !ctx.options.bidirectional && !ctx.is_aborted() {
+        ctx.sweep_and_report(once).await;
+    }
+    ctx.end_cycle().await;
+}
+
+/// Runs the sync loop until it stops, restarting it with a delay that doubles every time
+/// when it fails or panics. Whatever changed in the meantime is swept up first. Fails once
+/// it failed more than [`SyncOptions::max_restarts`] times in a row
+async fn supervise(ctx: &Arc<SyncContext>, once: bool) -> Result<()> {
+    let max_restarts = ctx.options.max_restarts.unwrap_or(DEFAULT_MAX_RESTARTS);
+    let mut failures = 0;
+    loop {
+        let started = Instant::now();
+        // A task of its own, so a panic is caught instead of taking the events with it
+        let run = {
+            let ctx = ctx.clone();
+            tokio::task::spawn(
+                async move {
+                    match (ctx.options.bidirectional, once, ctx.options.poll) {
+                        (true, _, _) => sync_both_ways(ctx).await,
+                        (false, true, _) => Ok(()),
+                        (false, false, true) => copy_files(ctx).await,
+                        (false, false, false) => watch_files(ctx).await,
+                    }
+                }
+                .instrument(Span::current()),
+            )
+        };
+        let error = match run.await {
+            Ok(Ok(())) => return Ok(()),
+            Ok(Err(error)) => error,
+            Err(join_error) => match join_error.try_into_panic() {
+                Ok(panic) => anyhow!("Syncing panicked: {}", panic_message(&*panic)),
+                Err(join_error) => anyhow!("Syncing was cancelled: {join_error}"),
+            },
+        };
+        if ctx.is_shutting_down() {
+            return Err(error);
+        }
+
+        if started.elapsed() >= HEALTHY_RUN {
+            failures = 0;
+        }
+        failures += 1;
+        if failures > max_restarts {
+            return Err(error.context(format!(
+                "Syncing failed {failures} times in a row, giving up"
+            )));
+        }
+        let delay = RETRY_DELAY
+            .saturating_mul(1 << (failures - 1).min(16))
+            .min(MAX_RETRY_DELAY);
+        ctx.emit_error(
+            Operation::Watch,
+            ctx.work_dir.clone(),
+            error.context(format!(
+                "Syncing stopped, restarting it in {delay:?} ({failures} of {max_restarts})"
+            )),
+        );
+        ctx.sleep(delay).await;
+        if ctx.is_shutting_down() {
+            return Ok(());
+        }
+        // Syncing both ways starts with a full cycle anyway
+        if !ctx.options.bidirectional {
+            ctx.sweep_and_report(true).await;
+            ctx.end_cycle().await;
+        }
+    }
+}
+
+/// What a panic said, if it said it with a string
+fn panic_message(panic: &(dyn std::any::Any + Send)) -> &str {
+    match panic.downcast_ref::<&str>() {
+        Some(message) => message,
+        None => panic
+            .downcast_ref::<String>()
+            .map_or("unknown cause", String::as_str),
+    }
+}
+
+/// The events produced by [`Syncer::run`]
+pub struct SyncEvents {
+    events: UnboundedReceiver<SyncEvent>,
+    _task: JoinHandle<()>,
+}
+
+impl Stream for SyncEvents {
+    type Item = SyncEvent;
+
+    fn poll_next(mut self: Pin<&mut Self>, cx: &mut task::Context<'_>) -> Poll<Option<SyncEvent>> {
+        self.events.poll_recv(cx)
+    }
+}
+
+/// Bytes of [`SyncOptions::quota`] held for a copy, given back unless it succeeded
+#[must_use]
+struct Reservation<'a> {
+    usage: &'a AtomicU64,
+    bytes: u64,
+}
+
+impl<'a> Reservation<'a> {
+    /// Holds nothing, for syncing without a quota
+    fn none(usage: &'a AtomicU64) -> Self {
+        Self { usage, bytes: 0 }
+    }
+
+    /// Keeps the bytes counted, now that they're in the backup
+    fn keep(mut self) {
+        self.bytes = 0;
+    }
+}
+
+impl Drop for Reservation<'_> {
+    fn drop(&mut self) {
+        if self.bytes > 0 {
+            let _ = self
+                .usage
+                .fetch_update(Ordering::Relaxed, Ordering::Relaxed, |used| {
+                    Some(used.saturating_sub(self.bytes))
+                });
+        }
+    }
+}
+
+/// What every run of a [`Syncer`] shares, however often a reload restarts it
+#[derive(Clone, Default)]
+pub(crate) struct Shared {
+    pub metrics: Arc<Metrics>,
+    pub control: Arc<SyncControl>,
+    pub email_state: Arc<EmailState>,
+}
+
+/// State shared between all of the tasks of a single [`Syncer::run`]
+pub(crate) struct SyncContext {
+    pub work_dir: PathBuf,
+    pub backend: Arc<dyn Backend>,
+    /// The filesystem work_dir is on, [`RealFs`] but in tests
+    pub vfs: Arc<dyn Vfs>,
+    pub options: SyncOptions,
+    pub detector: ChangeDetector,
+    moves: MoveTracker,
+    /// What happened since the last cycle ended
+    copied: AtomicUsize,
+    pulled: AtomicUsize,
+    removed: AtomicUsize,
+    events: UnboundedSender<SyncEvent>,
+    shutdown: CancellationToken,
+    /// Files waiting to settle before they are copied
+    settling: Mutex<HashSet<PathBuf>>,
+    /// Files whose copy waits out [`SyncOptions::debounce`], with when they last changed
+    debouncing: Mutex<HashMap<PathBuf, Instant>>,
+    /// Files whose copy failed, and that are being retried
+    retries: Mutex<HashMap<PathBuf, Retry>>,
+    /// Files in work_dir that couldn't be read, with what they looked like then. They are
+    /// only tried again once that changes
+    unreadable: Mutex<HashMap<PathBuf, Unreadable>>,
+    /// How many copies failed for good, counted with [`ErrorPolicy::Abort`]
+    failed_copies: AtomicU64,
+    /// Whether that many failed that syncing stopped
+    aborted: AtomicBool,
+    /// Whether the backup timed out lately, see [`SyncOptions::copy_timeout`]
+    watchdog: Watchdog,
+    /// Limits how many copies run at once
+    copies: Semaphore,
+    /// How many bytes the backup takes up, with [`SyncOptions::quota`]. Measured after
+    /// every cycle that changed it, copies are added as they happen
+    usage: AtomicU64,
+    /// Makes room for one copy at a time
+    evicting: tokio::sync::Mutex<()>,
+    /// Finds the paths that collide in a case-insensitive backup
+    case: Option<CaseCheck>,
+    /// How long to wait between scans when polling or syncing both ways
+    pub schedule: Schedule,
+    /// The hashes of verified copies, for a local backup with [`CopyOptions::verify_writes`]
+    manifest: Option<Arc<Manifest>>,
+    /// What was written to a local backup, with [`SyncOptions::guard_backup`]
+    pub written: Option<Written>,
+    /// Both trees as they were last seen, for a local backup, see [`crate::index`]
+    index: Option<LiveIndex>,
+    /// The changed paths and errors of the current cycle, for the hooks
+    report: Mutex<CycleReport>,
+    notifier: Notifier,
+    pub metrics: Arc<Metrics>,
+    pub control: Arc<SyncControl>,
+}
+
+/// How a file whose copy keeps failing is being retried
+#[derive(Debug, Default)]
+struct Retry {
+    attempts: u32,
+    /// Whether a retry is waiting to run
+    pending: bool,
+}
+
+/// The metadata of a file that couldn't be read. Fixing its permissions, its owner or its
+/// SELinux label changes it, and so does writing to it
+#[derive(Debug, Clone, PartialEq, Eq)]
+struct Unreadable {
+    version: Version,
+    permissions: Permissions,
+    /// When its inode last changed, in seconds and nanoseconds
+    #[cfg(unix)]
+    changed: (i64, i64),
+}
+
+impl From<&Metadata> for Unreadable {
+    fn from(metadata: &Metadata) -> Self {
+        #[cfg(unix)]
+        use std::os::unix::fs::MetadataExt;
+
+        Self {
+            version: Version::from(metadata),
+            permissions: metadata.permissions(),
+            #[cfg(unix)]
+            changed: (metadata.ctime(), metadata.ctime_nsec()),
+        }
+    }
+}
+
+impl SyncContext {
+    /// A run of syncing `work_dir`, which is on `vfs`, into `backend`. It stops once
+    /// `shutdown` is cancelled
+    pub fn new(
+        work_dir: PathBuf,
+        backend: Arc<dyn Backend>,
+        vfs: Arc<dyn Vfs>,
+        options: SyncOptions,
+        events: UnboundedSender<SyncEvent>,
+        shutdown: CancellationToken,
+        shared: &Shared,
+    ) -> Self {
+        let detector = ChangeDetector::new(
+            options.detect_changes,
+            work_dir.clone(),
+            backend.local_dir().map(hash_cache_path),
+            backend.local_dir().map(tracking_path),
+        )
+        .with_mtime_tolerance(options.mtime_tolerance)
+        .with_normalization(options.normalize_unicode)
+        .with_hash_pool(options.hash_pool.clone());
+        Self {
+            work_dir: work_dir.clone(),
+            backend: backend.clone(),
+            vfs,
+            detector,
+            moves: MoveTracker::default(),
+            copied: AtomicUsize::new(0),
+            pulled: AtomicUsize::new(0),
+            removed: AtomicUsize::new(0),
+            events,
+            shutdown,
+            settling: Mutex::new(HashSet::new()),
+            debouncing: Mutex::new(HashMap::new()),
+            retries: Mutex::new(HashMap::new()),
+            unreadable: Mutex::new(HashMap::new()),
+            failed_copies: AtomicU64::new(0),
+            aborted: AtomicBool::new(false),
+            watchdog: Watchdog::default(),
+            schedule: Schedule::new(&options),
+            manifest: match (options.copy.verify_writes, backend.local_dir()) {
+                (VerifyWrites::Off, _) | (_, None) => None,
+                (_, Some(backup_dir)) => Some(Arc::new(Manifest::load(backup_dir))),
+            },
+            written: match (options.guard_backup, backend.local_dir()) {
+                (Some(_), Some(_)) => Some(Written::default()),
+                _ => None,
+            },
+            index: match (options.dry_run, backend.local_dir()) {
+                (false, Some(backup_dir)) => Some(LiveIndex::new(backup_dir)),
+                _ => None,
+            },
+            report: Mutex::default(),
+            metrics: shared.metrics.clone(),
+            control: shared.control.clone(),
+            notifier: Notifier::new(
+                options.notify,
+                options.notify_webhook.clone(),
+                options.notify_email.clone(),
+                shared.email_state.clone(),
+            ),
+            case: case_check(&work_dir, &*backend, &options),
+            usage: AtomicU64::new(0),
+            evicting: tokio::sync::Mutex::new(()),
+            copies: Semaphore::new(
+                options
+                    .max_concurrent_copies
+                    .unwrap_or(DEFAULT_MAX_CONCURRENT_COPIES)
+                    .max(1),
+            ),
+            options,
+        }
+    }
+
+    pub fn emit(&self, event: SyncEvent) {
+        match &event {
+            SyncEvent::Copied { path, .. } => {
+                debug!(path = %path.display(), "Copied");
+                self.copied.fetch_add(1, Ordering::Relaxed);
+                self.metrics.file_synced();
+            }
+            SyncEvent::Pulled(path) => {
+                debug!(path = %path.display(), "Pulled");
+                self.pulled.fetch_add(1, Ordering::Relaxed);
+                self.metrics.file_synced();
+            }
+            SyncEvent::Removed(path) => {
+                debug!(path = %path.display(), "Removed");
+                self.removed.fetch_add(1, Ordering::Relaxed);
+                self.metrics.file_removed();
+            }
+            SyncEvent::Renamed { from, to } => {
+                debug!(from = %from.display(), to = %to.display(), "Renamed");
+                // The backup changed just like it does for a copy
+                self.copied.fetch_add(1, Ordering::Relaxed);
+                self.metrics.file_synced();
+            }
+            SyncEvent::Error(error) => {
+                self.metrics.copy_error(error.path(), error.to_string());
+            }
+            _ => {}
+        }
+        match &event {
+            SyncEvent::Copied { path, .. } | SyncEvent::Pulled(path) | SyncEvent::Removed(path) => {
+                let relative_path = path.strip_prefix(&self.work_dir).unwrap_or(path);
+                let mut report = self.report.lock().unwrap();
+                report.changed.push(relative_path.to_path_buf());
+            }
+            SyncEvent::Renamed { from, to } => {
+                let mut report = self.report.lock().unwrap();
+                for path in [from, to] {
+                    let relative_path = path.strip_prefix(&self.work_dir).unwrap_or(path);
+                    report.changed.push(relative_path.to_path_buf());
+                }
+            }
+            SyncEvent::Error(error) => {
+                let path = error.path();
+                let relative_path = path.strip_prefix(&self.work_dir).unwrap_or(path);
+                let mut report = self.report.lock().unwrap();
+                report.errors.push(format!("{}: {error}", path.display()));
+                report.failed.push(relative_path.to_path_buf());
+            }
+            _ => {}
+        }
+        match &event {
+            SyncEvent::Copied { path, .. } | SyncEvent::Pulled(path) | SyncEvent::Removed(path) => {
+                self.update_index(path)
+            }
+            SyncEvent::Renamed { from, to } => {
+                self.update_index(from);
+                self.update_index(to);
+            }
+            _ => {}
+        }
+
+        // Nobody listening is fine, syncing carries on regardless
+        let _ = self.events.send(event);
+    }
+
+    /// Records what `path` and its backup look like now in the index
+    fn update_index(&self, path: &Path) {
+        let (Some(index), Some(backup_dir)) = (&self.index, self.backend.local_dir()) else {
+            return;
+        };
+        let Ok(relative_path) = self.relative_path(path) else {
+            return;
+        };
+        index.update(Side::Work, relative_path, file_metadata(path));
+        if let Ok(Some(backup_path)) = self.backup_path(relative_path) {
+            let metadata = file_metadata(&backup_dir.join(&backup_path));
+            index.update(Side::Backup, &backup_path, metadata);
+        }
+    }
+
+    /// Lists both trees into the index, in the background
+    fn start_index(self: &Arc<Self>) {
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                let Some(index) = &ctx.index else {
+                    return;
+                };
+                index.rebuilding();
+                let listed = async {
+                    let work_dir = ctx.work_dir.clone();
+                    let ignore = ctx.options.ignore.clone();
+                    let symlinks = ctx.options.copy.symlinks;
+                    let work_files = tokio::task::spawn_blocking(move || {
+                        list_files(&work_dir, &ignore, symlinks)
+                    })
+                    .await??;
+                    anyhow::Ok((work_files, ctx.backend.list().await?))
+                }
+                .await;
+                match listed {
+                    Ok((work_files, backup_files)) => index.rebuilt(work_files, backup_files),
+                    // The next sweep tries again
+                    Err(error) => debug!("Error listing the trees for the index: {error:#}"),
+                }
+                ctx.save_index(false).await;
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Writes the index if it's due, or right away with `now`
+    async fn save_index(self: &Arc<Self>, now: bool) {
+        if self.index.is_none() {
+            return;
+        }
+        let ctx = self.clone();
+        let saved = tokio::task::spawn_blocking(move || match &ctx.index {
+            Some(index) => index.save(now),
+            None => Ok(()),
+        })
+        .await;
+        if let Ok(Err(error)) = saved {
+            self.emit_error(Operation::Save, self.work_dir.clone(), error);
+        }
+    }
+
+    /// Emits a [`SyncEvent::Error`] for `error`, which `operation` on `path` failed with
+    pub fn emit_error(&self, operation: Operation, path: impl Into<PathBuf>, error: anyhow::Error) {
+        self.emit(SyncEvent::Error(SyncError::new(operation, path, error)));
+    }
+
+    pub fn is_shutting_down(&self) -> bool {
+        self.shutdown.is_cancelled()
+    }
+
+    /// Waits until syncing is shut down
+    pub async fn shut_down(&self) {
+        self.shutdown.cancelled().await;
+    }
+
+    /// Whether syncing is paused, from the control socket, for running on battery, for
+    /// quiet hours or while backup_dir is gone
+    pub fn is_paused(&self) -> bool {
+        self.control.is_paused()
+            || self.options.pause_on_battery && self.control.on_battery()
+            || self.control.is_quiet()
+            || self.control.is_missing()
+    }
+
+    /// How long to wait before the next scan, no shorter than
+    /// [`SyncOptions::battery_interval`] while on battery
+    pub fn interval(&self) -> Duration {
+        match self.options.battery_interval {
+            Some(interval) if self.control.on_battery() => self.schedule.interval().max(interval),
+            _ => self.schedule.interval(),
+        }
+    }
+
+    /// How long a file has to go without changes before it's copied, see
+    /// [`SyncOptions::debounce`] and [`SyncOptions::battery_interval`]
+    fn debounce(&self) -> Duration {
+        match self.options.battery_interval {
+            Some(interval) if self.control.on_battery() => self.options.debounce.max(interval),
+            _ => self.options.debounce,
+        }
+    }
+
+    /// Waits for `duration`, or until syncing is shut down
+    pub async fn sleep(&self, duration: Duration) {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => {}
+            _ = self.shutdown.cancelled() => {}
+        }
+    }
+
+    /// Watches a local backup in the background for files changed by others, see
+    /// [`SyncOptions::guard_backup`]. Syncing both ways picks those changes up instead
+    fn start_guard(self: &Arc<Self>, guard: GuardBackup) {
+        let Some(backup_dir) = self.backend.local_dir().map(Path::to_path_buf) else {
+            warn!(
+                "Only backups in a local directory can be guarded, leaving {} unguarded",
+                self.backend
+            );
+            return;
+        };
+        if self.options.bidirectional {
+            warn!("Syncing both ways copies changes made in the backup, leaving it unguarded");
+            return;
+        }
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                if let Err(error) = guard_backup(ctx.clone(), backup_dir, guard).await {
+                    let path = ctx.work_dir.clone();
+                    ctx.emit_error(Operation::Watch, path, error);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Rotates the backup at the end of every period in the background, checking at the
+    /// start of every minute, see [`SyncOptions::rotation`]
+    fn start_rotation(self: &Arc<Self>, rotation: Rotation) {
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                while !ctx.is_shutting_down() {
+                    let second = Local::now().second() as u64;
+                    ctx.sleep(Duration::from_secs(60 - second.min(59))).await;
+                    ctx.rotate(rotation).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Rotates a local backup if the period it mirrors is over
+    async fn rotate(&self, rotation: Rotation) {
+        let Some(backup_dir) = self.backend.local_dir().map(Path::to_path_buf) else {
+            return;
+        };
+        if self.options.dry_run {
+            return;
+        }
+        let ignore = self.options.ignore.clone();
+        let rotated = tokio::task::spawn_blocking({
+            let backup_dir = backup_dir.clone();
+            move || rotate_if_due(&backup_dir, &ignore, rotation, &Local::now())
+        })
+        .await;
+        match rotated {
+            Ok(Ok(Some(rotation_dir))) => self.emit(SyncEvent::Rotated(rotation_dir)),
+            Ok(Ok(None)) => {}
+            Ok(Err(error)) => self.emit_error(Operation::Rotate, backup_dir, error),
+            Err(error) => self.emit_error(Operation::Rotate, backup_dir, error.into()),
+        }
+    }
+
+    /// Sends the scheduled emails of [`SyncOptions::notify_email`] in the background
+    fn start_email_reports(self: &Arc<Self>) {
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                let work_dir = ctx.work_dir.display().to_string();
+                while !ctx.is_shutting_down() {
+                    ctx.notifier
+                        .check_email(&work_dir, &*ctx.backend, &ctx.metrics)
+                        .await;
+                    ctx.sleep(EMAIL_CHECK_INTERVAL).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Keeps [`SyncControl::on_battery`] up to date in the background, logging whenever it
+    /// changes
+    fn start_power_monitor(self: &Arc<Self>) {
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                while !ctx.is_shutting_down() {
+                    let state = tokio::task::spawn_blocking(PowerState::current)
+                        .await
+                        .unwrap_or_default();
+                    if state.constrained() != ctx.control.on_battery() {
+                        match (state.constrained(), ctx.options.pause_on_battery) {
+                            (true, true) => info!("Running {state}, pausing syncing"),
+                            (true, false) => info!("Running {state}, syncing less often"),
+                            (false, true) => info!("Running {state} again, resuming syncing"),
+                            (false, false) => info!("Running {state} again, syncing as usual"),
+                        }
+                        ctx.control.set_on_battery(state.constrained());
+                    }
+                    ctx.sleep(POWER_CHECK_INTERVAL).await;
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Keeps [`SyncControl::is_missing`] up to date in the background, see
+    /// [`crate::vanished`]
+    fn start_mount_monitor(self: &Arc<Self>, backup_dir: &Path) {
+        let root = BackupRoot::new(backup_dir);
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                while !ctx.is_shutting_down() {
+                    ctx.sleep(MOUNT_CHECK_INTERVAL).await;
+                    let checked = root.clone();
+                    let Ok(vanished) = tokio::task::spawn_blocking(move || checked.check()).await
+                    else {
+                        continue;
+                    };
+                    if vanished.is_some() != ctx.control.is_missing() {
+                        ctx.backup_vanished(root.path(), vanished);
+                    }
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Pauses syncing while `backup_dir` is `vanished`, and catches up once it's back
+    fn backup_vanished(self: &Arc<Self>, backup_dir: &Path, vanished: Option<Vanished>) {
+        let message = match vanished {
+            Some(vanished) => {
+                let message = format!("{} {vanished}", backup_dir.display());
+                warn!("{message}, pausing syncing until it's back");
+                message
+            }
+            None => format!("{} is back", backup_dir.display()),
+        };
+        self.control.set_missing(vanished.is_some());
+        self.emit(match vanished {
+            Some(vanished) => SyncEvent::Vanished(vanished),
+            None => SyncEvent::Returned,
+        });
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            let work_dir = ctx.work_dir.display().to_string();
+            match vanished {
+                Some(_) => {
+                    ctx.notifier
+                        .vanished(&work_dir, &*ctx.backend, &message)
+                        .await
+                }
+                None => {
+                    ctx.notifier
+                        .returned(&work_dir, &*ctx.backend, &message)
+                        .await
+                }
+            }
+        });
+    }
+
+    /// Whether changes are held back right now, see [`SyncOptions::quiet_hours`] and
+    /// [`SyncOptions::sync_window`]
+    fn is_quiet_at(&self, time: &DateTime<Local>) -> bool {
+        let SyncOptions {
+            quiet_hours,
+            sync_window,
+            ..
+        } = &self.options;
+        quiet_hours
+            .as_ref()
+            .is_some_and(|quiet_hours| quiet_hours.contains(time))
+            || sync_window
+                .as_ref()
+                .is_some_and(|sync_window| !sync_window.contains(time))
+    }
+
+    /// Keeps [`SyncControl::is_quiet`] up to date in the background, checking at the start
+    /// of every minute
+    fn start_quiet_monitor(self: &Arc<Self>) {
+        let update = |ctx: &Self| {
+            let quiet = ctx.is_quiet_at(&Local::now());
+            if quiet != ctx.control.is_quiet() {
+                match quiet {
+                    true => info!("Quiet hours, holding changes back until they're over"),
+                    false => info!("Quiet hours are over, syncing what changed in the meantime"),
+                }
+                ctx.control.set_quiet(quiet);
+            }
+        };
+        // The first sweep already holds back
+        update(self);
+        let ctx = self.clone();
+        tokio::task::spawn(
+            async move {
+                while !ctx.is_shutting_down() {
+                    let second = Local::now().second() as u64;
+                    ctx.sleep(Duration::from_secs(60 - second.min(59))).await;
+                    update(&ctx);
+                }
+            }
+            .in_current_span(),
+        );
+    }
+
+    /// Whether moved files get their backup moved along with them, which only makes sense
+    /// when the backup of their old path would be removed
+    pub fn detects_moves(&self) -> bool {
+        self.options.delete_after.is_some() && !self.options.bidirectional
+    }
+
+    /// Remembers the identity of every file in work_dir, which initializing made match the
+    /// backup. This does blocking IO
+    fn remember_files(&self) {
+        let walk = walk_files_in(
+            &self.work_dir,
+            &self.work_dir,
+            &self.options.ignore,
+            self.options.copy.symlinks,
+        );
+        for file_info in walk {
+            let (Ok(metadata), Ok(relative_path)) = (
+                file_info.metadata(),
+                file_info.path().strip_prefix(&self.work_dir),
+            ) else {
+                continue;
+            };
+            self.moves.record(relative_path, &Entry::from(&metadata));
+        }
+    }
+
+    /// Moves the backup of the file that `path` was moved from, if it was moved. `None`
+    /// leaves the file to be copied as usual, which is also what happens if anything goes
+    /// wrong
+    pub async fn move_backup(&self, path: &Path) -> Option<Moved> {
+        if !self.detects_moves() {
+            return None;
+        }
+        let relative_path = self.relative_path(path).ok()?;
+        let entry = self.vfs.follow(path).await.ok()??;
+        if self.moves.moved_here(relative_path, &entry) {
+            return Some(Moved::Already);
+        }
+        let from = self.moves.moved_from(relative_path, &entry)?;
+        // Still there, so this is another link to it rather than a move
+        if let Ok(Some(_)) = self.vfs.metadata(&self.work_dir.join(&from)).await {
+            return None;
+        }
+
+        // Either one leaving the backup, or failing to, is left to copying as usual
+        let (Ok(Some(from_backup)), Ok(Some(to_backup))) =
+            (self.backup_path(&from), self.backup_path(relative_path))
+        else {
+            return None;
+        };
+
+        if self.options.dry_run {
+            println!(
+                "WOULD RENAME {} to {}",
+                self.backup_location(&from_backup),
+                self.backup_location(&to_backup)
+            );
+            return Some(Moved::From(self.work_dir.join(from)));
+        }
+        match self.backend.rename(&from_backup, &to_backup).await {
+            Ok(true) => {}
+            Ok(false) => return None,
+            Err(err) => {
+                debug!(path = %path.display(), "Copying it instead of moving its backup: {err:#}");
+                return None;
+            }
+        }
+        self.moves.moved(&from, relative_path, &entry);
+        if let Some(manifest) = &self.manifest {
+            manifest.rename(&from_backup, &to_backup);
+        }
+        if let Some(written) = &self.written {
+            written.rename(&from_backup, &to_backup);
+        }
+        let from = self.work_dir.join(from);
+        self.keep_parent_dir(&from).await;
+        Some(Moved::From(from))
+    }
+
+    /// Waits for `duration`, until syncing is shut down, or until a sweep is requested with
+    /// [`SyncControl::sync_now`]. Returns whether one was
+    pub async fn wait(&self, duration: Duration) -> bool {
+        tokio::select! {
+            _ = tokio::time::sleep(duration) => false,
+            _ = self.shutdown.cancelled() => false,
+            _ = self.control.sync_requested() => true,
+        }
+    }
+
+    /// [`SyncContext::sweep`], reporting a failure as an event
+    pub async fn sweep_and_report(self: &Arc<Self>, remove_missing: bool) {
+        // It would copy everything into whatever is left where backup_dir was
+        if self.control.is_missing() {
+            debug!("Not sweeping while the backup is gone");
+            return;
+        }
+        if let Err(error) = self.sweep(remove_missing).await {
+            self.emit_error(Operation::Scan, self.work_dir.clone(), error);
+        }
+    }
+
+    /// Copies every file in work_dir that is missing from the backup or newer than its
+    /// copy there. Backups are never older than the file they were copied from, so this
+    /// skips everything that was already synced. With `remove_missing` and deletions
+    /// enabled, backups of files that are gone from work_dir are removed too
+    pub async fn sweep(self: &Arc<Self>, remove_missing: bool) -> Result<()> {
+        let span = debug_span!("sweep", work_dir = %self.work_dir.display());
+        let sweep = self.sweep_in_span(remove_missing).instrument(span);
+        let Some(timeout) = self.options.cycle_timeout else {
+            return sweep.await;
+        };
+        match tokio::time::timeout(timeout, sweep).await {
+            Ok(swept) => swept,
+            Err(_) => {
+                let timed_out = TimedOut {
+                    cycle: true,
+                    after: timeout,
+                };
+                self.degrade(timed_out);
+                Err(timed_out.into())
+            }
+        }
+    }
+
+    async fn sweep_in_span(self: &Arc<Self>, remove_missing: bool) -> Result<()> {
+        info!("Copying the files that are newer than their backup...");
+        let start = Instant::now();
+
+        if let Some(index) = &self.index {
+            index.rebuilding();
+        }
+        let walk_errors = self.options.ignore.walk_errors().count();
+        let (work_files, backup_files) = async {
+            let work_dir = self.work_dir.clone();
+            let ignore = self.options.ignore.clone();
+            let symlinks = self.options.copy.symlinks;
+            let work_files =
+                tokio::task::spawn_blocking(move || list_files(&work_dir, &ignore, symlinks))
+                    .await??;
+            anyhow::Ok((work_files, self.backend.list().await?))
+        }
+        .instrument(debug_span!("scan"))
+        .await?;
+        self.metrics.scanned(work_files.len(), start.elapsed());
+        if let Some(index) = &self.index {
+            index.rebuilt(work_files.clone(), backup_files.clone());
+        }
+
+        let mut changed_files = Vec::new();
+        for (relative_path, metadata) in &work_files {
+            let backup = match self.backup_path(relative_path) {
+                Ok(Some(backup_path)) => backup_files.get(&backup_path),
+                Ok(None) => continue,
+                // Copying it reports the error
+                Err(_) => None,
+            };
+            let changed = match backup {
+                // Transformed backups are a different size
+                Some(backup) if self.options.transforms.applies_to(relative_path) => {
+                    metadata.modified > backup.modified
+                }
+                Some(backup) => backup.size != metadata.size || metadata.modified > backup.modified,
+                None => true,
+            };
+            if changed {
+                let order = self.options.priorities.order(relative_path, metadata.size);
+                changed_files.push((order, relative_path));
+            }
+        }
+        // Stable, so files of the same order keep going in the order of their paths
+        changed_files.sort_by_key(|&(order, _)| Reverse(order));
+        for (_, relative_path) in changed_files {
+            if self.is_aborted() {
+                return Ok(());
+            }
+            self.sync_file(self.work_dir.join(relative_path)).await;
+        }
+
+        if remove_missing && self.options.delete_after.is_some() {
+            // What couldn't be read is left out of work_files, and would look deleted
+            match self.options.ignore.walk_errors().count() == walk_errors {
+                true => self.remove_deleted(&work_files, &backup_files).await,
+                false => warn!(
+                    "Not removing any backups this sweep, since some of {} couldn't be read",
+                    self.work_dir.display()
+                ),
+            }
+        }
+        if self.options.syncs_dirs() {
+            self.sync_dirs(remove_missing).await?;
+        }
+
+        Ok(())
+    }
+
+    /// Removes the backups of files that are gone from work_dir, after the same delay and
+    /// check that they're still gone as deletions found while syncing. A single run has no
+    /// later to wait for, and removes them right away
+    async fn remove_deleted(
+        self: &Arc<Self>,
+        work_files: &BTreeMap<PathBuf, FileMetadata>,
+        backup_files: &BTreeMap<PathBuf, FileMetadata>,
+    ) {
+        // Backups of files whose names are normalized are under a different path
+        let normalized: Option<HashSet<PathBuf>> =
+            (self.options.normalize_unicode != NormalizeUnicode::None).then(|| {
+                work_files
+                    .keys()
+                    .map(|relative_path| self.normalized(relative_path.clone()))
+                    .collect()
+            });
+        // A backup that can't tell them apart holds files that only differ in case as one
+        let folded: Option<HashSet<String>> = self.case.as_ref().map(|_| {
+            work_files
+                .keys()
+                .map(|relative_path| fold(self.normalized(relative_path.clone()).as_os_str()))
+                .collect()
+        });
+        for relative_path in backup_files.keys() {
+            let synced = match &normalized {
+                Some(normalized) => normalized.contains(relative_path),
+                None => work_files.contains_key(relative_path),
+            };
+            if self.options.ignore.is_ignored(relative_path, false)
+                || synced
+                || self.moves.moved_away(relative_path)
+            {
+                continue;
+            }
+            if let (Some(case), Some(folded)) = (&self.case, &folded) {
+                let kept = match original_path(relative_path) {
+                    Some(original) => {
+                        work_files.contains_key(&original)
+                            && case.destination(&original)
+                                == Destination::Renamed(relative_path.clone())
+                    }
+                    None => folded.contains(&fold(relative_path.as_os_str())),
+                };
+                if kept {
+                    continue;
+                }
+            }
+            let path = self.work_dir.join(relative_path);
+            match self.is_shutting_down() {
+                true => self.remove_now(path, Some(relative_path.clone())).await,
+                false => self.remove_later(path, Some(relative_path.clone())),
+            }
+        }
+    }
+
+    /// Creates the directories of work_dir that are missing from the backup, empty ones
+    /// included. With `remove_missing` and deletions enabled, empty directories that are
+    /// gone from work_dir are removed from the backup
+    pub async fn sync_dirs(&self, remove_missing: bool) -> Result<()> {
+        let Some(backup_dirs) = self.backend.list_dirs().await? else {
+            return Ok(());
+        };
+        let work_dirs = {
+            let work_dir = self.work_dir.clone();
+            let ignore = self.options.ignore.clone();
+            let symlinks = self.options.copy.symlinks;
+            tokio::task::spawn_blocking(move || list_dirs(&work_dir, &ignore, symlinks)).await??
+        };
+        let work_dirs: BTreeSet<PathBuf> = work_dirs
+            .into_iter()
+            .map(|relative_path| self.normalized(relative_path))
+            .collect();
+
+        for relative_path in work_dirs.difference(&backup_dirs) {
+            self.sync_dir(&self.work_dir.join(relative_path)).await;
+        }
+
+        if !remove_missing || self.options.delete_after.is_none() || self.options.dry_run {
+            return Ok(());
+        }
+        // Deepest first, so directories that only had empty ones in them go too
+        for relative_path in backup_dirs.iter().rev() {
+            if work_dirs.contains(relative_path)
+                || self.options.ignore.is_ignored(relative_path, true)
+            {
+                continue;
+            }
+            if let Err(error) = self.backend.delete_dir(relative_path).await {
+                let path = self.work_dir.join(relative_path);
+                self.emit_error(Operation::Remove, path, error);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Creates the backup of the directory `path` in work_dir
+    pub async fn sync_dir(&self, path: &Path) {
+        let relative_path = match self.relative_path(path) {
+            Ok(relative_path) => relative_path,
+            Err(error) => {
+                let path = path.to_path_buf();
+                return self.emit_error(Operation::Copy, path, error);
+            }
+        };
+        let backup_path = self.normalized(relative_path.to_path_buf());
+        if self.options.dry_run {
+            println!("WOULD CREATE {}", self.backup_location(&backup_path));
+            return;
+        }
+        match self.backend.create_dir(&backup_path).await {
+            Ok(()) => debug!(path = %path.display(), "Created directory"),
+            Err(error) => {
+                let path = path.to_path_buf();
+                self.emit_error(Operation::Copy, path, error);
+            }
+        }
+    }
+
+    /// Recreates the backup of the directory that `path` was in, in case removing or moving
+    /// the backup of `path` left it empty and pruned it while it's still in work_dir
+    async fn keep_parent_dir(&self, path: &Path) {
+        if !self.options.syncs_dirs() || self.options.dry_run {
+            return;
+        }
+        let Some(parent) = path.parent() else {
+            return;
+        };
+        if parent != self.work_dir
+            && fs::metadata(parent)
+                .await
+                .is_ok_and(|metadata| metadata.is_dir())
+        {
+            self.sync_dir(parent).await;
+        }
+    }
+
+    /// Removes the backup of the directory `path` once the configured delay has passed,
+    /// unless it reappeared in the meantime or something is still in it. Does nothing if
+    /// deletions aren't being propagated
+    pub fn schedule_dir_removal(self: &Arc<Self>, path: PathBuf) {
+        let Some(delay) = self.options.delete_after else {
+            return;
+        };
+
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            // The files in it are removed after the same delay, give them a head start
+            ctx.sleep(delay + Duration::from_millis(100)).await;
+            if ctx.is_shutting_down()
+                || ctx.options.dry_run
+                || !matches!(ctx.vfs.metadata(&path).await, Ok(None))
+            {
+                return;
+            }
+            let removed = match ctx.relative_path(&path) {
+                Ok(relative_path) => {
+                    let backup_path = ctx.normalized(relative_path.to_path_buf());
+                    ctx.backend.delete_dir(&backup_path).await
+                }
+                Err(error) => Err(error),
+            };
+            if let Err(error) = removed {
+                ctx.emit_error(Operation::Remove, path, error);
+            }
+        });
+    }
+
+    /// Remembers the current state of `path` so only later changes get copied
+    pub async fn observe_file(self: &Arc<Self>, path: &Path) -> Result<()> {
+        if !self.detector.is_expensive() {
+            let entry = self.detected_entry(path).await?;
+            let entry = entry.ok_or_else(|| not_found(path))?;
+            return self.detector.observe_entry(path, &entry);
+        }
+
+        // Hashing reads the whole file, so keep it off the async runtime
+        let ctx = self.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || ctx.detector.observe(&path)).await?
+    }
+
+    /// Whether `path` changed since it was last seen. Files that still can't be read haven't
+    pub async fn file_changed(self: &Arc<Self>, path: &Path) -> Result<bool> {
+        if self.still_unreadable(path).await {
+            return Ok(false);
+        }
+        if !self.detector.is_expensive() {
+            let entry = self.detected_entry(path).await?;
+            return self.detector.entry_changed(path, entry.as_ref());
+        }
+
+        let ctx = self.clone();
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || ctx.detector.has_changed(&path)).await?
+    }
+
+    /// The metadata change detection looks at: of what `path` links to, or of the link
+    /// itself where that's nothing
+    async fn detected_entry(&self, path: &Path) -> Result<Option<Entry>> {
+        match self.vfs.follow(path).await? {
+            Some(entry) => Ok(Some(entry)),
+            None => self.vfs.metadata(path).await,
+        }
+    }
+
+    /// Called whenever a scan finishes or the watcher goes quiet. Logs what the cycle did,
+    /// saves the hash cache, takes a snapshot if anything in the backup changed and runs
+    /// the hooks. Returns whether anything was synced
+    pub async fn end_cycle(self: &Arc<Self>) -> bool {
+        let span = debug_span!(
+            "end_cycle",
+            work_dir = %self.work_dir.display(),
+            copied = field::Empty,
+            removed = field::Empty,
+            errors = field::Empty,
+        );
+        self.finish_cycle().instrument(span).await
+    }
+
+    async fn finish_cycle(self: &Arc<Self>) -> bool {
+        let copied = self.copied.swap(0, Ordering::Relaxed);
+        let pulled = self.pulled.swap(0, Ordering::Relaxed);
+        let removed = self.removed.swap(0, Ordering::Relaxed);
+        let changed = copied + pulled + removed > 0;
+        if let Some(case) = &self.case {
+            case.forget();
+        }
+        Span::current()
+            .record("copied", copied + pulled)
+            .record("removed", removed);
+        if self.options.dry_run {
+            self.metrics.end_cycle();
+            let errors = std::mem::take(&mut *self.report.lock().unwrap()).errors;
+            if changed {
+                println!(
+                    "Would copy {} files and delete {removed} files",
+                    copied + pulled
+                );
+            }
+            if changed || !errors.is_empty() {
+                self.emit(SyncEvent::CycleComplete {
+                    copied,
+                    pulled,
+                    removed,
+                    errors: errors.len(),
+                });
+            }
+            return changed;
+        }
+        if let Err(error) = self.backend.flush().await {
+            self.emit_error(
+                Operation::Save,
+                PathBuf::from(self.backend.to_string()),
+                error,
+            );
+        }
+        self.save_and_snapshot(copied + removed > 0).await;
+        self.save_index(self.is_shutting_down()).await;
+        // Copies that failed may have left part of a file behind, or nothing at all
+        let failed = !self.report.lock().unwrap().errors.is_empty();
+        if copied + removed > 0 || failed {
+            if let Err(error) = self.measure_usage().await {
+                self.emit_error(
+                    Operation::Save,
+                    PathBuf::from(self.backend.to_string()),
+                    error,
+                );
+            }
+        }
+        if self.options.git && copied + removed > 0 {
+            self.commit_backup().await;
+        }
+
+        // Taken last, so errors saving or snapshotting are part of this cycle
+        let report = CycleReport {
+            copied,
+            pulled,
+            removed,
+            ..std::mem::take(&mut *self.report.lock().unwrap())
+        };
+        if report.errors.is_empty() {
+            self.metrics.synced();
+        }
+        let stats = self.metrics.end_cycle();
+        Span::current().record("errors", stats.errors);
+        if changed || stats.errors > 0 {
+            info!(
+                copied,
+                pulled,
+                removed,
+                errors = stats.errors,
+                "Synced {copied} files, pulled {pulled}, removed {removed}, {} errors. Scanned {} \
+                 files in {:.2}s, {} unreadable, copied {} in {:.2}s",
+                stats.errors,
+                stats.files_scanned,
+                stats.scan_ms as f64 / 1000.0,
+                stats.walk_errors,
+                HumanBytes(stats.bytes_copied),
+                stats.copy_ms as f64 / 1000.0,
+            );
+        }
+        if changed || !report.errors.is_empty() {
+            self.emit(SyncEvent::CycleComplete {
+                copied,
+                pulled,
+                removed,
+                errors: report.errors.len(),
+            });
+        }
+        self.run_hooks(&report).await;
+        self.notifier
+            .end_cycle(
+                &self.work_dir.display().to_string(),
+                &*self.backend,
+                &report,
+            )
+            .await;
+        changed
+    }
+
+    /// Runs [`SyncOptions::on_sync_complete`] if the cycle changed anything and
+    /// [`SyncOptions::on_error`] if anything failed
+    async fn run_hooks(&self, report: &CycleReport) {
+        let hook = HookContext {
+            dir: self.backend.local_dir().unwrap_or(&self.work_dir),
+            work_dir: &self.work_dir,
+            backup: self.backend.to_string(),
+        };
+        if let Some(command) = &self.options.on_sync_complete {
+            if report.copied + report.pulled + report.removed > 0 {
+                run_hook("sync-complete", command, &hook, report).await;
+            }
+        }
+        if let Some(command) = &self.options.on_error {
+            if !report.errors.is_empty() {
+                run_hook("error", command, &hook, report).await;
+            }
+        }
+    }
+
+    /// Commits the backup to its git history, see [`SyncOptions::git`]
+    async fn commit_backup(&self) {
+        let Some(backup_dir) = self.backend.local_dir() else {
+            return;
+        };
+        let changed = self.report.lock().unwrap().changed.clone();
+        match commit_backup(backup_dir, &changed).await {
+            Ok(true) => debug!("Committed {} changed files to git", changed.len()),
+            Ok(false) => {}
+            Err(error) => self.emit_error(Operation::Save, git_dir(backup_dir), error),
+        }
+    }
+
+    /// Saves the hash cache of a local backup, and snapshots it if `changed`
+    async fn save_and_snapshot(&self, changed: bool) {
+        let Some(backup_dir) = self.backend.local_dir() else {
+            return;
+        };
+
+        if let Err(error) = self.detector.save() {
+            self.emit_error(Operation::Save, backup_dir.to_path_buf(), error);
+        }
+        if let Some(key) = &self.options.sign_key {
+            if changed || !manifests_dir(backup_dir).exists() {
+                match self.sign_manifest(backup_dir, key).await {
+                    Ok(Some(manifest_path)) => self.emit(SyncEvent::ManifestSigned(manifest_path)),
+                    Ok(None) => {}
+                    Err(error) => {
+                        self.emit_error(Operation::Save, manifests_dir(backup_dir), error)
+                    }
+                }
+            }
+        }
+        if let Some(Err(error)) = self
+            .manifest
+            .as_ref()
+            .map(|manifest| manifest.save(backup_dir))
+        {
+            self.emit_error(Operation::Save, backup_dir.to_path_buf(), error);
+        }
+
+        let Some(keep) = self.options.snapshots else {
+            return;
+        };
+        if !changed {
+            return;
+        }
+
+        let backup_dir = backup_dir.to_path_buf();
+        let ignore = self.options.ignore.clone();
+        let snapshot = tokio::task::spawn_blocking({
+            let backup_dir = backup_dir.clone();
+            move || {
+                let snapshot_dir = take_snapshot(&backup_dir, &ignore)?;
+                prune_snapshots(&backup_dir, keep)?;
+                Ok::<_, anyhow::Error>(snapshot_dir)
+            }
+        })
+        .await;
+
+        match snapshot {
+            Ok(Ok(snapshot_dir)) => self.emit(SyncEvent::Snapshot(snapshot_dir)),
+            Ok(Err(error)) => self.emit_error(Operation::Snapshot, backup_dir, error),
+            Err(error) => self.emit_error(Operation::Snapshot, backup_dir, error.into()),
+        }
+    }
+
+    /// Hashes the files of `backup_dir` that copies didn't, and signs the result as its
+    /// newest manifest
+    async fn sign_manifest(
+        &self,
+        backup_dir: &Path,
+        key: &Arc<SigningKey>,
+    ) -> Result<Option<PathBuf>> {
+        let Some(manifest) = &self.manifest else {
+            return Ok(None);
+        };
+        let files = {
+            let backup_dir = backup_dir.to_path_buf();
+            let ignore = self.options.ignore.clone();
+            let symlinks = self.options.copy.symlinks;
+            tokio::task::spawn_blocking(move || list_files(&backup_dir, &ignore, symlinks))
+                .await??
+        };
+        let (backup_dir, manifest, key) = (backup_dir.to_path_buf(), manifest.clone(), key.clone());
+        tokio::task::spawn_blocking(move || {
+            let hashes = manifest.reconcile(&backup_dir, files.into_keys())?;
+            sign_manifest(&backup_dir, &hashes, &key)
+        })
+        .await?
+    }
+
+    /// Copies `path` into backup_dir if the change detector thinks it changed
+    pub async fn sync_file_if_changed(self: &Arc<Self>, path: PathBuf) {
+        match self.file_changed(&path).await {
+            Ok(true) => self.sync_file(path).await,
+            Ok(false) => {}
+            Err(error) if self.skip_unreadable(&path, &error).await => {}
+            Err(error) => self.emit_error(Operation::Scan, path, error),
+        }
+    }
+
+    /// The path of `path` relative to work_dir, which is where the backend keeps it
+    fn relative_path<'a>(&self, path: &'a Path) -> Result<&'a Path> {
+        path.strip_prefix(&self.work_dir).with_context(|| {
+            anyhow!(
+                "Error stripping prefix {} from {}",
+                self.work_dir.display(),
+                path.display()
+            )
+        })
+    }
+
+    /// Where the backup of `relative_path` is, for messages
+    pub fn backup_location(&self, relative_path: &Path) -> String {
+        match self.backend.local_dir() {
+            Some(backup_dir) => backup_dir.join(relative_path).display().to_string(),
+            None => format!("{} in {}", relative_path.display(), self.backend),
+        }
+    }
+
+    /// Copies `path` from work_dir into the backup, once fewer than the maximum number of
+    /// copies are running. Returns how it was copied, or `None` for files that are too large,
+    /// which are reported as skipped instead
+    pub async fn put_file(&self, path: &Path) -> Result<Option<CopyStats>> {
+        let relative_path = self.relative_path(path)?;
+        let entry = self
+            .vfs
+            .follow(path)
+            .await?
+            .ok_or_else(|| not_found(path))?;
+        let size = entry.size;
+        if self.options.too_large(size) {
+            debug!(path = %path.display(), size, "Too large, skipping it");
+            self.emit(SyncEvent::Skipped(path.to_path_buf()));
+            return Ok(None);
+        }
+        let Some(backup_path) = self.backup_path(relative_path)? else {
+            self.emit(SyncEvent::Skipped(path.to_path_buf()));
+            return Ok(None);
+        };
+        if self.options.dry_run {
+            println!("WOULD COPY {}", path.display());
+            return Ok(Some(CopyStats {
+                bytes: size,
+                duration: Duration::ZERO,
+            }));
+        }
+        let reservation = self.reserve(&backup_path, size).await?;
+
+        let queued = self.metrics.enqueue();
+        let put = async {
+            let _permit = self.copies.acquire().await?;
+            // Waiting for a copy slot doesn't count
+            let start = Instant::now();
+            let copy = async {
+                let is_link = self
+                    .vfs
+                    .metadata(path)
+                    .await?
+                    .is_some_and(|entry| entry.kind == EntryKind::Symlink);
+                if self.options.skip_open_files && !is_link {
+                    self.check_closed(path).await?;
+                }
+                let atomic = !is_link && self.options.atomic_copies.contains(relative_path);
+                let lock = match (self.options.lock_before_copy && !is_link) || atomic {
+                    true => Some(self.lock_source(path).await?),
+                    false => None,
+                };
+                let version = match atomic {
+                    true => Some(Version::from(&fs::metadata(path).await?)),
+                    false => None,
+                };
+                let staged = match self.transformed(relative_path, path).await? {
+                    Some(transformed) => Some(transformed),
+                    None if atomic => Some(self.snapshot(path).await?),
+                    None => None,
+                };
+                if let Some(version) = version {
+                    let unchanged = fs::metadata(path)
+                        .await
+                        .is_ok_and(|metadata| Version::from(&metadata) == version);
+                    if !unchanged {
+                        if let Some(staged) = &staged {
+                            let _ = fs::remove_file(staged).await;
+                        }
+                        return Err(Busy::Changed.into());
+                    }
+                    // The copy is staged, so writers don't have to wait for it to be put
+                    drop(lock);
+                }
+                let hash = match staged {
+                    Some(staged) => {
+                        let put = self.backend.put_verified(&backup_path, &staged).await;
+                        let _ = fs::remove_file(&staged).await;
+                        put?
+                    }
+                    None => self.backend.put_verified(&backup_path, path).await?,
+                };
+                anyhow::Ok(hash)
+            };
+            let hash = match self.options.copy_timeout {
+                Some(timeout) => {
+                    tokio::time::timeout(timeout, copy)
+                        .await
+                        .map_err(|_| TimedOut {
+                            cycle: false,
+                            after: timeout,
+                        })??
+                }
+                None => copy.await?,
+            };
+            anyhow::Ok((hash, start.elapsed()))
+        }
+        .await;
+        self.metrics.dequeue(queued);
+        let (hash, duration) = put?;
+        reservation.keep();
+        self.metrics.copied(duration);
+        if let (Some(manifest), Some(hash)) = (&self.manifest, hash) {
+            manifest.record(&backup_path, hash);
+        }
+        if let (Some(written), Some(backup_dir)) = (&self.written, self.backend.local_dir()) {
+            written.record(backup_dir, &backup_path);
+        }
+        self.metrics.add_bytes_copied(size);
+        self.retries.lock().unwrap().remove(path);
+        self.metrics.stopped_failing(path);
+        if self.detects_moves() {
+            self.moves.record(relative_path, &entry);
+        }
+        Ok(Some(CopyStats {
+            bytes: size,
+            duration,
+        }))
+    }
+
+    /// A temporary copy of `path` with [`SyncOptions::transforms`] applied, with the same
+    /// permissions and modification time. `None` if no transform applies to it
+    async fn transformed(&self, relative_path: &Path, path: &Path) -> Result<Option<PathBuf>> {
+        let transforms = &self.options.transforms;
+        if !transforms.applies_to(relative_path) || fs::symlink_metadata(path).await?.is_symlink() {
+            return Ok(None);
+        }
+
+        let (transforms, relative_path, path) = (
+            transforms.clone(),
+            relative_path.to_path_buf(),
+            path.to_path_buf(),
+        );
+        tokio::task::spawn_blocking(move || {
+            let metadata = std::fs::metadata(&path)?;
+            let contents = std::fs::read(&path)
+                .with_context(|| anyhow!("Error reading {}", path.display()))?;
+            let contents = transforms.apply(&relative_path, contents)?;
+            let transformed = temp_file();
+            std::fs::write(&transformed, contents)?;
+            std::fs::set_permissions(&transformed, metadata.permissions())?;
+            if let Ok(modified) = metadata.modified() {
+                filetime::set_file_mtime(
+                    &transformed,
+                    filetime::FileTime::from_system_time(modified),
+                )?;
+            }
+            Ok(Some(transformed))
+        })
+        .await?
+    }
+
+    /// A shared lock on `path` for as long as it's copied, see
+    /// [`SyncOptions::lock_before_copy`]. Fails with [`Busy::Locked`] if a writer holds one
+    async fn lock_source(&self, path: &Path) -> Result<SourceLock> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || match SourceLock::try_shared(&path) {
+            Ok(Some(lock)) => Ok(lock),
+            Ok(None) => Err(Busy::Locked.into()),
+            Err(err) => Err(err).with_context(|| anyhow!("Error locking {}", path.display())),
+        })
+        .await?
+    }
+
+    /// Fails with [`Busy::OpenForWriting`] if another program has `path` open for writing,
+    /// see [`SyncOptions::skip_open_files`]
+    async fn check_closed(&self, path: &Path) -> Result<()> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || match open_for_writing(&path) {
+            Ok(false) => Ok(()),
+            Ok(true) => Err(Busy::OpenForWriting.into()),
+            Err(err) => Err(err)
+                .with_context(|| anyhow!("Error checking whether {} is open", path.display())),
+        })
+        .await?
+    }
+
+    /// A temporary copy of `path` with the same permissions and modification time, which
+    /// can be put into the backup after `path` has changed again
+    async fn snapshot(&self, path: &Path) -> Result<PathBuf> {
+        let path = path.to_path_buf();
+        tokio::task::spawn_blocking(move || {
+            let snapshot = temp_file();
+            std::fs::copy(&path, &snapshot)
+                .with_context(|| anyhow!("Error reading {}", path.display()))?;
+            if let Ok(modified) = std::fs::metadata(&path).and_then(|metadata| metadata.modified())
+            {
+                filetime::set_file_mtime(
+                    &snapshot,
+                    filetime::FileTime::from_system_time(modified),
+                )?;
+            }
+            Ok(snapshot)
+        })
+        .await?
+    }
+
+    /// How much longer `path` has to go unmodified before it counts as settled, or `None`
+    /// if it already has
+    pub fn time_to_settle(&self, path: &Path) -> Option<Duration> {
+        if self.options.settle.is_zero() {
+            return None;
+        }
+
+        let modified = std::fs::symlink_metadata(path).ok()?.modified().ok()?;
+        let age = SystemTime::now()
+            .duration_since(modified)
+            .unwrap_or_default();
+        self.options
+            .settle
+            .checked_sub(age)
+            .filter(|wait| !wait.is_zero())
+    }
+
+    /// Where `relative_path` goes in the backup, `None` if it's left out for colliding with
+    /// another path there, see [`SyncOptions::case_collisions`] and
+    /// [`SyncOptions::normalize_unicode`]
+    fn backup_path(&self, relative_path: &Path) -> Result<Option<PathBuf>> {
+        let backup_path = match &self.case {
+            Some(case) => case.backup_path(relative_path)?,
+            None => Some(relative_path.to_path_buf()),
+        };
+        Ok(backup_path.map(|backup_path| self.normalized(backup_path)))
+    }
+
+    /// `backup_path` in the form of [`SyncOptions::normalize_unicode`]
+    fn normalized(&self, backup_path: PathBuf) -> PathBuf {
+        match self.options.normalize_unicode.path(&backup_path) {
+            Cow::Borrowed(_) => backup_path,
+            Cow::Owned(normalized) => normalized,
+        }
+    }
+
+    /// Measures what the backup takes up, with [`SyncOptions::quota`]
+    async fn measure_usage(&self) -> Result<()> {
+        let Some(quota) = self.options.quota else {
+            return Ok(());
+        };
+        let used = match self.backend.local_dir() {
+            Some(backup_dir) => {
+                let backup_dir = backup_dir.to_path_buf();
+                tokio::task::spawn_blocking(move || disk_usage(&backup_dir)).await??
+            }
+            None => self
+                .backend
+                .list()
+                .await?
+                .values()
+                .map(|file| file.size)
+                .sum(),
+        };
+        self.usage.store(used, Ordering::Relaxed);
+        debug!(used, quota = quota.bytes, "Measured the backup");
+        if used > quota.bytes {
+            warn!(
+                "{} takes up {}, more than its quota of {}",
+                self.backend,
+                HumanBytes(used),
+                HumanBytes(quota.bytes)
+            );
+        }
+        Ok(())
+    }
+
+    /// Counts a copy of `size` bytes to `relative_path` towards [`SyncOptions::quota`],
+    /// evicting old snapshots and cleared directories first to make room for it with
+    /// [`QuotaPolicy::Evict`]. Fails with [`QuotaExceeded`] when it doesn't fit. The bytes
+    /// are given back when the reservation is dropped before [`Reservation::keep`]
+    async fn reserve(&self, relative_path: &Path, size: u64) -> Result<Reservation<'_>> {
+        let Some(quota) = self.options.quota else {
+            return Ok(Reservation::none(&self.usage));
+        };
+        // Replacing a backup frees its old copy, unless a snapshot still links to it
+        let replaced = match self.options.snapshots {
+            Some(_) => 0,
+            None => self
+                .backend
+                .metadata(relative_path)
+                .await
+                .ok()
+                .flatten()
+                .map_or(0, |metadata| metadata.size),
+        };
+        let needed = size.saturating_sub(replaced);
+
+        let _evicting = self.evicting.lock().await;
+        let used = self.usage.load(Ordering::Relaxed);
+        if let (QuotaPolicy::Evict, Some(backup_dir), true) = (
+            quota.policy,
+            self.backend.local_dir(),
+            used + needed > quota.bytes,
+        ) {
+            let backup_dir = backup_dir.to_path_buf();
+            let over = used + needed - quota.bytes;
+            let (evicted, freed) =
+                tokio::task::spawn_blocking(move || evict(&backup_dir, over)).await??;
+            for dir in &evicted {
+                warn!("Removed {} to stay within the backup quota", dir.display());
+            }
+            self.usage
+                .store(used.saturating_sub(freed), Ordering::Relaxed);
+        }
+
+        let used = self.usage.load(Ordering::Relaxed);
+        if used + needed > quota.bytes {
+            return Err(QuotaExceeded {
+                quota: quota.bytes,
+                used,
+                needed,
+            }
+            .into());
+        }
+        self.usage.fetch_add(needed, Ordering::Relaxed);
+        Ok(Reservation {
+            usage: &self.usage,
+            bytes: needed,
+        })
+    }
+
+    /// Copies `path` from work_dir into the backup, reporting the outcome as an event. A
+    /// file that changed again within [`SyncOptions::debounce`] or hasn't settled yet is
+    /// copied later instead
+    pub async fn sync_file(self: &Arc<Self>, path: PathBuf) {
+        if self.debounce().is_zero() || self.is_shutting_down() {
+            return self.sync_settled(path).await;
+        }
+        // Every later change to it pushes back the copy that is already waiting
+        if self
+            .debouncing
+            .lock()
+            .unwrap()
+            .insert(path.clone(), Instant::now())
+            .is_some()
+        {
+            return;
+        }
+        let queued = self.metrics.enqueue();
+
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            loop {
+                let changed = ctx.debouncing.lock().unwrap()[&path];
+                let wait = (changed + ctx.debounce()).saturating_duration_since(Instant::now());
+                // Shutting down copies it right away
+                if wait.is_zero() || ctx.is_shutting_down() {
+                    break;
+                }
+                ctx.sleep(wait).await;
+            }
+            ctx.debouncing.lock().unwrap().remove(&path);
+            ctx.metrics.dequeue(queued);
+            ctx.sync_settled(path).await;
+        });
+    }
+
+    /// Copies `path` like [`SyncContext::sync_file`] once it has settled
+    async fn sync_settled(self: &Arc<Self>, path: PathBuf) {
+        if let Some(wait) = self.time_to_settle(&path) {
+            // Every later change to it is picked up by the copy that is already waiting
+            if !self.settling.lock().unwrap().insert(path.clone()) {
+                return;
+            }
+            let queued = self.metrics.enqueue();
+            debug!(path = %path.display(), "Waiting for it to settle");
+
+            // Not cut short by shutting down, the stream of events only ends after this
+            let ctx = self.clone();
+            tokio::task::spawn(async move {
+                let mut wait = wait;
+                loop {
+                    tokio::time::sleep(wait).await;
+                    match ctx.time_to_settle(&path) {
+                        Some(more) => wait = more,
+                        None => break,
+                    }
+                }
+                ctx.settling.lock().unwrap().remove(&path);
+                ctx.metrics.dequeue(queued);
+                ctx.copy_and_report(path).await;
+            });
+            return;
+        }
+
+        self.copy_and_report(path).await;
+    }
+
+    async fn copy_and_report(self: &Arc<Self>, path: PathBuf) {
+        let relative_path = path.strip_prefix(&self.work_dir).unwrap_or(&path);
+        let span = debug_span!(
+            "copy",
+            path = %relative_path.display(),
+            bytes = field::Empty,
+            error = field::Empty,
+        );
+        self.copy_and_report_in_span(path).instrument(span).await
+    }
+
+    async fn copy_and_report_in_span(self: &Arc<Self>, path: PathBuf) {
+        if self.still_unreadable(&path).await {
+            debug!(path = %path.display(), "Still can't be read, skipping it");
+            return;
+        }
+        // Copied by the sweep once the backup is back
+        if self.control.is_missing() {
+            debug!(path = %path.display(), "The backup is gone, skipping it");
+            return;
+        }
+        match self.move_backup(&path).await {
+            Some(Moved::From(from)) => return self.emit(SyncEvent::Renamed { from, to: path }),
+            Some(Moved::Already) => return,
+            None => {}
+        }
+        if let Some(backoff) = self.watchdog.backoff() {
+            debug!(path = %path.display(), "Waiting {backoff:?} for the degraded backup");
+            self.sleep(backoff).await;
+        }
+        match self.put_file(&path).await {
+            Ok(Some(copy)) => {
+                Span::current().record("bytes", copy.bytes);
+                self.responded();
+                self.emit(copy.event(path))
+            }
+            Ok(None) => {}
+            Err(error) => match error.downcast_ref::<Busy>() {
+                Some(&busy) if busy == Busy::OpenForWriting || self.is_atomic(&path) => {
+                    self.skip_cycle(path, busy)
+                }
+                _ if self.skip_unreadable(&path, &error).await => {}
+                _ => {
+                    if let Some(&timed_out) = error.downcast_ref::<TimedOut>() {
+                        self.degrade(timed_out);
+                    }
+                    Span::current().record("error", format!("{error:#}"));
+                    self.copy_failed(path, error)
+                }
+            },
+        }
+    }
+
+    /// Where `path` goes among the files waiting to be copied, see [`SyncOptions::priorities`]
+    pub async fn copy_order(&self, path: &Path) -> CopyOrder {
+        // A file that's gone by now fails once it's copied, wherever it goes
+        let size = match self.vfs.follow(path).await {
+            Ok(Some(entry)) => entry.size,
+            _ => 0,
+        };
+        let relative_path = path.strip_prefix(&self.work_dir).unwrap_or(path);
+        self.options.priorities.order(relative_path, size)
+    }
+
+    /// Whether `path` is copied atomically or not at all, see [`SyncOptions::atomic_copies`]
+    fn is_atomic(&self, path: &Path) -> bool {
+        self.relative_path(path)
+            .is_ok_and(|relative_path| self.options.atomic_copies.contains(relative_path))
+    }
+
+    /// Copies `path` on the next cycle instead, since it couldn't be copied atomically on
+    /// this one or was open for writing. That isn't an error, and it's tried for as long as
+    /// it takes
+    fn skip_cycle(self: &Arc<Self>, path: PathBuf, busy: Busy) {
+        debug!(path = %path.display(), "Not copied, {busy}, trying next cycle");
+        self.emit(SyncEvent::Skipped(path.clone()));
+        {
+            let mut retries = self.retries.lock().unwrap();
+            let retry = retries.entry(path.clone()).or_default();
+            if retry.pending || self.is_shutting_down() {
+                return;
+            }
+            retry.pending = true;
+        }
+
+        let delay = self.options.interval.unwrap_or(DEFAULT_INTERVAL);
+        let queued = self.metrics.enqueue();
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            ctx.sleep(delay).await;
+            ctx.metrics.dequeue(queued);
+            let gone = fs::symlink_metadata(&path).await.is_err();
+            {
+                let mut retries = ctx.retries.lock().unwrap();
+                if gone || ctx.is_shutting_down() {
+                    retries.remove(&path);
+                    return;
+                }
+                if let Some(retry) = retries.get_mut(&path) {
+                    retry.pending = false;
+                }
+            }
+            ctx.copy_and_report(path).await;
+        });
+    }
+
+    /// Skips `path` from now on if `error` is because it can't be read, which retrying
+    /// doesn't fix, until its metadata changes. Reported once, and listed by `status`.
+    /// Returns whether it was skipped
+    pub async fn skip_unreadable(&self, path: &Path, error: &anyhow::Error) -> bool {
+        let denied = error.chain().any(|cause| {
+            cause
+                .downcast_ref::<io::Error>()
+                .is_some_and(|err| err.kind() == io::ErrorKind::PermissionDenied)
+        });
+        if !denied {
+            return false;
+        }
+        // Rather than the backup not letting it be written
+        let Ok(metadata) = fs::metadata(path).await else {
+            return false;
+        };
+        match fs::File::open(path).await {
+            Err(err) if err.kind() == io::ErrorKind::PermissionDenied => {}
+            _ => return false,
+        }
+
+        let unreadable = Unreadable::from(&metadata);
+        let previous = self
+            .unreadable
+            .lock()
+            .unwrap()
+            .insert(path.to_path_buf(), unreadable);
+        if previous.is_none() {
+            warn!(
+                path = %path.display(),
+                "Can't be read, skipping it until its permissions change: {error:#}"
+            );
+        }
+        self.retries.lock().unwrap().remove(path);
+        self.metrics.permission_denied(path, format!("{error:#}"));
+        self.emit(SyncEvent::Skipped(path.to_path_buf()));
+        true
+    }
+
+    /// Whether `path` couldn't be read and its metadata didn't change since, see
+    /// [`SyncContext::skip_unreadable`]. One that changed is forgotten by the detector, so
+    /// it's copied whether or not its contents changed
+    async fn still_unreadable(&self, path: &Path) -> bool {
+        let Some(unreadable) = self.unreadable.lock().unwrap().get(path).cloned() else {
+            return false;
+        };
+        if let Ok(metadata) = fs::metadata(path).await {
+            if Unreadable::from(&metadata) == unreadable {
+                return true;
+            }
+        }
+        self.unreadable.lock().unwrap().remove(path);
+        self.detector.forget(path);
+        false
+    }
+
+    /// Copies `path` again after a delay that doubles with every attempt, since a busy file
+    /// or a network hiccup usually goes away. Once it failed [`SyncOptions::max_retries`]
+    /// times in a row, or when shutting down, `error` is reported as an event instead
+    pub fn copy_failed(self: &Arc<Self>, path: PathBuf, error: anyhow::Error) {
+        let max_retries = self.options.max_retries.unwrap_or(DEFAULT_MAX_RETRIES);
+        let attempt = {
+            let mut retries = self.retries.lock().unwrap();
+            let retry = retries.entry(path.clone()).or_default();
+            // The retry that is already waiting copies whatever changed since
+            if retry.pending {
+                return;
+            }
+            // Retrying doesn't make room in the quota
+            let retrying = !self.is_shutting_down() && !error.is::<QuotaExceeded>();
+            match retry.attempts < max_retries && retrying {
+                true => {
+                    retry.attempts += 1;
+                    retry.pending = true;
+                    Some(retry.attempts)
+                }
+                false => {
+                    retries.remove(&path);
+                    None
+                }
+            }
+        };
+        let Some(attempt) = attempt else {
+            self.metrics.failing(&path, format!("{error:#}"));
+            self.emit_error(Operation::Copy, path, error);
+            return self.count_failed_copy();
+        };
+
+        let delay = RETRY_DELAY
+            .saturating_mul(2u32.saturating_pow(attempt - 1))
+            .min(MAX_RETRY_DELAY);
+        warn!(
+            path = %path.display(),
+            attempt,
+            "Error copying, retrying in {}s: {error:#}",
+            delay.as_secs()
+        );
+        let queued = self.metrics.enqueue();
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            ctx.sleep(delay).await;
+            ctx.metrics.dequeue(queued);
+            let gone = fs::symlink_metadata(&path).await.is_err();
+            {
+                let mut retries = ctx.retries.lock().unwrap();
+                // The sweep on shutdown copies it one last time
+                if gone || ctx.is_shutting_down() {
+                    retries.remove(&path);
+                    return;
+                }
+                if let Some(retry) = retries.get_mut(&path) {
+                    retry.pending = false;
+                }
+            }
+            ctx.copy_and_report(path).await;
+        });
+    }
+
+    /// Counts the backup as degraded after `timed_out`, so copies wait for it a while
+    fn degrade(self: &Arc<Self>, timed_out: TimedOut) {
+        let (backoff, degraded) = self.watchdog.timed_out();
+        warn!(
+            "{timed_out}. {} might be failing, copies wait {}s before trying it again",
+            self.backend,
+            backoff.as_secs()
+        );
+        if !degraded {
+            return;
+        }
+        self.control.set_degraded(true);
+        self.emit(SyncEvent::Degraded(timed_out));
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            let message = format!("{} might be failing: {timed_out}", ctx.backend);
+            ctx.notifier
+                .degraded(&ctx.work_dir.display().to_string(), &*ctx.backend, &message)
+                .await;
+        });
+    }
+
+    /// Ends the degraded state of the backup once a copy finished in time again
+    fn responded(self: &Arc<Self>) {
+        if !self.watchdog.responded() {
+            return;
+        }
+        self.control.set_degraded(false);
+        self.emit(SyncEvent::Responding);
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            let message = format!("{} responds again", ctx.backend);
+            ctx.notifier
+                .responding(&ctx.work_dir.display().to_string(), &*ctx.backend, &message)
+                .await;
+        });
+    }
+
+    /// Counts a copy that failed for good, and stops syncing once there were
+    /// [`SyncOptions::max_errors`] of them with [`ErrorPolicy::Abort`]
+    fn count_failed_copy(&self) {
+        if self.options.error_policy != ErrorPolicy::Abort {
+            return;
+        }
+        let failed = self.failed_copies.fetch_add(1, Ordering::Relaxed) + 1;
+        if failed < self.options.max_errors.unwrap_or(1)
+            || self.aborted.swap(true, Ordering::Relaxed)
+        {
+            return;
+        }
+        self.emit(SyncEvent::Aborted { failed });
+        self.shutdown.cancel();
+    }
+
+    /// Whether syncing stopped because too many copies failed
+    pub fn is_aborted(&self) -> bool {
+        self.aborted.load(Ordering::Relaxed)
+    }
+
+    /// Removes the backup of `path` once the configured delay has passed, unless it
+    /// reappeared in the meantime. Does nothing if deletions aren't being propagated
+    pub fn schedule_removal(self: &Arc<Self>, path: PathBuf) {
+        self.detector.forget(&path);
+        self.metrics.stopped_failing(&path);
+        self.remove_later(path, None);
+    }
+
+    /// Removes the backup of `path` like [`SyncContext::schedule_removal`]. `backup_path` is
+    /// where it is in the backup, which is worked out from `path` without one
+    fn remove_later(self: &Arc<Self>, path: PathBuf, backup_path: Option<PathBuf>) {
+        let Some(delay) = self.options.delete_after else {
+            return;
+        };
+
+        let delay = match self.detects_moves() {
+            true => delay.max(MOVE_GRACE),
+            false => delay,
+        };
+        let ctx = self.clone();
+        tokio::task::spawn(async move {
+            ctx.sleep(delay).await;
+            // The delay didn't pass, so the backup is kept until the next run decides
+            if !ctx.is_shutting_down() {
+                ctx.remove_now(path, backup_path).await;
+            }
+        });
+    }
+
+    /// Removes the backup of `path` if it's still gone. Only what's known to be gone is
+    /// removed, not what just can't be read right now
+    async fn remove_now(self: &Arc<Self>, path: PathBuf, backup_path: Option<PathBuf>) {
+        if !matches!(self.vfs.metadata(&path).await, Ok(None)) {
+            return;
+        }
+        if let Ok(relative_path) = self.relative_path(&path) {
+            if self.moves.moved_away(relative_path) {
+                return;
+            }
+        }
+
+        let backup_path = match backup_path {
+            Some(backup_path) => Ok(Some(backup_path)),
+            None => self.relative_path(&path).map(|relative_path| {
+                match self
+                    .case
+                    .as_ref()
+                    .map(|case| case.destination(relative_path))
+                {
+                    None | Some(Destination::Same) => Some(relative_path.to_path_buf()),
+                    Some(Destination::Renamed(backup_path)) => Some(backup_path),
+                    // Its backup is the one of the file it collides with
+                    Some(Destination::Collides { .. }) => None,
+                }
+                .map(|backup_path| self.normalized(backup_path))
+            }),
+        };
+        let removed = match backup_path {
+            Ok(Some(backup_path)) if self.options.dry_run => {
+                println!("WOULD DELETE {}", self.backup_location(&backup_path));
+                Ok(())
+            }
+            Ok(Some(backup_path)) => {
+                let deleted = self.backend.delete(&backup_path).await;
+                if let (Ok(()), Some(manifest)) = (&deleted, &self.manifest) {
+                    manifest.remove(&backup_path);
+                }
+                if let (Ok(()), Some(written)) = (&deleted, &self.written) {
+                    written.forget(&backup_path);
+                }
+                deleted
+            }
+            Ok(None) => Ok(()),
+            Err(error) => Err(error),
+        };
+        match removed {
+            Ok(()) => {
+                self.keep_parent_dir(&path).await;
+                self.emit(SyncEvent::Removed(path));
+            }
+            Err(error) => self.emit_error(Operation::Remove, path, error),
+        }
+    }
+}
+
+/// The `files` at or under one of `paths`, or all of them without any. Fails if one of
+/// `paths` matches nothing
+fn select_paths(files: Vec<PathBuf>, paths: &[PathBuf]) -> Result<Vec<PathBuf>> {
+    if paths.is_empty() {
+        return Ok(files);
+    }
+    if let Some(missing) = paths
+        .iter()
+        .find(|path| !files.iter().any(|file| file.starts_with(path)))
+    {
+        return Err(anyhow!("{} isn't in the backup", missing.display()));
+    }
+    Ok(files
+        .into_iter()
+        .filter(|file| paths.iter().any(|path| file.starts_with(path)))
+        .collect())
+}
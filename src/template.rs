@@ -0,0 +1,338 @@
+//! Variables in backup_dir, for sending several machines or profiles to one share, like
+//! `/mnt/nas/{hostname}/{profile}/{date}`.
+//!
+//! `{hostname}`, `{user}` and `{profile}` are the name of this machine, the user running
+//! evil_mount and the `--profile` of the config file. `{date}` is the local date like
+//! 2024-05-01, and `{year}`, `{month}`, `{day}` and `{hour}` are its parts, with leading
+//! zeros. `{{` and `}}` stand for `{` and `}` themselves. Paths are expanded at startup,
+//! and a running sync moves on to the new path once one of the date variables changes
+
+use anyhow::{anyhow, Result};
+use chrono::{DateTime, Local};
+use std::path::{Component, Path, PathBuf};
+
+/// The variables backup_dir can have
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Var {
+    Hostname,
+    User,
+    Profile,
+    Date,
+    Year,
+    Month,
+    Day,
+    Hour,
+}
+
+impl Var {
+    fn parse(name: &str) -> Option<Self> {
+        Some(match name {
+            "hostname" => Self::Hostname,
+            "user" => Self::User,
+            "profile" => Self::Profile,
+            "date" => Self::Date,
+            "year" => Self::Year,
+            "month" => Self::Month,
+            "day" => Self::Day,
+            "hour" => Self::Hour,
+            _ => return None,
+        })
+    }
+
+    /// The `strftime` format of the date variables
+    fn time_format(self) -> Option<&'static str> {
+        match self {
+            Self::Date => Some("%Y-%m-%d"),
+            Self::Year => Some("%Y"),
+            Self::Month => Some("%m"),
+            Self::Day => Some("%d"),
+            Self::Hour => Some("%H"),
+            Self::Hostname | Self::User | Self::Profile => None,
+        }
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq)]
+enum Part {
+    Text(String),
+    Var(Var),
+}
+
+/// What the variables that don't change while running stand for
+#[derive(Debug, Clone, Default)]
+pub struct PathVars {
+    pub hostname: String,
+    pub user: String,
+    pub profile: Option<String>,
+}
+
+impl PathVars {
+    /// The variables of this machine and user, with the `--profile` if there is one
+    pub fn current(profile: Option<&str>) -> Self {
+        Self {
+            hostname: hostname(),
+            user: user(),
+            profile: profile.map(str::to_string),
+        }
+    }
+}
+
+#[cfg(unix)]
+fn hostname() -> String {
+    nix::unistd::gethostname()
+        .map(|hostname| hostname.to_string_lossy().into_owned())
+        .unwrap_or_default()
+}
+
+#[cfg(not(unix))]
+fn hostname() -> String {
+    std::env::var("COMPUTERNAME").unwrap_or_default()
+}
+
+#[cfg(unix)]
+fn user() -> String {
+    match nix::unistd::User::from_uid(nix::unistd::getuid()) {
+        Ok(Some(user)) => user.name,
+        _ => std::env::var("USER").unwrap_or_default(),
+    }
+}
+
+#[cfg(not(unix))]
+fn user() -> String {
+    std::env::var("USERNAME").unwrap_or_default()
+}
+
+/// Whether `path` has variables, or braces that would be taken for them
+pub fn is_template(path: &str) -> bool {
+    path.contains(['{', '}'])
+}
+
+/// Whether what `template` expands to changes with the date
+pub fn depends_on_time(template: &str) -> bool {
+    parse(template).is_ok_and(|parts| {
+        parts
+            .iter()
+            .any(|part| matches!(part, Part::Var(var) if var.time_format().is_some()))
+    })
+}
+
+/// The directories at the start of `template` without a variable in them, like `/mnt/nas`
+/// of `/mnt/nas/{hostname}/{date}`
+pub fn fixed_prefix(template: &str) -> PathBuf {
+    let mut prefix = PathBuf::new();
+    for component in Path::new(template).components() {
+        let name = match component {
+            Component::Normal(name) => name.to_string_lossy(),
+            component => {
+                prefix.push(component);
+                continue;
+            }
+        };
+        let Ok(parts) = parse(&name) else {
+            break;
+        };
+        let mut fixed = String::new();
+        for part in parts {
+            match part {
+                Part::Text(text) => fixed.push_str(&text),
+                Part::Var(_) => return prefix,
+            }
+        }
+        prefix.push(fixed);
+    }
+    prefix
+}
+
+/// `template` with its variables replaced by what they stand for `now`. Fails on unknown
+/// variables, unmatched braces, and values that aren't a single path component
+pub fn expand(template: &str, vars: &PathVars, now: &DateTime<Local>) -> Result<String> {
+    let mut expanded = String::with_capacity(template.len());
+    for part in parse(template)? {
+        let var = match part {
+            Part::Text(text) => {
+                expanded.push_str(&text);
+                continue;
+            }
+            Part::Var(var) => var,
+        };
+        if let Some(format) = var.time_format() {
+            expanded.push_str(&now.format(format).to_string());
+            continue;
+        }
+        let (name, value) = match var {
+            Var::Hostname => ("hostname", vars.hostname.as_str()),
+            Var::User => ("user", vars.user.as_str()),
+            _ => (
+                "profile",
+                vars.profile.as_deref().ok_or_else(|| {
+                    anyhow!("{template} has {{profile}} in it, which needs --profile")
+                })?,
+            ),
+        };
+        if value.is_empty() || value == "." || value == ".." || value.contains(['/', '\\', '\0']) {
+            return Err(anyhow!(
+                "{{{name}}} in {template} would be {value:?}, which can't be a directory name"
+            ));
+        }
+        expanded.push_str(value);
+    }
+    Ok(expanded)
+}
+
+fn parse(template: &str) -> Result<Vec<Part>> {
+    let mut parts = Vec::new();
+    let mut text = String::new();
+    let mut chars = template.chars().peekable();
+    while let Some(c) = chars.next() {
+        match c {
+            '{' if chars.peek() == Some(&'{') => {
+                chars.next();
+                text.push('{');
+            }
+            '}' if chars.peek() == Some(&'}') => {
+                chars.next();
+                text.push('}');
+            }
+            '{' => {
+                let mut name = String::new();
+                let mut closed = false;
+                for c in chars.by_ref() {
+                    if c == '}' {
+                        closed = true;
+                        break;
+                    }
+                    name.push(c);
+                }
+                if !closed {
+                    return Err(anyhow!(
+                        "{template} has a {{ that isn't closed, write {{{{ for a brace"
+                    ));
+                }
+                let var = Var::parse(&name).ok_or_else(|| {
+                    anyhow!(
+                        "Unknown variable {{{name}}} in {template}, expected {{hostname}}, \
+                         {{user}}, {{profile}}, {{date}}, {{year}}, {{month}}, {{day}} or \
+                         {{hour}}. Write {{{{ and }}}} for braces"
+                    )
+                })?;
+                if !text.is_empty() {
+                    parts.push(Part::Text(std::mem::take(&mut text)));
+                }
+                parts.push(Part::Var(var));
+            }
+            '}' => {
+                return Err(anyhow!(
+                    "{template} has a }} that wasn't opened, write }}}} for a brace"
+                ))
+            }
+            c => text.push(c),
+        }
+    }
+    if !text.is_empty() {
+        parts.push(Part::Text(text));
+    }
+    Ok(parts)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    fn vars() -> PathVars {
+        PathVars {
+            hostname: "laptop".to_string(),
+            user: "nina".to_string(),
+            profile: Some("work".to_string()),
+        }
+    }
+
+    fn now() -> DateTime<Local> {
+        Local.with_ymd_and_hms(2024, 5, 1, 9, 30, 0).unwrap()
+    }
+
+    #[test]
+    fn expands() {
+        let cases = [
+            ("/mnt/nas/{hostname}/{user}", "/mnt/nas/laptop/nina"),
+            ("/mnt/{profile}/{date}", "/mnt/work/2024-05-01"),
+            ("/mnt/{year}/{month}/{day}-{hour}", "/mnt/2024/05/01-09"),
+            ("/mnt/{{hostname}}", "/mnt/{hostname}"),
+            ("/mnt/a}}b{{c", "/mnt/a}b{c"),
+            ("/mnt/{{{user}}}", "/mnt/{nina}"),
+            ("/mnt/plain", "/mnt/plain"),
+        ];
+        for (template, expanded) in cases {
+            assert_eq!(
+                expand(template, &vars(), &now()).unwrap(),
+                expanded,
+                "{template}"
+            );
+        }
+    }
+
+    #[test]
+    fn rejects() {
+        let cases = [
+            ("/mnt/{host}", "Unknown variable {host}"),
+            ("/mnt/{}", "Unknown variable {}"),
+            ("/mnt/{hostname", "isn't closed"),
+            ("/mnt/{date/x", "isn't closed"),
+            ("/mnt/hostname}", "wasn't opened"),
+        ];
+        for (template, error) in cases {
+            let err = expand(template, &vars(), &now()).unwrap_err().to_string();
+            assert!(err.contains(error), "{template}: {err}");
+        }
+    }
+
+    #[test]
+    fn checks_values() {
+        let now = now();
+        let without_profile = PathVars {
+            profile: None,
+            ..vars()
+        };
+        let err = expand("/mnt/{profile}", &without_profile, &now).unwrap_err();
+        assert!(err.to_string().contains("needs --profile"), "{err}");
+
+        for hostname in ["", ".", "..", "a/b", "a\\b", "a\0b"] {
+            let vars = PathVars {
+                hostname: hostname.to_string(),
+                ..vars()
+            };
+            let err = expand("/mnt/{hostname}", &vars, &now).unwrap_err();
+            assert!(
+                err.to_string().contains("can't be a directory name"),
+                "{err}"
+            );
+            // Unused variables aren't checked
+            assert_eq!(expand("/mnt/{user}", &vars, &now).unwrap(), "/mnt/nina");
+        }
+    }
+
+    #[test]
+    fn time_dependence() {
+        assert!(depends_on_time("/mnt/{date}"));
+        assert!(depends_on_time("/mnt/{hostname}/{hour}"));
+        assert!(!depends_on_time("/mnt/{hostname}"));
+        assert!(!depends_on_time("/mnt/{{date}}"));
+        assert!(!depends_on_time("/mnt/{date"));
+        assert!(is_template("/mnt/{{x}}"));
+        assert!(!is_template("/mnt/x"));
+    }
+
+    #[test]
+    fn fixed_prefixes() {
+        let cases = [
+            ("/mnt/nas/{hostname}/{date}", "/mnt/nas"),
+            ("/mnt/nas/x{date}", "/mnt/nas"),
+            ("/mnt/{{nas}}/{date}", "/mnt/{nas}"),
+            ("{hostname}/backup", ""),
+            ("/mnt/plain", "/mnt/plain"),
+        ];
+        for (template, prefix) in cases {
+            assert_eq!(fixed_prefix(template), Path::new(prefix), "{template}");
+        }
+    }
+}
@@ -0,0 +1,168 @@
+//! Keeping background syncing out of the way: a limit on how fast copies write into the
+//! backup, and a lower IO priority for the whole process.
+//!
+//! The limit is a token bucket shared by every copy, so they don't write more than it allows
+//! together. It holds up to a second's worth of bytes, which lets small files through
+//! without waiting while large ones are spread out evenly
+
+use anyhow::{anyhow, Result};
+use std::{
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+use tokio::io::{self, AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt};
+
+/// How much is read before waiting for the bucket
+const CHUNK_SIZE: usize = 64 * 1024;
+
+/// Limits how many bytes per second are written, see the module docs
+#[derive(Debug)]
+pub struct Throttle {
+    bytes_per_sec: AtomicU64,
+    bucket: Mutex<Bucket>,
+}
+
+#[derive(Debug)]
+struct Bucket {
+    /// Negative while writes are waiting for bytes that haven't been refilled yet
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Throttle {
+    /// `bytes_per_sec` has to be at least 1
+    pub fn new(bytes_per_sec: u64) -> Self {
+        let bytes_per_sec = bytes_per_sec.max(1);
+        Self {
+            bytes_per_sec: AtomicU64::new(bytes_per_sec),
+            bucket: Mutex::new(Bucket {
+                tokens: bytes_per_sec as f64,
+                refilled: Instant::now(),
+            }),
+        }
+    }
+
+    pub fn bytes_per_sec(&self) -> u64 {
+        self.bytes_per_sec.load(Ordering::Relaxed)
+    }
+
+    /// Changes the limit for every copy sharing it, including those already running.
+    /// `u64::MAX` doesn't limit anything
+    pub fn set_bytes_per_sec(&self, bytes_per_sec: u64) {
+        self.bytes_per_sec
+            .store(bytes_per_sec.max(1), Ordering::Relaxed);
+    }
+
+    /// Waits until `bytes` more may be written
+    pub async fn take(&self, bytes: usize) {
+        let wait = self.reserve(bytes);
+        if !wait.is_zero() {
+            tokio::time::sleep(wait).await;
+        }
+    }
+
+    /// Takes `bytes` out of the bucket, returning how long to wait until they are there
+    fn reserve(&self, bytes: usize) -> Duration {
+        let rate = self.bytes_per_sec() as f64;
+        let mut bucket = self.bucket.lock().unwrap();
+        let now = Instant::now();
+        let refill = now.duration_since(bucket.refilled).as_secs_f64() * rate;
+        bucket.tokens = (bucket.tokens + refill).min(rate) - bytes as f64;
+        bucket.refilled = now;
+        match bucket.tokens < 0.0 {
+            true => Duration::from_secs_f64(-bucket.tokens / rate),
+            false => Duration::ZERO,
+        }
+    }
+}
+
+/// Like [`tokio::io::copy`], but no faster than `throttle` allows
+pub(crate) async fn copy_throttled<R, W>(
+    reader: &mut R,
+    writer: &mut W,
+    throttle: &Throttle,
+) -> io::Result<u64>
+where
+    R: AsyncRead + Unpin + ?Sized,
+    W: AsyncWrite + Unpin + ?Sized,
+{
+    let mut buf = vec![0; CHUNK_SIZE];
+    let mut copied = 0;
+    loop {
+        let read = reader.read(&mut buf).await?;
+        if read == 0 {
+            writer.flush().await?;
+            return Ok(copied);
+        }
+        throttle.take(read).await;
+        writer.write_all(&buf[..read]).await?;
+        copied += read as u64;
+    }
+}
+
+/// Gives the IO of this process, and every thread it starts from now on, the lowest
+/// priority, so it only gets the disk when nothing else wants it
+#[cfg(any(target_os = "linux", target_os = "android"))]
+pub fn lower_io_priority() -> Result<()> {
+    use nix::libc;
+
+    const IOPRIO_WHO_PROCESS: libc::c_int = 1;
+    const IOPRIO_CLASS_IDLE: libc::c_int = 3;
+    const IOPRIO_CLASS_SHIFT: libc::c_int = 13;
+
+    // The priority belongs to each thread, and new threads inherit it from the one that
+    // starts them
+    let threads = std::fs::read_dir("/proc/self/task")?;
+    for thread in threads {
+        let name = thread?.file_name();
+        let Some(tid) = name
+            .to_str()
+            .and_then(|tid| tid.parse::<libc::c_int>().ok())
+        else {
+            continue;
+        };
+        // SAFETY: ioprio_set only takes integers
+        let set = unsafe {
+            libc::syscall(
+                libc::SYS_ioprio_set,
+                IOPRIO_WHO_PROCESS,
+                tid,
+                IOPRIO_CLASS_IDLE << IOPRIO_CLASS_SHIFT,
+            )
+        };
+        if set != 0 {
+            return Err(anyhow!(
+                "Error lowering the IO priority: {}",
+                std::io::Error::last_os_error()
+            ));
+        }
+    }
+    Ok(())
+}
+
+/// Puts this process in background mode, which lowers the priority of its IO
+#[cfg(windows)]
+pub fn lower_io_priority() -> Result<()> {
+    use windows_sys::Win32::System::Threading::{
+        GetCurrentProcess, SetPriorityClass, PROCESS_MODE_BACKGROUND_BEGIN,
+    };
+
+    // SAFETY: the pseudo handle of the current process is always valid
+    if unsafe { SetPriorityClass(GetCurrentProcess(), PROCESS_MODE_BACKGROUND_BEGIN) } == 0 {
+        return Err(anyhow!(
+            "Error entering background mode: {}",
+            std::io::Error::last_os_error()
+        ));
+    }
+    Ok(())
+}
+
+#[cfg(not(any(target_os = "linux", target_os = "android", windows)))]
+pub fn lower_io_priority() -> Result<()> {
+    Err(anyhow!(
+        "Lowering the IO priority is only supported on Linux and Windows"
+    ))
+}
@@ -0,0 +1,176 @@
+//! Changing files on their way into the backup, like leaving secrets out of `.env` files or
+//! turning CRLF line endings into LF, see [`SyncOptions::transforms`](crate::SyncOptions::transforms).
+//!
+//! Every [`Transform`] applies to the files matching a glob, and library users can add
+//! their own next to the built-in ones. A transformed backup no longer matches its file in
+//! work_dir, so initialization never copies it back over the original, and sweeps only
+//! look at modification times to tell whether it's out of date
+
+use anyhow::{anyhow, Context, Result};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use regex::Regex;
+use std::{
+    fmt,
+    io::Write,
+    path::Path,
+    process::{Command, Stdio},
+    sync::Arc,
+};
+
+/// Something done to the contents of a file before it's copied into the backup
+pub trait Transform: fmt::Debug + Send + Sync {
+    /// The contents the backup of `relative_path` gets instead of `contents`. This may do
+    /// blocking IO
+    fn apply(&self, relative_path: &Path, contents: Vec<u8>) -> Result<Vec<u8>>;
+}
+
+/// Leaves out every line that matches a regex
+#[derive(Debug, Clone)]
+pub struct RedactLines(pub Regex);
+
+impl Transform for RedactLines {
+    fn apply(&self, _relative_path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+        let mut redacted = Vec::with_capacity(contents.len());
+        for line in contents.split_inclusive(|&byte| byte == b'\n') {
+            let text = String::from_utf8_lossy(line);
+            if !self.0.is_match(text.trim_end_matches(['\r', '\n'])) {
+                redacted.extend_from_slice(line);
+            }
+        }
+        Ok(redacted)
+    }
+}
+
+/// Turns CRLF line endings into LF
+#[derive(Debug, Clone, Copy)]
+pub struct CrlfToLf;
+
+impl Transform for CrlfToLf {
+    fn apply(&self, _relative_path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+        let mut converted = Vec::with_capacity(contents.len());
+        let mut bytes = contents.iter().peekable();
+        while let Some(&byte) = bytes.next() {
+            if byte != b'\r' || bytes.peek() != Some(&&b'\n') {
+                converted.push(byte);
+            }
+        }
+        Ok(converted)
+    }
+}
+
+/// Compresses files with the `gzip` command. The backup keeps the name of the file
+#[derive(Debug, Clone, Copy)]
+pub struct Gzip;
+
+impl Transform for Gzip {
+    fn apply(&self, _relative_path: &Path, contents: Vec<u8>) -> Result<Vec<u8>> {
+        // Without a name or a timestamp in the header, the same file compresses the same
+        let mut child = Command::new("gzip")
+            .args(["-c", "-n"])
+            .stdin(Stdio::piped())
+            .stdout(Stdio::piped())
+            .stderr(Stdio::piped())
+            .spawn()
+            .context("Error running gzip, is it installed?")?;
+        let mut stdin = child.stdin.take().expect("stdin is piped");
+        // Written from another thread, or a full stdout pipe would block us both
+        let writer = std::thread::spawn(move || stdin.write_all(&contents));
+        let output = child.wait_with_output()?;
+        writer
+            .join()
+            .map_err(|_| anyhow!("Writing to gzip panicked"))?
+            .context("Error writing to gzip")?;
+        match output.status.success() {
+            true => Ok(output.stdout),
+            false => Err(anyhow!(
+                "gzip failed: {}",
+                String::from_utf8_lossy(&output.stderr).trim_end()
+            )),
+        }
+    }
+}
+
+/// One of the built-in transforms, by name: `redact:REGEX`, `crlf-to-lf` or `gzip`
+pub fn builtin(name: &str) -> Result<Arc<dyn Transform>> {
+    if let Some(regex) = name.strip_prefix("redact:") {
+        let regex = Regex::new(regex).with_context(|| anyhow!("Invalid regex {regex}"))?;
+        return Ok(Arc::new(RedactLines(regex)));
+    }
+    match name {
+        "crlf-to-lf" => Ok(Arc::new(CrlfToLf)),
+        "gzip" => Ok(Arc::new(Gzip)),
+        _ => Err(anyhow!(
+            "unknown transform {name}, expected redact:REGEX, crlf-to-lf or gzip"
+        )),
+    }
+}
+
+/// A transform and the files it applies to
+#[derive(Debug, Clone)]
+struct Rule {
+    matcher: Gitignore,
+    transform: Arc<dyn Transform>,
+}
+
+/// The transforms applied to files matching their globs, in the order they were added
+#[derive(Debug, Clone, Default)]
+pub struct Transforms {
+    rules: Vec<Rule>,
+}
+
+impl Transforms {
+    /// Parses rules like `*.env=redact:^SECRET`, a glob and the name of a built-in
+    /// transform, see [`builtin`]
+    pub fn parse(rules: &[String]) -> Result<Self> {
+        let mut transforms = Self::default();
+        for rule in rules {
+            let (glob, name) = rule
+                .split_once('=')
+                .ok_or_else(|| anyhow!("Invalid transform {rule}, expected GLOB=TRANSFORM"))?;
+            transforms.add(glob, builtin(name)?)?;
+        }
+        Ok(transforms)
+    }
+
+    /// Applies `transform` to the files matching `glob`, after the transforms added before
+    pub fn add(&mut self, glob: &str, transform: Arc<dyn Transform>) -> Result<()> {
+        let mut builder = GitignoreBuilder::new(".");
+        builder
+            .add_line(None, glob)
+            .map_err(|err| anyhow!("Invalid transform pattern {glob}: {err}"))?;
+        let matcher = builder
+            .build()
+            .map_err(|err| anyhow!("Error building transform pattern {glob}: {err}"))?;
+        self.rules.push(Rule { matcher, transform });
+        Ok(())
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether any transform applies to `relative_path`
+    pub fn applies_to(&self, relative_path: &Path) -> bool {
+        self.rules.iter().any(|rule| rule.matches(relative_path))
+    }
+
+    /// `contents` after every transform that applies to `relative_path`. This may do
+    /// blocking IO
+    pub fn apply(&self, relative_path: &Path, mut contents: Vec<u8>) -> Result<Vec<u8>> {
+        for rule in self.rules.iter().filter(|rule| rule.matches(relative_path)) {
+            contents = rule
+                .transform
+                .apply(relative_path, contents)
+                .with_context(|| anyhow!("Error transforming {}", relative_path.display()))?;
+        }
+        Ok(contents)
+    }
+}
+
+impl Rule {
+    fn matches(&self, relative_path: &Path) -> bool {
+        self.matcher
+            .matched_path_or_any_parents(relative_path, false)
+            .is_ignore()
+    }
+}
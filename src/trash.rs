@@ -0,0 +1,75 @@
+//! Setting aside files that initialization would otherwise delete for good.
+//!
+//! Files go to the platform trash when there is one. When that fails they are moved into a
+//! `cleared-<timestamp>` directory in the backup's metadata directory instead
+
+use anyhow::{anyhow, Context, Result};
+use chrono::Utc;
+use std::path::{Path, PathBuf};
+use tracing::{debug, warn};
+use walkdir::WalkDir;
+
+use crate::{meta::metadata_dir, snapshot::SNAPSHOT_FORMAT};
+
+/// Where the files cleared by one initialization are moved when the trash can't take them
+pub fn quarantine_dir_for(backup_dir: &Path) -> PathBuf {
+    let timestamp = Utc::now().format(SNAPSHOT_FORMAT);
+    metadata_dir(backup_dir).join(format!("cleared-{timestamp}"))
+}
+
+/// Moves `path`, which is inside `work_dir`, to the trash. Falls back to moving it to the
+/// same place under `quarantine` if there is one. This does blocking IO
+pub fn discard(path: &Path, work_dir: &Path, quarantine: Option<&Path>) -> Result<()> {
+    let err = match ::trash::delete(path) {
+        Ok(()) => {
+            debug!(path = %path.display(), "Moved to the trash");
+            return Ok(());
+        }
+        Err(err) => err,
+    };
+
+    let Some(quarantine) = quarantine else {
+        return Err(err).with_context(|| anyhow!("Error moving {} to the trash", path.display()));
+    };
+    warn!(
+        "Error moving {} to the trash, moving it to {} instead: {err}",
+        path.display(),
+        quarantine.display()
+    );
+
+    let dst_path = quarantine.join(path.strip_prefix(work_dir)?);
+    if let Some(parent) = dst_path.parent() {
+        std::fs::create_dir_all(parent)
+            .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+    }
+    move_path(path, &dst_path)
+        .with_context(|| anyhow!("Error moving {} to {}", path.display(), dst_path.display()))
+}
+
+/// Renames `src` to `dst`, copying and removing it when they are on different filesystems
+fn move_path(src: &Path, dst: &Path) -> Result<()> {
+    if std::fs::rename(src, dst).is_ok() {
+        return Ok(());
+    }
+
+    for file_info in WalkDir::new(src) {
+        let file_info = file_info?;
+        let target = dst.join(file_info.path().strip_prefix(src)?);
+        let file_type = file_info.file_type();
+        if file_type.is_dir() {
+            std::fs::create_dir_all(&target)?;
+        } else if file_type.is_symlink() {
+            #[cfg(unix)]
+            std::os::unix::fs::symlink(std::fs::read_link(file_info.path())?, &target)?;
+        } else {
+            std::fs::copy(file_info.path(), &target)?;
+        }
+    }
+
+    match std::fs::symlink_metadata(src)?.is_dir() {
+        true => std::fs::remove_dir_all(src)?,
+        false => std::fs::remove_file(src)?,
+    }
+
+    Ok(())
+}
@@ -0,0 +1,208 @@
+//! `evil_mount status --tui`, a dashboard of a running sync that updates live
+
+use anyhow::Result;
+use evil_mount::control::{self, ControlCommand, PairStatus};
+use indicatif::HumanBytes;
+use ratatui::{
+    crossterm::event::{self, Event, KeyCode, KeyEventKind},
+    layout::{Constraint, Layout},
+    style::{Modifier, Style, Stylize},
+    text::Line,
+    widgets::{Block, List, ListItem, Paragraph, Row, Table},
+    DefaultTerminal, Frame,
+};
+use std::{
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use crate::{epoch, format_age};
+
+/// How often the running sync is asked for its status
+const REFRESH: Duration = Duration::from_secs(1);
+
+/// What the dashboard shows
+#[derive(Default)]
+struct Dashboard {
+    statuses: Vec<PairStatus>,
+    /// Bytes per second of each pair since the previous refresh
+    throughput: Vec<u64>,
+    /// The reply to the last key press
+    message: String,
+    /// Why the sync can't be reached, if it can't
+    unreachable: Option<String>,
+}
+
+/// Shows the dashboard until q or Esc is pressed
+pub async fn run(socket: &Path) -> Result<()> {
+    let mut terminal = ratatui::init();
+    let result = show(&mut terminal, socket).await;
+    ratatui::restore();
+    result
+}
+
+async fn show(terminal: &mut DefaultTerminal, socket: &Path) -> Result<()> {
+    let mut dashboard = Dashboard::default();
+    let mut refreshed: Option<Instant> = None;
+
+    loop {
+        if refreshed.is_none_or(|refreshed| refreshed.elapsed() >= REFRESH) {
+            let elapsed = refreshed.map(|refreshed| refreshed.elapsed());
+            refreshed = Some(Instant::now());
+            match control::query(socket).await {
+                Ok(statuses) => {
+                    dashboard.throughput = statuses
+                        .iter()
+                        .map(|status| {
+                            let previous = dashboard
+                                .statuses
+                                .iter()
+                                .find(|previous| previous.work_dir == status.work_dir);
+                            match (previous, elapsed) {
+                                (Some(previous), Some(elapsed)) => {
+                                    let bytes =
+                                        status.bytes_copied.saturating_sub(previous.bytes_copied);
+                                    (bytes as f64 / elapsed.as_secs_f64()) as u64
+                                }
+                                _ => 0,
+                            }
+                        })
+                        .collect();
+                    dashboard.statuses = statuses;
+                    dashboard.unreachable = None;
+                }
+                // It might come back, e.g. after a restart
+                Err(err) => dashboard.unreachable = Some(format!("{err:#}")),
+            }
+        }
+
+        terminal.draw(|frame| draw(frame, &dashboard))?;
+
+        // Polling blocks, but only briefly
+        if !tokio::task::block_in_place(|| event::poll(Duration::from_millis(250)))? {
+            continue;
+        }
+        let Event::Key(key) = event::read()? else {
+            continue;
+        };
+        if key.kind != KeyEventKind::Press {
+            continue;
+        }
+        let command = match key.code {
+            KeyCode::Char('q') | KeyCode::Esc => return Ok(()),
+            KeyCode::Char('p') => ControlCommand::Pause,
+            KeyCode::Char('r') => ControlCommand::Resume,
+            KeyCode::Char('s') => ControlCommand::SyncNow,
+            _ => continue,
+        };
+        dashboard.message = match control::send(socket, command).await {
+            Ok(reply) => reply.trim_end().to_string(),
+            Err(err) => format!("{err:#}"),
+        };
+        refreshed = None;
+    }
+}
+
+fn draw(frame: &mut Frame, dashboard: &Dashboard) {
+    let [pairs_area, errors_area, footer_area] = Layout::vertical([
+        Constraint::Length(dashboard.statuses.len() as u16 + 3),
+        Constraint::Min(3),
+        Constraint::Length(1),
+    ])
+    .areas(frame.area());
+
+    let header = Row::new([
+        "Work dir",
+        "Backup",
+        "Status",
+        "Pending",
+        "Synced",
+        "Copied",
+        "Throughput",
+        "Errors",
+        "Failing",
+        "Last synced",
+    ])
+    .style(Style::new().add_modifier(Modifier::BOLD));
+    let rows = dashboard
+        .statuses
+        .iter()
+        .zip(dashboard.throughput.iter().chain(std::iter::repeat(&0)))
+        .map(|(status, throughput)| {
+            Row::new([
+                status.work_dir.clone(),
+                status.backup.clone(),
+                match (status.paused, status.quiet, status.on_battery) {
+                    (true, _, _) => "paused".to_string(),
+                    _ if status.missing => "missing".to_string(),
+                    (false, true, _) => "quiet".to_string(),
+                    _ if status.degraded => "degraded".to_string(),
+                    (false, false, true) => "on battery".to_string(),
+                    (false, false, false) => "syncing".to_string(),
+                },
+                status.queue_depth.to_string(),
+                status.files_synced.to_string(),
+                HumanBytes(status.bytes_copied).to_string(),
+                format!("{}/s", HumanBytes(*throughput)),
+                status.copy_errors.to_string(),
+                status.failing.len().to_string(),
+                match status.last_sync {
+                    Some(secs) => format_age(epoch(secs)),
+                    None => "never".to_string(),
+                },
+            ])
+        });
+    let widths = [
+        Constraint::Fill(2),
+        Constraint::Fill(2),
+        Constraint::Length(8),
+        Constraint::Length(8),
+        Constraint::Length(8),
+        Constraint::Length(11),
+        Constraint::Length(12),
+        Constraint::Length(7),
+        Constraint::Length(8),
+        Constraint::Length(12),
+    ];
+    let table = Table::new(rows, widths)
+        .header(header)
+        .block(Block::bordered().title(" evil_mount "));
+    frame.render_widget(table, pairs_area);
+
+    // The newest first, across every pair
+    let mut errors: Vec<(u64, String)> = dashboard
+        .statuses
+        .iter()
+        .flat_map(|status| {
+            status
+                .recent_errors
+                .iter()
+                .map(|error| (error.at, error.message.clone()))
+        })
+        .collect();
+    errors.sort_by_key(|(at, _)| std::cmp::Reverse(*at));
+    let errors: Vec<ListItem> = errors
+        .into_iter()
+        .map(|(at, message)| ListItem::new(format!("{} {message}", format_age(epoch(at)))))
+        .collect();
+    frame.render_widget(
+        List::new(errors).block(Block::bordered().title(" Recent errors ")),
+        errors_area,
+    );
+
+    let footer = Line::from(vec![
+        " q".bold(),
+        " quit  ".into(),
+        "p".bold(),
+        " pause  ".into(),
+        "r".bold(),
+        " resume  ".into(),
+        "s".bold(),
+        " sync now  ".into(),
+        match &dashboard.unreachable {
+            Some(error) => error.clone().red(),
+            None => dashboard.message.clone().into(),
+        },
+    ]);
+    frame.render_widget(Paragraph::new(footer), footer_area);
+}
@@ -0,0 +1,71 @@
+//! Filenames that look the same but are encoded differently. macOS writes `é` as `e`
+//! followed by a combining accent (NFD), most everything else writes it as one character
+//! (NFC), so the same file synced from both ends up in the backup twice. Normalizing the
+//! paths in the backup and in the change detector gives it one entry
+
+use icu_normalizer::{ComposingNormalizerBorrowed, DecomposingNormalizerBorrowed};
+use serde::{Deserialize, Serialize};
+use std::{
+    borrow::Cow,
+    fmt,
+    path::{Path, PathBuf},
+    str::FromStr,
+};
+
+/// Which Unicode normalization form paths are brought into
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub enum NormalizeUnicode {
+    /// Leave paths as they are
+    #[default]
+    #[serde(rename = "none")]
+    None,
+    /// Composed, as on Linux and Windows
+    #[serde(rename = "nfc")]
+    Nfc,
+    /// Decomposed, as on macOS
+    #[serde(rename = "nfd")]
+    Nfd,
+}
+
+impl NormalizeUnicode {
+    /// `path` in this normalization form. Paths that aren't valid UTF-8 are left as they are
+    pub fn path<'a>(&self, path: &'a Path) -> Cow<'a, Path> {
+        let Some(text) = path.to_str() else {
+            return Cow::Borrowed(path);
+        };
+        let normalized = match self {
+            Self::None => return Cow::Borrowed(path),
+            Self::Nfc => ComposingNormalizerBorrowed::new_nfc().normalize(text),
+            Self::Nfd => DecomposingNormalizerBorrowed::new_nfd().normalize(text),
+        };
+        match normalized {
+            Cow::Borrowed(_) => Cow::Borrowed(path),
+            Cow::Owned(normalized) => Cow::Owned(PathBuf::from(normalized)),
+        }
+    }
+}
+
+impl FromStr for NormalizeUnicode {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Self::None),
+            "nfc" => Ok(Self::Nfc),
+            "nfd" => Ok(Self::Nfd),
+            _ => Err(format!(
+                "unknown unicode normalization {s}, expected nfc, nfd or none"
+            )),
+        }
+    }
+}
+
+impl fmt::Display for NormalizeUnicode {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::None => "none",
+            Self::Nfc => "nfc",
+            Self::Nfd => "nfd",
+        })
+    }
+}
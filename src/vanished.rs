@@ -0,0 +1,133 @@
+//! Noticing that a local backup_dir went away, like a USB drive that was pulled out or a
+//! network share that was unmounted, so nothing is copied into the empty mount point left
+//! behind.
+//!
+//! The filesystem backup_dir is on, and whether it's a mount point, are remembered at
+//! startup. While syncing it's looked at every [`MOUNT_CHECK_INTERVAL`], and once it's
+//! gone, nothing is mounted there anymore or it's on another filesystem, like one mounted
+//! over its parent, syncing pauses until it's back. A full sweep then catches up with what
+//! changed in the meantime. A filesystem is the same one if it has the same device number
+//! or the same filesystem id, which survives remounting for most of them. One that comes
+//! back with neither, like a new tmpfs, counts as a different one until evil_mount is
+//! started again
+
+use std::{
+    fmt,
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+/// How often backup_dir is looked at while syncing
+pub const MOUNT_CHECK_INTERVAL: Duration = Duration::from_secs(5);
+
+/// What happened to backup_dir
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Vanished {
+    /// It doesn't exist anymore
+    Missing,
+    /// It was a mount point at startup and the filesystem was unmounted, leaving the empty
+    /// directory behind
+    Unmounted,
+    /// It's on another filesystem than at startup
+    OtherFilesystem,
+}
+
+impl fmt::Display for Vanished {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Self::Missing => "is gone",
+            Self::Unmounted => "isn't mounted anymore",
+            Self::OtherFilesystem => "is on another filesystem than it was",
+        })
+    }
+}
+
+/// A local backup_dir, the filesystem it was on at startup and whether it was mounted there
+#[derive(Debug, Clone)]
+pub(crate) struct BackupRoot {
+    path: PathBuf,
+    filesystem: Option<FilesystemId>,
+    mount_point: bool,
+}
+
+impl BackupRoot {
+    /// Remembers what `path` is now. This does blocking IO
+    pub fn new(path: &Path) -> Self {
+        Self {
+            path: path.to_path_buf(),
+            filesystem: FilesystemId::of(path),
+            mount_point: is_mount_point(path),
+        }
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// What happened to backup_dir since startup, if anything. This does blocking IO
+    pub fn check(&self) -> Option<Vanished> {
+        if !self.path.is_dir() {
+            return Some(Vanished::Missing);
+        }
+        if self.mount_point && !is_mount_point(&self.path) {
+            return Some(Vanished::Unmounted);
+        }
+        match (self.filesystem, FilesystemId::of(&self.path)) {
+            (Some(was), Some(is)) if !was.is_same(&is) => Some(Vanished::OtherFilesystem),
+            _ => None,
+        }
+    }
+}
+
+/// Which filesystem a path is on
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+struct FilesystemId {
+    device: u64,
+    /// What `statvfs` says, 0 where it doesn't tell
+    fsid: u64,
+}
+
+impl FilesystemId {
+    // The fsid is a c_ulong, only 32 bits on some platforms
+    #[cfg(unix)]
+    #[allow(clippy::useless_conversion)]
+    fn of(path: &Path) -> Option<Self> {
+        use std::os::unix::fs::MetadataExt;
+        let device = std::fs::metadata(path).ok()?.dev();
+        let fsid = nix::sys::statvfs::statvfs(path)
+            .map(|stat| u64::from(stat.filesystem_id()))
+            .unwrap_or(0);
+        Some(Self { device, fsid })
+    }
+
+    /// Only a backup_dir that's gone is noticed
+    #[cfg(not(unix))]
+    fn of(_path: &Path) -> Option<Self> {
+        None
+    }
+
+    fn is_same(&self, other: &Self) -> bool {
+        self.device == other.device || self.fsid != 0 && self.fsid == other.fsid
+    }
+}
+
+/// Whether `path` is on another filesystem than its parent
+#[cfg(unix)]
+fn is_mount_point(path: &Path) -> bool {
+    use std::os::unix::fs::MetadataExt;
+    let device = |path: &Path| std::fs::metadata(path).ok().map(|metadata| metadata.dev());
+    let Some(parent) = path
+        .canonicalize()
+        .ok()
+        .and_then(|path| path.parent().map(Path::to_path_buf))
+    else {
+        return false;
+    };
+    matches!((device(path), device(&parent)), (Some(path), Some(parent)) if path != parent)
+}
+
+/// Only a backup_dir that's gone is noticed
+#[cfg(not(unix))]
+fn is_mount_point(_path: &Path) -> bool {
+    false
+}
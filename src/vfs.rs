@@ -0,0 +1,693 @@
+//! The filesystem that scanning work_dir and copying into a local backup go through, so
+//! they can run against [`MemoryFs`] instead of a real disk. [`RealFs`] is what syncing uses
+
+use anyhow::{anyhow, Context, Result};
+use async_trait::async_trait;
+use blake3::Hash;
+use std::{
+    collections::BTreeMap,
+    ffi::OsString,
+    fmt,
+    fs::Metadata,
+    io,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex,
+    },
+    time::{Duration, SystemTime},
+};
+use tokio::fs;
+
+use crate::copy::{copy_file, CopyOptions, VerifyWrites};
+
+/// What an entry in a directory is, without following links
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum EntryKind {
+    File,
+    Dir,
+    Symlink,
+    /// FIFOs, sockets and devices
+    Other,
+}
+
+/// Which file an entry is, however many paths lead to it
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+pub struct FileId {
+    pub device: u64,
+    pub inode: u64,
+}
+
+/// The metadata of a file or directory
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Entry {
+    pub kind: EntryKind,
+    pub size: u64,
+    pub modified: Option<SystemTime>,
+    /// Only on Unix, and not in a [`MemoryFs`]
+    pub id: Option<FileId>,
+}
+
+impl From<&Metadata> for Entry {
+    fn from(metadata: &Metadata) -> Self {
+        let kind = match () {
+            _ if metadata.is_symlink() => EntryKind::Symlink,
+            _ if metadata.is_dir() => EntryKind::Dir,
+            _ if metadata.is_file() => EntryKind::File,
+            _ => EntryKind::Other,
+        };
+        #[cfg(unix)]
+        let id = {
+            use std::os::unix::fs::MetadataExt;
+            Some(FileId {
+                device: metadata.dev(),
+                inode: metadata.ino(),
+            })
+        };
+        #[cfg(not(unix))]
+        let id = None;
+        Self {
+            kind,
+            size: metadata.len(),
+            modified: metadata.modified().ok(),
+            id,
+        }
+    }
+}
+
+#[async_trait]
+pub trait Vfs: fmt::Debug + Send + Sync {
+    /// The names in the directory `path` and what they are, in no particular order
+    async fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, EntryKind)>>;
+
+    /// The metadata of `path`, not following links, or `None` if there's nothing there
+    async fn metadata(&self, path: &Path) -> Result<Option<Entry>>;
+
+    /// The metadata of what `path` leads to, following links. `None` if there's nothing
+    /// there, or only a link that leads nowhere
+    async fn follow(&self, path: &Path) -> Result<Option<Entry>>;
+
+    /// Copies the file `from` to `to`, creating the directories above `to` and replacing
+    /// what's there. Returns the hash of what was read when
+    /// [`CopyOptions::verify_writes`] is on, like [`copy_file`]
+    async fn copy(&self, from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<Hash>>;
+
+    /// Removes the file or directory `path`, with everything in it. Removing something that
+    /// doesn't exist is fine
+    async fn remove(&self, path: &Path) -> Result<()>;
+
+    /// Removes the directory `path` if it's empty. False if it isn't, or isn't there
+    async fn remove_empty_dir(&self, path: &Path) -> Result<bool>;
+
+    /// Moves `from` to `to`, creating the directories above `to` and replacing what's there
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()>;
+}
+
+/// The filesystem of the machine, through [`tokio::fs`]
+#[derive(Debug, Clone, Copy, Default)]
+pub struct RealFs;
+
+#[async_trait]
+impl Vfs for RealFs {
+    async fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, EntryKind)>> {
+        let mut dir = fs::read_dir(path)
+            .await
+            .with_context(|| anyhow!("Error reading {}", path.display()))?;
+        let mut entries = Vec::new();
+        while let Some(entry) = dir.next_entry().await? {
+            let file_type = entry.file_type().await?;
+            let kind = match () {
+                _ if file_type.is_symlink() => EntryKind::Symlink,
+                _ if file_type.is_dir() => EntryKind::Dir,
+                _ if file_type.is_file() => EntryKind::File,
+                _ => EntryKind::Other,
+            };
+            entries.push((entry.file_name(), kind));
+        }
+        Ok(entries)
+    }
+
+    async fn metadata(&self, path: &Path) -> Result<Option<Entry>> {
+        real_entry(path, fs::symlink_metadata(path).await)
+    }
+
+    async fn follow(&self, path: &Path) -> Result<Option<Entry>> {
+        real_entry(path, fs::metadata(path).await)
+    }
+
+    async fn copy(&self, from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<Hash>> {
+        copy_file(from, to, options).await
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        let removed = match fs::symlink_metadata(path).await {
+            Ok(metadata) if metadata.is_dir() => fs::remove_dir_all(path).await,
+            Ok(_) => fs::remove_file(path).await,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            Err(err) => Err(err),
+        };
+        removed.with_context(|| anyhow!("Error removing {}", path.display()))
+    }
+
+    async fn remove_empty_dir(&self, path: &Path) -> Result<bool> {
+        Ok(fs::remove_dir(path).await.is_ok())
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        if let Some(parent) = to.parent() {
+            fs::create_dir_all(parent)
+                .await
+                .with_context(|| anyhow!("Error creating {}", parent.display()))?;
+        }
+        fs::rename(from, to)
+            .await
+            .with_context(|| anyhow!("Error renaming {} to {}", from.display(), to.display()))
+    }
+}
+
+fn real_entry(path: &Path, metadata: io::Result<Metadata>) -> Result<Option<Entry>> {
+    match metadata {
+        Ok(metadata) => Ok(Some(Entry::from(&metadata))),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+        Err(err) => Err(err).with_context(|| anyhow!("Error reading {}", path.display())),
+    }
+}
+
+#[derive(Debug, Clone)]
+enum Node {
+    Dir,
+    File {
+        contents: Arc<[u8]>,
+        modified: SystemTime,
+    },
+}
+
+/// A filesystem that only lives in memory, for tests. Its clock moves forward a second with
+/// every write, so files written one after the other never have the same modification time
+#[derive(Debug, Default)]
+pub struct MemoryFs {
+    nodes: Mutex<BTreeMap<PathBuf, Node>>,
+    clock: AtomicU64,
+    /// The directories whose contents can't be read
+    denied: Mutex<Vec<PathBuf>>,
+}
+
+impl MemoryFs {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    fn tick(&self) -> SystemTime {
+        let secs = self.clock.fetch_add(1, Ordering::Relaxed) + 1;
+        SystemTime::UNIX_EPOCH + Duration::from_secs(secs)
+    }
+
+    /// Writes `contents` to the file `path`, creating the directories above it
+    pub fn write(&self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) {
+        let path = path.as_ref();
+        let modified = self.tick();
+        let mut nodes = self.nodes.lock().unwrap();
+        create_parents(&mut nodes, path);
+        nodes.insert(
+            path.to_path_buf(),
+            Node::File {
+                contents: contents.as_ref().into(),
+                modified,
+            },
+        );
+    }
+
+    /// Creates the directory `path` and the directories above it
+    pub fn create_dir_all(&self, path: impl AsRef<Path>) {
+        let path = path.as_ref();
+        let mut nodes = self.nodes.lock().unwrap();
+        create_parents(&mut nodes, path);
+        nodes.insert(path.to_path_buf(), Node::Dir);
+    }
+
+    /// The contents of the file `path`
+    pub fn read(&self, path: impl AsRef<Path>) -> Option<Vec<u8>> {
+        match self.nodes.lock().unwrap().get(path.as_ref()) {
+            Some(Node::File { contents, .. }) => Some(contents.to_vec()),
+            _ => None,
+        }
+    }
+
+    /// Whether there's a file or directory at `path`
+    pub fn exists(&self, path: impl AsRef<Path>) -> bool {
+        self.nodes.lock().unwrap().contains_key(path.as_ref())
+    }
+
+    /// Has reading what's in the directory `dir` fail, like after `chmod 000`, until
+    /// [`MemoryFs::allow`]
+    pub fn deny(&self, dir: impl AsRef<Path>) {
+        self.denied.lock().unwrap().push(dir.as_ref().to_path_buf());
+    }
+
+    pub fn allow(&self, dir: impl AsRef<Path>) {
+        self.denied
+            .lock()
+            .unwrap()
+            .retain(|denied| denied != dir.as_ref());
+    }
+
+    /// Fails if `path` is in a denied directory, or is one and `listing` it
+    fn check_denied(&self, path: &Path, listing: bool) -> Result<()> {
+        let denied = self.denied.lock().unwrap();
+        match denied
+            .iter()
+            .any(|dir| path.starts_with(dir) && (listing || path != dir))
+        {
+            true => Err(
+                anyhow::Error::from(io::Error::from(io::ErrorKind::PermissionDenied))
+                    .context(format!("Error reading {}", path.display())),
+            ),
+            false => Ok(()),
+        }
+    }
+
+    /// Every file under `dir` and its contents, keyed by its path relative to `dir`
+    pub fn files(&self, dir: impl AsRef<Path>) -> BTreeMap<PathBuf, Vec<u8>> {
+        let dir = dir.as_ref();
+        self.nodes
+            .lock()
+            .unwrap()
+            .iter()
+            .filter_map(|(path, node)| match node {
+                Node::File { contents, .. } => Some((
+                    path.strip_prefix(dir).ok()?.to_path_buf(),
+                    contents.to_vec(),
+                )),
+                Node::Dir => None,
+            })
+            .collect()
+    }
+}
+
+/// The error reading `path` fails with when there's nothing there
+pub(crate) fn not_found(path: &Path) -> anyhow::Error {
+    anyhow::Error::from(io::Error::from(io::ErrorKind::NotFound))
+        .context(format!("Error reading {}", path.display()))
+}
+
+/// Creates the directories above `path` that are missing
+fn create_parents(nodes: &mut BTreeMap<PathBuf, Node>, path: &Path) {
+    for ancestor in path.ancestors().skip(1) {
+        if ancestor.as_os_str().is_empty() || nodes.contains_key(ancestor) {
+            break;
+        }
+        nodes.insert(ancestor.to_path_buf(), Node::Dir);
+    }
+}
+
+/// The paths of what's in the directory `path`, everything below it included
+fn descendants(nodes: &BTreeMap<PathBuf, Node>, path: &Path) -> Vec<PathBuf> {
+    nodes
+        .range(path.to_path_buf()..)
+        .skip_while(|(descendant, _)| *descendant == path)
+        .take_while(|(descendant, _)| descendant.starts_with(path))
+        .map(|(descendant, _)| descendant.clone())
+        .collect()
+}
+
+#[async_trait]
+impl Vfs for MemoryFs {
+    async fn read_dir(&self, path: &Path) -> Result<Vec<(OsString, EntryKind)>> {
+        self.check_denied(path, true)?;
+        let nodes = self.nodes.lock().unwrap();
+        match nodes.get(path) {
+            Some(Node::Dir) => {}
+            Some(Node::File { .. }) => {
+                return Err(anyhow!("Error reading {}: not a directory", path.display()))
+            }
+            None => return Err(not_found(path)),
+        }
+        Ok(descendants(&nodes, path)
+            .into_iter()
+            .filter(|child| child.parent() == Some(path))
+            .map(|child| {
+                let kind = match nodes[&child] {
+                    Node::Dir => EntryKind::Dir,
+                    Node::File { .. } => EntryKind::File,
+                };
+                (child.file_name().unwrap_or_default().to_os_string(), kind)
+            })
+            .collect())
+    }
+
+    async fn metadata(&self, path: &Path) -> Result<Option<Entry>> {
+        self.check_denied(path, false)?;
+        Ok(self.nodes.lock().unwrap().get(path).map(|node| match node {
+            Node::Dir => Entry {
+                kind: EntryKind::Dir,
+                size: 0,
+                modified: None,
+                id: None,
+            },
+            Node::File { contents, modified } => Entry {
+                kind: EntryKind::File,
+                size: contents.len() as u64,
+                modified: Some(*modified),
+                id: None,
+            },
+        }))
+    }
+
+    /// There are no links in memory
+    async fn follow(&self, path: &Path) -> Result<Option<Entry>> {
+        self.metadata(path).await
+    }
+
+    async fn copy(&self, from: &Path, to: &Path, options: &CopyOptions) -> Result<Option<Hash>> {
+        let now = self.tick();
+        let mut nodes = self.nodes.lock().unwrap();
+        let (contents, modified) = match nodes.get(from) {
+            Some(Node::File { contents, modified }) => (contents.clone(), *modified),
+            Some(Node::Dir) => return Err(anyhow!("{} is a directory", from.display())),
+            None => return Err(not_found(from)),
+        };
+        if let Some(Node::Dir) = nodes.get(to) {
+            return Err(anyhow!(
+                "Error copying into {}: is a directory",
+                to.display()
+            ));
+        }
+        let hash = match options.verify_writes {
+            VerifyWrites::Off => None,
+            _ => Some(blake3::hash(&contents)),
+        };
+        let modified = match options.preserve.times {
+            true => modified,
+            false => now,
+        };
+        create_parents(&mut nodes, to);
+        nodes.insert(to.to_path_buf(), Node::File { contents, modified });
+        Ok(hash)
+    }
+
+    async fn remove(&self, path: &Path) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        for descendant in descendants(&nodes, path) {
+            nodes.remove(&descendant);
+        }
+        nodes.remove(path);
+        Ok(())
+    }
+
+    async fn remove_empty_dir(&self, path: &Path) -> Result<bool> {
+        let mut nodes = self.nodes.lock().unwrap();
+        if !matches!(nodes.get(path), Some(Node::Dir)) || !descendants(&nodes, path).is_empty() {
+            return Ok(false);
+        }
+        nodes.remove(path);
+        Ok(true)
+    }
+
+    async fn rename(&self, from: &Path, to: &Path) -> Result<()> {
+        let mut nodes = self.nodes.lock().unwrap();
+        let Some(node) = nodes.remove(from) else {
+            return Err(not_found(from));
+        };
+        if let Some(Node::Dir) = nodes.get(to) {
+            for descendant in descendants(&nodes, to) {
+                nodes.remove(&descendant);
+            }
+        }
+        let moved: Vec<_> = descendants(&nodes, from)
+            .into_iter()
+            .filter_map(|descendant| {
+                let node = nodes.remove(&descendant)?;
+                Some((to.join(descendant.strip_prefix(from).ok()?), node))
+            })
+            .collect();
+        create_parents(&mut nodes, to);
+        nodes.insert(to.to_path_buf(), node);
+        nodes.extend(moved);
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::copy::{copy_to_dst, remove_from_dst, rename_in_dst};
+
+    const WORK: &str = "/work";
+    const BACKUP: &str = "/backup";
+
+    fn set_up() -> MemoryFs {
+        let vfs = MemoryFs::new();
+        vfs.create_dir_all(WORK);
+        vfs.create_dir_all(BACKUP);
+        vfs
+    }
+
+    async fn copy(vfs: &MemoryFs, path: &str) {
+        let path = Path::new(WORK).join(path);
+        copy_to_dst(
+            vfs,
+            &path,
+            Path::new(WORK),
+            Path::new(BACKUP),
+            &CopyOptions::default(),
+        )
+        .await
+        .unwrap();
+    }
+
+    fn backup(vfs: &MemoryFs) -> BTreeMap<PathBuf, Vec<u8>> {
+        vfs.files(BACKUP)
+    }
+
+    #[tokio::test]
+    async fn added_files_are_copied() {
+        let vfs = set_up();
+        vfs.write("/work/a", "one");
+        vfs.write("/work/dir/nested/b", "two");
+        copy(&vfs, "a").await;
+        copy(&vfs, "dir/nested/b").await;
+
+        assert_eq!(backup(&vfs), vfs.files(WORK));
+        assert!(vfs
+            .metadata(Path::new("/backup/dir/nested"))
+            .await
+            .unwrap()
+            .is_some());
+    }
+
+    #[tokio::test]
+    async fn modified_files_replace_their_backup() {
+        let vfs = set_up();
+        vfs.write("/work/a", "one");
+        copy(&vfs, "a").await;
+        let copied = vfs.metadata(Path::new("/backup/a")).await.unwrap().unwrap();
+
+        vfs.write("/work/a", "three");
+        copy(&vfs, "a").await;
+        let recopied = vfs.metadata(Path::new("/backup/a")).await.unwrap().unwrap();
+        assert_eq!(vfs.read("/backup/a").unwrap(), b"three");
+        assert_eq!(recopied.size, 5);
+        assert!(recopied.modified > copied.modified);
+    }
+
+    #[tokio::test]
+    async fn preserved_times_are_carried_over() {
+        let vfs = set_up();
+        vfs.write("/work/a", "one");
+        let options = CopyOptions {
+            preserve: "times".parse().unwrap(),
+            ..CopyOptions::default()
+        };
+        copy_to_dst(
+            &vfs,
+            Path::new("/work/a"),
+            Path::new(WORK),
+            Path::new(BACKUP),
+            &options,
+        )
+        .await
+        .unwrap();
+
+        let original = vfs.metadata(Path::new("/work/a")).await.unwrap().unwrap();
+        let copied = vfs.metadata(Path::new("/backup/a")).await.unwrap().unwrap();
+        assert_eq!(copied.modified, original.modified);
+    }
+
+    #[tokio::test]
+    async fn deleted_files_are_removed_with_empty_directories() {
+        let vfs = set_up();
+        vfs.write("/work/dir/nested/a", "one");
+        vfs.write("/work/dir/b", "two");
+        copy(&vfs, "dir/nested/a").await;
+        copy(&vfs, "dir/b").await;
+
+        let (work, backup_dir) = (Path::new(WORK), Path::new(BACKUP));
+        remove_from_dst(&vfs, Path::new("/work/dir/nested/a"), work, backup_dir)
+            .await
+            .unwrap();
+        assert!(!vfs.exists("/backup/dir/nested"));
+        assert!(vfs.exists("/backup/dir"));
+
+        remove_from_dst(&vfs, Path::new("/work/dir/b"), work, backup_dir)
+            .await
+            .unwrap();
+        assert!(!vfs.exists("/backup/dir"));
+        assert!(vfs.exists(BACKUP));
+
+        // Removing it twice is fine
+        remove_from_dst(&vfs, Path::new("/work/dir/b"), work, backup_dir)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn deleted_directories_are_removed_with_everything_in_them() {
+        let vfs = set_up();
+        vfs.write("/work/dir/a", "one");
+        vfs.write("/work/dir/sub/b", "two");
+        vfs.write("/work/dir2/c", "three");
+        for path in ["dir/a", "dir/sub/b", "dir2/c"] {
+            copy(&vfs, path).await;
+        }
+
+        remove_from_dst(
+            &vfs,
+            Path::new("/work/dir"),
+            Path::new(WORK),
+            Path::new(BACKUP),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            backup(&vfs).into_keys().collect::<Vec<_>>(),
+            [PathBuf::from("dir2/c")]
+        );
+    }
+
+    #[tokio::test]
+    async fn renamed_files_are_moved_in_the_backup() {
+        let vfs = set_up();
+        vfs.write("/work/old/a", "one");
+        copy(&vfs, "old/a").await;
+
+        vfs.rename(Path::new("/work/old/a"), Path::new("/work/new/b"))
+            .await
+            .unwrap();
+        rename_in_dst(
+            &vfs,
+            Path::new("/work/old/a"),
+            Path::new("/work/new/b"),
+            Path::new(WORK),
+            Path::new(BACKUP),
+        )
+        .await
+        .unwrap();
+
+        assert_eq!(backup(&vfs), vfs.files(WORK));
+        assert!(!vfs.exists("/backup/old"));
+    }
+
+    #[tokio::test]
+    async fn renamed_directories_keep_what_was_in_them() {
+        let vfs = set_up();
+        vfs.write("/work/old/a", "one");
+        vfs.write("/work/old/sub/b", "two");
+        vfs.write("/work/older/c", "three");
+
+        vfs.rename(Path::new("/work/old"), Path::new("/work/new"))
+            .await
+            .unwrap();
+        assert_eq!(
+            vfs.files("/work/new").into_keys().collect::<Vec<_>>(),
+            [PathBuf::from("a"), PathBuf::from("sub/b")]
+        );
+        assert!(vfs.exists("/work/older/c"));
+        assert!(!vfs.exists("/work/old"));
+    }
+
+    #[tokio::test]
+    async fn reading_directories() {
+        let vfs = set_up();
+        vfs.write("/work/a", "one");
+        vfs.write("/work/dir/b", "two");
+
+        let mut entries = vfs.read_dir(Path::new(WORK)).await.unwrap();
+        entries.sort_by(|a, b| a.0.cmp(&b.0));
+        assert_eq!(
+            entries,
+            [
+                (OsString::from("a"), EntryKind::File),
+                (OsString::from("dir"), EntryKind::Dir)
+            ]
+        );
+        assert!(vfs.read_dir(Path::new("/work/a")).await.is_err());
+    }
+
+    #[tokio::test]
+    async fn missing_files_fail_with_not_found() {
+        let vfs = set_up();
+        let err = vfs
+            .copy(
+                Path::new("/work/gone"),
+                Path::new("/backup/gone"),
+                &CopyOptions::default(),
+            )
+            .await
+            .unwrap_err();
+        let err = err.downcast_ref::<io::Error>().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::NotFound);
+        assert!(vfs
+            .metadata(Path::new("/work/gone"))
+            .await
+            .unwrap()
+            .is_none());
+    }
+
+    #[tokio::test]
+    async fn verified_copies_return_their_hash() {
+        let vfs = set_up();
+        vfs.write("/work/a", "one");
+        let options = CopyOptions {
+            verify_writes: VerifyWrites::Hash,
+            ..CopyOptions::default()
+        };
+        let hash = vfs
+            .copy(Path::new("/work/a"), Path::new("/backup/a"), &options)
+            .await
+            .unwrap();
+        assert_eq!(hash, Some(blake3::hash(b"one")));
+    }
+
+    #[tokio::test]
+    async fn real_fs_does_the_same() {
+        let dir = tempfile::tempdir().unwrap();
+        let (work, backup_dir) = (dir.path().join("work"), dir.path().join("backup"));
+        std::fs::create_dir_all(work.join("dir")).unwrap();
+        std::fs::create_dir(&backup_dir).unwrap();
+        std::fs::write(work.join("dir/a"), "one").unwrap();
+
+        let path = work.join("dir/a");
+        copy_to_dst(&RealFs, &path, &work, &backup_dir, &CopyOptions::default())
+            .await
+            .unwrap();
+        assert_eq!(std::fs::read(backup_dir.join("dir/a")).unwrap(), b"one");
+        let entry = RealFs.metadata(&backup_dir.join("dir/a")).await.unwrap();
+        assert_eq!(
+            entry.map(|entry| (entry.kind, entry.size)),
+            Some((EntryKind::File, 3))
+        );
+
+        let renamed = work.join("new/b");
+        rename_in_dst(&RealFs, &path, &renamed, &work, &backup_dir)
+            .await
+            .unwrap();
+        assert!(backup_dir.join("new/b").is_file());
+        assert!(!backup_dir.join("dir").exists());
+
+        remove_from_dst(&RealFs, &renamed, &work, &backup_dir)
+            .await
+            .unwrap();
+        assert!(!backup_dir.join("new").exists());
+        assert!(backup_dir.is_dir());
+    }
+}
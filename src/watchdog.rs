@@ -0,0 +1,86 @@
+//! Noticing a backup that stopped responding, like a dying USB drive whose writes hang
+//! forever instead of failing.
+//!
+//! Copies and sync cycles that run past their timeout are cancelled, and the backup counts
+//! as degraded from then on. While it is, every copy first waits out a delay that doubles
+//! with every further timeout, so a drive that keeps hanging isn't hammered with writes. The
+//! first copy that finishes in time again ends it. Copies done by a thread of their own,
+//! like those into a local directory, can't be interrupted: the thread is left to finish or
+//! hang on its own, and the file is retried like any other failed copy
+
+use std::{
+    fmt,
+    sync::Mutex,
+    time::{Duration, Instant},
+};
+
+/// How long copies wait after the first timeout, every further one waits twice as long
+const FIRST_BACKOFF: Duration = Duration::from_secs(5);
+
+/// Copies never wait longer than this for a degraded backup
+const MAX_BACKOFF: Duration = Duration::from_secs(5 * 60);
+
+/// The error of a copy or a sync cycle that took longer than it may
+#[derive(Debug, Clone, Copy)]
+pub struct TimedOut {
+    /// Whether it was a whole sync cycle, rather than a copy
+    pub cycle: bool,
+    pub after: Duration,
+}
+
+impl fmt::Display for TimedOut {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let (what, flag) = match self.cycle {
+            true => ("The sync cycle", "--cycle-timeout"),
+            false => ("The copy", "--copy-timeout"),
+        };
+        write!(
+            f,
+            "{what} took longer than the {flag} of {}s and was cancelled",
+            self.after.as_secs()
+        )
+    }
+}
+
+impl std::error::Error for TimedOut {}
+
+/// Whether the backup is degraded, and how long copies wait for it
+#[derive(Debug, Default)]
+pub(crate) struct Watchdog {
+    state: Mutex<State>,
+}
+
+#[derive(Debug, Default)]
+struct State {
+    /// Timeouts since the last copy that finished in time
+    timeouts: u32,
+    /// Copies wait until then
+    until: Option<Instant>,
+}
+
+impl Watchdog {
+    /// Records a timeout. Returns how long copies wait from now on, and whether the backup
+    /// only became degraded with it
+    pub fn timed_out(&self) -> (Duration, bool) {
+        let mut state = self.state.lock().unwrap();
+        state.timeouts += 1;
+        let backoff = FIRST_BACKOFF
+            .saturating_mul(1 << (state.timeouts - 1).min(16))
+            .min(MAX_BACKOFF);
+        state.until = Some(Instant::now() + backoff);
+        (backoff, state.timeouts == 1)
+    }
+
+    /// Records a copy that finished in time. Returns whether the backup was degraded
+    pub fn responded(&self) -> bool {
+        let mut state = self.state.lock().unwrap();
+        state.until = None;
+        std::mem::take(&mut state.timeouts) > 0
+    }
+
+    /// How long the next copy has to wait for a degraded backup, if at all
+    pub fn backoff(&self) -> Option<Duration> {
+        let until = self.state.lock().unwrap().until?;
+        Some(until.saturating_duration_since(Instant::now())).filter(|wait| !wait.is_zero())
+    }
+}
@@ -0,0 +1,172 @@
+use anyhow::{anyhow, Context, Result};
+use notify::{
+    event::{ModifyKind, RenameMode},
+    Event, EventKind, RecommendedWatcher, RecursiveMode, Watcher,
+};
+use std::{
+    path::{Path, PathBuf},
+    sync::Arc,
+    time::Duration,
+};
+use tokio::sync::mpsc::{self, UnboundedReceiver};
+use tracing::{error, info};
+
+use crate::{
+    detect::DetectChanges,
+    filter::{walk_dirs_in, walk_files_in},
+    syncer::SyncContext,
+};
+
+/// A change observed inside a watched directory
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum FsEvent {
+    /// The path was created or its contents changed
+    Changed(PathBuf),
+    /// The path no longer exists
+    Removed(PathBuf),
+}
+
+/// Recursively watches a directory using the native event API of the platform
+/// (inotify, FSEvents or ReadDirectoryChangesW)
+pub struct DirWatcher {
+    // Dropping the watcher stops the events, so it has to live as long as the receiver
+    _watcher: RecommendedWatcher,
+    events: UnboundedReceiver<FsEvent>,
+}
+
+impl DirWatcher {
+    pub fn new(dir: &Path) -> Result<Self> {
+        let (tx, events) = mpsc::unbounded_channel();
+
+        let mut watcher =
+            notify::recommended_watcher(move |res: notify::Result<Event>| match res {
+                Ok(event) => {
+                    for fs_event in translate_event(event) {
+                        // The receiver only goes away when we're shutting down
+                        let _ = tx.send(fs_event);
+                    }
+                }
+                Err(err) => error!("Error watching for file changes: {err}"),
+            })
+            .with_context(|| anyhow!("Error creating file watcher"))?;
+
+        watcher
+            .watch(dir, RecursiveMode::Recursive)
+            .with_context(|| anyhow!("Error watching {}", dir.display()))?;
+
+        Ok(Self {
+            _watcher: watcher,
+            events,
+        })
+    }
+
+    /// Waits for the next event, returns `None` if the watcher stopped
+    pub async fn next(&mut self) -> Option<FsEvent> {
+        self.events.recv().await
+    }
+}
+
+fn translate_event(event: Event) -> Vec<FsEvent> {
+    let Event { kind, paths, .. } = event;
+
+    match kind {
+        EventKind::Create(_)
+        | EventKind::Modify(ModifyKind::Data(_))
+        | EventKind::Modify(ModifyKind::Metadata(_))
+        | EventKind::Modify(ModifyKind::Name(RenameMode::To)) => {
+            paths.into_iter().map(FsEvent::Changed).collect()
+        }
+        EventKind::Remove(_) | EventKind::Modify(ModifyKind::Name(RenameMode::From)) => {
+            paths.into_iter().map(FsEvent::Removed).collect()
+        }
+        // The first path is where the file came from, the second is where it went
+        EventKind::Modify(ModifyKind::Name(RenameMode::Both)) => {
+            let mut paths = paths.into_iter();
+            let mut fs_events = Vec::new();
+            if let Some(from) = paths.next() {
+                fs_events.push(FsEvent::Removed(from));
+            }
+            fs_events.extend(paths.map(FsEvent::Changed));
+            fs_events
+        }
+        // Some backends can't tell us what happened, so check the disk instead
+        EventKind::Modify(_) | EventKind::Any => paths
+            .into_iter()
+            .map(|path| match path.exists() {
+                true => FsEvent::Changed(path),
+                false => FsEvent::Removed(path),
+            })
+            .collect(),
+        EventKind::Access(_) | EventKind::Other => Vec::new(),
+    }
+}
+
+/// Copies files to backup_dir as the filesystem reports changes to them
+pub(crate) async fn watch_files(ctx: Arc<SyncContext>) -> Result<()> {
+    let SyncContext {
+        work_dir, options, ..
+    } = &*ctx;
+    let mut watcher = DirWatcher::new(work_dir)?;
+
+    info!("Watching for file changes...");
+
+    loop {
+        if ctx.is_shutting_down() {
+            return Ok(());
+        }
+
+        // Wake up every now and then to check whether we should shut down
+        let event = tokio::select! {
+            event = tokio::time::timeout(Duration::from_secs(1), watcher.next()) => event,
+            _ = ctx.control.sync_requested() => {
+                ctx.sweep_and_report(true).await;
+                ctx.end_cycle().await;
+                continue;
+            }
+        };
+        let event = match event {
+            Ok(Some(event)) => event,
+            Ok(None) => return Err(anyhow!("File watcher stopped unexpectedly")),
+            Err(_) => {
+                ctx.end_cycle().await;
+                continue;
+            }
+        };
+        // Resuming sweeps up whatever changed in the meantime
+        if ctx.is_paused() {
+            continue;
+        }
+
+        let path = match &event {
+            FsEvent::Changed(path) | FsEvent::Removed(path) => path,
+        };
+        if options.ignore.is_ignored_in(work_dir, path, path.is_dir()) {
+            continue;
+        }
+
+        match event {
+            FsEvent::Changed(path) => {
+                if options.syncs_dirs() && path.is_dir() {
+                    for dir_info in
+                        walk_dirs_in(work_dir, &path, &options.ignore, options.copy.symlinks)
+                    {
+                        ctx.sync_dir(dir_info.path()).await;
+                    }
+                }
+                // A directory that was moved into work_dir doesn't produce events for its contents
+                for file_info in
+                    walk_files_in(work_dir, &path, &options.ignore, options.copy.symlinks)
+                {
+                    match ctx.detector.mode() {
+                        // The event already tells us the file changed, and filesystems with coarse
+                        // mtimes would hide a second write within the same tick
+                        DetectChanges::Mtime => ctx.sync_file(file_info.into_path()).await,
+                        _ => ctx.sync_file_if_changed(file_info.into_path()).await,
+                    }
+                }
+                options.ignore.walk_errors().check()?;
+            }
+            FsEvent::Removed(path) => ctx.schedule_removal(path),
+        }
+    }
+}
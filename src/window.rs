@@ -0,0 +1,382 @@
+//! When syncing is allowed to touch the disk. During [`QuietHours`], or outside of a
+//! [`SyncWindow`], changes are held back like while paused, and the first sweep after them
+//! copies whatever changed in the meantime. Both go by local time, to the minute
+
+use chrono::{DateTime, Datelike, Local, NaiveDate, Timelike};
+use serde::{Deserialize, Serialize};
+use std::{fmt, str::FromStr};
+
+/// Times of day when nothing is synced, like `22:00-07:00` or `12:00-13:00,22:00-07:00`.
+/// A range that ends before it starts goes past midnight
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct QuietHours {
+    /// Start and end in minutes since midnight, the end not included
+    ranges: Vec<(u32, u32)>,
+}
+
+impl QuietHours {
+    pub fn contains(&self, time: &DateTime<Local>) -> bool {
+        let minute = time.hour() * 60 + time.minute();
+        self.ranges.iter().any(|&(start, end)| match start < end {
+            true => (start..end).contains(&minute),
+            false => minute >= start || minute < end,
+        })
+    }
+}
+
+fn parse_time(s: &str) -> Option<u32> {
+    let (hours, minutes) = s.trim().split_once(':')?;
+    let (hours, minutes): (u32, u32) = (hours.parse().ok()?, minutes.parse().ok()?);
+    // 24:00 is the end of the day
+    (hours < 24 && minutes < 60 || hours == 24 && minutes == 0).then_some(hours * 60 + minutes)
+}
+
+impl FromStr for QuietHours {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let ranges = s
+            .split(',')
+            .map(|range| {
+                let invalid = || format!("invalid quiet hours {range}, expected e.g. 22:00-07:00");
+                let (start, end) = range.split_once('-').ok_or_else(invalid)?;
+                let (start, end) = (parse_time(start), parse_time(end));
+                match (start, end) {
+                    (Some(start), Some(end)) if start % 1440 == end % 1440 => {
+                        Err(format!("quiet hours {range} start when they end"))
+                    }
+                    (Some(start), Some(end)) => Ok((start % 1440, end % 1440)),
+                    _ => Err(invalid()),
+                }
+            })
+            .collect::<Result<_, _>>()?;
+        Ok(Self { ranges })
+    }
+}
+
+impl TryFrom<String> for QuietHours {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for QuietHours {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for (i, (start, end)) in self.ranges.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            write!(
+                f,
+                "{:02}:{:02}-{:02}:{:02}",
+                start / 60,
+                start % 60,
+                end / 60,
+                end % 60
+            )?;
+        }
+        Ok(())
+    }
+}
+
+impl From<QuietHours> for String {
+    fn from(quiet_hours: QuietHours) -> Self {
+        quiet_hours.to_string()
+    }
+}
+
+/// A time of day like `08:00`, in local time
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct TimeOfDay {
+    /// Minutes since midnight
+    minute: u32,
+}
+
+impl TimeOfDay {
+    /// The day it was last this time of day at `time`, today if it's past it
+    pub fn last_day(&self, time: &DateTime<Local>) -> NaiveDate {
+        let today = time.date_naive();
+        match time.hour() * 60 + time.minute() >= self.minute {
+            true => today,
+            false => today.pred_opt().unwrap_or(today),
+        }
+    }
+}
+
+impl FromStr for TimeOfDay {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match parse_time(s) {
+            Some(minute) if minute < 1440 => Ok(Self { minute }),
+            _ => Err(format!("invalid time of day {s}, expected e.g. 08:00")),
+        }
+    }
+}
+
+impl TryFrom<String> for TimeOfDay {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for TimeOfDay {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "{:02}:{:02}", self.minute / 60, self.minute % 60)
+    }
+}
+
+impl From<TimeOfDay> for String {
+    fn from(time: TimeOfDay) -> Self {
+        time.to_string()
+    }
+}
+
+/// The minutes when syncing is allowed, as a cron expression like `* 9-17 * * mon-fri`:
+/// minute, hour, day of the month, month and day of the week. Fields take `*`, numbers,
+/// ranges, steps like `*/15` and lists, and months and days of the week also take their
+/// first three letters. Like cron, a day matches either day field when both are restricted
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(try_from = "String", into = "String")]
+pub struct SyncWindow {
+    expression: String,
+    /// Bit n is set for every n the field matches
+    minutes: u64,
+    hours: u64,
+    days: u64,
+    months: u64,
+    weekdays: u64,
+    /// Whether the day fields are `*`
+    any_day: bool,
+    any_weekday: bool,
+}
+
+const MONTHS: &[&str] = &[
+    "jan", "feb", "mar", "apr", "may", "jun", "jul", "aug", "sep", "oct", "nov", "dec",
+];
+const WEEKDAYS: &[&str] = &["sun", "mon", "tue", "wed", "thu", "fri", "sat"];
+
+impl SyncWindow {
+    pub fn contains(&self, time: &DateTime<Local>) -> bool {
+        let has = |bits: u64, n: u32| bits & (1 << n) != 0;
+        let day = has(self.days, time.day());
+        let weekday = has(self.weekdays, time.weekday().num_days_from_sunday());
+        let day = match (self.any_day, self.any_weekday) {
+            (false, false) => day || weekday,
+            _ => day && weekday,
+        };
+        has(self.minutes, time.minute())
+            && has(self.hours, time.hour())
+            && has(self.months, time.month())
+            && day
+    }
+}
+
+/// The bits of a cron field that takes `min` to `max`. `names` stand for `min` and up
+fn parse_field(field: &str, min: u32, max: u32, names: &[&str]) -> Result<u64, String> {
+    let value = |s: &str| {
+        let lower = s.to_ascii_lowercase();
+        names
+            .iter()
+            .position(|name| *name == lower)
+            .map(|i| i as u32 + min)
+            .or_else(|| s.parse().ok())
+            .filter(|n| (min..=max).contains(n))
+            .ok_or_else(|| format!("invalid value {s} in cron field {field}"))
+    };
+    let mut bits = 0;
+    for part in field.split(',') {
+        let (range, step) = match part.split_once('/') {
+            Some((range, step)) => match step.parse::<u32>() {
+                Ok(step) if step > 0 => (range, Some(step)),
+                _ => return Err(format!("invalid step {step} in cron field {field}")),
+            },
+            None => (part, None),
+        };
+        let (start, end) = match range.split_once('-') {
+            _ if range == "*" => (min, max),
+            Some((start, end)) => (value(start)?, value(end)?),
+            None if step.is_some() => (value(range)?, max),
+            None => (value(range)?, value(range)?),
+        };
+        if start > end {
+            return Err(format!("invalid range {range} in cron field {field}"));
+        }
+        for n in (start..=end).step_by(step.unwrap_or(1) as usize) {
+            bits |= 1 << n;
+        }
+    }
+    Ok(bits)
+}
+
+impl FromStr for SyncWindow {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let fields: Vec<&str> = s.split_whitespace().collect();
+        let [minutes, hours, days, months, weekdays] = fields[..] else {
+            return Err(format!(
+                "invalid sync window {s}, expected 5 cron fields like `* 9-17 * * mon-fri`"
+            ));
+        };
+        let mut weekday_bits = parse_field(weekdays, 0, 7, WEEKDAYS)?;
+        // 7 is Sunday too
+        if weekday_bits & (1 << 7) != 0 {
+            weekday_bits = (weekday_bits & !(1 << 7)) | 1;
+        }
+        Ok(Self {
+            expression: fields.join(" "),
+            minutes: parse_field(minutes, 0, 59, &[])?,
+            hours: parse_field(hours, 0, 23, &[])?,
+            days: parse_field(days, 1, 31, &[])?,
+            months: parse_field(months, 1, 12, MONTHS)?,
+            weekdays: weekday_bits,
+            any_day: days == "*",
+            any_weekday: weekdays == "*",
+        })
+    }
+}
+
+impl TryFrom<String> for SyncWindow {
+    type Error = String;
+
+    fn try_from(s: String) -> Result<Self, Self::Error> {
+        s.parse()
+    }
+}
+
+impl fmt::Display for SyncWindow {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.expression)
+    }
+}
+
+impl From<SyncWindow> for String {
+    fn from(window: SyncWindow) -> Self {
+        window.expression
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use chrono::TimeZone;
+
+    /// 2024-05-01 is a Wednesday
+    fn at(day: u32, hour: u32, minute: u32) -> DateTime<Local> {
+        Local
+            .with_ymd_and_hms(2024, 5, day, hour, minute, 0)
+            .unwrap()
+    }
+
+    #[test]
+    fn quiet_hours() {
+        let cases = [
+            ("22:00-07:00", (23, 30), true),
+            ("22:00-07:00", (0, 0), true),
+            ("22:00-07:00", (6, 59), true),
+            ("22:00-07:00", (7, 0), false),
+            ("22:00-07:00", (21, 59), false),
+            ("22:00-07:00", (22, 0), true),
+            ("12:00-13:00,22:00-07:00", (12, 30), true),
+            ("12:00-13:00,22:00-07:00", (13, 0), false),
+            ("18:00-24:00", (23, 59), true),
+            ("18:00-24:00", (0, 0), false),
+        ];
+        for (quiet_hours, (hour, minute), quiet) in cases {
+            let parsed: QuietHours = quiet_hours.parse().unwrap();
+            assert_eq!(
+                parsed.contains(&at(1, hour, minute)),
+                quiet,
+                "{quiet_hours} at {hour:02}:{minute:02}"
+            );
+        }
+        assert_eq!(
+            "12:00-13:00, 22:00-07:00"
+                .parse::<QuietHours>()
+                .unwrap()
+                .to_string(),
+            "12:00-13:00,22:00-07:00"
+        );
+        for invalid in [
+            "",
+            "22:00",
+            "22-07",
+            "25:00-07:00",
+            "22:60-07:00",
+            "07:00-07:00",
+        ] {
+            assert!(invalid.parse::<QuietHours>().is_err(), "{invalid}");
+        }
+    }
+
+    #[test]
+    fn sync_windows() {
+        let cases = [
+            ("* 9-17 * * mon-fri", at(1, 9, 0), true),
+            ("* 9-17 * * mon-fri", at(1, 17, 59), true),
+            ("* 9-17 * * mon-fri", at(1, 18, 0), false),
+            ("* 9-17 * * mon-fri", at(4, 12, 0), false),
+            ("*/15 * * * *", at(1, 3, 45), true),
+            ("*/15 * * * *", at(1, 3, 46), false),
+            ("10-30/10 * * * *", at(1, 3, 20), true),
+            ("10-30/10 * * * *", at(1, 3, 25), false),
+            ("5/20 * * * *", at(1, 3, 45), true),
+            ("0,30 * * * *", at(1, 3, 30), true),
+            ("* * * may *", at(1, 0, 0), true),
+            ("* * * JAN-apr *", at(1, 0, 0), false),
+            // Sunday is both 0 and 7
+            ("* * * * 0", at(5, 12, 0), true),
+            ("* * * * 7", at(5, 12, 0), true),
+            ("* * * * sat-7", at(5, 12, 0), true),
+            ("* * * * 1-6", at(5, 12, 0), false),
+            // Either day field matches once both are restricted
+            ("* * 1 * sun", at(1, 12, 0), true),
+            ("* * 1 * sun", at(5, 12, 0), true),
+            ("* * 1 * sun", at(2, 12, 0), false),
+            // Otherwise both have to
+            ("* * 1 * *", at(2, 12, 0), false),
+            ("* * * * wed", at(1, 12, 0), true),
+            ("* * */2 * *", at(3, 12, 0), true),
+        ];
+        for (window, time, allowed) in cases {
+            let parsed: SyncWindow = window.parse().unwrap();
+            assert_eq!(parsed.contains(&time), allowed, "{window} at {time}");
+        }
+    }
+
+    #[test]
+    fn invalid_sync_windows() {
+        let cases = [
+            "* * * *",
+            "* * * * * *",
+            "60 * * * *",
+            "* 24 * * *",
+            "* * 0 * *",
+            "* * * 13 *",
+            "* * * * 8",
+            "*/0 * * * *",
+            "*/x * * * *",
+            "30-10 * * * *",
+            // Ranges don't wrap around, write sat,sun or sat-7
+            "* * * * sat-sun",
+            "* * * foo *",
+        ];
+        for window in cases {
+            assert!(window.parse::<SyncWindow>().is_err(), "{window}");
+        }
+        assert_eq!(
+            "*  9-17 * *   mon-fri"
+                .parse::<SyncWindow>()
+                .unwrap()
+                .to_string(),
+            "* 9-17 * * mon-fri"
+        );
+    }
+}
@@ -0,0 +1,195 @@
+//! What Windows needs on top of the rest: paths longer than 260 characters, hidden and
+//! system files, and files that editors keep locked.
+//!
+//! Long paths get the `\\?\` prefix, which lifts the limit. A copy that fails because
+//! another program has the file open is tried again a few times, and with
+//! [`CopyOptions::shadow_copies`] the file is finally read out of a Volume Shadow Copy,
+//! which sees it as it was on disk. Shadow copies need administrator rights
+
+use std::{borrow::Cow, path::Path};
+
+#[cfg(windows)]
+use {
+    crate::copy::{copy_file_once, CopyOptions},
+    anyhow::{anyhow, Context, Result},
+    blake3::Hash,
+    std::path::{Component, PathBuf, Prefix},
+};
+
+/// The attribute of files hidden from Explorer
+pub const FILE_ATTRIBUTE_HIDDEN: u32 = 0x2;
+/// The attribute of files that belong to the operating system
+pub const FILE_ATTRIBUTE_SYSTEM: u32 = 0x4;
+
+/// Paths at least this long need the `\\?\` prefix
+#[cfg(windows)]
+const MAX_PATH: usize = 260;
+
+/// `path` with the `\\?\` prefix if it's too long to be used without it. Other paths, and
+/// every path on other platforms, are returned as they are
+pub fn long_path(path: &Path) -> Cow<'_, Path> {
+    #[cfg(windows)]
+    if path.as_os_str().len() >= MAX_PATH {
+        let mut components = path.components();
+        let long = match components.next() {
+            Some(Component::Prefix(prefix)) => match prefix.kind() {
+                Prefix::Disk(_) => {
+                    // Joining would replace the prefix with the absolute path
+                    let mut long = std::ffi::OsString::from(r"\\?\");
+                    long.push(path.as_os_str());
+                    Some(PathBuf::from(long))
+                }
+                Prefix::UNC(server, share) => Some(
+                    Path::new(r"\\?\UNC")
+                        .join(server)
+                        .join(share)
+                        .join(components.as_path()),
+                ),
+                // Verbatim already, or a device
+                _ => None,
+            },
+            _ => None,
+        };
+        if let Some(long) = long {
+            return Cow::Owned(long);
+        }
+    }
+    Cow::Borrowed(path)
+}
+
+/// Whether `path` has any of the file `attributes`. Always false on other platforms
+pub fn has_attributes(path: &Path, attributes: u32) -> bool {
+    #[cfg(windows)]
+    {
+        use std::os::windows::fs::MetadataExt;
+        std::fs::symlink_metadata(long_path(path))
+            .is_ok_and(|metadata| metadata.file_attributes() & attributes != 0)
+    }
+    #[cfg(not(windows))]
+    {
+        let _ = (path, attributes);
+        false
+    }
+}
+
+/// Whether `err` was caused by another program having the file open or locked
+pub fn is_sharing_violation(err: &anyhow::Error) -> bool {
+    const ERROR_SHARING_VIOLATION: i32 = 32;
+    const ERROR_LOCK_VIOLATION: i32 = 33;
+
+    cfg!(windows)
+        && err.chain().any(|cause| {
+            cause
+                .downcast_ref::<std::io::Error>()
+                .and_then(std::io::Error::raw_os_error)
+                .is_some_and(|code| code == ERROR_SHARING_VIOLATION || code == ERROR_LOCK_VIOLATION)
+        })
+}
+
+/// A Volume Shadow Copy of one volume, deleted again when dropped
+#[cfg(windows)]
+struct ShadowCopy {
+    id: String,
+    /// Like `\\?\GLOBALROOT\Device\HarddiskVolumeShadowCopy3`
+    device: PathBuf,
+    volume: PathBuf,
+}
+
+#[cfg(windows)]
+impl ShadowCopy {
+    /// Shadows the volume, like `C:\`, with PowerShell. This does blocking IO
+    fn create(volume: &Path) -> Result<Self> {
+        let script = format!(
+            "$ErrorActionPreference = 'Stop'; \
+             $created = Invoke-CimMethod -ClassName Win32_ShadowCopy -MethodName Create \
+                 -Arguments @{{Volume = '{}'; Context = 'ClientAccessible'}}; \
+             $copy = Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq $created.ShadowID; \
+             Write-Output $created.ShadowID $copy.DeviceObject",
+            volume.display().to_string().replace('\'', "''")
+        );
+        let output = powershell(&script)?;
+        let mut lines = output.lines().map(str::trim);
+        match (lines.next(), lines.next()) {
+            (Some(id), Some(device)) if !id.is_empty() && !device.is_empty() => Ok(Self {
+                id: id.to_string(),
+                device: PathBuf::from(device),
+                volume: volume.to_path_buf(),
+            }),
+            _ => Err(anyhow!(
+                "Creating a shadow copy of {} didn't say where it is",
+                volume.display()
+            )),
+        }
+    }
+
+    /// Where `path`, which is on the shadowed volume, is in the shadow copy
+    fn path_of(&self, path: &Path) -> Option<PathBuf> {
+        let relative_path = path
+            .strip_prefix(&self.volume)
+            .or_else(|_| path.strip_prefix(Path::new(r"\\?\").join(&self.volume)))
+            .ok()?;
+        Some(self.device.join(relative_path))
+    }
+}
+
+#[cfg(windows)]
+impl Drop for ShadowCopy {
+    fn drop(&mut self) {
+        let script = format!(
+            "Get-CimInstance Win32_ShadowCopy | Where-Object ID -eq '{}' | Remove-CimInstance",
+            self.id
+        );
+        if let Err(err) = powershell(&script) {
+            tracing::warn!("Error deleting shadow copy {}: {err:#}", self.id);
+        }
+    }
+}
+
+/// Runs `script` and returns what it printed. This does blocking IO
+#[cfg(windows)]
+fn powershell(script: &str) -> Result<String> {
+    let output = std::process::Command::new("powershell")
+        .args(["-NoProfile", "-NonInteractive", "-Command", script])
+        .output()
+        .context("Error running powershell")?;
+    match output.status.success() {
+        true => Ok(String::from_utf8_lossy(&output.stdout).into_owned()),
+        false => Err(anyhow!(
+            "powershell failed: {}",
+            String::from_utf8_lossy(&output.stderr).trim_end()
+        )),
+    }
+}
+
+/// Copies `path`, which another program keeps locked, out of a fresh shadow copy of its
+/// volume. The shadow copy is deleted afterwards
+#[cfg(windows)]
+pub(crate) async fn copy_from_shadow_copy(
+    path: &Path,
+    dst_path: &Path,
+    options: &CopyOptions,
+) -> Result<Option<Hash>> {
+    let path = path.canonicalize()?;
+    let volume = match path.components().next() {
+        Some(Component::Prefix(prefix)) => match prefix.kind() {
+            Prefix::Disk(letter) | Prefix::VerbatimDisk(letter) => {
+                Some(PathBuf::from(format!("{}:\\", letter as char)))
+            }
+            _ => None,
+        },
+        _ => None,
+    }
+    .ok_or_else(|| anyhow!("{} isn't on a drive with a letter", path.display()))?;
+    tracing::info!(
+        "{} is locked, copying it from a shadow copy",
+        path.display()
+    );
+
+    let shadow = tokio::task::spawn_blocking(move || ShadowCopy::create(&volume)).await??;
+    let shadow_path = shadow
+        .path_of(&path)
+        .ok_or_else(|| anyhow!("{} isn't in the shadow copy", path.display()))?;
+    let copied = copy_file_once(&shadow_path, dst_path, options).await;
+    tokio::task::spawn_blocking(move || drop(shadow)).await?;
+    copied
+}